@@ -26,14 +26,48 @@ static LIBFUSE_NAME: &str = "fuse";
 #[cfg(target_os = "macos")]
 static LIBFUSE_NAME: &str = "osxfuse";
 
+static LIBFUSE3_NAME: &str = "fuse3";
+
+/// Finds the libfuse to build against, and the FUSE_USE_VERSION to build with.  libfuse 3 is used when the `fuse3`
+/// feature asks for it, or on linux when there's no libfuse 2 to be found, since newer distributions have stopped
+/// packaging it.
+fn probe_fuse() -> (pkg_config::Library, u32) {
+    let want_fuse3 = std::env::var_os("CARGO_FEATURE_FUSE3").is_some();
+    if want_fuse3 && cfg!(target_os = "macos") {
+        panic!("The fuse3 feature isn't supported on macos");
+    }
+
+    if !want_fuse3 {
+        // this will also print out the appropriate "cargo:rustc" meta commands to stdout
+        match pkg_config::Config::new()
+            .atleast_version("2.6.0")
+            .probe(LIBFUSE_NAME)
+        {
+            Ok(lib) => return (lib, 26),
+            Err(e) if cfg!(target_os = "macos") => {
+                panic!("Invalid version of {}: {}", LIBFUSE_NAME, e)
+            }
+            Err(e) => println!(
+                "cargo:warning=No usable {}, falling back to {}: {}",
+                LIBFUSE_NAME, LIBFUSE3_NAME, e
+            ),
+        }
+    }
+
+    // 3.2 is the first to have fuse_loop_mt_31, which the high-level api needs to loop the way it did in 2.x
+    let lib = pkg_config::Config::new()
+        .atleast_version("3.2.0")
+        .probe(LIBFUSE3_NAME)
+        .expect(&format!("Invalid version of {}", LIBFUSE3_NAME));
+    println!("cargo:rustc-cfg=fuse3");
+    (lib, 31)
+}
+
 fn main() {
     let out_dir: std::path::PathBuf = std::env::var("OUT_DIR").unwrap().into();
 
-    // this will also print out the appropriate "cargo:rustc" meta commands to stdout
-    let fuse_lib = pkg_config::Config::new()
-        .atleast_version("2.6.0")
-        .probe(LIBFUSE_NAME)
-        .expect(&format!("Invalid version of {}", LIBFUSE_NAME));
+    println!("cargo:rustc-check-cfg=cfg(fuse3)");
+    let (fuse_lib, use_version) = probe_fuse();
 
     // Tell cargo to invalidate the built crate whenever the wrapper changes
     println!("cargo:rerun-if-changed=wrapper.h");
@@ -58,7 +92,7 @@ fn main() {
         // Tell cargo to invalidate the built crate whenever any of the
         // included header files changed.
         .parse_callbacks(Box::new(bindgen::CargoCallbacks))
-        .clang_arg(format!("-DFUSE_USE_VERSION={}", 26))
+        .clang_arg(format!("-DFUSE_USE_VERSION={}", use_version))
         .clang_args(cflags)
         .clang_args(include_paths)
         .generate()
@@ -177,6 +177,8 @@ impl From<FuseConfig> for Vec<String> {
         opt_expand!(int, conf, args, ac_attr_timeout);
         opt_expand!(bool, conf, args, noforget);
         opt_expand!(int, conf, args, remember);
+        // libfuse 3 rejects the options it dropped, instead of ignoring them
+        #[cfg(not(fuse3))]
         opt_expand!(bool, conf, args, nopath);
         opt_expand!(bool, conf, args, intr);
         opt_expand!(int, conf, args, intr_signal);
@@ -186,6 +188,7 @@ impl From<FuseConfig> for Vec<String> {
         opt_expand!(bool, conf, args, async_read);
         opt_expand!(bool, conf, args, sync_read);
         opt_expand!(bool, conf, args, atomic_o_trunc);
+        #[cfg(not(fuse3))]
         opt_expand!(bool, conf, args, big_writes);
         opt_expand!(bool, conf, args, no_remote_lock);
         opt_expand!(bool, conf, args, no_remote_flock);
@@ -308,10 +311,13 @@ impl From<MountConfig> for Vec<String> {
         opt_expand!(bool, conf, args, allow_other);
         opt_expand!(bool, conf, args, allow_root);
         opt_expand!(bool, conf, args, auto_unmount);
+        // libfuse 3 rejects the options it dropped, instead of ignoring them
+        #[cfg(not(fuse3))]
         opt_expand!(bool, conf, args, nonempty);
         opt_expand!(bool, conf, args, default_permissions);
         opt_expand!(str, conf, args, fsname);
         opt_expand!(str, conf, args, subtype);
+        #[cfg(not(fuse3))]
         opt_expand!(bool, conf, args, large_read);
         opt_expand!(int, conf, args, max_read);
 
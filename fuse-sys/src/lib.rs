@@ -17,8 +17,11 @@
  */
 
 use libc::{c_char, c_int, c_void};
+#[cfg(fuse3)]
+use nix::errno::Errno::{EEXIST, EINVAL};
 use nix::errno::Errno::{ENOENT, ENOSYS};
-use parking_lot::Mutex;
+use parking_lot::{Condvar, Mutex};
+use std::collections::BTreeSet;
 use std::ffi::{CStr, CString, OsStr};
 use std::mem::size_of;
 use std::os::unix::ffi::OsStrExt;
@@ -29,8 +32,9 @@ use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
 use std::sync::mpsc;
 use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant};
 
-use log::{debug, error, info, trace, warn};
+use tracing::{debug, error, field, info, info_span, trace, warn};
 
 pub use bindings::*;
 
@@ -51,6 +55,10 @@ const FUSEOP_TAG: &str = "fuse_op";
 const FUSE_TAG: &str = "fuse";
 const FS_TAG: &str = "fuse_fs";
 
+/// How long the invalidation flusher lets a burst of invalidations pile up before sending them, so that a path queued
+/// many times by one big operation is only sent once
+const INVALIDATE_BATCH_MS: u64 = 50;
+
 #[derive(Ord, PartialOrd, Eq, PartialEq, Hash, Clone, Debug)]
 pub struct Request {
     pub uid: uid_t,
@@ -112,31 +120,107 @@ pub fn new_statvfs() -> statvfs {
 /// things like invalidating paths.
 pub struct FuseHandle {
     disabled: AtomicBool,
+    // set by the fuse_loop thread when the loop returns, whether we asked it to or the filesystem was unmounted
+    // externally
+    loop_done: AtomicBool,
     handle_struct: AtomicPtr<fuse>,
+    // libfuse 3 does away with channels, the fuse handle is all there is
+    #[cfg(not(fuse3))]
     channel_struct: AtomicPtr<fuse_chan>,
+    // paths waiting for the flusher thread to invalidate them
+    invalidations: Mutex<Vec<PathBuf>>,
+    invalidations_ready: Condvar,
 }
 
 impl FuseHandle {
     fn disable(&self) {
         self.disabled.store(true, Ordering::SeqCst);
+        // the flusher has to notice, so that it can be joined
+        let _queue = self.invalidations.lock();
+        self.invalidations_ready.notify_all();
+    }
+
+    /// Whether this build of fuse can invalidate paths at all.  fuse_invalidate_path only lives on libfuse >= 3.0,
+    /// which isn't in ubuntu 18.04 LTS.  It's on mac though.
+    pub fn can_invalidate() -> bool {
+        cfg!(any(target_os = "macos", fuse3))
     }
+
+    /// Queues `path` to have its kernel caches invalidated, see `invalidate_many`
     pub fn invalidate(&self, path: &Path) {
-        if self.disabled.load(Ordering::SeqCst) {
+        self.invalidate_many(&[path.to_owned()]);
+    }
+
+    /// Queues `paths` to have their kernel caches invalidated by the flusher thread.  Invalidating from inside a fuse
+    /// operation can deadlock on the kernel's locks for the operation's own path, so it's never done inline, and it
+    /// lets a big operation queue all of the paths it touched without waiting on the kernel for each one.
+    pub fn invalidate_many(&self, paths: &[PathBuf]) {
+        if paths.is_empty() || !Self::can_invalidate() || self.disabled.load(Ordering::SeqCst) {
             return;
         }
+        trace!(target: FUSE_TAG, "Queueing {} invalidations", paths.len());
+        let mut queue = self.invalidations.lock();
+        queue.extend_from_slice(paths);
+        self.invalidations_ready.notify_one();
+    }
 
-        let path_bytes = CString::new(path.as_os_str().as_bytes()).unwrap();
-        let _path_raw = path_bytes.into_raw();
+    #[cfg(any(target_os = "macos", fuse3))]
+    fn invalidate_now(&self, path: &Path) {
+        let path_bytes = match CString::new(path.as_os_str().as_bytes()) {
+            Ok(bytes) => bytes,
+            Err(_) => return,
+        };
+        let ret = unsafe {
+            fuse_invalidate_path(
+                self.handle_struct.load(Ordering::Relaxed),
+                path_bytes.as_ptr(),
+            )
+        };
+        // it's usually that the kernel had nothing cached for the path, which is fine
+        if ret != 0 {
+            trace!(target: FUSE_TAG, "Invalidating {:?} returned {}", path, ret);
+        }
+    }
+    #[cfg(not(any(target_os = "macos", fuse3)))]
+    fn invalidate_now(&self, _path: &Path) {}
+
+    /// Invalidates the queued paths in batches until the handle is disabled
+    fn flush_invalidations(&self) {
+        loop {
+            {
+                let mut queue = self.invalidations.lock();
+                while queue.is_empty() && !self.disabled.load(Ordering::SeqCst) {
+                    self.invalidations_ready.wait(&mut queue);
+                }
+            }
+            if self.disabled.load(Ordering::SeqCst) {
+                break;
+            }
 
-        // fuse_invalidate_path only lives on libfuse >= 3.0, which isn't in ubuntu 18.04 LTS. it's on mac though.
-        // FIXME make this smarter wrt detecting fuse version number
-        #[cfg(target_os = "macos")]
-        unsafe {
-            fuse_invalidate_path(self.handle_struct.load(Ordering::Relaxed), _path_raw);
+            thread::sleep(std::time::Duration::from_millis(INVALIDATE_BATCH_MS));
+            let batch: BTreeSet<PathBuf> = self.invalidations.lock().drain(..).collect();
+            debug!(target: FUSE_TAG, "Invalidating {} paths", batch.len());
+            for path in batch {
+                if self.disabled.load(Ordering::SeqCst) || self.loop_done.load(Ordering::SeqCst) {
+                    break;
+                }
+                self.invalidate_now(&path);
+            }
         }
+        debug!(target: FUSE_TAG, "Stopped invalidation flusher");
     }
 }
 
+#[cfg(not(fuse3))]
+unsafe fn loop_mt(f: *mut fuse) -> std::os::raw::c_int {
+    fuse_loop_mt(f)
+}
+#[cfg(fuse3)]
+unsafe fn loop_mt(f: *mut fuse) -> std::os::raw::c_int {
+    // not cloning the /dev/fuse fd for each thread, which is what 2.x did
+    fuse_loop_mt_31(f, 0)
+}
+
 #[cfg(target_os = "macos")]
 unsafe fn fdatasync(fd: std::os::raw::c_int) -> std::os::raw::c_int {
     libc::fsync(fd)
@@ -146,6 +230,103 @@ unsafe fn fdatasync(fd: std::os::raw::c_int) -> std::os::raw::c_int {
     libc::fdatasync(fd)
 }
 
+// macos has no fallocate, only preallocation through fcntl, which can't punch holes.  osxfuse passes the flags of
+// the preallocation through as the mode, where bit 0x10 means allocating from the physical end of the file
+#[cfg(target_os = "macos")]
+unsafe fn fallocate_fd(
+    fd: std::os::raw::c_int,
+    mode: std::os::raw::c_int,
+    offset: off_t,
+    len: off_t,
+) -> std::os::raw::c_int {
+    let flags = mode as u32 & (libc::F_ALLOCATECONTIG | libc::F_ALLOCATEALL);
+    let mut store = libc::fstore_t {
+        fst_flags: if flags == 0 {
+            libc::F_ALLOCATEALL
+        } else {
+            flags
+        },
+        fst_posmode: if mode & 0x10 != 0 {
+            libc::F_PEOFPOSMODE
+        } else {
+            libc::F_VOLPOSMODE
+        },
+        fst_offset: offset,
+        fst_length: len,
+        fst_bytesalloc: 0,
+    };
+    libc::fcntl(fd, libc::F_PREALLOCATE, &mut store)
+}
+#[cfg(not(target_os = "macos"))]
+unsafe fn fallocate_fd(
+    fd: std::os::raw::c_int,
+    mode: std::os::raw::c_int,
+    offset: off_t,
+    len: off_t,
+) -> std::os::raw::c_int {
+    libc::fallocate(fd, mode, offset, len)
+}
+
+/// Allocates `len` bytes at `offset` of the open file `fd`, the way `Filesystem::fallocate` does by default
+pub fn fallocate_fd_range(fd: RawFd, mode: i32, offset: off_t, len: off_t) -> FuseResult<()> {
+    if unsafe { fallocate_fd(fd, mode, offset, len) } == -1 {
+        Err(std::io::Error::last_os_error().into())
+    } else {
+        Ok(())
+    }
+}
+
+/// Copies the contents of a buffer vector from fuse into memory
+pub fn bufvec_to_vec(src: *mut fuse_bufvec) -> FuseResult<Vec<u8>> {
+    unsafe {
+        let size = fuse_buf_size(src);
+        let mut data = vec![0u8; size];
+        let mut dst = fuse_bufvec {
+            count: 1,
+            idx: 0,
+            off: 0,
+            buf: [fuse_buf {
+                size,
+                flags: 0,
+                mem: data.as_mut_ptr() as *mut c_void,
+                fd: -1,
+                pos: 0,
+            }],
+        };
+        let copied = fuse_buf_copy(&mut dst, src, 0);
+        if copied < 0 {
+            return Err(std::io::Error::from_raw_os_error(-copied as i32).into());
+        }
+        data.truncate(copied as usize);
+        Ok(data)
+    }
+}
+
+/// Writes a buffer vector from fuse into `fd` at `offset`.  If the data is still in the fuse device, this can splice
+/// it straight into the file, without ever copying it into memory
+pub fn write_bufvec_to_fd(src: *mut fuse_bufvec, fd: RawFd, offset: off_t) -> FuseResult<usize> {
+    unsafe {
+        let mut dst = fuse_bufvec {
+            count: 1,
+            idx: 0,
+            off: 0,
+            buf: [fuse_buf {
+                size: fuse_buf_size(src),
+                flags: fuse_buf_flags_FUSE_BUF_IS_FD | fuse_buf_flags_FUSE_BUF_FD_SEEK,
+                mem: ptr::null_mut(),
+                fd,
+                pos: offset,
+            }],
+        };
+        let copied = fuse_buf_copy(&mut dst, src, 0);
+        if copied < 0 {
+            Err(std::io::Error::from_raw_os_error(-copied as i32).into())
+        } else {
+            Ok(copied as usize)
+        }
+    }
+}
+
 /// A Filesystem represents a filesystem with callbacks for fuse to call.  Notice not all of the
 /// fuse functions are implemented.  They can be fleshed out as needed.
 pub trait Filesystem {
@@ -154,13 +335,22 @@ pub trait Filesystem {
     // serially, but we don't want to rely on that behavior.  so we don't let our Filesystem
     // implementors mutate.  if you need mutation, use interior mutation and use locking.
 
-    fn init_request_id(&self);
+    /// Called at the start of every fuse callback, returning an id for the request that its log lines can carry
+    fn init_request_id(&self) -> usize;
+
+    /// Called at the end of every fuse callback with how long it took and what it returned to fuse, which is negative
+    /// for an error.  Does nothing by default.
+    fn op_done(&self, _op: &'static str, _elapsed: Duration, _result: c_int) {}
 
     fn getattr(&self, req: &Request, path: &Path) -> FuseResult<stat>;
+    /// Lists the entries of `path` after the common ones, skipping the first `offset` of them.  The iterator may end
+    /// before the listing does, as long as it yields something: readdir is called again from where it left off, until
+    /// it yields nothing.
     fn readdir(
         &self,
         req: &Request,
         path: &Path,
+        offset: u64,
     ) -> FuseResult<Box<dyn Iterator<Item = FileEntry>>>;
     fn readdir_common(
         &self,
@@ -221,6 +411,20 @@ pub trait Filesystem {
         }
     }
 
+    /// Returns a descriptor that a read of `size` bytes at `offset` can be served from directly, so that fuse can
+    /// splice it to the reader without copying it through `read`.  The default returns None, which falls back to
+    /// `read`, so only return a descriptor if `read` would have read the same bytes from it.
+    fn read_buf(
+        &self,
+        _req: &Request,
+        _path: &Path,
+        _size: usize,
+        _offset: off_t,
+        _fi: *const fuse_file_info,
+    ) -> FuseResult<Option<RawFd>> {
+        Ok(None)
+    }
+
     fn write(
         &self,
         _req: &Request,
@@ -255,6 +459,22 @@ pub trait Filesystem {
         }
     }
 
+    /// Writes a buffer vector that fuse may not have copied into memory yet.  The default copies it into memory and
+    /// hands it to `write`.  Implementors that write straight to a file can use `write_bufvec_to_fd` instead, which
+    /// avoids the copy.
+    fn write_buf(
+        &self,
+        req: &Request,
+        path: &Path,
+        buf: *mut fuse_bufvec,
+        offset: off_t,
+        fi: *const fuse_file_info,
+    ) -> FuseResult<usize> {
+        debug!(target: FS_TAG, "Calling default write_buf implementation");
+        let data = bufvec_to_vec(buf)?;
+        self.write(req, path, &data, offset, fi)
+    }
+
     fn flush(&self, _req: &Request, _path: &Path, fi: *const fuse_file_info) -> FuseResult<()> {
         unsafe {
             info!(
@@ -275,6 +495,25 @@ pub trait Filesystem {
         Err(ENOSYS.into())
     }
 
+    /// Allocates `len` bytes at `offset` of an open file, so that a large copy can reserve its space up front.  On
+    /// linux, `mode` takes the `FALLOC_FL_*` flags, so it can also punch holes to keep a file sparse.
+    fn fallocate(
+        &self,
+        _req: &Request,
+        _path: &Path,
+        mode: i32,
+        offset: off_t,
+        len: off_t,
+        fi: *const fuse_file_info,
+    ) -> FuseResult<()> {
+        let handle = unsafe { (*fi).fh };
+        info!(
+            target: FS_TAG,
+            "Calling default fallocate implementation on {}", handle
+        );
+        fallocate_fd_range(handle as RawFd, mode, offset, len)
+    }
+
     fn fsync(
         &self,
         _req: &Request,
@@ -322,8 +561,44 @@ pub trait Filesystem {
     fn unlink(&self, req: &Request, path: &Path) -> FuseResult<()>;
     fn mkdir(&self, req: &Request, path: &Path, mode: mode_t) -> FuseResult<()>;
     fn rename(&self, req: &Request, src: &Path, dst: &Path) -> FuseResult<()>;
+
+    /// Swaps `a` and `b` in one step, for renameat2's RENAME_EXCHANGE with libfuse 3, and exchangedata on macos.
+    /// Unsupported by default.
+    fn exchange(&self, _req: &Request, a: &Path, b: &Path) -> FuseResult<()> {
+        info!(
+            target: FS_TAG,
+            "Calling default exchange on {} and {}",
+            a.display(),
+            b.display()
+        );
+        Err(EINVAL.into())
+    }
+
     fn statfs(&self, req: &Request, path: &Path) -> FuseResult<statvfs>;
 
+    /// The 64-bit statfs that macos asks for when it's available, so that volumes too big for `statvfs`'s 32-bit block
+    /// counts report their capacity correctly.  The default fills it in from `statfs`.
+    #[cfg(target_os = "macos")]
+    fn statfs_x(&self, req: &Request, path: &Path, stbuf: &mut statfs) -> FuseResult<()> {
+        debug!(target: FS_TAG, "Calling default statfs_x implementation");
+        let vfs = self.statfs(req, path)?;
+        stbuf.f_bsize = vfs.f_frsize as u32;
+        stbuf.f_iosize = vfs.f_bsize as i32;
+        stbuf.f_blocks = vfs.f_blocks as u64;
+        stbuf.f_bfree = vfs.f_bfree as u64;
+        stbuf.f_bavail = vfs.f_bavail as u64;
+        stbuf.f_files = vfs.f_files as u64;
+        stbuf.f_ffree = vfs.f_ffree as u64;
+        Ok(())
+    }
+
+    /// Called when the volume is renamed, like from Finder.  Unsupported by default.
+    #[cfg(target_os = "macos")]
+    fn setvolname(&self, _req: &Request, name: &str) -> FuseResult<()> {
+        info!(target: FS_TAG, "Calling default setvolname on {}", name);
+        Err(ENOSYS.into())
+    }
+
     fn set_handle(&mut self, _handle: Arc<FuseHandle>) {}
 
     fn chmod(&self, _req: &Request, path: &Path, mode: mode_t) -> FuseResult<()> {
@@ -420,8 +695,8 @@ fn to_pathname(ptr: *const c_char) -> PathBuf {
     path.to_owned()
 }
 
-/// Get the Filesystem trait object that we passed into mount
-fn ops_from_ctx() -> (Request, &'static dyn Filesystem) {
+/// Get the Filesystem trait object that we passed into mount, along with the id it gave this request
+fn ops_from_ctx() -> (Request, &'static dyn Filesystem, usize) {
     unsafe {
         let ctx = fuse_get_context();
 
@@ -444,68 +719,113 @@ fn ops_from_ctx() -> (Request, &'static dyn Filesystem) {
         // See the comment in the mount() function for more information on exactly what is happening
         let boxed = (*ctx).private_data as *const &dyn Filesystem;
         let fs_trait_ref = *boxed;
-        fs_trait_ref.init_request_id();
-        (req, fs_trait_ref)
+        let req_id = fs_trait_ref.init_request_id();
+        (req, fs_trait_ref, req_id)
     }
 }
 
+/// Runs a fuse callback inside a span carrying the request and the path it's for, and records on the span how long the
+/// callback took and what it returned to fuse
+fn fuse_op<F>(op: &'static str, path: &Path, callback: F) -> c_int
+where
+    F: FnOnce(&Request, &'static dyn Filesystem) -> c_int,
+{
+    let (req, ops, req_id) = ops_from_ctx();
+    let span = info_span!(
+        target: FUSEOP_TAG,
+        "fuse_op",
+        op,
+        req_id,
+        uid = req.uid,
+        pid = req.pid,
+        path = %path.display(),
+        duration_us = field::Empty,
+        result = field::Empty,
+    );
+    span.in_scope(|| {
+        let start = Instant::now();
+        let res = callback(&req, ops);
+        let elapsed = start.elapsed();
+        span.record("duration_us", &(elapsed.as_micros() as u64));
+        span.record("result", &res);
+        ops.op_done(op, elapsed, res);
+        res
+    })
+}
+
 extern "C" fn readdir(
     arg1: *const ::std::os::raw::c_char,
     arg2: *mut ::std::os::raw::c_void,
     arg3: fuse_fill_dir_t,
     offset: off_t,
     _arg5: *mut fuse_file_info,
+    #[cfg(fuse3)] flags: fuse_readdir_flags,
 ) -> ::std::os::raw::c_int {
     let name = to_pathname(arg1);
+    fuse_op("readdir", &name, |req, ops| {
+        let filler = arg3.unwrap();
 
-    let filler = arg3.unwrap();
-    let (req, ops) = ops_from_ctx();
+        info!(target: FUSEOP_TAG, "readdir {:?} at offset {}", name, offset);
+
+        // libfuse 3 can ask for the attributes of each entry along with its name, but we don't have them without
+        // looking each one up, so we leave them out and the kernel falls back to looking up the entries it needs
+        #[cfg(fuse3)]
+        {
+            if flags & fuse_readdir_flags_FUSE_READDIR_PLUS != 0 {
+                debug!(target: FUSEOP_TAG, "readdirplus requested, filling names only");
+            }
+        }
 
-    info!(target: FUSEOP_TAG, "readdir {:?}", name);
+        // every entry is given its position in the listing, plus one, as its offset.  this puts libfuse in its
+        // offset mode, where it only asks for as many entries as fit in the kernel's buffer, and then asks again,
+        // starting from the offset of the last entry it kept.  so a huge directory is never held in memory all at once
+        let offset = offset as u64;
+        let common: Vec<FileEntry> = match ops.readdir_common(req, &name) {
+            Ok(entry_iter) => entry_iter.collect(),
+            Err(num) => {
+                error!(target: FUSEOP_TAG, "Error getting readdir_common {}", num);
+                return num.into();
+            }
+        };
+        let num_common = common.len() as u64;
 
-    if offset == 0 {
-        match ops.readdir_common(&req, &name) {
+        match ops.readdir(req, &name, offset.saturating_sub(num_common)) {
             Ok(entry_iter) => {
-                for entry in entry_iter {
+                let mut next_offset = offset;
+                for entry in common.into_iter().skip(offset as usize).chain(entry_iter) {
+                    next_offset += 1;
                     let entry_name = CString::new(entry.name).unwrap();
-                    let done = unsafe { filler(arg2, entry_name.as_ptr(), ptr::null(), 0) };
-
-                    // this should never happen while we're filling our common directories, since
-                    // there should only be a few, and the fill buffer is supposedly large, but
-                    // let's handle it anyways
+                    #[cfg(not(fuse3))]
+                    let done = unsafe {
+                        filler(arg2, entry_name.as_ptr(), ptr::null(), next_offset as off_t)
+                    };
+                    #[cfg(fuse3)]
+                    let done = unsafe {
+                        filler(
+                            arg2,
+                            entry_name.as_ptr(),
+                            ptr::null(),
+                            next_offset as off_t,
+                            0,
+                        )
+                    };
                     if done > 0 {
-                        return 0;
+                        break;
                     }
                 }
+                0
             }
             Err(num) => {
-                error!(target: FUSEOP_TAG, "Error getting readdir_common {}", num);
-                return num.into();
-            }
-        }
-    }
-
-    match ops.readdir(&req, &name) {
-        Ok(entry_iter) => {
-            for entry in entry_iter {
-                let entry_name = CString::new(entry.name).unwrap();
-                let done = unsafe { filler(arg2, entry_name.as_ptr(), ptr::null(), 0) };
-                if done > 0 {
-                    break;
-                }
+                error!(
+                    target: FUSEOP_TAG,
+                    "readdir error {} for {}",
+                    num,
+                    name.display()
+                );
+                num.into()
             }
-            0
         }
-        Err(num) => {
-            error!(
-                target: FUSEOP_TAG,
-                "readdir error {} for {}",
-                num,
-                name.display()
-            );
-            num.into()
-        }
-    }
+    })
 }
 
 extern "C" fn opendir(
@@ -532,23 +852,24 @@ extern "C" fn readlink(
     _arg3: usize,
 ) -> ::std::os::raw::c_int {
     let name = to_pathname(arg1);
-    let (req, ops) = ops_from_ctx();
-    info!(target: FUSEOP_TAG, "readlink {:?}", name);
-
-    match ops.readlink(&req, &name) {
-        Ok(link_path) => {
-            // FIXME can fail if path as an interior null byte
-            let link_str = CString::new(link_path.as_os_str().as_bytes()).unwrap();
-            unsafe {
-                ptr::copy(link_str.as_ptr(), arg2, link_str.as_bytes_with_nul().len());
-            };
-            0
-        }
-        Err(num) => {
-            error!(target: FUSEOP_TAG, "readlink error {}", num);
-            num.into()
+    fuse_op("readlink", &name, |req, ops| {
+        info!(target: FUSEOP_TAG, "readlink {:?}", name);
+
+        match ops.readlink(req, &name) {
+            Ok(link_path) => {
+                // FIXME can fail if path as an interior null byte
+                let link_str = CString::new(link_path.as_os_str().as_bytes()).unwrap();
+                unsafe {
+                    ptr::copy(link_str.as_ptr(), arg2, link_str.as_bytes_with_nul().len());
+                };
+                0
+            }
+            Err(num) => {
+                error!(target: FUSEOP_TAG, "readlink error {}", num);
+                num.into()
+            }
         }
-    }
+    })
 }
 
 extern "C" fn flush(
@@ -556,45 +877,48 @@ extern "C" fn flush(
     arg2: *mut fuse_file_info,
 ) -> ::std::os::raw::c_int {
     let name = to_pathname(arg1);
-    let (req, ops) = ops_from_ctx();
-    info!(target: FUSEOP_TAG, "flush {:?}", name);
+    fuse_op("flush", &name, |req, ops| {
+        info!(target: FUSEOP_TAG, "flush {:?}", name);
 
-    match ops.flush(&req, &name, arg2) {
-        Ok(_) => 0,
-        Err(num) => {
-            error!(target: FUSEOP_TAG, "flush error {}", num,);
-            num.into()
+        match ops.flush(req, &name, arg2) {
+            Ok(_) => 0,
+            Err(num) => {
+                error!(target: FUSEOP_TAG, "flush error {}", num,);
+                num.into()
+            }
         }
-    }
+    })
 }
 
 extern "C" fn getattr(
     arg1: *const ::std::os::raw::c_char,
     arg2: *mut stat,
+    #[cfg(fuse3)] _arg3: *mut fuse_file_info,
 ) -> ::std::os::raw::c_int {
     let name = to_pathname(arg1);
-    let (req, ops) = ops_from_ctx();
-    info!(target: FUSEOP_TAG, "getattr {:?}", name);
-
-    let maybe_file_stat = ops.getattr(&req, &name);
-    match maybe_file_stat {
-        Ok(file_stat) => {
-            debug!(target: FUSEOP_TAG, "stat for {:?} is {:?}", name, file_stat);
-            unsafe {
-                let attr = &mut *arg2;
-                *attr = file_stat;
+    fuse_op("getattr", &name, |req, ops| {
+        info!(target: FUSEOP_TAG, "getattr {:?}", name);
+
+        let maybe_file_stat = ops.getattr(req, &name);
+        match maybe_file_stat {
+            Ok(file_stat) => {
+                debug!(target: FUSEOP_TAG, "stat for {:?} is {:?}", name, file_stat);
+                unsafe {
+                    let attr = &mut *arg2;
+                    *attr = file_stat;
+                }
+                0
             }
-            0
-        }
-        Err(num) => {
-            if num.errno == ENOENT {
-                warn!(target: FUSEOP_TAG, "getattr ENOENT for {:?}", name);
-            } else {
-                error!(target: FUSEOP_TAG, "getattr error {:?} for {:?}", num, name);
+            Err(num) => {
+                if num.errno == ENOENT {
+                    warn!(target: FUSEOP_TAG, "getattr ENOENT for {:?}", name);
+                } else {
+                    error!(target: FUSEOP_TAG, "getattr error {:?} for {:?}", num, name);
+                }
+                num.into()
             }
-            num.into()
         }
-    }
+    })
 }
 
 extern "C" fn symlink(
@@ -602,105 +926,158 @@ extern "C" fn symlink(
     arg2: *const ::std::os::raw::c_char,
 ) -> ::std::os::raw::c_int {
     let src = to_pathname(arg1);
-    let dst = to_pathname(arg2);
-    let (req, ops) = ops_from_ctx();
-    info!(target: FUSEOP_TAG, "symlink {:?} to {:?}", src, dst);
+    fuse_op("symlink", &src, |req, ops| {
+        let dst = to_pathname(arg2);
+        info!(target: FUSEOP_TAG, "symlink {:?} to {:?}", src, dst);
 
-    match ops.symlink(&req, &src, &dst) {
-        Ok(_) => 0,
-        Err(num) => {
-            error!(
-                target: FUSEOP_TAG,
-                "symlink error {} for {} => {}",
-                num,
-                src.display(),
-                dst.display()
-            );
-            num.into()
+        match ops.symlink(req, &src, &dst) {
+            Ok(_) => 0,
+            Err(num) => {
+                error!(
+                    target: FUSEOP_TAG,
+                    "symlink error {} for {} => {}",
+                    num,
+                    src.display(),
+                    dst.display()
+                );
+                num.into()
+            }
         }
-    }
+    })
 }
 
 extern "C" fn rmdir(arg1: *const ::std::os::raw::c_char) -> ::std::os::raw::c_int {
-    let (req, ops) = ops_from_ctx();
     let name = to_pathname(arg1);
-    info!(target: FUSEOP_TAG, "rmdir {:?}", name);
+    fuse_op("rmdir", &name, |req, ops| {
+        info!(target: FUSEOP_TAG, "rmdir {:?}", name);
 
-    match ops.rmdir(&req, &name) {
-        Ok(_) => 0,
-        Err(num) => {
-            error!(
-                target: FUSEOP_TAG,
-                "rmdir error {} for {}",
-                num,
-                name.display()
-            );
-            num.into()
+        match ops.rmdir(req, &name) {
+            Ok(_) => 0,
+            Err(num) => {
+                error!(
+                    target: FUSEOP_TAG,
+                    "rmdir error {} for {}",
+                    num,
+                    name.display()
+                );
+                num.into()
+            }
         }
-    }
+    })
 }
 
 extern "C" fn unlink(arg1: *const ::std::os::raw::c_char) -> ::std::os::raw::c_int {
-    let (req, ops) = ops_from_ctx();
     let name = to_pathname(arg1);
-    info!(target: FUSEOP_TAG, "unlink {:?}", name);
+    fuse_op("unlink", &name, |req, ops| {
+        info!(target: FUSEOP_TAG, "unlink {:?}", name);
 
-    match ops.unlink(&req, &name) {
-        Ok(_) => 0,
-        Err(num) => {
-            error!(
-                target: FUSEOP_TAG,
-                "unlink error {} for {}",
-                num,
-                name.display()
-            );
-            num.into()
+        match ops.unlink(req, &name) {
+            Ok(_) => 0,
+            Err(num) => {
+                error!(
+                    target: FUSEOP_TAG,
+                    "unlink error {} for {}",
+                    num,
+                    name.display()
+                );
+                num.into()
+            }
         }
-    }
+    })
 }
 
 extern "C" fn mkdir(arg1: *const ::std::os::raw::c_char, arg2: mode_t) -> ::std::os::raw::c_int {
-    let (req, ops) = ops_from_ctx();
     let name = to_pathname(arg1);
-    info!(target: FUSEOP_TAG, "mkdir {:?}", name);
+    fuse_op("mkdir", &name, |req, ops| {
+        info!(target: FUSEOP_TAG, "mkdir {:?}", name);
 
-    match ops.mkdir(&req, &name, arg2) {
-        Ok(_) => 0,
-        Err(num) => {
-            error!(
-                target: FUSEOP_TAG,
-                "mkdir error {} for {}",
-                num,
-                name.display()
-            );
-            num.into()
+        match ops.mkdir(req, &name, arg2) {
+            Ok(_) => 0,
+            Err(num) => {
+                error!(
+                    target: FUSEOP_TAG,
+                    "mkdir error {} for {}",
+                    num,
+                    name.display()
+                );
+                num.into()
+            }
         }
-    }
+    })
 }
 
 extern "C" fn rename(
     arg1: *const ::std::os::raw::c_char,
     arg2: *const ::std::os::raw::c_char,
+    #[cfg(fuse3)] flags: ::std::os::raw::c_uint,
 ) -> ::std::os::raw::c_int {
-    let (req, ops) = ops_from_ctx();
     let src = to_pathname(arg1);
-    let dst = to_pathname(arg2);
-    info!(target: FUSEOP_TAG, "rename {:?} to {:?}", src, dst);
+    fuse_op("rename", &src, |req, ops| {
+        let dst = to_pathname(arg2);
+        info!(target: FUSEOP_TAG, "rename {:?} to {:?}", src, dst);
+
+        // libfuse 3 passes through the flags of renameat2.  swapping two paths is its own operation, and not replacing
+        // the destination only needs it to be missing
+        #[cfg(fuse3)]
+        {
+            if flags & libc::RENAME_EXCHANGE != 0 {
+                return exchange_paths(req, ops, &src, &dst);
+            }
+            if flags & libc::RENAME_NOREPLACE != 0 && ops.getattr(req, &dst).is_ok() {
+                return FuseErrno::from(EEXIST).into();
+            }
+        }
 
-    match ops.rename(&req, &src, &dst) {
+        match ops.rename(req, &src, &dst) {
+            Ok(_) => 0,
+            Err(num) => {
+                error!(
+                    target: FUSEOP_TAG,
+                    "rename error {} for {}",
+                    num,
+                    src.display()
+                );
+                num.into()
+            }
+        }
+    })
+}
+
+/// The one place that exchanges happen, whether they arrived as a flag on rename or as their own operation
+#[cfg(any(fuse3, target_os = "macos"))]
+fn exchange_paths(
+    req: &Request,
+    ops: &dyn Filesystem,
+    a: &Path,
+    b: &Path,
+) -> ::std::os::raw::c_int {
+    info!(target: FUSEOP_TAG, "exchange {:?} and {:?}", a, b);
+    match ops.exchange(req, a, b) {
         Ok(_) => 0,
         Err(num) => {
             error!(
                 target: FUSEOP_TAG,
-                "rename error {} for {}",
+                "exchange error {} for {}",
                 num,
-                src.display()
+                a.display()
             );
             num.into()
         }
     }
 }
 
+#[cfg(target_os = "macos")]
+extern "C" fn exchange(
+    arg1: *const ::std::os::raw::c_char,
+    arg2: *const ::std::os::raw::c_char,
+    _options: ::std::os::raw::c_ulong,
+) -> ::std::os::raw::c_int {
+    let a = to_pathname(arg1);
+    fuse_op("exchange", &a, |req, ops| {
+        exchange_paths(req, ops, &a, &to_pathname(arg2))
+    })
+}
+
 extern "C" fn write(
     arg1: *const ::std::os::raw::c_char,
     arg2: *const ::std::os::raw::c_char,
@@ -708,27 +1085,28 @@ extern "C" fn write(
     arg4: off_t,
     arg5: *mut fuse_file_info,
 ) -> ::std::os::raw::c_int {
-    let (req, ops) = ops_from_ctx();
     let name = to_pathname(arg1);
-    info!(
-        target: FUSEOP_TAG,
-        "write {} bytes to {:?} at offset {}", arg3, name, arg4
-    );
+    fuse_op("write", &name, |req, ops| {
+        info!(
+            target: FUSEOP_TAG,
+            "write {} bytes to {:?} at offset {}", arg3, name, arg4
+        );
 
-    let data = unsafe {
-        let tmp_slice = std::slice::from_raw_parts(arg2, arg3);
-        &*(tmp_slice as *const _ as *const [u8])
-    };
-    match ops.write(&req, &name, data, arg4, arg5) {
-        Ok(written) => {
-            debug!(target: FUSEOP_TAG, "wrote {} bytes", written);
-            written as i32
-        }
-        Err(num) => {
-            error!(target: FUSEOP_TAG, "write error {}", num,);
-            num.into()
+        let data = unsafe {
+            let tmp_slice = std::slice::from_raw_parts(arg2, arg3);
+            &*(tmp_slice as *const _ as *const [u8])
+        };
+        match ops.write(req, &name, data, arg4, arg5) {
+            Ok(written) => {
+                debug!(target: FUSEOP_TAG, "wrote {} bytes", written);
+                written as i32
+            }
+            Err(num) => {
+                error!(target: FUSEOP_TAG, "write error {}", num,);
+                num.into()
+            }
         }
-    }
+    })
 }
 
 extern "C" fn fsync(
@@ -736,91 +1114,99 @@ extern "C" fn fsync(
     arg2: ::std::os::raw::c_int,
     arg3: *mut fuse_file_info,
 ) -> ::std::os::raw::c_int {
-    let (req, ops) = ops_from_ctx();
     let name = to_pathname(arg1);
-    info!(target: FUSEOP_TAG, "fsync {:?}", name);
+    fuse_op("fsync", &name, |req, ops| {
+        info!(target: FUSEOP_TAG, "fsync {:?}", name);
 
-    match ops.fsync(&req, &name, arg2, arg3) {
-        Ok(_) => 0,
-        Err(num) => {
-            error!(
-                target: FUSEOP_TAG,
-                "fsync error {} for {}",
-                num,
-                name.display()
-            );
-            num.into()
+        match ops.fsync(req, &name, arg2, arg3) {
+            Ok(_) => 0,
+            Err(num) => {
+                error!(
+                    target: FUSEOP_TAG,
+                    "fsync error {} for {}",
+                    num,
+                    name.display()
+                );
+                num.into()
+            }
         }
-    }
+    })
 }
 
-extern "C" fn truncate(arg1: *const ::std::os::raw::c_char, arg2: off_t) -> ::std::os::raw::c_int {
-    let (req, ops) = ops_from_ctx();
+extern "C" fn truncate(
+    arg1: *const ::std::os::raw::c_char,
+    arg2: off_t,
+    #[cfg(fuse3)] _arg3: *mut fuse_file_info,
+) -> ::std::os::raw::c_int {
     let name = to_pathname(arg1);
-    info!(target: FUSEOP_TAG, "truncate {:?}", name);
+    fuse_op("truncate", &name, |req, ops| {
+        info!(target: FUSEOP_TAG, "truncate {:?}", name);
 
-    match ops.truncate(&req, &name, arg2) {
-        Ok(_) => 0,
-        Err(num) => {
-            error!(
-                target: FUSEOP_TAG,
-                "truncate error {} for {}",
-                num,
-                name.display()
-            );
-            num.into()
+        match ops.truncate(req, &name, arg2) {
+            Ok(_) => 0,
+            Err(num) => {
+                error!(
+                    target: FUSEOP_TAG,
+                    "truncate error {} for {}",
+                    num,
+                    name.display()
+                );
+                num.into()
+            }
         }
-    }
+    })
 }
 
 extern "C" fn release(
     arg1: *const ::std::os::raw::c_char,
     arg2: *mut fuse_file_info,
 ) -> ::std::os::raw::c_int {
-    let (req, ops) = ops_from_ctx();
     let name = to_pathname(arg1);
-    info!(target: FUSEOP_TAG, "release {:?}", name);
+    fuse_op("release", &name, |req, ops| {
+        info!(target: FUSEOP_TAG, "release {:?}", name);
 
-    match ops.release(&req, &name, arg2) {
-        Ok(_) => 0,
-        Err(num) => {
-            error!(
-                target: FUSEOP_TAG,
-                "release error {} for {}",
-                num,
-                name.display()
-            );
-            num.into()
+        match ops.release(req, &name, arg2) {
+            Ok(_) => 0,
+            Err(num) => {
+                error!(
+                    target: FUSEOP_TAG,
+                    "release error {} for {}",
+                    num,
+                    name.display()
+                );
+                num.into()
+            }
         }
-    }
+    })
 }
 
 extern "C" fn open(
     arg1: *const ::std::os::raw::c_char,
     arg2: *mut fuse_file_info,
 ) -> ::std::os::raw::c_int {
-    let (req, ops) = ops_from_ctx();
     let name = to_pathname(arg1);
-    info!(target: FUSEOP_TAG, "open {:?}", name);
-
-    match ops.open(&req, &name, arg2) {
-        Ok(fd) => {
-            unsafe {
-                (*arg2).fh = fd as u64;
-                debug!(target: FUSEOP_TAG, "open made fd {}", fd);
+    fuse_op("open", &name, |req, ops| {
+        info!(target: FUSEOP_TAG, "open {:?}", name);
+
+        match ops.open(req, &name, arg2) {
+            Ok(fd) => {
+                unsafe {
+                    (*arg2).fh = fd as u64;
+                    debug!(target: FUSEOP_TAG, "open made fd {}", fd);
+                }
+                0
+            }
+            Err(num) => {
+                error!(
+                    target: FUSEOP_TAG,
+                    "open error {} for {}",
+                    num,
+                    name.display()
+                );
+                num.into()
             }
-            0
-        }
-        Err(num) => {
-            error!(
-                target: FUSEOP_TAG,
-                "open error {} for {}",
-                num,
-                name.display()
-            );
-            num.into()
         }
-    }
+    })
 }
 
 extern "C" fn create(
@@ -828,134 +1214,324 @@ extern "C" fn create(
     mode: mode_t,
     arg3: *mut fuse_file_info,
 ) -> ::std::os::raw::c_int {
-    let (req, ops) = ops_from_ctx();
     let name = to_pathname(arg1);
-    info!(target: FUSEOP_TAG, "create {:?} with mode {}", name, mode);
+    fuse_op("create", &name, |req, ops| {
+        info!(target: FUSEOP_TAG, "create {:?} with mode {}", name, mode);
+
+        match ops.create(req, &name, mode) {
+            Ok(fd) => {
+                unsafe {
+                    (*arg3).fh = fd as u64;
+                    debug!(target: FUSEOP_TAG, "create made fd {}", (*arg3).fh);
+                }
+                0
+            }
+            Err(num) => {
+                error!(
+                    target: FUSEOP_TAG,
+                    "create error {} for {}",
+                    num,
+                    name.display()
+                );
+                num.into()
+            }
+        }
+    })
+}
 
-    match ops.create(&req, &name, mode) {
-        Ok(fd) => {
-            unsafe {
-                (*arg3).fh = fd as u64;
-                debug!(target: FUSEOP_TAG, "create made fd {}", (*arg3).fh);
+extern "C" fn read(
+    arg1: *const ::std::os::raw::c_char,
+    arg2: *mut ::std::os::raw::c_char,
+    arg3: usize,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> ::std::os::raw::c_int {
+    let name = to_pathname(arg1);
+    fuse_op("read", &name, |req, ops| {
+        info!(
+            target: FUSEOP_TAG,
+            "read desired {} bytes at offset {} for {:?} ", arg3, arg4, name
+        );
+
+        let buf = unsafe {
+            let tmp_slice = std::slice::from_raw_parts(arg2, arg3);
+            &mut *(tmp_slice as *const _ as *mut [u8])
+        };
+
+        match ops.read(req, &name, buf, arg4, arg5) {
+            Ok(read) => {
+                debug!(target: FUSEOP_TAG, "read {} bytes", read);
+                read as i32
+            }
+            Err(num) => {
+                error!(
+                    target: FUSEOP_TAG,
+                    "read error {} for {}",
+                    num,
+                    name.display()
+                );
+                num.into()
             }
-            0
         }
-        Err(num) => {
-            error!(
-                target: FUSEOP_TAG,
-                "create error {} for {}",
-                num,
-                name.display()
-            );
-            num.into()
+    })
+}
+
+extern "C" fn read_buf(
+    arg1: *const ::std::os::raw::c_char,
+    bufp: *mut *mut fuse_bufvec,
+    size: usize,
+    off: off_t,
+    arg2: *mut fuse_file_info,
+) -> ::std::os::raw::c_int {
+    let name = to_pathname(arg1);
+    fuse_op("read_buf", &name, |req, ops| {
+        info!(
+            target: FUSEOP_TAG,
+            "read_buf desired {} bytes at offset {} for {:?} ", size, off, name
+        );
+
+        // fuse frees the buffer vector, and any memory in it, with free(), so they have to come from malloc()
+        let bufv = unsafe { libc::malloc(size_of::<fuse_bufvec>()) as *mut fuse_bufvec };
+        if bufv.is_null() {
+            return -libc::ENOMEM;
         }
-    }
+        let buf = match ops.read_buf(req, &name, size, off, arg2) {
+            Ok(Some(fd)) => {
+                debug!(target: FUSEOP_TAG, "read_buf from fd {}", fd);
+                fuse_buf {
+                    size,
+                    flags: fuse_buf_flags_FUSE_BUF_IS_FD | fuse_buf_flags_FUSE_BUF_FD_SEEK,
+                    mem: ptr::null_mut(),
+                    fd,
+                    pos: off,
+                }
+            }
+            Ok(None) => {
+                let mem = unsafe { libc::malloc(size.max(1)) };
+                if mem.is_null() {
+                    unsafe { libc::free(bufv as *mut c_void) };
+                    return -libc::ENOMEM;
+                }
+                let data = unsafe { std::slice::from_raw_parts_mut(mem as *mut u8, size) };
+                match ops.read(req, &name, data, off, arg2) {
+                    Ok(read) => {
+                        debug!(target: FUSEOP_TAG, "read_buf read {} bytes", read);
+                        fuse_buf {
+                            size: read,
+                            flags: 0,
+                            mem,
+                            fd: -1,
+                            pos: 0,
+                        }
+                    }
+                    Err(num) => {
+                        unsafe {
+                            libc::free(mem);
+                            libc::free(bufv as *mut c_void);
+                        }
+                        error!(
+                            target: FUSEOP_TAG,
+                            "read_buf error {} for {}",
+                            num,
+                            name.display()
+                        );
+                        return num.into();
+                    }
+                }
+            }
+            Err(num) => {
+                unsafe { libc::free(bufv as *mut c_void) };
+                error!(
+                    target: FUSEOP_TAG,
+                    "read_buf error {} for {}",
+                    num,
+                    name.display()
+                );
+                return num.into();
+            }
+        };
+
+        unsafe {
+            *bufv = fuse_bufvec {
+                count: 1,
+                idx: 0,
+                off: 0,
+                buf: [buf],
+            };
+            *bufp = bufv;
+        }
+        0
+    })
 }
 
-extern "C" fn read(
+extern "C" fn write_buf(
+    arg1: *const ::std::os::raw::c_char,
+    buf: *mut fuse_bufvec,
+    off: off_t,
+    arg2: *mut fuse_file_info,
+) -> ::std::os::raw::c_int {
+    let name = to_pathname(arg1);
+    fuse_op("write_buf", &name, |req, ops| {
+        info!(target: FUSEOP_TAG, "write_buf to {:?} at offset {}", name, off);
+
+        match ops.write_buf(req, &name, buf, off, arg2) {
+            Ok(written) => {
+                debug!(target: FUSEOP_TAG, "wrote {} bytes", written);
+                written as i32
+            }
+            Err(num) => {
+                error!(target: FUSEOP_TAG, "write_buf error {}", num,);
+                num.into()
+            }
+        }
+    })
+}
+
+extern "C" fn fallocate(
     arg1: *const ::std::os::raw::c_char,
-    arg2: *mut ::std::os::raw::c_char,
-    arg3: usize,
+    arg2: ::std::os::raw::c_int,
+    arg3: off_t,
     arg4: off_t,
     arg5: *mut fuse_file_info,
 ) -> ::std::os::raw::c_int {
-    let (req, ops) = ops_from_ctx();
     let name = to_pathname(arg1);
-    info!(
-        target: FUSEOP_TAG,
-        "read desired {} bytes at offset {} for {:?} ", arg3, arg4, name
-    );
-
-    let buf = unsafe {
-        let tmp_slice = std::slice::from_raw_parts(arg2, arg3);
-        &mut *(tmp_slice as *const _ as *mut [u8])
-    };
+    fuse_op("fallocate", &name, |req, ops| {
+        info!(
+            target: FUSEOP_TAG,
+            "fallocate {} bytes at offset {} for {:?} with mode {}", arg4, arg3, name, arg2
+        );
 
-    match ops.read(&req, &name, buf, arg4, arg5) {
-        Ok(read) => {
-            debug!(target: FUSEOP_TAG, "read {} bytes", read);
-            read as i32
-        }
-        Err(num) => {
-            error!(
-                target: FUSEOP_TAG,
-                "read error {} for {}",
-                num,
-                name.display()
-            );
-            num.into()
+        match ops.fallocate(req, &name, arg2, arg3, arg4, arg5) {
+            Ok(_) => 0,
+            Err(num) => {
+                error!(
+                    target: FUSEOP_TAG,
+                    "fallocate error {} for {}",
+                    num,
+                    name.display()
+                );
+                num.into()
+            }
         }
-    }
+    })
 }
 
 extern "C" fn statfs(
     arg1: *const ::std::os::raw::c_char,
     arg2: *mut statvfs,
 ) -> ::std::os::raw::c_int {
-    let (req, ops) = ops_from_ctx();
     let name = to_pathname(arg1);
-    info!(target: FUSEOP_TAG, "statfs {:?}", name);
+    fuse_op("statfs", &name, |req, ops| {
+        info!(target: FUSEOP_TAG, "statfs {:?}", name);
+
+        match ops.statfs(req, &name) {
+            Ok(data) => unsafe {
+                *arg2 = data;
+                0
+            },
+            Err(num) => {
+                error!(
+                    target: FUSEOP_TAG,
+                    "statfs error {} for {}",
+                    num,
+                    name.display()
+                );
+                num.into()
+            }
+        }
+    })
+}
 
-    match ops.statfs(&req, &name) {
-        Ok(data) => unsafe {
-            *arg2 = data;
-            0
-        },
-        Err(num) => {
-            error!(
-                target: FUSEOP_TAG,
-                "statfs error {} for {}",
-                num,
-                name.display()
-            );
-            num.into()
+#[cfg(target_os = "macos")]
+extern "C" fn statfs_x(
+    arg1: *const ::std::os::raw::c_char,
+    arg2: *mut statfs,
+) -> ::std::os::raw::c_int {
+    let name = to_pathname(arg1);
+    fuse_op("statfs_x", &name, |req, ops| {
+        info!(target: FUSEOP_TAG, "statfs_x {:?}", name);
+
+        match ops.statfs_x(req, &name, unsafe { &mut *arg2 }) {
+            Ok(_) => 0,
+            Err(num) => {
+                error!(
+                    target: FUSEOP_TAG,
+                    "statfs_x error {} for {}",
+                    num,
+                    name.display()
+                );
+                num.into()
+            }
         }
-    }
+    })
+}
+
+#[cfg(target_os = "macos")]
+extern "C" fn setvolname(arg1: *const ::std::os::raw::c_char) -> ::std::os::raw::c_int {
+    let name = unsafe { CStr::from_ptr(arg1) }.to_string_lossy();
+    fuse_op("setvolname", Path::new(name.as_ref()), |req, ops| {
+        info!(target: FUSEOP_TAG, "setvolname {:?}", name);
+
+        match ops.setvolname(req, &name) {
+            Ok(_) => 0,
+            Err(num) => {
+                error!(target: FUSEOP_TAG, "setvolname error {} for {}", num, name);
+                num.into()
+            }
+        }
+    })
 }
 
-extern "C" fn chmod(arg1: *const ::std::os::raw::c_char, mode: mode_t) -> ::std::os::raw::c_int {
-    let (req, ops) = ops_from_ctx();
+extern "C" fn chmod(
+    arg1: *const ::std::os::raw::c_char,
+    mode: mode_t,
+    #[cfg(fuse3)] _arg3: *mut fuse_file_info,
+) -> ::std::os::raw::c_int {
     let name = to_pathname(arg1);
-    info!(target: FUSEOP_TAG, "chmod {:?} with mode {}", name, mode);
+    fuse_op("chmod", &name, |req, ops| {
+        info!(target: FUSEOP_TAG, "chmod {:?} with mode {}", name, mode);
 
-    match ops.chmod(&req, &name, mode) {
-        Ok(_) => 0,
-        Err(num) => {
-            error!(
-                target: FUSEOP_TAG,
-                "chmod error {} for {}",
-                num,
-                name.display()
-            );
-            num.into()
+        match ops.chmod(req, &name, mode) {
+            Ok(_) => 0,
+            Err(num) => {
+                error!(
+                    target: FUSEOP_TAG,
+                    "chmod error {} for {}",
+                    num,
+                    name.display()
+                );
+                num.into()
+            }
         }
-    }
+    })
 }
 
 extern "C" fn chown(
     arg1: *const ::std::os::raw::c_char,
     uid: uid_t,
     gid: gid_t,
+    #[cfg(fuse3)] _arg4: *mut fuse_file_info,
 ) -> ::std::os::raw::c_int {
-    let (req, ops) = ops_from_ctx();
     let name = to_pathname(arg1);
-    info!(
-        target: FUSEOP_TAG,
-        "chown {:?} with uid:gid {}:{}", name, uid, gid
-    );
+    fuse_op("chown", &name, |req, ops| {
+        info!(
+            target: FUSEOP_TAG,
+            "chown {:?} with uid:gid {}:{}", name, uid, gid
+        );
 
-    match ops.chown(&req, &name, uid, gid) {
-        Ok(_) => 0,
-        Err(num) => {
-            error!(
-                target: FUSEOP_TAG,
-                "chown error {} for {}",
-                num,
-                name.display()
-            );
-            num.into()
+        match ops.chown(req, &name, uid, gid) {
+            Ok(_) => 0,
+            Err(num) => {
+                error!(
+                    target: FUSEOP_TAG,
+                    "chown error {} for {}",
+                    num,
+                    name.display()
+                );
+                num.into()
+            }
         }
-    }
+    })
 }
 
 extern "C" fn access(
@@ -985,6 +1561,7 @@ extern "C" fn fsyncdir(
     FuseErrno::from(ENOSYS).into()
 }
 
+#[cfg(not(fuse3))]
 extern "C" fn ftruncate(
     arg1: *const ::std::os::raw::c_char,
     arg2: off_t,
@@ -996,7 +1573,8 @@ extern "C" fn ftruncate(
 
 extern "C" fn ioctl(
     _arg1: *const ::std::os::raw::c_char,
-    _cmd: ::std::os::raw::c_int,
+    #[cfg(not(fuse3))] _cmd: ::std::os::raw::c_int,
+    #[cfg(fuse3)] _cmd: ::std::os::raw::c_uint,
     _arg: *mut ::std::os::raw::c_void,
     _arg2: *mut fuse_file_info,
     _flags: ::std::os::raw::c_uint,
@@ -1064,36 +1642,37 @@ unsafe fn common_setxattr(
     flags: ::std::os::raw::c_int,
     position: ::std::os::raw::c_uint,
 ) -> ::std::os::raw::c_int {
-    let (req, ops) = ops_from_ctx();
     let path = to_pathname(arg1);
+    fuse_op("setxattr", &path, |req, ops| {
+        let name = CStr::from_ptr(arg2).to_string_lossy().into_owned();
+        let value = std::slice::from_raw_parts(arg3 as *const ::std::os::raw::c_uchar, val_size);
 
-    let name = CStr::from_ptr(arg2).to_string_lossy().into_owned();
-    let value = std::slice::from_raw_parts(arg3 as *const ::std::os::raw::c_uchar, val_size);
-
-    info!(
-        target: FUSEOP_TAG,
-        "setxattr for {}, name {}, value {:?}, position {}, flags {}",
-        path.display(),
-        name,
-        value,
-        position,
-        flags,
-    );
+        info!(
+            target: FUSEOP_TAG,
+            "setxattr for {}, name {}, value {:?}, position {}, flags {}",
+            path.display(),
+            name,
+            value,
+            position,
+            flags,
+        );
 
-    match ops.setxattr(&req, &path, &name, value, position, flags) {
-        Ok(_) => 0,
-        Err(num) => {
-            error!(
-                target: FUSEOP_TAG,
-                "setxattr error {} for {}",
-                num,
-                path.display()
-            );
-            num.into()
+        match ops.setxattr(req, &path, &name, value, position, flags) {
+            Ok(_) => 0,
+            Err(num) => {
+                error!(
+                    target: FUSEOP_TAG,
+                    "setxattr error {} for {}",
+                    num,
+                    path.display()
+                );
+                num.into()
+            }
         }
-    }
+    })
 }
 
+#[cfg(not(fuse3))]
 extern "C" fn utime(
     _arg1: *const ::std::os::raw::c_char,
     _arg2: *mut utimbuf,
@@ -1105,6 +1684,7 @@ extern "C" fn utime(
 extern "C" fn utimens(
     _arg1: *const ::std::os::raw::c_char,
     _tv: *const timespec,
+    #[cfg(fuse3)] _arg3: *mut fuse_file_info,
 ) -> ::std::os::raw::c_int {
     info!(target: FUSEOP_TAG, "utimens");
     FuseErrno::from(ENOSYS).into()
@@ -1133,22 +1713,23 @@ extern "C" fn setattr_x(
     arg1: *const ::std::os::raw::c_char,
     arg2: *mut setattr_x,
 ) -> ::std::os::raw::c_int {
-    let (req, ops) = ops_from_ctx();
     let name = to_pathname(arg1);
-    info!(target: FUSEOP_TAG, "setattr_x {}", name.display());
+    fuse_op("setattr_x", &name, |req, ops| {
+        info!(target: FUSEOP_TAG, "setattr_x {}", name.display());
 
-    match ops.setattr_x(&req, &name, arg2 as *const setattr_x) {
-        Ok(_) => 0,
-        Err(num) => {
-            error!(
-                target: FUSEOP_TAG,
-                "setattr_x error {} for {}",
-                num,
-                name.display()
-            );
-            num.into()
+        match ops.setattr_x(req, &name, arg2 as *const setattr_x) {
+            Ok(_) => 0,
+            Err(num) => {
+                error!(
+                    target: FUSEOP_TAG,
+                    "setattr_x error {} for {}",
+                    num,
+                    name.display()
+                );
+                num.into()
+            }
         }
-    }
+    })
 }
 
 #[cfg(target_os = "macos")]
@@ -1186,65 +1767,65 @@ fn listxattr_common(
     bufsize: usize,
     options: ::std::os::raw::c_int,
 ) -> ::std::os::raw::c_int {
-    let (req, ops) = ops_from_ctx();
     let path = to_pathname(arg1);
-
-    info!(
-        target: FUSEOP_TAG,
-        "listxattr {}, bufsize {}, options {}",
-        path.display(),
-        bufsize,
-        options
-    );
-
-    let size_only = buf.is_null() || bufsize == 0;
-
-    if size_only {
-        debug!(
+    fuse_op("listxattr", &path, |req, ops| {
+        info!(
             target: FUSEOP_TAG,
-            "Caller is interested in the size of the xattrs"
+            "listxattr {}, bufsize {}, options {}",
+            path.display(),
+            bufsize,
+            options
         );
-    }
 
-    match ops.listxattr(&req, &path, options) {
-        Ok(names) => {
-            let mut size = 0;
-            unsafe {
-                let mut offset = 0;
-                for name in names {
-                    let c_name = CString::new(name).unwrap().into_bytes_with_nul();
-                    size += c_name.len();
-
-                    if !size_only {
-                        ptr::copy_nonoverlapping(
-                            c_name.as_ptr() as *const i8,
-                            buf.offset(offset),
-                            c_name.len(),
-                        );
-                        trace!(
-                            target: FUSEOP_TAG,
-                            "Copying {:?} to offset {} with len {}",
-                            c_name,
-                            offset,
-                            c_name.len()
-                        );
-                        offset += c_name.len() as isize;
-                    }
-                }
-            }
+        let size_only = buf.is_null() || bufsize == 0;
 
-            size as i32
-        }
-        Err(num) => {
-            error!(
+        if size_only {
+            debug!(
                 target: FUSEOP_TAG,
-                "listxattr error {} for {}",
-                num,
-                path.display()
+                "Caller is interested in the size of the xattrs"
             );
-            num.into()
         }
-    }
+
+        match ops.listxattr(req, &path, options) {
+            Ok(names) => {
+                let mut size = 0;
+                unsafe {
+                    let mut offset = 0;
+                    for name in names {
+                        let c_name = CString::new(name).unwrap().into_bytes_with_nul();
+                        size += c_name.len();
+
+                        if !size_only {
+                            ptr::copy_nonoverlapping(
+                                c_name.as_ptr() as *const i8,
+                                buf.offset(offset),
+                                c_name.len(),
+                            );
+                            trace!(
+                                target: FUSEOP_TAG,
+                                "Copying {:?} to offset {} with len {}",
+                                c_name,
+                                offset,
+                                c_name.len()
+                            );
+                            offset += c_name.len() as isize;
+                        }
+                    }
+                }
+
+                size as i32
+            }
+            Err(num) => {
+                error!(
+                    target: FUSEOP_TAG,
+                    "listxattr error {} for {}",
+                    num,
+                    path.display()
+                );
+                num.into()
+            }
+        }
+    })
 }
 
 #[cfg(target_os = "linux")]
@@ -1270,29 +1851,30 @@ fn removexattr_common(
     arg2: *const ::std::os::raw::c_char,
     arg3: ::std::os::raw::c_int,
 ) -> ::std::os::raw::c_int {
-    let (req, ops) = ops_from_ctx();
     let path = to_pathname(arg1);
-    let name = unsafe { CStr::from_ptr(arg2).to_string_lossy().into_owned() };
+    fuse_op("removexattr", &path, |req, ops| {
+        let name = unsafe { CStr::from_ptr(arg2).to_string_lossy().into_owned() };
 
-    info!(
-        target: FUSEOP_TAG,
-        "removexattr {} name {}, options {}",
-        path.display(),
-        name,
-        arg3
-    );
-    match ops.removexattr(&req, &path, &name, arg3) {
-        Ok(_) => 0,
-        Err(num) => {
-            error!(
-                target: FUSEOP_TAG,
-                "removexattr error {} for {}",
-                num,
-                path.display()
-            );
-            num.into()
+        info!(
+            target: FUSEOP_TAG,
+            "removexattr {} name {}, options {}",
+            path.display(),
+            name,
+            arg3
+        );
+        match ops.removexattr(req, &path, &name, arg3) {
+            Ok(_) => 0,
+            Err(num) => {
+                error!(
+                    target: FUSEOP_TAG,
+                    "removexattr error {} for {}",
+                    num,
+                    path.display()
+                );
+                num.into()
+            }
         }
-    }
+    })
 }
 
 #[cfg(target_os = "linux")]
@@ -1323,34 +1905,35 @@ fn getxattr_common(
     bufsize: usize,
     position: ::std::os::raw::c_uint,
 ) -> ::std::os::raw::c_int {
-    let (req, ops) = ops_from_ctx();
     let path = to_pathname(arg1);
-
-    let name = unsafe { CStr::from_ptr(arg2) }
-        .to_string_lossy()
-        .into_owned();
-
-    info!(target: FUSEOP_TAG, "getxattr for {:?}, name {}", path, name,);
-
-    match ops.getxattr(&req, &path, &name, position) {
-        Ok(value) => unsafe {
-            // according to the man pages, if size is 0, the caller is requesting the size of the value, in order to
-            // determine what size buffer to call us again with
-            if bufsize == 0 {
-                value.len() as i32
-            } else {
-                let copied = std::cmp::min(value.len(), bufsize);
-                ptr::copy(value.as_ptr(), arg3 as *mut u8, copied);
-                copied as i32
+    fuse_op("getxattr", &path, |req, ops| {
+        let name = unsafe { CStr::from_ptr(arg2) }
+            .to_string_lossy()
+            .into_owned();
+
+        info!(target: FUSEOP_TAG, "getxattr for {:?}, name {}", path, name,);
+
+        match ops.getxattr(req, &path, &name, position) {
+            Ok(value) => unsafe {
+                // according to the man pages, if size is 0, the caller is requesting the size of the value, in order to
+                // determine what size buffer to call us again with
+                if bufsize == 0 {
+                    value.len() as i32
+                } else {
+                    let copied = std::cmp::min(value.len(), bufsize);
+                    ptr::copy(value.as_ptr(), arg3 as *mut u8, copied);
+                    copied as i32
+                }
+            },
+            Err(num) => {
+                error!(target: FUSEOP_TAG, "getxattr error {} for {:?}", num, path);
+                num.into()
             }
-        },
-        Err(num) => {
-            error!(target: FUSEOP_TAG, "getxattr error {} for {:?}", num, path);
-            num.into()
         }
-    }
+    })
 }
 
+#[cfg(not(fuse3))]
 extern "C" fn fgetattr(
     arg1: *const ::std::os::raw::c_char,
     arg2: *mut stat,
@@ -1374,7 +1957,7 @@ impl FuseOperations {
 
 impl Default for FuseOperations {
     fn default() -> Self {
-        #[cfg(target_os = "linux")]
+        #[cfg(all(target_os = "linux", not(fuse3)))]
         return Self {
             access: Some(access),
             bmap: None,
@@ -1382,7 +1965,7 @@ impl Default for FuseOperations {
             chown: Some(chown),
             create: Some(create),
             destroy: None,
-            fallocate: None,
+            fallocate: Some(fallocate),
             fgetattr: Some(fgetattr),
             flock: None,
             flush: Some(flush),
@@ -1403,7 +1986,7 @@ impl Default for FuseOperations {
             opendir: Some(opendir),
             poll: Some(poll),
             read: Some(read),
-            read_buf: None,
+            read_buf: Some(read_buf),
             readdir: Some(readdir),
             readlink: Some(readlink),
             release: Some(release),
@@ -1419,11 +2002,53 @@ impl Default for FuseOperations {
             utime: Some(utime),
             utimens: Some(utimens),
             write: Some(write),
-            write_buf: None,
+            write_buf: Some(write_buf),
 
             _bitfield_1: Default::default(),
         };
 
+        // libfuse 3's operations have grown over its minor versions, so we start from all of them unset and only set
+        // the ones we have, instead of naming every field of whichever version we were built against
+        #[cfg(all(target_os = "linux", fuse3))]
+        {
+            let mut ops: Self = unsafe { std::mem::zeroed() };
+            ops.access = Some(access);
+            ops.chmod = Some(chmod);
+            ops.chown = Some(chown);
+            ops.create = Some(create);
+            ops.fallocate = Some(fallocate);
+            ops.flush = Some(flush);
+            ops.fsync = Some(fsync);
+            ops.fsyncdir = Some(fsyncdir);
+            ops.getattr = Some(getattr);
+            ops.getxattr = Some(getxattr);
+            ops.ioctl = Some(ioctl);
+            ops.listxattr = Some(listxattr);
+            ops.mkdir = Some(mkdir);
+            ops.mknod = Some(mknod);
+            ops.open = Some(open);
+            ops.opendir = Some(opendir);
+            ops.poll = Some(poll);
+            ops.read = Some(read);
+            ops.read_buf = Some(read_buf);
+            ops.readdir = Some(readdir);
+            ops.readlink = Some(readlink);
+            ops.release = Some(release);
+            ops.releasedir = Some(releasedir);
+            ops.removexattr = Some(removexattr);
+            ops.rename = Some(rename);
+            ops.rmdir = Some(rmdir);
+            ops.setxattr = Some(setxattr);
+            ops.statfs = Some(statfs);
+            ops.symlink = Some(symlink);
+            ops.truncate = Some(truncate);
+            ops.unlink = Some(unlink);
+            ops.utimens = Some(utimens);
+            ops.write = Some(write);
+            ops.write_buf = Some(write_buf);
+            return ops;
+        }
+
         #[cfg(target_os = "macos")]
         return Self {
             access: Some(access),
@@ -1433,8 +2058,8 @@ impl Default for FuseOperations {
             chown: Some(chown),
             create: Some(create),
             destroy: None,
-            exchange: None,
-            fallocate: None,
+            exchange: Some(exchange),
+            fallocate: Some(fallocate),
             fgetattr: Some(fgetattr),
             flock: None,
             flush: Some(flush),
@@ -1457,7 +2082,7 @@ impl Default for FuseOperations {
             opendir: Some(opendir),
             poll: Some(poll),
             read: Some(read),
-            read_buf: None,
+            read_buf: Some(read_buf),
             readdir: Some(readdir),
             readlink: Some(readlink),
             release: Some(release),
@@ -1473,16 +2098,16 @@ impl Default for FuseOperations {
             setbkuptime: None,
             setchgtime: Some(setchgtime),
             setcrtime: Some(setcrtime),
-            setvolname: None,
+            setvolname: Some(setvolname),
             statfs: Some(statfs),
-            statfs_x: None,
+            statfs_x: Some(statfs_x),
             symlink: Some(symlink),
             truncate: Some(truncate),
             unlink: Some(unlink),
             utime: Some(utime),
             utimens: Some(utimens),
             write: Some(write),
-            write_buf: None,
+            write_buf: Some(write_buf),
 
             _bitfield_1: Default::default(),
         };
@@ -1492,8 +2117,11 @@ impl Default for FuseOperations {
 pub struct MountHandle {
     mountpoint: PathBuf,
     loop_join: Option<thread::JoinHandle<i32>>,
+    flusher_join: Option<thread::JoinHandle<()>>,
     handle: Arc<FuseHandle>,
     user_data: *const c_void,
+    exited: bool,
+    unmounted: bool,
 }
 
 impl MountHandle {
@@ -1501,59 +2129,75 @@ impl MountHandle {
         mountpoint: &Path,
         handle: Arc<FuseHandle>,
         loop_join: thread::JoinHandle<i32>,
+        flusher_join: thread::JoinHandle<()>,
         user_data: *const c_void,
     ) -> Self {
         Self {
             mountpoint: mountpoint.to_owned(),
             handle,
             loop_join: Some(loop_join),
+            flusher_join: Some(flusher_join),
             user_data,
+            exited: false,
+            unmounted: false,
         }
     }
 
-    /// Waits for the fuse_loop event loop to terminate.  This can block indefinitely if it is not
-    /// part of the fuse shutdown process.  This consumes the thread's join handle, so it only
-    /// ever runs once.
-    pub fn wait(&mut self) -> Option<i32> {
-        debug!(target: FUSE_TAG, "Waiting for fuse_loop to terminate...");
-        // it may have been already consumed by a previous MountHandle::wait() call
-        if self.loop_join.is_some() {
-            let ret_val = self.loop_join.take().unwrap().join().ok();
-            debug!(
-                target: FUSE_TAG,
-                "fuse_loop has terminated with {:?}", ret_val
-            );
-            ret_val
-        } else {
-            debug!(target: FUSE_TAG, "fuse_loop was already joined, skipping");
-            None
-        }
+    /// Whether the fuse_loop event loop is still serving requests.  It stops after `exit`, or if the filesystem was
+    /// unmounted out from under us, for example with `umount`.
+    pub fn is_running(&self) -> bool {
+        !self.exited && !self.handle.loop_done.load(Ordering::SeqCst)
     }
-}
-impl Drop for MountHandle {
-    fn drop(&mut self) {
-        info!(target: FUSE_TAG, "Unmounting {:?}", self.mountpoint);
-        let mount_char = CString::new(self.mountpoint.to_str().unwrap())
-            .unwrap()
-            .into_raw();
 
+    /// Tells the fuse_loop event loop to stop dispatching requests.  Requests that are already being handled are
+    /// allowed to finish.  This only ever runs once.
+    pub fn exit(&mut self) {
+        if self.exited {
+            return;
+        }
         self.handle.disable();
 
+        // an invalidation can't be in flight once the fuse handle is destroyed
+        if let Some(flusher) = self.flusher_join.take() {
+            debug!(target: FUSE_TAG, "Joining on invalidation flusher");
+            let _ = flusher.join();
+        }
+
         // if we don't sleep, we sometimes get a:
         //     fuse_kern_chan.c:67: fuse_kern_chan_send: Assertion `se != NULL' failed
         std::thread::sleep(std::time::Duration::from_millis(100));
 
+        debug!(target: FUSE_TAG, "Calling fuse_exit");
         unsafe {
-            debug!(target: FUSE_TAG, "Calling fuse_exit");
             // exits our fuse_loop
             fuse_exit(self.handle.handle_struct.load(Ordering::Relaxed));
+        }
+        self.exited = true;
+    }
+
+    /// Exits the event loop if it hasn't been already, unmounts the filesystem, and releases the `Filesystem`.  This
+    /// only ever runs once, and is also run when the `MountHandle` is dropped.
+    pub fn unmount(&mut self) {
+        if self.unmounted {
+            return;
+        }
+        info!(target: FUSE_TAG, "Unmounting {:?}", self.mountpoint);
+        self.exit();
+
+        let mount_char = CString::new(self.mountpoint.to_str().unwrap())
+            .unwrap()
+            .into_raw();
 
+        unsafe {
             debug!(target: FUSE_TAG, "Calling fuse_unmount");
             // unmounts the file system and destroys the comm channel
+            #[cfg(not(fuse3))]
             fuse_unmount(
                 mount_char,
                 self.handle.channel_struct.load(Ordering::Relaxed),
             );
+            #[cfg(fuse3)]
+            fuse_unmount(self.handle.handle_struct.load(Ordering::Relaxed));
 
             debug!(target: FUSE_TAG, "Joining on loop handle");
             self.wait();
@@ -1570,12 +2214,37 @@ impl Drop for MountHandle {
             let ops = Box::from_raw(*boxed);
             drop(ops);
         }
+        self.unmounted = true;
         info!(
             target: FUSE_TAG,
             "Done unmounting {}",
             self.mountpoint.display()
         );
     }
+
+    /// Waits for the fuse_loop event loop to terminate.  This can block indefinitely if it is not
+    /// part of the fuse shutdown process.  This consumes the thread's join handle, so it only
+    /// ever runs once.
+    pub fn wait(&mut self) -> Option<i32> {
+        debug!(target: FUSE_TAG, "Waiting for fuse_loop to terminate...");
+        // it may have been already consumed by a previous MountHandle::wait() call
+        if self.loop_join.is_some() {
+            let ret_val = self.loop_join.take().unwrap().join().ok();
+            debug!(
+                target: FUSE_TAG,
+                "fuse_loop has terminated with {:?}", ret_val
+            );
+            ret_val
+        } else {
+            debug!(target: FUSE_TAG, "fuse_loop was already joined, skipping");
+            None
+        }
+    }
+}
+impl Drop for MountHandle {
+    fn drop(&mut self) {
+        self.unmount();
+    }
 }
 
 #[derive(Debug)]
@@ -1625,7 +2294,7 @@ where
     // What is happening here is fairly complicated and nuanced.  There are subtleties here that
     // are easy to miss.  Basically what we're doing first is moving ops onto the heap and then ensuring
     // it won't be dropped.  We do this by leaking the box, which is fine because `mount` is only
-    // ever called once per program run, so we're not leaking much.  The reason we need
+    // ever called once per mounted collection, so we're not leaking much.  The reason we need
     // to do this is because we need to cast `ops` to a `&dyn Filesystem`, and if `ops` gets dropped
     // at the end of this function, we would SEGFAULT.  This is not obvious, doing that compiles:
     //
@@ -1659,44 +2328,82 @@ where
     let mount_char = CString::new(mountpoint.to_str().unwrap())
         .unwrap()
         .into_raw();
+    // libfuse 3 takes the fuse and mount args together
+    #[cfg(fuse3)]
+    fuse_argv.extend(mount_argv.drain(1..));
+
     let fuse_args_struct = &mut fuse_args {
         argc: fuse_argv.len() as c_int,
         argv: fuse_argv.as_mut_ptr(),
         allocated: 0,
     } as *mut fuse_args;
 
+    #[cfg(not(fuse3))]
     let mount_args_struct = &mut fuse_args {
         argc: mount_argv.len() as c_int,
         argv: mount_argv.as_mut_ptr(),
         allocated: 0,
     } as *mut fuse_args;
 
-    debug!(target: FUSE_TAG, "Mounting {:?}", mountpoint);
-    let chan = AtomicPtr::new(unsafe { fuse_mount(mount_char, mount_args_struct) });
+    #[cfg(not(fuse3))]
+    let (chan, handle) = {
+        debug!(target: FUSE_TAG, "Mounting {:?}", mountpoint);
+        let chan = AtomicPtr::new(unsafe { fuse_mount(mount_char, mount_args_struct) });
 
-    if chan.load(Ordering::Relaxed).is_null() {
-        error!(target: FUSE_TAG, "fuse_chan was NULL!");
-        return Err(MountError::BadFuseChannel);
-    }
+        if chan.load(Ordering::Relaxed).is_null() {
+            error!(target: FUSE_TAG, "fuse_chan was NULL!");
+            return Err(MountError::BadFuseChannel);
+        }
 
-    debug!(target: FUSE_TAG, "Creating fuse handle");
-    let handle = AtomicPtr::new(unsafe {
-        fuse_new(
-            chan.load(Ordering::Relaxed),
-            fuse_args_struct,
-            &low_level_ops,
-            size_of::<FuseOperations>(),
-            user_data as *mut c_void,
-        )
-    });
+        debug!(target: FUSE_TAG, "Creating fuse handle");
+        let handle = AtomicPtr::new(unsafe {
+            fuse_new(
+                chan.load(Ordering::Relaxed),
+                fuse_args_struct,
+                &low_level_ops,
+                size_of::<FuseOperations>(),
+                user_data as *mut c_void,
+            )
+        });
 
-    if handle.load(Ordering::Relaxed).is_null() {
-        error!(target: FUSE_TAG, "fuse handle was NULL!");
-        unsafe {
-            fuse_unmount(mount_char, chan.load(Ordering::Relaxed));
+        if handle.load(Ordering::Relaxed).is_null() {
+            error!(target: FUSE_TAG, "fuse handle was NULL!");
+            unsafe {
+                fuse_unmount(mount_char, chan.load(Ordering::Relaxed));
+            }
+            return Err(MountError::BadFuseHandle);
+        }
+        (chan, handle)
+    };
+
+    // libfuse 3 makes the fuse handle first, and then mounts it
+    #[cfg(fuse3)]
+    let handle = {
+        debug!(target: FUSE_TAG, "Creating fuse handle");
+        let handle = AtomicPtr::new(unsafe {
+            fuse_new(
+                fuse_args_struct,
+                &low_level_ops,
+                size_of::<FuseOperations>(),
+                user_data as *mut c_void,
+            )
+        });
+
+        if handle.load(Ordering::Relaxed).is_null() {
+            error!(target: FUSE_TAG, "fuse handle was NULL!");
+            return Err(MountError::BadFuseHandle);
         }
-        return Err(MountError::BadFuseHandle);
-    }
+
+        debug!(target: FUSE_TAG, "Mounting {:?}", mountpoint);
+        if unsafe { fuse_mount(handle.load(Ordering::Relaxed), mount_char) } != 0 {
+            error!(target: FUSE_TAG, "fuse_mount failed!");
+            unsafe {
+                fuse_destroy(handle.load(Ordering::Relaxed));
+            }
+            return Err(MountError::BadFuseChannel);
+        }
+        handle
+    };
 
     unsafe {
         debug!(target: FUSE_TAG, "Installing fuse signal handlers");
@@ -1712,10 +2419,22 @@ where
 
     let fuse_handle = Arc::new(FuseHandle {
         disabled: AtomicBool::new(false),
+        loop_done: AtomicBool::new(false),
         handle_struct: handle,
+        #[cfg(not(fuse3))]
         channel_struct: chan,
+        invalidations: Mutex::new(vec![]),
+        invalidations_ready: Condvar::new(),
     });
 
+    let flusher_join = {
+        let fuse_handle = fuse_handle.clone();
+        thread::Builder::new()
+            .name("fuse_invalidate".to_string())
+            .spawn(move || fuse_handle.flush_invalidations())
+            .expect("Couldn't spawn invalidation flusher thread")
+    };
+
     let (tx, rx) = mpsc::sync_channel(1);
     let join_handle: thread::JoinHandle<i32>;
     {
@@ -1741,10 +2460,11 @@ where
                         // thread safe
                         // FIXME use return code
                         let _ = tx.send(true);
-                        unsafe { fuse_loop_mt(fuse_handle.handle_struct.load(Ordering::Relaxed)) }
+                        unsafe { loop_mt(fuse_handle.handle_struct.load(Ordering::Relaxed)) }
                     }
                 };
                 debug!(target: FUSE_TAG, "Stopped fuse_loop thread");
+                fuse_handle.loop_done.store(true, Ordering::SeqCst);
                 handle
             })
             .expect("Couldn't spawn join thread");
@@ -1760,6 +2480,7 @@ where
     // fuse_kern_chan.c:67: fuse_kern_chan_send: Assertion `se != NULL' failed.
     // on ubuntu 18.04 LTS, 4.15.0-72-generic, x86_64, fuse 2.9.7
     if let Err(mpsc::RecvError) = rx.recv() {
+        fuse_handle.disable();
         return Err(MountError::LoopDied);
     }
     thread::sleep(std::time::Duration::from_millis(400));
@@ -1768,6 +2489,7 @@ where
         mountpoint,
         fuse_handle.clone(),
         join_handle,
+        flusher_join,
         user_data,
     )));
 
@@ -0,0 +1,59 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use clap::{AppSettings, Arg, SubCommand};
+
+type ValidatorResult = Result<(), String>;
+
+fn base_dir_validator(v: String) -> ValidatorResult {
+    if !std::path::Path::new(&v).is_absolute() {
+        return Err(format!("{} is not an absolute path", v));
+    }
+    Ok(())
+}
+
+pub(super) fn add_subcommands<'a, 'b>(app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+    app.subcommand(
+        SubCommand::with_name("collection")
+            .about("Manages where a collection lives")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(
+                SubCommand::with_name("move-mountpoint")
+                    .about("Moves a collection to a new mount.base_dir, unmounting and remounting it if it's mounted")
+                    .arg(
+                        Arg::with_name("collection")
+                            .help("Supertag collection name, eg 'media_files'.")
+                            .required(true)
+                            .takes_value(true),
+                    )
+                    .arg(
+                        Arg::with_name("base_dir")
+                            .help("The absolute directory to mount the collection under from now on, eg '/media'.  The collection is mounted at <base_dir>/<collection>.")
+                            .required(true)
+                            .takes_value(true)
+                            .validator(base_dir_validator),
+                    )
+                    .arg(
+                        Arg::with_name("profile")
+                            .help("The mount profile to remount with, if the collection was mounted with one.")
+                            .takes_value(true)
+                            .validator(super::mount::profile_validator)
+                            .long("--profile"),
+                    ),
+            ),
+    )
+}
@@ -0,0 +1,58 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use clap::{AppSettings, Arg, SubCommand};
+
+pub(super) fn add_subcommands<'a, 'b>(app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+    app.subcommand(
+        SubCommand::with_name("db")
+            .about("Inspects collection databases")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(
+                SubCommand::with_name("schema")
+                    .about("Prints the documented database schema, its version, and its compatibility policy")
+                    .arg(
+                        Arg::with_name("collection")
+                            .help("Supertag collection name, eg 'media_files'.  Without it, the schema of this release is shown.")
+                            .takes_value(true),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("gate")
+                    .about("Shows, closes or opens the write gate, which holds off writes to a collection during maintenance")
+                    .arg(
+                        Arg::with_name("collection")
+                            .help("Supertag collection name, eg 'media_files'")
+                            .required(true)
+                            .takes_value(true),
+                    )
+                    .arg(
+                        Arg::with_name("close")
+                            .long("close")
+                            .help("Closes the gate, giving the reason that's shown to anything that tries to write")
+                            .value_name("REASON")
+                            .takes_value(true),
+                    )
+                    .arg(
+                        Arg::with_name("open")
+                            .long("open")
+                            .help("Opens the gate, even if a backup or migration that died is still holding it")
+                            .conflicts_with("close"),
+                    ),
+            ),
+    )
+}
@@ -0,0 +1,59 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use clap::{Arg, SubCommand};
+
+pub(super) fn add_subcommands<'a, 'b>(app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+    app.subcommand(
+        SubCommand::with_name("demo")
+            .about("Generates a synthetic collection of fake files with realistically distributed tags.  The same options always generate the same collection, so they can be shared to reproduce a problem without sharing private files.")
+            .arg(
+                Arg::with_name("files")
+                    .help("How many files to generate.")
+                    .long("--files")
+                    .takes_value(true)
+                    .default_value("1000"),
+            )
+            .arg(
+                Arg::with_name("tags")
+                    .help("How many distinct tags to draw from.  A few of them end up on most files, and most of them on a few.")
+                    .long("--tags")
+                    .takes_value(true)
+                    .default_value("50"),
+            )
+            .arg(
+                Arg::with_name("seed")
+                    .help("Seed for choosing each file's tags.")
+                    .long("--seed")
+                    .takes_value(true)
+                    .default_value("0"),
+            )
+            .arg(
+                Arg::with_name("output")
+                    .help("The directory to write the fake files into.  It must be empty if it exists.  Defaults to a directory under the system temp directory named after the options.")
+                    .long("--output")
+                    .short("o")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("collection")
+                    .help("Supertag collection name to tag the files into, eg 'demo'.")
+                    .required(true)
+                    .takes_value(true),
+            ),
+    )
+}
@@ -0,0 +1,46 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use clap::{Arg, SubCommand};
+
+pub(super) fn add_subcommands<'a, 'b>(app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+    app.subcommand(
+        SubCommand::with_name("doctor")
+            .about("Finds tagged files that have moved or been replaced on disk since they were tagged, and optionally repairs them.")
+            .arg(
+                Arg::with_name("repair")
+                    .help("How to repair broken files.  `search` looks for missing files by name under the search roots, and adopts replaced files.  `drop` removes broken files from the collection.")
+                    .long("--repair")
+                    .takes_value(true)
+                    .possible_values(&["search", "drop"]),
+            )
+            .arg(
+                Arg::with_name("root")
+                    .help("A directory to search for missing files, in addition to doctor.search_roots in the config.  Can be given more than once.")
+                    .long("--root")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1),
+            )
+            .arg(
+                Arg::with_name("collection")
+                    .help("Supertag collection name, eg 'media_files'.")
+                    .required(true)
+                    .takes_value(true),
+            ),
+    )
+}
@@ -0,0 +1,36 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use clap::{Arg, SubCommand};
+
+pub(super) fn add_subcommands<'a, 'b>(app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+    app.subcommand(
+        SubCommand::with_name("dupes")
+            .about("Lists tagged files whose contents are the same as another tagged file's, hashing any files that haven't been hashed yet.")
+            .arg(
+                Arg::with_name("rehash")
+                    .help("Hash every tagged file again, instead of only the ones without a hash.")
+                    .long("--rehash"),
+            )
+            .arg(
+                Arg::with_name("collection")
+                    .help("Supertag collection name, eg 'media_files'.")
+                    .required(true)
+                    .takes_value(true),
+            ),
+    )
+}
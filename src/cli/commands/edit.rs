@@ -0,0 +1,55 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use clap::{Arg, SubCommand};
+
+pub(super) fn add_subcommands<'a, 'b>(app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+    app.subcommand(
+        SubCommand::with_name("edit")
+            .about("Sets a tag's color, icon or description.  Without any options, shows them.")
+            .arg(
+                Arg::with_name("collection")
+                    .help("Supertag collection name, eg 'media_files'.")
+                    .required(true)
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("tag")
+                    .help("The tag to edit, eg 'important'.")
+                    .required(true)
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("color")
+                    .long("color")
+                    .help("One of gray, green, purple, blue, yellow, red or orange, or 'none' to remove the color.")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("icon")
+                    .long("icon")
+                    .help("Path of an image to use as the tag's icon.  An empty path removes the icon.")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("description")
+                    .long("description")
+                    .help("A description of the tag.  An empty description removes it.")
+                    .takes_value(true),
+            ),
+    )
+}
@@ -0,0 +1,64 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use clap::{Arg, SubCommand};
+
+type ValidatorResult = Result<(), String>;
+
+fn number_validator(v: String) -> ValidatorResult {
+    let _ = v
+        .parse::<u32>()
+        .map_err(|_| format!("{} is not a valid number", v))?;
+    Ok(())
+}
+
+pub(super) fn add_subcommands<'a, 'b>(app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+    app.subcommand(
+        SubCommand::with_name("events")
+            .about("Prints the changes made to a collection after a cursor, for tools that mirror it.")
+            .arg(
+                Arg::with_name("format")
+                    .help("How to print the events.")
+                    .long("--format")
+                    .short("f")
+                    .takes_value(true)
+                    .possible_values(&["plain", "json"])
+                    .default_value("plain"),
+            )
+            .arg(
+                Arg::with_name("limit")
+                    .help("The most events to print.  Poll again with the last event's id to get the rest.")
+                    .long("--limit")
+                    .short("l")
+                    .takes_value(true)
+                    .validator(number_validator)
+                    .default_value("1000"),
+            )
+            .arg(
+                Arg::with_name("collection")
+                    .help("Supertag collection name, eg 'media_files'.")
+                    .required(true)
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("since")
+                    .help("Only print events after this event id.  By default, every event is printed.")
+                    .validator(number_validator)
+                    .default_value("0"),
+            ),
+    )
+}
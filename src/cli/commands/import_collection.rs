@@ -0,0 +1,50 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use clap::{Arg, SubCommand};
+
+pub(super) fn add_subcommands<'a, 'b>(app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+    app.subcommand(
+        SubCommand::with_name("import-collection")
+            .about("Restores a collection from an archive made by `tag export`.  Tagged files that don't exist on this machine are grouped by directory, and you're asked where each directory has moved to.")
+            .arg(
+                Arg::with_name("remap")
+                    .help("Replaces the path prefix OLD with NEW in every tagged file, like /home/amy=/Users/amy.  Can be given more than once.")
+                    .long("--remap")
+                    .short("r")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1),
+            )
+            .arg(
+                Arg::with_name("no-prompt")
+                    .help("Don't ask about missing files.  They keep their tags, but their links will be broken.")
+                    .long("--no-prompt"),
+            )
+            .arg(
+                Arg::with_name("archive")
+                    .help("The archive to import.")
+                    .required(true)
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("collection")
+                    .help("The name of the new collection.  Defaults to the name it was exported with.")
+                    .takes_value(true),
+            ),
+    )
+}
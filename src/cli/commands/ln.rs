@@ -47,6 +47,26 @@ collection.
                     )
                     .required(true)
                     .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("children")
+                    .long("children")
+                    .help("When linking a directory, also link every file beneath it, so that they're listed on their own too"),
+            )
+            .arg(
+                Arg::with_name("recursive")
+                    .help("Link every file in each directory's tree, instead of the directories themselves")
+                    .long("--recursive")
+                    .short("r")
+                    .conflicts_with("children"),
+            )
+            .arg(
+                Arg::with_name("subdirs")
+                    .help("What to do with the subdirectories a file is in, when linking recursively.  `tags` tags the file with each of them, and `prefix` puts them in front of its name.  By default they're dropped.")
+                    .long("--subdirs")
+                    .takes_value(true)
+                    .possible_values(&["tags", "prefix"])
+                    .requires("recursive"),
             ),
     )
 }
@@ -0,0 +1,42 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use clap::{Arg, SubCommand};
+
+pub(super) fn add_subcommands<'a, 'b>(app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+    app.subcommand(
+        SubCommand::with_name("ls")
+            .about("Lists the tags and files in a tag intersection straight from the collection's database, so it works without the collection being mounted.")
+            .arg(
+                Arg::with_name("long")
+                    .help("Also print each tag's file count and each entry's mtime, and each file's target path.")
+                    .long("--long")
+                    .short("l"),
+            )
+            .arg(
+                Arg::with_name("collection")
+                    .help("Supertag collection name, eg 'media_files'.")
+                    .required(true)
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("tag-path")
+                    .help("Tags to intersect, as they'd be in the mount, eg 'rust/projects'.  By default, every tag is listed.")
+                    .takes_value(true),
+            ),
+    )
+}
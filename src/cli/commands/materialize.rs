@@ -0,0 +1,53 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use clap::{Arg, SubCommand};
+
+pub(super) fn add_subcommands<'a, 'b>(app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+    app.subcommand(
+        SubCommand::with_name("materialize")
+            .about("Writes the files matching a tag expression into a real directory outside of the mount, so that it can be shared or synced by tools that can't read the mount.")
+            .arg(
+                Arg::with_name("mode")
+                    .help("How each file is written.  'symlink' points at the file, 'hardlink' shares it, which only works on the same filesystem, and 'copy' duplicates its contents.")
+                    .long("--mode")
+                    .short("m")
+                    .takes_value(true)
+                    .possible_values(&["symlink", "hardlink", "copy"])
+                    .default_value("symlink"),
+            )
+            .arg(
+                Arg::with_name("collection")
+                    .help("Supertag collection name, eg 'media_files'.")
+                    .required(true)
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("output")
+                    .help("The directory to write the files into.  It's created if it doesn't exist, and must be empty if it does.")
+                    .required(true)
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("terms")
+                    .help("Tags to intersect.  Prefix a tag with '-' to exclude it, and join tags with '|' to match any of them.")
+                    .required(true)
+                    .multiple(true)
+                    .allow_hyphen_values(true),
+            ),
+    )
+}
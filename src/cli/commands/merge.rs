@@ -0,0 +1,50 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use clap::{Arg, SubCommand};
+
+pub(super) fn add_subcommands<'a, 'b>(app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+    app.subcommand(
+        SubCommand::with_name("merge")
+            .about("Merges one tag into another, retagging all of its files and removing it.  Merging a tag group into another moves all of its tags.")
+            .arg(
+                Arg::with_name("resolve")
+                    .help("How to settle files that merging would give the same name as a file already in the destination.  'keep-both' keeps both under their device/inode names, 'newest' keeps the most recently modified, 'source' keeps the merged file, and 'dest' leaves the merged file behind in its old tag.  By default you're asked about each one, or 'keep-both' is used if there's no terminal to ask on.")
+                    .long("--resolve")
+                    .takes_value(true)
+                    .possible_values(&["keep-both", "newest", "source", "dest", "prompt"]),
+            )
+            .arg(
+                Arg::with_name("collection")
+                    .help("Supertag collection name, eg 'media_files'.")
+                    .required(true)
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("src")
+                    .help("The tag or tag group to merge")
+                    .required(true)
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("dst")
+                    .help("The tag or tag group to merge into")
+                    .required(true)
+                    .takes_value(true),
+            ),
+    )
+}
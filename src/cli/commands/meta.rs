@@ -0,0 +1,97 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use clap::{AppSettings, Arg, SubCommand};
+
+fn collection_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("collection")
+        .help("Supertag collection name, eg 'media_files'.")
+        .required(true)
+        .takes_value(true)
+}
+
+fn file_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("file")
+        .help("The tagged file, or a link to it in the mount.")
+        .required(true)
+        .takes_value(true)
+}
+
+fn tag_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("tag")
+        .help("A tag that the file is tagged with.")
+        .required(true)
+        .takes_value(true)
+}
+
+pub(super) fn add_subcommands<'a, 'b>(app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+    app.subcommand(
+        SubCommand::with_name("meta")
+            .about("Sets key/value metadata on a tagged file, which can be matched with a `meta:key=value` path component.  Without any values, shows the file's metadata.")
+            .setting(AppSettings::SubcommandsNegateReqs)
+            .arg(collection_arg())
+            .arg(file_arg())
+            .arg(
+                Arg::with_name("values")
+                    .help("Metadata to set, eg 'client=acme'.")
+                    .multiple(true)
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("remove")
+                    .long("remove")
+                    .help("A metadata key to remove from the file.")
+                    .multiple(true)
+                    .number_of_values(1)
+                    .takes_value(true),
+            )
+            .subcommand(
+                SubCommand::with_name("set")
+                    .about("Sets key/value metadata on a file's tagging with one tag, like why it applies, eg 'source=scanner'.  Its links in the tag's directories show it as `user.supertag.tag_meta.<key>` xattrs.")
+                    .arg(collection_arg())
+                    .arg(file_arg())
+                    .arg(tag_arg())
+                    .arg(
+                        Arg::with_name("values")
+                            .help("Metadata to set, eg 'rating=5'.")
+                            .multiple(true)
+                            .takes_value(true)
+                            .required_unless("remove"),
+                    )
+                    .arg(
+                        Arg::with_name("remove")
+                            .long("remove")
+                            .help("A metadata key to remove from the file's tagging.")
+                            .multiple(true)
+                            .number_of_values(1)
+                            .takes_value(true),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("get")
+                    .about("Shows the key/value metadata on a file's tagging with one tag.")
+                    .arg(collection_arg())
+                    .arg(file_arg())
+                    .arg(tag_arg())
+                    .arg(
+                        Arg::with_name("key")
+                            .help("Only show the value of this key.")
+                            .takes_value(true),
+                    ),
+            ),
+    )
+}
@@ -15,12 +15,49 @@
  * You should have received a copy of the GNU Affero General Public License
  * along with this program.  If not, see <http://www.gnu.org/licenses/>.
  */
+use clap::Arg;
+
+mod alias;
+mod collection;
+mod db;
+mod demo;
+mod doctor;
+mod dupes;
+mod edit;
+mod events;
+mod export;
 mod fstab;
+mod groups;
+mod import;
+mod import_collection;
 mod ln;
+mod ls;
+mod materialize;
+mod merge;
+mod meta;
+mod migrate_symbols;
 mod mount;
 mod mv;
+mod open;
+mod queries;
+mod rate;
+mod replay;
+mod report_issue;
+mod retag;
 mod rm;
 mod rmdir;
+mod rpc;
+mod search;
+mod service;
+mod stats;
+mod status;
+mod swap;
+mod sync;
+mod undo;
+mod verify;
+mod watch;
+
+pub use mount::MountExit;
 
 pub struct ArgDefaults {
     pub uid: String,
@@ -32,12 +69,50 @@ pub fn add_subcommands<'a, 'b>(
     app: clap::App<'a, 'b>,
     defaults: &'a ArgDefaults,
 ) -> clap::App<'a, 'b> {
-    let mut attached = app;
+    let mut attached = app.arg(
+        Arg::with_name("dry-run")
+            .long("dry-run")
+            .global(true)
+            .help("Print what ln, rm, rmdir, mv or merge would change, without changing anything"),
+    );
     attached = mv::add_subcommands(attached);
     attached = ln::add_subcommands(attached);
     attached = mount::add_subcommands(attached, defaults);
     attached = rmdir::add_subcommands(attached);
     attached = rm::add_subcommands(attached);
     attached = fstab::add_subcommands(attached);
+    attached = migrate_symbols::add_subcommands(attached);
+    attached = db::add_subcommands(attached);
+    attached = alias::add_subcommands(attached);
+    attached = search::add_subcommands(attached);
+    attached = import::add_subcommands(attached);
+    attached = groups::add_subcommands(attached);
+    attached = watch::add_subcommands(attached);
+    attached = events::add_subcommands(attached);
+    attached = queries::add_subcommands(attached);
+    attached = report_issue::add_subcommands(attached);
+    attached = export::add_subcommands(attached);
+    attached = import_collection::add_subcommands(attached);
+    attached = doctor::add_subcommands(attached);
+    attached = edit::add_subcommands(attached);
+    attached = stats::add_subcommands(attached);
+    attached = meta::add_subcommands(attached);
+    attached = rate::add_subcommands(attached);
+    attached = undo::add_subcommands(attached);
+    attached = rpc::add_subcommands(attached);
+    attached = merge::add_subcommands(attached);
+    attached = materialize::add_subcommands(attached);
+    attached = demo::add_subcommands(attached);
+    attached = open::add_subcommands(attached);
+    attached = replay::add_subcommands(attached);
+    attached = status::add_subcommands(attached);
+    attached = swap::add_subcommands(attached);
+    attached = retag::add_subcommands(attached);
+    attached = sync::add_subcommands(attached);
+    attached = collection::add_subcommands(attached);
+    attached = dupes::add_subcommands(attached);
+    attached = service::add_subcommands(attached);
+    attached = verify::add_subcommands(attached);
+    attached = ls::add_subcommands(attached);
     attached
 }
@@ -20,6 +20,38 @@ use clap::{Arg, SubCommand};
 
 type ValidatorResult = Result<(), String>;
 
+/// How the child process that `tag mount --supervise` runs the mount in exits, which tells the supervisor whether to
+/// restart it.  A child that panics exits with Rust's 101, and one that's killed has no exit code at all, and both are
+/// treated like `Crashed`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MountExit {
+    /// Unmounted or signaled to stop, so the supervisor stops too
+    Done,
+    /// Couldn't mount at all, eg because of a bad config, which a restart won't fix
+    Failed,
+    /// A filesystem's fuse loop stopped while it was still mounted
+    Crashed,
+}
+
+impl MountExit {
+    pub fn code(self) -> i32 {
+        match self {
+            MountExit::Done => 0,
+            MountExit::Failed => 1,
+            MountExit::Crashed => 75,
+        }
+    }
+
+    /// The exit that the child's exit `code` means, where `None` is a child that was killed by a signal
+    pub fn from_code(code: Option<i32>) -> Self {
+        match code {
+            Some(0) => MountExit::Done,
+            Some(1) => MountExit::Failed,
+            _ => MountExit::Crashed,
+        }
+    }
+}
+
 fn id_validator(v: String) -> ValidatorResult {
     let _ = v
         .parse::<u32>()
@@ -32,6 +64,17 @@ fn perm_validator(v: String) -> ValidatorResult {
     Ok(())
 }
 
+pub(super) fn profile_validator(v: String) -> ValidatorResult {
+    if v.is_empty()
+        || !v
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(format!("{} is not a valid profile name", v));
+    }
+    Ok(())
+}
+
 pub(super) fn add_subcommands<'a, 'b>(
     app: clap::App<'a, 'b>,
     defaults: &'a ArgDefaults,
@@ -46,19 +89,30 @@ pub(super) fn add_subcommands<'a, 'b>(
             ),
     ).subcommand(
         SubCommand::with_name("mount")
-            .about("Mounts a Supertag collection")
+            .about("Mounts one or more Supertag collections, all served by a single process")
             .arg(
                 Arg::with_name("collection")
-                    .help("Supertag collection name, eg 'media_files'.  This will be the name of our mounted drive.")
-                    .required(true)
+                    .help("Supertag collection names, eg 'media_files'.  These will be the names of our mounted drives.")
+                    .required_unless("status")
+                    .multiple(true)
                     .takes_value(true),
             )
+            .arg(
+                Arg::with_name("status")
+                    .help("Instead of mounting, report whether the collections, or all collections if none are given, are mounted.")
+                    .long("--status"),
+            )
             .arg(
                 Arg::with_name("foreground")
                     .help("Don't run in the background as a daemon.")
                     .short("-f")
                     .long("--foreground"),
             )
+            .arg(
+                Arg::with_name("supervise")
+                    .help("Runs the mount in a child process, and restarts it, waiting longer after each crash in a row, if it panics or its filesystem stops answering.  Before a restart, the crashed mount is cleaned up, its warm cache snapshot is thrown away, and the database is checked.")
+                    .long("--supervise"),
+            )
             .arg(
                 Arg::with_name("uid")
                     .help("The UID of the mounted directory.  By default, the process owner is used.")
@@ -80,5 +134,55 @@ pub(super) fn add_subcommands<'a, 'b>(
                     .validator(perm_validator)
                     .long("--permissions"),
             )
+            .arg(
+                Arg::with_name("volname")
+                    .help("The name the mounted volume is shown with on macOS.  By default, the collection name.  Only for a single collection.")
+                    .takes_value(true)
+                    .long("--volname"),
+            )
+            .arg(
+                Arg::with_name("volicon")
+                    .help("An .icns icon for the mounted volume on macOS.  Only for a single collection.")
+                    .takes_value(true)
+                    .long("--volicon"),
+            )
+            .arg(
+                Arg::with_name("encrypted")
+                    .help("Encrypts the collection's database at rest, so that other users on the machine can't read its tags.  The key is taken from the system keyring, stored under the service 'supertag' with the collection as the account, or asked for.  Once a collection is encrypted, every command that opens it needs the key.  Needs supertag built with the 'encryption' feature.")
+                    .long("--encrypted"),
+            )
+            .arg(
+                Arg::with_name("trace")
+                    .help("Records every filesystem callback into this file, so that `tag replay` can reproduce a problem.  Only for a single collection.")
+                    .takes_value(true)
+                    .long("--trace"),
+            )
+            .arg(
+                Arg::with_name("log-format")
+                    .help("How the log is written.  json writes every filesystem callback as a span, with who made it, its path, how long it took and what it returned, for feeding to a log collector.")
+                    .takes_value(true)
+                    .possible_values(&["plain", "json"])
+                    .default_value("plain")
+                    .long("--log-format"),
+            )
+            .arg(
+                Arg::with_name("profile")
+                    .help("Merges the collection's profiles/<profile>.toml over its config, so that this mount can have its own symbols and display settings.  Symbols given with the flags below are saved to the profile instead of the collection's config.")
+                    .takes_value(true)
+                    .validator(profile_validator)
+                    .long("--profile"),
+            )
+            .arg(
+                Arg::with_name("filedir-symbol")
+                    .help("Overrides the symbol that filedirs are named with, and saves it to the collection's config or the mount's profile.  Refused if an existing name would be mistaken for it.  Only for a single collection.")
+                    .takes_value(true)
+                    .long("--filedir-symbol"),
+            )
+            .arg(
+                Arg::with_name("tag-group-symbol")
+                    .help("Overrides the symbol that tag group names end with, and saves it to the collection's config or the mount's profile.  Refused if an existing name would be mistaken for it.  Only for a single collection.")
+                    .takes_value(true)
+                    .long("--tag-group-symbol"),
+            )
     )
 }
@@ -22,9 +22,16 @@ pub(super) fn add_subcommands<'a, 'b>(app: clap::App<'a, 'b>) -> clap::App<'a, '
         // TODO better help
         SubCommand::with_name("mv")
             .about("Moves or merges tags")
+            .arg(
+                Arg::with_name("resolve")
+                    .help("How to settle files that merging would give the same name as a file already in the destination.  'keep-both' keeps both under their device/inode names, 'newest' keeps the most recently modified, 'source' keeps the merged file, and 'dest' leaves the merged file behind in its old tag.  By default you're asked about each one, or 'keep-both' is used if there's no terminal to ask on.")
+                    .long("--resolve")
+                    .takes_value(true)
+                    .possible_values(&["keep-both", "newest", "source", "dest", "prompt"]),
+            )
             .arg(
                 Arg::with_name("src")
-                    .help("The source tag or tagged file")
+                    .help("The source tag or tagged file.  A * or ? in the file name is matched against the database, moving every file in the filedir that it matches into dst, which must then be a filedir")
                     .required(true)
                     .takes_value(true),
             )
@@ -0,0 +1,43 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use clap::{Arg, SubCommand};
+
+pub(super) fn add_subcommands<'a, 'b>(app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+    app.subcommand(
+        SubCommand::with_name("open")
+            .about("Opens the file matching a tag expression with its default application, without browsing the mount.  If several files match, a name narrows them down, and a terminal is asked which one was meant.")
+            .arg(
+                Arg::with_name("collection")
+                    .help("Supertag collection name, eg 'media_files'.  Defaults to the collection of the current directory, or the primary collection.")
+                    .long("--collection")
+                    .short("c")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("expr")
+                    .help("Tags to intersect, eg 'rust -wip'.  Prefix a tag with '-' to exclude it, and join tags with '|' to match any of them.")
+                    .required(true)
+                    .allow_hyphen_values(true),
+            )
+            .arg(
+                Arg::with_name("name")
+                    .help("The file's name, or enough of it to fuzzily match it, eg 'rpt' for 'report.pdf'.")
+                    .takes_value(true),
+            ),
+    )
+}
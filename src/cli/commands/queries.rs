@@ -0,0 +1,91 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use crate::common::search::parse_age;
+use clap::{AppSettings, Arg, SubCommand};
+
+type ValidatorResult = Result<(), String>;
+
+fn age_validator(v: String) -> ValidatorResult {
+    parse_age(&v)
+        .map(|_| ())
+        .ok_or_else(|| format!("{} is not a valid age, eg '90m', '12h', '7d'", v))
+}
+
+fn collection_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("collection")
+        .help("Supertag collection name, eg 'media_files'.")
+        .required(true)
+        .takes_value(true)
+}
+
+pub(super) fn add_subcommands<'a, 'b>(app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+    app.subcommand(
+        SubCommand::with_name("queries")
+            .about("Manages saved searches, which appear as directories of their live results")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(
+                SubCommand::with_name("add")
+                    .about(
+                        "Saves a search under a name, replacing any search already saved under it",
+                    )
+                    .arg(
+                        Arg::with_name("ext")
+                            .help("Only match files with this extension, eg 'rs'.")
+                            .long("--ext")
+                            .takes_value(true),
+                    )
+                    .arg(
+                        Arg::with_name("newer_than")
+                            .help("Only match files modified within this long, eg '7d'.")
+                            .long("--newer-than")
+                            .takes_value(true)
+                            .validator(age_validator),
+                    )
+                    .arg(collection_arg())
+                    .arg(
+                        Arg::with_name("name")
+                            .help("The name of the search's directory, eg 'recent-rust'.")
+                            .required(true)
+                            .takes_value(true),
+                    )
+                    .arg(
+                        Arg::with_name("terms")
+                            .help("Tags to intersect, as 'tag search' takes them, eg 'rust -wip'.")
+                            .required(true)
+                            .multiple(true)
+                            .allow_hyphen_values(true),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("rm")
+                    .about("Removes a saved search")
+                    .arg(collection_arg())
+                    .arg(
+                        Arg::with_name("name")
+                            .help("The name of the search to remove.")
+                            .required(true)
+                            .takes_value(true),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("ls")
+                    .about("Lists the saved searches")
+                    .arg(collection_arg()),
+            ),
+    )
+}
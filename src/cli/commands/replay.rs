@@ -0,0 +1,37 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use clap::{Arg, SubCommand};
+
+pub(super) fn add_subcommands<'a, 'b>(app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+    app.subcommand(
+        SubCommand::with_name("replay")
+            .about("A developer tool that replays a trace recorded with `tag mount --trace` against a new collection, without mounting it, and reports every callback whose result differs from the trace.")
+            .arg(
+                Arg::with_name("trace")
+                    .help("The trace file to replay.")
+                    .required(true)
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("collection")
+                    .help("Name for the new collection to replay into, eg 'replay'.  It must not exist yet, and it's kept afterwards, so that it can be inspected or mounted.")
+                    .required(true)
+                    .takes_value(true),
+            ),
+    )
+}
@@ -0,0 +1,62 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use clap::{Arg, SubCommand};
+
+type ValidatorResult = Result<(), String>;
+
+fn number_validator(v: String) -> ValidatorResult {
+    let _ = v
+        .parse::<usize>()
+        .map_err(|_| format!("{} is not a valid number", v))?;
+    Ok(())
+}
+
+pub(super) fn add_subcommands<'a, 'b>(app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+    app.subcommand(
+        SubCommand::with_name("report-issue")
+            .about("Bundles logs, versions, config, and a database summary into a tarball for attaching to an issue.  Home directories and user names are scrubbed, and the contents are shown before anything is written.")
+            .arg(
+                Arg::with_name("logs")
+                    .help("How many of the most recent log files to include.")
+                    .long("--logs")
+                    .short("l")
+                    .takes_value(true)
+                    .validator(number_validator)
+                    .default_value("3"),
+            )
+            .arg(
+                Arg::with_name("output")
+                    .help("Where to write the tarball.  Defaults to a timestamped file in the current directory.")
+                    .long("--output")
+                    .short("o")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("yes")
+                    .help("Write the tarball without asking for confirmation.")
+                    .long("--yes")
+                    .short("y"),
+            )
+            .arg(
+                Arg::with_name("collection")
+                    .help("Supertag collection name, eg 'media_files'.")
+                    .required(true)
+                    .takes_value(true),
+            ),
+    )
+}
@@ -23,7 +23,7 @@ pub(super) fn add_subcommands<'a, 'b>(app: clap::App<'a, 'b>) -> clap::App<'a, '
             .about("Removes the last tag in a path from a specific file.")
             .arg(
                 Arg::with_name("file")
-                    .help("The file path to remove from the tags in the path")
+                    .help("The file path to remove from the tags in the path.  A * or ? in the file name is matched against the database, removing every file in the filedir that it matches")
                     .required(true)
                     .takes_value(true),
             ),
@@ -0,0 +1,47 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use clap::{Arg, SubCommand};
+
+pub(super) fn add_subcommands<'a, 'b>(app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+    app.subcommand(
+        SubCommand::with_name("search")
+            .about("Prints the files matching a tag expression, eg 'rust -wip +projects', without browsing the mount.")
+            .arg(
+                Arg::with_name("format")
+                    .help("How to print the matching files.")
+                    .long("--format")
+                    .short("f")
+                    .takes_value(true)
+                    .possible_values(&["plain", "json"])
+                    .default_value("plain"),
+            )
+            .arg(
+                Arg::with_name("collection")
+                    .help("Supertag collection name, eg 'media_files'.")
+                    .required(true)
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("terms")
+                    .help("Tags to intersect.  Prefix a tag with '-' to exclude it, and join tags with '|' to match any of them.")
+                    .required(true)
+                    .multiple(true)
+                    .allow_hyphen_values(true),
+            ),
+    )
+}
@@ -0,0 +1,41 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use clap::{AppSettings, Arg, SubCommand};
+
+pub(super) fn add_subcommands<'a, 'b>(app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+    let collection = Arg::with_name("collection")
+        .help("Supertag collection name, eg 'media_files'")
+        .required(true)
+        .takes_value(true);
+
+    app.subcommand(
+        SubCommand::with_name("service")
+            .about("Mounts a collection at login, with a systemd user unit on Linux or a launchd agent on macOS")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(
+                SubCommand::with_name("install")
+                    .about("Installs and starts the service that mounts the collection.  Installing it again replaces it.  With --dry-run, prints it instead.")
+                    .arg(collection.clone()),
+            )
+            .subcommand(
+                SubCommand::with_name("uninstall")
+                    .about("Stops and removes the service that mounts the collection, if it's installed")
+                    .arg(collection),
+            ),
+    )
+}
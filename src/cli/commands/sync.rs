@@ -0,0 +1,66 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use clap::{Arg, SubCommand};
+
+pub(super) fn add_subcommands<'a, 'b>(app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+    app.subcommand(
+        SubCommand::with_name("sync")
+            .about("Syncs a collection with the same collection on another machine, over ssh.  Files are matched up by path, or by contents if they've moved, and whichever machine changed a file's tags last wins.  Files removed on one machine are removed on the other, and files that only exist on one machine are left alone on the other.")
+            .arg(
+                Arg::with_name("collection")
+                    .help("Supertag collection name, eg 'media_files'.")
+                    .required(true)
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("remote")
+                    .help("The other machine, as an ssh destination like 'amy@laptop', optionally followed by ':collection' if the collection has a different name there.")
+                    .required_unless("serve")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("remap")
+                    .help("Replaces the path prefix OLD on the other machine with NEW on this one, like /home/amy=/Users/amy.  Can be given more than once.")
+                    .long("--remap")
+                    .short("r")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1),
+            )
+            .arg(
+                Arg::with_name("ssh")
+                    .help("The ssh command to reach the other machine with.")
+                    .long("--ssh")
+                    .takes_value(true)
+                    .default_value("ssh"),
+            )
+            .arg(
+                Arg::with_name("remote-tag")
+                    .help("The path of the tag command on the other machine, if it isn't on its PATH.")
+                    .long("--remote-tag")
+                    .takes_value(true)
+                    .default_value("tag"),
+            )
+            .arg(
+                Arg::with_name("serve")
+                    .help("Speaks the sync protocol on stdin and stdout, as the other end of a sync.  This is what a sync runs on the other machine.")
+                    .long("--serve")
+                    .hidden(true),
+            ),
+    )
+}
@@ -0,0 +1,45 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use clap::{Arg, SubCommand};
+
+pub(super) fn add_subcommands<'a, 'b>(app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+    app.subcommand(
+        SubCommand::with_name("verify")
+            .about("Re-hashes the collection's managed files and reports any that are missing or have changed since they were linked.  Exits with an error if any have.")
+            .arg(
+                Arg::with_name("format")
+                    .help("How to print the results.")
+                    .long("--format")
+                    .short("f")
+                    .takes_value(true)
+                    .possible_values(&["plain", "json"])
+                    .default_value("plain"),
+            )
+            .arg(
+                Arg::with_name("record")
+                    .help("Record checksums for managed files that were linked before checksums were recorded.")
+                    .long("--record"),
+            )
+            .arg(
+                Arg::with_name("collection")
+                    .help("Supertag collection name, eg 'media_files'.")
+                    .required(true)
+                    .takes_value(true),
+            ),
+    )
+}
@@ -0,0 +1,47 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use clap::{Arg, SubCommand};
+
+pub(super) fn add_subcommands<'a, 'b>(app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+    app.subcommand(
+        SubCommand::with_name("watch")
+            .about("Links every new file that appears in a directory to tags, until interrupted")
+            .arg(
+                Arg::with_name("dir")
+                    .help("The directory to watch. It can be a relative path.")
+                    .required(true)
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("tags")
+                    .long_help(
+                        r#"
+The tags to link to every new file.  These are joined into a tag path, so they can also be given in path form, like
+with `tag ln`:
+    screenshots unsorted
+    screenshots/unsorted
+    /mnt/supertag/myfiles/screenshots/unsorted
+"#
+                        .trim(),
+                    )
+                    .required(true)
+                    .min_values(1)
+                    .takes_value(true),
+            ),
+    )
+}
@@ -0,0 +1,250 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use super::CLI_TAG;
+use crate::common::constants::LN_CHUNK_SIZE;
+use crate::common::err::{STagError, STagResult};
+use crate::common::fsops;
+use crate::common::notify::Notifier;
+use crate::common::settings::Settings;
+use crate::common::types::file_perms::UMask;
+use crate::sql;
+use libc::{gid_t, uid_t};
+use rusqlite::Connection;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// The most tags that a single demo file is given
+const MAX_TAGS_PER_FILE: usize = 5;
+
+/// Describes a synthetic collection.  The same spec always generates the same files with the same tags, so that it can
+/// be shared in place of a private collection when reproducing a problem.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DemoSpec {
+    pub files: usize,
+    pub tags: usize,
+    pub seed: u64,
+}
+
+impl fmt::Display for DemoSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "--files {} --tags {} --seed {}",
+            self.files, self.tags, self.seed
+        )
+    }
+}
+
+impl DemoSpec {
+    /// Where the demo files go when no directory is given
+    pub fn default_dir(&self) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "supertag-demo-{}-{}-{}",
+            self.files, self.tags, self.seed
+        ))
+    }
+
+    fn tag_name(&self, rank: usize) -> String {
+        format!("tag{:0width$}", rank, width = digits(self.tags))
+    }
+
+    fn file_name(&self, idx: usize) -> String {
+        format!("file{:0width$}.txt", idx, width = digits(self.files))
+    }
+
+    /// The tag ranks for every file, in file order.  Tags are drawn from a Zipfian distribution, so that like a real
+    /// collection, a few tags are on most files and most tags are on a few.
+    pub fn plan(&self) -> Vec<BTreeSet<usize>> {
+        let mut rng = SplitMix64(self.seed);
+
+        // cumulative 1/rank weights, for sampling a rank by binary search
+        let mut cumulative = Vec::with_capacity(self.tags);
+        let mut total = 0.0;
+        for rank in 1..=self.tags {
+            total += 1.0 / rank as f64;
+            cumulative.push(total);
+        }
+
+        let per_file = MAX_TAGS_PER_FILE.min(self.tags);
+        (0..self.files)
+            .map(|_| {
+                let mut ranks = BTreeSet::new();
+                if per_file == 0 {
+                    return ranks;
+                }
+                let wanted = 1 + (rng.next() % per_file as u64) as usize;
+                while ranks.len() < wanted {
+                    let point = rng.next_f64() * total;
+                    let rank = match cumulative.binary_search_by(|w| w.partial_cmp(&point).unwrap())
+                    {
+                        Ok(idx) => idx + 1,
+                        Err(idx) => idx,
+                    };
+                    ranks.insert(rank.min(self.tags - 1));
+                }
+                ranks
+            })
+            .collect()
+    }
+}
+
+/// What `demo` generated
+#[derive(Debug, Default)]
+pub struct DemoSummary {
+    pub files: usize,
+    pub tags: usize,
+}
+
+/// A small generator with a fixed algorithm, so that a seed generates the same collection regardless of which version
+/// of `rand` we were built with
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// A float in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+fn digits(n: usize) -> usize {
+    n.saturating_sub(1).to_string().len()
+}
+
+/// Writes the fake files for `spec` into `out_dir` and tags them into the current collection.  `out_dir` is created if
+/// needed, and must be empty, so that a collection is never generated on top of real files.
+pub fn demo<P: AsRef<Path>, N: Notifier>(
+    settings: &Settings,
+    conn: &mut Connection,
+    spec: &DemoSpec,
+    out_dir: P,
+    uid: uid_t,
+    gid: gid_t,
+    umask: &UMask,
+    notifier: &N,
+) -> STagResult<DemoSummary> {
+    let out_dir = out_dir.as_ref();
+    info!(target: CLI_TAG, "Generating demo {} into {:?}", spec, out_dir);
+
+    if let Some(col) = settings.collection_from_path(out_dir, false) {
+        return Err(STagError::Other(
+            format!("{} is inside of collection {}", out_dir.display(), col).into(),
+        ));
+    }
+    if out_dir.exists() && std::fs::read_dir(out_dir)?.next().is_some() {
+        return Err(STagError::Other(
+            format!("{} isn't empty", out_dir.display()).into(),
+        ));
+    }
+    std::fs::create_dir_all(out_dir)?;
+    let out_dir = out_dir.canonicalize()?;
+
+    let plan = spec.plan();
+    let mut used = BTreeSet::new();
+    for (chunk_idx, ranks_chunk) in plan.chunks(LN_CHUNK_SIZE).enumerate() {
+        let tx = sql::begin_write(conn)?;
+        for (offset, ranks) in ranks_chunk.iter().enumerate() {
+            let idx = chunk_idx * LN_CHUNK_SIZE + offset;
+            let src = out_dir.join(spec.file_name(idx));
+            // distinct contents, so that deduplicating tools don't collapse the files
+            std::fs::write(&src, format!("supertag demo file {} of {}\n", idx, spec))?;
+
+            if ranks.is_empty() {
+                continue;
+            }
+            let rel_dst: PathBuf = ranks.iter().map(|rank| spec.tag_name(*rank)).collect();
+            let primary_tag = spec.file_name(idx);
+            fsops::ln(
+                settings,
+                &tx,
+                &src,
+                &rel_dst,
+                &primary_tag,
+                uid,
+                gid,
+                umask,
+                None,
+                notifier,
+            )?;
+            used.extend(ranks.iter().copied());
+        }
+        tx.commit()?;
+    }
+
+    let mountpoint = settings.mountpoint(&settings.get_collection());
+    for rank in &used {
+        fsops::flush_path(mountpoint.join(spec.tag_name(*rank)), settings);
+    }
+
+    info!(
+        target: CLI_TAG,
+        "Generated {} files with {} tags",
+        plan.len(),
+        used.len()
+    );
+    Ok(DemoSummary {
+        files: plan.len(),
+        tags: used.len(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan_is_deterministic() {
+        let spec = DemoSpec {
+            files: 200,
+            tags: 30,
+            seed: 7,
+        };
+        assert_eq!(spec.plan(), spec.plan());
+        assert_ne!(spec.plan(), DemoSpec { seed: 8, ..spec }.plan());
+    }
+
+    #[test]
+    fn test_plan_is_zipfian() {
+        let spec = DemoSpec {
+            files: 2000,
+            tags: 50,
+            seed: 1,
+        };
+        let mut counts = vec![0; spec.tags];
+        for ranks in spec.plan() {
+            assert!(!ranks.is_empty() && ranks.len() <= MAX_TAGS_PER_FILE);
+            for rank in ranks {
+                counts[rank] += 1;
+            }
+        }
+        // the most popular tag is on far more files than the least popular ones
+        assert!(counts[0] > counts[1]);
+        assert!(counts[0] > 5 * counts[spec.tags - 1]);
+        assert_eq!(spec.tag_name(3), "tag03");
+        assert_eq!(spec.file_name(3), "file0003.txt");
+    }
+}
@@ -0,0 +1,78 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Shell globs over a filedir are expanded by listing it through the mount, which is slow for big tag intersections.
+//! These expand a glob in the last component of a filedir path straight from the database instead.
+
+use super::CLI_TAG;
+use crate::common::err::{STagError, STagResult};
+use crate::common::glob_matches;
+use crate::common::settings::Settings;
+use crate::common::types::TagType;
+use crate::sql;
+use rusqlite::Connection;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// Whether the last component of `path` is a glob that should be expanded with `expand`
+pub fn is_glob(path: &Path) -> bool {
+    path.file_name().map_or(false, |name| {
+        name.to_string_lossy().contains(&['*', '?'][..])
+    })
+}
+
+/// Expands the glob at the end of `pattern`, which must be in a filedir, into the paths of the files in that filedir
+/// whose displayed names match it.  The paths are inodified, so that each one names exactly one file.
+pub fn expand<P: AsRef<Path>>(
+    settings: &Settings,
+    conn: &Connection,
+    mountpoint: P,
+    pattern: &Path,
+) -> STagResult<Vec<PathBuf>> {
+    let invalid = || STagError::InvalidPath(pattern.to_owned());
+    let glob = pattern.file_name().ok_or_else(invalid)?.to_string_lossy();
+    let filedir = pattern.parent().ok_or_else(invalid)?;
+
+    let mut tags = settings.path_to_tags(super::strip_prefix(filedir, mountpoint.as_ref()));
+    if tags.pop() != Some(TagType::FileDir) {
+        return Err(invalid());
+    }
+    let collection_tag = settings.collection_tag();
+    let tags: Vec<TagType> = tags
+        .into_iter()
+        .map(|tag| match tag {
+            TagType::Regular(name) if collection_tag.as_deref() == Some(name.as_str()) => {
+                TagType::CollectionTag(name)
+            }
+            tag => tag,
+        })
+        .collect();
+
+    let matched: Vec<PathBuf> = sql::files_tagged_with(conn, &tags)?
+        .into_iter()
+        .filter(|tf| glob_matches(&glob, &settings.display_name(&tf.primary_tag)))
+        .map(|tf| filedir.join(settings.inodify_filename(&tf.primary_tag, tf.device, tf.inode)))
+        .collect();
+    info!(
+        target: CLI_TAG,
+        "Glob {} matched {} files",
+        pattern.display(),
+        matched.len()
+    );
+    Ok(matched)
+}
@@ -0,0 +1,54 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use super::TAG;
+use crate::common::settings::Settings;
+use crate::{common, sql};
+use clap::ArgMatches;
+use std::error::Error;
+use tracing::info;
+
+pub fn handle(args: &ArgMatches, mut settings: Settings) -> Result<(), Box<dyn Error>> {
+    info!(target: TAG, "Running alias");
+    let col = args.value_of("collection").expect("Collection required!");
+    settings.set_collection(col, true);
+
+    let mut conn = sql::db_for_collection(&settings, col)?;
+    sql::migrations::migrate(&mut conn, &common::version_str())?;
+
+    // aliasing an alias is the same as aliasing its canonical tag
+    let tag = sql::resolve_alias(&conn, args.value_of("tag").expect("Tag required!"))?;
+    let tag_id = sql::get_tag_id(&conn, &tag)?.ok_or(format!("Tag {} doesn't exist", tag))?;
+
+    let aliases = args.values_of("aliases").map(|v| v.collect::<Vec<_>>());
+    if let Some(aliases) = aliases {
+        let tx = sql::begin_write(&mut conn)?;
+        let now = sql::get_now_secs();
+        for alias in aliases {
+            if sql::get_tag_id(&tx, alias)?.is_some() {
+                return Err(format!("{} is already a tag, it can't be an alias", alias).into());
+            }
+            sql::add_alias(&tx, alias, tag_id, now)?;
+        }
+        tx.commit()?;
+    }
+
+    for alias in sql::aliases_for_tag(&conn, &tag)? {
+        println!("{} -> {}", alias, tag);
+    }
+    Ok(())
+}
@@ -0,0 +1,303 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use super::TAG;
+use crate::common::settings::{persist_config_value, Settings};
+use crate::common::types::cli::CliError;
+use crate::platform::{self, MountState};
+use clap::ArgMatches;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// How long a daemon gets to let go of its old mountpoint, or to come up at its new one
+const MOUNT_WAIT: Duration = Duration::from_secs(10);
+
+pub fn handle(args: &ArgMatches, settings: Settings) -> Result<(), Box<dyn Error>> {
+    info!(target: TAG, "Running collection");
+    match args.subcommand() {
+        ("move-mountpoint", Some(sub_args)) => handle_move_mountpoint(sub_args, settings),
+        _ => Err("Command not found".into()),
+    }
+}
+
+/// Polls `done` until it's true or `MOUNT_WAIT` has passed, returning whether it became true
+fn wait_for(mut done: impl FnMut() -> bool) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < MOUNT_WAIT {
+        if done() {
+            return true;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    done()
+}
+
+/// What moving a mountpoint does to the running system, apart from rewriting the collection's files, so that the
+/// move can be tested without mounting anything
+trait MountOps {
+    /// Unmounts `col` if it's mounted, and waits for its daemon to let go.  Returns whether it was mounted.
+    fn unmount(&self, col: &str, pid_file: &Path) -> Result<bool, Box<dyn Error>>;
+
+    /// Mounts `col` again, as its config is on disk now, and waits for it to show up at `mountpoint`
+    fn remount(&self, col: &str, mountpoint: &Path) -> Result<(), Box<dyn Error>>;
+
+    /// Where the service that mounts `col` at login is installed, if it is
+    fn service_file(&self, col: &str) -> std::io::Result<PathBuf>;
+}
+
+struct SystemMounts<'a> {
+    profile: Option<&'a str>,
+}
+
+impl<'a> MountOps for SystemMounts<'a> {
+    fn unmount(&self, col: &str, pid_file: &Path) -> Result<bool, Box<dyn Error>> {
+        let was_mounted = match platform::mount_state(col, pid_file)? {
+            MountState::Unmounted => false,
+            MountState::Mounted { mountpoint, .. } => {
+                println!("Unmounting {} from {:?}", col, mountpoint);
+                platform::unmount(&mountpoint)?;
+                true
+            }
+            MountState::Stale { mountpoint, reason } => {
+                println!(
+                    "Cleaning up a stale mount at {:?}, because {}",
+                    mountpoint, reason
+                );
+                platform::force_unmount(&mountpoint)?;
+                let _ = std::fs::remove_file(pid_file);
+                true
+            }
+        };
+        if was_mounted && !wait_for(|| !platform::daemon_running(pid_file)) {
+            return Err(format!("The daemon serving {} didn't shut down", col).into());
+        }
+        Ok(was_mounted)
+    }
+
+    /// A collection with a service is restarted through it, so that it stays managed by the service.  Otherwise it's
+    /// mounted in the background with a new process of ourselves.
+    fn remount(&self, col: &str, mountpoint: &Path) -> Result<(), Box<dyn Error>> {
+        let service_file = self.service_file(col)?;
+        if service_file.exists() {
+            info!(target: TAG, "Remounting {} with its service {:?}", col, service_file);
+            platform::install_service(&service_file, &std::fs::read_to_string(&service_file)?)?;
+        } else {
+            let mut cmd = Command::new(std::env::current_exe()?);
+            cmd.arg("mount").arg(col);
+            if let Some(profile) = self.profile {
+                cmd.arg("--profile").arg(profile);
+            }
+            info!(target: TAG, "Remounting {} with {:?}", col, cmd);
+            let status = cmd.status()?;
+            if !status.success() {
+                return Err(format!("mount exited with {}", status).into());
+            }
+        }
+
+        let mounted = wait_for(|| {
+            platform::mounted_collections()
+                .map(|mounted| mounted.get(col).map(PathBuf::from).as_deref() == Some(mountpoint))
+                .unwrap_or(false)
+        });
+        if !mounted {
+            return Err(format!("{} didn't show up at {:?}", col, mountpoint).into());
+        }
+        Ok(())
+    }
+
+    fn service_file(&self, col: &str) -> std::io::Result<PathBuf> {
+        platform::service_file(col)
+    }
+}
+
+fn handle_move_mountpoint(args: &ArgMatches, settings: Settings) -> Result<(), Box<dyn Error>> {
+    info!(target: TAG, "Running collection move-mountpoint");
+    let col = args.value_of("collection").expect("Collection required!");
+    let base_dir = PathBuf::from(args.value_of("base_dir").expect("Base dir required!"));
+    let ops = SystemMounts {
+        profile: args.value_of("profile"),
+    };
+    move_mountpoint(&settings, col, &base_dir, &ops)
+}
+
+/// Points `col` at a new `mount.base_dir`.  A mounted collection is unmounted, its daemon is waited on so that it
+/// can't clean up after the new one, and it's remounted at the new mountpoint.  An installed service is rewritten
+/// along with the config.  If the collection doesn't come up at the new mountpoint, its config and service are put
+/// back as they were and it's remounted where it was.
+fn move_mountpoint<M: MountOps>(
+    settings: &Settings,
+    col: &str,
+    base_dir: &Path,
+    ops: &M,
+) -> Result<(), Box<dyn Error>> {
+    let col_settings = settings.for_collection(col);
+    let config_file = col_settings.config_file(col);
+    if !config_file.exists() {
+        return Err(format!(
+            "No collection {} at {:?}",
+            col,
+            col_settings.collection_dir(col)
+        )
+        .into());
+    }
+    let old_mountpoint = col_settings.mountpoint(col);
+    let new_mountpoint = base_dir.join(col);
+    if old_mountpoint == new_mountpoint {
+        return Err(format!("{} already mounts at {:?}", col, new_mountpoint).into());
+    }
+
+    // only on linux do we have to mount over an existing directory
+    if cfg!(target_os = "linux") && !new_mountpoint.exists() {
+        std::fs::create_dir_all(&new_mountpoint)
+            .map_err(|_| CliError::InvalidMountDir(new_mountpoint.clone()))?;
+    }
+
+    let was_mounted = ops.unmount(col, &col_settings.pid_file(col))?;
+
+    let old_config = std::fs::read_to_string(&config_file)?;
+    let service_file = ops.service_file(col)?;
+    let old_service = std::fs::read_to_string(&service_file).ok();
+    persist_config_value(
+        &config_file,
+        "mount",
+        "base_dir",
+        &base_dir.to_string_lossy(),
+    )?;
+    if old_service.is_some() {
+        let contents = platform::service_contents(&std::env::current_exe()?, col);
+        std::fs::write(&service_file, contents)?;
+    }
+    println!("Moved {} to {:?}", col, new_mountpoint);
+
+    if was_mounted {
+        if let Err(e) = ops.remount(col, &new_mountpoint) {
+            warn!(target: TAG, "Couldn't remount {} at {:?}: {}", col, new_mountpoint, e);
+            std::fs::write(&config_file, old_config)?;
+            if let Some(old_service) = old_service {
+                std::fs::write(&service_file, old_service)?;
+            }
+            ops.remount(col, &old_mountpoint)?;
+            return Err(CliError::RemountFailed(new_mountpoint, old_mountpoint).into());
+        }
+        println!("Mounted {} at {:?}", col, new_mountpoint);
+    }
+
+    // the old mountpoint is only our leftover if nothing else was put in it
+    if cfg!(target_os = "linux") && std::fs::remove_dir(&old_mountpoint).is_ok() {
+        info!(target: TAG, "Removed old mountpoint {:?}", old_mountpoint);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testing::{self, COLLECTION};
+    use std::cell::RefCell;
+
+    const OLD_SERVICE: &str = "[Service]\nExecStart=tag mount --foreground old\n";
+
+    /// Stands in for a system where the collection is mounted, and where mounting it at `broken` fails
+    struct FakeMounts {
+        service_file: PathBuf,
+        broken: Option<PathBuf>,
+        remounts: RefCell<Vec<PathBuf>>,
+    }
+
+    impl MountOps for FakeMounts {
+        fn unmount(&self, _col: &str, _pid_file: &Path) -> Result<bool, Box<dyn Error>> {
+            Ok(true)
+        }
+
+        fn remount(&self, _col: &str, mountpoint: &Path) -> Result<(), Box<dyn Error>> {
+            self.remounts.borrow_mut().push(mountpoint.to_owned());
+            if self.broken.as_deref() == Some(mountpoint) {
+                Err("Mount failed".into())
+            } else {
+                Ok(())
+            }
+        }
+
+        fn service_file(&self, _col: &str) -> std::io::Result<PathBuf> {
+            Ok(self.service_file.clone())
+        }
+    }
+
+    /// Settings for a collection with a config file and an installed service, and the fake system it's mounted on
+    fn setup(
+        dir: &Path,
+        broken: Option<PathBuf>,
+    ) -> Result<(Settings, FakeMounts), Box<dyn Error>> {
+        let settings = testing::settings(dir, &[]).for_collection(COLLECTION);
+        std::fs::write(settings.config_file(COLLECTION), "[mount]\n")?;
+        let service_file = dir.join("supertag-test.service");
+        std::fs::write(&service_file, OLD_SERVICE)?;
+        let ops = FakeMounts {
+            service_file,
+            broken,
+            remounts: Default::default(),
+        };
+        Ok((settings, ops))
+    }
+
+    #[test]
+    fn test_move_mountpoint() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let new_base = dir.path().join("new");
+        let (settings, ops) = setup(dir.path(), None)?;
+
+        move_mountpoint(&settings, COLLECTION, &new_base, &ops)?;
+        assert_eq!(*ops.remounts.borrow(), vec![new_base.join(COLLECTION)]);
+        assert_eq!(
+            settings.for_collection(COLLECTION).mountpoint(COLLECTION),
+            new_base.join(COLLECTION)
+        );
+        assert_eq!(
+            std::fs::read_to_string(&ops.service_file)?,
+            platform::service_contents(&std::env::current_exe()?, COLLECTION)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_move_mountpoint_rollback() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let new_base = dir.path().join("new");
+        let (settings, ops) = setup(dir.path(), Some(new_base.join(COLLECTION)))?;
+        let old_mountpoint = settings.mountpoint(COLLECTION);
+        let config_file = settings.config_file(COLLECTION);
+        let old_config = std::fs::read_to_string(&config_file)?;
+
+        assert!(move_mountpoint(&settings, COLLECTION, &new_base, &ops).is_err());
+        // it's mounted back where it was, with everything as it was
+        assert_eq!(
+            *ops.remounts.borrow(),
+            vec![new_base.join(COLLECTION), old_mountpoint.clone()]
+        );
+        assert_eq!(std::fs::read_to_string(&config_file)?, old_config);
+        assert_eq!(std::fs::read_to_string(&ops.service_file)?, OLD_SERVICE);
+        assert_eq!(
+            settings.for_collection(COLLECTION).mountpoint(COLLECTION),
+            old_mountpoint
+        );
+        Ok(())
+    }
+}
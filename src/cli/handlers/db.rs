@@ -0,0 +1,98 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use super::TAG;
+use crate::common::control;
+use crate::common::settings::Settings;
+use crate::{common, sql};
+use clap::ArgMatches;
+use std::error::Error;
+use tracing::info;
+
+pub fn handle(args: &ArgMatches, settings: Settings) -> Result<(), Box<dyn Error>> {
+    info!(target: TAG, "Running db");
+    match args.subcommand() {
+        ("schema", Some(sub_args)) => handle_schema(sub_args, settings),
+        ("gate", Some(sub_args)) => handle_gate(sub_args, settings),
+        _ => Err("Command not found".into()),
+    }
+}
+
+fn handle_schema(args: &ArgMatches, mut settings: Settings) -> Result<(), Box<dyn Error>> {
+    info!(target: TAG, "Running db schema");
+
+    // without a collection, we describe the schema that this release would create
+    let conn = match args.value_of("collection") {
+        Some(col) => {
+            settings.set_collection(col, true);
+            let db_file = settings.db_file(col);
+            if !db_file.exists() {
+                return Err(format!("No database for collection {} at {:?}", col, db_file).into());
+            }
+            sql::db_for_collection(&settings, col)?
+        }
+        None => sql::schema::reference_db()?,
+    };
+
+    print!("{}", sql::schema::describe(&conn)?);
+    Ok(())
+}
+
+/// The holder for gates closed by hand, so that closing it again replaces the reason instead of stacking up
+const MANUAL_HOLDER: &str = "manual";
+
+fn handle_gate(args: &ArgMatches, mut settings: Settings) -> Result<(), Box<dyn Error>> {
+    info!(target: TAG, "Running db gate");
+    let col = args.value_of("collection").expect("Collection required!");
+    settings.set_collection(col, true);
+
+    let mut conn = sql::db_for_collection(&settings, col)?;
+    sql::migrations::migrate(&mut conn, &common::version_str())?;
+
+    if let Some(reason) = args.value_of("close") {
+        let pid = std::process::id() as i32;
+        sql::gate::close_gate(&conn, MANUAL_HOLDER, reason, pid, sql::get_now_secs())?;
+        control::sync_write_gate(&settings, col)?;
+    } else if args.is_present("open") {
+        sql::gate::clear_gate(&conn)?;
+        control::sync_write_gate(&settings, col)?;
+    }
+
+    let holders = sql::gate::gate_holders(&conn)?;
+    if holders.is_empty() {
+        println!("{}: open", col);
+    }
+    for holder in holders {
+        println!(
+            "{}: closed by pid {} since {}: {}",
+            col, holder.pid, holder.ts, holder.reason
+        );
+    }
+
+    // the daemon's copy of the gate only changes through the control socket, so it's worth showing on its own
+    match control::send(
+        &settings.control_socket_file(col),
+        &control::ControlRequest::GateStatus,
+    ) {
+        Ok(control::ControlResponse::Gate(Some(reason))) => {
+            println!("{}: daemon is holding off writes: {}", col, reason)
+        }
+        Ok(_) => println!("{}: daemon is accepting writes", col),
+        Err(_) => println!("{}: no daemon is listening", col),
+    }
+    Ok(())
+}
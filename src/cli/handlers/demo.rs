@@ -0,0 +1,71 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use super::TAG;
+use crate::cli::demo::DemoSpec;
+use crate::common::notify::desktop::DesktopNotifier;
+use crate::common::settings::Settings;
+use crate::common::types::file_perms::UMask;
+use crate::{common, sql};
+use clap::{value_t, ArgMatches};
+use std::error::Error;
+use std::path::PathBuf;
+use tracing::info;
+
+pub fn handle(args: &ArgMatches, mut settings: Settings) -> Result<(), Box<dyn Error>> {
+    info!(target: TAG, "Running demo");
+    if settings.dry_run() {
+        return Err("--dry-run isn't supported by demo".into());
+    }
+    let col = args.value_of("collection").expect("Collection required!");
+    settings.set_collection(col, true);
+
+    let spec = DemoSpec {
+        files: value_t!(args, "files", usize)?,
+        tags: value_t!(args, "tags", usize)?,
+        seed: value_t!(args, "seed", u64)?,
+    };
+    let out_dir = args
+        .value_of("output")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| spec.default_dir());
+
+    // FIXME make a cli arg
+    let umask = UMask::default();
+    let uid = unsafe { libc::getuid() };
+    let gid = unsafe { libc::getgid() };
+
+    let mut conn = sql::db_for_collection(&settings, col)?;
+    sql::migrations::migrate(&mut conn, &common::version_str())?;
+    let notifier = DesktopNotifier::from_settings(&settings);
+
+    let summary = crate::demo(
+        &settings, &mut conn, &spec, &out_dir, uid, gid, &umask, &notifier,
+    )?;
+    println!(
+        "Generated {} files with {} tags in {}, tagged into collection {}",
+        summary.files,
+        summary.tags,
+        out_dir.display(),
+        col
+    );
+    println!(
+        "Regenerate this collection elsewhere with: tag demo {} {}",
+        spec, col
+    );
+    Ok(())
+}
@@ -0,0 +1,97 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use super::TAG;
+use crate::common;
+use crate::common::doctor::{self, Outcome, Problem};
+use crate::common::settings::Settings;
+use crate::sql;
+use clap::ArgMatches;
+use std::error::Error;
+use std::path::PathBuf;
+use tracing::info;
+
+pub fn handle(args: &ArgMatches, mut settings: Settings) -> Result<(), Box<dyn Error>> {
+    info!(target: TAG, "Running doctor");
+    let col = args.value_of("collection").expect("Collection required!");
+    settings.set_collection(col, true);
+
+    let db_file = settings.db_file(col);
+    if !db_file.exists() {
+        return Err(format!("No database for collection {} at {:?}", col, db_file).into());
+    }
+    let mut conn = sql::db_for_collection(&settings, col)?;
+    sql::migrations::migrate(&mut conn, &common::version_str())?;
+
+    let findings = doctor::scan(&conn)?;
+    for finding in &findings {
+        let problem = match finding.problem {
+            Problem::Missing => "missing",
+            Problem::Replaced { .. } => "replaced",
+        };
+        println!("{:<9} {}", problem, finding.file.path);
+    }
+    eprintln!("{} broken file(s)", findings.len());
+
+    let repair = match args.value_of("repair") {
+        Some(repair) if !findings.is_empty() => repair,
+        _ => return Ok(()),
+    };
+
+    let now = sql::get_now_secs();
+    let tx = sql::begin_write(&mut conn)?;
+    let outcomes = match repair {
+        "search" => {
+            let mut roots = settings.get_config().doctor.search_roots;
+            roots.extend(
+                args.values_of("root")
+                    .into_iter()
+                    .flatten()
+                    .map(PathBuf::from),
+            );
+            if roots.is_empty() {
+                return Err("No search roots, set doctor.search_roots or pass --root".into());
+            }
+            doctor::repair_by_search(&tx, &findings, &roots, now)?
+        }
+        "drop" => doctor::repair_by_drop(&tx, &findings, now)?,
+        _ => return Err(format!("Unknown repair mode {}", repair).into()),
+    };
+    tx.commit()?;
+
+    for (finding, outcome) in findings.iter().zip(outcomes.iter()) {
+        let path = &finding.file.path;
+        match outcome {
+            Outcome::Relocated(new_path) => {
+                println!("relocated {} -> {}", path, new_path.display())
+            }
+            Outcome::Reidentified => println!("adopted   {}", path),
+            Outcome::Dropped => println!("dropped   {}", path),
+            Outcome::Ambiguous(candidates) => {
+                println!("ambiguous {}, could be any of:", path);
+                for candidate in candidates {
+                    println!("            {}", candidate.display());
+                }
+            }
+            Outcome::NotFound => println!("not found {}", path),
+            Outcome::AlreadyTagged(other) => {
+                println!("skipped   {}, {} is already tagged", path, other.display())
+            }
+        }
+    }
+    Ok(())
+}
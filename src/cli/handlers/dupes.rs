@@ -0,0 +1,83 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use super::TAG;
+use crate::cli::progress::ProgressBar;
+use crate::common;
+use crate::common::dupes;
+use crate::common::settings::Settings;
+use crate::sql;
+use clap::ArgMatches;
+use std::error::Error;
+use tracing::info;
+
+pub fn handle(args: &ArgMatches, mut settings: Settings) -> Result<(), Box<dyn Error>> {
+    info!(target: TAG, "Running dupes");
+    let col = args.value_of("collection").expect("Collection required!");
+    settings.set_collection(col, true);
+
+    let db_file = settings.db_file(col);
+    if !db_file.exists() {
+        return Err(format!("No database for collection {} at {:?}", col, db_file).into());
+    }
+    let mut conn = sql::db_for_collection(&settings, col)?;
+    sql::migrations::migrate(&mut conn, &common::version_str())?;
+
+    // hashing reads every file, so it happens before the write transaction, to keep the collection writable meanwhile
+    let unhashed = sql::get_unhashed_files(&conn, args.is_present("rehash"))?;
+    if !unhashed.is_empty() {
+        let mut bar = if unsafe { libc::isatty(libc::STDERR_FILENO) == 1 } {
+            Some(ProgressBar::new(std::io::stderr(), unhashed.len()))
+        } else {
+            None
+        };
+        let hashed = dupes::hash_files(&unhashed, |done, file| {
+            if let Some(bar) = bar.as_mut() {
+                let _ = bar.update(done, &file.path);
+            }
+        });
+        if let Some(bar) = bar.as_mut() {
+            bar.finish()?;
+        }
+
+        let tx = sql::begin_write(&mut conn)?;
+        dupes::record(&tx, &hashed)?;
+        tx.commit()?;
+        eprintln!("Hashed {} of {} file(s)", hashed.len(), unhashed.len());
+    }
+
+    let duplicates = sql::get_duplicate_files(&conn)?;
+    let mut groups = 0;
+    let mut last_hash = None;
+    for (hash, file) in &duplicates {
+        if last_hash != Some(hash) {
+            if last_hash.is_some() {
+                println!();
+            }
+            println!("{}", hash);
+            groups += 1;
+            last_hash = Some(hash);
+        }
+        println!("  {}", file.path);
+    }
+    eprintln!(
+        "{} duplicate file(s) in {} group(s)",
+        duplicates.len(),
+        groups
+    );
+    Ok(())
+}
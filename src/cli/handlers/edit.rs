@@ -0,0 +1,84 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use super::TAG;
+use crate::common::settings::Settings;
+use crate::common::types::color::TagColor;
+use crate::{common, sql};
+use clap::ArgMatches;
+use std::error::Error;
+use tracing::info;
+
+/// Empty values clear an optional field
+fn non_empty(value: &str) -> Option<String> {
+    if value.is_empty() {
+        None
+    } else {
+        Some(value.to_string())
+    }
+}
+
+pub fn handle(args: &ArgMatches, mut settings: Settings) -> Result<(), Box<dyn Error>> {
+    info!(target: TAG, "Running edit");
+    let col = args.value_of("collection").expect("Collection required!");
+    settings.set_collection(col, true);
+
+    let mut conn = sql::db_for_collection(&settings, col)?;
+    sql::migrations::migrate(&mut conn, &common::version_str())?;
+
+    let tag = sql::resolve_alias(&conn, args.value_of("tag").expect("Tag required!"))?;
+    let tag_id = sql::get_tag_id(&conn, &tag)?.ok_or(format!("Tag {} doesn't exist", tag))?;
+    let mut meta = sql::get_tag_meta(&conn, &tag)?.unwrap_or_default();
+
+    let mut changed = false;
+    if let Some(color) = args.value_of("color") {
+        meta.color = match color {
+            "" | "none" => None,
+            _ => Some(color.parse::<TagColor>()?),
+        };
+        changed = true;
+    }
+    if let Some(icon) = args.value_of("icon") {
+        meta.icon = match non_empty(icon) {
+            Some(icon) => Some(std::fs::canonicalize(&icon)?.to_string_lossy().into_owned()),
+            None => None,
+        };
+        changed = true;
+    }
+    if let Some(description) = args.value_of("description") {
+        meta.description = non_empty(description);
+        changed = true;
+    }
+
+    if changed {
+        let tx = sql::begin_write(&mut conn)?;
+        sql::set_tag_meta(&tx, tag_id, &meta, sql::get_now_secs())?;
+        tx.commit()?;
+    }
+
+    println!("tag: {}", tag);
+    if let Some(color) = meta.color {
+        println!("color: {}", color);
+    }
+    if let Some(icon) = &meta.icon {
+        println!("icon: {}", icon);
+    }
+    if let Some(description) = &meta.description {
+        println!("description: {}", description);
+    }
+    Ok(())
+}
@@ -0,0 +1,98 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use super::TAG;
+use crate::common::settings::Settings;
+use crate::sql;
+use clap::ArgMatches;
+use serde::Serialize;
+use std::error::Error;
+use tracing::info;
+
+#[derive(Serialize)]
+struct EventResult<'a> {
+    id: i64,
+    op: &'a str,
+    tag_id: Option<i64>,
+    file_id: Option<i64>,
+    tag_group_id: Option<i64>,
+    tx_id: i64,
+    ts: String,
+}
+
+#[derive(Serialize)]
+struct EventPage<'a> {
+    /// The cursor to pass as `since` on the next poll
+    cursor: i64,
+    events: Vec<EventResult<'a>>,
+}
+
+fn fmt_id(id: Option<i64>) -> String {
+    id.map_or_else(|| "-".to_string(), |id| id.to_string())
+}
+
+pub fn handle(args: &ArgMatches, mut settings: Settings) -> Result<(), Box<dyn Error>> {
+    info!(target: TAG, "Running events");
+    let col = args.value_of("collection").expect("Collection required!");
+    settings.set_collection(col, true);
+
+    let since = args.value_of("since").unwrap_or("0").parse::<i64>()?;
+    let limit = args.value_of("limit").unwrap_or("1000").parse::<u32>()?;
+
+    let db_file = settings.db_file(col);
+    if !db_file.exists() {
+        return Err(format!("No database for collection {} at {:?}", col, db_file).into());
+    }
+    let conn = sql::db_for_collection(&settings, col)?;
+    let events = sql::events_since(&conn, since, limit)?;
+
+    match args.value_of("format") {
+        Some("json") => {
+            let page = EventPage {
+                cursor: events.last().map_or(since, |e| e.id),
+                events: events
+                    .iter()
+                    .map(|e| EventResult {
+                        id: e.id,
+                        op: &e.op,
+                        tag_id: e.tag_id,
+                        file_id: e.file_id,
+                        tag_group_id: e.tag_group_id,
+                        tx_id: e.tx_id,
+                        ts: e.ts.to_rfc3339(),
+                    })
+                    .collect(),
+            };
+            println!("{}", serde_json::to_string_pretty(&page)?);
+        }
+        _ => {
+            for e in events {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                    e.id,
+                    e.tx_id,
+                    e.ts.to_rfc3339(),
+                    e.op,
+                    fmt_id(e.tag_id),
+                    fmt_id(e.file_id),
+                    fmt_id(e.tag_group_id)
+                );
+            }
+        }
+    }
+    Ok(())
+}
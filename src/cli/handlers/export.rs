@@ -0,0 +1,48 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use super::TAG;
+use crate::common::archive::Archive;
+use crate::common::control;
+use crate::common::settings::Settings;
+use clap::ArgMatches;
+use std::error::Error;
+use std::path::Path;
+use tracing::info;
+
+pub fn handle(args: &ArgMatches, mut settings: Settings) -> Result<(), Box<dyn Error>> {
+    info!(target: TAG, "Running export");
+    let col = args.value_of("collection").expect("Collection required!");
+    let output = Path::new(args.value_of("archive").expect("Archive required!"));
+    settings.set_collection(col, true);
+
+    // held closed so the daemon's writes don't land between the tables we copy
+    let archive = control::with_write_gate(&settings, col, "backing up", || {
+        Ok(Archive::collect(&settings, col)?)
+    })?;
+    let compress = output.extension().map_or(false, |ext| ext == "zst");
+    let mut file = std::fs::File::create(output)?;
+    archive.write(&mut file, compress)?;
+
+    eprintln!(
+        "Exported {} tables and {} managed files",
+        archive.tables.len(),
+        archive.managed.len()
+    );
+    println!("{}", output.display());
+    Ok(())
+}
@@ -20,8 +20,8 @@ use super::TAG;
 use crate::common::settings::Settings;
 use crate::platform;
 use clap::ArgMatches;
-use log::info;
 use std::error::Error;
+use tracing::info;
 
 pub fn handle(_args: &ArgMatches, settings: Settings) -> Result<(), Box<dyn Error>> {
     info!(target: TAG, "Running fstab");
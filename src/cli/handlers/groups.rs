@@ -0,0 +1,79 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use super::TAG;
+use crate::common::settings::Settings;
+use crate::common::types::file_perms::UMask;
+use crate::{common, sql};
+use clap::ArgMatches;
+use std::error::Error;
+use tracing::info;
+
+pub fn handle(args: &ArgMatches, settings: Settings) -> Result<(), Box<dyn Error>> {
+    info!(target: TAG, "Running groups");
+    match args.subcommand() {
+        ("sync", Some(sub_args)) => handle_sync(sub_args, settings),
+        _ => Err("Command not found".into()),
+    }
+}
+
+fn handle_sync(args: &ArgMatches, mut settings: Settings) -> Result<(), Box<dyn Error>> {
+    info!(target: TAG, "Running groups sync");
+    let col = args.value_of("collection").expect("Collection required!");
+    settings.set_collection(col, true);
+
+    let db_file = settings.db_file(col);
+    if !db_file.exists() {
+        return Err(format!("No database for collection {} at {:?}", col, db_file).into());
+    }
+
+    // FIXME make a cli arg
+    let umask = UMask::default();
+    let uid = unsafe { libc::getuid() };
+    let gid = unsafe { libc::getgid() };
+
+    let mut conn = sql::db_for_collection(&settings, col)?;
+    sql::migrations::migrate(&mut conn, &common::version_str())?;
+
+    let tx = sql::begin_write(&mut conn)?;
+    let sync = sql::sync_tag_groups(
+        &tx,
+        &settings.get_config().groups,
+        uid,
+        gid,
+        &umask.dir_perms(),
+        sql::get_now_secs(),
+    )?;
+    tx.commit()?;
+
+    for (tag, group) in &sync.added {
+        println!("{} -> {}", tag, group);
+    }
+    for (tag, groups) in &sync.conflicts {
+        eprintln!(
+            "Conflict: {} matches rules for {}, not grouping it",
+            tag,
+            groups.join(", ")
+        );
+    }
+    println!(
+        "Grouped {} tags, {} conflicts",
+        sync.added.len(),
+        sync.conflicts.len()
+    );
+    Ok(())
+}
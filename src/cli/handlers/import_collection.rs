@@ -0,0 +1,71 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use super::TAG;
+use crate::cli::prompt::RemapPrompt;
+use crate::common::archive::{Archive, PathRemaps};
+use crate::common::settings::Settings;
+use clap::ArgMatches;
+use std::collections::HashSet;
+use std::error::Error;
+use tracing::info;
+
+pub fn handle(args: &ArgMatches, mut settings: Settings) -> Result<(), Box<dyn Error>> {
+    info!(target: TAG, "Running import-collection");
+    let archive_file = args.value_of("archive").expect("Archive required!");
+    let archive = Archive::read(&std::fs::read(archive_file)?)?;
+
+    let col = args
+        .value_of("collection")
+        .map(str::to_string)
+        .unwrap_or_else(|| archive.manifest.collection.clone());
+    if settings.db_file(&col).exists() {
+        return Err(format!("Collection {} already exists", col).into());
+    }
+    settings.set_collection(&col, true);
+
+    let mut remaps = PathRemaps::default();
+    for remap in args.values_of("remap").into_iter().flatten() {
+        remaps.add_arg(remap)?;
+    }
+
+    if !args.is_present("no-prompt") {
+        let stdin = std::io::stdin();
+        let mut prompt = RemapPrompt::new(stdin.lock(), std::io::stderr());
+
+        // each answer can resolve other missing directories too, so look again after every one
+        let mut asked = HashSet::new();
+        while let Some((dir, count)) = archive
+            .missing_dirs(&remaps)
+            .into_iter()
+            .find(|(dir, _count)| !asked.contains(dir))
+        {
+            if let Some(new_dir) = prompt.ask(&dir, count)? {
+                remaps.add(dir.clone(), new_dir);
+            }
+            asked.insert(dir);
+        }
+    }
+
+    let summary = archive.restore(&settings, &col, &remaps)?;
+    eprintln!(
+        "Imported {} tagged files, {} found on this machine and {} missing",
+        summary.files, summary.found, summary.missing
+    );
+    println!("{}", col);
+    Ok(())
+}
@@ -16,19 +16,25 @@
  * along with this program.  If not, see <http://www.gnu.org/licenses/>.
  */
 use super::TAG;
+use crate::cli::ln::{ln_tree_with_progress, ln_with_progress, tree_files, SubdirNames};
+use crate::cli::progress::ProgressBar;
 use crate::common::notify::desktop::DesktopNotifier;
+use crate::common::settings::config::HashMapSource;
 use crate::common::settings::Settings;
 use crate::common::types::file_perms::UMask;
 use crate::sql;
 use clap::{values_t, ArgMatches};
-use log::info;
 use std::error::Error;
+use std::io::Stderr;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use tracing::info;
 
 pub fn handle(args: &ArgMatches, mut settings: Settings) -> Result<(), Box<dyn Error>> {
     info!(target: TAG, "Running ln");
     let files = values_t!(args.values_of("file"), String).expect("file is required!");
-    let files = files.iter().map(Path::new).collect();
+    let files: Vec<&Path> = files.iter().map(Path::new).collect();
 
     // FIXME make a cli arg
     let umask = UMask::default();
@@ -37,22 +43,88 @@ pub fn handle(args: &ArgMatches, mut settings: Settings) -> Result<(), Box<dyn E
 
     let tag_path: PathBuf = args.value_of("path").expect("path is required!").into();
 
+    if args.is_present("children") {
+        let mut source = HashMapSource(Default::default());
+        source
+            .0
+            .insert("tagging.dir_children".to_string(), true.into());
+        settings.update_config(source);
+    }
+
     let col = settings.resolve_collection(&tag_path)?;
     let mut conn = sql::db_for_collection(&settings, &col)?;
     let mountpoint = settings.mountpoint(&col);
 
-    let notifier = DesktopNotifier::new(settings.notification_icon());
-
-    crate::ln(
-        &settings,
-        &mut conn,
-        &mountpoint,
-        files,
-        &tag_path,
-        uid,
-        gid,
-        &umask,
-        &notifier,
-    )?;
+    let notifier = DesktopNotifier::from_settings(&settings);
+
+    // Ctrl-C stops linking between files, instead of killing us partway through a chunk
+    let stop = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::SIGINT, Arc::clone(&stop))?;
+    signal_hook::flag::register(signal_hook::SIGTERM, Arc::clone(&stop))?;
+
+    let mut bar;
+    let summary = if args.is_present("recursive") {
+        let subdirs = match args.value_of("subdirs") {
+            Some("tags") => SubdirNames::Tags,
+            Some("prefix") => SubdirNames::Prefix,
+            _ => SubdirNames::Ignore,
+        };
+        let tree = tree_files(&settings, files, subdirs)?;
+        bar = new_bar(tree.len());
+        ln_tree_with_progress(
+            &settings,
+            &mut conn,
+            &mountpoint,
+            &tree,
+            &tag_path,
+            uid,
+            gid,
+            &umask,
+            &stop,
+            |done, file| update_bar(&mut bar, done, file),
+        )
+    } else {
+        bar = new_bar(files.len());
+        ln_with_progress(
+            &settings,
+            &mut conn,
+            &mountpoint,
+            files,
+            &tag_path,
+            uid,
+            gid,
+            &umask,
+            &notifier,
+            &stop,
+            |done, file| update_bar(&mut bar, done, file),
+        )
+    };
+    if let Some(bar) = bar.as_mut() {
+        bar.finish()?;
+    }
+
+    let summary = summary?;
+    if summary.interrupted() {
+        return Err(format!(
+            "Interrupted, linked {} of {} files. The rest were rolled back",
+            summary.linked, summary.total
+        )
+        .into());
+    }
     Ok(())
 }
+
+/// Progress is only drawn for a person watching, not into a pipe or a log
+fn new_bar(total: usize) -> Option<ProgressBar<Stderr>> {
+    if unsafe { libc::isatty(libc::STDERR_FILENO) == 1 } {
+        Some(ProgressBar::new(std::io::stderr(), total))
+    } else {
+        None
+    }
+}
+
+fn update_bar(bar: &mut Option<ProgressBar<Stderr>>, done: usize, file: &Path) {
+    if let Some(bar) = bar.as_mut() {
+        let _ = bar.update(done, &file.to_string_lossy());
+    }
+}
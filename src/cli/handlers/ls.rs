@@ -0,0 +1,120 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use super::TAG;
+use crate::common::settings::Settings;
+use crate::common::types::{TagType, UtcDt};
+use crate::sql;
+use crate::sql::types::{Tag, TaggedFile};
+use clap::ArgMatches;
+use rusqlite::Connection;
+use std::error::Error;
+use tracing::info;
+
+fn fmt_mtime(mtime: &UtcDt) -> String {
+    mtime
+        .with_timezone(&chrono::Local)
+        .format("%Y-%m-%d %H:%M")
+        .to_string()
+}
+
+pub fn handle(args: &ArgMatches, mut settings: Settings) -> Result<(), Box<dyn Error>> {
+    info!(target: TAG, "Running ls");
+    let col = args.value_of("collection").expect("Collection required!");
+    settings.set_collection(col, true);
+
+    let db_file = settings.db_file(col);
+    if !db_file.exists() {
+        return Err(format!("No database for collection {} at {:?}", col, db_file).into());
+    }
+    let conn = sql::db_for_collection(&settings, col)?;
+
+    let tag_path = args.value_of("tag-path").unwrap_or("");
+    let (subtags, files) = list(&settings, &conn, tag_path)?;
+
+    let long = args.is_present("long");
+    for tag in subtags {
+        if long {
+            println!(
+                "{:>8}  {}  {}/",
+                tag.num_files,
+                fmt_mtime(&tag.mtime),
+                tag.name
+            );
+        } else {
+            println!("{}/", tag.name);
+        }
+    }
+    for (name, tf) in files {
+        if long {
+            println!(
+                "{:>8}  {}  {} -> {}",
+                "",
+                fmt_mtime(&tf.mtime),
+                name,
+                tf.path
+            );
+        } else {
+            println!("{}", name);
+        }
+    }
+    Ok(())
+}
+
+/// The tags and files under `tag_path`, as the mount would list them: the tags in its tag directory, and the files in
+/// its filedir, each with the name it's listed under there
+pub fn list(
+    settings: &Settings,
+    conn: &Connection,
+    tag_path: &str,
+) -> Result<(Vec<Tag>, Vec<(String, TaggedFile)>), Box<dyn Error>> {
+    let collection_tag = settings.collection_tag();
+    let form = sql::tag_form(conn)?;
+    let mut tags = vec![];
+    // paths copied out of the mount can have filedirs in them, which don't change what's listed here
+    for tag in settings.path_to_tags(tag_path) {
+        match tag {
+            TagType::FileDir => {}
+            TagType::Regular(name) if collection_tag.as_deref() == Some(name.as_str()) => {
+                tags.push(TagType::CollectionTag(name))
+            }
+            TagType::Regular(ref name) | TagType::Negation(ref name) => {
+                if sql::get_tag_in_form(conn, form, name)?.is_none() {
+                    return Err(format!("No tag named {}", name).into());
+                }
+                tags.push(tag)
+            }
+            TagType::Symlink(_) | TagType::DeviceFileSymlink(_) => {
+                return Err(format!("{} is a file, not a tag path", tag_path).into());
+            }
+            _ => tags.push(tag),
+        }
+    }
+
+    let sort = settings.get_config().mount.sort;
+    let (subtags, files) = if tags.is_empty() {
+        (sql::get_all_tags_sorted(conn, sort)?, vec![])
+    } else {
+        (
+            sql::intersect_tag_sorted(conn, &tags, true, sort)?,
+            sql::files_tagged_with_sorted(conn, &tags, sort)?,
+        )
+    };
+
+    let names = settings.filedir_names(&files);
+    Ok((subtags, names.into_iter().zip(files).collect()))
+}
@@ -0,0 +1,57 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use super::TAG;
+use crate::cli::materialize::MaterializeMode;
+use crate::common::settings::Settings;
+use crate::sql;
+use clap::ArgMatches;
+use std::error::Error;
+use std::path::Path;
+use tracing::info;
+
+pub fn handle(args: &ArgMatches, mut settings: Settings) -> Result<(), Box<dyn Error>> {
+    info!(target: TAG, "Running materialize");
+    let col = args.value_of("collection").expect("Collection required!");
+    settings.set_collection(col, true);
+
+    let out_dir = Path::new(args.value_of("output").expect("Output required!"));
+    let terms = args
+        .values_of("terms")
+        .expect("Terms required!")
+        .collect::<Vec<_>>();
+    let tags = settings.query_to_tags(&terms)?;
+
+    let mode = match args.value_of("mode") {
+        Some("hardlink") => MaterializeMode::Hardlink,
+        Some("copy") => MaterializeMode::Copy,
+        _ => MaterializeMode::Symlink,
+    };
+
+    let db_file = settings.db_file(col);
+    if !db_file.exists() {
+        return Err(format!("No database for collection {} at {:?}", col, db_file).into());
+    }
+    let conn = sql::db_for_collection(&settings, col)?;
+
+    let summary = crate::materialize(&settings, &conn, &tags, out_dir, mode)?;
+    println!("Wrote {} files to {}", summary.written, out_dir.display());
+    for missing in &summary.missing {
+        println!("Skipped {}, which no longer exists", missing.display());
+    }
+    Ok(())
+}
@@ -0,0 +1,95 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use super::TAG;
+use crate::cli::prompt::CollisionPrompt;
+use crate::common::notify::desktop::DesktopNotifier;
+use crate::common::settings::Settings;
+use crate::common::types::file_perms::UMask;
+use crate::common::types::MergeResolution;
+use crate::{common, sql};
+use clap::ArgMatches;
+use std::collections::HashMap;
+use std::error::Error;
+use tracing::info;
+
+pub fn handle(args: &ArgMatches, mut settings: Settings) -> Result<(), Box<dyn Error>> {
+    info!(target: TAG, "Running merge");
+    let col = args.value_of("collection").expect("Collection required!");
+    let src = args.value_of("src").expect("src is required!");
+    let dst = args.value_of("dst").expect("dst is required!");
+    settings.set_collection(col, true);
+
+    // FIXME come in from cli
+    let umask = UMask::default();
+    let uid = unsafe { libc::getuid() };
+    let gid = unsafe { libc::getgid() };
+
+    let mut conn = sql::db_for_collection(&settings, col)?;
+    sql::migrations::migrate(&mut conn, &common::version_str())?;
+    let notifier = DesktopNotifier::from_settings(&settings);
+
+    // the same up front collision handling as `tag mv`
+    let interactive = match args.value_of("resolve") {
+        Some("prompt") => true,
+        Some(_) => false,
+        None => unsafe { libc::isatty(libc::STDIN_FILENO) == 1 },
+    };
+    let fixed = args
+        .value_of("resolve")
+        .and_then(MergeResolution::from_name)
+        .unwrap_or(MergeResolution::KeepBoth);
+
+    let mut decided = HashMap::new();
+    let stdin = std::io::stdin();
+    let mut prompt = CollisionPrompt::new(stdin.lock(), std::io::stderr());
+    for collision in &common::fsops::merge_collisions(&settings, &conn, src, dst)? {
+        let resolution = if interactive {
+            prompt.ask(collision)?
+        } else {
+            fixed
+        };
+        decided.insert((collision.source.id, collision.dest.id), resolution);
+    }
+
+    let num_files = crate::merge(
+        &settings,
+        &mut conn,
+        settings.mountpoint(col),
+        src,
+        dst,
+        uid,
+        gid,
+        &umask,
+        &notifier,
+        |collision| {
+            Ok(decided
+                .get(&(collision.source.id, collision.dest.id))
+                .copied()
+                .unwrap_or(MergeResolution::KeepBoth))
+        },
+    )?;
+    if settings.dry_run() {
+        println!(
+            "Would merge {} into {}, {} files moved",
+            src, dst, num_files
+        );
+    } else {
+        println!("Merged {} into {}, {} files moved", src, dst, num_files);
+    }
+    Ok(())
+}
@@ -0,0 +1,151 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use super::TAG;
+use crate::common::constants::META_TAG_SEPARATOR;
+use crate::common::identity;
+use crate::common::settings::Settings;
+use crate::{common, sql};
+use clap::ArgMatches;
+use rusqlite::Connection;
+use std::error::Error;
+use std::path::Path;
+use tracing::info;
+
+pub fn handle(args: &ArgMatches, settings: Settings) -> Result<(), Box<dyn Error>> {
+    match args.subcommand() {
+        ("set", Some(sub_args)) => set_tag_meta(sub_args, settings),
+        ("get", Some(sub_args)) => get_tag_meta(sub_args, settings),
+        _ => file_meta(args, settings),
+    }
+}
+
+/// Opens the collection named in `args`, and finds the id of the tagged file named in it
+fn open_file(
+    args: &ArgMatches,
+    settings: &mut Settings,
+) -> Result<(Connection, i64), Box<dyn Error>> {
+    let col = args.value_of("collection").expect("Collection required!");
+    settings.set_collection(col, true);
+
+    let mut conn = sql::db_for_collection(settings, col)?;
+    sql::migrations::migrate(&mut conn, &common::version_str())?;
+
+    let file = args.value_of("file").expect("File required!");
+    let (device, inode) = settings.file_identity(Path::new(file))?;
+    let file_id = identity::find_file(&conn, device, inode, Path::new(file))?
+        .ok_or(format!("{} isn't tagged", file))?;
+    Ok((conn, file_id))
+}
+
+/// The `key=value` pairs in `args`' values, and the keys to remove
+fn changes<'a>(
+    args: &'a ArgMatches,
+) -> Result<(Vec<(&'a str, &'a str)>, Vec<&'a str>), Box<dyn Error>> {
+    let mut values = vec![];
+    for pair in args.values_of("values").into_iter().flatten() {
+        let mut parts = pair.splitn(2, META_TAG_SEPARATOR);
+        match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) if !key.is_empty() && !value.is_empty() => {
+                values.push((key, value))
+            }
+            _ => return Err(format!("{} isn't a key=value pair", pair).into()),
+        }
+    }
+    let removes = args
+        .values_of("remove")
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+    Ok((values, removes))
+}
+
+fn file_meta(args: &ArgMatches, mut settings: Settings) -> Result<(), Box<dyn Error>> {
+    info!(target: TAG, "Running meta");
+    let (mut conn, file_id) = open_file(args, &mut settings)?;
+    let (values, removes) = changes(args)?;
+
+    if !values.is_empty() || !removes.is_empty() {
+        let now = sql::get_now_secs();
+        let tx = sql::begin_write(&mut conn)?;
+        for key in removes {
+            sql::remove_file_meta(&tx, file_id, key)?;
+        }
+        for (key, value) in values {
+            sql::set_file_meta(&tx, file_id, key, value, now)?;
+        }
+        tx.commit()?;
+    }
+
+    for (key, value) in sql::get_file_meta(&conn, file_id)? {
+        println!("{}{}{}", key, META_TAG_SEPARATOR, value);
+    }
+    Ok(())
+}
+
+/// The id of the tag named in `args`
+fn tag_id(conn: &Connection, args: &ArgMatches) -> Result<i64, Box<dyn Error>> {
+    let tag = args.value_of("tag").expect("Tag required!");
+    Ok(sql::get_tag(conn, tag)?
+        .ok_or(format!("{} isn't a tag", tag))?
+        .id)
+}
+
+fn set_tag_meta(args: &ArgMatches, mut settings: Settings) -> Result<(), Box<dyn Error>> {
+    info!(target: TAG, "Running meta set");
+    let (mut conn, file_id) = open_file(args, &mut settings)?;
+    let tag_id = tag_id(&conn, args)?;
+    let (values, removes) = changes(args)?;
+
+    let now = sql::get_now_secs();
+    let tx = sql::begin_write(&mut conn)?;
+    for key in removes {
+        sql::remove_file_tag_meta(&tx, file_id, tag_id, key)?;
+    }
+    for (key, value) in values {
+        if !sql::set_file_tag_meta(&tx, file_id, tag_id, key, value, now)? {
+            return Err(format!(
+                "{} isn't tagged with {}",
+                args.value_of("file").unwrap_or_default(),
+                args.value_of("tag").unwrap_or_default()
+            )
+            .into());
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+fn get_tag_meta(args: &ArgMatches, mut settings: Settings) -> Result<(), Box<dyn Error>> {
+    info!(target: TAG, "Running meta get");
+    let (conn, file_id) = open_file(args, &mut settings)?;
+    let tag_id = tag_id(&conn, args)?;
+
+    let meta = sql::get_file_tag_meta(&conn, file_id, tag_id)?;
+    match args.value_of("key") {
+        Some(key) => match meta.into_iter().find(|(k, _)| k == key) {
+            Some((_, value)) => println!("{}", value),
+            None => return Err(format!("No {} metadata", key).into()),
+        },
+        None => {
+            for (key, value) in meta {
+                println!("{}{}{}", key, META_TAG_SEPARATOR, value);
+            }
+        }
+    }
+    Ok(())
+}
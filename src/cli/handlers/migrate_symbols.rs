@@ -0,0 +1,62 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use super::TAG;
+use crate::common::control;
+use crate::common::settings::Settings;
+use crate::{common, sql};
+use clap::ArgMatches;
+use std::error::Error;
+use tracing::info;
+
+pub fn handle(args: &ArgMatches, mut settings: Settings) -> Result<(), Box<dyn Error>> {
+    info!(target: TAG, "Running migrate-symbols");
+    let col = args.value_of("collection").expect("Collection required!");
+    settings.set_collection(col, true);
+
+    let version = common::version_str();
+    let mut conn = sql::db_for_collection(&settings, col)?;
+    sql::migrations::migrate(&mut conn, &version)?;
+    let symbols = settings.get_config().symbols;
+
+    if args.is_present("check") {
+        common::symbols::verify_symbols(&conn, &symbols)?;
+        println!(
+            "No names conflict with symbols {} {}",
+            symbols.device_char, symbols.inode_char
+        );
+        return Ok(());
+    }
+
+    let legacy = control::with_write_gate(&settings, col, "migrating symbols", || {
+        Ok(common::symbols::migrate_symbols(
+            &mut conn, &settings, &version,
+        )?)
+    })?;
+
+    println!(
+        "Using symbols {} {}",
+        symbols.device_char, symbols.inode_char
+    );
+    for (device_char, inode_char) in legacy {
+        println!(
+            "Still accepting legacy symbols {} {}",
+            device_char, inode_char
+        );
+    }
+    Ok(())
+}
@@ -15,12 +15,45 @@
  * You should have received a copy of the GNU Affero General Public License
  * along with this program.  If not, see <http://www.gnu.org/licenses/>.
  */
+pub mod alias;
+pub mod collection;
+pub mod db;
+pub mod demo;
+pub mod doctor;
+pub mod dupes;
+pub mod edit;
+pub mod events;
+pub mod export;
 pub mod fstab;
+pub mod groups;
+pub mod import;
+pub mod import_collection;
 pub mod ln;
+pub mod ls;
+pub mod materialize;
+pub mod merge;
+pub mod meta;
+pub mod migrate_symbols;
 pub mod mount;
 pub mod mv;
+pub mod open;
+pub mod queries;
+pub mod rate;
+pub mod replay;
+pub mod report_issue;
+pub mod retag;
 pub mod rm;
 pub mod rmdir;
+pub mod rpc;
+pub mod search;
+pub mod service;
+pub mod stats;
+pub mod status;
+pub mod swap;
+pub mod sync;
+pub mod undo;
 pub mod unmount;
+pub mod verify;
+pub mod watch;
 
 const TAG: &str = "cli-handlers";
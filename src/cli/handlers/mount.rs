@@ -16,55 +16,443 @@
  * along with this program.  If not, see <http://www.gnu.org/licenses/>.
  */
 use super::TAG;
+use crate::cli::commands::MountExit;
 use crate::common::notify::desktop::DesktopNotifier;
 use crate::common::notify::uds::UDSNotifier;
-use crate::common::settings::Settings;
+use crate::common::notify::Notifier;
+use crate::common::settings::config::HashMapSource;
+use crate::common::settings::{persist_config_value, Settings};
 use crate::common::types::cli::CliError;
+use crate::common::{control, keyring, metrics};
+use crate::fuse::{Shutdown, ShutdownState};
 use crate::sql::tpool::ThreadConnPool;
-use crate::{common, fuse, sql};
+use crate::{cli, common, fuse, platform, sql};
 use clap::ArgMatches;
-use log::{debug, info};
+use fuse_sys::MountHandle;
 use nix::unistd::{fork, ForkResult};
 use parking_lot::Mutex;
-use rusqlite::{Connection, Result as SqliteResult};
 use std::error::Error;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread;
+use tracing::{debug, info, warn};
+
+fn run_migrations(target: &MountTarget) -> Result<(), Box<dyn Error>> {
+    let settings = &target.settings;
+    if target.encrypt {
+        // a collection that's never been mounted doesn't have a database yet, and gets an encrypted one when it's
+        // first opened
+        if target.db_path.exists() {
+            sql::cipher::encrypt(&target.db_path)?;
+        }
+        persist_config_value(
+            &settings.config_file(&target.col),
+            "collection",
+            "encrypted",
+            "true",
+        )?;
+    }
 
-fn run_migrations<P: AsRef<Path>>(db_path: P) -> SqliteResult<()> {
     debug!(target: TAG, "Running migrations");
-    let mut conn = Connection::open(&db_path)?;
+    let mut conn = sql::get_conn(&target.db_path)?;
     sql::migrations::migrate(&mut conn, &*common::version_str())?;
+
+    debug!(target: TAG, "Running symbol migration");
+    let legacy = common::symbols::migrate_symbols(&mut conn, settings, &*common::version_str())?;
+    settings.set_legacy_symbols(legacy);
+
+    let unions = common::symbols::union_names(&conn)?;
+    if !unions.is_empty() {
+        warn!(
+            target: TAG,
+            "These names read as unions of tags, so they can't be reached until they're renamed: {}",
+            unions.join(", ")
+        );
+    }
+
+    // symbols from the command line only stick once we know that no existing names would be misread with them
+    if !target.symbol_overrides.is_empty() {
+        common::symbols::verify_dir_symbols(&conn, &settings.get_config().symbols)?;
+        for (key, symbol) in &target.symbol_overrides {
+            info!(target: TAG, "Saving {} {:?} to {:?}", key, symbol, target.overrides_file);
+            persist_config_value(&target.overrides_file, "symbols", key, symbol)?;
+        }
+    }
+
+    // maintenance that's still running, or that died, keeps holding off writes once we're mounted
+    if let Some(reason) = sql::gate::gate_reason(&conn)? {
+        settings.close_write_gate(&reason);
+    }
+
+    // a move between collections that was interrupted leaves them disagreeing until it's settled
+    let (finished, rolled_back) = common::xtx::recover(settings)?;
+    for intent in &finished {
+        info!(target: TAG, "Finished interrupted {} {}", intent.op, intent.id);
+    }
+    for intent in &rolled_back {
+        warn!(target: TAG, "Rolled back interrupted {} {}", intent.op, intent.id);
+    }
     Ok(())
 }
 
-pub fn handle(args: &ArgMatches, mut settings: Settings) -> Result<(), Box<dyn Error>> {
-    info!(target: TAG, "Running mount");
-    let col = args.value_of("collection").expect("Collection required!");
-    settings.set_collection(col, true);
+/// Cleans up after a supervised mount that crashed, before it's mounted again.  Its filesystem may still be mounted
+/// without anything serving it, and its warm cache snapshot may be what it crashed on.  Opening the database rolls
+/// back whatever transaction it was in the middle of, and the database has to pass a check to be mounted again.
+fn recover(target: &MountTarget) -> Result<(), Box<dyn Error>> {
+    info!(target: TAG, "Recovering {} after a crash", target.col);
+    clean_stale_mount(&target.settings, &target.col, &target.mountpoint)?;
 
-    let mountpoint = settings.mountpoint(col);
-    println!("Mounting to {:?}", mountpoint);
+    let snapshot = target.settings.warm_snapshot_file(&target.col);
+    if snapshot.exists() {
+        debug!(target: TAG, "Removing warm cache snapshot {:?}", snapshot);
+        std::fs::remove_file(&snapshot)?;
+    }
 
-    // only on linux do we have to mount over an existing directory
-    // https://unix.stackexchange.com/questions/251090/why-does-mount-happen-over-an-existing-directory
-    if cfg!(target_os = "linux") && !mountpoint.exists() {
-        return Err(CliError::InvalidMountDir(mountpoint).into());
+    if target.db_path.exists() {
+        let conn = sql::get_conn(&target.db_path)?;
+        let problems = sql::quick_check(&conn)?;
+        if !problems.is_empty() {
+            return Err(CliError::CorruptDatabase(target.db_path.clone(), problems).into());
+        }
     }
+    Ok(())
+}
 
-    let db_path = settings.db_file(col);
-    let share_settings = Arc::new(settings);
+/// Unmounts a supertag mount left at the collection's mountpoint by a daemon that has died.  Otherwise the mountpoint
+/// can't even be stat'd, and mounting over it fails with a confusing error.
+fn clean_stale_mount(
+    settings: &Settings,
+    col: &str,
+    mountpoint: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let pid_file = settings.pid_file(col);
+    if let Some(reason) = platform::stale_mount(mountpoint, &pid_file)? {
+        println!(
+            "Cleaning up a stale mount at {:?}, because {}",
+            mountpoint, reason
+        );
+        platform::force_unmount(mountpoint)?;
+        let _ = std::fs::remove_file(&pid_file);
+    }
+    Ok(())
+}
 
-    let volicon = share_settings.volicon();
-    let fuse_conf = fuse::util::make_fuse_config(volicon.as_deref());
-    let mount_conf = fuse::util::make_mount_config(col, &db_path);
+/// Records our pid as the daemon serving `target`, for `clean_stale_mount`
+fn write_pid_file(target: &MountTarget) {
+    let pid_file = target.settings.pid_file(&target.col);
+    if let Err(e) = std::fs::write(&pid_file, std::process::id().to_string()) {
+        warn!(target: TAG, "Couldn't write pid file {:?}: {}", pid_file, e);
+    }
+}
+
+/// Listens for requests from other processes for `target`, like closing its write gate during a backup
+fn start_control(target: &MountTarget) {
+    let socket_file = target.settings.control_socket_file(&target.col);
+    if let Err(e) = control::serve(&socket_file, target.settings.clone()) {
+        warn!(target: TAG, "Couldn't listen on control socket {:?}: {}", socket_file, e);
+    }
+}
+
+/// Exports the mount's metrics however the config asks to.  One process serves all of its mounts, so this is only done
+/// once, from the first collection's config.
+fn start_metrics(target: &MountTarget) {
+    let conf = target.settings.get_config();
+    if let Some(addr) = &conf.mount.metrics_listen {
+        if let Err(e) = metrics::serve(addr) {
+            warn!(target: TAG, "Couldn't serve metrics on {}: {}", addr, e);
+        }
+    }
+    if let Some(textfile) = &conf.mount.metrics_textfile {
+        metrics::write_textfile(textfile.clone());
+    }
+}
+
+fn remove_daemon_files(targets: &[MountTarget]) {
+    for target in targets {
+        let _ = std::fs::remove_file(target.settings.pid_file(&target.col));
+        let _ = std::fs::remove_file(target.settings.control_socket_file(&target.col));
+    }
+}
+
+/// Our own handlers for SIGINT and SIGTERM need to be registered before mounting, otherwise fuse installs handlers that
+/// exit its loop immediately, without giving us a chance to shut down in order
+fn register_signals() -> std::io::Result<Arc<AtomicBool>> {
+    let stop = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::SIGINT, Arc::clone(&stop))?;
+    signal_hook::flag::register(signal_hook::SIGTERM, Arc::clone(&stop))?;
+    Ok(stop)
+}
+
+/// A collection to mount, with its own settings
+struct MountTarget {
+    col: String,
+    settings: Arc<Settings>,
+    mountpoint: PathBuf,
+    db_path: PathBuf,
+    /// Symbols given on the command line, by their key in the config's `[symbols]`
+    symbol_overrides: Vec<(&'static str, String)>,
+    /// Where the symbols given on the command line are saved, the mount's profile if it has one, otherwise the
+    /// collection's config
+    overrides_file: PathBuf,
+    /// Whether the collection's plain database should be encrypted before it's mounted
+    encrypt: bool,
+}
+
+type Mounted<N> = (Arc<Mutex<MountHandle>>, Arc<Shutdown<N>>);
+
+/// Whether `mountpoint` is still in the mount table
+fn still_mounted(mountpoint: &Path) -> bool {
+    platform::mount_table()
+        .map(|entries| entries.iter().any(|entry| entry.mountpoint == mountpoint))
+        .unwrap_or(false)
+}
+
+/// Serves the mounted filesystems, one for each of `targets`, until we're signaled to stop or they've all been
+/// unmounted out from under us.  A filesystem that's unmounted while the others keep running is shut down on its own,
+/// and on a signal, they're all shut down in the order they were mounted.  Returns whether any of them crashed, with
+/// its fuse loop stopping while it was still mounted.
+fn serve<N: Notifier>(targets: &[MountTarget], mounts: &[Mounted<N>], stop: &AtomicBool) -> bool {
+    let mut crashed = false;
+    loop {
+        let mut any_running = false;
+        for (target, (mount_handle, shutdown)) in targets.iter().zip(mounts) {
+            if shutdown.state() != ShutdownState::Running {
+                continue;
+            }
+            if mount_handle.lock().is_running() {
+                any_running = true;
+            } else if still_mounted(&target.mountpoint) {
+                warn!(target: "mount", "Filesystem stopped while still mounted, shutting it down");
+                crashed = true;
+                shutdown.run(mount_handle);
+            } else {
+                info!(target: "mount", "Filesystem was unmounted, shutting it down");
+                shutdown.run(mount_handle);
+            }
+        }
+
+        if stop.load(Ordering::Relaxed) {
+            info!(target: "mount", "Got a signal, shutting down");
+            break;
+        }
+        if !any_running {
+            break;
+        }
+        thread::sleep(std::time::Duration::from_millis(100));
+    }
+
+    // a no-op for any filesystem that has already been shut down
+    for (mount_handle, shutdown) in mounts {
+        shutdown.run(mount_handle);
+    }
+    crashed
+}
+
+/// Mounts every target and serves them until shutdown, each with a notifier from `make_notifier`.  A supervised mount
+/// that's been `restarted` after a crash is recovered first.
+fn run_daemon<N, F>(
+    targets: &[MountTarget],
+    restarted: bool,
+    make_notifier: F,
+) -> Result<MountExit, Box<dyn Error>>
+where
+    N: Notifier + 'static,
+    F: FnMut(&MountTarget) -> Result<N, Box<dyn Error>>,
+{
+    for target in targets {
+        if restarted {
+            recover(target)?;
+        }
+        run_migrations(target)?;
+    }
+
+    let stop = register_signals()?;
+
+    debug!(target: TAG, "Mounting filesystems");
+    let mounts = mount_all(targets, make_notifier)?;
+    start_metrics(&targets[0]);
+    debug!(target: TAG, "Serving until shutdown");
+    let crashed = serve(targets, &mounts, &stop);
+    remove_daemon_files(targets);
+    debug!(target: TAG, "Done shutting down");
+
+    Ok(if crashed {
+        MountExit::Crashed
+    } else {
+        MountExit::Done
+    })
+}
+
+/// Creates and mounts a filesystem for every target, each with its own notifier.  The targets' connection pools all
+/// share one connection map.  If any mount fails, the ones before it are shut down again.
+fn mount_all<N, F>(
+    targets: &[MountTarget],
+    mut make_notifier: F,
+) -> Result<Vec<Mounted<N>>, Box<dyn Error>>
+where
+    N: Notifier + 'static,
+    F: FnMut(&MountTarget) -> Result<N, Box<dyn Error>>,
+{
+    let shared_pool = ThreadConnPool::new(targets[0].db_path.clone());
+    let mut mounts: Vec<Mounted<N>> = vec![];
+
+    for target in targets {
+        info!(
+            target: TAG,
+            "Mounting {} to {}",
+            target.db_path.display(),
+            target.mountpoint.display()
+        );
+        let mounted = make_notifier(target).and_then(|notifier| {
+            notifier.serve_rpc(cli::rpc::socket_source(target.settings.clone()));
+            let volicon = target.settings.volicon();
+            let fuse_conf = fuse::util::make_fuse_config(volicon.as_deref());
+            let mut mount_conf = fuse::util::make_mount_config(&target.col, &target.db_path);
+            if target.settings.allow_other() {
+                mount_conf.allow_other = Some(true);
+            }
+            #[cfg(target_os = "macos")]
+            {
+                mount_conf.volname = Some(target.settings.volname(&target.col));
+            }
+
+            let conn_pool = shared_pool.for_db(target.db_path.clone());
+            let fsh = fuse::TagFilesystem::new(
+                target.settings.clone(),
+                conn_pool,
+                Arc::new(Mutex::new(notifier)),
+            );
+            let shutdown = fsh.shutdown_handle();
+            let mount_handle = match target.settings.get_config().mount.trace {
+                Some(trace_file) => {
+                    let traced = fuse::trace::TracingFilesystem::new(fsh, &trace_file)?;
+                    fuse_sys::mount(&target.mountpoint, traced, false, fuse_conf, mount_conf)?
+                }
+                None => fuse_sys::mount(&target.mountpoint, fsh, false, fuse_conf, mount_conf)?,
+            };
+            Ok((mount_handle, shutdown))
+        });
+
+        match mounted {
+            Ok(mounted) => {
+                write_pid_file(target);
+                start_control(target);
+                mounts.push(mounted)
+            }
+            Err(e) => {
+                for (mount_handle, shutdown) in &mounts {
+                    shutdown.run(mount_handle);
+                }
+                return Err(e);
+            }
+        }
+    }
+    Ok(mounts)
+}
+
+/// Collects the symbols given on the command line, which override the collection's config
+fn symbol_overrides(args: &ArgMatches) -> Vec<(&'static str, String)> {
+    [
+        ("filedir-symbol", "filedir_str"),
+        ("tag-group-symbol", "tag_group_str"),
+    ]
+    .iter()
+    .filter_map(|(arg, key)| Some((*key, args.value_of(arg)?.to_string())))
+    .collect()
+}
+
+/// Prints whether each of the collections is mounted
+fn status(args: &ArgMatches, settings: &Settings) -> Result<(), Box<dyn Error>> {
+    let cols = match args.values_of("collection") {
+        Some(cols) => cols.map(|c| c.to_string()).collect(),
+        None => platform::all_collections(settings)?,
+    };
+
+    for col in cols {
+        let state = platform::mount_state(&col, &settings.pid_file(&col))?;
+        println!("{}: {}", col, state);
+    }
+    Ok(())
+}
+
+pub fn handle(args: &ArgMatches, settings: Settings) -> Result<(), Box<dyn Error>> {
+    if args.is_present("status") {
+        info!(target: TAG, "Running mount status");
+        return status(args, &settings);
+    }
+
+    info!(target: TAG, "Running mount");
+    let cols = args
+        .values_of("collection")
+        .expect("Collection required!")
+        .collect::<Vec<_>>();
+
+    let overrides = symbol_overrides(args);
+    if !overrides.is_empty() && cols.len() > 1 {
+        return Err("Symbols can only be given when mounting one collection".into());
+    }
+
+    let mut targets = vec![];
+    for col in cols {
+        let mut col_settings = settings.for_collection(col);
+        let mut overrides_file = col_settings.config_file(col);
+        if let Some(profile) = args.value_of("profile") {
+            let profile_file = col_settings.profile_file(col, profile);
+            if !profile_file.exists() {
+                return Err(CliError::MissingProfile(profile_file).into());
+            }
+            info!(target: TAG, "Applying mount profile {:?}", profile_file);
+            col_settings.update_config(::config::File::from(profile_file.clone()));
+            overrides_file = profile_file;
+        }
+        if !overrides.is_empty() {
+            // applied on top of the collection's config and profile, which would otherwise win over the command line
+            let mut source = HashMapSource(Default::default());
+            for (key, symbol) in &overrides {
+                source
+                    .0
+                    .insert(format!("symbols.{}", key), symbol.as_str().into());
+            }
+            col_settings.update_config(source);
+        }
+        // the key has to be asked for before forking, while we still have the terminal
+        let encrypted = col_settings.get_config().collection.encrypted;
+        if encrypted || args.is_present("encrypted") {
+            if !sql::cipher::SUPPORTED {
+                return Err(CliError::EncryptionUnsupported.into());
+            }
+            sql::cipher::set_key(&col_settings.db_file(col), keyring::collection_key(col)?);
+        }
+
+        let mountpoint = col_settings.mountpoint(col);
+        println!("Mounting {} to {:?}", col, mountpoint);
+
+        if col_settings.get_config().mount.clean_stale {
+            clean_stale_mount(&col_settings, col, &mountpoint)?;
+        }
+
+        // only on linux do we have to mount over an existing directory
+        // https://unix.stackexchange.com/questions/251090/why-does-mount-happen-over-an-existing-directory
+        if cfg!(target_os = "linux") && !mountpoint.exists() {
+            return Err(CliError::InvalidMountDir(mountpoint).into());
+        }
+
+        targets.push(MountTarget {
+            col: col.to_string(),
+            db_path: col_settings.db_file(col),
+            mountpoint,
+            settings: Arc::new(col_settings),
+            symbol_overrides: overrides.clone(),
+            overrides_file,
+            encrypt: !encrypted && args.is_present("encrypted"),
+        });
+    }
 
     let background = !args.is_present("foreground");
+    let supervised = args.is_present("supervise");
 
     if background {
-        let conn_pool = ThreadConnPool::new(db_path.clone());
         debug!(target: TAG, "Forking into the background...");
         match unsafe { fork() }.expect("Fork failed") {
             ForkResult::Parent { child } => {
@@ -80,48 +468,33 @@ pub fn handle(args: &ArgMatches, mut settings: Settings) -> Result<(), Box<dyn E
                 // i haven't been able to hunt down the cause of this yet, but it occurs even when
                 // i am very careful to close + cleanup the database connection that existed in
                 // the parent process. as such, we do the migrations here, to avoid the deadlock
-                run_migrations(&db_path)?;
-
-                debug!(target: TAG, "Creating notifier");
-                let notifier = Arc::new(Mutex::new(DesktopNotifier::new(
-                    share_settings.notification_icon(),
-                )));
-
-                debug!(target: TAG, "Creating TagFilesystem");
-                let fsh = fuse::TagFilesystem::new(share_settings, conn_pool, notifier);
-                debug!(target: TAG, "Mounting filesystem");
-                let mount_handle = fuse_sys::mount(&mountpoint, fsh, false, fuse_conf, mount_conf)?;
-                debug!(target: TAG, "Waiting on mount handle");
-                mount_handle.lock().wait();
-                debug!(target: TAG, "Done waiting on mount handle");
-                Ok(())
+                let run = |restarted: bool| {
+                    run_daemon(&targets, restarted, |target| {
+                        debug!(target: TAG, "Creating notifier for {}", target.col);
+                        Ok(DesktopNotifier::from_settings(&target.settings))
+                    })
+                };
+                if supervised {
+                    // the supervisor itself never touches the database, only the children it runs the mount in
+                    let stop = register_signals()?;
+                    cli::supervisor::supervise(&stop, run)
+                } else {
+                    run(false).map(|_| ())
+                }
             }
         }
     } else {
-        run_migrations(&db_path)?;
-
-        let conn_pool = ThreadConnPool::new(db_path.clone());
-        info!(
-            target: TAG,
-            "Mounting {} to {}",
-            db_path.display(),
-            mountpoint.display()
-        );
-
-        let notifier_socket = share_settings.notify_socket_file(col);
-        let notifier = Arc::new(Mutex::new(UDSNotifier::new(notifier_socket, true)?));
-
-        let sigint = Arc::new(AtomicBool::new(false));
-        signal_hook::flag::register(signal_hook::SIGINT, Arc::clone(&sigint))?;
-
-        let fsh = fuse::TagFilesystem::new(share_settings, conn_pool, notifier);
-        let _mount_handle = fuse_sys::mount(&mountpoint, fsh, false, fuse_conf, mount_conf)?;
-
-        while !sigint.load(Ordering::Relaxed) {
-            thread::sleep(std::time::Duration::from_millis(100));
+        let run = |restarted: bool| {
+            run_daemon(&targets, restarted, |target| {
+                let notifier_socket = target.settings.notify_socket_file(&target.col);
+                Ok(UDSNotifier::new(notifier_socket, true)?)
+            })
+        };
+        if supervised {
+            let stop = register_signals()?;
+            cli::supervisor::supervise(&stop, run)
+        } else {
+            run(false).map(|_| ())
         }
-        info!(target: "mount", "Got SIGINT, unmounting and cleaning up");
-
-        Ok(())
     }
 }
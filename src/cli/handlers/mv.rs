@@ -16,13 +16,19 @@
  * along with this program.  If not, see <http://www.gnu.org/licenses/>.
  */
 use super::TAG;
+use crate::cli::glob;
+use crate::cli::prompt::CollisionPrompt;
 use crate::common::notify::uds::UDSNotifier;
 use crate::common::settings::Settings;
 use crate::common::types::file_perms::UMask;
+use crate::common::types::MergeResolution;
 use crate::sql;
 use clap::ArgMatches;
-use log::info;
+use std::collections::HashMap;
 use std::error::Error;
+use std::ffi::OsStr;
+use std::path::Path;
+use tracing::info;
 
 pub fn handle(args: &ArgMatches, mut settings: Settings) -> Result<(), Box<dyn Error>> {
     info!(target: TAG, "Running mv");
@@ -35,11 +41,97 @@ pub fn handle(args: &ArgMatches, mut settings: Settings) -> Result<(), Box<dyn E
     let gid = unsafe { libc::getgid() };
 
     let col = settings.resolve_collection(src)?;
-    let mut conn = sql::db_for_collection(&settings, &col)?;
-
     let notifier_socket = settings.notify_socket_file(&col);
     let notifier = UDSNotifier::new(notifier_socket, false)?;
 
+    // a glob moves every file it matches into the same filedir, keeping their names
+    if glob::is_glob(Path::new(src)) {
+        let filedir_str = settings.get_config().symbols.filedir_str.clone();
+        if Path::new(dst).file_name() != Some(OsStr::new(&filedir_str)) {
+            return Err(format!(
+                "Moving {} needs a destination ending in {}",
+                src, filedir_str
+            )
+            .into());
+        }
+        let mountpoint = settings.mountpoint(&col);
+        let dst_col = settings
+            .collection_from_path(dst, true)
+            .unwrap_or_else(|| col.clone());
+        let mut conn = sql::db_for_collection(&settings, &col)?;
+        let matched = glob::expand(&settings, &conn, &mountpoint, Path::new(src))?;
+        if matched.is_empty() {
+            return Err(format!("No files match {}", src).into());
+        }
+        for file in &matched {
+            if dst_col != col {
+                crate::cli::rename::move_between_collections(
+                    &settings, &col, &dst_col, file, dst, uid, gid, &umask, &notifier,
+                )?;
+            } else {
+                crate::rename(
+                    &settings,
+                    &mut conn,
+                    &mountpoint,
+                    file,
+                    dst,
+                    uid,
+                    gid,
+                    &umask,
+                    &notifier,
+                    |_| Ok(MergeResolution::KeepBoth),
+                )?;
+            }
+        }
+        return Ok(());
+    }
+
+    // a file moved into another collection's tag directory leaves this collection
+    if let Some(dst_col) = settings.collection_from_path(dst, true) {
+        if dst_col != col {
+            crate::cli::rename::move_between_collections(
+                &settings, &col, &dst_col, src, dst, uid, gid, &umask, &notifier,
+            )?;
+            return Ok(());
+        }
+    }
+    let mut conn = sql::db_for_collection(&settings, &col)?;
+
+    // decide on every collision up front, so we aren't holding the database lock while waiting on an answer
+    let interactive = match args.value_of("resolve") {
+        Some("prompt") => true,
+        Some(_) => false,
+        None => unsafe { libc::isatty(libc::STDIN_FILENO) == 1 },
+    };
+    let fixed = args
+        .value_of("resolve")
+        .and_then(MergeResolution::from_name)
+        .unwrap_or(MergeResolution::KeepBoth);
+
+    let mut decided = HashMap::new();
+    let collisions = crate::cli::rename::merge_collisions(
+        &settings,
+        &conn,
+        settings.mountpoint(&col),
+        src,
+        dst,
+    )?;
+    let stdin = std::io::stdin();
+    let mut prompt = CollisionPrompt::new(stdin.lock(), std::io::stderr());
+    for collision in &collisions {
+        let resolution = if interactive {
+            prompt.ask(collision)?
+        } else {
+            fixed
+        };
+        decided.insert((collision.source.id, collision.dest.id), resolution);
+    }
+    info!(
+        target: TAG,
+        "Decided on {} merge collisions",
+        decided.len()
+    );
+
     crate::rename(
         &settings,
         &mut conn,
@@ -50,6 +142,12 @@ pub fn handle(args: &ArgMatches, mut settings: Settings) -> Result<(), Box<dyn E
         gid,
         &umask,
         &notifier,
+        |collision| {
+            Ok(decided
+                .get(&(collision.source.id, collision.dest.id))
+                .copied()
+                .unwrap_or(MergeResolution::KeepBoth))
+        },
     )?;
     Ok(())
 }
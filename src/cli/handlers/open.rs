@@ -0,0 +1,100 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use super::TAG;
+use crate::cli::open::resolve;
+use crate::cli::prompt::PickPrompt;
+use crate::common::settings::Settings;
+use crate::{platform, sql};
+use clap::ArgMatches;
+use std::error::Error;
+use std::path::Path;
+use tracing::info;
+
+/// How many of the matching files an ambiguous, non-interactive open lists
+const AMBIGUOUS_LISTED: usize = 10;
+
+pub fn handle(args: &ArgMatches, mut settings: Settings) -> Result<(), Box<dyn Error>> {
+    info!(target: TAG, "Running open");
+    let col = match args.value_of("collection") {
+        Some(col) => {
+            settings.set_collection(col, true);
+            col.to_string()
+        }
+        None => settings.resolve_collection(std::env::current_dir()?)?,
+    };
+
+    let expr = args.value_of("expr").expect("Expression required!");
+    let terms = expr.split_whitespace().collect::<Vec<_>>();
+    let tags = settings.query_to_tags(&terms)?;
+    let name = args.value_of("name");
+
+    let db_file = settings.db_file(&col);
+    if !db_file.exists() {
+        return Err(format!("No database for collection {} at {:?}", col, db_file).into());
+    }
+    let conn = sql::db_for_collection(&settings, &col)?;
+    let mut found = resolve(&conn, &tags, name)?;
+
+    let tf = match found.len() {
+        0 => {
+            return Err(match name {
+                Some(name) => format!("No file in '{}' is named like '{}'", expr, name),
+                None => format!("No files are tagged with '{}'", expr),
+            }
+            .into())
+        }
+        1 => found.remove(0),
+        _ => {
+            let interactive = unsafe {
+                libc::isatty(libc::STDIN_FILENO) == 1 && libc::isatty(libc::STDOUT_FILENO) == 1
+            };
+            if !interactive {
+                let mut msg = format!("{} files match, give a name to pick one:", found.len());
+                for tf in found.iter().take(AMBIGUOUS_LISTED) {
+                    msg.push_str(&format!("\n  {} ({})", tf.primary_tag, tf.path));
+                }
+                if found.len() > AMBIGUOUS_LISTED {
+                    msg.push_str(&format!(
+                        "\n  ...and {} more",
+                        found.len() - AMBIGUOUS_LISTED
+                    ));
+                }
+                return Err(msg.into());
+            }
+
+            let choices = found
+                .iter()
+                .map(|tf| format!("{} ({})", tf.primary_tag, tf.path))
+                .collect::<Vec<_>>();
+            let stdin = std::io::stdin();
+            let mut prompt = PickPrompt::new(stdin.lock(), std::io::stdout());
+            match prompt.ask(&choices)? {
+                Some(idx) => found.remove(idx),
+                None => return Ok(()),
+            }
+        }
+    };
+
+    let target = Path::new(&tf.path);
+    if !target.exists() {
+        return Err(format!("{} no longer exists", target.display()).into());
+    }
+    info!(target: TAG, "Opening {:?}", target);
+    platform::open_file(target)?;
+    Ok(())
+}
@@ -0,0 +1,123 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use super::TAG;
+use crate::common::search::parse_age;
+use crate::common::settings::Settings;
+use crate::{common, sql};
+use clap::ArgMatches;
+use rusqlite::Connection;
+use std::error::Error;
+use std::path::Path;
+use tracing::info;
+
+pub fn handle(args: &ArgMatches, settings: Settings) -> Result<(), Box<dyn Error>> {
+    info!(target: TAG, "Running queries");
+    match args.subcommand() {
+        ("add", Some(sub_args)) => handle_add(sub_args, settings),
+        ("rm", Some(sub_args)) => handle_rm(sub_args, settings),
+        ("ls", Some(sub_args)) => handle_ls(sub_args, settings),
+        _ => Err("Command not found".into()),
+    }
+}
+
+fn open_collection(
+    args: &ArgMatches,
+    settings: &mut Settings,
+) -> Result<Connection, Box<dyn Error>> {
+    let col = args.value_of("collection").expect("Collection required!");
+    settings.set_collection(col, true);
+
+    let db_file = settings.db_file(col);
+    if !db_file.exists() {
+        return Err(format!("No database for collection {} at {:?}", col, db_file).into());
+    }
+
+    let mut conn = sql::db_for_collection(settings, col)?;
+    sql::migrations::migrate(&mut conn, &common::version_str())?;
+    Ok(conn)
+}
+
+fn handle_add(args: &ArgMatches, mut settings: Settings) -> Result<(), Box<dyn Error>> {
+    info!(target: TAG, "Running queries add");
+    let mut conn = open_collection(args, &mut settings)?;
+
+    let name = args.value_of("name").expect("Name required!");
+    if name.is_empty() || name.contains(std::path::MAIN_SEPARATOR) {
+        return Err(format!("Invalid search name {:?}", name).into());
+    }
+
+    let terms = args
+        .values_of("terms")
+        .expect("Terms required!")
+        .collect::<Vec<_>>();
+    // make sure the terms parse now, rather than leaving a broken directory around
+    settings.query_to_tags(&terms)?;
+
+    let extension = args.value_of("ext").map(|ext| ext.trim_start_matches('.'));
+    let max_age_s = args
+        .value_of("newer_than")
+        .map(|age| parse_age(age).expect("Age validated by clap"));
+
+    let tx = sql::begin_write(&mut conn)?;
+    sql::add_saved_search(&tx, name, &terms, extension, max_age_s, sql::get_now_secs())?;
+    tx.commit()?;
+
+    let search_dir = Path::new("/")
+        .join(settings.get_config().searches.dir)
+        .join(name);
+    println!("{}", settings.abs_mountpoint(&search_dir).display());
+    Ok(())
+}
+
+fn handle_rm(args: &ArgMatches, mut settings: Settings) -> Result<(), Box<dyn Error>> {
+    info!(target: TAG, "Running queries rm");
+    let mut conn = open_collection(args, &mut settings)?;
+    let name = args.value_of("name").expect("Name required!");
+
+    let tx = sql::begin_write(&mut conn)?;
+    let removed = sql::remove_saved_search(&tx, name)?;
+    tx.commit()?;
+
+    if removed {
+        Ok(())
+    } else {
+        Err(format!("No saved search named {}", name).into())
+    }
+}
+
+fn handle_ls(args: &ArgMatches, mut settings: Settings) -> Result<(), Box<dyn Error>> {
+    info!(target: TAG, "Running queries ls");
+    let conn = open_collection(args, &mut settings)?;
+
+    for search in sql::get_saved_searches(&conn)? {
+        let mut filters = vec![];
+        if let Some(ext) = &search.extension {
+            filters.push(format!("ext={}", ext));
+        }
+        if let Some(max_age_s) = search.max_age_s {
+            filters.push(format!("newer-than={}s", max_age_s));
+        }
+        let mut line = format!("{}: {}", search.name, search.terms.join(" "));
+        for filter in filters {
+            line.push(' ');
+            line.push_str(&filter);
+        }
+        println!("{}", line);
+    }
+    Ok(())
+}
@@ -0,0 +1,60 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use super::TAG;
+use crate::common::constants::MAX_RATING;
+use crate::common::identity;
+use crate::common::settings::Settings;
+use crate::{common, sql};
+use clap::ArgMatches;
+use std::error::Error;
+use std::path::Path;
+use tracing::info;
+
+pub fn handle(args: &ArgMatches, mut settings: Settings) -> Result<(), Box<dyn Error>> {
+    info!(target: TAG, "Running rate");
+    let file = args.value_of("file").expect("file is required!");
+    let stars = match args.value_of("stars").map(|s| (s, s.parse::<u8>())) {
+        None => None,
+        Some((_, Ok(0))) => Some(None),
+        Some((_, Ok(n))) if n <= MAX_RATING => Some(Some(n)),
+        Some((s, _)) => {
+            return Err(format!("A rating is from 0 to {} stars, not {}", MAX_RATING, s).into())
+        }
+    };
+
+    let col = settings.resolve_collection(file)?;
+    let mut conn = sql::db_for_collection(&settings, &col)?;
+    sql::migrations::migrate(&mut conn, &common::version_str())?;
+
+    let (device, inode) = settings.file_identity(Path::new(file))?;
+    let file_id = identity::find_file(&conn, device, inode, Path::new(file))?
+        .ok_or(format!("{} isn't tagged", file))?;
+
+    match stars {
+        Some(stars) => {
+            let tx = sql::begin_write(&mut conn)?;
+            sql::set_rating(&tx, file_id, stars)?;
+            tx.commit()?;
+        }
+        None => match sql::get_rating(&conn, file_id)? {
+            Some(stars) => println!("{}", stars),
+            None => println!("Unrated"),
+        },
+    }
+    Ok(())
+}
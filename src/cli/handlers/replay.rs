@@ -0,0 +1,87 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use super::TAG;
+use crate::common::notify::uds::UDSNotifier;
+use crate::common::settings::Settings;
+use crate::fuse::trace;
+use crate::sql::tpool::ThreadConnPool;
+use crate::{common, fuse, sql};
+use clap::ArgMatches;
+use parking_lot::Mutex;
+use std::error::Error;
+use std::path::Path;
+use std::sync::Arc;
+use tracing::info;
+
+pub fn handle(args: &ArgMatches, mut settings: Settings) -> Result<(), Box<dyn Error>> {
+    info!(target: TAG, "Running replay");
+    if settings.dry_run() {
+        return Err("--dry-run isn't supported by replay".into());
+    }
+    let trace_file = Path::new(args.value_of("trace").expect("Trace required!"));
+    let col = args.value_of("collection").expect("Collection required!");
+
+    // replaying into a collection that already has data would make the results depend on it
+    if settings.db_file(col).exists() {
+        return Err(format!("Collection {} already exists, replay needs a new one", col).into());
+    }
+    let entries = trace::read_trace(trace_file)?;
+
+    settings.set_collection(col, true);
+    let settings = Arc::new(settings);
+    let db_file = settings.db_file(col);
+    let mut conn = sql::get_conn(&db_file)?;
+    sql::migrations::migrate(&mut conn, &common::version_str())?;
+
+    let notifier = UDSNotifier::new(settings.notify_socket_file(col), true)?;
+    let fs = fuse::TagFilesystem::new(
+        settings.clone(),
+        ThreadConnPool::new(db_file),
+        Arc::new(Mutex::new(notifier)),
+    );
+
+    let divergences = trace::replay(&fs, &entries);
+    for div in &divergences {
+        println!(
+            "{}: {:?} expected {}, got {}",
+            div.index,
+            div.op,
+            describe_errno(div.expected),
+            describe_errno(div.got)
+        );
+    }
+    println!(
+        "Replayed {} callbacks into collection {}, {} diverged",
+        entries.len(),
+        col,
+        divergences.len()
+    );
+
+    if divergences.is_empty() {
+        Ok(())
+    } else {
+        Err("The replay diverged from the trace".into())
+    }
+}
+
+fn describe_errno(errno: Option<i32>) -> String {
+    match errno {
+        Some(errno) => format!("{:?}", nix::errno::Errno::from_i32(errno)),
+        None => "success".to_string(),
+    }
+}
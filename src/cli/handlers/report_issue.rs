@@ -0,0 +1,82 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use super::TAG;
+use crate::common::diagnostics::{self, Scrubber};
+use crate::common::settings::Settings;
+use clap::ArgMatches;
+use std::error::Error;
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+use tracing::info;
+
+pub fn handle(args: &ArgMatches, mut settings: Settings) -> Result<(), Box<dyn Error>> {
+    info!(target: TAG, "Running report-issue");
+    let col = args.value_of("collection").expect("Collection required!");
+    settings.set_collection(col, true);
+
+    let num_logs = args
+        .value_of("logs")
+        .expect("Logs has a default")
+        .parse::<usize>()?;
+    let output = args
+        .value_of("output")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| {
+            PathBuf::from(format!(
+                "supertag-report-{}-{}.tar",
+                col,
+                chrono::Local::now().format("%Y%m%d-%H%M%S")
+            ))
+        });
+
+    let bundle = diagnostics::collect(&settings, col, num_logs, &Scrubber::for_current_user())?;
+
+    let stderr = std::io::stderr();
+    let mut out = stderr.lock();
+    writeln!(out, "The report will contain:")?;
+    for entry in &bundle.entries {
+        writeln!(
+            out,
+            "  {:<40} {:>9} bytes  {}",
+            entry.name,
+            entry.contents.len(),
+            entry.description
+        )?;
+    }
+    writeln!(
+        out,
+        "Your home directory and user name are replaced in every file, but logs can still mention tag and file names."
+    )?;
+
+    if !args.is_present("yes") {
+        write!(out, "Write it to {}? [y/N] ", output.display())?;
+        out.flush()?;
+
+        let mut answer = String::new();
+        std::io::stdin().lock().read_line(&mut answer)?;
+        if !answer.trim().eq_ignore_ascii_case("y") {
+            writeln!(out, "Nothing was written")?;
+            return Ok(());
+        }
+    }
+
+    let mut file = std::fs::File::create(&output)?;
+    bundle.write_tar(&mut file)?;
+    println!("{}", output.display());
+    Ok(())
+}
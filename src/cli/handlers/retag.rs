@@ -0,0 +1,73 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use super::TAG;
+use crate::common::fsops::parse_tag_list;
+use crate::common::identity;
+use crate::common::notify::desktop::DesktopNotifier;
+use crate::common::settings::Settings;
+use crate::common::types::file_perms::UMask;
+use crate::{common, sql};
+use clap::ArgMatches;
+use std::error::Error;
+use std::path::Path;
+use tracing::info;
+
+pub fn handle(args: &ArgMatches, mut settings: Settings) -> Result<(), Box<dyn Error>> {
+    info!(target: TAG, "Running retag");
+    let col = args.value_of("collection").expect("Collection required!");
+    let file = args.value_of("file").expect("File required!");
+    let from = parse_tag_list(args.value_of("from").unwrap_or_default());
+    let to = parse_tag_list(args.value_of("to").unwrap_or_default());
+    settings.set_collection(col, true);
+
+    // FIXME come in from cli
+    let umask = UMask::default();
+    let uid = unsafe { libc::getuid() };
+    let gid = unsafe { libc::getgid() };
+
+    let mut conn = sql::db_for_collection(&settings, col)?;
+    sql::migrations::migrate(&mut conn, &common::version_str())?;
+    let notifier = DesktopNotifier::from_settings(&settings);
+
+    let (device, inode) = settings.file_identity(Path::new(file))?;
+    let file_id = identity::find_file(&conn, device, inode, Path::new(file))?
+        .ok_or(format!("{} isn't tagged", file))?;
+    let tagged = sql::get_file(&conn, file_id)?.ok_or(format!("{} isn't tagged", file))?;
+
+    let (added, removed) = crate::retag(
+        &settings,
+        &mut conn,
+        settings.mountpoint(col),
+        &tagged,
+        &from,
+        &to,
+        uid,
+        gid,
+        &umask,
+        &notifier,
+    )?;
+    if !settings.dry_run() {
+        println!(
+            "Retagged {}, added {}, removed {}",
+            file,
+            added.join(", "),
+            removed.join(", ")
+        );
+    }
+    Ok(())
+}
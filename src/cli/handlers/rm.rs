@@ -16,11 +16,13 @@
  * along with this program.  If not, see <http://www.gnu.org/licenses/>.
  */
 use super::TAG;
+use crate::cli::glob;
 use crate::common::settings::Settings;
 use crate::sql;
 use clap::ArgMatches;
-use log::info;
 use std::error::Error;
+use std::path::{Path, PathBuf};
+use tracing::info;
 
 pub fn handle(args: &ArgMatches, mut settings: Settings) -> Result<(), Box<dyn Error>> {
     info!(target: TAG, "Running rm");
@@ -29,6 +31,16 @@ pub fn handle(args: &ArgMatches, mut settings: Settings) -> Result<(), Box<dyn E
     let col = settings.resolve_collection(file)?;
     let mut conn = sql::db_for_collection(&settings, &col)?;
 
-    crate::rm(&settings, &mut conn, file, settings.mountpoint(&col))?;
+    let mountpoint = settings.mountpoint(&col);
+    if glob::is_glob(Path::new(file)) {
+        let matched = glob::expand(&settings, &conn, &mountpoint, Path::new(file))?;
+        if matched.is_empty() {
+            return Err(format!("No files match {}", file).into());
+        }
+        let matched: Vec<&Path> = matched.iter().map(PathBuf::as_path).collect();
+        crate::cli::rm::rm_all(&settings, &mut conn, &matched, &mountpoint)?;
+    } else {
+        crate::rm(&settings, &mut conn, file, &mountpoint)?;
+    }
     Ok(())
 }
@@ -19,8 +19,8 @@ use super::TAG;
 use crate::common::settings::Settings;
 use crate::sql;
 use clap::{values_t, ArgMatches};
-use log::info;
 use std::error::Error;
+use tracing::info;
 
 pub fn handle(args: &ArgMatches, mut settings: Settings) -> Result<(), Box<dyn Error>> {
     info!(target: TAG, "Running rmdir");
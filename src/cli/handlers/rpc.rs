@@ -0,0 +1,35 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use super::TAG;
+use crate::cli::rpc::RpcServer;
+use crate::common::settings::Settings;
+use clap::ArgMatches;
+use std::error::Error;
+use tracing::info;
+
+pub fn handle(_args: &ArgMatches, settings: Settings) -> Result<(), Box<dyn Error>> {
+    info!(target: TAG, "Running rpc");
+    if settings.dry_run() {
+        return Err("--dry-run isn't supported by rpc".into());
+    }
+    let stdin = std::io::stdin();
+    let stdout = std::io::stdout();
+    let mut server = RpcServer::new(settings, stdin.lock(), stdout.lock());
+    server.serve()?;
+    Ok(())
+}
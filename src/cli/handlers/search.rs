@@ -0,0 +1,74 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use super::TAG;
+use crate::common::settings::Settings;
+use crate::sql;
+use clap::ArgMatches;
+use serde::Serialize;
+use std::error::Error;
+use tracing::info;
+
+#[derive(Serialize)]
+struct SearchResult<'a> {
+    path: &'a str,
+    name: &'a str,
+    device: u64,
+    inode: u64,
+    mtime: String,
+}
+
+pub fn handle(args: &ArgMatches, mut settings: Settings) -> Result<(), Box<dyn Error>> {
+    info!(target: TAG, "Running search");
+    let col = args.value_of("collection").expect("Collection required!");
+    settings.set_collection(col, true);
+
+    let terms = args
+        .values_of("terms")
+        .expect("Terms required!")
+        .collect::<Vec<_>>();
+    let tags = settings.query_to_tags(&terms)?;
+
+    let db_file = settings.db_file(col);
+    if !db_file.exists() {
+        return Err(format!("No database for collection {} at {:?}", col, db_file).into());
+    }
+    let conn = sql::db_for_collection(&settings, col)?;
+    let files = sql::files_tagged_with(&conn, &tags)?;
+
+    match args.value_of("format") {
+        Some("json") => {
+            let results = files
+                .iter()
+                .map(|tf| SearchResult {
+                    path: &tf.path,
+                    name: &tf.primary_tag,
+                    device: tf.device,
+                    inode: tf.inode,
+                    mtime: tf.mtime.to_rfc3339(),
+                })
+                .collect::<Vec<_>>();
+            println!("{}", serde_json::to_string_pretty(&results)?);
+        }
+        _ => {
+            for tf in files {
+                println!("{}", tf.path);
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,68 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use super::TAG;
+use crate::common::settings::Settings;
+use crate::platform;
+use clap::ArgMatches;
+use std::error::Error;
+use tracing::info;
+
+pub fn handle(args: &ArgMatches, settings: Settings) -> Result<(), Box<dyn Error>> {
+    info!(target: TAG, "Running service");
+    match args.subcommand() {
+        ("install", Some(sub_args)) => handle_install(sub_args, settings),
+        ("uninstall", Some(sub_args)) => handle_uninstall(sub_args, settings),
+        _ => Err("Command not found".into()),
+    }
+}
+
+fn handle_install(args: &ArgMatches, settings: Settings) -> Result<(), Box<dyn Error>> {
+    info!(target: TAG, "Running service install");
+    let col = args.value_of("collection").expect("Collection required!");
+
+    // the service runs whichever tag is installing it
+    let exe = std::env::current_exe()?;
+    let file = platform::service_file(col)?;
+    let contents = platform::service_contents(&exe, col);
+
+    if settings.dry_run() {
+        println!("Would install {:?}:\n\n{}", file, contents);
+        return Ok(());
+    }
+    platform::install_service(&file, &contents)?;
+    println!("Installed {:?}, {} is mounted at login", file, col);
+    Ok(())
+}
+
+fn handle_uninstall(args: &ArgMatches, settings: Settings) -> Result<(), Box<dyn Error>> {
+    info!(target: TAG, "Running service uninstall");
+    let col = args.value_of("collection").expect("Collection required!");
+    let file = platform::service_file(col)?;
+
+    if !file.exists() {
+        println!("No service is installed for {}", col);
+        return Ok(());
+    }
+    if settings.dry_run() {
+        println!("Would uninstall {:?}", file);
+        return Ok(());
+    }
+    platform::uninstall_service(&file)?;
+    println!("Uninstalled {:?}", file);
+    Ok(())
+}
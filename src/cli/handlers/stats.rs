@@ -0,0 +1,91 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use super::TAG;
+use crate::common;
+use crate::common::settings::Settings;
+use crate::sql;
+use crate::sql::stats;
+use clap::ArgMatches;
+use std::error::Error;
+use tracing::info;
+
+pub fn handle(args: &ArgMatches, mut settings: Settings) -> Result<(), Box<dyn Error>> {
+    info!(target: TAG, "Running stats");
+    let col = args.value_of("collection").expect("Collection required!");
+    settings.set_collection(col, true);
+
+    let top: usize = args
+        .value_of("top")
+        .expect("Top required!")
+        .parse()
+        .map_err(|_| "--top must be a number")?;
+
+    let mut conn = sql::db_for_collection(&settings, col)?;
+    sql::migrations::migrate(&mut conn, &common::version_str())?;
+
+    let counts = stats::tag_counts(&conn)?;
+    println!("Tags ({}):", counts.len());
+    for count in &counts {
+        println!("{:>8}  {}", count.num_files, count.name);
+    }
+
+    let co = stats::co_occurrence(&conn, top)?;
+    if !co.tags.is_empty() {
+        println!();
+        println!("Co-occurrence of the top {} tags:", co.tags.len());
+        let width = co.tags.iter().map(|t| t.chars().count()).max().unwrap_or(0);
+        for (i, tag) in co.tags.iter().enumerate() {
+            let row: Vec<String> = co.counts[i].iter().map(|n| format!("{:>6}", n)).collect();
+            println!(
+                "{:>2}. {:<width$} {}",
+                i + 1,
+                tag,
+                row.join(""),
+                width = width
+            );
+        }
+    }
+
+    let orphans = stats::orphaned_files(&conn)?;
+    println!();
+    println!("Files without tags ({}):", orphans.len());
+    for path in &orphans {
+        println!("  {}", path);
+    }
+
+    let managed_dir = settings.managed_dir(col);
+    let mut untagged = vec![];
+    if managed_dir.exists() {
+        for entry in walkdir::WalkDir::new(&managed_dir).follow_links(false) {
+            let entry = entry?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let path = entry.path().to_string_lossy();
+            if !stats::managed_file_tagged(&conn, &path)? {
+                untagged.push(path.into_owned());
+            }
+        }
+    }
+    println!();
+    println!("Untagged managed files ({}):", untagged.len());
+    for path in &untagged {
+        println!("  {}", path);
+    }
+    Ok(())
+}
@@ -0,0 +1,71 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use super::TAG;
+use crate::cli::progress::fmt_duration;
+use crate::common::notify::uds;
+use crate::common::settings::Settings;
+use clap::ArgMatches;
+use std::error::Error;
+use std::time::Duration;
+use tracing::info;
+
+pub fn handle(args: &ArgMatches, mut settings: Settings) -> Result<(), Box<dyn Error>> {
+    info!(target: TAG, "Running status");
+    let col = args.value_of("collection").expect("Collection required!");
+    settings.set_collection(col, true);
+
+    // only a daemon mounted in the foreground has a notifier socket to ask
+    let socket_file = settings.notify_socket_file(col);
+    let health = uds::request_health(&socket_file).map_err(|e| {
+        format!(
+            "Couldn't ask the daemon for {} how it's doing, is it mounted with --foreground? {}",
+            col, e
+        )
+    })?;
+
+    println!(
+        "{}: mounted at {} by pid {}, up {}",
+        health.collection,
+        health.mountpoint.display(),
+        health.pid,
+        fmt_duration(Duration::from_secs(health.uptime_secs))
+    );
+    println!("  db: {}", health.db_path.display());
+    println!("  tags: {}, files: {}", health.num_tags, health.num_files);
+    println!("  requests served: {}", health.requests);
+    match &health.write_gate {
+        Some(reason) => println!("  writes: held off for {}", reason),
+        None if health.disk_full => {
+            println!("  writes: held off until the database's disk has room")
+        }
+        None => println!("  writes: accepted"),
+    }
+    let cache = &health.cache;
+    println!(
+        "  caches: readdir {}, readdir pages {}, symlinks {}, aliases {}, unlink canaries {}, rename deletes {}, \
+        tags {}",
+        cache.readdir,
+        cache.readdir_pages,
+        cache.symlinks,
+        cache.aliases,
+        cache.unlink_canaries,
+        cache.rename_deletes,
+        cache.tags
+    );
+    Ok(())
+}
@@ -0,0 +1,171 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use super::TAG;
+use crate::common::archive::PathRemaps;
+use crate::common::settings::Settings;
+use crate::common::sync::SyncSummary;
+use crate::common::types::file_perms::UMask;
+use crate::{common, sql};
+use clap::ArgMatches;
+use std::error::Error;
+use std::io::BufReader;
+use std::process::{Command, Stdio};
+use tracing::info;
+
+/// Quotes `arg` for the shell on the other end of ssh, which gets the command as a single string
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+/// Refuses a host that ssh would take for one of its own options, like `-oProxyCommand=...`, which runs a command
+/// here.  The host is also passed after `--`, but a `--ssh` program other than OpenSSH might not honor that.
+fn check_host(host: &str) -> Result<(), Box<dyn Error>> {
+    if host.is_empty() || host.starts_with('-') {
+        return Err(format!("{:?} isn't a host that can be synced with", host).into());
+    }
+    Ok(())
+}
+
+fn print_summary(summary: &SyncSummary, dry_run: bool) {
+    eprintln!(
+        "{} {} files, added {}, removed {}, {} not on this machine",
+        if dry_run { "Would retag" } else { "Retagged" },
+        summary.retagged,
+        summary.added,
+        summary.removed,
+        summary.missing
+    );
+}
+
+pub fn handle(args: &ArgMatches, mut settings: Settings) -> Result<(), Box<dyn Error>> {
+    info!(target: TAG, "Running sync");
+    let col = args.value_of("collection").expect("Collection required!");
+    settings.set_collection(col, true);
+
+    // FIXME come in from cli
+    let umask = UMask::default();
+    let uid = unsafe { libc::getuid() };
+    let gid = unsafe { libc::getgid() };
+
+    let mut conn = sql::db_for_collection(&settings, col)?;
+    sql::migrations::migrate(&mut conn, &common::version_str())?;
+
+    let mut remaps = PathRemaps::default();
+    let remap_args = args
+        .values_of("remap")
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+    for remap in &remap_args {
+        remaps.add_arg(remap)?;
+    }
+
+    if args.is_present("serve") {
+        // stdout is the protocol, so everything else goes to stderr, which ssh passes along
+        let stdin = std::io::stdin();
+        let summary = crate::cli::sync::sync(
+            &settings,
+            &mut conn,
+            settings.mountpoint(col),
+            stdin.lock(),
+            std::io::stdout(),
+            true,
+            &remaps,
+            uid,
+            gid,
+            &umask,
+        )?;
+        print_summary(&summary, settings.dry_run());
+        return Ok(());
+    }
+
+    let remote = args.value_of("remote").expect("Remote required!");
+    let (host, remote_col) = match remote.rfind(':') {
+        Some(idx) => (&remote[..idx], &remote[idx + 1..]),
+        None => (remote, col),
+    };
+    check_host(host)?;
+
+    // the other side sees our paths through the remaps turned around
+    let mut remote_cmd = vec![
+        args.value_of("remote-tag").unwrap_or("tag").to_string(),
+        "sync".to_string(),
+        "--serve".to_string(),
+        remote_col.to_string(),
+    ];
+    if settings.dry_run() {
+        remote_cmd.push("--dry-run".to_string());
+    }
+    for remap in &remap_args {
+        let mut parts = remap.splitn(2, '=');
+        if let (Some(from), Some(to)) = (parts.next(), parts.next()) {
+            remote_cmd.push("--remap".to_string());
+            remote_cmd.push(format!("{}={}", to, from));
+        }
+    }
+    let remote_cmd = remote_cmd
+        .iter()
+        .map(|arg| shell_quote(arg))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let ssh = args.value_of("ssh").unwrap_or("ssh");
+    info!(target: TAG, "Running {} {} {}", ssh, host, remote_cmd);
+    let mut child = Command::new(ssh)
+        .arg("--")
+        .arg(host)
+        .arg(remote_cmd)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let writer = child.stdin.take().expect("Child stdin is piped");
+    let reader = BufReader::new(child.stdout.take().expect("Child stdout is piped"));
+
+    let synced = crate::cli::sync::sync(
+        &settings,
+        &mut conn,
+        settings.mountpoint(col),
+        reader,
+        writer,
+        false,
+        &remaps,
+        uid,
+        gid,
+        &umask,
+    );
+    let status = child.wait()?;
+    let summary = synced?;
+    if !status.success() {
+        return Err(format!("The other side of the sync exited with {}", status).into());
+    }
+    print_summary(&summary, settings.dry_run());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_host() {
+        assert!(check_host("host").is_ok());
+        assert!(check_host("user@host").is_ok());
+        assert!(check_host("-oProxyCommand=touch /tmp/pwned").is_err());
+        assert!(check_host("").is_err());
+    }
+}
@@ -0,0 +1,67 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use super::TAG;
+use crate::common;
+use crate::common::settings::Settings;
+use crate::sql;
+use crate::sql::undo;
+use clap::ArgMatches;
+use std::error::Error;
+use tracing::info;
+
+pub fn handle(args: &ArgMatches, mut settings: Settings) -> Result<(), Box<dyn Error>> {
+    info!(target: TAG, "Running undo");
+    let col = args.value_of("collection").expect("Collection required!");
+    settings.set_collection(col, true);
+
+    let count: usize = args
+        .value_of("count")
+        .expect("Count required!")
+        .parse()
+        .map_err(|_| "--count must be a number")?;
+
+    let mut conn = sql::db_for_collection(&settings, col)?;
+    sql::migrations::migrate(&mut conn, &common::version_str())?;
+
+    if args.is_present("list") {
+        let keep = settings.get_config().undo.keep;
+        for op in undo::recent_ops(&conn, keep)? {
+            println!("{}\t{}\t{}", op.ts.to_rfc3339(), op.op, op.detail);
+        }
+        return Ok(());
+    }
+
+    // every operation is undone in one transaction, so that if one can't be, none are
+    let tx = sql::begin_write(&mut conn)?;
+    let mut undone = vec![];
+    for _ in 0..count {
+        match undo::undo_last(&tx)? {
+            Some(op) => undone.push(op),
+            None => break,
+        }
+    }
+    tx.commit()?;
+
+    if undone.is_empty() {
+        println!("Nothing to undo");
+    }
+    for op in undone {
+        println!("Undid {} {}", op.op, op.detail);
+    }
+    Ok(())
+}
@@ -19,8 +19,8 @@
 use super::TAG;
 use crate::common::settings::Settings;
 use clap::ArgMatches;
-use log::info;
 use std::error::Error;
+use tracing::info;
 
 pub fn handle(args: &ArgMatches, settings: Settings) -> Result<(), Box<dyn Error>> {
     info!(target: TAG, "Running umount");
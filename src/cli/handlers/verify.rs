@@ -0,0 +1,123 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use super::TAG;
+use crate::common;
+use crate::common::managed_file::{self, Integrity};
+use crate::common::settings::Settings;
+use crate::sql;
+use clap::ArgMatches;
+use serde::Serialize;
+use std::error::Error;
+use tracing::info;
+
+#[derive(Serialize)]
+struct VerifyResult<'a> {
+    managed_file: &'a str,
+    path: &'a str,
+    status: &'static str,
+    expected: Option<&'a str>,
+    actual: Option<&'a str>,
+    error: Option<&'a str>,
+}
+
+pub fn handle(args: &ArgMatches, mut settings: Settings) -> Result<(), Box<dyn Error>> {
+    info!(target: TAG, "Running verify");
+    let col = args.value_of("collection").expect("Collection required!");
+    settings.set_collection(col, true);
+
+    let db_file = settings.db_file(col);
+    if !db_file.exists() {
+        return Err(format!("No database for collection {} at {:?}", col, db_file).into());
+    }
+    let mut conn = sql::db_for_collection(&settings, col)?;
+    sql::migrations::migrate(&mut conn, &common::version_str())?;
+
+    let records = sql::get_managed_files(&conn)?;
+    let results = records
+        .iter()
+        .map(|record| (record, managed_file::verify(record)))
+        .collect::<Vec<_>>();
+
+    if args.is_present("record") {
+        let tx = sql::begin_write(&mut conn)?;
+        let mut recorded = 0;
+        for (record, integrity) in &results {
+            if let Integrity::Unrecorded { actual } = integrity {
+                sql::set_alias_checksum(&tx, &record.alias_file, actual)?;
+                recorded += 1;
+            }
+        }
+        tx.commit()?;
+        eprintln!("Recorded {} checksum(s)", recorded);
+    }
+
+    match args.value_of("format") {
+        Some("json") => {
+            let json = results
+                .iter()
+                .map(|(record, integrity)| {
+                    let (expected, actual, error) = match integrity {
+                        Integrity::Intact => {
+                            (record.checksum.as_deref(), record.checksum.as_deref(), None)
+                        }
+                        Integrity::Corrupt { expected, actual } => {
+                            (Some(expected.as_str()), Some(actual.as_str()), None)
+                        }
+                        Integrity::Unrecorded { actual } => (None, Some(actual.as_str()), None),
+                        Integrity::Unreadable(e) => {
+                            (record.checksum.as_deref(), None, Some(e.as_str()))
+                        }
+                        Integrity::Missing => (record.checksum.as_deref(), None, None),
+                    };
+                    VerifyResult {
+                        managed_file: &record.alias_file,
+                        path: &record.path,
+                        status: integrity.name(),
+                        expected,
+                        actual,
+                        error,
+                    }
+                })
+                .collect::<Vec<_>>();
+            println!("{}", serde_json::to_string_pretty(&json)?);
+        }
+        _ => {
+            for (record, integrity) in &results {
+                if let Integrity::Unreadable(e) = integrity {
+                    println!("{:<10} {} ({})", integrity.name(), record.alias_file, e);
+                } else {
+                    println!("{:<10} {}", integrity.name(), record.alias_file);
+                }
+            }
+        }
+    }
+
+    let failed = results
+        .iter()
+        .filter(|(_, integrity)| integrity.failed())
+        .count();
+    eprintln!(
+        "{} of {} managed file(s) failed verification",
+        failed,
+        results.len()
+    );
+    if failed > 0 {
+        return Err(format!("{} managed file(s) are missing or corrupt", failed).into());
+    }
+    Ok(())
+}
@@ -0,0 +1,70 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use super::TAG;
+use crate::common::notify::desktop::DesktopNotifier;
+use crate::common::settings::Settings;
+use crate::common::types::file_perms::UMask;
+use crate::sql;
+use clap::ArgMatches;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use tracing::info;
+
+pub fn handle(args: &ArgMatches, mut settings: Settings) -> Result<(), Box<dyn Error>> {
+    info!(target: TAG, "Running watch");
+    let dir = Path::new(args.value_of("dir").expect("dir is required!"));
+    let tag_path: PathBuf = args
+        .values_of("tags")
+        .expect("tags are required!")
+        .collect();
+
+    // FIXME make a cli arg
+    let umask = UMask::default();
+    let uid = unsafe { libc::getuid() };
+    let gid = unsafe { libc::getgid() };
+
+    let col = settings.resolve_collection(&tag_path)?;
+    let mut conn = sql::db_for_collection(&settings, &col)?;
+    let mountpoint = settings.mountpoint(&col);
+    let notifier = DesktopNotifier::from_settings(&settings);
+
+    let stop = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::SIGINT, Arc::clone(&stop))?;
+    signal_hook::flag::register(signal_hook::SIGTERM, Arc::clone(&stop))?;
+
+    println!(
+        "Watching {} for new files, press Ctrl-C to stop",
+        dir.display()
+    );
+    crate::watch(
+        &settings,
+        &mut conn,
+        &mountpoint,
+        dir,
+        &tag_path,
+        uid,
+        gid,
+        &umask,
+        &notifier,
+        &stop,
+        |file| println!("Linked {}", file.display()),
+    )?;
+    Ok(())
+}
@@ -0,0 +1,158 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use super::CLI_TAG;
+use crate::common::err::{STagError, STagResult};
+use crate::common::fsops::flush_tags;
+use crate::common::get_filename;
+use crate::common::settings::config::Groups;
+use crate::common::settings::Settings;
+use crate::common::types::file_perms::UMask;
+use crate::common::types::{TagCollectible, TagCollection, TagType};
+use crate::sql;
+use crate::sql::types::NewFile;
+use libc::{gid_t, uid_t};
+use rusqlite::Connection;
+use std::path::Path;
+use tracing::{debug, info, warn};
+
+/// How many files we add per transaction.  Large enough that the per-transaction overhead disappears, small enough that
+/// we don't hold the database lock for too long at once.
+pub const IMPORT_BATCH_SIZE: usize = 1000;
+
+/// Recursively links every file under `dir` to the tags in `tag_path`.  If `dirs_as_tags` is set, each file is also
+/// linked to the names of the directories between `dir` and itself.  `on_batch` is called with the running total of
+/// files after every committed batch.  Returns the number of files imported.
+pub fn import<P: AsRef<Path>, F: FnMut(usize)>(
+    settings: &Settings,
+    conn: &mut Connection,
+    mountpoint: P,
+    dir: &Path,
+    tag_path: &Path,
+    dirs_as_tags: bool,
+    uid: uid_t,
+    gid: gid_t,
+    umask: &UMask,
+    mut on_batch: F,
+) -> STagResult<usize> {
+    let rel_tagpath = super::strip_prefix(tag_path, mountpoint.as_ref());
+    let base_tags = TagCollection::new(settings, rel_tagpath)
+        .iter()
+        .collect_regular_names()
+        .into_iter()
+        .map(String::from)
+        .collect::<Vec<_>>();
+    if base_tags.is_empty() {
+        return Err(STagError::InvalidPath(rel_tagpath.to_owned()));
+    }
+
+    let root = std::fs::canonicalize(dir)?;
+    // importing the collection into itself is undefined behavior, the same as linking a single file into itself
+    if settings.collection_from_path(&root, false) == Some(settings.get_collection()) {
+        return Err(STagError::RecursiveLink(root));
+    }
+
+    info!(
+        target: CLI_TAG,
+        "Importing {:?} to {:?}, with dirs as tags: {}", root, base_tags, dirs_as_tags
+    );
+
+    let groups = settings.get_config().groups;
+    let mut total = 0;
+    let mut batch = Vec::with_capacity(IMPORT_BATCH_SIZE);
+    for entry in walkdir::WalkDir::new(&root).follow_links(false) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!(target: CLI_TAG, "Skipping unreadable entry: {}", e);
+                continue;
+            }
+        };
+        if !entry.file_type().is_file() {
+            continue;
+        }
+
+        let path = entry.path();
+        let mut tags = base_tags.clone();
+        if dirs_as_tags {
+            if let Ok(rel_parent) = path.parent().unwrap_or(&root).strip_prefix(&root) {
+                for tag in dir_tags(settings, rel_parent) {
+                    if !tags.contains(&tag) {
+                        tags.push(tag);
+                    }
+                }
+            }
+        }
+
+        let (device, inode) = settings.file_identity(path)?;
+        batch.push(NewFile {
+            device,
+            inode,
+            path: path
+                .to_str()
+                .ok_or_else(|| STagError::InvalidPath(path.to_owned()))?
+                .to_string(),
+            primary_tag: get_filename(path)?.to_string(),
+            tags,
+        });
+
+        if batch.len() >= IMPORT_BATCH_SIZE {
+            total += add_batch(conn, &mut batch, &groups, uid, gid, umask)?;
+            on_batch(total);
+        }
+    }
+
+    if !batch.is_empty() {
+        total += add_batch(conn, &mut batch, &groups, uid, gid, umask)?;
+        on_batch(total);
+    }
+
+    flush_tags(rel_tagpath, settings, mountpoint);
+    Ok(total)
+}
+
+/// The directory names in `rel_dir` that can be used as tags.  Names that would mean something else in a tag path,
+/// like a negation or a tag group, are skipped.
+pub(super) fn dir_tags(settings: &Settings, rel_dir: &Path) -> Vec<String> {
+    let mut tags = vec![];
+    for comp in rel_dir.iter() {
+        let name = comp.to_string_lossy();
+        match settings.path_to_tags(Path::new(name.as_ref())).pop() {
+            Some(TagType::Regular(tag)) => tags.push(tag),
+            _ => debug!(target: CLI_TAG, "Directory {:?} can't be a tag, skipping", name),
+        }
+    }
+    tags
+}
+
+fn add_batch(
+    conn: &mut Connection,
+    batch: &mut Vec<NewFile>,
+    groups: &Groups,
+    uid: uid_t,
+    gid: gid_t,
+    umask: &UMask,
+) -> STagResult<usize> {
+    let tx = sql::begin_write(conn)?;
+    sql::add_files(&tx, batch, groups, uid, gid, umask, sql::get_now_secs())?;
+    tx.commit()?;
+
+    let added = batch.len();
+    batch.clear();
+    Ok(added)
+}
@@ -18,16 +18,31 @@
 
 use super::CLI_TAG;
 use crate::common;
-use crate::common::err::STagResult;
-use crate::common::fsops::flush_tags;
+use crate::common::constants::{LN_CHUNK_SIZE, SUBDIR_PREFIX_SEPARATOR};
+use crate::common::err::{STagError, STagResult};
+use crate::common::fsops::{flush_tags, TreeFile};
 use crate::common::get_filename;
 use crate::common::notify::Notifier;
 use crate::common::settings::Settings;
 use crate::common::types::file_perms::UMask;
 use libc::{gid_t, uid_t};
-use log::info;
-use rusqlite::{Connection, TransactionBehavior};
+use rusqlite::Connection;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::{info, warn};
+
+/// How far `ln_with_progress` got before it finished or was stopped
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LnSummary {
+    pub linked: usize,
+    pub total: usize,
+}
+
+impl LnSummary {
+    pub fn interrupted(&self) -> bool {
+        self.linked < self.total
+    }
+}
 
 pub fn ln<P: AsRef<Path>, N: Notifier>(
     settings: &Settings,
@@ -40,6 +55,43 @@ pub fn ln<P: AsRef<Path>, N: Notifier>(
     umask: &UMask,
     notifier: &N,
 ) -> STagResult<()> {
+    ln_with_progress(
+        settings,
+        conn,
+        mountpoint,
+        files,
+        tag_path,
+        uid,
+        gid,
+        umask,
+        notifier,
+        &AtomicBool::new(false),
+        |_, _| {},
+    )?;
+    Ok(())
+}
+
+/// Links `files` in chunks of `LN_CHUNK_SIZE`, each in its own transaction, calling `on_progress` with how many have
+/// been linked so far after each one.  If `stop` is set partway through, the chunk in progress is rolled back and the
+/// chunks before it are kept.
+pub fn ln_with_progress<P, N, F>(
+    settings: &Settings,
+    conn: &mut Connection,
+    mountpoint: P,
+    files: Vec<&Path>,
+    tag_path: &Path,
+    uid: uid_t,
+    gid: gid_t,
+    umask: &UMask,
+    notifier: &N,
+    stop: &AtomicBool,
+    mut on_progress: F,
+) -> STagResult<LnSummary>
+where
+    P: AsRef<Path>,
+    N: Notifier,
+    F: FnMut(usize, &Path),
+{
     let rel_tagpath = super::strip_prefix(tag_path, mountpoint.as_ref());
 
     // we have to do this outside of a transaction, because it will call the fuse handler getattr if we're attempting
@@ -51,30 +103,207 @@ pub fn ln<P: AsRef<Path>, N: Notifier>(
 
     info!(
         target: CLI_TAG,
-        "Linking files {:?} to {:?}", abs_files, rel_tagpath
+        "Linking {} files to {:?}",
+        abs_files.len(),
+        rel_tagpath
     );
 
-    let tx = conn.transaction_with_behavior(TransactionBehavior::Exclusive)?;
-    for target in abs_files {
-        let primary_tag = get_filename(&target)?;
-        common::fsops::ln(
+    let total = abs_files.len();
+    // a dry run reports its plan as a whole, so it isn't split up
+    let chunk_size = if settings.dry_run() {
+        total.max(1)
+    } else {
+        LN_CHUNK_SIZE
+    };
+
+    let mut linked = 0;
+    let mut committed = false;
+    for chunk in abs_files.chunks(chunk_size) {
+        let hashes = common::fsops::hash_targets(settings, chunk);
+        let tx = super::begin_write(settings, conn)?;
+        let mut in_chunk = 0;
+        let done = common::fsops::ln_batch(
             settings,
             &tx,
-            &target,
+            chunk,
             rel_tagpath,
-            &primary_tag,
             uid,
             gid,
             umask,
-            None,
             notifier,
+            stop,
+            |file| {
+                in_chunk += 1;
+                on_progress(linked + in_chunk, file);
+            },
         )?;
+
+        // dropping the transaction rolls back the partial chunk
+        if done < chunk.len() {
+            info!(
+                target: CLI_TAG,
+                "Stopped, keeping {} of {} files and rolling back the last {}", linked, total, done
+            );
+            break;
+        }
+        common::fsops::record_hashes(settings, &tx, &hashes)?;
+        committed |= super::commit(settings, tx)?;
+        linked += done;
     }
-    tx.commit()?;
 
-    // now that we've created a link, we need to send a signal (via stat) to flush the readdir
-    // cache for the tag directory, so that the tag directory's mtime reports correctly
-    flush_tags(rel_tagpath, settings, mountpoint);
+    if committed {
+        // now that we've created a link, we need to send a signal (via stat) to flush the readdir
+        // cache for the tag directory, so that the tag directory's mtime reports correctly
+        flush_tags(rel_tagpath, settings, mountpoint);
+    }
 
-    Ok(())
+    Ok(LnSummary { linked, total })
+}
+
+/// What becomes of the directories between a recursively linked directory and each of its files
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SubdirNames {
+    /// They're dropped, so every file is only linked to the destination's tags
+    Ignore,
+    /// Each directory is another tag, like `tag import --dirs-as-tags`
+    Tags,
+    /// They're prefixed onto the file's name, ie `2021_07_photo.jpg`
+    Prefix,
+}
+
+/// The directories a recursive link never descends into: every mounted collection, and the collections dir with all
+/// of their databases and sockets.  Walking into a mount would link a collection's own symlinks back into it.
+pub fn skipped_dirs(settings: &Settings) -> Vec<PathBuf> {
+    let mut skipped = vec![settings.collections_dir()];
+    match crate::platform::mount_table() {
+        Ok(mounts) => skipped.extend(
+            mounts
+                .into_iter()
+                .filter(|entry| entry.collection().is_some())
+                .map(|entry| entry.mountpoint),
+        ),
+        Err(e) => warn!(target: CLI_TAG, "Couldn't read the mount table: {}", e),
+    }
+    skipped
+        .into_iter()
+        .map(|dir| std::fs::canonicalize(&dir).unwrap_or(dir))
+        .collect()
+}
+
+/// Every regular file in each of `paths`, recursively, named and tagged for `subdirs`.  A path that's a file is just
+/// itself.  Nothing under `skipped_dirs` is included.
+pub fn tree_files(
+    settings: &Settings,
+    paths: Vec<&Path>,
+    subdirs: SubdirNames,
+) -> STagResult<Vec<TreeFile>> {
+    let skipped = skipped_dirs(settings);
+    let mut tree = vec![];
+    for path in paths {
+        let root = std::fs::canonicalize(path)?;
+        // linking the collection into itself is undefined behavior
+        if settings.collection_from_path(&root, false) == Some(settings.get_collection()) {
+            return Err(STagError::RecursiveLink(root));
+        }
+
+        let walk = walkdir::WalkDir::new(&root)
+            .follow_links(false)
+            .into_iter()
+            .filter_entry(|entry| !skipped.iter().any(|dir| entry.path() == dir));
+        for entry in walk {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    warn!(target: CLI_TAG, "Skipping unreadable entry: {}", e);
+                    continue;
+                }
+            };
+            if !entry.file_type().is_file() {
+                continue;
+            }
+
+            let src = entry.into_path();
+            let name = get_filename(&src)?.to_string();
+            let rel_parent = src
+                .parent()
+                .and_then(|parent| parent.strip_prefix(&root).ok())
+                .unwrap_or_else(|| Path::new(""));
+            let (primary_tag, dir_tags) = match subdirs {
+                SubdirNames::Ignore => (name, vec![]),
+                SubdirNames::Tags => (name, super::import::dir_tags(settings, rel_parent)),
+                SubdirNames::Prefix => {
+                    let mut parts: Vec<String> = rel_parent
+                        .iter()
+                        .map(|part| part.to_string_lossy().to_string())
+                        .collect();
+                    parts.push(name);
+                    (parts.join(SUBDIR_PREFIX_SEPARATOR), vec![])
+                }
+            };
+            tree.push(TreeFile {
+                src,
+                primary_tag,
+                dir_tags,
+            });
+        }
+    }
+    Ok(tree)
+}
+
+/// Links the files from `tree_files` in chunks of `LN_CHUNK_SIZE`, each with a single batched insert in its own
+/// transaction, calling `on_progress` with how many have been linked so far after each chunk.  If `stop` is set, the
+/// chunks that are done are kept.
+pub fn ln_tree_with_progress<P, F>(
+    settings: &Settings,
+    conn: &mut Connection,
+    mountpoint: P,
+    tree: &[TreeFile],
+    tag_path: &Path,
+    uid: uid_t,
+    gid: gid_t,
+    umask: &UMask,
+    stop: &AtomicBool,
+    mut on_progress: F,
+) -> STagResult<LnSummary>
+where
+    P: AsRef<Path>,
+    F: FnMut(usize, &Path),
+{
+    let rel_tagpath = super::strip_prefix(tag_path, mountpoint.as_ref());
+    info!(
+        target: CLI_TAG,
+        "Linking a tree of {} files to {:?}",
+        tree.len(),
+        rel_tagpath
+    );
+
+    let total = tree.len();
+    // a dry run reports its plan as a whole, so it isn't split up
+    let chunk_size = if settings.dry_run() {
+        total.max(1)
+    } else {
+        LN_CHUNK_SIZE
+    };
+
+    let mut linked = 0;
+    let mut committed = false;
+    for chunk in tree.chunks(chunk_size) {
+        if stop.load(Ordering::Relaxed) {
+            info!(target: CLI_TAG, "Stopped after linking {} of {} files", linked, total);
+            break;
+        }
+        let tx = super::begin_write(settings, conn)?;
+        common::fsops::ln_tree(settings, &tx, chunk, rel_tagpath, uid, gid, umask)?;
+        committed |= super::commit(settings, tx)?;
+        linked += chunk.len();
+        if let Some(last) = chunk.last() {
+            on_progress(linked, &last.src);
+        }
+    }
+
+    if committed {
+        flush_tags(rel_tagpath, settings, mountpoint);
+    }
+
+    Ok(LnSummary { linked, total })
 }
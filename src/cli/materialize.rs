@@ -0,0 +1,116 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use super::CLI_TAG;
+use crate::common::err::{STagError, STagResult};
+use crate::common::settings::Settings;
+use crate::common::types::TagType;
+use crate::sql;
+use rusqlite::Connection;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// How each file of a materialized query is written into the output directory
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MaterializeMode {
+    Symlink,
+    Hardlink,
+    Copy,
+}
+
+/// What `materialize` wrote
+#[derive(Debug, Default)]
+pub struct MaterializeSummary {
+    pub written: usize,
+    /// Targets that no longer exist, so they were left out
+    pub missing: Vec<PathBuf>,
+}
+
+/// Writes the files matching `tags` into `out_dir` as real filesystem entries, outside of any mount, so that tools
+/// which can't read a mount can still see the result of a query.  `out_dir` is created if needed, and must be empty.
+/// Files whose names collide are disambiguated the same way a filedir does it, by their device and inode.
+pub fn materialize<P: AsRef<Path>>(
+    settings: &Settings,
+    conn: &Connection,
+    tags: &[TagType],
+    out_dir: P,
+    mode: MaterializeMode,
+) -> STagResult<MaterializeSummary> {
+    let out_dir = out_dir.as_ref();
+    info!(
+        target: CLI_TAG,
+        "Materializing {:?} into {:?} as {:?}", tags, out_dir, mode
+    );
+
+    // writing into a mount would tag the files instead of snapshotting them
+    if let Some(col) = settings.collection_from_path(out_dir, false) {
+        return Err(STagError::Other(
+            format!("{} is inside of collection {}", out_dir.display(), col).into(),
+        ));
+    }
+    if out_dir.exists() && std::fs::read_dir(out_dir)?.next().is_some() {
+        return Err(STagError::Other(
+            format!("{} isn't empty", out_dir.display()).into(),
+        ));
+    }
+    std::fs::create_dir_all(out_dir)?;
+
+    let files = sql::files_tagged_with(conn, tags)?;
+    let duplicates = {
+        let mut seen = HashSet::new();
+        files
+            .iter()
+            .filter(|tf| !seen.insert(tf.primary_tag.as_str()))
+            .map(|tf| tf.primary_tag.clone())
+            .collect::<HashSet<String>>()
+    };
+
+    let mut summary = MaterializeSummary::default();
+    for tf in &files {
+        let target = Path::new(&tf.path);
+        if !target.exists() {
+            warn!(target: CLI_TAG, "Skipping missing target {:?}", target);
+            summary.missing.push(target.to_owned());
+            continue;
+        }
+
+        let name = if duplicates.contains(&tf.primary_tag) {
+            settings.inodify_filename(&tf.primary_tag, tf.device, tf.inode)
+        } else {
+            tf.primary_tag.clone()
+        };
+        let dst = out_dir.join(name);
+
+        match mode {
+            MaterializeMode::Symlink => std::os::unix::fs::symlink(target, &dst)?,
+            MaterializeMode::Hardlink => std::fs::hard_link(target, &dst)?,
+            MaterializeMode::Copy => {
+                std::fs::copy(target, &dst)?;
+            }
+        }
+        summary.written += 1;
+    }
+
+    info!(
+        target: CLI_TAG,
+        "Materialized {} files, skipped {} missing",
+        summary.written,
+        summary.missing.len()
+    );
+    Ok(summary)
+}
@@ -0,0 +1,60 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use super::CLI_TAG;
+use crate::common;
+use crate::common::err::STagResult;
+use crate::common::fsops::flush_path;
+use crate::common::notify::Notifier;
+use crate::common::settings::Settings;
+use crate::common::types::file_perms::UMask;
+use crate::common::types::MergeResolution;
+use crate::sql::types::MergeCollision;
+use libc::{gid_t, uid_t};
+use rusqlite::Connection;
+use std::path::Path;
+use tracing::info;
+
+/// Merges the tag or tag group `src` into `dst` in one transaction, and tells `notifier` how many files were moved
+pub fn merge<P, N, F>(
+    settings: &Settings,
+    conn: &mut Connection,
+    mountpoint: P,
+    src: &str,
+    dst: &str,
+    uid: uid_t,
+    gid: gid_t,
+    umask: &UMask,
+    notifier: &N,
+    resolve: F,
+) -> STagResult<usize>
+where
+    P: AsRef<Path>,
+    N: Notifier,
+    F: FnMut(&MergeCollision) -> STagResult<MergeResolution>,
+{
+    info!(target: CLI_TAG, "Merging {} into {}", src, dst);
+
+    let tx = super::begin_write(settings, conn)?;
+    let num_files = common::fsops::merge(settings, &tx, src, dst, uid, gid, umask, resolve)?;
+    if super::commit(settings, tx)? {
+        flush_path(mountpoint.as_ref().join(src), settings);
+        flush_path(mountpoint.as_ref().join(dst), settings);
+        let _ = notifier.merged(src, dst, num_files);
+    }
+    Ok(num_files)
+}
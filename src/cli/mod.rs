@@ -16,17 +16,58 @@
  * along with this program.  If not, see <http://www.gnu.org/licenses/>.
  */
 
+use crate::common::err::STagResult;
+use crate::common::settings::Settings;
+use crate::sql;
+use rusqlite::{Connection, Transaction};
 use std::path::Path;
+use tracing::info;
 
 pub mod commands;
+pub mod demo;
+pub mod glob;
 pub mod handlers;
+pub mod import;
 pub mod ln;
+pub mod materialize;
+pub mod merge;
+pub mod open;
+pub mod progress;
+pub mod prompt;
 pub mod rename;
+pub mod retag;
 pub mod rm;
 pub mod rmdir;
+pub mod rpc;
+pub mod supervisor;
+pub mod swap;
+pub mod sync;
 
 const CLI_TAG: &str = "cli";
 
+/// Begins the write transaction for a cli operation, which only reports its changes if `--dry-run` was given
+fn begin_write<'a>(settings: &Settings, conn: &'a mut Connection) -> STagResult<Transaction<'a>> {
+    Ok(if settings.dry_run() {
+        sql::plan::begin(conn)?
+    } else {
+        sql::begin_write(conn)?
+    })
+}
+
+/// Commits a transaction from `begin_write`, or for a dry run, prints what it would have changed and rolls it back.
+/// Returns whether anything was committed, so that callers know whether to flush the caches
+fn commit(settings: &Settings, tx: Transaction) -> STagResult<bool> {
+    if settings.dry_run() {
+        let plan = sql::plan::rollback(tx)?;
+        info!(target: CLI_TAG, "Dry run, rolled back {} changes", plan.changes.len());
+        println!("Dry run, nothing was changed. This would:\n{}", plan);
+        Ok(false)
+    } else {
+        tx.commit()?;
+        Ok(true)
+    }
+}
+
 fn strip_prefix<'a>(p: &'a Path, prefix: &Path) -> &'a Path {
     p.strip_prefix(prefix).unwrap_or(p)
 }
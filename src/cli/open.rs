@@ -0,0 +1,148 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use super::CLI_TAG;
+use crate::common::err::STagResult;
+use crate::common::types::TagType;
+use crate::sql;
+use crate::sql::types::TaggedFile;
+use rusqlite::Connection;
+use tracing::debug;
+
+/// How well `query` fuzzily matches `name`, or None if it doesn't.  Every character of the query has to appear in the
+/// name, in order, ignoring case.  Higher is better: runs of consecutive characters and matches at the start of a word
+/// score more, and shorter names win ties.
+pub fn fuzzy_score(query: &str, name: &str) -> Option<i64> {
+    let name_chars: Vec<char> = name.to_lowercase().chars().collect();
+    let mut score = 0;
+    let mut pos = 0;
+    let mut prev_match: Option<usize> = None;
+
+    for qc in query.to_lowercase().chars() {
+        let found = (pos..name_chars.len()).find(|idx| name_chars[*idx] == qc)?;
+        score += 1;
+        if prev_match.map_or(false, |prev| prev + 1 == found) {
+            score += 5;
+        }
+        if found == 0 || !name_chars[found - 1].is_alphanumeric() {
+            score += 3;
+        }
+        prev_match = Some(found);
+        pos = found + 1;
+    }
+    Some(score * 1000 - name_chars.len() as i64)
+}
+
+/// Narrows `files` down to the ones named like `name`, best first.  A file named exactly `name` wins outright, otherwise
+/// they're fuzzily matched.  Without a name, every file is a candidate.
+pub fn candidates(mut files: Vec<TaggedFile>, name: Option<&str>) -> Vec<TaggedFile> {
+    let name = match name {
+        Some(name) => name,
+        None => {
+            files.sort_by(|a, b| a.primary_tag.cmp(&b.primary_tag));
+            return files;
+        }
+    };
+
+    let exact: Vec<TaggedFile> = files
+        .iter()
+        .filter(|tf| tf.primary_tag == name)
+        .cloned()
+        .collect();
+    if !exact.is_empty() {
+        return exact;
+    }
+
+    let mut scored: Vec<(i64, TaggedFile)> = files
+        .into_iter()
+        .filter_map(|tf| fuzzy_score(name, &tf.primary_tag).map(|score| (score, tf)))
+        .collect();
+    scored.sort_by(|a, b| {
+        b.0.cmp(&a.0)
+            .then_with(|| a.1.primary_tag.cmp(&b.1.primary_tag))
+    });
+    scored.into_iter().map(|(_, tf)| tf).collect()
+}
+
+/// The files in the intersection of `tags` that could be the one named `name`, best first
+pub fn resolve(
+    conn: &Connection,
+    tags: &[TagType],
+    name: Option<&str>,
+) -> STagResult<Vec<TaggedFile>> {
+    let files = sql::files_tagged_with(conn, tags)?;
+    let found = candidates(files, name);
+    debug!(
+        target: CLI_TAG,
+        "{} candidates to open for {:?} named {:?}",
+        found.len(),
+        tags,
+        name
+    );
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::types::file_perms::Permissions;
+
+    fn tagged_file(id: i64, name: &str) -> TaggedFile {
+        TaggedFile {
+            id,
+            inode: id as u64,
+            device: 1,
+            path: format!("/files/{}", name),
+            primary_tag: name.to_string(),
+            mtime: chrono::Utc::now(),
+            uid: 0,
+            gid: 0,
+            permissions: Permissions::from(0o644),
+            alias_file: None,
+            target_size: None,
+            target_mtime: None,
+            is_dir: false,
+        }
+    }
+
+    #[test]
+    fn test_fuzzy_score() {
+        assert!(fuzzy_score("rpt", "report.pdf").is_some());
+        assert!(fuzzy_score("tpr", "report.pdf").is_none());
+        // consecutive and word-start matches beat scattered ones
+        assert!(fuzzy_score("rep", "report.pdf") > fuzzy_score("rep", "a-r-e-p.pdf"));
+        assert!(fuzzy_score("pdf", "x.pdf") > fuzzy_score("pdf", "longer-name.pdf"));
+    }
+
+    #[test]
+    fn test_candidates() {
+        let files = vec![
+            tagged_file(1, "notes.txt"),
+            tagged_file(2, "report-final.pdf"),
+            tagged_file(3, "report.pdf"),
+        ];
+
+        let found = candidates(files.clone(), Some("report.pdf"));
+        assert_eq!(found.iter().map(|tf| tf.id).collect::<Vec<_>>(), vec![3]);
+
+        let found = candidates(files.clone(), Some("report"));
+        assert_eq!(found.iter().map(|tf| tf.id).collect::<Vec<_>>(), vec![3, 2]);
+
+        assert!(candidates(files.clone(), Some("zzz")).is_empty());
+        assert_eq!(candidates(files, None).len(), 3);
+    }
+}
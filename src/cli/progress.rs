@@ -0,0 +1,151 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+const BAR_WIDTH: usize = 30;
+
+/// Redrawing for every item would slow down a fast batch more than it helps
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A one-line progress bar with an ETA, redrawn in place on any output, usually stderr
+pub struct ProgressBar<W: Write> {
+    output: W,
+    total: usize,
+    started: Instant,
+    last_draw: Option<Instant>,
+}
+
+impl<W: Write> ProgressBar<W> {
+    pub fn new(output: W, total: usize) -> Self {
+        Self {
+            output,
+            total,
+            started: Instant::now(),
+            last_draw: None,
+        }
+    }
+
+    /// Reports that `done` items are finished, `item` being the latest
+    pub fn update(&mut self, done: usize, item: &str) -> std::io::Result<()> {
+        let now = Instant::now();
+        let due = self
+            .last_draw
+            .map_or(true, |last| now.duration_since(last) >= REDRAW_INTERVAL);
+        if !due && done < self.total {
+            return Ok(());
+        }
+        self.last_draw = Some(now);
+
+        let line = render(done, self.total, now.duration_since(self.started), item);
+        write!(self.output, "\r{}\x1b[K", line)?;
+        self.output.flush()
+    }
+
+    /// Ends the bar's line, so that whatever is printed next starts on its own
+    pub fn finish(&mut self) -> std::io::Result<()> {
+        if self.last_draw.is_some() {
+            writeln!(self.output)?;
+        }
+        Ok(())
+    }
+}
+
+/// Estimates the time left from the average time per item so far
+fn eta(done: usize, total: usize, elapsed: Duration) -> Option<Duration> {
+    if done == 0 {
+        return None;
+    }
+    let per_item = elapsed.as_secs_f64() / done as f64;
+    Some(Duration::from_secs_f64(
+        per_item * (total - done.min(total)) as f64,
+    ))
+}
+
+pub fn fmt_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    if secs >= 3600 {
+        format!("{}h{:02}m", secs / 3600, secs % 3600 / 60)
+    } else if secs >= 60 {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+fn render(done: usize, total: usize, elapsed: Duration, item: &str) -> String {
+    let filled = if total == 0 {
+        BAR_WIDTH
+    } else {
+        BAR_WIDTH * done.min(total) / total
+    };
+    let eta = eta(done, total, elapsed).map_or_else(|| "?".to_string(), fmt_duration);
+    format!(
+        "[{}{}] {}/{} ETA {} {}",
+        "#".repeat(filled),
+        " ".repeat(BAR_WIDTH - filled),
+        done,
+        total,
+        eta,
+        item
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        assert_eq!(
+            render(0, 10, Duration::from_secs(0), "a"),
+            format!("[{}] 0/10 ETA ? a", " ".repeat(BAR_WIDTH))
+        );
+        assert_eq!(
+            render(5, 10, Duration::from_secs(50), "b"),
+            format!(
+                "[{}{}] 5/10 ETA 50s b",
+                "#".repeat(BAR_WIDTH / 2),
+                " ".repeat(BAR_WIDTH / 2)
+            )
+        );
+        assert_eq!(
+            render(1, 3, Duration::from_secs(3600), "c"),
+            format!("[{}{}] 1/3 ETA 2h00m c", "#".repeat(10), " ".repeat(20))
+        );
+    }
+
+    #[test]
+    fn test_last_update_always_draws() -> std::io::Result<()> {
+        let mut out = Vec::new();
+        let mut bar = ProgressBar::new(&mut out, 3);
+        bar.update(1, "a")?;
+        // too soon after the first to redraw
+        bar.update(2, "b")?;
+        bar.update(3, "c")?;
+        bar.finish()?;
+        drop(bar);
+
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("1/3"));
+        assert!(!out.contains("2/3"));
+        assert!(out.contains("3/3"));
+        assert!(out.ends_with('\n'));
+        Ok(())
+    }
+}
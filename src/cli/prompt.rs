@@ -0,0 +1,298 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use crate::cli::open::fuzzy_score;
+use crate::common::err::STagResult;
+use crate::common::types::MergeResolution;
+use crate::sql::types::MergeCollision;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+/// Asks how to settle merge collisions, one at a time.  It reads answers a line at a time from any input, so it can be
+/// driven by a terminal or by a script piping in answers.
+pub struct CollisionPrompt<R: BufRead, W: Write> {
+    input: R,
+    output: W,
+    /// Set when an answer was capitalized, meaning "do this for every remaining collision"
+    for_all: Option<MergeResolution>,
+}
+
+impl<R: BufRead, W: Write> CollisionPrompt<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        Self {
+            input,
+            output,
+            for_all: None,
+        }
+    }
+
+    pub fn ask(&mut self, collision: &MergeCollision) -> STagResult<MergeResolution> {
+        if let Some(resolution) = self.for_all {
+            return Ok(resolution);
+        }
+
+        writeln!(
+            self.output,
+            "\"{}\" already exists in the destination",
+            collision.source.primary_tag
+        )?;
+        writeln!(
+            self.output,
+            "  source: {} (modified {})",
+            collision.source.path,
+            collision.source.mtime.to_rfc3339()
+        )?;
+        writeln!(
+            self.output,
+            "  dest:   {} (modified {})",
+            collision.dest.path,
+            collision.dest.mtime.to_rfc3339()
+        )?;
+
+        loop {
+            write!(
+                self.output,
+                "[k]eep both, [n]ewest, [s]ource, [d]est (capitalize to apply to the rest): "
+            )?;
+            self.output.flush()?;
+
+            let mut line = String::new();
+            // out of answers, so leave the rest how a merge would without asking
+            if self.input.read_line(&mut line)? == 0 {
+                self.for_all = Some(MergeResolution::KeepBoth);
+                return Ok(MergeResolution::KeepBoth);
+            }
+
+            let answer = line.trim();
+            let resolution = match answer.to_lowercase().as_str() {
+                "k" => MergeResolution::KeepBoth,
+                "n" => MergeResolution::Newest,
+                "s" => MergeResolution::Source,
+                "d" => MergeResolution::Dest,
+                _ => continue,
+            };
+            if answer.chars().all(char::is_uppercase) {
+                self.for_all = Some(resolution);
+            }
+            return Ok(resolution);
+        }
+    }
+}
+
+/// Asks where directories of tagged files have moved to, when importing a collection onto another machine.  Like
+/// `CollisionPrompt`, it reads answers a line at a time from any input.
+pub struct RemapPrompt<R: BufRead, W: Write> {
+    input: R,
+    output: W,
+}
+
+impl<R: BufRead, W: Write> RemapPrompt<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        Self { input, output }
+    }
+
+    /// Asks for the new location of `dir`, which holds `count` missing files.  A blank answer, or running out of
+    /// answers, leaves the files where they were.
+    pub fn ask(&mut self, dir: &Path, count: usize) -> STagResult<Option<PathBuf>> {
+        writeln!(
+            self.output,
+            "{} tagged file(s) under {} don't exist on this machine",
+            count,
+            dir.display()
+        )?;
+
+        loop {
+            write!(
+                self.output,
+                "New location for {} (blank to leave as is): ",
+                dir.display()
+            )?;
+            self.output.flush()?;
+
+            let mut line = String::new();
+            if self.input.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+
+            let answer = line.trim();
+            if answer.is_empty() {
+                return Ok(None);
+            }
+            let new_dir = PathBuf::from(answer);
+            if !new_dir.is_absolute() {
+                writeln!(self.output, "The new location must be an absolute path")?;
+                continue;
+            }
+            return Ok(Some(new_dir));
+        }
+    }
+}
+
+/// How many choices `PickPrompt` lists at once, before asking for more letters to narrow them down
+const PICK_LISTED: usize = 20;
+
+/// Asks which of several choices was meant, by number, or by typing part of a name to narrow the list down.  Like the
+/// other prompts, it reads answers a line at a time from any input.
+pub struct PickPrompt<R: BufRead, W: Write> {
+    input: R,
+    output: W,
+}
+
+impl<R: BufRead, W: Write> PickPrompt<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        Self { input, output }
+    }
+
+    /// Returns the index of the picked choice, or None for a blank answer or running out of answers
+    pub fn ask(&mut self, choices: &[String]) -> STagResult<Option<usize>> {
+        let mut shown: Vec<usize> = (0..choices.len()).collect();
+        loop {
+            for (num, idx) in shown.iter().take(PICK_LISTED).enumerate() {
+                writeln!(self.output, "{:>3}) {}", num + 1, choices[*idx])?;
+            }
+            if shown.len() > PICK_LISTED {
+                writeln!(
+                    self.output,
+                    "     ...and {} more",
+                    shown.len() - PICK_LISTED
+                )?;
+            }
+            write!(self.output, "Number to open, or part of a name to narrow: ")?;
+            self.output.flush()?;
+
+            let mut line = String::new();
+            if self.input.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            let answer = line.trim();
+            if answer.is_empty() {
+                return Ok(None);
+            }
+
+            if let Ok(num) = answer.parse::<usize>() {
+                if num >= 1 && num <= shown.len().min(PICK_LISTED) {
+                    return Ok(Some(shown[num - 1]));
+                }
+                writeln!(self.output, "There's no choice {}", num)?;
+                continue;
+            }
+
+            let mut narrowed: Vec<(i64, usize)> = shown
+                .iter()
+                .filter_map(|idx| fuzzy_score(answer, &choices[*idx]).map(|score| (score, *idx)))
+                .collect();
+            if narrowed.is_empty() {
+                writeln!(self.output, "Nothing matches {:?}", answer)?;
+                continue;
+            }
+            narrowed.sort_by(|a, b| b.0.cmp(&a.0));
+            shown = narrowed.into_iter().map(|(_, idx)| idx).collect();
+            if shown.len() == 1 {
+                return Ok(Some(shown[0]));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::types::file_perms::Permissions;
+    use crate::sql::types::TaggedFile;
+
+    fn tagged_file(id: i64, path: &str) -> TaggedFile {
+        TaggedFile {
+            id,
+            inode: id as u64,
+            device: 1,
+            path: path.to_string(),
+            primary_tag: "a.txt".to_string(),
+            mtime: chrono::Utc::now(),
+            uid: 0,
+            gid: 0,
+            permissions: Permissions::from(0o644),
+            alias_file: None,
+            target_size: None,
+            target_mtime: None,
+            is_dir: false,
+        }
+    }
+
+    #[test]
+    fn test_scripted_answers() -> STagResult<()> {
+        let collision = MergeCollision {
+            source: tagged_file(1, "/src/a.txt"),
+            dest: tagged_file(2, "/dst/a.txt"),
+        };
+        let mut output = vec![];
+        let mut prompt = CollisionPrompt::new(&b"x\ns\nD\n"[..], &mut output);
+
+        // the bad answer is asked again
+        assert_eq!(prompt.ask(&collision)?, MergeResolution::Source);
+        assert_eq!(prompt.ask(&collision)?, MergeResolution::Dest);
+        // the capital D applies to everything after it
+        assert_eq!(prompt.ask(&collision)?, MergeResolution::Dest);
+        Ok(())
+    }
+
+    #[test]
+    fn test_out_of_answers() -> STagResult<()> {
+        let collision = MergeCollision {
+            source: tagged_file(1, "/src/a.txt"),
+            dest: tagged_file(2, "/dst/a.txt"),
+        };
+        let mut prompt = CollisionPrompt::new(&b"n\n"[..], std::io::sink());
+        assert_eq!(prompt.ask(&collision)?, MergeResolution::Newest);
+        assert_eq!(prompt.ask(&collision)?, MergeResolution::KeepBoth);
+        Ok(())
+    }
+
+    #[test]
+    fn test_remap_answers() -> STagResult<()> {
+        let dir = Path::new("/home/amy");
+        let mut prompt = RemapPrompt::new(&b"relative\n/Users/amy\n\n"[..], std::io::sink());
+
+        // the relative answer is asked again
+        assert_eq!(prompt.ask(dir, 3)?, Some(PathBuf::from("/Users/amy")));
+        assert_eq!(prompt.ask(dir, 3)?, None);
+        assert_eq!(prompt.ask(dir, 3)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_pick_answers() -> STagResult<()> {
+        let choices = vec![
+            "notes.txt".to_string(),
+            "report.pdf".to_string(),
+            "report-final.pdf".to_string(),
+        ];
+
+        // an out of range number is asked again
+        let mut prompt = PickPrompt::new(&b"9\n2\n"[..], std::io::sink());
+        assert_eq!(prompt.ask(&choices)?, Some(1));
+
+        // narrowing down to one picks it, and narrowing to several lists them again
+        let mut prompt = PickPrompt::new(&b"final\n"[..], std::io::sink());
+        assert_eq!(prompt.ask(&choices)?, Some(2));
+        let mut prompt = PickPrompt::new(&b"report\n2\n"[..], std::io::sink());
+        assert_eq!(prompt.ask(&choices)?, Some(2));
+
+        let mut prompt = PickPrompt::new(&b"\n"[..], std::io::sink());
+        assert_eq!(prompt.ask(&choices)?, None);
+        Ok(())
+    }
+}
@@ -16,18 +16,20 @@
  * along with this program.  If not, see <http://www.gnu.org/licenses/>.
  */
 use super::CLI_TAG;
-use crate::common;
-use crate::common::err::STagResult;
+use crate::common::err::{STagError, STagResult};
 use crate::common::fsops::flush_path;
 use crate::common::notify::Notifier;
 use crate::common::settings::Settings;
 use crate::common::types::file_perms::UMask;
+use crate::common::types::{MergeResolution, TagCollection, TagType};
+use crate::sql::types::MergeCollision;
+use crate::{common, sql};
 use libc::{gid_t, uid_t};
-use log::info;
-use rusqlite::{Connection, TransactionBehavior};
+use rusqlite::Connection;
 use std::path::Path;
+use tracing::info;
 
-pub fn rename<P: AsRef<Path>, Q: AsRef<Path>, R: AsRef<Path>, N: Notifier>(
+pub fn rename<P, Q, R, N, F>(
     settings: &Settings,
     conn: &mut Connection,
     mountpoint: R,
@@ -37,7 +39,15 @@ pub fn rename<P: AsRef<Path>, Q: AsRef<Path>, R: AsRef<Path>, N: Notifier>(
     gid: gid_t,
     umask: &UMask,
     notifier: &N,
-) -> STagResult<()> {
+    resolve: F,
+) -> STagResult<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+    R: AsRef<Path>,
+    N: Notifier,
+    F: FnMut(&MergeCollision) -> STagResult<MergeResolution>,
+{
     info!(
         target: CLI_TAG,
         "Renaming file from {} to {}",
@@ -48,7 +58,7 @@ pub fn rename<P: AsRef<Path>, Q: AsRef<Path>, R: AsRef<Path>, N: Notifier>(
     let relative_src = super::strip_prefix(src.as_ref(), mountpoint.as_ref());
     let relative_dst = super::strip_prefix(dst.as_ref(), mountpoint.as_ref());
 
-    let tx = conn.transaction_with_behavior(TransactionBehavior::Exclusive)?;
+    let tx = super::begin_write(settings, conn)?;
     common::fsops::move_or_merge(
         settings,
         &tx,
@@ -58,11 +68,104 @@ pub fn rename<P: AsRef<Path>, Q: AsRef<Path>, R: AsRef<Path>, N: Notifier>(
         gid,
         umask,
         notifier,
+        resolve,
     )?;
-    tx.commit()?;
+    if super::commit(settings, tx)? {
+        flush_path(src.as_ref(), settings);
+        flush_path(dst.as_ref(), settings);
+    }
+
+    Ok(())
+}
+
+/// Finds the filename collisions that `rename` would cause, so that they can be decided on before it runs
+pub fn merge_collisions<P, Q, R>(
+    settings: &Settings,
+    conn: &Connection,
+    mountpoint: R,
+    src: P,
+    dst: Q,
+) -> STagResult<Vec<MergeCollision>>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+    R: AsRef<Path>,
+{
+    let relative_src = super::strip_prefix(src.as_ref(), mountpoint.as_ref());
+    let relative_dst = super::strip_prefix(dst.as_ref(), mountpoint.as_ref());
+    common::fsops::merge_collisions(settings, conn, relative_src, relative_dst)
+}
+
+/// Moves the tagged file `src`, in the collection mounted at `src_mountpoint`, to the tag directory `dst` in another
+/// collection.  The file is tagged with `dst`'s tags and removed from the tag it was in, the same as a move between
+/// tags within one collection.  Both collections change together, or neither does.
+pub fn move_between_collections<P, Q, N>(
+    settings: &Settings,
+    src_col: &str,
+    dst_col: &str,
+    src: P,
+    dst: Q,
+    uid: uid_t,
+    gid: gid_t,
+    umask: &UMask,
+    notifier: &N,
+) -> STagResult<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+    N: Notifier,
+{
+    info!(
+        target: CLI_TAG,
+        "Moving file {} in {} to {} in {}",
+        src.as_ref().display(),
+        src_col,
+        dst.as_ref().display(),
+        dst_col
+    );
+
+    // the two collections are committed separately, so there's no single transaction to roll back
+    if settings.dry_run() {
+        return Err(STagError::Other(
+            "--dry-run isn't supported for moves between collections".into(),
+        ));
+    }
+
+    let relative_src = super::strip_prefix(src.as_ref(), &settings.mountpoint(src_col));
+    let relative_dst = super::strip_prefix(dst.as_ref(), &settings.mountpoint(dst_col));
+
+    common::xtx::run(settings, "mv", src_col, dst_col, |from, to| {
+        let tags = TagCollection::new(from.settings, relative_src);
+        let intersect = tags.all_but_last().as_slice();
+        let maybe_tf = match tags.primary_type()? {
+            TagType::DeviceFileSymlink(df) => sql::contains_file(from.tx, intersect, |tf| {
+                tf.device == df.device && tf.inode == df.inode
+            })?,
+            TagType::Symlink(name) => sql::contains_file(from.tx, intersect, |tf| {
+                from.settings.display_matches(name, &tf.primary_tag)
+            })?,
+            _ => None,
+        };
+        // only files can move between collections, because tags and tag groups don't mean the same thing in each
+        let tf = maybe_tf.ok_or_else(|| STagError::InvalidPath(src.as_ref().into()))?;
+
+        common::fsops::ln(
+            to.settings,
+            to.tx,
+            Path::new(&tf.path),
+            relative_dst,
+            &tf.primary_tag,
+            uid,
+            gid,
+            umask,
+            None,
+            notifier,
+        )?;
+        common::fsops::rm(from.settings, from.tx, relative_src)?;
+        Ok(())
+    })?;
 
     flush_path(src.as_ref(), settings);
     flush_path(dst.as_ref(), settings);
-
     Ok(())
 }
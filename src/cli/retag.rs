@@ -0,0 +1,61 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use super::CLI_TAG;
+use crate::common;
+use crate::common::err::STagResult;
+use crate::common::fsops::flush_path;
+use crate::common::notify::Notifier;
+use crate::common::settings::Settings;
+use crate::common::types::file_perms::UMask;
+use crate::sql::types::TaggedFile;
+use libc::{gid_t, uid_t};
+use rusqlite::Connection;
+use std::path::Path;
+use tracing::info;
+
+/// Takes `file` out of the tags `from` and puts it in the tags `to` in one transaction, and tells `notifier` what
+/// changed.  Returns the names of the tags that were (added, removed).
+pub fn retag<P, N>(
+    settings: &Settings,
+    conn: &mut Connection,
+    mountpoint: P,
+    file: &TaggedFile,
+    from: &[&str],
+    to: &[&str],
+    uid: uid_t,
+    gid: gid_t,
+    umask: &UMask,
+    notifier: &N,
+) -> STagResult<(Vec<String>, Vec<String>)>
+where
+    P: AsRef<Path>,
+    N: Notifier,
+{
+    info!(target: CLI_TAG, "Retagging {} from {:?} to {:?}", file.path, from, to);
+
+    let tx = super::begin_write(settings, conn)?;
+    let (added, removed) =
+        common::fsops::retag_from_to(settings, &tx, file, from, to, uid, gid, umask)?;
+    if super::commit(settings, tx)? {
+        for tag in added.iter().chain(&removed) {
+            flush_path(mountpoint.as_ref().join(tag), settings);
+        }
+        let _ = notifier.retagged(Path::new(&file.path), &added, &removed);
+    }
+    Ok((added, removed))
+}
@@ -20,9 +20,9 @@ use crate::common;
 use crate::common::err::STagResult;
 use crate::common::fsops::flush_tags;
 use crate::common::settings::Settings;
-use log::{debug, info};
-use rusqlite::{Connection, TransactionBehavior};
+use rusqlite::Connection;
 use std::path::Path;
+use tracing::{debug, info};
 
 pub fn rm<P1: AsRef<Path>, P2: AsRef<Path>>(
     settings: &Settings,
@@ -30,29 +30,43 @@ pub fn rm<P1: AsRef<Path>, P2: AsRef<Path>>(
     file: P1,
     mountpoint: P2,
 ) -> STagResult<()> {
-    let file = file.as_ref();
-    info!(target: CLI_TAG, "Removing file {:?}", file);
+    rm_all(settings, conn, &[file.as_ref()], mountpoint)
+}
 
-    let relpath = super::strip_prefix(file.as_ref(), mountpoint.as_ref());
+/// Like `rm`, but removes every one of `files` in a single transaction
+pub fn rm_all<P: AsRef<Path>>(
+    settings: &Settings,
+    conn: &mut Connection,
+    files: &[&Path],
+    mountpoint: P,
+) -> STagResult<()> {
+    let mountpoint = mountpoint.as_ref();
 
-    // this will remove our file from the database
-    let tx = conn.transaction_with_behavior(TransactionBehavior::Exclusive)?;
-    common::fsops::rm(settings, &tx, relpath)?;
-    tx.commit()?;
+    // this will remove our files from the database
+    let tx = super::begin_write(settings, conn)?;
+    for file in files {
+        info!(target: CLI_TAG, "Removing file {:?}", file);
+        common::fsops::rm(settings, &tx, super::strip_prefix(file, mountpoint))?;
+    }
+    if !super::commit(settings, tx)? {
+        return Ok(());
+    }
 
-    // but now we need to communicate to supertag that we want to clear the entry from its caches.
-    // we do this by removing the file, but appending a special char, so that when supertag sees this
-    // path in the unlink handler, it will know that we just want it cleared from the caches
-    let sync_file = settings.suffix_sync_char(file)?;
-    debug!(
-        target: CLI_TAG,
-        "Sending readdir cache sync for {:?}", sync_file
-    );
-    let _ = std::fs::metadata(sync_file);
+    for file in files {
+        // but now we need to communicate to supertag that we want to clear the entry from its caches.
+        // we do this by removing the file, but appending a special char, so that when supertag sees this
+        // path in the unlink handler, it will know that we just want it cleared from the caches
+        let sync_file = settings.suffix_sync_char(file)?;
+        debug!(
+            target: CLI_TAG,
+            "Sending readdir cache sync for {:?}", sync_file
+        );
+        let _ = std::fs::metadata(sync_file);
 
-    // this flushes all of the tags that contain the file removed. this is necessary because these tags
-    // may exist in the readdir cache with the wrong size/num_files count now
-    flush_tags(relpath, settings, mountpoint);
+        // this flushes all of the tags that contain the file removed. this is necessary because these tags
+        // may exist in the readdir cache with the wrong size/num_files count now
+        flush_tags(super::strip_prefix(file, mountpoint), settings, mountpoint);
+    }
 
     Ok(())
 }
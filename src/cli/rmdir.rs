@@ -20,9 +20,9 @@ use crate::common;
 use crate::common::err::STagResult;
 use crate::common::fsops::flush_path;
 use crate::common::settings::Settings;
-use log::info;
-use rusqlite::{Connection, TransactionBehavior};
+use rusqlite::Connection;
 use std::path::Path;
+use tracing::info;
 
 pub fn rmdir<P1: AsRef<Path>, P2: AsRef<Path>>(
     settings: &Settings,
@@ -35,11 +35,11 @@ pub fn rmdir<P1: AsRef<Path>, P2: AsRef<Path>>(
 
     let relpath = super::strip_prefix(path.as_ref(), mountpoint.as_ref());
 
-    let tx = conn.transaction_with_behavior(TransactionBehavior::Exclusive)?;
+    let tx = super::begin_write(settings, conn)?;
     common::fsops::rmdir(settings, &tx, relpath)?;
-    tx.commit()?;
-
-    flush_path(path, settings);
+    if super::commit(settings, tx)? {
+        flush_path(path, settings);
+    }
 
     Ok(())
 }
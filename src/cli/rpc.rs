@@ -0,0 +1,824 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! A JSON-RPC 2.0 server for `tag rpc`, so that a frontend can spawn the cli as a child process and drive it over
+//! stdin and stdout, without a socket or http server.  Each request and each response is one line of JSON.  A mounted
+//! collection's daemon also answers the same requests on its notifier socket, in between the notes it sends, so that
+//! a frontend that's already listening for notes doesn't need a second channel.
+//!
+//! Methods are kept in a registry along with the protocol version that introduced them.  A frontend calls
+//! `rpc.describe` first, and uses the version it gets back to decide what it can ask for.  Long operations report
+//! their progress with `progress` notifications, which carry the id of the request they belong to, and are always
+//! written before that request's response.
+//!
+//! Any call can carry an `idempotency_key` param.  If a call with the same key was already made recently, its result is
+//! returned again instead of the call being made, so that a frontend can safely retry a call that it never heard back
+//! from.
+
+use super::CLI_TAG;
+use crate::common::err::STagError;
+use crate::common::fsops::flush_path;
+use crate::common::identity;
+use crate::common::notify::desktop::DesktopNotifier;
+use crate::common::notify::uds::UDSNotifier;
+use crate::common::notify::RpcSource;
+use crate::common::settings::Settings;
+use crate::common::types::file_perms::UMask;
+use crate::common::types::MergeResolution;
+use crate::sql::idempotency::{self, Reservation};
+use crate::sql::stats;
+use crate::sql::types::TagOrTagGroup;
+use crate::{common, sql};
+use rusqlite::Connection;
+use serde_json::{json, Value};
+use std::error::Error;
+use std::io::{BufRead, Write};
+use std::path::Path;
+use std::sync::Arc;
+use tracing::{debug, info, warn};
+
+/// Bumped whenever a method is added, or an existing method's params or result change
+pub const PROTOCOL_VERSION: u32 = 4;
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// The method ran, but the operation itself failed.  The message has the reason.
+const OP_FAILED: i64 = -32000;
+
+#[derive(Debug)]
+pub struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        json!({"code": self.code, "message": self.message})
+    }
+}
+
+impl From<STagError> for RpcError {
+    fn from(e: STagError) -> Self {
+        RpcError::new(OP_FAILED, e.to_string())
+    }
+}
+
+impl From<Box<dyn Error>> for RpcError {
+    fn from(e: Box<dyn Error>) -> Self {
+        RpcError::new(OP_FAILED, e.to_string())
+    }
+}
+
+impl From<rusqlite::Error> for RpcError {
+    fn from(e: rusqlite::Error) -> Self {
+        RpcError::new(OP_FAILED, e.to_string())
+    }
+}
+
+impl From<std::io::Error> for RpcError {
+    fn from(e: std::io::Error) -> Self {
+        RpcError::new(OP_FAILED, e.to_string())
+    }
+}
+
+type RpcResult = Result<Value, RpcError>;
+type Handler = fn(&mut Settings, &Value, &mut Progress) -> RpcResult;
+
+struct Method {
+    name: &'static str,
+    /// The protocol version that this method first appeared in
+    since: u32,
+    about: &'static str,
+    handler: Handler,
+}
+
+const METHODS: &[Method] = &[
+    Method {
+        name: "rpc.describe",
+        since: 1,
+        about: "The protocol version and every method it supports",
+        handler: describe,
+    },
+    Method {
+        name: "query",
+        since: 1,
+        about: "Files matching the query terms in a collection",
+        handler: query,
+    },
+    Method {
+        name: "ln",
+        since: 1,
+        about: "Links files into a tag path, reporting progress per file",
+        handler: ln,
+    },
+    Method {
+        name: "rm",
+        since: 1,
+        about: "Removes a file from its tags",
+        handler: rm,
+    },
+    Method {
+        name: "rename",
+        since: 1,
+        about: "Renames or merges a tag path, the same as `tag mv`",
+        handler: rename,
+    },
+    Method {
+        name: "pins",
+        since: 1,
+        about: "Every pinned directory in a collection",
+        handler: pins,
+    },
+    Method {
+        name: "pin",
+        since: 1,
+        about: "Pins a nested tag path, so it exists without files",
+        handler: pin,
+    },
+    Method {
+        name: "stats",
+        since: 1,
+        about: "Tag counts, co-occurrence of the top tags, and files without tags",
+        handler: stats,
+    },
+    Method {
+        name: "merge",
+        since: 2,
+        about: "Merges one tag or tag group into another, the same as `tag merge`",
+        handler: merge,
+    },
+    Method {
+        name: "tags",
+        since: 3,
+        about: "Every tag in a collection, with how many files it has",
+        handler: tags,
+    },
+    Method {
+        name: "retag",
+        since: 3,
+        about: "Adds tags to and removes tags from a file, the same as `tag retag`",
+        handler: retag,
+    },
+    Method {
+        name: "flush",
+        since: 3,
+        about: "Flushes the mount's cached listing of a directory, so that it's read from the database again",
+        handler: flush,
+    },
+];
+
+/// Writes `progress` notifications for the request currently being handled
+pub struct Progress<'a> {
+    id: &'a Value,
+    output: &'a mut dyn Write,
+}
+
+impl<'a> Progress<'a> {
+    pub fn report(&mut self, done: usize, total: usize, item: &str) -> std::io::Result<()> {
+        let note = json!({
+            "jsonrpc": "2.0",
+            "method": "progress",
+            "params": {"id": self.id, "done": done, "total": total, "item": item},
+        });
+        writeln!(self.output, "{}", note)?;
+        self.output.flush()
+    }
+}
+
+/// The methods that the peers of a daemon's notifier socket may call.  They're only the ones that a file manager
+/// integration needs, since anything that the socket's owner can connect to can call them.
+pub const SOCKET_METHODS: &[&str] = &["rpc.describe", "tags", "retag", "query", "flush"];
+
+pub struct RpcServer<R: BufRead, W: Write> {
+    settings: Settings,
+    input: R,
+    output: W,
+    /// If set, the only methods that may be called
+    only: Option<&'static [&'static str]>,
+}
+
+impl<R: BufRead, W: Write> RpcServer<R, W> {
+    pub fn new(settings: Settings, input: R, output: W) -> Self {
+        Self {
+            settings,
+            input,
+            output,
+            only: None,
+        }
+    }
+
+    /// Refuses every method but `methods`, as though they didn't exist
+    pub fn only(mut self, methods: &'static [&'static str]) -> Self {
+        self.only = Some(methods);
+        self
+    }
+
+    fn callable(&self, method: &str) -> bool {
+        self.only.map_or(true, |only| only.contains(&method))
+    }
+
+    /// Answers requests until the input is closed
+    pub fn serve(&mut self) -> std::io::Result<()> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.input.read_line(&mut line)? == 0 {
+                info!(target: CLI_TAG, "RPC input closed, exiting");
+                return Ok(());
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+            if let Some(response) = self.dispatch(line.trim()) {
+                writeln!(self.output, "{}", response)?;
+                self.output.flush()?;
+            }
+        }
+    }
+
+    /// Handles one line of input, returning the response to write, if any.  Notifications, which are requests
+    /// without an id, get no response, even if they fail.
+    pub fn dispatch(&mut self, line: &str) -> Option<Value> {
+        let request: Value = match serde_json::from_str(line) {
+            Ok(request) => request,
+            Err(e) => {
+                warn!(target: CLI_TAG, "Couldn't parse RPC request: {}", e);
+                return Some(error_response(
+                    &Value::Null,
+                    &RpcError::new(PARSE_ERROR, e.to_string()),
+                ));
+            }
+        };
+
+        let id = request.get("id").cloned();
+        let method = match (
+            request.get("jsonrpc").and_then(Value::as_str),
+            request.get("method").and_then(Value::as_str),
+        ) {
+            (Some("2.0"), Some(method)) => method,
+            _ => {
+                let err = RpcError::new(INVALID_REQUEST, "Not a JSON-RPC 2.0 request");
+                return Some(error_response(&id.unwrap_or(Value::Null), &err));
+            }
+        };
+        let params = request.get("params").cloned().unwrap_or(Value::Null);
+        debug!(target: CLI_TAG, "RPC call {} {}", method, params);
+
+        let progress_id = id.clone().unwrap_or(Value::Null);
+        let mut progress = Progress {
+            id: &progress_id,
+            output: &mut self.output,
+        };
+        let found = METHODS
+            .iter()
+            .find(|m| m.name == method && self.callable(m.name));
+        let mut result = match (found, params.get("idempotency_key")) {
+            (Some(m), None) => (m.handler)(&mut self.settings, &params, &mut progress),
+            (Some(m), Some(key)) => match key.as_str() {
+                Some(key) => call_once(m, key, &mut self.settings, &params, &mut progress),
+                None => Err(RpcError::new(
+                    INVALID_PARAMS,
+                    "Param idempotency_key must be a string",
+                )),
+            },
+            (None, _) => Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("No method named {}", method),
+            )),
+        };
+        // and it only describes the methods that can be called
+        if let (Ok(described), "rpc.describe") = (&mut result, method) {
+            if let Some(methods) = described["methods"].as_array_mut() {
+                methods.retain(|m| m["name"].as_str().map_or(false, |name| self.callable(name)));
+            }
+        }
+
+        let id = id?;
+        Some(match result {
+            Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+            Err(e) => {
+                warn!(target: CLI_TAG, "RPC call {} failed: {}", method, e.message);
+                error_response(&id, &e)
+            }
+        })
+    }
+}
+
+/// Answers JSON-RPC from the peers of a daemon's notifier socket, for the `SOCKET_METHODS`.  Each request gets settings
+/// of its own, like a separate `tag rpc` would, but they're held to the daemon's write gate.
+pub fn socket_source(settings: Arc<Settings>) -> RpcSource {
+    Arc::new(move |line: &str, output: &mut dyn Write| {
+        let req_settings = settings.for_collection(&settings.get_collection());
+        if let Some(reason) = settings.write_gate() {
+            req_settings.close_write_gate(&reason);
+        }
+        req_settings.set_disk_full(settings.disk_full());
+        RpcServer::new(req_settings, std::io::empty(), output)
+            .only(SOCKET_METHODS)
+            .dispatch(line)
+    })
+}
+
+/// Calls `m`, unless it was already called with `key`, in which case the result it returned then is returned again.
+/// The key is reserved before the call is made, so a retry that arrives while the first call is still being made is
+/// refused instead of making it a second time.  Failed calls aren't kept, so retrying one makes it again.
+fn call_once(
+    m: &Method,
+    key: &str,
+    settings: &mut Settings,
+    params: &Value,
+    progress: &mut Progress,
+) -> RpcResult {
+    let col = key_collection(settings, params)?;
+    let mut conn = sql::db_for_collection(settings, &col)?;
+    sql::migrations::migrate(&mut conn, &common::version_str())?;
+
+    match idempotency::reserve(&mut conn, key, m.name, sql::get_now_secs())? {
+        Reservation::Reserved => (),
+        Reservation::Running(method) | Reservation::Done(method, _) if method != m.name => {
+            return Err(RpcError::new(
+                INVALID_PARAMS,
+                format!("Idempotency key {} was already used for {}", key, method),
+            ));
+        }
+        Reservation::Running(method) => {
+            return Err(RpcError::new(
+                OP_FAILED,
+                format!(
+                    "A {} call with idempotency key {} is still being made",
+                    method, key
+                ),
+            ));
+        }
+        Reservation::Done(method, result) => {
+            info!(target: CLI_TAG, "Repeating the result of {} for idempotency key {}", method, key);
+            return serde_json::from_str(&result)
+                .map_err(|e| RpcError::new(OP_FAILED, e.to_string()));
+        }
+    }
+
+    match (m.handler)(settings, params, progress) {
+        Ok(result) => {
+            idempotency::remember(&conn, key, &result.to_string())?;
+            Ok(result)
+        }
+        Err(e) => {
+            idempotency::release(&conn, key)?;
+            Err(e)
+        }
+    }
+}
+
+/// The collection whose database keeps a call's idempotency key, which is the collection that the call changes.
+/// That's its `collection` param, or else the collection of the path that it's given.
+fn key_collection(settings: &mut Settings, params: &Value) -> Result<String, RpcError> {
+    if let Some(col) = params.get("collection").and_then(Value::as_str) {
+        return Ok(col.to_string());
+    }
+    let path = ["path", "src", "file"]
+        .iter()
+        .find_map(|name| params.get(*name).and_then(Value::as_str));
+    match path {
+        Some(path) => Ok(settings.resolve_collection(path)?),
+        None => Err(RpcError::new(
+            INVALID_PARAMS,
+            "An idempotency key needs a collection or a path to be kept with",
+        )),
+    }
+}
+
+fn error_response(id: &Value, err: &RpcError) -> Value {
+    json!({"jsonrpc": "2.0", "id": id, "error": err.to_json()})
+}
+
+fn str_param<'a>(params: &'a Value, name: &str) -> Result<&'a str, RpcError> {
+    params.get(name).and_then(Value::as_str).ok_or_else(|| {
+        RpcError::new(
+            INVALID_PARAMS,
+            format!("Param {} is required, and must be a string", name),
+        )
+    })
+}
+
+fn strs_param<'a>(params: &'a Value, name: &str) -> Result<Vec<&'a str>, RpcError> {
+    params
+        .get(name)
+        .and_then(Value::as_array)
+        .and_then(|values| values.iter().map(Value::as_str).collect())
+        .ok_or_else(|| {
+            RpcError::new(
+                INVALID_PARAMS,
+                format!("Param {} is required, and must be a list of strings", name),
+            )
+        })
+}
+
+/// Like `strs_param`, but the param can be left out, which is the same as an empty list
+fn opt_strs_param<'a>(params: &'a Value, name: &str) -> Result<Vec<&'a str>, RpcError> {
+    match params.get(name) {
+        Some(_) => strs_param(params, name),
+        None => Ok(vec![]),
+    }
+}
+
+/// How to settle merge collisions.  There's nobody to prompt, so every collision is settled the same way.
+fn resolution_param(params: &Value) -> Result<MergeResolution, RpcError> {
+    match params.get("resolve").and_then(Value::as_str) {
+        Some(name) => MergeResolution::from_name(name)
+            .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("Unknown resolution {}", name))),
+        None => Ok(MergeResolution::KeepBoth),
+    }
+}
+
+/// Opens an existing collection's database, for the methods that take a collection name instead of a path
+fn open_collection(settings: &mut Settings, params: &Value) -> Result<Connection, RpcError> {
+    let col = str_param(params, "collection")?;
+    settings.set_collection(col, true);
+    let db_file = settings.db_file(col);
+    if !db_file.exists() {
+        return Err(RpcError::new(
+            OP_FAILED,
+            format!("No database for collection {} at {:?}", col, db_file),
+        ));
+    }
+    let mut conn = sql::db_for_collection(settings, col)?;
+    sql::migrations::migrate(&mut conn, &common::version_str())?;
+    Ok(conn)
+}
+
+fn describe(_settings: &mut Settings, _params: &Value, _progress: &mut Progress) -> RpcResult {
+    let methods: Vec<Value> = METHODS
+        .iter()
+        .map(|m| json!({"name": m.name, "since": m.since, "about": m.about}))
+        .collect();
+    Ok(json!({
+        "version": PROTOCOL_VERSION,
+        "supertag": common::version_str(),
+        "methods": methods,
+    }))
+}
+
+fn query(settings: &mut Settings, params: &Value, _progress: &mut Progress) -> RpcResult {
+    let conn = open_collection(settings, params)?;
+    let terms = strs_param(params, "terms")?;
+    let tags = settings.query_to_tags(&terms)?;
+    let files: Vec<Value> = sql::files_tagged_with(&conn, &tags)?
+        .iter()
+        .map(|tf| {
+            json!({
+                "path": tf.path,
+                "name": tf.primary_tag,
+                "device": tf.device,
+                "inode": tf.inode,
+                "mtime": tf.mtime.to_rfc3339(),
+            })
+        })
+        .collect();
+    Ok(Value::from(files))
+}
+
+fn ln(settings: &mut Settings, params: &Value, progress: &mut Progress) -> RpcResult {
+    let files = strs_param(params, "files")?;
+    let tag_path = Path::new(str_param(params, "path")?);
+
+    let umask = UMask::default();
+    let uid = unsafe { libc::getuid() };
+    let gid = unsafe { libc::getgid() };
+
+    let col = settings.resolve_collection(tag_path)?;
+    let mut conn = sql::db_for_collection(settings, &col)?;
+    let mountpoint = settings.mountpoint(&col);
+    let notifier = DesktopNotifier::from_settings(&settings);
+
+    // one file at a time, so that there's something to report between them
+    for (i, file) in files.iter().enumerate() {
+        crate::ln(
+            settings,
+            &mut conn,
+            &mountpoint,
+            vec![Path::new(file)],
+            tag_path,
+            uid,
+            gid,
+            &umask,
+            &notifier,
+        )?;
+        progress.report(i + 1, files.len(), file)?;
+    }
+    Ok(json!({"linked": files.len()}))
+}
+
+fn rm(settings: &mut Settings, params: &Value, _progress: &mut Progress) -> RpcResult {
+    let file = str_param(params, "path")?;
+    let col = settings.resolve_collection(file)?;
+    let mut conn = sql::db_for_collection(settings, &col)?;
+    crate::rm(settings, &mut conn, file, settings.mountpoint(&col))?;
+    Ok(Value::Null)
+}
+
+fn rename(settings: &mut Settings, params: &Value, _progress: &mut Progress) -> RpcResult {
+    let src = str_param(params, "src")?;
+    let dst = str_param(params, "dst")?;
+    let resolution = resolution_param(params)?;
+
+    let umask = UMask::default();
+    let uid = unsafe { libc::getuid() };
+    let gid = unsafe { libc::getgid() };
+
+    let col = settings.resolve_collection(src)?;
+    let mut conn = sql::db_for_collection(settings, &col)?;
+    let notifier = UDSNotifier::new(settings.notify_socket_file(&col), false)?;
+
+    crate::rename(
+        settings,
+        &mut conn,
+        settings.mountpoint(&col),
+        src,
+        dst,
+        uid,
+        gid,
+        &umask,
+        &notifier,
+        |_| Ok(resolution),
+    )?;
+    Ok(Value::Null)
+}
+
+fn pins(settings: &mut Settings, params: &Value, _progress: &mut Progress) -> RpcResult {
+    let conn = open_collection(settings, params)?;
+    let pins: Vec<Value> = sql::all_pins(&conn)?
+        .iter()
+        .map(|pin| {
+            let parts: Vec<String> = pin
+                .iter()
+                .map(|part| match part {
+                    TagOrTagGroup::Tag(tag) => tag.name.clone(),
+                    TagOrTagGroup::Group(group) => common::name_to_tag_group(settings, &group.name),
+                })
+                .collect();
+            Value::from(parts.join("/"))
+        })
+        .collect();
+    Ok(Value::from(pins))
+}
+
+fn pin(settings: &mut Settings, params: &Value, _progress: &mut Progress) -> RpcResult {
+    let path = Path::new(str_param(params, "path")?);
+    let uid = unsafe { libc::getuid() };
+    let gid = unsafe { libc::getgid() };
+
+    let col = settings.resolve_collection(path)?;
+    let mut conn = sql::db_for_collection(settings, &col)?;
+    let mountpoint = settings.mountpoint(&col);
+    let relpath = super::strip_prefix(path, &mountpoint);
+
+    let tx = sql::begin_write(&mut conn)?;
+    common::fsops::mkdir(
+        settings,
+        &tx,
+        relpath,
+        uid,
+        gid,
+        &UMask::default().dir_perms(),
+    )?;
+    tx.commit()?;
+
+    if let Some(parent) = path.parent() {
+        flush_path(parent, settings);
+    }
+    Ok(Value::Null)
+}
+
+fn merge(settings: &mut Settings, params: &Value, _progress: &mut Progress) -> RpcResult {
+    let mut conn = open_collection(settings, params)?;
+    let col = str_param(params, "collection")?;
+    let src = str_param(params, "src")?;
+    let dst = str_param(params, "dst")?;
+    let resolution = resolution_param(params)?;
+
+    let umask = UMask::default();
+    let uid = unsafe { libc::getuid() };
+    let gid = unsafe { libc::getgid() };
+    let notifier = DesktopNotifier::from_settings(&settings);
+
+    let num_files = crate::merge(
+        settings,
+        &mut conn,
+        settings.mountpoint(col),
+        src,
+        dst,
+        uid,
+        gid,
+        &umask,
+        &notifier,
+        |_| Ok(resolution),
+    )?;
+    Ok(json!({ "moved": num_files }))
+}
+
+fn stats(settings: &mut Settings, params: &Value, _progress: &mut Progress) -> RpcResult {
+    let conn = open_collection(settings, params)?;
+    let top = match params.get("top") {
+        Some(top) => top
+            .as_u64()
+            .ok_or_else(|| RpcError::new(INVALID_PARAMS, "Param top must be a positive number"))?
+            as usize,
+        None => 10,
+    };
+
+    let counts: Vec<Value> = stats::tag_counts(&conn)?
+        .iter()
+        .map(|c| json!({"name": c.name, "num_files": c.num_files}))
+        .collect();
+    let co = stats::co_occurrence(&conn, top)?;
+    Ok(json!({
+        "tags": counts,
+        "co_occurrence": {"tags": co.tags, "counts": co.counts},
+        "orphaned_files": stats::orphaned_files(&conn)?,
+    }))
+}
+
+fn tags(settings: &mut Settings, params: &Value, _progress: &mut Progress) -> RpcResult {
+    let conn = open_collection(settings, params)?;
+    let tags: Vec<Value> = stats::tag_counts(&conn)?
+        .iter()
+        .map(|c| json!({"name": c.name, "num_files": c.num_files}))
+        .collect();
+    Ok(Value::from(tags))
+}
+
+fn retag(settings: &mut Settings, params: &Value, _progress: &mut Progress) -> RpcResult {
+    let mut conn = open_collection(settings, params)?;
+    let col = str_param(params, "collection")?;
+    let file = str_param(params, "file")?;
+    let add = opt_strs_param(params, "add")?;
+    let remove = opt_strs_param(params, "remove")?;
+
+    let umask = UMask::default();
+    let uid = unsafe { libc::getuid() };
+    let gid = unsafe { libc::getgid() };
+    let notifier = DesktopNotifier::from_settings(&settings);
+
+    let not_tagged = || RpcError::new(OP_FAILED, format!("{} isn't tagged", file));
+    let (device, inode) = settings.file_identity(Path::new(file))?;
+    let file_id =
+        identity::find_file(&conn, device, inode, Path::new(file))?.ok_or_else(not_tagged)?;
+    let tagged = sql::get_file(&conn, file_id)?.ok_or_else(not_tagged)?;
+
+    let (added, removed) = crate::retag(
+        settings,
+        &mut conn,
+        settings.mountpoint(col),
+        &tagged,
+        &remove,
+        &add,
+        uid,
+        gid,
+        &umask,
+        &notifier,
+    )?;
+    Ok(json!({"added": added, "removed": removed}))
+}
+
+fn flush(settings: &mut Settings, params: &Value, _progress: &mut Progress) -> RpcResult {
+    flush_path(str_param(params, "path")?, settings);
+    Ok(Value::Null)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(line: &str) -> (Vec<Value>, Option<Value>) {
+        let mut output = vec![];
+        let response =
+            RpcServer::new(Settings::default(), std::io::empty(), &mut output).dispatch(line);
+        let notes = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        (notes, response)
+    }
+
+    #[test]
+    fn test_describe() {
+        let (notes, response) = call(r#"{"jsonrpc": "2.0", "id": 1, "method": "rpc.describe"}"#);
+        let response = response.unwrap();
+        assert!(notes.is_empty());
+        assert_eq!(response["id"], 1);
+        assert_eq!(response["result"]["version"], PROTOCOL_VERSION);
+        let names: Vec<&str> = response["result"]["methods"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|m| m["name"].as_str())
+            .collect();
+        for name in &[
+            "query", "ln", "rm", "rename", "pins", "pin", "stats", "merge", "tags", "retag",
+            "flush",
+        ] {
+            assert!(names.contains(name), "{} isn't registered", name);
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let (_, response) = call("{not json");
+        assert_eq!(response.unwrap()["error"]["code"], PARSE_ERROR);
+
+        let (_, response) = call(r#"{"id": 2, "method": "rpc.describe"}"#);
+        let response = response.unwrap();
+        assert_eq!(response["id"], 2);
+        assert_eq!(response["error"]["code"], INVALID_REQUEST);
+
+        let (_, response) = call(r#"{"jsonrpc": "2.0", "id": "a", "method": "nope"}"#);
+        let response = response.unwrap();
+        assert_eq!(response["id"], "a");
+        assert_eq!(response["error"]["code"], METHOD_NOT_FOUND);
+
+        let (_, response) = call(r#"{"jsonrpc": "2.0", "id": 3, "method": "rm", "params": {}}"#);
+        assert_eq!(response.unwrap()["error"]["code"], INVALID_PARAMS);
+    }
+
+    #[test]
+    fn test_only() {
+        let mut output = vec![];
+        let mut server =
+            RpcServer::new(Settings::default(), std::io::empty(), &mut output).only(SOCKET_METHODS);
+
+        let response = server
+            .dispatch(r#"{"jsonrpc": "2.0", "id": 1, "method": "rm", "params": {}}"#)
+            .unwrap();
+        assert_eq!(response["error"]["code"], METHOD_NOT_FOUND);
+
+        let response = server
+            .dispatch(r#"{"jsonrpc": "2.0", "id": 2, "method": "rpc.describe"}"#)
+            .unwrap();
+        let names: Vec<&str> = response["result"]["methods"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|m| m["name"].as_str())
+            .collect();
+        assert_eq!(
+            names,
+            vec!["rpc.describe", "query", "tags", "retag", "flush"]
+        );
+    }
+
+    #[test]
+    fn test_notification_has_no_response() {
+        let (notes, response) = call(r#"{"jsonrpc": "2.0", "method": "nope"}"#);
+        assert!(notes.is_empty());
+        assert!(response.is_none());
+    }
+
+    #[test]
+    fn test_progress() -> std::io::Result<()> {
+        let mut output = vec![];
+        let id = json!(7);
+        let mut progress = Progress {
+            id: &id,
+            output: &mut output,
+        };
+        progress.report(1, 2, "/a.txt")?;
+        progress.report(2, 2, "/b.txt")?;
+
+        let notes: Vec<Value> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(notes.len(), 2);
+        assert_eq!(notes[1]["method"], "progress");
+        assert_eq!(notes[1]["params"]["id"], 7);
+        assert_eq!(notes[1]["params"]["done"], 2);
+        assert_eq!(notes[1]["params"]["item"], "/b.txt");
+        Ok(())
+    }
+}
@@ -0,0 +1,223 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+//! Runs a mount in a child process and restarts it when it crashes, for `tag mount --supervise`.  The child tells us
+//! whether it should be restarted with how it exits, see `MountExit`.
+
+use super::commands::MountExit;
+use super::CLI_TAG;
+use crate::common::types::cli::CliError;
+use nix::sys::signal::{kill, Signal};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{fork, ForkResult, Pid};
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+/// How long we wait before the first restart after a crash
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+/// The longest we wait before a restart, however many crashes there have been in a row
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// A mount that stays up for this long before it crashes is considered to have recovered, so its next restart is
+/// back to waiting for `MIN_BACKOFF`
+const STABLE_AFTER: Duration = Duration::from_secs(300);
+
+/// How often we check on the child, and whether we've been signaled to stop
+const POLL: Duration = Duration::from_millis(100);
+
+/// Runs `run` in a child process until it exits without crashing, or we're signaled to `stop`, which is passed on to
+/// the child.  `run` is told whether it's a restart after a crash, so that it can clean up after the crashed mount
+/// first.  Returns an error if the child couldn't mount at all.
+pub fn supervise<F>(stop: &AtomicBool, mut run: F) -> Result<(), Box<dyn Error>>
+where
+    F: FnMut(bool) -> Result<MountExit, Box<dyn Error>>,
+{
+    let mut backoff = MIN_BACKOFF;
+    let mut restarted = false;
+
+    loop {
+        let started = Instant::now();
+        let child = match unsafe { fork() }? {
+            ForkResult::Child => {
+                let exit = run(restarted).unwrap_or_else(|e| {
+                    error!(target: CLI_TAG, "Mount failed: {}", e);
+                    eprintln!("Error: {}", e);
+                    MountExit::Failed
+                });
+                std::process::exit(exit.code());
+            }
+            ForkResult::Parent { child } => child,
+        };
+        info!(target: CLI_TAG, "Supervising mount in PID {}", child);
+
+        let status = wait_for(child, stop)?;
+        if stop.load(Ordering::Relaxed) {
+            info!(target: CLI_TAG, "Got a signal, mount exited with {:?}", status);
+            return Ok(());
+        }
+
+        let code = match status {
+            WaitStatus::Exited(_, code) => Some(code),
+            _ => None,
+        };
+        match MountExit::from_code(code) {
+            MountExit::Done => {
+                info!(target: CLI_TAG, "Mount is done, stopping");
+                return Ok(());
+            }
+            MountExit::Failed => return Err(CliError::MountFailed.into()),
+            MountExit::Crashed => {}
+        }
+
+        let (wait, next) = next_backoff(backoff, started.elapsed());
+        warn!(
+            target: CLI_TAG,
+            "Mount crashed with {:?}, restarting it in {:?}", status, wait
+        );
+        if sleep_unless_stopped(wait, stop) {
+            return Ok(());
+        }
+        backoff = next;
+        restarted = true;
+    }
+}
+
+/// How long to wait before restarting a mount that crashed after being up for `uptime`, when the backoff has reached
+/// `backoff`, along with the backoff for the crash after that
+fn next_backoff(backoff: Duration, uptime: Duration) -> (Duration, Duration) {
+    let wait = if uptime >= STABLE_AFTER {
+        MIN_BACKOFF
+    } else {
+        backoff
+    };
+    (wait, (wait * 2).min(MAX_BACKOFF))
+}
+
+/// Waits for `child` to exit.  If we're signaled to stop first, the child is asked to stop too, and we wait for it
+/// to finish shutting down.
+fn wait_for(child: Pid, stop: &AtomicBool) -> nix::Result<WaitStatus> {
+    let mut signaled = false;
+    loop {
+        match waitpid(child, Some(WaitPidFlag::WNOHANG))? {
+            WaitStatus::StillAlive => {}
+            // stopped or continued, but not gone
+            WaitStatus::Stopped(..) | WaitStatus::Continued(..) => {}
+            status => return Ok(status),
+        }
+        if stop.load(Ordering::Relaxed) && !signaled {
+            info!(target: CLI_TAG, "Passing stop signal on to PID {}", child);
+            kill(child, Signal::SIGTERM)?;
+            signaled = true;
+        }
+        thread::sleep(POLL);
+    }
+}
+
+/// Sleeps for `duration`, unless we're signaled to stop in the meantime.  Returns whether we were.
+fn sleep_unless_stopped(duration: Duration, stop: &AtomicBool) -> bool {
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+        if stop.load(Ordering::Relaxed) {
+            return true;
+        }
+        thread::sleep(POLL);
+    }
+    stop.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_backoff() {
+        let quick = Duration::from_secs(1);
+        let mut backoff = MIN_BACKOFF;
+        let mut waits = vec![];
+        for _ in 0..8 {
+            let (wait, next) = next_backoff(backoff, quick);
+            waits.push(wait.as_secs());
+            backoff = next;
+        }
+        assert_eq!(waits, vec![1, 2, 4, 8, 16, 32, 60, 60]);
+
+        // a mount that stayed up for a while starts over
+        assert_eq!(
+            next_backoff(MAX_BACKOFF, STABLE_AFTER),
+            (MIN_BACKOFF, MIN_BACKOFF * 2)
+        );
+        assert_eq!(
+            next_backoff(MAX_BACKOFF, STABLE_AFTER - quick).0,
+            MAX_BACKOFF
+        );
+    }
+
+    #[test]
+    fn test_exit_codes() {
+        for exit in [MountExit::Done, MountExit::Failed, MountExit::Crashed].iter() {
+            assert_eq!(MountExit::from_code(Some(exit.code())), *exit);
+        }
+        // killed by a signal, or exited some other way, is a crash
+        assert_eq!(MountExit::from_code(None), MountExit::Crashed);
+        assert_eq!(MountExit::from_code(Some(101)), MountExit::Crashed);
+    }
+
+    #[test]
+    fn test_supervise_exits() {
+        let stop = AtomicBool::new(false);
+        assert!(supervise(&stop, |_| Ok(MountExit::Done)).is_ok());
+        assert!(supervise(&stop, |_| Ok(MountExit::Failed)).is_err());
+        assert!(supervise(&stop, |_| Err("bad config".into())).is_err());
+
+        // a crash is restarted, and the restart is told about it
+        let started = Instant::now();
+        let res = supervise(&stop, |restarted| {
+            Ok(if restarted {
+                MountExit::Done
+            } else {
+                MountExit::Crashed
+            })
+        });
+        assert!(res.is_ok());
+        assert!(started.elapsed() >= MIN_BACKOFF);
+    }
+
+    #[test]
+    fn test_supervise_forwards_stop() {
+        let stop = Arc::new(AtomicBool::new(false));
+        let signaler = {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(300));
+                stop.store(true, Ordering::Relaxed);
+            })
+        };
+
+        // the child would never exit on its own, so it only stops if the signal is passed on to it
+        let started = Instant::now();
+        let res = supervise(&stop, |_| {
+            thread::sleep(Duration::from_secs(60));
+            Ok(MountExit::Crashed)
+        });
+        assert!(res.is_ok());
+        assert!(started.elapsed() < Duration::from_secs(30));
+        signaler.join().unwrap();
+    }
+}
@@ -0,0 +1,47 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use super::CLI_TAG;
+use crate::common;
+use crate::common::err::STagResult;
+use crate::common::fsops::flush_path;
+use crate::common::settings::Settings;
+use rusqlite::Connection;
+use std::path::Path;
+use tracing::info;
+
+/// Exchanges the names of the tags or tag groups `a` and `b` in one transaction
+pub fn swap<P>(
+    settings: &Settings,
+    conn: &mut Connection,
+    mountpoint: P,
+    a: &str,
+    b: &str,
+) -> STagResult<()>
+where
+    P: AsRef<Path>,
+{
+    info!(target: CLI_TAG, "Swapping {} and {}", a, b);
+
+    let tx = super::begin_write(settings, conn)?;
+    common::fsops::swap(settings, &tx, a, b)?;
+    if super::commit(settings, tx)? {
+        flush_path(mountpoint.as_ref().join(a), settings);
+        flush_path(mountpoint.as_ref().join(b), settings);
+    }
+    Ok(())
+}
@@ -0,0 +1,72 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use super::CLI_TAG;
+use crate::common;
+use crate::common::archive::PathRemaps;
+use crate::common::err::STagResult;
+use crate::common::fsops::flush_path;
+use crate::common::settings::Settings;
+use crate::common::sync::{recv_state, send_state, SyncState, SyncSummary};
+use crate::common::types::file_perms::UMask;
+use libc::{gid_t, uid_t};
+use rusqlite::Connection;
+use std::io::{BufRead, Write};
+use std::path::Path;
+use tracing::info;
+
+/// Syncs the collection with another machine's copy of it, which is on the other end of `reader` and `writer`.  The
+/// side that's `serving` waits to hear the other's state before sending its own.  The other side's state is applied
+/// here in one transaction, with its paths remapped by `remaps`.
+pub fn sync<P, R, W>(
+    settings: &Settings,
+    conn: &mut Connection,
+    mountpoint: P,
+    reader: R,
+    mut writer: W,
+    serving: bool,
+    remaps: &PathRemaps,
+    uid: uid_t,
+    gid: gid_t,
+    umask: &UMask,
+) -> STagResult<SyncSummary>
+where
+    P: AsRef<Path>,
+    R: BufRead,
+    W: Write,
+{
+    info!(target: CLI_TAG, "Syncing, serving: {}", serving);
+
+    let ours = SyncState::load(conn)?;
+    let theirs = if serving {
+        let theirs = recv_state(reader)?;
+        send_state(&mut writer, &ours)?;
+        theirs
+    } else {
+        send_state(&mut writer, &ours)?;
+        recv_state(reader)?
+    };
+
+    let tx = super::begin_write(settings, conn)?;
+    let summary = common::fsops::sync_from(settings, &tx, &ours, &theirs, remaps, uid, gid, umask)?;
+    if super::commit(settings, tx)? {
+        for tag in &summary.tags {
+            flush_path(mountpoint.as_ref().join(tag), settings);
+        }
+    }
+    Ok(summary)
+}
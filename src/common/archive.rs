@@ -0,0 +1,564 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Packs a collection into a single archive that can be carried to another machine, and unpacks it there.  An archive
+//! is a tarball, optionally compressed with zstd, holding a manifest, every table of the database as JSON, the
+//! collection's config, and its managed files.
+//!
+//! Tagged files are recorded by absolute path and by device/inode, neither of which survive a move to another
+//! machine, so importing can remap path prefixes.  Files that exist at their (remapped) path are re-identified by
+//! their new device/inode, or by their new path if they're identified by path.
+
+use crate::common::constants;
+use crate::common::err::{STagError, STagResult};
+use crate::common::identity;
+use crate::common::settings::Settings;
+use crate::common::tar;
+use crate::sql;
+use crate::sql::migrations;
+use crate::sql::portable::{self, TableDump};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use tracing::{debug, info, warn};
+
+const TAG: &str = "archive";
+
+/// Bumped whenever the layout of an archive changes in a way that older releases can't read
+const FORMAT_VERSION: u32 = 1;
+
+const MANIFEST_NAME: &str = "manifest.json";
+const CONFIG_NAME: &str = "config.toml";
+const TABLES_DIR: &str = "tables";
+const FILES_TABLE: &str = "files";
+
+/// Every zstd frame starts with these bytes, which is how we tell a compressed archive from a plain one
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Zero picks zstd's default level
+const ZSTD_LEVEL: i32 = 0;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Manifest {
+    pub format: u32,
+    pub collection: String,
+    pub supertag_version: String,
+    pub migration_version: i64,
+    pub exported_at: String,
+    /// Where the collection's managed files lived, so that the paths pointing into it can be moved along with them
+    pub managed_dir: PathBuf,
+    /// The tag that every file in the collection implicitly had, so that tools can tell where the files came from
+    #[serde(default)]
+    pub collection_tag: Option<String>,
+}
+
+pub struct Archive {
+    pub manifest: Manifest,
+    pub tables: Vec<TableDump>,
+    pub config: Option<Vec<u8>>,
+    /// Managed files, keyed by their path relative to the managed dir
+    pub managed: Vec<(PathBuf, Vec<u8>)>,
+}
+
+/// Path prefixes to replace when importing, like `/home/amy` to `/Users/amy`
+#[derive(Default, Debug)]
+pub struct PathRemaps {
+    remaps: Vec<(PathBuf, PathBuf)>,
+}
+
+impl PathRemaps {
+    pub fn add(&mut self, from: PathBuf, to: PathBuf) {
+        self.remaps.push((from, to));
+        // the most specific prefix wins
+        self.remaps
+            .sort_by_key(|(from, _to)| std::cmp::Reverse(from.components().count()));
+    }
+
+    /// Parses a remap given as `OLD=NEW`
+    pub fn add_arg(&mut self, arg: &str) -> STagResult<()> {
+        let mut parts = arg.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(from), Some(to)) if !from.is_empty() && !to.is_empty() => {
+                self.add(PathBuf::from(from), PathBuf::from(to));
+                Ok(())
+            }
+            _ => Err(STagError::Other(
+                format!("Remap {} should look like OLD=NEW", arg).into(),
+            )),
+        }
+    }
+
+    pub fn apply(&self, path: &Path) -> PathBuf {
+        for (from, to) in &self.remaps {
+            if let Ok(rest) = path.strip_prefix(from) {
+                return to.join(rest);
+            }
+        }
+        path.to_owned()
+    }
+}
+
+/// What happened to the tagged files during an import
+#[derive(Default, Debug)]
+pub struct RestoreSummary {
+    pub files: usize,
+    /// Files that exist on this machine, and were re-identified by their new device/inode
+    pub found: usize,
+    /// Files that don't exist on this machine.  They keep their tags, but their symlinks will be broken.
+    pub missing: usize,
+}
+
+fn to_json<T: Serialize>(value: &T) -> STagResult<Vec<u8>> {
+    serde_json::to_vec_pretty(value).map_err(|e| STagError::Other(Box::new(e)))
+}
+
+/// Rejects names that could escape the directory they're unpacked into
+fn safe_relative(name: &Path) -> STagResult<&Path> {
+    if name.components().all(|c| matches!(c, Component::Normal(_))) {
+        Ok(name)
+    } else {
+        Err(STagError::InvalidPath(name.to_owned()))
+    }
+}
+
+/// The top-most ancestor of a missing `path` that is also missing, which is the directory that most likely moved.  If
+/// only the file itself is missing, that's its directory.
+fn missing_root(path: &Path) -> PathBuf {
+    let mut root = path;
+    while let Some(parent) = root.parent() {
+        if parent.exists() {
+            break;
+        }
+        root = parent;
+    }
+    if root == path {
+        path.parent().unwrap_or(path).to_owned()
+    } else {
+        root.to_owned()
+    }
+}
+
+impl Archive {
+    /// Gathers everything that makes up the collection `col`.  Nothing is written anywhere.
+    pub fn collect(settings: &Settings, col: &str) -> STagResult<Self> {
+        info!(target: TAG, "Collecting collection {} for export", col);
+        let db_file = settings.db_file(col);
+        if !db_file.exists() {
+            return Err(STagError::InvalidPath(db_file));
+        }
+        let conn = sql::db_for_collection(settings, col)?;
+        let managed_dir = settings.managed_dir(col);
+
+        let manifest = Manifest {
+            format: FORMAT_VERSION,
+            collection: col.to_string(),
+            supertag_version: crate::common::version_str(),
+            migration_version: migrations::current_version(&conn)?,
+            exported_at: chrono::Utc::now().to_rfc3339(),
+            managed_dir: managed_dir.clone(),
+            collection_tag: settings.collection_tag(),
+        };
+
+        let config_file = settings.config_file(col);
+        let config = if config_file.exists() {
+            Some(std::fs::read(&config_file)?)
+        } else {
+            None
+        };
+
+        let mut managed = vec![];
+        if managed_dir.exists() {
+            for entry in walkdir::WalkDir::new(&managed_dir).follow_links(false) {
+                let entry = entry.map_err(|e| STagError::Other(Box::new(e)))?;
+                if !entry.file_type().is_file() {
+                    continue;
+                }
+                let rel = entry
+                    .path()
+                    .strip_prefix(&managed_dir)
+                    .map_err(|e| STagError::Other(Box::new(e)))?;
+                managed.push((rel.to_owned(), std::fs::read(entry.path())?));
+            }
+        }
+
+        Ok(Self {
+            manifest,
+            tables: portable::dump_tables(&conn)?,
+            config,
+            managed,
+        })
+    }
+
+    /// Writes the archive as a tarball, compressing it with zstd if `compress` is set
+    pub fn write<W: Write>(&self, out: &mut W, compress: bool) -> STagResult<()> {
+        let mtime = chrono::Utc::now().timestamp() as u64;
+        let mut tarball = vec![];
+        tar::append(
+            &mut tarball,
+            MANIFEST_NAME,
+            &to_json(&self.manifest)?,
+            mtime,
+        )?;
+        for table in &self.tables {
+            let name = format!("{}/{}.json", TABLES_DIR, table.name);
+            tar::append(&mut tarball, &name, &to_json(table)?, mtime)?;
+        }
+        if let Some(config) = &self.config {
+            tar::append(&mut tarball, CONFIG_NAME, config, mtime)?;
+        }
+        for (rel, contents) in &self.managed {
+            let name = Path::new(constants::MANAGED_FILES_DIR_NAME).join(rel);
+            tar::append(&mut tarball, &name.to_string_lossy(), contents, mtime)?;
+        }
+        tar::finish(&mut tarball)?;
+
+        if compress {
+            out.write_all(&zstd::stream::encode_all(tarball.as_slice(), ZSTD_LEVEL)?)?;
+        } else {
+            out.write_all(&tarball)?;
+        }
+        Ok(())
+    }
+
+    /// Reads an archive written by `write`, compressed or not
+    pub fn read(data: &[u8]) -> STagResult<Self> {
+        let decompressed;
+        let tarball = if data.starts_with(ZSTD_MAGIC) {
+            decompressed = zstd::stream::decode_all(data)?;
+            decompressed.as_slice()
+        } else {
+            data
+        };
+
+        let mut manifest = None;
+        let mut tables = vec![];
+        let mut config = None;
+        let mut managed = vec![];
+        for (name, contents) in tar::entries(tarball)? {
+            let path = Path::new(&name);
+            if name == MANIFEST_NAME {
+                manifest = Some(
+                    serde_json::from_slice::<Manifest>(&contents)
+                        .map_err(|e| STagError::Other(Box::new(e)))?,
+                );
+            } else if name == CONFIG_NAME {
+                config = Some(contents);
+            } else if path.starts_with(TABLES_DIR) {
+                tables.push(
+                    serde_json::from_slice::<TableDump>(&contents)
+                        .map_err(|e| STagError::Other(Box::new(e)))?,
+                );
+            } else if let Ok(rel) = path.strip_prefix(constants::MANAGED_FILES_DIR_NAME) {
+                managed.push((safe_relative(rel)?.to_owned(), contents));
+            } else {
+                warn!(target: TAG, "Skipping unknown archive entry {}", name);
+            }
+        }
+
+        let manifest = manifest
+            .ok_or_else(|| STagError::Other(format!("Archive has no {}", MANIFEST_NAME).into()))?;
+        if manifest.format > FORMAT_VERSION {
+            return Err(STagError::Other(
+                format!(
+                    "Archive was made by supertag {}, which is newer than this release",
+                    manifest.supertag_version
+                )
+                .into(),
+            ));
+        }
+
+        Ok(Self {
+            manifest,
+            tables,
+            config,
+            managed,
+        })
+    }
+
+    fn files_table(&mut self) -> Option<&mut TableDump> {
+        self.tables.iter_mut().find(|t| t.name == FILES_TABLE)
+    }
+
+    /// The paths of every tagged file in the archive, after `remaps`
+    fn file_paths<'a>(&'a self, remaps: &'a PathRemaps) -> impl Iterator<Item = PathBuf> + 'a {
+        self.tables
+            .iter()
+            .filter(|t| t.name == FILES_TABLE)
+            .flat_map(move |t| {
+                let path_idx = t.column_idx("path");
+                t.rows.iter().filter_map(move |row| {
+                    let path = row.get(path_idx?)?.as_str()?;
+                    Some(remaps.apply(Path::new(path)))
+                })
+            })
+    }
+
+    /// The directories holding tagged files that don't exist on this machine, after `remaps`, along with how many
+    /// files are missing from each.  These are the directories worth asking the user to remap.
+    pub fn missing_dirs(&self, remaps: &PathRemaps) -> Vec<(PathBuf, usize)> {
+        let mut dirs = BTreeMap::new();
+        for path in self.file_paths(remaps).filter(|p| !p.exists()) {
+            *dirs.entry(missing_root(&path)).or_insert(0) += 1;
+        }
+        dirs.into_iter().collect()
+    }
+
+    /// Creates the collection `col` from the archive, remapping the paths of tagged files with `remaps`.  The
+    /// collection must not already have a database.
+    pub fn restore(
+        mut self,
+        settings: &Settings,
+        col: &str,
+        remaps: &PathRemaps,
+    ) -> STagResult<RestoreSummary> {
+        info!(target: TAG, "Restoring collection {} from archive", col);
+        let db_file = settings.db_file(col);
+        if db_file.exists() {
+            return Err(STagError::PathExists(db_file));
+        }
+        if self.manifest.migration_version > migrations::latest_version() {
+            return Err(STagError::Other(
+                format!(
+                    "Archive's database is at version {}, but this release only knows up to version {}",
+                    self.manifest.migration_version,
+                    migrations::latest_version()
+                )
+                .into(),
+            ));
+        }
+
+        let old_managed_dir = self.manifest.managed_dir.clone();
+        let managed_dir = settings.managed_dir(col);
+        let summary = match self.files_table() {
+            Some(files) => relocate_files(files, remaps, &old_managed_dir, &managed_dir),
+            None => RestoreSummary::default(),
+        };
+
+        let mut conn = sql::get_conn(&db_file)?;
+        let loaded = migrations::migrate(&mut conn, &crate::common::version_str()).and_then(|_| {
+            let tx = sql::begin_write(&mut conn)?;
+            portable::load_tables(&tx, &self.tables)?;
+            tx.commit()
+        });
+        if let Err(e) = loaded {
+            drop(conn);
+            if let Err(rm_err) = std::fs::remove_file(&db_file) {
+                warn!(target: TAG, "Couldn't remove partial database {:?}: {:?}", db_file, rm_err);
+            }
+            return Err(e.into());
+        }
+
+        for (rel, contents) in &self.managed {
+            let dst = managed_dir.join(rel);
+            if let Some(parent) = dst.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(&dst, contents)?;
+        }
+
+        if let Some(config) = &self.config {
+            let config_file = settings.config_file(col);
+            if config_file.exists() {
+                warn!(
+                    target: TAG,
+                    "Keeping existing config {:?} instead of the archived one", config_file
+                );
+            } else {
+                std::fs::write(&config_file, config)?;
+            }
+        }
+
+        debug!(target: TAG, "Restored {:?}", summary);
+        Ok(summary)
+    }
+}
+
+/// Rewrites the rows of the files table for this machine: paths are remapped, files that exist get their new
+/// device/inode, and alias files are moved into the new managed dir
+fn relocate_files(
+    files: &mut TableDump,
+    remaps: &PathRemaps,
+    old_managed_dir: &Path,
+    managed_dir: &Path,
+) -> RestoreSummary {
+    let mut summary = RestoreSummary::default();
+    let path_idx = files.column_idx("path");
+    let device_idx = files.column_idx("device");
+    let inode_idx = files.column_idx("inode");
+    let alias_idx = files.column_idx("alias_file");
+    let width = files.columns.len();
+
+    for row in files.rows.iter_mut() {
+        summary.files += 1;
+        row.resize(width, serde_json::Value::Null);
+
+        if let Some(idx) = alias_idx {
+            if let Some(alias) = row[idx].as_str().map(PathBuf::from) {
+                if let Ok(rel) = alias.strip_prefix(old_managed_dir) {
+                    row[idx] = managed_dir.join(rel).to_string_lossy().into();
+                }
+            }
+        }
+
+        let path = match path_idx.and_then(|idx| row[idx].as_str()) {
+            Some(path) => remaps.apply(Path::new(path)),
+            None => continue,
+        };
+        // a path-identity file stays one, under the identity of its new path
+        let path_identity = device_idx
+            .and_then(|idx| row[idx].as_i64())
+            .map_or(false, |device| identity::is_path_identity(device as u64));
+        let found = if path_identity {
+            path.canonicalize()
+                .map(|canonical| identity::path_identity(&canonical))
+                .map_err(STagError::from)
+        } else {
+            crate::common::get_device_inode(&path)
+        };
+        match found {
+            Ok((device, inode)) => {
+                summary.found += 1;
+                if let (Some(device_idx), Some(inode_idx)) = (device_idx, inode_idx) {
+                    row[device_idx] = (device as i64).into();
+                    row[inode_idx] = (inode as i64).into();
+                }
+            }
+            Err(_) => summary.missing += 1,
+        }
+        if let Some(idx) = path_idx {
+            row[idx] = path.to_string_lossy().into();
+        }
+    }
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remaps() -> STagResult<()> {
+        let mut remaps = PathRemaps::default();
+        remaps.add_arg("/home/amy=/Users/amy")?;
+        remaps.add_arg("/home/amy/music=/mnt/nas/music")?;
+        assert!(remaps.add_arg("/home/amy").is_err());
+        assert!(remaps.add_arg("=/Users/amy").is_err());
+
+        assert_eq!(
+            remaps.apply(Path::new("/home/amy/docs/a.txt")),
+            PathBuf::from("/Users/amy/docs/a.txt")
+        );
+        assert_eq!(
+            remaps.apply(Path::new("/home/amy/music/b.mp3")),
+            PathBuf::from("/mnt/nas/music/b.mp3")
+        );
+        // prefixes match whole components only
+        assert_eq!(
+            remaps.apply(Path::new("/home/amyx/c.txt")),
+            PathBuf::from("/home/amyx/c.txt")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_round_trip_and_relocate() -> STagResult<()> {
+        let real_dir = tempfile::tempdir()?;
+        let real_file = real_dir.path().join("here.txt");
+        std::fs::write(&real_file, b"hi")?;
+
+        let files = TableDump {
+            name: FILES_TABLE.to_string(),
+            columns: ["id", "device", "inode", "path", "alias_file"]
+                .iter()
+                .map(|c| c.to_string())
+                .collect(),
+            rows: vec![
+                vec![
+                    1.into(),
+                    1.into(),
+                    1.into(),
+                    "/old/home/here.txt".into(),
+                    "/old/managed/ab/cd".into(),
+                ],
+                vec![
+                    2.into(),
+                    1.into(),
+                    2.into(),
+                    "/old/elsewhere/gone.txt".into(),
+                    serde_json::Value::Null,
+                ],
+            ],
+        };
+        let archive = Archive {
+            manifest: Manifest {
+                format: FORMAT_VERSION,
+                collection: "col".to_string(),
+                supertag_version: "0.1.4".to_string(),
+                migration_version: 1,
+                exported_at: "2020-01-01T00:00:00+00:00".to_string(),
+                managed_dir: PathBuf::from("/old/managed"),
+                collection_tag: Some("col:test".to_string()),
+            },
+            tables: vec![files],
+            config: Some(b"[symbols]\n".to_vec()),
+            managed: vec![(PathBuf::from("ab/cd"), b"alias".to_vec())],
+        };
+
+        for compress in &[false, true] {
+            let mut out = vec![];
+            archive.write(&mut out, *compress)?;
+            assert_eq!(out.starts_with(ZSTD_MAGIC), *compress);
+
+            let mut read = Archive::read(&out)?;
+            assert_eq!(read.manifest.collection, "col");
+            assert_eq!(read.manifest.collection_tag.as_deref(), Some("col:test"));
+            assert_eq!(read.config, archive.config);
+            assert_eq!(read.managed, archive.managed);
+            assert_eq!(read.tables, archive.tables);
+
+            let mut remaps = PathRemaps::default();
+            remaps.add(PathBuf::from("/old/home"), real_dir.path().to_owned());
+            assert_eq!(
+                read.missing_dirs(&remaps),
+                vec![(PathBuf::from("/old"), 1)],
+                "the top-most missing directory is the one to ask about"
+            );
+
+            let new_managed = Path::new("/new/managed");
+            let files = read.files_table().unwrap();
+            let summary = relocate_files(files, &remaps, Path::new("/old/managed"), new_managed);
+            assert_eq!((summary.files, summary.found, summary.missing), (2, 1, 1));
+
+            let (device, inode) = crate::common::get_device_inode(&real_file)?;
+            assert_eq!(files.rows[0][1], serde_json::Value::from(device as i64));
+            assert_eq!(files.rows[0][2], serde_json::Value::from(inode as i64));
+            assert_eq!(files.rows[0][3], &*real_file.to_string_lossy());
+            assert_eq!(files.rows[0][4], "/new/managed/ab/cd");
+            assert_eq!(files.rows[1][3], "/old/elsewhere/gone.txt");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_safe_relative() {
+        assert!(safe_relative(Path::new("ab/cd/ef")).is_ok());
+        assert!(safe_relative(Path::new("ab/../../etc/passwd")).is_err());
+        assert!(safe_relative(Path::new("/etc/passwd")).is_err());
+    }
+}
@@ -36,6 +36,20 @@ pub const TRACKER_IGNORE: &str = ".trackerignore";
 
 // TODO put this in the settings symbols
 pub const NEGATIVE_TAG_PREFIX: &str = "-";
+pub const UNION_TAG_SEPARATOR: &str = "|";
+// follows a tag group to match files that have every tag in it, instead of any, ie `a_tags+!`
+pub const ALL_OF_GROUP_SUFFIX: &str = "!";
+pub const META_TAG_PREFIX: &str = "meta:";
+pub const META_TAG_SEPARATOR: &str = "=";
+// surround a boolean tag expression, ie `?(rust&(cli|tui)&!wip)`
+pub const EXPRESSION_TAG_PREFIX: &str = "?(";
+pub const EXPRESSION_TAG_SUFFIX: &str = ")";
+// a year or month of file modification times, ie `@2021` or `@2021-07`
+pub const DATE_TAG_PREFIX: &str = "@";
+// files rated at least so many stars, ie `★4+`
+pub const RATING_TAG_PREFIX: &str = "★";
+pub const RATING_TAG_SUFFIX: &str = "+";
+pub const MAX_RATING: u8 = 5;
 
 pub const DB_FILE_NAME: &str = "db.sqlite3";
 pub const DB_FILE_PATH: &str = "/.supertag/db.sqlite3";
@@ -49,6 +63,11 @@ pub const STAG_ROOT_CONF_NAME: &str = ".supertag";
 pub const FACE_NAME: &str = "face|.png";
 
 pub const MANAGED_FILES_DIR_NAME: &str = "managed_files";
+/// Under the collection dir, holds the config overlays that `tag mount --profile` picks between
+pub const PROFILES_DIR_NAME: &str = "profiles";
+/// The service that encrypted collections' keys are stored under in the system keyring, with the collection's name as
+/// the account
+pub const KEYRING_SERVICE: &str = "supertag";
 
 // an unlink on this file helps us detect whether we're deleting an entire directory tree recursively or deleting a
 // single file.
@@ -61,10 +80,55 @@ pub const XATTR_FINDER_INFO: &str = "com.apple.FinderInfo";
 #[cfg(target_os = "macos")]
 pub const XATTR_RESOURCE_FORK: &str = "com.apple.ResourceFork";
 
+// computed, read-only xattrs exposed on tag directories, so that scripts can read intersection metadata without
+// parsing directory listings
+pub const XATTR_NUM_FILES: &str = "user.supertag.num_files";
+// on file entries, this one is writable: setting it to a newline or comma separated list of tags makes those the
+// file's tags, so that file managers with xattr editing can tag files.  linux refuses user xattrs on symlinks, so there
+// it's only on entries that are shown as regular files, which is every entry with `mount.write_through`, and media
+// files with `thumbnails.passthrough`
+pub const XATTR_TAGS: &str = "user.supertag.tags";
+pub const XATTR_QUERY: &str = "user.supertag.query";
+
+// exposed on tag directories whose tag has been given them with `tag edit`
+pub const XATTR_COLOR: &str = "user.supertag.color";
+pub const XATTR_ICON: &str = "user.supertag.icon";
+pub const XATTR_DESCRIPTION: &str = "user.supertag.description";
+
+// on file entries, `user.supertag.meta.<key>` is the file's value for that metadata key, and is writable
+pub const XATTR_META_PREFIX: &str = "user.supertag.meta.";
+
+// on file entries, `user.supertag.tag_meta.<key>` is the value for that key on the file's tagging with the tag that
+// the entry's directory is named after, ie `music` for `jazz/music/⋂/song.mp3`, and is writable
+pub const XATTR_TAG_META_PREFIX: &str = "user.supertag.tag_meta.";
+
+// exposed on file entries when remote mtime refreshing is on.  either "cached" or "stale"
+pub const XATTR_FRESHNESS: &str = "user.supertag.freshness";
+
 pub const ALIAS_HEADER: &[u8] = b"book\0\0\0\0mark";
 
 pub const UNLINK_NAME: &str = "delete";
 
+// the longest name, in bytes, that most filesystems and file managers will accept for a single path component
+pub const NAME_MAX: usize = 255;
+
+// how many files of a filedir are fetched from the database at a time while it's being listed
+pub const READDIR_PAGE_SIZE: usize = 1000;
+
+// how many files a bulk `tag ln` links per transaction, so that an interrupted run keeps the chunks it finished
+pub const LN_CHUNK_SIZE: usize = 500;
+
+// joins the directories of a recursively linked file onto its name, when they're kept as a prefix
+pub const SUBDIR_PREFIX_SEPARATOR: &str = "_";
+
+// how many minor releases we continue to parse device files named with symbols that have since been changed
+pub const LEGACY_SYMBOL_RELEASES: u64 = 3;
+
+// once the database's disk fills up, how often we check it for room, and how much room it needs before we take
+// writes again.  the margin keeps us from flapping in and out of read-only as sqlite's journal comes and goes
+pub const DISK_FULL_POLL_SECS: u64 = 5;
+pub const DISK_FULL_RESUME_BYTES: u64 = 64 * 1024 * 1024;
+
 pub const DEFAULT_CONFIG_TOML: &str = r###"
 [symbols]
 inode_char = "-"
@@ -73,8 +137,95 @@ sync_char = "\u007F"
 filedir_str = "⋂"
 filedir_cli_str = "_"
 tag_group_str = "+"
+name_template = "{name}{device_char}{device}{inode_char}{inode}"
 
 [mount]
+sort = "name"
+clean_stale = true
+write_through = false
+listing_sizes = false
+allow_other = false
+
+[display]
+strip_extensions = false
+underscores_to_spaces = false
+title_case = false
+
+[cache]
+warm_on_mount = false
+warm_top_tags = 50
+warm_threads = 4
+warm_ttl_s = 60
+digest_keys = true
+digest_keys_min_len = 256
+
+[rename]
+file_mode = "move"
+redirect_tags = false
+redirect_days = 30
+
+[remote]
+refresh_mtimes = false
+slow_stat_ms = 25
+stale_after_s = 300
+refresh_batch = 32
+refresh_interval_ms = 2000
+
+[groups]
+rules = []
+
+[shutdown]
+deadline_ms = 5000
+
+[searches]
+dir = "queries"
+
+[doctor]
+search_roots = []
+
+[thumbnails]
+passthrough = false
+extensions = ["jpg", "jpeg", "png", "gif", "webp", "heic", "mp4", "mkv", "mov", "webm", "pdf"]
+
+[tracking]
+follow_renames = false
+index_depth = 2
+index_interval_s = 600
+
+[tagging]
+allow_collection_files = false
+dir_children = false
+
+[undo]
+keep = 50
+
+[notifications]
+max_per_window = 5
+window_s = 60
+
+[query]
+max_negations = 8
+
+[dates]
+enabled = false
+
+[filetypes]
+enabled = false
+group = "filetype"
+
+[media]
+enabled = false
+camera_prefix = "camera:"
+date_prefix = "taken:"
+artist_prefix = "artist:"
+album_prefix = "album:"
+
+[duplicates]
+enabled = false
+dir = "+duplicates"
+
+[untagged]
+dir = "⊘untagged"
 "###;
 
 // https://github.com/torvalds/linux/blob/master/Documentation/admin-guide/devices.txt
@@ -0,0 +1,193 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+//! The control socket, which lets other processes talk to the daemon that has a collection mounted.  Requests and
+//! responses are JSON, one per line.  For now, it's only used to close and open the daemon's write gate around
+//! maintenance, like backups and migrations, that another process does to the collection.
+
+use crate::common::settings::Settings;
+use crate::sql;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread::spawn;
+use std::time::Duration;
+use tracing::{debug, error, info, warn};
+
+const TAG: &str = "control";
+
+// how long a client waits on the daemon before giving up on it
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ControlRequest {
+    /// Holds off writes, because of the reason given
+    CloseGate(String),
+    OpenGate,
+    GateStatus,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ControlResponse {
+    /// The state of the write gate after the request, with the reason it's closed, if it is
+    Gate(Option<String>),
+    Error(String),
+}
+
+fn handle_request(request: ControlRequest, settings: &Settings) -> ControlResponse {
+    match request {
+        ControlRequest::CloseGate(reason) => settings.close_write_gate(&reason),
+        ControlRequest::OpenGate => settings.open_write_gate(),
+        ControlRequest::GateStatus => {}
+    }
+    ControlResponse::Gate(settings.write_gate())
+}
+
+fn handle_conn(stream: UnixStream, settings: &Settings) -> std::io::Result<()> {
+    let reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    for line in reader.lines() {
+        let line = line?;
+        debug!(target: TAG, "Got request {}", line);
+        let response = match serde_json::from_str::<ControlRequest>(&line) {
+            Ok(request) => handle_request(request, settings),
+            Err(e) => ControlResponse::Error(format!("Bad request: {}", e)),
+        };
+        let mut blob = serde_json::to_vec(&response)?;
+        blob.push(b'\n');
+        writer.write_all(blob.as_slice())?;
+    }
+    Ok(())
+}
+
+/// Listens on `socket_file` in the background, applying requests to `settings`.  Requests are tiny, so connections
+/// are handled one at a time.
+pub fn serve(socket_file: &Path, settings: Arc<Settings>) -> std::io::Result<()> {
+    if socket_file.exists() {
+        warn!(
+            target: TAG,
+            "Control socket file {} exists, removing first",
+            socket_file.display()
+        );
+        std::fs::remove_file(socket_file)?;
+    }
+
+    let socket = UnixListener::bind(socket_file)?;
+    info!(target: TAG, "Listening on {}", socket_file.display());
+
+    spawn(move || {
+        for maybe_stream in socket.incoming() {
+            match maybe_stream {
+                Ok(stream) => {
+                    if let Err(e) = handle_conn(stream, &settings) {
+                        error!(target: TAG, "Error handling control connection: {:?}", e);
+                    }
+                }
+                Err(e) => error!(target: TAG, "Error getting control connection: {:?}", e),
+            }
+        }
+        debug!(target: TAG, "Exiting thread");
+    });
+    Ok(())
+}
+
+/// Sends `request` to the daemon listening on `socket_file`.  Fails if there's no daemon, like when the collection
+/// isn't mounted.
+pub fn send(
+    socket_file: &Path,
+    request: &ControlRequest,
+) -> Result<ControlResponse, Box<dyn Error>> {
+    let mut stream = UnixStream::connect(socket_file)?;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+
+    let mut blob = serde_json::to_vec(request)?;
+    blob.push(b'\n');
+    stream.write_all(blob.as_slice())?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    match serde_json::from_str(&line)? {
+        ControlResponse::Error(e) => Err(e.into()),
+        response => Ok(response),
+    }
+}
+
+/// Brings the write gate of the daemon that has `col` mounted, if there is one, in line with the database
+pub fn sync_write_gate(settings: &Settings, col: &str) -> Result<(), Box<dyn Error>> {
+    let conn = sql::db_for_collection(settings, col)?;
+    let request = match sql::gate::gate_reason(&conn)? {
+        Some(reason) => ControlRequest::CloseGate(reason),
+        None => ControlRequest::OpenGate,
+    };
+    if let Err(e) = send(&settings.control_socket_file(col), &request) {
+        debug!(target: TAG, "No daemon took {:?}: {}", request, e);
+    }
+    Ok(())
+}
+
+/// Runs `f` with the write gate of `col` closed because of `reason`.  The gate is held in the database, so that it
+/// outlives us if we crash, and the daemon that has `col` mounted is told, so that it holds off writes until `f` is
+/// done.
+pub fn with_write_gate<T, F>(
+    settings: &Settings,
+    col: &str,
+    reason: &str,
+    f: F,
+) -> Result<T, Box<dyn Error>>
+where
+    F: FnOnce() -> Result<T, Box<dyn Error>>,
+{
+    let pid = std::process::id();
+    let holder = format!("{}:{}", reason, pid);
+    {
+        let conn = sql::db_for_collection(settings, col)?;
+        sql::gate::close_gate(&conn, &holder, reason, pid as i32, sql::get_now_secs())?;
+    }
+    sync_write_gate(settings, col)?;
+
+    let res = f();
+
+    {
+        let conn = sql::db_for_collection(settings, col)?;
+        sql::gate::open_gate(&conn, &holder)?;
+    }
+    sync_write_gate(settings, col)?;
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_roundtrip() {
+        for request in vec![
+            ControlRequest::CloseGate("backing up".to_string()),
+            ControlRequest::OpenGate,
+            ControlRequest::GateStatus,
+        ] {
+            let blob = serde_json::to_string(&request).unwrap();
+            assert!(!blob.contains('\n'));
+            let parsed: ControlRequest = serde_json::from_str(&blob).unwrap();
+            assert_eq!(parsed, request);
+        }
+    }
+}
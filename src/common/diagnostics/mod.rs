@@ -0,0 +1,236 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Collects what we need to debug a problem into a single bundle that a user can attach to an issue.  Everything in
+//! the bundle passes through a `Scrubber` first, which replaces the user's home directory and user name, but logs can
+//! still mention tag and file names, so the contents are always shown to the user before anything is written.
+
+use crate::common::err::STagResult;
+use crate::common::settings::Settings;
+use crate::common::tar;
+use crate::sql;
+use rusqlite::Connection;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+const TAG: &str = "diagnostics";
+
+/// The directory that every file in the bundle is placed under
+const BUNDLE_DIR: &str = "supertag-report";
+
+/// Replaces identifying strings in text before it goes into a bundle
+pub struct Scrubber {
+    replacements: Vec<(String, String)>,
+}
+
+impl Scrubber {
+    pub fn new(mut replacements: Vec<(String, String)>) -> Self {
+        // replace the longest strings first, so that the home dir is replaced before the user name inside of it
+        replacements.retain(|(from, _to)| !from.is_empty());
+        replacements.sort_by(|a, b| b.0.len().cmp(&a.0.len()));
+        Self { replacements }
+    }
+
+    /// Scrubs the current user's home directory and user name
+    pub fn for_current_user() -> Self {
+        let mut replacements = vec![];
+        if let Some(base) = directories::BaseDirs::new() {
+            replacements.push((
+                base.home_dir().to_string_lossy().to_string(),
+                "~".to_string(),
+            ));
+        }
+        if let Ok(user) = std::env::var("USER") {
+            replacements.push((user, "<user>".to_string()));
+        }
+        Self::new(replacements)
+    }
+
+    pub fn scrub(&self, text: &str) -> String {
+        self.replacements
+            .iter()
+            .fold(text.to_string(), |acc, (from, to)| {
+                acc.replace(from.as_str(), to)
+            })
+    }
+}
+
+pub struct BundleEntry {
+    pub name: String,
+    pub description: String,
+    pub contents: Vec<u8>,
+}
+
+pub struct Bundle {
+    pub entries: Vec<BundleEntry>,
+}
+
+impl Bundle {
+    fn add(&mut self, name: &str, description: &str, contents: String) {
+        self.entries.push(BundleEntry {
+            name: name.to_string(),
+            description: description.to_string(),
+            contents: contents.into_bytes(),
+        });
+    }
+
+    /// Writes every entry into a tar archive, under a single top-level directory
+    pub fn write_tar<W: Write>(&self, out: &mut W) -> std::io::Result<()> {
+        let mtime = chrono::Utc::now().timestamp() as u64;
+        for entry in &self.entries {
+            let name = format!("{}/{}", BUNDLE_DIR, entry.name);
+            tar::append(out, &name, &entry.contents, mtime)?;
+        }
+        tar::finish(out)
+    }
+}
+
+/// Gathers the versions, platform, scrubbed config, database summary, and the `num_logs` most recent log files of the
+/// collection `col`.  Nothing is written anywhere.
+pub fn collect(
+    settings: &Settings,
+    col: &str,
+    num_logs: usize,
+    scrubber: &Scrubber,
+) -> STagResult<Bundle> {
+    info!(target: TAG, "Collecting diagnostics for {}", col);
+    let mut bundle = Bundle { entries: vec![] };
+
+    let db_file = settings.db_file(col);
+    let conn = if db_file.exists() {
+        Some(sql::db_for_collection(settings, col)?)
+    } else {
+        None
+    };
+
+    bundle.add(
+        "version.txt",
+        "supertag, database, and platform versions",
+        scrubber.scrub(&versions(conn.as_ref())),
+    );
+
+    let config = serde_json::to_string_pretty(&settings.get_config())
+        .map_err(|e| Box::new(e) as Box<dyn std::error::Error>)?;
+    bundle.add(
+        "config.json",
+        "the collection's effective config",
+        scrubber.scrub(&config),
+    );
+
+    let summary = match &conn {
+        Some(conn) => db_summary(conn, &db_file)?,
+        None => format!("No database at {}\n", db_file.display()),
+    };
+    bundle.add(
+        "database.txt",
+        "the database schema and row counts, without any rows",
+        scrubber.scrub(&summary),
+    );
+
+    for log in recent_logs(&settings.log_dir(col), num_logs) {
+        let name = log
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+        match std::fs::read(&log) {
+            Ok(contents) => bundle.add(
+                &format!("logs/{}", name),
+                "a log file, which may mention tag and file names",
+                scrubber.scrub(&String::from_utf8_lossy(&contents)),
+            ),
+            Err(e) => warn!(target: TAG, "Couldn't read log {:?}: {:?}", log, e),
+        }
+    }
+
+    Ok(bundle)
+}
+
+fn versions(conn: Option<&Connection>) -> String {
+    let mut out = format!("supertag: {}\n", crate::common::version_str());
+    match conn.map(sql::get_supertag_version) {
+        Some(Ok(version)) => out.push_str(&format!("database last opened by: {}\n", version)),
+        Some(Err(e)) => out.push_str(&format!("database last opened by: unknown ({})\n", e)),
+        None => {}
+    }
+    out.push_str(&format!(
+        "platform: {} {}\n",
+        std::env::consts::OS,
+        std::env::consts::ARCH
+    ));
+    let uts = nix::sys::utsname::uname();
+    out.push_str(&format!(
+        "kernel: {} {} {}\n",
+        uts.sysname(),
+        uts.release(),
+        uts.version()
+    ));
+    out
+}
+
+fn db_summary(conn: &Connection, db_file: &Path) -> STagResult<String> {
+    let mut out = sql::schema::describe(conn)?;
+    out.push_str("\nRow counts\n");
+    for (table, count) in sql::schema::table_counts(conn)? {
+        out.push_str(&format!("    {:<18} {}\n", table, count));
+    }
+    if let Ok(md) = std::fs::metadata(db_file) {
+        out.push_str(&format!("\nDatabase size: {} bytes\n", md.len()));
+    }
+    Ok(out)
+}
+
+/// The `num` most recently modified log files in `log_dir`, newest first
+fn recent_logs(log_dir: &Path, num: usize) -> Vec<PathBuf> {
+    let mut logs = match std::fs::read_dir(log_dir) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_name().to_string_lossy().ends_with(".log"))
+            .filter_map(|e| Some((e.path(), e.metadata().and_then(|md| md.modified()).ok()?)))
+            .collect::<Vec<_>>(),
+        Err(e) => {
+            warn!(target: TAG, "Couldn't list logs in {:?}: {:?}", log_dir, e);
+            vec![]
+        }
+    };
+    logs.sort_by(|a, b| b.1.cmp(&a.1));
+    logs.into_iter()
+        .take(num)
+        .map(|(path, _mtime)| path)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scrub() {
+        let scrubber = Scrubber::new(vec![
+            ("amy".to_string(), "<user>".to_string()),
+            ("/home/amy".to_string(), "~".to_string()),
+            ("".to_string(), "nothing".to_string()),
+        ]);
+        assert_eq!(
+            scrubber.scrub("mounted /home/amy/supertag/music for amy"),
+            "mounted ~/supertag/music for <user>"
+        );
+        assert_eq!(scrubber.scrub("no secrets"), "no secrets");
+    }
+}
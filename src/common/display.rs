@@ -0,0 +1,125 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Display transforms change how file names are rendered in filedir listings, without changing the names that are
+//! stored in the database.  Because a transform is not necessarily invertible (title-casing, for example, loses
+//! information), we "invert" a transform on lookup by applying it to each candidate's real name and comparing the
+//! results against the name being looked up.
+
+use crate::common::settings::config::Display;
+
+/// Renders `name` with all of the transforms enabled in `conf`, in a fixed order
+pub fn display_name(conf: &Display, name: &str) -> String {
+    let mut displayed = name.to_string();
+    if conf.strip_extensions {
+        displayed = strip_extension(&displayed).to_string();
+    }
+    if conf.underscores_to_spaces {
+        displayed = displayed.replace('_', " ");
+    }
+    if conf.title_case {
+        displayed = title_case(&displayed);
+    }
+    displayed
+}
+
+/// Whether a lookup of `displayed` should resolve to a file whose real name is `original`.  The real name always
+/// matches, so that paths from before a transform was enabled continue to work.
+pub fn matches(conf: &Display, displayed: &str, original: &str) -> bool {
+    displayed == original || (conf.is_active() && display_name(conf, original) == displayed)
+}
+
+/// When a displayed name is renamed, we try to preserve the parts of the original name that the transforms hid from
+/// the user.  Currently that is just the extension.
+pub fn restore_name(conf: &Display, new_displayed: &str, original: &str) -> String {
+    if conf.strip_extensions {
+        if let Some(ext) = extension(original) {
+            if extension(new_displayed).is_none() {
+                return format!("{}.{}", new_displayed, ext);
+            }
+        }
+    }
+    new_displayed.to_string()
+}
+
+fn extension(name: &str) -> Option<&str> {
+    match name.rfind('.') {
+        // a leading dot is a hidden file, not an extension
+        Some(0) | None => None,
+        Some(idx) => Some(&name[idx + 1..]),
+    }
+}
+
+fn strip_extension(name: &str) -> &str {
+    match name.rfind('.') {
+        Some(0) | None => name,
+        Some(idx) => &name[..idx],
+    }
+}
+
+fn title_case(name: &str) -> String {
+    let mut titled = String::with_capacity(name.len());
+    let mut word_start = true;
+    for ch in name.chars() {
+        if word_start {
+            titled.extend(ch.to_uppercase());
+        } else {
+            titled.push(ch);
+        }
+        word_start = ch.is_whitespace() || ch == '_' || ch == '-';
+    }
+    titled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conf(strip_extensions: bool, underscores_to_spaces: bool, title_case: bool) -> Display {
+        Display {
+            strip_extensions,
+            underscores_to_spaces,
+            title_case,
+        }
+    }
+
+    #[test]
+    fn test_display_name() {
+        let all = conf(true, true, true);
+        assert_eq!(display_name(&all, "my_cool_song.mp3"), "My Cool Song");
+        assert_eq!(display_name(&all, ".hidden"), ".hidden");
+        assert_eq!(
+            display_name(&conf(false, false, false), "a_b.txt"),
+            "a_b.txt"
+        );
+        assert_eq!(display_name(&conf(true, false, false), "a.tar.gz"), "a.tar");
+    }
+
+    #[test]
+    fn test_matches_and_restore() {
+        let strip = conf(true, true, false);
+        assert!(matches(&strip, "my song", "my_song.mp3"));
+        assert!(matches(&strip, "my_song.mp3", "my_song.mp3"));
+        assert!(!matches(&strip, "my song", "other.mp3"));
+        assert_eq!(
+            restore_name(&strip, "new song", "my_song.mp3"),
+            "new song.mp3"
+        );
+        assert_eq!(restore_name(&strip, "new.ogg", "my_song.mp3"), "new.ogg");
+    }
+}
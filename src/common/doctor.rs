@@ -0,0 +1,248 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Finds tagged files whose real files have moved or been replaced since they were tagged, and reconciles the
+//! database with what's actually on disk.  A file is healthy when something exists at its recorded path and that
+//! something has the recorded device/inode, or for a file identified by path, the identity of that path.
+
+use crate::common::err::STagResult;
+use crate::common::types::DeviceFile;
+use crate::common::{get_device_inode, identity};
+use crate::sql;
+use crate::sql::types::FileRecord;
+use rusqlite::{Connection, Transaction};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use tracing::{debug, info, warn};
+
+const TAG: &str = "doctor";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    /// Nothing exists at the recorded path
+    Missing,
+    /// Something exists at the recorded path, but with a different device/inode.  It's usually the same file,
+    /// rewritten by a program that saves by replacing the file instead of writing to it.
+    Replaced { device: u64, inode: u64 },
+}
+
+#[derive(Debug, Clone)]
+pub struct Finding {
+    pub file: FileRecord,
+    pub problem: Problem,
+}
+
+/// What a repair did with a single finding
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    /// The file was found somewhere else, and now points there
+    Relocated(PathBuf),
+    /// The file at the recorded path was adopted under its new device/inode
+    Reidentified,
+    /// The file was removed from the collection
+    Dropped,
+    /// More than one file could be the missing one, so nothing was changed
+    Ambiguous(Vec<PathBuf>),
+    NotFound,
+    /// The file that was found is already tagged under another entry, so nothing was changed
+    AlreadyTagged(PathBuf),
+}
+
+/// Checks every tagged file against the real filesystem
+pub fn scan(conn: &Connection) -> STagResult<Vec<Finding>> {
+    let files = sql::get_all_files(conn)?;
+    info!(target: TAG, "Checking {} files", files.len());
+
+    let mut findings = vec![];
+    for file in files {
+        let problem = match identity_at(&file, Path::new(&file.path)) {
+            Ok((device, inode)) if device == file.device && inode == file.inode => continue,
+            Ok((device, inode)) => Problem::Replaced { device, inode },
+            Err(_) => Problem::Missing,
+        };
+        debug!(target: TAG, "{:?} is {:?}", file.path, problem);
+        findings.push(Finding { file, problem });
+    }
+    Ok(findings)
+}
+
+/// Every regular file under `roots`, by file name
+fn index_roots(roots: &[PathBuf]) -> HashMap<OsString, Vec<PathBuf>> {
+    let mut index: HashMap<OsString, Vec<PathBuf>> = HashMap::new();
+    for root in roots {
+        info!(target: TAG, "Indexing {:?}", root);
+        for entry in walkdir::WalkDir::new(root).follow_links(false) {
+            match entry {
+                Ok(entry) if entry.file_type().is_file() => index
+                    .entry(entry.file_name().to_owned())
+                    .or_default()
+                    .push(entry.into_path()),
+                Ok(_) => {}
+                Err(e) => warn!(target: TAG, "Couldn't index under {:?}: {:?}", root, e),
+            }
+        }
+    }
+    index
+}
+
+/// Files under the search roots that could be `file`: they have the same name, and the same size if we know it
+fn candidates(index: &HashMap<OsString, Vec<PathBuf>>, file: &FileRecord) -> Vec<PathBuf> {
+    let name = match Path::new(&file.path).file_name() {
+        Some(name) => name,
+        None => return vec![],
+    };
+    index
+        .get(name)
+        .map(|paths| {
+            paths
+                .iter()
+                .filter(|path| match (file.target_size, std::fs::metadata(path)) {
+                    (Some(size), Ok(md)) => md.len() == size,
+                    (None, Ok(_)) => true,
+                    (_, Err(_)) => false,
+                })
+                .cloned()
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// The identity that the real file at `path` would have as `file`, which is by path if `file` is identified by path
+fn identity_at(file: &FileRecord, path: &Path) -> STagResult<(u64, u64)> {
+    if identity::is_path_identity(file.device) {
+        Ok(identity::path_identity(&path.canonicalize()?))
+    } else {
+        get_device_inode(path)
+    }
+}
+
+fn relocate(tx: &Transaction, file: &FileRecord, path: &Path, now: f64) -> STagResult<bool> {
+    let (device, inode) = identity_at(file, path)?;
+    if let Some(other) = sql::get_file_id(tx, device, inode)? {
+        if other != file.id {
+            return Ok(false);
+        }
+    }
+    sql::relocate_file(tx, file.id, device, inode, &path.to_string_lossy(), now)?;
+    Ok(true)
+}
+
+/// Repairs `findings` by pointing each file at where it is now.  Replaced files adopt whatever is at their path, and
+/// missing files are searched for by name under `roots`, and only relocated if there's exactly one candidate.
+pub fn repair_by_search(
+    tx: &Transaction,
+    findings: &[Finding],
+    roots: &[PathBuf],
+    now: f64,
+) -> STagResult<Vec<Outcome>> {
+    let needs_search = findings.iter().any(|f| f.problem == Problem::Missing);
+    let index = if needs_search {
+        index_roots(roots)
+    } else {
+        HashMap::new()
+    };
+
+    let mut outcomes = vec![];
+    for finding in findings {
+        let path = PathBuf::from(&finding.file.path);
+        let outcome = match &finding.problem {
+            Problem::Replaced { .. } => {
+                if relocate(tx, &finding.file, &path, now)? {
+                    Outcome::Reidentified
+                } else {
+                    Outcome::AlreadyTagged(path)
+                }
+            }
+            Problem::Missing => {
+                let mut found = candidates(&index, &finding.file);
+                match found.len() {
+                    0 => Outcome::NotFound,
+                    1 => {
+                        let new_path = found.remove(0);
+                        if relocate(tx, &finding.file, &new_path, now)? {
+                            Outcome::Relocated(new_path)
+                        } else {
+                            Outcome::AlreadyTagged(new_path)
+                        }
+                    }
+                    _ => Outcome::Ambiguous(found),
+                }
+            }
+        };
+        debug!(target: TAG, "{:?}: {:?}", finding.file.path, outcome);
+        outcomes.push(outcome);
+    }
+    Ok(outcomes)
+}
+
+/// Repairs `findings` by removing every broken file from the collection
+pub fn repair_by_drop(
+    tx: &Transaction,
+    findings: &[Finding],
+    now: f64,
+) -> STagResult<Vec<Outcome>> {
+    let mut outcomes = vec![];
+    for finding in findings {
+        let df = DeviceFile::new(
+            &finding.file.primary_tag,
+            finding.file.device,
+            finding.file.inode,
+        );
+        sql::purge_devicefile(tx, &df, now)?;
+        outcomes.push(Outcome::Dropped);
+    }
+    Ok(outcomes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(path: &Path, target_size: Option<u64>) -> FileRecord {
+        FileRecord {
+            id: 1,
+            device: 1,
+            inode: 1,
+            path: path.to_string_lossy().to_string(),
+            primary_tag: "a.txt".to_string(),
+            target_size,
+        }
+    }
+
+    #[test]
+    fn test_candidates() -> STagResult<()> {
+        let root = tempfile::tempdir()?;
+        std::fs::create_dir_all(root.path().join("x"))?;
+        std::fs::create_dir_all(root.path().join("y"))?;
+        std::fs::write(root.path().join("x/a.txt"), b"12345")?;
+        std::fs::write(root.path().join("y/a.txt"), b"123")?;
+        std::fs::write(root.path().join("y/b.txt"), b"12345")?;
+        let index = index_roots(&[root.path().to_owned()]);
+
+        let gone = Path::new("/nowhere/a.txt");
+        assert_eq!(candidates(&index, &record(gone, None)).len(), 2);
+        assert_eq!(
+            candidates(&index, &record(gone, Some(5))),
+            vec![root.path().join("x/a.txt")],
+            "a known size narrows it down"
+        );
+        assert!(candidates(&index, &record(Path::new("/nowhere/c.txt"), None)).is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,126 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Finds the same file tagged more than once under different paths, like a download that was saved twice and tagged
+//! separately each time.  Files are compared by a BLAKE3 hash of their contents, which is recorded as they're linked
+//! when `duplicates.enabled` is set, or by `tag dupes` for files that haven't been hashed yet.
+
+use crate::common::err::STagResult;
+use crate::common::types::UtcDt;
+use crate::sql;
+use crate::sql::types::FileRecord;
+use rusqlite::Transaction;
+use std::fs::File;
+use std::path::Path;
+use tracing::{debug, info};
+
+const TAG: &str = "dupes";
+
+/// A tagged file whose contents have been hashed, with what its target looked like just before
+#[derive(Debug, Clone)]
+pub struct Hashed {
+    pub file: FileRecord,
+    pub size: u64,
+    pub mtime: UtcDt,
+    pub hash: String,
+}
+
+/// The hex BLAKE3 hash of the contents of the file at `path`
+pub fn hash_file(path: &Path) -> std::io::Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    std::io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// Hashes the targets of `files`, calling `on_hash` after each one.  Targets that can't be read, usually because
+/// they're missing, are skipped; `tag doctor` is what deals with those.
+pub fn hash_files<F: FnMut(usize, &FileRecord)>(
+    files: &[FileRecord],
+    mut on_hash: F,
+) -> Vec<Hashed> {
+    info!(target: TAG, "Hashing {} files", files.len());
+    let mut hashed = vec![];
+    for (done, file) in files.iter().enumerate() {
+        let path = Path::new(&file.path);
+        let result = std::fs::metadata(path)
+            .and_then(|md| Ok((md.len(), md.modified()?)))
+            .and_then(|(size, modified)| Ok((size, modified, hash_file(path)?)));
+        match result {
+            Ok((size, modified, hash)) => hashed.push(Hashed {
+                file: file.clone(),
+                size,
+                mtime: UtcDt::from(modified),
+                hash,
+            }),
+            Err(e) => debug!(target: TAG, "Couldn't hash {:?}: {:?}", path, e),
+        }
+        on_hash(done + 1, file);
+    }
+    hashed
+}
+
+/// Records the hashes from `hash_files`, along with the target metadata they were taken with, so that a later change
+/// to a target is noticed and its hash forgotten
+pub fn record(tx: &Transaction, hashed: &[Hashed]) -> STagResult<()> {
+    for h in hashed {
+        sql::set_target_metadata(tx, h.file.device, h.file.inode, h.size, &h.mtime)?;
+        sql::set_content_hash(tx, h.file.device, h.file.inode, &h.hash)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_hash_files() -> STagResult<()> {
+        let dir = tempfile::tempdir()?;
+        let mut records = vec![];
+        for (id, contents) in ["same", "same", "different"].iter().enumerate() {
+            let path = dir.path().join(format!("f{}", id));
+            File::create(&path)?.write_all(contents.as_bytes())?;
+            records.push(FileRecord {
+                id: id as i64,
+                device: 1,
+                inode: id as u64,
+                path: path.to_string_lossy().to_string(),
+                primary_tag: format!("f{}", id),
+                target_size: None,
+            });
+        }
+        records.push(FileRecord {
+            id: 3,
+            device: 1,
+            inode: 3,
+            path: dir.path().join("missing").to_string_lossy().to_string(),
+            primary_tag: "missing".to_string(),
+            target_size: None,
+        });
+
+        let mut seen = 0;
+        let hashed = hash_files(&records, |done, _| seen = done);
+        assert_eq!(seen, 4);
+        assert_eq!(hashed.len(), 3, "the missing file is skipped");
+        assert_eq!(hashed[0].hash, hashed[1].hash);
+        assert_ne!(hashed[0].hash, hashed[2].hash);
+        assert_eq!(hashed[2].size, 9);
+        Ok(())
+    }
+}
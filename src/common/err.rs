@@ -16,10 +16,12 @@
  * along with this program.  If not, see <http://www.gnu.org/licenses/>.
  */
 
+use crate::common::constants;
 #[cfg(target_os = "macos")]
 use core_foundation::error::CFError;
 use fuse_sys::err::FuseErrno;
 use nix::errno::Errno;
+use rusqlite::ErrorCode;
 use std::error::Error;
 use std::io::ErrorKind;
 use std::path::PathBuf;
@@ -36,6 +38,18 @@ pub enum STagError {
     BadDeviceFile(String),
     PathExists(PathBuf),
     RecursiveLink(PathBuf),
+    SymbolConflict(Vec<String>),
+    /// The configured name template, given first, can't be used for the reason given second
+    BadNameTemplate(String, String),
+    NameTooLong(String),
+    /// A path or search expression has more negated tags than the configured limit, which is given second
+    TooManyNegations(usize, usize),
+    NotEmpty(PathBuf),
+    ProtectedPath(PathBuf),
+    /// Writes are held off while the collection is closed for maintenance, for the given reason
+    Maintenance(String),
+    /// The disk holding the collection's database is full, so writes are held off until there's room again
+    DiskFull,
     IOError(Box<dyn Error>),
     Other(Box<dyn Error>),
     #[cfg(target_os = "macos")]
@@ -86,10 +100,68 @@ impl Error for STagError {
     }
 }
 
+/// The closest errno to a database failure.  Most of them really are just IO errors, but the few that have a
+/// better-fitting errno get it, so that file managers can show something more helpful than "Input/output error"
+pub fn sqlite_errno(e: &rusqlite::Error) -> Errno {
+    match e {
+        rusqlite::Error::SqliteFailure(ffi_err, _) => match ffi_err.code {
+            ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked => Errno::EBUSY,
+            ErrorCode::DiskFull => Errno::ENOSPC,
+            ErrorCode::ConstraintViolation => Errno::EEXIST,
+            ErrorCode::ReadOnly => Errno::EROFS,
+            ErrorCode::TooBig => Errno::ENAMETOOLONG,
+            _ => Errno::EIO,
+        },
+        rusqlite::Error::QueryReturnedNoRows => Errno::ENOENT,
+        _ => Errno::EIO,
+    }
+}
+
+impl STagError {
+    /// The errno that best describes this error to something on the other side of the FUSE mount.  Anything without
+    /// a better fit is an EIO.
+    pub fn errno(&self) -> Errno {
+        match self {
+            STagError::PathExists(_) => Errno::EEXIST,
+            STagError::BadTag(_)
+            | STagError::BadTagGroup(_)
+            | STagError::BadDeviceFile(_)
+            | STagError::NotEnoughTags
+            | STagError::InvalidPath(_)
+            | STagError::SymbolConflict(_)
+            | STagError::BadNameTemplate(..) => Errno::EINVAL,
+            STagError::NonCollectionPath(_) => Errno::EXDEV,
+            STagError::RecursiveLink(_) => Errno::ELOOP,
+            STagError::NameTooLong(_) | STagError::TooManyNegations(..) => Errno::ENAMETOOLONG,
+            STagError::NotEmpty(_) => Errno::ENOTEMPTY,
+            STagError::ProtectedPath(_) => Errno::EPERM,
+            STagError::Maintenance(_) => Errno::EBUSY,
+            STagError::DiskFull => Errno::ENOSPC,
+            STagError::DatabaseError(e) => sqlite_errno(e),
+            STagError::IOError(e) | STagError::Other(e) => {
+                if let Some(io_err) = e.downcast_ref::<std::io::Error>() {
+                    io_err.raw_os_error().map_or(Errno::EIO, Errno::from_i32)
+                } else if let Some(nix_err) = e.downcast_ref::<nix::Error>() {
+                    nix_err.as_errno().unwrap_or(Errno::EIO)
+                } else {
+                    Errno::EIO
+                }
+            }
+            #[cfg(target_os = "macos")]
+            STagError::MacosError(_) => Errno::EIO,
+        }
+    }
+
+    /// Whether this error came from the database's disk running out of room, however it reached us
+    pub fn is_disk_full(&self) -> bool {
+        self.errno() == Errno::ENOSPC
+    }
+}
+
 impl From<STagError> for FuseErrno {
     fn from(e: STagError) -> Self {
         Self {
-            errno: Errno::EIO,
+            errno: e.errno(),
             original: Some(Box::new(e)),
         }
     }
@@ -108,6 +180,40 @@ impl std::fmt::Display for STagError {
             STagError::Other(e) => write!(f, "Other unknown error: {:?}", e),
             STagError::NotEnoughTags => write!(f, "Not enough tags"),
             STagError::RecursiveLink(src) => write!(f, "Recursive symlink {:?}", src),
+            STagError::SymbolConflict(names) => write!(
+                f,
+                "These names conflict with the configured symbols: {}",
+                names.join(", ")
+            ),
+            STagError::BadNameTemplate(template, reason) => {
+                write!(f, "Invalid name template {:?}, it {}", template, reason)
+            }
+            STagError::NameTooLong(name) => write!(
+                f,
+                "Name {:?} is longer than {} bytes",
+                name,
+                constants::NAME_MAX
+            ),
+            STagError::TooManyNegations(count, max) => write!(
+                f,
+                "{} negated tags is more than the {} allowed by max_negations in the [query] config",
+                count, max
+            ),
+            STagError::NotEmpty(path) => write!(f, "{:?} is not empty", path),
+            STagError::ProtectedPath(path) => write!(
+                f,
+                "{:?} belongs to the collection itself and can't be tagged",
+                path
+            ),
+            STagError::Maintenance(reason) => write!(
+                f,
+                "The collection is closed for maintenance ({}), try again once it's done",
+                reason
+            ),
+            STagError::DiskFull => write!(
+                f,
+                "The disk holding the collection's database is full, the collection is read-only until space is freed"
+            ),
             #[cfg(target_os = "macos")]
             STagError::MacosError(cfe) => write!(f, "Macos error: {:?}", cfe),
             STagError::NonCollectionPath(src) => write!(
@@ -134,3 +240,65 @@ impl std::fmt::Display for ParseOctalError {
     }
 }
 impl Error for ParseOctalError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_errno() {
+        assert_eq!(
+            STagError::PathExists(PathBuf::from("/a")).errno(),
+            Errno::EEXIST
+        );
+        assert_eq!(STagError::BadTag("a".into()).errno(), Errno::EINVAL);
+        assert_eq!(
+            STagError::NameTooLong("a".into()).errno(),
+            Errno::ENAMETOOLONG
+        );
+        assert_eq!(
+            STagError::TooManyNegations(9, 8).errno(),
+            Errno::ENAMETOOLONG
+        );
+        assert_eq!(
+            STagError::NotEmpty(PathBuf::from("a")).errno(),
+            Errno::ENOTEMPTY
+        );
+        assert_eq!(
+            STagError::NonCollectionPath(PathBuf::from("/a")).errno(),
+            Errno::EXDEV
+        );
+
+        let io_err = std::io::Error::from_raw_os_error(Errno::EACCES as i32);
+        assert_eq!(STagError::from(io_err).errno(), Errno::EACCES);
+
+        let nix_err = nix::Error::Sys(Errno::ENOSPC);
+        assert_eq!(STagError::from(nix_err).errno(), Errno::ENOSPC);
+
+        let full = rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_FULL),
+            None,
+        );
+        assert_eq!(STagError::from(full).errno(), Errno::ENOSPC);
+        assert_eq!(
+            STagError::from(rusqlite::Error::InvalidQuery).errno(),
+            Errno::EIO
+        );
+    }
+
+    #[test]
+    fn test_disk_full() {
+        let full = rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_FULL),
+            None,
+        );
+        assert!(STagError::from(full).is_disk_full());
+        assert!(STagError::from(nix::Error::Sys(Errno::ENOSPC)).is_disk_full());
+        assert!(STagError::DiskFull.is_disk_full());
+        assert_eq!(STagError::DiskFull.errno(), Errno::ENOSPC);
+
+        let io_err = std::io::Error::from_raw_os_error(Errno::EACCES as i32);
+        assert!(!STagError::from(io_err).is_disk_full());
+        assert!(!STagError::Maintenance("backup".to_string()).is_disk_full());
+    }
+}
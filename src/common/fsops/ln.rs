@@ -15,7 +15,8 @@
  * You should have received a copy of the GNU Affero General Public License
  * along with this program.  If not, see <http://www.gnu.org/licenses/>.
  */
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use rusqlite::Transaction;
 
@@ -24,14 +25,188 @@ use crate::sql;
 use super::super::err::STagResult;
 use super::super::settings::Settings;
 use super::super::types::file_perms::UMask;
-use super::WRAPPER_TAG;
+use super::{check_name_len, check_source, check_tag_name, WRAPPER_TAG};
+use crate::common::dupes;
 use crate::common::err::STagError;
-use crate::common::get_device_inode;
+use crate::common::managed_file;
 use crate::common::notify::Notifier;
-use crate::common::types::{TagCollectible, TagCollection};
-use crate::sql::types::TaggedFile;
+use crate::common::types::{TagCollectible, TagCollection, UtcDt};
+use crate::common::{get_filename, identity};
+use crate::sql::types::{NewFile, TaggedFile};
 use fuse_sys::{gid_t, uid_t};
-use log::{debug, error, info};
+use tracing::{debug, error, info, warn};
+
+/// Links each of `srcs` to `rel_dst`, named after its filename, calling `on_link` after each one.  Stops early if `stop`
+/// is set, and returns how many were linked, so that the caller can decide whether to commit a partial batch.
+pub fn ln_batch<N: Notifier, F: FnMut(&Path)>(
+    settings: &Settings,
+    tx: &Transaction,
+    srcs: &[PathBuf],
+    rel_dst: &Path,
+    uid: uid_t,
+    gid: gid_t,
+    umask: &UMask,
+    notifier: &N,
+    stop: &AtomicBool,
+    mut on_link: F,
+) -> STagResult<usize> {
+    for (linked, src) in srcs.iter().enumerate() {
+        if stop.load(Ordering::Relaxed) {
+            info!(target: WRAPPER_TAG, "Stopped after linking {} of {} files", linked, srcs.len());
+            return Ok(linked);
+        }
+        let primary_tag = get_filename(src)?;
+        ln(
+            settings,
+            tx,
+            src,
+            rel_dst,
+            primary_tag,
+            uid,
+            gid,
+            umask,
+            None,
+            notifier,
+        )?;
+        on_link(src);
+    }
+    Ok(srcs.len())
+}
+
+/// A file found by linking a directory recursively
+#[derive(Debug, Clone)]
+pub struct TreeFile {
+    pub src: PathBuf,
+    /// The name it's listed under
+    pub primary_tag: String,
+    /// Tags on top of the destination's, from the directories between the linked directory and the file
+    pub dir_tags: Vec<String>,
+}
+
+/// Links `files` to `rel_dst` with one batched insert, instead of the round of queries per file that `ln` makes, which
+/// dominates when linking a whole tree.  They're all regular files, so there are no aliases or directories among them.
+/// Nothing is hashed, so, like imported files, they only show up as duplicates after `tag dupes`.
+pub fn ln_tree(
+    settings: &Settings,
+    tx: &Transaction,
+    files: &[TreeFile],
+    rel_dst: &Path,
+    uid: uid_t,
+    gid: gid_t,
+    umask: &UMask,
+) -> STagResult<()> {
+    settings.check_write_gate()?;
+    info!(target: WRAPPER_TAG, "ln {} files to {:?}", files.len(), rel_dst);
+    if rel_dst == Path::new("") {
+        return Err(STagError::InvalidPath(rel_dst.to_owned()));
+    }
+    check_name_len(rel_dst)?;
+
+    let mut batch = Vec::with_capacity(files.len());
+    for file in files {
+        check_source(settings, &file.src)?;
+        check_name_len(Path::new(&file.primary_tag))?;
+        let mut tags = link_tags(settings, &file.src, rel_dst)?;
+        for tag in &file.dir_tags {
+            if check_tag_name(tag).is_err() {
+                warn!(target: WRAPPER_TAG, "Skipping directory tag {:?} of {:?}", tag, file.src);
+            } else if !tags.contains(tag) {
+                tags.push(tag.clone());
+            }
+        }
+        let (device, inode) = settings.file_identity(&file.src)?;
+        batch.push(NewFile {
+            device,
+            inode,
+            path: file
+                .src
+                .to_str()
+                .ok_or_else(|| STagError::InvalidPath(file.src.to_owned()))?
+                .to_string(),
+            primary_tag: file.primary_tag.clone(),
+            tags,
+        });
+    }
+
+    let groups = settings.get_config().groups;
+    sql::add_files(tx, &batch, &groups, uid, gid, umask, sql::get_now_secs())?;
+    for (file, new) in files.iter().zip(&batch) {
+        if let Ok((size, modified)) =
+            std::fs::metadata(&file.src).and_then(|md| Ok((md.len(), md.modified()?)))
+        {
+            sql::set_target_metadata(tx, new.device, new.inode, size, &UtcDt::from(modified))?;
+        }
+    }
+    Ok(())
+}
+
+/// The tags that `src` gets when it's linked to `rel_dst`: the regular tags of `rel_dst`, and whatever rules,
+/// providers and the file's own media metadata add
+fn link_tags(settings: &Settings, src: &Path, rel_dst: &Path) -> STagResult<Vec<String>> {
+    let tag_parts = TagCollection::new(&settings, rel_dst);
+    let mut tags: Vec<String> = tag_parts
+        .iter()
+        .collect_regular_names()
+        .into_iter()
+        .map(String::from)
+        .collect();
+    for tag in &tags {
+        check_tag_name(tag)?;
+    }
+
+    let ruled = settings.rules().tags_for(src);
+    if !ruled.is_empty() {
+        debug!(target: WRAPPER_TAG, "Rules add tags {:?} to {:?}", ruled, src);
+    }
+    let provided = settings.provider_tags(src);
+    if !provided.is_empty() {
+        debug!(target: WRAPPER_TAG, "Providers add tags {:?} to {:?}", provided, src);
+    }
+    let media = settings.media_tags(src);
+    if !media.is_empty() {
+        debug!(target: WRAPPER_TAG, "Media metadata adds tags {:?} to {:?}", media, src);
+    }
+    // these come from outside, so one that can't be a tag is passed over, rather than failing the whole link
+    for tag in ruled.into_iter().chain(provided).chain(media) {
+        if check_tag_name(&tag).is_err() {
+            warn!(target: WRAPPER_TAG, "Skipping tag {:?} for {:?}", tag, src);
+        } else if !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    Ok(tags)
+}
+
+/// The content hashes of the files among `srcs`, for the duplicates directory, if it's enabled.  Hashing reads every
+/// file, so it happens before the write transaction that links them, and `record_hashes` keeps them inside it.
+pub fn hash_targets(settings: &Settings, srcs: &[PathBuf]) -> Vec<(PathBuf, String)> {
+    if !settings.get_config().duplicates.enabled {
+        return vec![];
+    }
+    srcs.iter()
+        .filter(|src| src.is_file())
+        .filter_map(|src| match dupes::hash_file(src) {
+            Ok(hash) => Some((src.clone(), hash)),
+            Err(e) => {
+                debug!(target: WRAPPER_TAG, "Couldn't hash target {:?}: {:?}", src, e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Records the hashes from `hash_targets`, once `ln` has linked their files in `tx`
+pub fn record_hashes(
+    settings: &Settings,
+    tx: &Transaction,
+    hashes: &[(PathBuf, String)],
+) -> STagResult<()> {
+    for (src, hash) in hashes {
+        let (device, inode) = settings.file_identity(src)?;
+        sql::set_content_hash(tx, device, inode, hash)?;
+    }
+    Ok(())
+}
 
 pub fn ln<N: Notifier>(
     settings: &Settings,
@@ -45,6 +220,7 @@ pub fn ln<N: Notifier>(
     alias_file: Option<&Path>,
     notifier: &N,
 ) -> STagResult<Vec<TaggedFile>> {
+    settings.check_write_gate()?;
     info!(target: WRAPPER_TAG, "ln {:?} to {:?}", src, rel_dst);
 
     if let Some(src_col) = settings.collection_from_path(src, false) {
@@ -63,17 +239,32 @@ pub fn ln<N: Notifier>(
         );
     }
 
+    if let Err(e) = check_source(settings, src) {
+        error!(target: WRAPPER_TAG, "{}", e);
+        notifier.protected_source(src)?;
+        return Err(e);
+    }
+
     if rel_dst == Path::new("") {
         notifier.dragged_to_root()?;
         return Err(STagError::InvalidPath(rel_dst.to_owned()));
     }
+    check_name_len(rel_dst)?;
 
-    let tag_parts = TagCollection::new(&settings, rel_dst);
-    let tags = tag_parts.iter().collect_regular_names();
-    let (device, inode) = get_device_inode(src)?;
+    let tags = link_tags(settings, src, rel_dst)?;
+    let tags: Vec<&str> = tags.iter().map(String::as_str).collect();
+    let (device, inode) = settings.file_identity(src)?;
+    let now = sql::get_now_secs();
+    let is_dir = src.is_dir();
+    // a path-identity file is found again by its contents, so hash it before it's linked, in case it has moved
+    let content_hash = if identity::is_path_identity(device) && !is_dir {
+        Some(identity::adopt_moved(tx, device, inode, src, now)?)
+    } else {
+        None
+    };
     let maybe_alias_file = alias_file.map(|a| a.to_str().unwrap());
 
-    let tagged = sql::add_file(
+    let mut tagged = sql::add_file(
         tx,
         device,
         inode,
@@ -81,12 +272,59 @@ pub fn ln<N: Notifier>(
             .ok_or_else(|| STagError::InvalidPath(src.to_owned()))?,
         primary_tag,
         tags.as_slice(),
+        &settings.get_config().groups,
         uid,
         gid,
         umask,
-        sql::get_now_secs(),
+        now,
         maybe_alias_file,
     )?;
+    if is_dir {
+        sql::set_is_dir(tx, device, inode)?;
+        for tf in tagged.iter_mut() {
+            tf.is_dir = true;
+        }
+    }
+
+    // the target is right at hand, so remember what it looks like for when its device isn't
+    match std::fs::metadata(src).and_then(|md| Ok((md.len(), md.modified()?))) {
+        Ok((size, modified)) => {
+            sql::set_target_metadata(tx, device, inode, size, &UtcDt::from(modified))?;
+        }
+        Err(e) => debug!(target: WRAPPER_TAG, "Couldn't stat target {:?}: {:?}", src, e),
+    }
+    if let Some(hash) = content_hash {
+        sql::set_content_hash(tx, device, inode, &hash)?;
+    }
+
+    // a managed file is all that's left of an alias, so remember what it looked like for `tag verify`
+    if let Some(alias_file) = maybe_alias_file {
+        match managed_file::checksum(Path::new(alias_file)) {
+            Ok(checksum) => {
+                sql::set_alias_checksum(tx, alias_file, &checksum)?;
+            }
+            Err(e) => {
+                warn!(target: WRAPPER_TAG, "Couldn't checksum managed file {:?}: {:?}", alias_file, e)
+            }
+        }
+    }
+
+    // the directory's files are listed in the intersection too, each as though it had been linked on its own
+    if is_dir && settings.get_config().tagging.dir_children {
+        for entry in walkdir::WalkDir::new(src).min_depth(1).follow_links(false) {
+            match entry {
+                Ok(entry) if entry.file_type().is_file() => {
+                    let child = entry.path();
+                    let name = get_filename(child)?;
+                    ln(
+                        settings, tx, child, rel_dst, name, uid, gid, umask, None, notifier,
+                    )?;
+                }
+                Ok(_) => {}
+                Err(e) => warn!(target: WRAPPER_TAG, "Couldn't list under {:?}: {:?}", src, e),
+            }
+        }
+    }
 
     Ok(tagged)
 }
@@ -0,0 +1,126 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::path::Path;
+
+use rusqlite::Transaction;
+
+use crate::common::err::{STagError, STagResult};
+use crate::common::fsops::mv::resolve_collisions;
+use crate::common::fsops::{journal, stored_tags, WRAPPER_TAG};
+use crate::common::settings::Settings;
+use crate::common::types::file_perms::UMask;
+use crate::common::types::{MergeResolution, TagType};
+use crate::sql;
+use crate::sql::types::MergeCollision;
+use fuse_sys::{gid_t, uid_t};
+use tracing::{debug, info};
+
+/// Merges the top-level tag or tag group `src` into `dst`, which must both already exist, and returns how many files
+/// were moved.  A merged tag's files are retagged with `dst`, and `dst` joins every tag group `src` was in.  A merged
+/// tag group's tags all join `dst`.  Either way, `src` is removed afterwards, unless `resolve` chose to leave some of
+/// its files behind.
+pub fn merge<R>(
+    settings: &Settings,
+    tx: &Transaction,
+    src: &str,
+    dst: &str,
+    uid: uid_t,
+    gid: gid_t,
+    umask: &UMask,
+    resolve: R,
+) -> STagResult<usize>
+where
+    R: FnMut(&MergeCollision) -> STagResult<MergeResolution>,
+{
+    settings.check_write_gate()?;
+    info!(target: WRAPPER_TAG, "Merging {} into {}", src, dst);
+    journal(settings, tx, "merge", &format!("{} -> {}", src, dst))?;
+
+    let src_tags = stored_tags(settings, tx, Path::new(src))?;
+    let dst_tags = stored_tags(settings, tx, Path::new(dst))?;
+    if src_tags.len() != 1 || dst_tags.len() != 1 {
+        return Err(STagError::InvalidPath(Path::new(src).join(dst)));
+    }
+
+    let now = sql::get_now_secs();
+    match (src_tags.primary_type()?, dst_tags.primary_type()?) {
+        (TagType::Regular(src_tag), TagType::Regular(dst_tag)) => {
+            let src_tag = sql::resolve_alias(tx, src_tag)?;
+            let dst_tag = sql::resolve_alias(tx, dst_tag)?;
+            for tag in &[&src_tag, &dst_tag] {
+                if !sql::tag_exists(tx, tag)? {
+                    return Err(STagError::BadTag(tag.to_string()));
+                }
+            }
+            if src_tag == dst_tag {
+                return Ok(0);
+            }
+
+            let src_types = [TagType::Regular(src_tag.clone())];
+            let dst_names = [dst_tag.as_str()];
+            let keep = resolve_collisions(tx, &src_types, &dst_names, resolve, now)?;
+            let num_files = sql::files_tagged_with(tx, &src_types)?.len() - keep.len();
+            sql::merge_tags(tx, &src_tag, &src_types, &dst_names, &keep, now)?;
+
+            let src_id =
+                sql::get_tag_id(tx, &src_tag)?.ok_or_else(|| STagError::BadTag(src_tag.clone()))?;
+            for group in sql::tag_groups_for_tag(tx, src_id)? {
+                debug!(
+                    target: WRAPPER_TAG,
+                    "Adding {} to {}'s tag group {}", dst_tag, src_tag, group.name
+                );
+                sql::add_tag_to_group(
+                    tx,
+                    &dst_tag,
+                    &group.name,
+                    uid,
+                    gid,
+                    &umask.dir_perms(),
+                    now,
+                )?;
+            }
+
+            if keep.is_empty() {
+                sql::remove_tag(tx, &src_tag, now, true)?;
+            }
+            Ok(num_files)
+        }
+        (TagType::Group(src_group), TagType::Group(dst_group)) => {
+            for group in &[src_group, dst_group] {
+                if !sql::tag_group_exists(tx, group)? {
+                    return Err(STagError::BadTagGroup(group.to_string()));
+                }
+            }
+            if src_group == dst_group {
+                return Ok(0);
+            }
+
+            let num_files = sql::num_files_for_tag_group(tx, src_group)? as usize;
+            for tag in sql::get_tags_in_tag_group(tx, src_group)? {
+                sql::add_tag_to_group(tx, &tag.name, dst_group, uid, gid, &umask.dir_perms(), now)?;
+            }
+            sql::update_tag_group_mtime(tx, dst_group, now)?;
+            sql::remove_taggroup(tx, src_group)?;
+            Ok(num_files)
+        }
+        (TagType::Group(_), _) => Err(STagError::BadTagGroup(dst.to_string())),
+        (TagType::Regular(_), _) => Err(STagError::BadTag(dst.to_string())),
+        _ => Err(STagError::BadTag(src.to_string())),
+    }
+}
@@ -19,14 +19,14 @@ use std::path::Path;
 
 use rusqlite::Transaction;
 
-use crate::common::err::STagResult;
-use crate::common::fsops::WRAPPER_TAG;
+use crate::common::err::{STagError, STagResult};
+use crate::common::fsops::{check_name_len, check_tag_name, WRAPPER_TAG};
 use crate::common::settings::Settings;
 use crate::common::types::file_perms::Permissions;
 use crate::common::types::{TagCollectible, TagCollection, TagType};
 use crate::sql;
 use fuse_sys::{gid_t, uid_t};
-use log::{debug, info};
+use tracing::{debug, info};
 
 pub fn mkdir(
     settings: &Settings,
@@ -36,14 +36,20 @@ pub fn mkdir(
     gid: gid_t,
     permissions: &Permissions,
 ) -> STagResult<()> {
+    settings.check_write_gate()?;
     info!(
         target: WRAPPER_TAG,
         "mkdir {:?} uid:{}, gid:{}, perms:{:?}", dir, uid, gid, permissions
     );
+    check_name_len(dir)?;
+    if let Some(name) = dir.file_name() {
+        check_tag_name(&name.to_string_lossy())?;
+    }
 
     let tags = TagCollection::new(settings, dir);
     let top_level = tags.len() == 1;
 
+    let groups = settings.get_config().groups;
     let now = sql::get_now_secs();
     if top_level {
         // can't fail because top_level == true
@@ -61,15 +67,29 @@ pub fn mkdir(
                     target: WRAPPER_TAG,
                     "{:?} is a top-level tag, ensuring it exists", tag
                 );
-                sql::ensure_tag(tx, tag, uid, gid, permissions, now)?;
+                sql::ensure_tag(tx, tag, &groups, uid, gid, permissions, now)?;
             }
             _ => {}
         }
     } else {
         let pinnable = tags.iter().collect_pinnable();
+        // a tag group can't be nested beneath itself
+        if let [.., TagType::Group(parent), TagType::Group(child)] = pinnable.as_slice() {
+            if sql::tag_group_exists(tx, child)? && sql::group_is_beneath(tx, parent, child)? {
+                return Err(STagError::BadTagGroup(child.to_owned()));
+            }
+        }
         if !pinnable.is_empty() {
             debug!(target: WRAPPER_TAG, "{:?} is a nested tag, pinning it", dir);
-            sql::pin_tags(&tx, pinnable.as_slice(), uid, gid, permissions, now)?;
+            sql::pin_tags(
+                &tx,
+                pinnable.as_slice(),
+                &groups,
+                uid,
+                gid,
+                permissions,
+                now,
+            )?;
         }
     }
 
@@ -23,23 +23,83 @@
 const WRAPPER_TAG: &str = "ops_wrapper";
 
 mod ln;
+mod merge;
 mod mkdir;
 mod mv;
+mod retag;
 mod rm;
 mod rmdir;
+mod swap;
+mod sync;
 
+use crate::common::constants;
+use crate::common::err::{STagError, STagResult};
 use crate::common::settings::Settings;
-use crate::common::types::TagCollectible;
-pub use ln::ln;
-use log::debug;
+use crate::common::types::{TagCollectible, TagCollection};
+use crate::sql;
+pub use ln::{hash_targets, ln, ln_batch, ln_tree, record_hashes, TreeFile};
+pub use merge::merge;
 pub use mkdir::mkdir;
-pub use mv::move_or_merge;
-pub use rm::rm;
+pub use mv::{merge_collisions, move_or_merge};
+pub use retag::{parse_tag_list, retag, retag_from_to};
+pub use rm::{purge_untagged, rm};
 pub use rmdir::rmdir;
+use rusqlite::{Connection, Transaction};
 use std::path::Path;
+pub use swap::swap;
+pub use sync::sync_from;
+use tracing::debug;
 
 const TAG: &str = "fsops";
 
+/// Records the changes `tx` makes as the destructive operation `op`, described by `detail`, so that `tag undo` can
+/// reverse them
+fn journal(settings: &Settings, tx: &Transaction, op: &str, detail: &str) -> STagResult<()> {
+    let keep = settings.get_config().undo.keep;
+    sql::undo::journal_op(tx, op, detail, keep, sql::get_now_secs())?;
+    Ok(())
+}
+
+/// The name that the tag `name` is stored under.  If tags are case-insensitive, that's the existing tag that only
+/// differs from it in case, since the statements that change tags match their names exactly.
+fn stored_name(settings: &Settings, conn: &Connection, name: &str) -> STagResult<String> {
+    if settings.get_config().collection.case_insensitive_tags {
+        if let Some(tag) = sql::get_tag(conn, name)? {
+            return Ok(tag.name);
+        }
+    }
+    Ok(name.to_owned())
+}
+
+/// The tags in `path`, with each tag and tag group spelled the way that it's stored, like `stored_name`
+fn stored_tags(settings: &Settings, conn: &Connection, path: &Path) -> STagResult<TagCollection> {
+    let mut tags = TagCollection::new(settings, path);
+    if settings.get_config().collection.case_insensitive_tags {
+        let form = sql::tag_form(conn)?;
+        let (mut failed, mut failed_group) = (None, None);
+        tags.canonicalize(
+            |name| match sql::get_tag_in_form(conn, form, name) {
+                Ok(tag) => tag.map(|t| t.name).filter(|stored| stored != name),
+                Err(e) => {
+                    failed.get_or_insert(e);
+                    None
+                }
+            },
+            |name| match sql::get_tag_group(conn, name) {
+                Ok(group) => group.map(|g| g.name).filter(|stored| stored != name),
+                Err(e) => {
+                    failed_group.get_or_insert(e);
+                    None
+                }
+            },
+        );
+        if let Some(e) = failed.or(failed_group) {
+            return Err(e.into());
+        }
+    }
+    Ok(tags)
+}
+
 // but now we need to communicate to supertag that we want to clear the entry from its caches.
 // we do this by removing the file, but appending a special char, so that when supertag sees this
 // path in the unlink handler, it will know that we just want it cleared from the caches
@@ -57,3 +117,48 @@ pub fn flush_tags(rel_path: &Path, settings: &Settings, mountpoint: impl AsRef<P
         flush_path(tag_path, settings);
     }
 }
+
+/// Rejects a `src` that belongs to the collection itself: its database, its managed files, or anything else in its
+/// directory.  Tagging one of those can create a cycle, or let a careless rename corrupt the collection.
+pub fn check_source(settings: &Settings, src: &Path) -> STagResult<()> {
+    if settings.get_config().tagging.allow_collection_files {
+        return Ok(());
+    }
+
+    let col = settings.get_collection();
+    let protected = [
+        settings.collection_dir(&col),
+        settings.managed_dir(&col),
+        settings.db_file(&col),
+    ];
+    for dir in &protected {
+        // our own paths may go through symlinks, while `src` is always canonical
+        let dir = dir.canonicalize().unwrap_or_else(|_| dir.to_owned());
+        if src.starts_with(&dir) {
+            debug!(target: TAG, "{:?} is under protected {:?}", src, dir);
+            return Err(STagError::ProtectedPath(src.to_owned()));
+        }
+    }
+    Ok(())
+}
+
+/// Rejects a tag name with the union separator in it.  A path component like `a|b` is always read as the union of `a`
+/// and `b`, so a tag named that could never be reached on its own.
+pub fn check_tag_name(name: &str) -> STagResult<()> {
+    if name.contains(constants::UNION_TAG_SEPARATOR) {
+        return Err(STagError::BadTag(name.to_owned()));
+    }
+    Ok(())
+}
+
+/// Rejects any component of `path` that is too long to be a name.  Tag names end up as directory names, and file names
+/// grow a suffix when they collide, so letting an oversized one in only breaks things later, in the file manager.
+pub fn check_name_len(path: &Path) -> STagResult<()> {
+    for component in path.components() {
+        let name = component.as_os_str();
+        if name.len() > constants::NAME_MAX {
+            return Err(STagError::NameTooLong(name.to_string_lossy().to_string()));
+        }
+    }
+    Ok(())
+}
@@ -17,25 +17,30 @@
  */
 use std::path::Path;
 
-use rusqlite::Transaction;
+use rusqlite::{Connection, Transaction};
 
 use crate::common::err::{STagError, STagResult};
-use crate::common::fsops::WRAPPER_TAG;
+use crate::common::fsops::{check_name_len, journal, stored_tags, WRAPPER_TAG};
 use crate::common::notify::Notifier;
+use crate::common::settings::config::FileMoveMode;
 use crate::common::settings::Settings;
 use crate::common::types::file_perms::UMask;
-use crate::common::types::{TagCollectible, TagCollection, TagType};
-use crate::common::{get_filename, primary_tag};
+use crate::common::types::{DeviceFile, MergeResolution, TagCollectible, TagCollection, TagType};
+use crate::common::{display, get_filename};
 use crate::sql;
+use crate::sql::types::MergeCollision;
 use fuse_sys::{gid_t, uid_t};
-use log::{debug, error, info, warn};
+use tracing::{debug, error, info, warn};
 
 /// src and dst must be relative
 /// This function does way too much, but it's difficult to avoid.  Since the FUSE handler only sees move/rename calls
 /// (as opposed to the CLI, which can have different functions for merge, group, rename, etc), we must put all of the
 /// logic for merge, group, rename into here.  If we don't put it here, we have to have it in two separate places:
 /// the CLI entrypoint and the FUSE entrypoint.  I prefer a larger function over duplicated logic.
-pub fn move_or_merge<P: AsRef<Path>, Q: AsRef<Path>, N: Notifier>(
+///
+/// When merging one tag into another would give two different files the same name in the destination, `resolve` is
+/// asked how to settle each collision.
+pub fn move_or_merge<P, Q, N, R>(
     settings: &Settings,
     tx: &Transaction,
     src: P,
@@ -44,13 +49,29 @@ pub fn move_or_merge<P: AsRef<Path>, Q: AsRef<Path>, N: Notifier>(
     gid: gid_t,
     umask: &UMask,
     notifier: &N,
-) -> STagResult<()> {
+    resolve: R,
+) -> STagResult<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+    N: Notifier,
+    R: FnMut(&MergeCollision) -> STagResult<MergeResolution>,
+{
+    settings.check_write_gate()?;
     info!(
         target: WRAPPER_TAG,
         "Move or merge from {} to {}",
         src.as_ref().display(),
         dst.as_ref().display()
     );
+    check_name_len(dst.as_ref())?;
+    // renames and merges both go through here, and either can be undone
+    journal(
+        settings,
+        tx,
+        "mv",
+        &format!("{} -> {}", src.as_ref().display(), dst.as_ref().display()),
+    )?;
 
     // this ugly helper lambda will map a constraint violation to a STagError::PathExists error
     let map_rename = |e| {
@@ -68,10 +89,19 @@ pub fn move_or_merge<P: AsRef<Path>, Q: AsRef<Path>, N: Notifier>(
         }
     };
 
-    let src_tags = TagCollection::new(&settings, src.as_ref());
-    let dst_tags = TagCollection::new(&settings, dst.as_ref());
+    let src_tags = stored_tags(&settings, tx, src.as_ref())?;
+    let dst_tags = stored_tags(&settings, tx, dst.as_ref())?;
     let src_pt = src_tags.primary_type()?;
 
+    // if we're moving a file into a filedir, as opposed to just renaming it, these are the tags of that filedir
+    let dst_filedir = match (dst_tags.last(), dst_tags.primary_parent()) {
+        (Some(TagType::FileDir), _) => Some(dst_tags.as_slice()),
+        (_, Some(TagType::FileDir)) => Some(dst_tags.all_but_last().as_slice()),
+        _ => None,
+    };
+    // moving a file onto a filedir itself keeps the file's name
+    let keep_name = dst_tags.last() == Some(&TagType::FileDir);
+
     match src_pt {
         TagType::DeviceFileSymlink(device_file) => {
             info!(
@@ -80,10 +110,28 @@ pub fn move_or_merge<P: AsRef<Path>, Q: AsRef<Path>, N: Notifier>(
                 src.as_ref().display(),
                 dst.as_ref().display()
             );
-            let new_name = primary_tag(dst.as_ref(), settings.get_config().symbols.device_char)?
-                .ok_or(STagError::InvalidPath(dst.as_ref().to_owned()))?;
-            sql::rename_file(tx, &device_file, &new_name, sql::get_now_secs())
-                .map_err(map_rename)?;
+            let now = sql::get_now_secs();
+            if !keep_name {
+                // a fully-qualified destination name is renamed to the name inside it
+                let new_name = match settings.path_to_device_file(dst.as_ref())? {
+                    Some(dst_file) => dst_file.filename,
+                    None => get_filename(dst.as_ref())?.to_string(),
+                };
+                sql::rename_file(tx, &device_file, &new_name, now).map_err(map_rename)?;
+            }
+            if let Some(dst_filedir) = dst_filedir {
+                retag_moved_file(
+                    settings,
+                    tx,
+                    device_file,
+                    src_tags.all_but_last().as_slice(),
+                    dst_filedir,
+                    uid,
+                    gid,
+                    umask,
+                    now,
+                )?;
+            }
         }
         // this arm is very similar to DeviceFileSymlink arm, except we need to first derive a device file by finding
         // the file by the tags first.  it's slower because we don't already immediately have the device/inode combo
@@ -94,12 +142,33 @@ pub fn move_or_merge<P: AsRef<Path>, Q: AsRef<Path>, N: Notifier>(
                 src.as_ref().display(),
                 dst.as_ref().display()
             );
-            let new_name = get_filename(dst.as_ref())?;
             let now = sql::get_now_secs();
-            let maybe_tf =
-                sql::contains_file(tx, src_tags.as_slice(), |tf| &tf.primary_tag == primary_tag)?;
+            let maybe_tf = sql::contains_file(tx, src_tags.as_slice(), |tf| {
+                settings.display_matches(primary_tag, &tf.primary_tag)
+            })?;
             if let Some(tf) = maybe_tf {
-                sql::rename_file(tx, &tf.into(), &new_name, now).map_err(map_rename)?;
+                let device_file = DeviceFile::from(tf);
+                if !keep_name {
+                    let new_name = display::restore_name(
+                        &settings.get_config().display,
+                        get_filename(dst.as_ref())?,
+                        &device_file.filename,
+                    );
+                    sql::rename_file(tx, &device_file, &new_name, now).map_err(map_rename)?;
+                }
+                if let Some(dst_filedir) = dst_filedir {
+                    retag_moved_file(
+                        settings,
+                        tx,
+                        &device_file,
+                        src_tags.all_but_last().as_slice(),
+                        dst_filedir,
+                        uid,
+                        gid,
+                        umask,
+                        now,
+                    )?;
+                }
             } else {
                 return Err(STagError::InvalidPath(src.as_ref().into()));
             }
@@ -116,8 +185,9 @@ pub fn move_or_merge<P: AsRef<Path>, Q: AsRef<Path>, N: Notifier>(
                 src.as_ref().display(),
                 dst.as_ref().display()
             );
+            // the destination is left as it's spelled, so that a tag can be renamed to a different case
             let mut dst_tags = TagCollection::new(&settings, dst.as_ref());
-            let src_tags = TagCollection::new(&settings, src.as_ref());
+            let src_tags = stored_tags(&settings, tx, src.as_ref())?;
 
             // this happens often when a file browser is doing a move.  if you try to do mv /t1 to /t2, it will do a
             // mv /t1 to /t2/t1.  we can detect that and be smart with it
@@ -148,12 +218,22 @@ pub fn move_or_merge<P: AsRef<Path>, Q: AsRef<Path>, N: Notifier>(
                             new_name,
                             src_tag
                         );
+                        let dst_names = dst_tags.iter().collect_regular_names();
+                        let now = sql::get_now_secs();
+                        let keep = resolve_collisions(
+                            tx,
+                            src_tags.as_slice(),
+                            dst_names.as_slice(),
+                            resolve,
+                            now,
+                        )?;
                         sql::merge_tags(
                             tx,
                             src_tag,
                             src_tags.as_slice(),
-                            dst_tags.iter().collect_regular_names().as_slice(),
-                            sql::get_now_secs(),
+                            dst_names.as_slice(),
+                            keep.as_slice(),
+                            now,
                         )?;
                     }
                 }
@@ -191,7 +271,7 @@ pub fn move_or_merge<P: AsRef<Path>, Q: AsRef<Path>, N: Notifier>(
                             Ok(())
                         } else {
                             let _ = notifier.tag_to_tg(src_tag);
-                            Err(STagError::BadTagGroup(new_name.to_string()))
+                            Err(STagError::NotEmpty(src.as_ref().into()))
                         };
                     }
 
@@ -217,6 +297,7 @@ pub fn move_or_merge<P: AsRef<Path>, Q: AsRef<Path>, N: Notifier>(
                             sql::pin_tags(
                                 &tx,
                                 pinnable.as_slice(),
+                                &settings.get_config().groups,
                                 uid,
                                 gid,
                                 &umask.dir_perms(),
@@ -250,3 +331,141 @@ pub fn move_or_merge<P: AsRef<Path>, Q: AsRef<Path>, N: Notifier>(
 
     Ok(())
 }
+
+/// Finds the filename collisions that moving `src` to `dst` would cause, if it's a merge of one tag into another.  This
+/// lets a caller decide how to settle them before starting the transaction that does the move.  src and dst must be
+/// relative.
+pub fn merge_collisions<P: AsRef<Path>, Q: AsRef<Path>>(
+    settings: &Settings,
+    conn: &Connection,
+    src: P,
+    dst: Q,
+) -> STagResult<Vec<MergeCollision>> {
+    let src_tags = stored_tags(&settings, conn, src.as_ref())?;
+    let mut dst_tags = TagCollection::new(&settings, dst.as_ref());
+    if !matches!(src_tags.primary_type(), Ok(TagType::Regular(_))) {
+        return Ok(vec![]);
+    }
+
+    // the same mv /t1 /t2/t1 handling as `move_or_merge`
+    if src_tags.last().is_some() && src_tags.last() == dst_tags.last() {
+        dst_tags.pop();
+    }
+
+    match dst_tags.primary_type() {
+        Ok(TagType::Regular(new_name)) if sql::tag_exists(conn, new_name)? => {
+            Ok(sql::merge_collisions(
+                conn,
+                src_tags.as_slice(),
+                dst_tags.iter().collect_regular_names().as_slice(),
+            )?)
+        }
+        _ => Ok(vec![]),
+    }
+}
+
+/// Settles each filename collision that merging `src_tags` into `dst_tags` would cause, as chosen by `resolve`.  Returns
+/// the ids of the source files that should stay behind instead of being merged.
+pub(super) fn resolve_collisions<R>(
+    tx: &Transaction,
+    src_tags: &[TagType],
+    dst_tags: &[&str],
+    mut resolve: R,
+    now: f64,
+) -> STagResult<Vec<i64>>
+where
+    R: FnMut(&MergeCollision) -> STagResult<MergeResolution>,
+{
+    let mut keep = vec![];
+    for collision in sql::merge_collisions(tx, src_tags, dst_tags)? {
+        let resolution = match resolve(&collision)? {
+            MergeResolution::Newest if collision.source.mtime >= collision.dest.mtime => {
+                MergeResolution::Source
+            }
+            MergeResolution::Newest => MergeResolution::Dest,
+            other => other,
+        };
+        debug!(
+            target: WRAPPER_TAG,
+            "Resolving collision of {} with {:?}", collision.source.primary_tag, resolution
+        );
+
+        match resolution {
+            MergeResolution::Source => {
+                for tag in dst_tags {
+                    sql::unlink_file_from_tag(
+                        tx,
+                        collision.dest.device,
+                        collision.dest.inode,
+                        tag,
+                        now,
+                    )?;
+                }
+            }
+            MergeResolution::Dest => keep.push(collision.source.id),
+            MergeResolution::KeepBoth | MergeResolution::Newest => {}
+        }
+    }
+    Ok(keep)
+}
+
+/// Applies the tag changes of moving a file from the filedir described by `src_tags` to the one described by
+/// `dst_tags`.  The file always gains the destination's tags.  In `FileMoveMode::Move`, it also loses the source's
+/// tags that aren't in the destination, so `/a/⋂/x` to `/a/b/⋂/` adds `b`, while `/a/⋂/x` to `/c/⋂/` replaces `a`
+/// with `c`.  In `FileMoveMode::Additive`, no tags are ever removed.
+fn retag_moved_file(
+    settings: &Settings,
+    tx: &Transaction,
+    device_file: &DeviceFile,
+    src_tags: &[TagType],
+    dst_tags: &[TagType],
+    uid: uid_t,
+    gid: gid_t,
+    umask: &UMask,
+    now: f64,
+) -> STagResult<()> {
+    let resolve = |tags: &[TagType]| -> STagResult<Vec<String>> {
+        let mut names = vec![];
+        for tag in tags.iter().collect_regular_names() {
+            names.push(sql::resolve_alias(tx, tag)?);
+        }
+        Ok(names)
+    };
+    let src_names = resolve(src_tags)?;
+    let dst_names = resolve(dst_tags)?;
+
+    // a filedir with no regular tags above it, like a tag group's, can't say what the file should be tagged with
+    if dst_names.is_empty() {
+        return Ok(());
+    }
+
+    let config = settings.get_config();
+    let mode = config.rename.file_mode;
+    debug!(
+        target: WRAPPER_TAG,
+        "Retagging {} from {:?} to {:?} in {:?} mode", device_file, src_names, dst_names, mode
+    );
+
+    for tag in dst_names.iter().filter(|t| !src_names.contains(t)) {
+        let (auth_tag, _) =
+            sql::ensure_tag(tx, tag, &config.groups, uid, gid, &umask.dir_perms(), now)?;
+        sql::link_file_to_tag(
+            tx,
+            device_file.device,
+            device_file.inode,
+            &auth_tag,
+            uid,
+            gid,
+            &umask.file_perms(),
+            now,
+        )?;
+    }
+
+    if mode == FileMoveMode::Move {
+        for tag in src_names.iter().filter(|t| !dst_names.contains(t)) {
+            sql::unlink_file_from_tag(tx, device_file.device, device_file.inode, tag, now)?;
+        }
+    }
+
+    Ok(())
+}
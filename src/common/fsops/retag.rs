@@ -0,0 +1,166 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use rusqlite::Transaction;
+
+use crate::common::err::{STagError, STagResult};
+use crate::common::fsops::{check_tag_name, journal, stored_name, WRAPPER_TAG};
+use crate::common::settings::Settings;
+use crate::common::types::file_perms::UMask;
+use crate::common::types::TagType;
+use crate::sql;
+use crate::sql::types::TaggedFile;
+use fuse_sys::{gid_t, uid_t};
+use tracing::{debug, info};
+
+/// Splits a list of tags, one per line or comma separated, like a user would write into the tags xattr
+pub fn parse_tag_list(value: &str) -> Vec<&str> {
+    value
+        .split(|c| c == '\n' || c == ',')
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty())
+        .collect()
+}
+
+/// Makes `tags` the complete set of tags for `file`, linking it to the ones it's missing and unlinking it from the
+/// ones it shouldn't have.  The tags must be regular tags, and there must be at least one, since a file with no tags
+/// would vanish from the collection.  Returns the names of the tags that were (added, removed).
+pub fn retag(
+    settings: &Settings,
+    tx: &Transaction,
+    file: &TaggedFile,
+    tags: &[&str],
+    uid: uid_t,
+    gid: gid_t,
+    umask: &UMask,
+) -> STagResult<(Vec<String>, Vec<String>)> {
+    settings.check_write_gate()?;
+    info!(target: WRAPPER_TAG, "retag {:?} to {:?}", file.path, tags);
+
+    if tags.is_empty() {
+        return Err(STagError::BadTag("".to_string()));
+    }
+
+    let mut wanted = vec![];
+    for tag in settings.query_to_tags(tags)? {
+        match tag {
+            TagType::Regular(name) => {
+                check_tag_name(&name)?;
+                let name = stored_name(settings, tx, &sql::resolve_alias(tx, &name)?)?;
+                if !wanted.contains(&name) {
+                    wanted.push(name);
+                }
+            }
+            // every file already has the collection's tag, without being linked to it
+            TagType::CollectionTag(_) => {}
+            other => return Err(STagError::BadTag(other.to_string())),
+        }
+    }
+
+    let current = sql::tags_for_file(tx, file.id)?;
+    let now = sql::get_now_secs();
+    let config = settings.get_config();
+
+    let mut added = vec![];
+    for tag in wanted.iter().filter(|t| !current.contains(t)) {
+        let (auth_tag, _) =
+            sql::ensure_tag(tx, tag, &config.groups, uid, gid, &umask.dir_perms(), now)?;
+        sql::link_file_to_tag(
+            tx,
+            file.device,
+            file.inode,
+            &auth_tag,
+            uid,
+            gid,
+            &umask.file_perms(),
+            now,
+        )?;
+        added.push(auth_tag);
+    }
+
+    let mut removed = vec![];
+    for tag in current.into_iter().filter(|t| !wanted.contains(t)) {
+        if sql::unlink_file_from_tag(tx, file.device, file.inode, &tag, now)? {
+            removed.push(tag);
+        }
+    }
+
+    debug!(
+        target: WRAPPER_TAG,
+        "Retagged {:?}, added {:?}, removed {:?}", file.path, added, removed
+    );
+    Ok((added, removed))
+}
+
+/// Takes `file` out of the tags `from` and puts it in the tags `to`, leaving the rest of its tags alone, so that it's
+/// never seen with only half of the change made.  It must have every tag in `from`, and must be left with at least
+/// one tag.  Returns the names of the tags that were (added, removed).
+pub fn retag_from_to(
+    settings: &Settings,
+    tx: &Transaction,
+    file: &TaggedFile,
+    from: &[&str],
+    to: &[&str],
+    uid: uid_t,
+    gid: gid_t,
+    umask: &UMask,
+) -> STagResult<(Vec<String>, Vec<String>)> {
+    settings.check_write_gate()?;
+    info!(target: WRAPPER_TAG, "retag {:?} from {:?} to {:?}", file.path, from, to);
+    journal(
+        settings,
+        tx,
+        "retag",
+        &format!("{}: {} -> {}", file.path, from.join(","), to.join(",")),
+    )?;
+
+    let current = sql::tags_for_file(tx, file.id)?;
+    let mut dropped = vec![];
+    for tag in settings.query_to_tags(from)? {
+        match tag {
+            TagType::Regular(name) => {
+                let name = stored_name(settings, tx, &sql::resolve_alias(tx, &name)?)?;
+                if !current.contains(&name) {
+                    return Err(STagError::BadTag(name));
+                }
+                dropped.push(name);
+            }
+            other => return Err(STagError::BadTag(other.to_string())),
+        }
+    }
+
+    let mut wanted: Vec<&str> = current
+        .iter()
+        .filter(|tag| !dropped.contains(tag))
+        .map(String::as_str)
+        .collect();
+    wanted.extend_from_slice(to);
+    retag(settings, tx, file, &wanted, uid, gid, umask)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_tag_list() {
+        assert_eq!(parse_tag_list("a\nb"), vec!["a", "b"]);
+        assert_eq!(parse_tag_list("a, b ,c,"), vec!["a", "b", "c"]);
+        assert_eq!(parse_tag_list(" a\n\n, b\n"), vec!["a", "b"]);
+        assert!(parse_tag_list(" \n, ").is_empty());
+    }
+}
@@ -20,17 +20,19 @@ use std::path::Path;
 use rusqlite::Transaction;
 
 use crate::common::err::{STagError, STagResult};
-use crate::common::fsops::WRAPPER_TAG;
+use crate::common::fsops::{journal, stored_tags, WRAPPER_TAG};
 use crate::common::settings::Settings;
-use crate::common::types::{TagCollectible, TagCollection, TagType};
+use crate::common::types::{DeviceFile, TagCollectible, TagType};
 use crate::sql;
-use log::info;
+use tracing::info;
 
 /// `file` must be relative to the collection, not an absolute path
 pub fn rm(settings: &Settings, tx: &Transaction, file: &Path) -> STagResult<Vec<i64>> {
+    settings.check_write_gate()?;
     info!(target: WRAPPER_TAG, "rm {:?}", file);
+    journal(settings, tx, "rm", &file.to_string_lossy())?;
 
-    let tags = TagCollection::new(settings, file);
+    let tags = stored_tags(settings, tx, file)?;
     let now = sql::get_now_secs();
 
     match tags.primary_type()? {
@@ -45,10 +47,26 @@ pub fn rm(settings: &Settings, tx: &Transaction, file: &Path) -> STagResult<Vec<
             Ok(removed)
         }
         TagType::Symlink(filename) => {
+            // the filename may be a transformed display name, so resolve it back to the real name
+            let real_name = match sql::contains_file(tx, tags.all_but_last().as_slice(), |tf| {
+                settings.display_matches(filename, &tf.primary_tag)
+            })? {
+                Some(tf) => tf.primary_tag,
+                None => filename.to_owned(),
+            };
             let last_tag = tags.iter().collect_regular().last().unwrap().to_owned();
-            let removed = sql::remove_links(tx, filename, &[last_tag], now)?;
+            let removed = sql::remove_links(tx, &real_name, &[last_tag], now)?;
             Ok(removed)
         }
         _ => Err(STagError::InvalidPath(file.into())),
     }
 }
+
+/// Removes a file that has no tags left from the collection entirely
+pub fn purge_untagged(settings: &Settings, tx: &Transaction, file: &DeviceFile) -> STagResult<()> {
+    settings.check_write_gate()?;
+    info!(target: WRAPPER_TAG, "Purging untagged file {:?}", file);
+    journal(settings, tx, "purge", &file.filename)?;
+    sql::purge_devicefile(tx, file, sql::get_now_secs())?;
+    Ok(())
+}
@@ -21,17 +21,19 @@ use std::path::Path;
 use rusqlite::Transaction;
 
 use crate::common::err::{STagError, STagResult};
-use crate::common::fsops::WRAPPER_TAG;
+use crate::common::fsops::{journal, stored_tags, WRAPPER_TAG};
 use crate::common::settings::Settings;
-use crate::common::types::{TagCollectible, TagCollection, TagType};
+use crate::common::types::{TagCollectible, TagType};
 use crate::sql;
-use log::{debug, info};
+use tracing::{debug, info};
 
 /// `path` must be relative to the mountpoint!
 pub fn rmdir(settings: &Settings, tx: &Transaction, path: &Path) -> STagResult<()> {
+    settings.check_write_gate()?;
     info!(target: WRAPPER_TAG, "rmdir {:?}", path);
+    journal(settings, tx, "rmdir", &path.to_string_lossy())?;
 
-    let tags = TagCollection::new(settings, path);
+    let tags = stored_tags(settings, tx, path)?;
     let pt = tags.primary_type()?;
     let now = sql::get_now_secs();
 
@@ -43,12 +45,17 @@ pub fn rmdir(settings: &Settings, tx: &Transaction, path: &Path) -> STagResult<(
             );
 
             let parts = tags.iter().collect_tags_and_groups();
-            match parts.len() {
-                0 => Err(STagError::InvalidPath(path.into())),
-                1 => {
+            match parts.as_slice() {
+                [] => Err(STagError::InvalidPath(path.into())),
+                [_] => {
                     sql::remove_taggroup(tx, group)?;
                     Ok(())
                 }
+                // a tag group right under another one is only taken out of it
+                [.., TagType::Group(parent), _] => {
+                    sql::remove_group_from_group(tx, group, parent, now)?;
+                    Ok(())
+                }
                 _ => {
                     sql::remove_taggroup_from_itersection(tx, group, tags.as_slice())?;
                     Ok(())
@@ -77,7 +84,7 @@ pub fn rmdir(settings: &Settings, tx: &Transaction, path: &Path) -> STagResult<(
                         "Removing {} from the intersection of {:?}", tag, intersect
                     );
                     let removed =
-                        sql::remove_tag_from_intersection(tx, tag, intersect.as_slice(), now)?;
+                        sql::remove_tag_from_intersection(tx, tag, intersect.as_slice(), &[], now)?;
                     debug!(
                         target: WRAPPER_TAG,
                         "Removed {} file associations",
@@ -0,0 +1,80 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::path::Path;
+
+use rusqlite::Transaction;
+
+use crate::common::err::{STagError, STagResult};
+use crate::common::fsops::{journal, stored_tags, WRAPPER_TAG};
+use crate::common::settings::Settings;
+use crate::common::types::TagType;
+use crate::sql;
+use tracing::info;
+
+/// Exchanges the names of the tags or tag groups at `a` and `b`, which would otherwise take three renames and a spare
+/// name.  Only the last component of each path is swapped, and both must already exist and be the same kind of
+/// directory.  Each keeps its files, groups and pins, which end up under the other's name.
+pub fn swap<P, Q>(settings: &Settings, tx: &Transaction, a: P, b: Q) -> STagResult<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    settings.check_write_gate()?;
+    let (a, b) = (a.as_ref(), b.as_ref());
+    info!(target: WRAPPER_TAG, "Swapping {} and {}", a.display(), b.display());
+    journal(
+        settings,
+        tx,
+        "swap",
+        &format!("{} <-> {}", a.display(), b.display()),
+    )?;
+
+    let a_tags = stored_tags(settings, tx, a)?;
+    let b_tags = stored_tags(settings, tx, b)?;
+    let now = sql::get_now_secs();
+    match (a_tags.primary_type()?, b_tags.primary_type()?) {
+        (TagType::Regular(a_tag), TagType::Regular(b_tag)) => {
+            let a_tag = sql::resolve_alias(tx, a_tag)?;
+            let b_tag = sql::resolve_alias(tx, b_tag)?;
+            for tag in &[&a_tag, &b_tag] {
+                if !sql::tag_exists(tx, tag)? {
+                    return Err(STagError::BadTag(tag.to_string()));
+                }
+            }
+            if a_tag != b_tag {
+                sql::swap_tags(tx, &a_tag, &b_tag, now)?;
+            }
+            Ok(())
+        }
+        (TagType::Group(a_group), TagType::Group(b_group)) => {
+            for group in &[a_group, b_group] {
+                if !sql::tag_group_exists(tx, group)? {
+                    return Err(STagError::BadTagGroup(group.to_string()));
+                }
+            }
+            if a_group != b_group {
+                sql::swap_tag_groups(tx, a_group, b_group, now)?;
+            }
+            Ok(())
+        }
+        (TagType::Group(_), _) => Err(STagError::BadTagGroup(b.display().to_string())),
+        (TagType::Regular(_), _) => Err(STagError::BadTag(b.display().to_string())),
+        _ => Err(STagError::InvalidPath(a.to_owned())),
+    }
+}
@@ -0,0 +1,156 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use std::collections::HashMap;
+use std::path::Path;
+
+use rusqlite::Transaction;
+
+use crate::common::archive::PathRemaps;
+use crate::common::err::{STagError, STagResult};
+use crate::common::fsops::{journal, retag, WRAPPER_TAG};
+use crate::common::settings::Settings;
+use crate::common::sync::{SyncState, SyncSummary};
+use crate::common::types::file_perms::UMask;
+use crate::common::types::DeviceFile;
+use crate::sql;
+use crate::sql::sync::SyncFile;
+use crate::sql::types::NewFile;
+use fuse_sys::{gid_t, uid_t};
+use tracing::{debug, info};
+
+/// Brings our collection up to date with `theirs`, another machine's copy of it, file by file.  `ours` is our own
+/// state from before anything was applied.  Their files are found here at their path, after `remaps`, or else by
+/// their contents, and whichever side changed a file's tags last wins.  Files that they tagged but that don't exist
+/// on this machine are skipped, and files that they removed are removed here, unless we've tagged them since.
+pub fn sync_from(
+    settings: &Settings,
+    tx: &Transaction,
+    ours: &SyncState,
+    theirs: &SyncState,
+    remaps: &PathRemaps,
+    uid: uid_t,
+    gid: gid_t,
+    umask: &UMask,
+) -> STagResult<SyncSummary> {
+    settings.check_write_gate()?;
+    info!(
+        target: WRAPPER_TAG,
+        "Syncing from {} files and {} removals",
+        theirs.files.len(),
+        theirs.tombstones.len()
+    );
+    journal(
+        settings,
+        tx,
+        "sync",
+        &format!("{} files", theirs.files.len()),
+    )?;
+
+    let by_path: HashMap<&str, &SyncFile> = ours
+        .files
+        .iter()
+        .map(|file| (file.path.as_str(), file))
+        .collect();
+    let mut by_hash: HashMap<&str, Vec<&SyncFile>> = HashMap::new();
+    for file in &ours.files {
+        if let Some(hash) = &file.content_hash {
+            by_hash.entry(hash).or_default().push(file);
+        }
+    }
+    let removed: HashMap<&str, f64> = ours
+        .tombstones
+        .iter()
+        .map(|tombstone| (tombstone.path.as_str(), tombstone.ts))
+        .collect();
+
+    let now = sql::get_now_secs();
+    let mut summary = SyncSummary::default();
+    let mut new_files = vec![];
+    for their in &theirs.files {
+        let path = remaps.apply(Path::new(&their.path));
+        let path_str = path
+            .to_str()
+            .ok_or_else(|| STagError::InvalidPath(path.clone()))?;
+        // a file that was moved on one side is still the same file, as long as its contents are unambiguous
+        let matched = by_path.get(path_str).copied().or_else(|| {
+            match by_hash.get(their.content_hash.as_deref()?)?.as_slice() {
+                [file] => Some(*file),
+                _ => None,
+            }
+        });
+
+        match matched {
+            Some(our) if our.tags != their.tags && their.tags_mtime > our.tags_mtime => {
+                debug!(target: WRAPPER_TAG, "Retagging {} to {:?}", our.path, their.tags);
+                if their.tags.is_empty() {
+                    for tag in &our.tags {
+                        sql::unlink_file_from_tag(tx, our.device, our.inode, tag, now)?;
+                    }
+                } else {
+                    let file = sql::get_file(tx, our.id)?
+                        .ok_or_else(|| STagError::InvalidPath(path.clone()))?;
+                    let tags: Vec<&str> = their.tags.iter().map(String::as_str).collect();
+                    retag(settings, tx, &file, &tags, uid, gid, umask)?;
+                }
+                summary.retagged += 1;
+                summary
+                    .tags
+                    .extend(our.tags.iter().chain(&their.tags).cloned());
+            }
+            Some(_) => {}
+            // we removed it after they last tagged it, so they'll be removing it too
+            None if removed
+                .get(path_str)
+                .map_or(false, |ts| *ts >= their.tags_mtime) => {}
+            None if their.tags.is_empty() => {}
+            None if !path.exists() => summary.missing += 1,
+            None => {
+                let (device, inode) = settings.file_identity(&path)?;
+                new_files.push(NewFile {
+                    device,
+                    inode,
+                    path: path_str.to_string(),
+                    primary_tag: their.primary_tag.clone(),
+                    tags: their.tags.clone(),
+                });
+                summary.tags.extend(their.tags.iter().cloned());
+            }
+        }
+    }
+    summary.added = new_files.len();
+    if !new_files.is_empty() {
+        let groups = settings.get_config().groups;
+        sql::add_files(tx, &new_files, &groups, uid, gid, umask, now)?;
+    }
+
+    for tombstone in &theirs.tombstones {
+        let path = remaps.apply(Path::new(&tombstone.path));
+        let our = match path.to_str().and_then(|path| by_path.get(path)) {
+            Some(our) if our.tags_mtime < tombstone.ts => our,
+            _ => continue,
+        };
+        debug!(target: WRAPPER_TAG, "Removing {}", our.path);
+        let df = DeviceFile::new(&our.primary_tag, our.device, our.inode);
+        sql::purge_devicefile(tx, &df, now)?;
+        summary.removed += 1;
+        summary.tags.extend(our.tags.iter().cloned());
+    }
+
+    debug!(target: WRAPPER_TAG, "Synced {:?}", summary);
+    Ok(summary)
+}
@@ -0,0 +1,160 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Identifies tagged files by their path and contents, instead of by device/inode.  The device/inode of a file on a
+//! network share isn't reliable: NFS and SMB can hand out different numbers for the same file across remounts, or
+//! the same numbers for different files.  A path-identity file is stored under a reserved device, with an inode
+//! derived from its canonical path, so everything that keys on device/inode keeps working.  Because that identity
+//! changes when the file moves, its content hash is recorded too, and is used to find it again.
+
+use crate::common::dupes;
+use crate::common::err::{STagError, STagResult};
+use crate::sql;
+use rusqlite::{Connection, Transaction};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use tracing::info;
+
+const TAG: &str = "identity";
+
+/// The device that path-identity files are stored under.  Real device numbers are nowhere near this.
+pub const PATH_DEVICE: u64 = i64::MAX as u64;
+
+pub fn is_path_identity(device: u64) -> bool {
+    device == PATH_DEVICE
+}
+
+/// The device/inode for the file at `canonical`, which must already be canonicalized, or the same file could get
+/// different identities
+pub fn path_identity(canonical: &Path) -> (u64, u64) {
+    let hash = blake3::hash(canonical.as_os_str().as_bytes());
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&hash.as_bytes()[..8]);
+    // sqlite stores these as signed, so keep the inode positive
+    (PATH_DEVICE, u64::from_le_bytes(bytes) & i64::MAX as u64)
+}
+
+/// Finds the tagged file for the real file at `path`, which has the identity `device`/`inode`.  A path-identity file
+/// that isn't found by its identity may have been tagged at a path that canonicalized differently, or may have moved
+/// since, so it's also looked for by its recorded path and then by its contents.
+pub fn find_file(
+    conn: &Connection,
+    device: u64,
+    inode: u64,
+    path: &Path,
+) -> STagResult<Option<i64>> {
+    if let Some(id) = sql::get_file_id(conn, device, inode)? {
+        return Ok(Some(id));
+    }
+    if !is_path_identity(device) {
+        return Ok(None);
+    }
+    let path_str = path
+        .to_str()
+        .ok_or_else(|| STagError::InvalidPath(path.to_owned()))?;
+    if let Some(id) = sql::get_file_id_by_path(conn, path_str)? {
+        return Ok(Some(id));
+    }
+    moved_from(conn, &dupes::hash_file(path)?)
+}
+
+/// The path-identity file with the contents `hash` whose recorded path no longer exists, which is what a file that
+/// was moved on the share looks like.  If there's more than one, we can't tell which it was, so there's none.
+fn moved_from(conn: &Connection, hash: &str) -> STagResult<Option<i64>> {
+    let gone: Vec<_> = sql::get_files_with_hash(conn, PATH_DEVICE, hash)?
+        .into_iter()
+        .filter(|file| !Path::new(&file.path).exists())
+        .collect();
+    match gone.as_slice() {
+        [file] => Ok(Some(file.id)),
+        _ => Ok(None),
+    }
+}
+
+/// Readies the path-identity file at `path` for being linked as `device`/`inode`.  If it isn't tagged under that
+/// identity, but was tagged before it moved, the old entry is pointed at it so that it keeps its tags.  Returns the
+/// hash of its contents, for recording once it's linked.
+pub fn adopt_moved(
+    tx: &Transaction,
+    device: u64,
+    inode: u64,
+    path: &Path,
+    now: f64,
+) -> STagResult<String> {
+    let hash = dupes::hash_file(path)?;
+    if sql::get_file_id(tx, device, inode)?.is_none() {
+        if let Some(id) = moved_from(tx, &hash)? {
+            info!(target: TAG, "{:?} was moved, adopting tagged file {}", path, id);
+            sql::relocate_file(tx, id, device, inode, &path.to_string_lossy(), now)?;
+        }
+    }
+    Ok(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::{params, NO_PARAMS};
+
+    #[test]
+    fn test_path_identity() {
+        let (device, inode) = path_identity(Path::new("/mnt/share/a.txt"));
+        assert!(is_path_identity(device));
+        assert!(inode <= i64::MAX as u64);
+        assert_eq!(
+            path_identity(Path::new("/mnt/share/a.txt")),
+            (device, inode)
+        );
+        assert_ne!(path_identity(Path::new("/mnt/share/b.txt")).1, inode);
+    }
+
+    #[test]
+    fn test_find_moved_file() -> STagResult<()> {
+        let root = tempfile::tempdir()?;
+        let old = root.path().join("a.txt");
+        let new = root.path().join("b.txt");
+        std::fs::write(&new, b"12345")?;
+        let hash = dupes::hash_file(&new)?;
+
+        let mut conn = Connection::open_in_memory()?;
+        sql::migrations::migrate(&mut conn, &crate::common::version_str())?;
+        let (device, inode) = path_identity(&old);
+        conn.execute(
+            "INSERT INTO files (id, device, inode, path, primary_tag, ts, mtime, content_hash)
+            VALUES (1, ?1, ?2, ?3, 'a.txt', 0, 0, ?4)",
+            params![device as i64, inode as i64, old.to_str().unwrap(), hash],
+        )?;
+
+        let (device, inode) = path_identity(&new);
+        assert_eq!(
+            find_file(&conn, device, inode, &new)?,
+            Some(1),
+            "found by its contents"
+        );
+
+        std::fs::write(&old, b"12345")?;
+        assert_eq!(
+            find_file(&conn, device, inode, &new)?,
+            None,
+            "the old path is still there"
+        );
+        conn.execute("DELETE FROM files", NO_PARAMS)?;
+        assert_eq!(find_file(&conn, device, inode, &new)?, None);
+        Ok(())
+    }
+}
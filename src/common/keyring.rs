@@ -0,0 +1,72 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+//! Keys for encrypted collection databases
+
+use crate::common::constants;
+use crate::platform;
+use nix::sys::termios::{tcgetattr, tcsetattr, LocalFlags, SetArg};
+use std::error::Error;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::io::AsRawFd;
+use tracing::debug;
+
+const TAG: &str = "keyring";
+
+/// The key for `col`'s database, from the system keyring if it's stored there, otherwise asked for on the terminal
+pub fn collection_key(col: &str) -> Result<String, Box<dyn Error>> {
+    if let Some(key) = platform::keyring_secret(constants::KEYRING_SERVICE, col) {
+        debug!(target: TAG, "Found the key for {} in the keyring", col);
+        return Ok(key);
+    }
+    prompt_key(col)
+}
+
+/// Asks for `col`'s key on the controlling terminal, without echoing it
+fn prompt_key(col: &str) -> Result<String, Box<dyn Error>> {
+    let tty = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .map_err(|_| {
+            format!(
+                "The key for {} isn't in the keyring, and there's no terminal to ask for it on",
+                col
+            )
+        })?;
+    let fd = tty.as_raw_fd();
+    let echoing = tcgetattr(fd)?;
+    let mut quiet = echoing.clone();
+    quiet.local_flags.remove(LocalFlags::ECHO);
+
+    let mut out = &tty;
+    write!(out, "Key for collection {}: ", col)?;
+    out.flush()?;
+    tcsetattr(fd, SetArg::TCSANOW, &quiet)?;
+    let mut key = String::new();
+    let read = BufReader::new(&tty).read_line(&mut key);
+    // echo has to come back even if the read failed
+    tcsetattr(fd, SetArg::TCSANOW, &echoing)?;
+    writeln!(out)?;
+    read?;
+
+    let key = key.trim_end_matches(&['\r', '\n'][..]);
+    if key.is_empty() {
+        return Err(format!("No key was given for {}", col).into());
+    }
+    Ok(key.to_string())
+}
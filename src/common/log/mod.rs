@@ -20,11 +20,15 @@ use log::{Metadata, Record};
 use parking_lot::Mutex;
 use std::cell::RefCell;
 use std::collections::VecDeque;
+use std::error::Error;
 use std::fs::{File, OpenOptions};
 use std::io::prelude::*;
 use std::io::BufWriter;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::fmt::format::FmtSpan;
 
 pub static REQ_COUNTER: AtomicUsize = AtomicUsize::new(0);
 thread_local!(pub static REQUEST_ID: RefCell<usize> = RefCell::new(0));
@@ -153,6 +157,20 @@ impl log::Log for RotatingLogger {
     }
 
     fn log(&self, record: &Record) {
+        self.append(format!("{}\n", record.args()).as_bytes());
+    }
+
+    fn flush(&self) {
+        let mut state = self.state.lock();
+        if let Err(e) = state.stream.flush() {
+            eprintln!("Couldn't flush log: {:?}", e);
+        }
+    }
+}
+
+impl RotatingLogger {
+    /// Writes already formatted lines to the current log file, rotating to a new one first if it's time
+    pub fn append(&self, buf: &[u8]) {
         let count = self.counter.fetch_add(1, Ordering::Relaxed);
         let mut state = self.state.lock();
         if count % self.rotate_check == 0 {
@@ -169,7 +187,7 @@ impl log::Log for RotatingLogger {
                 };
             }
         }
-        if let Err(e) = write!(state.stream, "{}\n", record.args()) {
+        if let Err(e) = state.stream.write_all(buf) {
             eprintln!("Couldn't write record to stream: {:?}", e);
         }
 
@@ -177,12 +195,33 @@ impl log::Log for RotatingLogger {
             eprintln!("Couldn't flush log: {:?}", e);
         }
     }
+}
 
-    fn flush(&self) {
-        let mut state = self.state.lock();
-        if let Err(e) = state.stream.flush() {
-            eprintln!("Couldn't flush log: {:?}", e);
+/// Where the json log goes, which is the same places that the plain log would go
+pub struct JsonOutputs {
+    pub logs: Vec<RotatingLogger>,
+    pub stdout: bool,
+}
+
+/// tracing-subscriber asks for a new writer for every line it writes, so this is the cheap handle it gets
+struct JsonWriter(Arc<JsonOutputs>);
+
+impl Write for JsonWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        for log in &self.0.logs {
+            log.append(buf);
         }
+        if self.0.stdout {
+            std::io::stdout().write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.0.stdout {
+            std::io::stdout().flush()?;
+        }
+        Ok(())
     }
 }
 
@@ -214,3 +253,33 @@ pub fn setup_logger(
 
     Ok(())
 }
+
+/// Sets up a log of one json object per line instead of the plain one.  Each fuse callback gets its own line when it
+/// finishes, with the request id, uid, path, duration and result code of its span, and the lines logged during the
+/// callback carry the span too, so that they can be grouped after ingestion.
+pub fn setup_json_logger(
+    level: log::LevelFilter,
+    outputs: JsonOutputs,
+) -> Result<(), Box<dyn Error>> {
+    let max_level = match level {
+        log::LevelFilter::Off => LevelFilter::OFF,
+        log::LevelFilter::Error => LevelFilter::ERROR,
+        log::LevelFilter::Warn => LevelFilter::WARN,
+        log::LevelFilter::Info => LevelFilter::INFO,
+        log::LevelFilter::Debug => LevelFilter::DEBUG,
+        log::LevelFilter::Trace => LevelFilter::TRACE,
+    };
+    let outputs = Arc::new(outputs);
+
+    // this also routes the few places still using the log crate through tracing
+    tracing_subscriber::fmt()
+        .json()
+        .with_current_span(true)
+        .with_span_events(FmtSpan::CLOSE)
+        .with_max_level(max_level)
+        .with_writer(move || JsonWriter(outputs.clone()))
+        .try_init()
+        .map_err(|e| e.to_string())?;
+
+    Ok(())
+}
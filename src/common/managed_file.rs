@@ -16,9 +16,73 @@
  * along with this program.  If not, see <http://www.gnu.org/licenses/>.
  */
 
+use crate::common::dupes;
+use crate::sql::types::ManagedRecord;
 use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
 
+/// How a managed file compares with the checksum that was recorded when it was linked
+#[derive(Debug, Clone, PartialEq)]
+pub enum Integrity {
+    Intact,
+    Missing,
+    Corrupt {
+        expected: String,
+        actual: String,
+    },
+    /// The file was linked before checksums were recorded, so there's nothing to compare it with
+    Unrecorded {
+        actual: String,
+    },
+    /// The file exists but couldn't be read
+    Unreadable(String),
+}
+
+impl Integrity {
+    /// Whether the managed file is gone or damaged
+    pub fn failed(&self) -> bool {
+        matches!(
+            self,
+            Integrity::Missing | Integrity::Corrupt { .. } | Integrity::Unreadable(_)
+        )
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Integrity::Intact => "ok",
+            Integrity::Missing => "missing",
+            Integrity::Corrupt { .. } => "corrupt",
+            Integrity::Unrecorded { .. } => "unrecorded",
+            Integrity::Unreadable(_) => "unreadable",
+        }
+    }
+}
+
+/// The checksum recorded for a managed file, which is the same hash that duplicates are found with
+pub fn checksum(path: &Path) -> std::io::Result<String> {
+    dupes::hash_file(path)
+}
+
+/// Re-hashes the managed file of `record` and compares it with its recorded checksum
+pub fn verify(record: &ManagedRecord) -> Integrity {
+    let path = Path::new(&record.alias_file);
+    if !path.exists() {
+        return Integrity::Missing;
+    }
+    let actual = match checksum(path) {
+        Ok(actual) => actual,
+        Err(e) => return Integrity::Unreadable(e.to_string()),
+    };
+    match &record.checksum {
+        Some(expected) if *expected == actual => Integrity::Intact,
+        Some(expected) => Integrity::Corrupt {
+            expected: expected.clone(),
+            actual,
+        },
+        None => Integrity::Unrecorded { actual },
+    }
+}
+
 /// Converts some original path into a unlikely-to-collide subdirectory path, based on chunks of
 /// a hash of the original path
 pub fn subdir_path<P: AsRef<Path>>(orig_path: P) -> (PathBuf, String) {
@@ -56,4 +120,39 @@ mod tests {
         );
         assert_eq!(hash, "882a46063fa07f5a062ce07557408b7b");
     }
+
+    #[test]
+    fn test_verify() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let alias_file = dir.path().join("alias");
+        std::fs::write(&alias_file, b"alias data")?;
+        let recorded = checksum(&alias_file)?;
+
+        let record = |alias_file: &Path, checksum: Option<&str>| ManagedRecord {
+            id: 1,
+            path: "/real/file".to_string(),
+            alias_file: alias_file.to_string_lossy().to_string(),
+            checksum: checksum.map(str::to_string),
+        };
+        assert_eq!(
+            verify(&record(&alias_file, Some(&recorded))),
+            Integrity::Intact
+        );
+        assert_eq!(
+            verify(&record(&alias_file, None)),
+            Integrity::Unrecorded {
+                actual: recorded.clone()
+            }
+        );
+        assert_eq!(
+            verify(&record(&dir.path().join("gone"), Some(&recorded))),
+            Integrity::Missing
+        );
+
+        std::fs::write(&alias_file, b"alias dat4")?;
+        let corrupt = verify(&record(&alias_file, Some(&recorded)));
+        assert!(corrupt.failed());
+        assert_eq!(corrupt.name(), "corrupt");
+        Ok(())
+    }
 }
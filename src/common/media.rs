@@ -0,0 +1,214 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Tags for media files, taken from the metadata inside them as they're linked: the camera a photo was taken with and
+//! the month it was taken in, from its EXIF data, and a song's artist and album, from its ID3 tags.  Reading the
+//! metadata needs the `media-tags` feature, and the `[media]` config turns it on and sets the tags' prefixes.
+
+use crate::common::settings::config::Media;
+use std::path::Path;
+use tracing::debug;
+
+const MEDIA_TAG: &str = "media";
+
+/// Extensions that can carry EXIF data
+const IMAGE_EXTENSIONS: &[&str] = &["jpg", "jpeg", "tif", "tiff", "heic", "heif", "png", "webp"];
+/// Extensions that can carry ID3 tags
+const AUDIO_EXTENSIONS: &[&str] = &["mp3"];
+
+/// The tags that the metadata inside the file at `path` gives it, if media tagging is enabled.  Files that can't be
+/// read, or that have no metadata, get no tags.
+pub fn tags_for(conf: &Media, path: &Path) -> Vec<String> {
+    if !conf.enabled {
+        return vec![];
+    }
+
+    let ext = match path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) => ext.to_lowercase(),
+        None => return vec![],
+    };
+    let found = if IMAGE_EXTENSIONS.contains(&ext.as_str()) {
+        read::image_tags(conf, path)
+    } else if AUDIO_EXTENSIONS.contains(&ext.as_str()) {
+        read::audio_tags(conf, path)
+    } else {
+        return vec![];
+    };
+
+    match found {
+        Ok(tags) => tags,
+        Err(e) => {
+            debug!(target: MEDIA_TAG, "Couldn't read the metadata of {:?}: {}", path, e);
+            vec![]
+        }
+    }
+}
+
+/// A metadata value that can be put in a tag name, with its whitespace collapsed and without path separators
+fn clean_value(value: &str) -> Option<String> {
+    let cleaned = value
+        .replace(std::path::MAIN_SEPARATOR, "-")
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+    if cleaned.is_empty() {
+        None
+    } else {
+        Some(cleaned)
+    }
+}
+
+/// The name of a camera, from its EXIF make and model, ie `fujifilm-x100v`.  Many models already start with the make,
+/// like `Canon EOS 5D`, so it isn't repeated.
+fn camera_name(make: Option<&str>, model: Option<&str>) -> Option<String> {
+    let make = make.and_then(clean_value).map(|m| m.to_lowercase());
+    let model = model.and_then(clean_value).map(|m| m.to_lowercase());
+    let name = match (make, model) {
+        (Some(make), Some(model)) if model.starts_with(&make) => model,
+        (Some(make), Some(model)) => format!("{} {}", make, model),
+        (make, model) => make.or(model)?,
+    };
+    Some(name.replace(' ', "-"))
+}
+
+/// The month of an EXIF date like `2021:07:15 12:00:00`, ie `2021-07`
+fn taken_month(datetime: &str) -> Option<String> {
+    let mut parts = datetime.trim().splitn(3, ':');
+    let year = parts
+        .next()
+        .filter(|y| y.len() == 4 && y.chars().all(|c| c.is_ascii_digit()))?;
+    let month = parts
+        .next()
+        .filter(|m| m.len() == 2 && m.chars().all(|c| c.is_ascii_digit()))?;
+    // cameras without a set clock write zeros
+    if year == "0000" || month == "00" {
+        return None;
+    }
+    Some(format!("{}-{}", year, month))
+}
+
+#[cfg(feature = "media-tags")]
+mod read {
+    use super::{camera_name, clean_value, taken_month};
+    use crate::common::settings::config::Media;
+    use std::error::Error;
+    use std::path::Path;
+
+    fn ascii_field(exif: &exif::Exif, tag: exif::Tag) -> Option<String> {
+        match &exif.get_field(tag, exif::In::PRIMARY)?.value {
+            exif::Value::Ascii(values) => values.first().map(|value| {
+                String::from_utf8_lossy(value)
+                    .trim_end_matches('\0')
+                    .to_string()
+            }),
+            _ => None,
+        }
+    }
+
+    pub(super) fn image_tags(conf: &Media, path: &Path) -> Result<Vec<String>, Box<dyn Error>> {
+        let file = std::fs::File::open(path)?;
+        let exif = exif::Reader::new().read_from_container(&mut std::io::BufReader::new(file))?;
+
+        let mut tags = vec![];
+        let make = ascii_field(&exif, exif::Tag::Make);
+        let model = ascii_field(&exif, exif::Tag::Model);
+        if let Some(camera) = camera_name(make.as_deref(), model.as_deref()) {
+            tags.push(format!("{}{}", conf.camera_prefix, camera));
+        }
+        if let Some(month) = ascii_field(&exif, exif::Tag::DateTimeOriginal)
+            .or_else(|| ascii_field(&exif, exif::Tag::DateTime))
+            .as_deref()
+            .and_then(taken_month)
+        {
+            tags.push(format!("{}{}", conf.date_prefix, month));
+        }
+        Ok(tags)
+    }
+
+    pub(super) fn audio_tags(conf: &Media, path: &Path) -> Result<Vec<String>, Box<dyn Error>> {
+        let tag = id3::Tag::read_from_path(path)?;
+
+        let mut tags = vec![];
+        if let Some(artist) = tag.artist().and_then(clean_value) {
+            tags.push(format!("{}{}", conf.artist_prefix, artist));
+        }
+        if let Some(album) = tag.album().and_then(clean_value) {
+            tags.push(format!("{}{}", conf.album_prefix, album));
+        }
+        Ok(tags)
+    }
+}
+
+/// Without the `media-tags` feature, there's nothing to read the metadata with
+#[cfg(not(feature = "media-tags"))]
+mod read {
+    use super::MEDIA_TAG;
+    use crate::common::settings::config::Media;
+    use log::warn;
+    use std::error::Error;
+    use std::path::Path;
+
+    fn unsupported() -> Result<Vec<String>, Box<dyn Error>> {
+        warn!(target: MEDIA_TAG, "Media tagging is enabled, but supertag was built without the media-tags feature");
+        Ok(vec![])
+    }
+
+    pub(super) fn image_tags(_conf: &Media, _path: &Path) -> Result<Vec<String>, Box<dyn Error>> {
+        unsupported()
+    }
+
+    pub(super) fn audio_tags(_conf: &Media, _path: &Path) -> Result<Vec<String>, Box<dyn Error>> {
+        unsupported()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_camera_name() {
+        assert_eq!(
+            camera_name(Some("FUJIFILM"), Some("X100V")),
+            Some("fujifilm-x100v".to_string())
+        );
+        assert_eq!(
+            camera_name(Some("Canon"), Some("Canon EOS  5D")),
+            Some("canon-eos-5d".to_string())
+        );
+        assert_eq!(camera_name(None, Some("X100V")), Some("x100v".to_string()));
+        assert_eq!(camera_name(Some(" "), None), None);
+    }
+
+    #[test]
+    fn test_taken_month() {
+        assert_eq!(
+            taken_month("2021:07:15 12:00:00"),
+            Some("2021-07".to_string())
+        );
+        assert_eq!(taken_month("0000:00:00 00:00:00"), None);
+        assert_eq!(taken_month("July 2021"), None);
+    }
+
+    #[test]
+    fn test_clean_value() {
+        assert_eq!(clean_value(" AC/DC "), Some("AC-DC".to_string()));
+        assert_eq!(clean_value("OK  Computer"), Some("OK Computer".to_string()));
+        assert_eq!(clean_value("   "), None);
+    }
+}
@@ -0,0 +1,355 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Counters and histograms about a running mount, in the Prometheus text format.  They're always counted, since that's
+//! only a few atomic adds, but they're only exported when the mount config has `metrics_listen` or `metrics_textfile`.
+
+use lazy_static::lazy_static;
+use parking_lot::RwLock;
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread::spawn;
+use std::time::Duration;
+use tracing::{debug, error, info, warn};
+
+const TAG: &str = "metrics";
+
+// how long a scraper gets to send its request before we give up on it
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often the textfile exporter rewrites its file
+const TEXTFILE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// The upper bounds, in seconds, of the buckets that fuse op durations are counted in
+const BUCKETS: [f64; 10] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+lazy_static! {
+    pub static ref METRICS: Metrics = Metrics::default();
+}
+
+#[derive(Default)]
+struct OpStats {
+    count: AtomicU64,
+    errors: AtomicU64,
+    micros: AtomicU64,
+    /// Not cumulative, unlike what's exported, so that recording only touches one bucket
+    buckets: [AtomicU64; BUCKETS.len()],
+}
+
+#[derive(Default)]
+struct CacheStats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    ops: RwLock<BTreeMap<&'static str, OpStats>>,
+    caches: RwLock<BTreeMap<&'static str, CacheStats>>,
+    sqlite_busy_retries: AtomicU64,
+    sqlite_busy_timeouts: AtomicU64,
+}
+
+/// Runs `f` on the stats for `key`, only taking the write lock the first time that `key` is seen
+fn with_entry<T: Default>(
+    map: &RwLock<BTreeMap<&'static str, T>>,
+    key: &'static str,
+    f: impl FnOnce(&T),
+) {
+    if let Some(stats) = map.read().get(key) {
+        return f(stats);
+    }
+    f(map.write().entry(key).or_default())
+}
+
+impl Metrics {
+    /// Counts a fuse callback that took `elapsed`
+    pub fn record_op(&self, op: &'static str, elapsed: Duration, failed: bool) {
+        let secs = elapsed.as_secs_f64();
+        with_entry(&self.ops, op, |stats| {
+            stats.count.fetch_add(1, Ordering::Relaxed);
+            if failed {
+                stats.errors.fetch_add(1, Ordering::Relaxed);
+            }
+            stats
+                .micros
+                .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+            if let Some(idx) = BUCKETS.iter().position(|bound| secs <= *bound) {
+                stats.buckets[idx].fetch_add(1, Ordering::Relaxed);
+            }
+        });
+    }
+
+    /// Counts a lookup in one of the mount's caches
+    pub fn cache_lookup(&self, cache: &'static str, hit: bool) {
+        with_entry(&self.caches, cache, |stats| {
+            let counter = if hit { &stats.hits } else { &stats.misses };
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+    }
+
+    /// Counts sqlite's busy handler being called because another connection held the lock
+    pub fn sqlite_busy_retry(&self) {
+        self.sqlite_busy_retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts the busy handler giving up on a lock
+    pub fn sqlite_busy_timeout(&self) {
+        self.sqlite_busy_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Everything counted so far, in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        let ops = self.ops.read();
+        let op_counts = ops.iter().map(|(op, stats)| (*op, load(&stats.count)));
+        counters(
+            &mut out,
+            "supertag_fuse_ops_total",
+            "Fuse callbacks handled",
+            "op",
+            op_counts,
+        );
+        let op_errors = ops.iter().map(|(op, stats)| (*op, load(&stats.errors)));
+        counters(
+            &mut out,
+            "supertag_fuse_op_errors_total",
+            "Fuse callbacks that failed",
+            "op",
+            op_errors,
+        );
+
+        let name = "supertag_fuse_op_duration_seconds";
+        header(&mut out, name, "histogram", "How long fuse callbacks took");
+        for (op, stats) in ops.iter() {
+            let mut cumulative = 0;
+            for (bound, bucket) in BUCKETS.iter().zip(stats.buckets.iter()) {
+                cumulative += load(bucket);
+                let le = bound.to_string();
+                sample(
+                    &mut out,
+                    name,
+                    "_bucket",
+                    &[("op", op), ("le", &le)],
+                    cumulative,
+                );
+            }
+            let count = load(&stats.count);
+            sample(
+                &mut out,
+                name,
+                "_bucket",
+                &[("op", op), ("le", "+Inf")],
+                count,
+            );
+            let secs = load(&stats.micros) as f64 / 1_000_000.0;
+            sample(&mut out, name, "_sum", &[("op", op)], secs);
+            sample(&mut out, name, "_count", &[("op", op)], count);
+        }
+        drop(ops);
+
+        let caches = self.caches.read();
+        let hits = caches
+            .iter()
+            .map(|(cache, stats)| (*cache, load(&stats.hits)));
+        counters(
+            &mut out,
+            "supertag_cache_hits_total",
+            "Lookups found in a cache",
+            "cache",
+            hits,
+        );
+        let misses = caches
+            .iter()
+            .map(|(cache, stats)| (*cache, load(&stats.misses)));
+        counters(
+            &mut out,
+            "supertag_cache_misses_total",
+            "Lookups not found in a cache",
+            "cache",
+            misses,
+        );
+        drop(caches);
+
+        let name = "supertag_sqlite_busy_retries_total";
+        header(
+            &mut out,
+            name,
+            "counter",
+            "Retries of a sqlite lock held by another connection",
+        );
+        sample(&mut out, name, "", &[], load(&self.sqlite_busy_retries));
+        let name = "supertag_sqlite_busy_timeouts_total";
+        header(
+            &mut out,
+            name,
+            "counter",
+            "Sqlite locks given up on after retrying",
+        );
+        sample(&mut out, name, "", &[], load(&self.sqlite_busy_timeouts));
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+}
+
+/// A counter with one sample per `label` value
+fn counters<'a>(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    label: &str,
+    values: impl Iterator<Item = (&'a str, u64)>,
+) {
+    header(out, name, "counter", help);
+    for (key, value) in values {
+        sample(out, name, "", &[(label, key)], value);
+    }
+}
+
+fn sample<V: std::fmt::Display>(
+    out: &mut String,
+    name: &str,
+    suffix: &str,
+    labels: &[(&str, &str)],
+    value: V,
+) {
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(key, val)| format!("{}=\"{}\"", key, val))
+        .collect();
+    if labels.is_empty() {
+        let _ = writeln!(out, "{}{} {}", name, suffix, value);
+    } else {
+        let _ = writeln!(out, "{}{}{{{}}} {}", name, suffix, labels.join(","), value);
+    }
+}
+
+/// Serves the metrics over http on `addr`, for Prometheus to scrape
+pub fn serve(addr: &str) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    info!(target: TAG, "Serving metrics on {}", addr);
+
+    spawn(move || {
+        for maybe_stream in listener.incoming() {
+            match maybe_stream {
+                Ok(stream) => {
+                    if let Err(e) = handle_conn(stream) {
+                        warn!(target: TAG, "Error handling metrics connection: {:?}", e);
+                    }
+                }
+                Err(e) => error!(target: TAG, "Error getting metrics connection: {:?}", e),
+            }
+        }
+        debug!(target: TAG, "Exiting thread");
+    });
+    Ok(())
+}
+
+fn handle_conn(mut stream: TcpStream) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    // nothing in the headers matters, but they're read anyways, so that closing the connection with them unread
+    // doesn't reset it before the scraper has read the response
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let (status, body) = match request_line.split_whitespace().nth(1) {
+        Some("/") | Some("/metrics") => ("200 OK", METRICS.render()),
+        _ => ("404 Not Found", String::new()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+/// Rewrites the metrics to `path` every so often, for node_exporter's textfile collector.  The file is replaced in one
+/// step, so the collector never reads half of it.
+pub fn write_textfile(path: PathBuf) {
+    info!(target: TAG, "Writing metrics to {}", path.display());
+    spawn(move || loop {
+        if let Err(e) = replace_file(&path, &METRICS.render()) {
+            warn!(target: TAG, "Couldn't write metrics to {}: {}", path.display(), e);
+        }
+        std::thread::sleep(TEXTFILE_INTERVAL);
+    });
+}
+
+fn replace_file(path: &Path, contents: &str) -> std::io::Result<()> {
+    let mut tmp_name = path.as_os_str().to_owned();
+    tmp_name.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_name);
+    std::fs::write(&tmp_path, contents)?;
+    std::fs::rename(&tmp_path, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.record_op("getattr", Duration::from_micros(300), false);
+        metrics.record_op("getattr", Duration::from_millis(20), true);
+        metrics.record_op("rename", Duration::from_secs(10), false);
+        metrics.cache_lookup("readdir", true);
+        metrics.cache_lookup("readdir", false);
+        metrics.cache_lookup("readdir", true);
+        metrics.sqlite_busy_retry();
+
+        let out = metrics.render();
+        let lines: Vec<&str> = out.lines().collect();
+        for expected in &[
+            "supertag_fuse_ops_total{op=\"getattr\"} 2",
+            "supertag_fuse_op_errors_total{op=\"getattr\"} 1",
+            "supertag_fuse_op_duration_seconds_bucket{op=\"getattr\",le=\"0.0001\"} 0",
+            "supertag_fuse_op_duration_seconds_bucket{op=\"getattr\",le=\"0.0005\"} 1",
+            "supertag_fuse_op_duration_seconds_bucket{op=\"getattr\",le=\"0.05\"} 2",
+            "supertag_fuse_op_duration_seconds_bucket{op=\"rename\",le=\"5\"} 0",
+            "supertag_fuse_op_duration_seconds_bucket{op=\"rename\",le=\"+Inf\"} 1",
+            "supertag_fuse_op_duration_seconds_sum{op=\"getattr\"} 0.0203",
+            "supertag_fuse_op_duration_seconds_count{op=\"rename\"} 1",
+            "supertag_cache_hits_total{cache=\"readdir\"} 2",
+            "supertag_cache_misses_total{cache=\"readdir\"} 1",
+            "supertag_sqlite_busy_retries_total 1",
+            "supertag_sqlite_busy_timeouts_total 0",
+        ] {
+            assert!(lines.contains(expected), "missing {}", expected);
+        }
+    }
+}
@@ -18,22 +18,46 @@
 
 use std::path::{Path, PathBuf};
 
-use super::common::constants::NEGATIVE_TAG_PREFIX;
+use super::common::constants::{
+    DATE_TAG_PREFIX, MAX_RATING, META_TAG_PREFIX, META_TAG_SEPARATOR, NEGATIVE_TAG_PREFIX,
+    RATING_TAG_PREFIX, RATING_TAG_SUFFIX, UNION_TAG_SEPARATOR,
+};
 use super::common::err::STagResult;
 use crate::common::constants::VERSION;
 use crate::common::settings::Settings;
 use nix::sys::stat::stat;
 
+pub mod archive;
 pub mod constants;
+pub mod control;
+pub mod diagnostics;
+pub mod display;
+pub mod doctor;
+pub mod dupes;
 pub mod err;
 pub mod fsops;
+pub mod identity;
 pub mod iter;
+pub mod keyring;
 pub mod log;
 pub mod managed_file;
+pub mod media;
+pub mod metrics;
+pub mod name_template;
 pub mod notify;
+pub mod providers;
+pub mod query;
+pub mod rules;
+pub mod search;
 pub mod settings;
+pub mod symbols;
+pub mod sync;
+pub mod tar;
+#[cfg(test)]
+pub(crate) mod testing;
 pub mod types;
 pub mod xattr;
+pub mod xtx;
 
 /// Takes a normal path on the filesystem and gets the device and inode nums
 pub fn get_device_inode(path: &Path) -> err::STagResult<(u64, u64)> {
@@ -53,34 +77,110 @@ pub fn get_filename(path: &Path) -> STagResult<&str> {
         .ok_or_else(|| err::STagError::InvalidPath(path.to_owned()))?)
 }
 
-/// Parses out the `primary_tag` portion of a path, which is essentially the filename sans the
-/// special characters we tack onto the end.
-pub fn primary_tag(path: &Path, device_char: char) -> Result<Option<String>, err::STagError> {
-    let filename = get_filename(path)?;
-    let mut primary_tag_chars = Vec::new();
-    for letter in filename.chars() {
-        if letter == device_char {
-            break;
+pub fn strip_negative_tag(tag: &str) -> Option<&str> {
+    if tag.starts_with(NEGATIVE_TAG_PREFIX) {
+        Some(&tag[NEGATIVE_TAG_PREFIX.len()..])
+    } else {
+        None
+    }
+}
+
+/// Splits a path component like `meta:client=acme` into its metadata key and value.  Returns `None` if the component
+/// isn't a metadata match, or if its key or value is empty.
+pub fn split_meta_tag(tag: &str) -> Option<(String, String)> {
+    let pair = tag.strip_prefix(META_TAG_PREFIX)?;
+    let mut parts = pair.splitn(2, META_TAG_SEPARATOR);
+    let key = parts.next().filter(|k| !k.is_empty())?;
+    let value = parts.next().filter(|v| !v.is_empty())?;
+    Some((key.to_owned(), value.to_owned()))
+}
+
+/// Takes the year or month out of a path component like `@2021` or `@2021-07`.  Returns `None` if the component
+/// isn't a four digit year, optionally followed by a month from 01 to 12.
+pub fn split_date_tag(tag: &str) -> Option<String> {
+    let bucket = tag.strip_prefix(DATE_TAG_PREFIX)?;
+    let mut parts = bucket.splitn(2, '-');
+    let year = parts.next()?;
+    if year.len() != 4 || !year.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    if let Some(month) = parts.next() {
+        let valid = month.len() == 2
+            && month.chars().all(|c| c.is_ascii_digit())
+            && matches!(month.parse::<u32>(), Ok(1..=12));
+        if !valid {
+            return None;
         }
-        primary_tag_chars.push(letter);
     }
+    Some(bucket.to_owned())
+}
 
-    if primary_tag_chars.len() > 1 {
-        let primary_tag = primary_tag_chars.into_iter().collect();
-        return Ok(Some(primary_tag));
-    } else {
-        return Ok(None);
+/// Takes the number of stars out of a path component like `★4+`.  Returns `None` if the component isn't a rating from
+/// 1 to `MAX_RATING`.
+pub fn split_rating_tag(tag: &str) -> Option<u8> {
+    let stars = tag
+        .strip_prefix(RATING_TAG_PREFIX)?
+        .strip_suffix(RATING_TAG_SUFFIX)?;
+    if stars.len() != 1 {
+        return None;
     }
+    stars.parse().ok().filter(|n| (1..=MAX_RATING).contains(n))
 }
 
-pub fn strip_negative_tag(tag: &str) -> Option<&str> {
-    if tag.starts_with(NEGATIVE_TAG_PREFIX) {
-        Some(&tag[NEGATIVE_TAG_PREFIX.len()..])
+/// Splits a path component like `music|podcasts` into its member tags.  Returns `None` if the component isn't a
+/// union of at least two non-empty tags.
+pub fn split_union_tag(tag: &str) -> Option<Vec<String>> {
+    if !tag.contains(UNION_TAG_SEPARATOR) {
+        return None;
+    }
+
+    let mut members = vec![];
+    for member in tag.split(UNION_TAG_SEPARATOR) {
+        if member.is_empty() {
+            return None;
+        }
+        if !members.iter().any(|m| m == member) {
+            members.push(member.to_owned());
+        }
+    }
+
+    if members.len() > 1 {
+        Some(members)
     } else {
         None
     }
 }
 
+/// Matches `name` against a glob `pattern`, where `*` matches any run of characters, including none, and `?` matches
+/// exactly one character.
+pub fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    let (mut p, mut n) = (0, 0);
+    // where we last saw a `*` in the pattern, and how far into the name it had consumed at that point
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = backtrack {
+            // let the last `*` swallow one more character and try again
+            p = star_p + 1;
+            n = star_n + 1;
+            backtrack = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
 pub fn strip_ext_prefix(name: &str, prefix: &str) -> Option<String> {
     let parts: Vec<&str> = name.rsplitn(2, ".").collect();
 
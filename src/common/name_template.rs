@@ -0,0 +1,348 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! A name template decides how a file's fully-qualified name is rendered, which is the name that tells it apart from
+//! other files with the same name in a filedir.  The template is parsed back on lookup, so it has to keep the file's
+//! device and inode.  For example, `{stem}{device_char}{device}{inode_char}{inode}{ext}` keeps the extension at the
+//! end, so that file managers still recognize the file's type.
+//!
+//! A template is parsed once, when the config is loaded, so a bad one is a config error.  The device and inode
+//! symbols are filled in when a name is rendered or parsed, since a collection may still have to parse names that were
+//! rendered with the symbols it used to have.
+
+use crate::common::err::{STagError, STagResult};
+use crate::common::types::DeviceFile;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+
+pub const DEFAULT_NAME_TEMPLATE: &str = "{name}{device_char}{device}{inode_char}{inode}";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    DeviceChar,
+    InodeChar,
+    /// The file's whole name
+    Name,
+    /// The file's name without its extension
+    Stem,
+    /// The file's extension with its dot, ie `.mp3`, or nothing if it has none
+    Ext,
+    Device,
+    Inode,
+}
+
+impl Part {
+    fn is_field(&self) -> bool {
+        !matches!(self, Part::Literal(_) | Part::DeviceChar | Part::InodeChar)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct NameTemplate {
+    template: String,
+    parts: Vec<Part>,
+}
+
+/// The device and inode symbols that a template is rendered and parsed with
+#[derive(Debug, Clone, Copy)]
+pub struct TemplateChars {
+    pub device_char: char,
+    pub inode_char: char,
+}
+
+#[derive(Default)]
+struct Captures<'a> {
+    name: &'a str,
+    stem: &'a str,
+    ext: &'a str,
+    device: u64,
+    inode: u64,
+}
+
+impl NameTemplate {
+    /// Parses `template`.  The template needs the device, the inode, and the name, either whole or as a stem, and its
+    /// fields need something between them so that they can be told apart again.
+    pub fn parse(template: &str) -> STagResult<Self> {
+        let bad =
+            |reason: &str| STagError::BadNameTemplate(template.to_string(), reason.to_string());
+
+        let mut parts: Vec<Part> = vec![];
+        let mut rest = template;
+        while !rest.is_empty() {
+            let (part, len) = match rest.find('{') {
+                Some(0) => {
+                    let end = rest.find('}').ok_or_else(|| bad("unclosed {"))?;
+                    let part = match &rest[1..end] {
+                        "name" => Part::Name,
+                        "stem" => Part::Stem,
+                        "ext" => Part::Ext,
+                        "device" => Part::Device,
+                        "inode" => Part::Inode,
+                        "device_char" => Part::DeviceChar,
+                        "inode_char" => Part::InodeChar,
+                        _ => return Err(bad("unknown field")),
+                    };
+                    (part, end + 1)
+                }
+                Some(idx) => (Part::Literal(rest[..idx].to_string()), idx),
+                None => (Part::Literal(rest.to_string()), rest.len()),
+            };
+            rest = &rest[len..];
+
+            match (parts.last_mut(), &part) {
+                (Some(Part::Literal(prev)), Part::Literal(lit)) => {
+                    prev.push_str(lit);
+                    continue;
+                }
+                // a stem and its extension can be split anywhere and still make the same name
+                (Some(Part::Stem), Part::Ext) | (Some(Part::Ext), Part::Stem) => (),
+                (Some(prev), _) if prev.is_field() && part.is_field() => {
+                    return Err(bad("fields need something between them"));
+                }
+                _ => (),
+            }
+            parts.push(part);
+        }
+
+        let count = |wanted: &Part| parts.iter().filter(|p| *p == wanted).count();
+        if count(&Part::Device) != 1 || count(&Part::Inode) != 1 {
+            return Err(bad("needs {device} and {inode} once each"));
+        }
+        let names = (count(&Part::Name), count(&Part::Stem), count(&Part::Ext));
+        if names != (1, 0, 0) && names != (0, 1, 1) && names != (0, 1, 0) {
+            return Err(bad(
+                "needs either {name}, or {stem} with an optional {ext}, once each",
+            ));
+        }
+        Ok(Self {
+            template: template.to_string(),
+            parts,
+        })
+    }
+
+    /// The fully-qualified name of the file named `filename`
+    pub fn render(&self, chars: TemplateChars, filename: &str, device: u64, inode: u64) -> String {
+        let (stem, ext) = split_ext(filename);
+        let has_ext = self.parts.contains(&Part::Ext);
+        let mut rendered = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(lit) => rendered.push_str(lit),
+                Part::DeviceChar => rendered.push(chars.device_char),
+                Part::InodeChar => rendered.push(chars.inode_char),
+                Part::Name => rendered.push_str(filename),
+                // without an {ext}, the stem is the whole name, so that it isn't lost
+                Part::Stem if !has_ext => rendered.push_str(filename),
+                Part::Stem => rendered.push_str(stem),
+                Part::Ext => rendered.push_str(ext),
+                Part::Device => rendered.push_str(&device.to_string()),
+                Part::Inode => rendered.push_str(&inode.to_string()),
+            }
+        }
+        rendered
+    }
+
+    /// Parses a fully-qualified name back into its file, or `None` if `name` wasn't rendered by this template
+    pub fn parse_name(&self, chars: TemplateChars, name: &str) -> Option<DeviceFile> {
+        let mut caps = Captures::default();
+        if !match_parts(&self.parts, chars, name, &mut caps) {
+            return None;
+        }
+        let filename = if self.parts.contains(&Part::Name) {
+            caps.name.to_string()
+        } else {
+            format!("{}{}", caps.stem, caps.ext)
+        };
+        Some(DeviceFile::new(&filename, caps.device, caps.inode))
+    }
+}
+
+impl TryFrom<String> for NameTemplate {
+    type Error = STagError;
+
+    fn try_from(template: String) -> STagResult<Self> {
+        Self::parse(&template)
+    }
+}
+
+impl From<NameTemplate> for String {
+    fn from(template: NameTemplate) -> Self {
+        template.template
+    }
+}
+
+/// Matches `parts` against all of `name`, backtracking over where each field ends.  Names are short and a template
+/// only has a couple of fields, so this is cheap.
+fn match_parts<'a>(
+    parts: &[Part],
+    chars: TemplateChars,
+    name: &'a str,
+    caps: &mut Captures<'a>,
+) -> bool {
+    let (part, rest) = match parts.split_first() {
+        Some(split) => split,
+        None => return name.is_empty(),
+    };
+    match part {
+        Part::Literal(lit) => {
+            name.starts_with(lit.as_str()) && match_parts(rest, chars, &name[lit.len()..], caps)
+        }
+        Part::DeviceChar | Part::InodeChar => {
+            let c = if *part == Part::DeviceChar {
+                chars.device_char
+            } else {
+                chars.inode_char
+            };
+            name.starts_with(c) && match_parts(rest, chars, &name[c.len_utf8()..], caps)
+        }
+        Part::Device | Part::Inode => {
+            let digits = name
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(name.len());
+            (1..=digits).rev().any(|end| {
+                let num = match name[..end].parse() {
+                    Ok(num) => num,
+                    Err(_) => return false,
+                };
+                if *part == Part::Device {
+                    caps.device = num;
+                } else {
+                    caps.inode = num;
+                }
+                match_parts(rest, chars, &name[end..], caps)
+            })
+        }
+        Part::Name | Part::Stem | Part::Ext => {
+            let mut ends: Vec<usize> = name.char_indices().map(|(idx, _)| idx).collect();
+            ends.push(name.len());
+            ends.into_iter().rev().any(|end| {
+                let value = &name[..end];
+                match part {
+                    Part::Ext if !value.is_empty() && !value.starts_with('.') => return false,
+                    Part::Ext => caps.ext = value,
+                    Part::Stem => caps.stem = value,
+                    _ => caps.name = value,
+                }
+                match_parts(rest, chars, &name[end..], caps)
+            })
+        }
+    }
+}
+
+/// Splits `filename` into its stem and its extension with the dot.  A leading dot is a hidden file, not an extension.
+fn split_ext(filename: &str) -> (&str, &str) {
+    match filename.rfind('.') {
+        Some(0) | None => (filename, ""),
+        Some(idx) => filename.split_at(idx),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHARS: TemplateChars = TemplateChars {
+        device_char: '﹫',
+        inode_char: '-',
+    };
+
+    fn template(template: &str) -> NameTemplate {
+        NameTemplate::parse(template).unwrap()
+    }
+
+    #[test]
+    fn test_default_template() {
+        let tpl = template(DEFAULT_NAME_TEMPLATE);
+        assert_eq!(
+            tpl.render(CHARS, "song-1.mp3", 12, 345),
+            "song-1.mp3﹫12-345"
+        );
+        assert_eq!(
+            tpl.parse_name(CHARS, "song-1.mp3﹫12-345"),
+            Some(DeviceFile::new("song-1.mp3", 12, 345))
+        );
+        assert_eq!(tpl.parse_name(CHARS, "song-1.mp3"), None);
+        assert_eq!(tpl.parse_name(CHARS, "song﹫x-345"), None);
+    }
+
+    #[test]
+    fn test_ext_template() {
+        let tpl = template("{stem} ({device}.{inode}){ext}");
+        assert_eq!(tpl.render(CHARS, "a.tar.gz", 1, 2), "a.tar (1.2).gz");
+        assert_eq!(tpl.render(CHARS, ".hidden", 1, 2), ".hidden (1.2)");
+        assert_eq!(
+            tpl.parse_name(CHARS, "a.tar (1.2).gz"),
+            Some(DeviceFile::new("a.tar.gz", 1, 2))
+        );
+        assert_eq!(
+            tpl.parse_name(CHARS, ".hidden (1.2)"),
+            Some(DeviceFile::new(".hidden", 1, 2))
+        );
+
+        // without {ext}, the stem is the whole name
+        let tpl = template("{device}_{inode}_{stem}");
+        assert_eq!(tpl.render(CHARS, "a.txt", 1, 2), "1_2_a.txt");
+        assert_eq!(
+            tpl.parse_name(CHARS, "1_2_a.txt"),
+            Some(DeviceFile::new("a.txt", 1, 2))
+        );
+    }
+
+    #[test]
+    fn test_bad_templates() {
+        for bad in &[
+            "{name}",
+            "{name}{device}-{inode}",
+            "{name}﹫{device}-{inode}-{inode}",
+            "{name}﹫{device}-{inode}{ext}",
+            "{name}﹫{device}-{inode",
+            "{name}﹫{device}-{inode}-{tag}",
+        ] {
+            assert!(NameTemplate::parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_symbols() {
+        let tpl = template(DEFAULT_NAME_TEMPLATE);
+        let legacy = TemplateChars {
+            device_char: '@',
+            inode_char: '_',
+        };
+        assert_eq!(tpl.render(legacy, "a.txt", 1, 2), "a.txt@1_2");
+        assert_eq!(
+            tpl.parse_name(legacy, "a.txt@1_2"),
+            Some(DeviceFile::new("a.txt", 1, 2))
+        );
+        assert_eq!(tpl.parse_name(CHARS, "a.txt@1_2"), None);
+    }
+
+    #[test]
+    fn test_deserialize() {
+        let tpl: NameTemplate = serde_json::from_str(r#""{stem}_{device}_{inode}{ext}""#).unwrap();
+        assert_eq!(tpl, template("{stem}_{device}_{inode}{ext}"));
+        assert_eq!(
+            serde_json::to_string(&tpl).unwrap(),
+            r#""{stem}_{device}_{inode}{ext}""#
+        );
+        let bad: Result<NameTemplate, _> = serde_json::from_str(r#""{name}""#);
+        assert!(bad.is_err());
+    }
+}
@@ -19,61 +19,264 @@
 use super::Notifier;
 use crate::common::constants;
 use crate::common::notify::Listener;
+use crate::common::settings::config::Notifications;
+use crate::common::settings::Settings;
 use crate::common::types::note::Note;
-use log::info;
+use log::{debug, info, warn};
 use notify_rust::{Notification, Timeout};
-use std::cell::RefCell;
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
+/// Decides which notes make it to the desktop, so that a script that keeps failing doesn't bury the desktop in
+/// notifications.  Each window shows the first `max` notes that differ from the ones it has already shown, and the
+/// rest are held, counted by kind, to be summarized once the window is over.
+struct Batcher {
+    max: usize,
+    window: Duration,
+    started: Option<Instant>,
+    shown: Vec<Note>,
+    held: BTreeMap<&'static str, usize>,
+    // whether a thread is already waiting for the window to end, to show its summary
+    flush_pending: bool,
+}
+
+impl Batcher {
+    fn new(conf: &Notifications) -> Self {
+        Self {
+            max: conf.max_per_window,
+            window: Duration::from_secs(conf.window_s),
+            started: None,
+            shown: vec![],
+            held: BTreeMap::new(),
+            flush_pending: false,
+        }
+    }
+
+    /// Whether `note` should be shown now.  Otherwise it's held for the window's summary.
+    fn admit(&mut self, note: &Note, now: Instant) -> bool {
+        if self.started.is_none() {
+            self.started = Some(now);
+        }
+        if self.shown.len() < self.max && !self.shown.contains(note) {
+            self.shown.push(note.clone());
+            true
+        } else {
+            *self.held.entry(note_kind(note)).or_default() += 1;
+            false
+        }
+    }
+
+    /// When the current window is over, if there is one
+    fn window_end(&self) -> Option<Instant> {
+        self.started.map(|started| started + self.window)
+    }
+
+    /// Ends the current window if it's over, returning a summary of the notes held during it, if there were any
+    fn finish(&mut self, now: Instant) -> Option<String> {
+        match self.window_end() {
+            Some(end) if now >= end => {}
+            _ => return None,
+        }
+        self.started = None;
+        self.shown.clear();
+        let held = std::mem::take(&mut self.held);
+        if held.is_empty() {
+            return None;
+        }
+
+        let counts: Vec<String> = held
+            .iter()
+            .map(|(kind, count)| held_summary(kind, *count))
+            .collect();
+        Some(format!(
+            "{} in the last {}",
+            counts.join(", "),
+            fmt_window(self.window)
+        ))
+    }
+}
+
+fn note_kind(note: &Note) -> &'static str {
+    match note {
+        Note::BadCopy => "bad_copy",
+        Note::DraggedToRoot => "dragged_to_root",
+        Note::Unlink(_) => "unlink",
+        Note::TagToTagGroup(_) => "tag_to_tg",
+        Note::WarmProgress(..) => "warm_progress",
+        Note::ProtectedSource(_) => "protected_source",
+        Note::OpFailed(..) => "op_failed",
+        Note::Merged(..) => "merged",
+        Note::Retagged(..) => "retagged",
+        Note::DiskFull(..) => "disk_full",
+    }
+}
+
+fn held_summary(kind: &str, count: usize) -> String {
+    match kind {
+        "bad_copy" => format!("{} copy attempts blocked", count),
+        "dragged_to_root" => format!("{} drags to the root collection blocked", count),
+        "unlink" => format!("{} regular deletes blocked", count),
+        "tag_to_tg" => format!("{} tag group changes blocked", count),
+        "protected_source" => format!("{} collection files kept from being tagged", count),
+        "op_failed" => format!("{} more operations failed", count),
+        "merged" => format!("{} more merges", count),
+        "retagged" => format!("{} more files retagged", count),
+        "disk_full" => format!("{} more disk full warnings", count),
+        _ => format!("{} more {} notifications", count, kind),
+    }
+}
+
+fn fmt_window(window: Duration) -> String {
+    match window.as_secs() {
+        60 => "minute".to_string(),
+        secs if secs % 60 == 0 => format!("{} minutes", secs / 60),
+        secs => format!("{} seconds", secs),
+    }
+}
+
+fn show(icon: &Option<PathBuf>, summary: &str, body: &str) -> Result<(), Box<dyn Error>> {
+    let mut notification = Notification::new();
+    if let Some(icon) = icon {
+        notification.icon(&icon.to_string_lossy());
+    }
+    notification
+        .summary(summary)
+        .body(body)
+        .timeout(Timeout::Milliseconds(6000))
+        .show()?;
+    Ok(())
+}
+
 pub struct DesktopNotifier {
     tag: String,
     icon: Option<PathBuf>,
-    last_message: RefCell<Instant>,
+    batcher: Arc<Mutex<Batcher>>,
 }
 
 impl DesktopNotifier {
-    pub fn new(icon: Option<PathBuf>) -> Self {
+    pub fn new(icon: Option<PathBuf>, conf: &Notifications) -> Self {
         let tag = "desktop-notification".to_string();
         Self {
             tag,
             icon,
-            last_message: RefCell::new(Instant::now()),
+            batcher: Arc::new(Mutex::new(Batcher::new(conf))),
         }
     }
 
+    /// A notifier with the settings' icon and rate limit
+    pub fn from_settings(settings: &Settings) -> Self {
+        Self::new(
+            settings.notification_icon(),
+            &settings.get_config().notifications,
+        )
+    }
+
+    /// Shows the summary of the current window once it's over, from a thread of its own, so that it's shown even if
+    /// no more notes come in
+    fn flush_later(&self, end: Instant) {
+        let batcher = self.batcher.clone();
+        let icon = self.icon.clone();
+        let tag = self.tag.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(end.saturating_duration_since(Instant::now()));
+            let summary = {
+                let mut guard = batcher.lock();
+                guard.flush_pending = false;
+                guard.finish(Instant::now())
+            };
+            if let Some(summary) = summary {
+                if let Err(e) = show(&icon, "Supertag", &summary) {
+                    warn!(target: &tag, "Couldn't show summary: {:?}", e);
+                }
+            }
+        });
+    }
+
     fn send_message(&self, note: Note) -> Result<(), Box<dyn Error>> {
-        let elapsed = self.last_message.borrow().elapsed();
-        self.last_message.replace(Instant::now());
+        let now = Instant::now();
+        let (summary, admitted, flush_at) = {
+            let mut guard = self.batcher.lock();
+            let summary = guard.finish(now);
+            let admitted = guard.admit(&note, now);
+            let flush_at = if admitted || guard.flush_pending {
+                None
+            } else {
+                guard.flush_pending = true;
+                guard.window_end()
+            };
+            (summary, admitted, flush_at)
+        };
 
-        if elapsed.as_millis() < 500 {
+        if let Some(summary) = summary {
+            show(&self.icon, "Supertag", &summary)?;
+        }
+        if let Some(end) = flush_at {
+            self.flush_later(end);
+        }
+        if !admitted {
+            debug!(target: &self.tag, "Holding {:?} for the summary", note);
             return Ok(());
         }
 
-        let mut base_note = Notification::new();
-
-        if let Some(icon) = &self.icon {
-            base_note.icon(&icon.to_string_lossy());
-        }
-        base_note
-            .summary("Supertag Error")
-            .timeout(Timeout::Milliseconds(6000));
-
-        let full_note = match note {
-            Note::BadCopy => base_note.body("Cannot copy file into collection, symlink instead"),
-            Note::DraggedToRoot => base_note.body("Cannot tag a file in the root collection"),
-            Note::Unlink(_) => base_note.body(&*format!(
-                "Delete by renaming folder to '{}'",
-                constants::UNLINK_NAME
-            )),
-            Note::TagToTagGroup(_) => {
-                base_note.body("Cannot change a non-empty tag to a tag group")
-            }
+        let (summary, body) = match note {
+            Note::BadCopy => (
+                "Supertag Error",
+                "Cannot copy file into collection, symlink instead".to_string(),
+            ),
+            Note::DraggedToRoot => (
+                "Supertag Error",
+                "Cannot tag a file in the root collection".to_string(),
+            ),
+            Note::Unlink(_) => (
+                "Supertag Error",
+                format!("Delete by renaming folder to '{}'", constants::UNLINK_NAME),
+            ),
+            Note::ProtectedSource(_) => (
+                "Supertag Error",
+                "Cannot tag the collection's own database or managed files".to_string(),
+            ),
+            Note::TagToTagGroup(_) => (
+                "Supertag Error",
+                "Cannot change a non-empty tag to a tag group".to_string(),
+            ),
+            // progress isn't an error, so it never makes it to the desktop
+            Note::WarmProgress(..) => return Ok(()),
+            Note::OpFailed(path, reason) => (
+                "Supertag Error",
+                format!(
+                    "Couldn't change {}: {}",
+                    path.file_name().unwrap_or_default().to_string_lossy(),
+                    reason
+                ),
+            ),
+            Note::Merged(src, dst, num_files) => (
+                "Supertag",
+                format!("Merged {} into {}, {} files moved", src, dst, num_files),
+            ),
+            Note::Retagged(path, added, removed) => (
+                "Supertag",
+                format!(
+                    "Retagged {}, added {}, removed {}",
+                    path.file_name().unwrap_or_default().to_string_lossy(),
+                    added.join(", "),
+                    removed.join(", ")
+                ),
+            ),
+            Note::DiskFull(db_path, free_bytes) => (
+                "Supertag Error",
+                format!(
+                    "The disk holding {} is full ({} MiB free), the collection is read-only until space is freed",
+                    db_path.display(),
+                    free_bytes / (1024 * 1024)
+                ),
+            ),
         };
 
-        full_note.show()?;
-        Ok(())
+        show(&self.icon, summary, &body)
     }
 }
 
@@ -98,12 +301,56 @@ impl Notifier for DesktopNotifier {
         Ok(())
     }
 
+    fn protected_source(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        info!(target: &self.tag, "protected_source");
+        self.send_message(Note::ProtectedSource(path.to_owned()))?;
+        Ok(())
+    }
+
     fn tag_to_tg(&self, tag: &str) -> Result<(), Box<dyn Error>> {
         info!(target: &self.tag, "tag_to_tg");
         self.send_message(Note::TagToTagGroup(tag.to_owned()))?;
         Ok(())
     }
 
+    fn warm_progress(&self, done: usize, total: usize) -> Result<(), Box<dyn Error>> {
+        info!(target: &self.tag, "warm_progress {}/{}", done, total);
+        Ok(())
+    }
+
+    fn op_failed(&self, path: &Path, reason: &str) -> Result<(), Box<dyn Error>> {
+        info!(target: &self.tag, "op_failed");
+        self.send_message(Note::OpFailed(path.to_owned(), reason.to_owned()))?;
+        Ok(())
+    }
+
+    fn merged(&self, src: &str, dst: &str, num_files: usize) -> Result<(), Box<dyn Error>> {
+        info!(target: &self.tag, "merged");
+        self.send_message(Note::Merged(src.to_owned(), dst.to_owned(), num_files))?;
+        Ok(())
+    }
+
+    fn retagged(
+        &self,
+        path: &Path,
+        added: &[String],
+        removed: &[String],
+    ) -> Result<(), Box<dyn Error>> {
+        info!(target: &self.tag, "retagged");
+        self.send_message(Note::Retagged(
+            path.to_owned(),
+            added.to_vec(),
+            removed.to_vec(),
+        ))?;
+        Ok(())
+    }
+
+    fn disk_full(&self, db_path: &Path, free_bytes: u64) -> Result<(), Box<dyn Error>> {
+        info!(target: &self.tag, "disk_full");
+        self.send_message(Note::DiskFull(db_path.to_owned(), free_bytes))?;
+        Ok(())
+    }
+
     fn listener(&self) -> Result<Self::Listener, Box<dyn Error>> {
         Ok(())
     }
@@ -131,3 +378,52 @@ impl Listener for () {
         0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn batcher(max_per_window: usize) -> Batcher {
+        Batcher::new(&Notifications {
+            max_per_window,
+            window_s: 60,
+        })
+    }
+
+    #[test]
+    fn test_batcher_dedup() {
+        let mut b = batcher(5);
+        let start = Instant::now();
+        assert!(b.admit(&Note::BadCopy, start));
+        assert!(b.admit(&Note::DraggedToRoot, start));
+        for _ in 0..36 {
+            assert!(!b.admit(&Note::BadCopy, start));
+        }
+
+        // nothing is summarized until the window is over
+        assert_eq!(b.finish(start + Duration::from_secs(30)), None);
+        assert_eq!(
+            b.finish(start + Duration::from_secs(60)),
+            Some("36 copy attempts blocked in the last minute".to_string())
+        );
+        assert!(b.admit(&Note::BadCopy, start + Duration::from_secs(61)));
+    }
+
+    #[test]
+    fn test_batcher_limit() {
+        let mut b = batcher(2);
+        let start = Instant::now();
+        let failed = |name: &str| Note::OpFailed(PathBuf::from(name), "too long".to_string());
+        assert!(b.admit(&failed("a"), start));
+        assert!(b.admit(&failed("b"), start));
+        assert!(!b.admit(&failed("c"), start));
+        assert!(!b.admit(&Note::BadCopy, start));
+        assert_eq!(
+            b.finish(start + Duration::from_secs(60)),
+            Some(
+                "1 copy attempts blocked, 1 more operations failed in the last minute".to_string()
+            )
+        );
+        assert_eq!(b.finish(start + Duration::from_secs(120)), None);
+    }
+}
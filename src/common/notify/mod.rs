@@ -16,15 +16,25 @@
  * along with this program.  If not, see <http://www.gnu.org/licenses/>.
  */
 
+use crate::common::types::health::Health;
 use crate::common::types::note::Note;
 use std::error::Error;
+use std::io::Write;
 use std::path::Path;
+use std::sync::Arc;
 use std::time::Duration;
 
 pub mod desktop;
 pub mod listener;
 pub mod uds;
 
+/// Builds the daemon's health report on demand, for notifiers that can answer requests for it
+pub type HealthSource = Arc<dyn Fn() -> Result<Health, Box<dyn Error>> + Send + Sync>;
+
+/// Answers one line of JSON-RPC from a peer, returning the response, if there is one.  Progress notifications for the
+/// request are written to the writer it's given, before the response is returned.
+pub type RpcSource = Arc<dyn Fn(&str, &mut dyn Write) -> Option<serde_json::Value> + Send + Sync>;
+
 pub trait Notifier: Send {
     type Listener: Listener;
 
@@ -37,10 +47,46 @@ pub trait Notifier: Send {
     /// When a user attempts a regular delete instead of renaming delete
     fn unlink(&self, path: &Path) -> Result<(), Box<dyn Error>>;
 
+    /// When a user tries to tag a file that belongs to the collection itself, like its database or a managed file
+    fn protected_source(&self, path: &Path) -> Result<(), Box<dyn Error>>;
+
     /// When a user attempts to rename a non-empty tag to a tag group
     fn tag_to_tg(&self, tag: &str) -> Result<(), Box<dyn Error>>;
 
+    /// Progress of the background cache warm-up that runs after mounting
+    fn warm_progress(&self, done: usize, total: usize) -> Result<(), Box<dyn Error>>;
+
+    /// When an operation fails for a reason the user can do something about, but the errno alone won't explain it
+    fn op_failed(&self, path: &Path, reason: &str) -> Result<(), Box<dyn Error>>;
+
+    /// When `tag merge` has merged `src` into `dst`, moving `num_files` files
+    fn merged(&self, src: &str, dst: &str, num_files: usize) -> Result<(), Box<dyn Error>>;
+
+    /// When `tag retag` has changed the tags of the file at `path`, adding `added` and removing `removed`
+    fn retagged(
+        &self,
+        path: &Path,
+        added: &[String],
+        removed: &[String],
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// When the disk holding the database at `db_path` fills up, with `free_bytes` left on it.  The collection stays
+    /// read-only until there's room again.
+    fn disk_full(&self, db_path: &Path, free_bytes: u64) -> Result<(), Box<dyn Error>>;
+
     fn listener(&self) -> Result<Self::Listener, Box<dyn Error>>;
+
+    /// Lets peers ask for the daemon's health, answered by `source`.  Notifiers that peers can't talk back to ignore
+    /// it.
+    fn serve_health(&self, _source: HealthSource) {}
+
+    /// Lets peers make JSON-RPC calls, answered by `source`.  Notifiers that peers can't talk back to ignore it.
+    fn serve_rpc(&self, _source: RpcSource) {}
+
+    /// Releases anything the notifier holds open, as part of unmounting.  No notes are sent afterwards.
+    fn shutdown(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
 }
 
 pub trait Listener {
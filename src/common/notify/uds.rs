@@ -15,13 +15,17 @@
  * You should have received a copy of the GNU Affero General Public License
  * along with this program.  If not, see <http://www.gnu.org/licenses/>.
  */
-use super::{Listener, Notifier};
+use super::{HealthSource, Listener, Notifier, RpcSource};
+use crate::common::types::health::Health;
 use crate::common::types::note::Note;
 use log::{debug, error, info, trace, warn};
-use parking_lot::Mutex;
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use std::collections::VecDeque;
 use std::error::Error;
 use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
 use std::os::unix::net::{UnixListener, UnixStream};
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
@@ -33,20 +37,71 @@ use std::time::{Duration, Instant};
 // how many historical messages a peer will store and be allowed to traverse
 const PEER_BUFFER: usize = 10_000;
 
+// how long a peer waits for an answer to a request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Something a peer can ask the notifier, as one line of JSON.  The answer comes back on the same connection, in
+/// between its notes, as a `UDSResponse`.  A line that isn't one of these is taken as a JSON-RPC request, if the
+/// notifier is serving them, and is answered the same way `tag rpc` would answer it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum UDSRequest {
+    Health,
+}
+
+/// The answer to a `UDSRequest`.  It's tagged differently from `Note`, so that neither can be mistaken for the other.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "r", content = "c")]
+pub enum UDSResponse {
+    Health(Health),
+    Error(String),
+}
+
 pub struct UDSNotifier {
     tag: String,
     peers: Arc<Mutex<Vec<Sender<Note>>>>,
+    health: Arc<RwLock<Option<HealthSource>>>,
+    rpc: Arc<RwLock<Option<RpcSource>>>,
     socket_file: PathBuf,
     bound: bool,
 }
 
-fn handle_conn(conn_id: uuid::Uuid, mut stream: UnixStream, rx: Receiver<Note>) {
-    let tag = format!("uds-conn-{}", conn_id.to_hyphenated().to_string());
+fn conn_tag(conn_id: uuid::Uuid) -> String {
+    format!("uds-conn-{}", conn_id.to_hyphenated().to_string())
+}
+
+fn write_line<T: Serialize>(stream: &Mutex<UnixStream>, msg: &T) -> std::io::Result<()> {
+    let mut blob = serde_json::to_vec(msg)?;
+    blob.push(b'\n');
+    stream.lock().write_all(blob.as_slice())
+}
+
+/// Writes only whole lines to a peer's connection, so that they can't be interleaved with the notes that are sent to
+/// it from another thread
+struct LineWriter<'a> {
+    stream: &'a Mutex<UnixStream>,
+    buf: Vec<u8>,
+}
+
+impl Write for LineWriter<'_> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        if let Some(end) = self.buf.iter().rposition(|b| *b == b'\n') {
+            let lines: Vec<u8> = self.buf.drain(..=end).collect();
+            self.stream.lock().write_all(&lines)?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn handle_conn(conn_id: uuid::Uuid, stream: Arc<Mutex<UnixStream>>, rx: Receiver<Note>) {
+    let tag = conn_tag(conn_id);
     for note in rx {
         debug!(target: &tag, "Sending note {:?} to peer", note);
-        let mut blob = serde_json::to_vec(&note).unwrap();
-        blob.push(b'\n');
-        match stream.write_all(blob.as_slice()) {
+        match write_line(&stream, &note) {
             Err(e) => {
                 error!(target: &tag, "Error writing note to peer: {:?}", e);
                 return;
@@ -59,6 +114,117 @@ fn handle_conn(conn_id: uuid::Uuid, mut stream: UnixStream, rx: Receiver<Note>)
     debug!(target: &tag, "Connection TX closed");
 }
 
+/// Answers the requests that a peer sends, until it hangs up
+fn handle_requests(
+    conn_id: uuid::Uuid,
+    reader: UnixStream,
+    writer: Arc<Mutex<UnixStream>>,
+    health: Arc<RwLock<Option<HealthSource>>>,
+    rpc: Arc<RwLock<Option<RpcSource>>>,
+) {
+    let tag = conn_tag(conn_id);
+    for line in BufReader::new(reader).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                debug!(target: &tag, "Stopped reading requests: {:?}", e);
+                break;
+            }
+        };
+        debug!(target: &tag, "Got request {}", line.trim());
+
+        // the source is cloned out, so that a long call doesn't hold up the notifier
+        let rpc_source = rpc.read().clone();
+        let written = match (serde_json::from_str::<UDSRequest>(&line), rpc_source) {
+            (Ok(UDSRequest::Health), _) => {
+                let response = match &*health.read() {
+                    Some(source) => match source() {
+                        Ok(report) => UDSResponse::Health(report),
+                        Err(e) => UDSResponse::Error(e.to_string()),
+                    },
+                    None => UDSResponse::Error("Health isn't being served yet".to_string()),
+                };
+                write_line(&writer, &response)
+            }
+            (Err(_), Some(source)) => {
+                let mut progress = LineWriter {
+                    stream: &writer,
+                    buf: vec![],
+                };
+                match source(line.trim(), &mut progress) {
+                    Some(response) => write_line(&writer, &response),
+                    None => Ok(()),
+                }
+            }
+            (Err(e), None) => {
+                write_line(&writer, &UDSResponse::Error(format!("Bad request: {}", e)))
+            }
+        };
+        if let Err(e) = written {
+            error!(target: &tag, "Error writing response to peer: {:?}", e);
+            break;
+        }
+    }
+    debug!(target: &tag, "Connection RX closed");
+}
+
+/// Asks the daemon listening on `socket_file` for its health
+pub fn request_health(socket_file: &Path) -> Result<Health, Box<dyn Error>> {
+    let tag = "uds-request";
+    let mut stream = UnixStream::connect(socket_file)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut blob = serde_json::to_vec(&UDSRequest::Health)?;
+    blob.push(b'\n');
+    stream.write_all(blob.as_slice())?;
+
+    // notes can come in before the answer, and they're skipped
+    let mut reader = BufReader::new(stream);
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err("The daemon hung up without answering".into());
+        }
+        match serde_json::from_str(&line) {
+            Ok(UDSResponse::Health(report)) => return Ok(report),
+            Ok(UDSResponse::Error(e)) => return Err(e.into()),
+            Err(_) => trace!(target: tag, "Skipping {}", line.trim()),
+        }
+    }
+}
+
+/// Calls `method` with `params` over JSON-RPC on the daemon listening on `socket_file`, returning its result
+pub fn request_rpc(
+    socket_file: &Path,
+    method: &str,
+    params: Value,
+) -> Result<Value, Box<dyn Error>> {
+    let tag = "uds-request";
+    let mut stream = UnixStream::connect(socket_file)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let request = json!({"jsonrpc": "2.0", "id": 1, "method": method, "params": params});
+    let mut blob = serde_json::to_vec(&request)?;
+    blob.push(b'\n');
+    stream.write_all(blob.as_slice())?;
+
+    // notes and progress notifications can come in before the answer, and they're skipped
+    let mut reader = BufReader::new(stream);
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err("The daemon hung up without answering".into());
+        }
+        let response: Value = serde_json::from_str(&line).unwrap_or(Value::Null);
+        if response.get("id") != Some(&json!(1)) {
+            trace!(target: tag, "Skipping {}", line.trim());
+            continue;
+        }
+        return match response.get("error") {
+            Some(err) => Err(err["message"].as_str().unwrap_or("Unknown error").into()),
+            None => Ok(response["result"].clone()),
+        };
+    }
+}
+
 impl UDSNotifier {
     /// If `bind` is false, we won't actually bind to the socket file. This is needed in cases
     /// where the cli needs to create a `UDSNotifier` purely to get access to `.listener()`, but
@@ -68,6 +234,8 @@ impl UDSNotifier {
     pub fn new(socket_file: PathBuf, bind: bool) -> std::io::Result<Self> {
         let tag = "uds-notifier";
         let peers = Arc::new(Mutex::new(Vec::new()));
+        let health: Arc<RwLock<Option<HealthSource>>> = Default::default();
+        let rpc: Arc<RwLock<Option<RpcSource>>> = Default::default();
 
         if bind {
             if socket_file.exists() {
@@ -80,8 +248,12 @@ impl UDSNotifier {
             }
 
             let socket = UnixListener::bind(&socket_file)?;
+            // anybody who can connect can make requests of the mount, so only its owner may
+            std::fs::set_permissions(&socket_file, std::fs::Permissions::from_mode(0o600))?;
 
             let peers_t1 = peers.clone();
+            let health_t1 = health.clone();
+            let rpc_t1 = rpc.clone();
             spawn(move || {
                 let tag = "uds-conn-listener";
                 debug!(target: tag, "Starting listener thread");
@@ -91,10 +263,24 @@ impl UDSNotifier {
                         Ok(stream) => {
                             let conn_id = uuid::Uuid::new_v4();
                             debug!(target: tag, "Got a new connection {}", conn_id);
+                            let reader = match stream.try_clone() {
+                                Ok(reader) => reader,
+                                Err(e) => {
+                                    error!(target: tag, "Error cloning peer connection: {:?}", e);
+                                    continue;
+                                }
+                            };
+                            let writer = Arc::new(Mutex::new(stream));
                             let (tx, rx): (Sender<Note>, _) = channel();
-                            let mut guard = peers_t1.lock();
-                            guard.push(tx);
-                            spawn(move || handle_conn(conn_id, stream, rx));
+                            peers_t1.lock().push(tx);
+
+                            let req_writer = writer.clone();
+                            let req_health = health_t1.clone();
+                            let req_rpc = rpc_t1.clone();
+                            spawn(move || handle_conn(conn_id, writer, rx));
+                            spawn(move || {
+                                handle_requests(conn_id, reader, req_writer, req_health, req_rpc)
+                            });
                         }
                         Err(e) => error!(target: tag, "Error getting peer connection: {:?}", e),
                     }
@@ -106,6 +292,8 @@ impl UDSNotifier {
         Ok(Self {
             tag: tag.to_string(),
             peers,
+            health,
+            rpc,
             socket_file,
             bound: bind,
         })
@@ -155,15 +343,83 @@ impl Notifier for UDSNotifier {
         Ok(())
     }
 
+    fn protected_source(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        info!(target: &self.tag, "protected_source");
+        self.send_message(Note::ProtectedSource(path.to_owned()))?;
+        Ok(())
+    }
+
     fn tag_to_tg(&self, tag: &str) -> Result<(), Box<dyn Error>> {
         info!(target: &self.tag, "tag_to_tg");
         self.send_message(Note::TagToTagGroup(tag.to_owned()))?;
         Ok(())
     }
 
+    fn warm_progress(&self, done: usize, total: usize) -> Result<(), Box<dyn Error>> {
+        debug!(target: &self.tag, "warm_progress {}/{}", done, total);
+        self.send_message(Note::WarmProgress(done, total))?;
+        Ok(())
+    }
+
+    fn op_failed(&self, path: &Path, reason: &str) -> Result<(), Box<dyn Error>> {
+        info!(target: &self.tag, "op_failed");
+        self.send_message(Note::OpFailed(path.to_owned(), reason.to_owned()))?;
+        Ok(())
+    }
+
+    fn merged(&self, src: &str, dst: &str, num_files: usize) -> Result<(), Box<dyn Error>> {
+        info!(target: &self.tag, "merged");
+        self.send_message(Note::Merged(src.to_owned(), dst.to_owned(), num_files))?;
+        Ok(())
+    }
+
+    fn retagged(
+        &self,
+        path: &Path,
+        added: &[String],
+        removed: &[String],
+    ) -> Result<(), Box<dyn Error>> {
+        info!(target: &self.tag, "retagged");
+        self.send_message(Note::Retagged(
+            path.to_owned(),
+            added.to_vec(),
+            removed.to_vec(),
+        ))?;
+        Ok(())
+    }
+
+    fn disk_full(&self, db_path: &Path, free_bytes: u64) -> Result<(), Box<dyn Error>> {
+        info!(target: &self.tag, "disk_full");
+        self.send_message(Note::DiskFull(db_path.to_owned(), free_bytes))?;
+        Ok(())
+    }
+
     fn listener(&self) -> Result<Self::Listener, Box<dyn Error>> {
         Ok(UDSListener::new(self.socket_file.clone())?)
     }
+
+    fn serve_health(&self, source: HealthSource) {
+        debug!(target: &self.tag, "Serving health");
+        *self.health.write() = Some(source);
+    }
+
+    fn serve_rpc(&self, source: RpcSource) {
+        debug!(target: &self.tag, "Serving rpc");
+        *self.rpc.write() = Some(source);
+    }
+
+    fn shutdown(&mut self) -> Result<(), Box<dyn Error>> {
+        info!(target: &self.tag, "shutdown");
+        // dropping the senders ends each peer's connection thread
+        self.peers.lock().clear();
+        if self.bound {
+            self.bound = false;
+            if self.socket_file.exists() {
+                std::fs::remove_file(&self.socket_file)?;
+            }
+        }
+        Ok(())
+    }
 }
 
 pub struct UDSListener {
@@ -0,0 +1,187 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Tag providers are external programs that contribute tags for a file when it's linked, like tags from a photo's
+//! EXIF data, a song's ID3 tags, or a file's MIME type.  They're declared in a collection's config:
+//!
+//! ```toml
+//! [[providers]]
+//! name = "exif"
+//! command = ["supertag-exif", "--prefix", "camera:"]
+//! timeout_ms = 2000
+//! ```
+//!
+//! Each provider is started once and kept running, and speaks JSON-RPC 2.0 over its stdin and stdout, one message per
+//! line.  For every linked file, it's sent a `tags_for` request, and answers with the tags for that file:
+//!
+//! ```text
+//! -> {"jsonrpc": "2.0", "id": 1, "method": "tags_for", "params": {"path": "/photos/beach.jpg"}}
+//! <- {"jsonrpc": "2.0", "id": 1, "result": {"tags": ["camera:x100v", "iso:200"]}}
+//! ```
+//!
+//! A provider should exit when its stdin is closed.  One that fails, answers with an error, or takes too long is
+//! logged and restarted on the next file, and the file is linked without its tags, so that a broken provider never
+//! keeps files from being tagged.
+
+pub mod subprocess;
+
+pub use subprocess::SubprocessProvider;
+
+use crate::common::settings::config::Provider;
+use parking_lot::Mutex;
+use std::fmt;
+use std::path::Path;
+use tracing::{debug, warn};
+
+const PROVIDERS_TAG: &str = "providers";
+
+/// Something that can tag files by looking at them
+pub trait TagProvider: Send {
+    fn name(&self) -> &str;
+
+    /// The tags for the file at `path`
+    fn tags_for(&mut self, path: &Path) -> Result<Vec<String>, ProviderError>;
+}
+
+#[derive(Debug)]
+pub enum ProviderError {
+    /// The provider couldn't be started, or talking to it failed
+    Io(std::io::Error),
+    /// The provider didn't answer within its timeout
+    Timeout,
+    /// The provider exited
+    Exited,
+    /// The provider answered with something that isn't a response to our request
+    Protocol(String),
+    /// The provider answered with an error
+    Failed(String),
+}
+
+impl fmt::Display for ProviderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProviderError::Io(e) => write!(f, "{}", e),
+            ProviderError::Timeout => write!(f, "it didn't answer in time"),
+            ProviderError::Exited => write!(f, "it exited"),
+            ProviderError::Protocol(reason) => write!(f, "bad response, {}", reason),
+            ProviderError::Failed(message) => write!(f, "it failed, {}", message),
+        }
+    }
+}
+
+impl std::error::Error for ProviderError {}
+
+impl From<std::io::Error> for ProviderError {
+    fn from(e: std::io::Error) -> Self {
+        ProviderError::Io(e)
+    }
+}
+
+/// The running providers of a collection, along with the config they were started from, so that they can be
+/// restarted when it changes
+#[derive(Default)]
+pub struct Providers {
+    running: Mutex<(Vec<Provider>, Vec<Box<dyn TagProvider>>)>,
+}
+
+impl Providers {
+    /// The tags that the providers in `configs` give the file at `path`, in the order the providers are declared.  A
+    /// provider that fails is logged and contributes nothing.
+    pub fn tags_for(&self, configs: &[Provider], path: &Path) -> Vec<String> {
+        if configs.is_empty() {
+            return vec![];
+        }
+
+        let mut running = self.running.lock();
+        if running.0 != configs {
+            debug!(target: PROVIDERS_TAG, "Starting providers {:?}", configs);
+            *running = (
+                configs.to_vec(),
+                configs
+                    .iter()
+                    .map(|conf| {
+                        Box::new(SubprocessProvider::new(conf.clone())) as Box<dyn TagProvider>
+                    })
+                    .collect(),
+            );
+        }
+
+        let mut tags: Vec<String> = vec![];
+        for provider in running.1.iter_mut() {
+            match provider.tags_for(path) {
+                Ok(provided) => {
+                    debug!(
+                        target: PROVIDERS_TAG,
+                        "Provider {} tags {:?} with {:?}",
+                        provider.name(),
+                        path,
+                        provided
+                    );
+                    for tag in provided {
+                        if !tags.contains(&tag) {
+                            tags.push(tag);
+                        }
+                    }
+                }
+                Err(e) => warn!(
+                    target: PROVIDERS_TAG,
+                    "Provider {} couldn't tag {:?}: {}",
+                    provider.name(),
+                    path,
+                    e
+                ),
+            }
+        }
+        tags
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(name: &str, script: &str) -> Provider {
+        Provider {
+            name: name.to_string(),
+            command: vec!["sh".to_string(), "-c".to_string(), script.to_string()],
+            timeout_ms: Some(1000),
+        }
+    }
+
+    #[test]
+    fn test_providers_skip_failures() {
+        let tagger = r#"while read -r line; do
+    id=$(echo "$line" | sed 's/.*"id":\([0-9]*\).*/\1/')
+    echo "{\"jsonrpc\":\"2.0\",\"id\":$id,\"result\":{\"tags\":[\"a\",\"b\"]}}"
+done"#;
+        let configs = vec![
+            config("first", tagger),
+            config("broken", "exit 1"),
+            config("second", tagger),
+        ];
+
+        let providers = Providers::default();
+        for _ in 0..2 {
+            assert_eq!(
+                providers.tags_for(&configs, Path::new("/f")),
+                vec!["a", "b"]
+            );
+        }
+        assert!(providers.tags_for(&[], Path::new("/f")).is_empty());
+    }
+}
@@ -0,0 +1,243 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use super::{ProviderError, TagProvider, PROVIDERS_TAG};
+use crate::common::settings::config::Provider;
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::Duration;
+use tracing::{debug, info};
+
+/// How long a provider has to answer for one file, unless its config says otherwise
+pub const DEFAULT_TIMEOUT_MS: u64 = 5000;
+
+/// A provider program that we've started
+struct Running {
+    child: Child,
+    stdin: ChildStdin,
+    /// The lines the provider writes, read on their own thread so that waiting for them can time out
+    lines: Receiver<std::io::Result<String>>,
+}
+
+/// A tag provider that's an external program, talked to with JSON-RPC over its stdin and stdout.  It's started on the
+/// first request, and restarted on the request after anything goes wrong.
+pub struct SubprocessProvider {
+    config: Provider,
+    running: Option<Running>,
+    next_id: u64,
+}
+
+impl SubprocessProvider {
+    pub fn new(config: Provider) -> Self {
+        Self {
+            config,
+            running: None,
+            next_id: 1,
+        }
+    }
+
+    fn start(&self) -> Result<Running, ProviderError> {
+        let (program, args) = self
+            .config
+            .command
+            .split_first()
+            .ok_or_else(|| ProviderError::Protocol("it has no command".to_string()))?;
+        info!(
+            target: PROVIDERS_TAG,
+            "Starting provider {}: {:?}", self.config.name, self.config.command
+        );
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+
+        let stdin = child.stdin.take().expect("Provider stdin was piped");
+        let stdout = child.stdout.take().expect("Provider stdout was piped");
+        let (sender, lines) = mpsc::channel();
+        std::thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Running {
+            child,
+            stdin,
+            lines,
+        })
+    }
+
+    /// Sends `path` to the running provider, and waits for its answer
+    fn request(&mut self, path: &Path) -> Result<Vec<String>, ProviderError> {
+        if self.running.is_none() {
+            self.running = Some(self.start()?);
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        let timeout = Duration::from_millis(self.config.timeout_ms.unwrap_or(DEFAULT_TIMEOUT_MS));
+        let running = self.running.as_mut().expect("Provider was just started");
+
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": "tags_for",
+            "params": {"path": path.to_string_lossy()},
+        });
+        writeln!(running.stdin, "{}", request)?;
+        running.stdin.flush()?;
+
+        let line = match running.lines.recv_timeout(timeout) {
+            Ok(line) => line?,
+            Err(RecvTimeoutError::Timeout) => return Err(ProviderError::Timeout),
+            Err(RecvTimeoutError::Disconnected) => return Err(ProviderError::Exited),
+        };
+        parse_response(&line, id)
+    }
+
+    fn stop(&mut self) {
+        if let Some(mut running) = self.running.take() {
+            debug!(target: PROVIDERS_TAG, "Stopping provider {}", self.config.name);
+            let _ = running.child.kill();
+            let _ = running.child.wait();
+        }
+    }
+}
+
+impl TagProvider for SubprocessProvider {
+    fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    fn tags_for(&mut self, path: &Path) -> Result<Vec<String>, ProviderError> {
+        let result = self.request(path);
+        // after a failure, we can't know what the provider will say next, so it starts over
+        if result.is_err() {
+            self.stop();
+        }
+        result
+    }
+}
+
+impl Drop for SubprocessProvider {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Takes the tags out of the provider's response to request `id`
+fn parse_response(line: &str, id: u64) -> Result<Vec<String>, ProviderError> {
+    let response: Value =
+        serde_json::from_str(line).map_err(|e| ProviderError::Protocol(e.to_string()))?;
+    if response.get("id").and_then(Value::as_u64) != Some(id) {
+        return Err(ProviderError::Protocol(format!(
+            "expected a response to request {}",
+            id
+        )));
+    }
+    if let Some(error) = response.get("error") {
+        let message = error
+            .get("message")
+            .and_then(Value::as_str)
+            .unwrap_or("no message");
+        return Err(ProviderError::Failed(message.to_string()));
+    }
+
+    let tags = response
+        .pointer("/result/tags")
+        .and_then(Value::as_array)
+        .ok_or_else(|| ProviderError::Protocol("no tags in its result".to_string()))?;
+    tags.iter()
+        .map(|tag| {
+            tag.as_str()
+                .map(str::to_string)
+                .ok_or_else(|| ProviderError::Protocol(format!("{} isn't a tag", tag)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A provider that tags every file with the name of the directory it's in
+    const DIR_PROVIDER: &str = r#"while read -r line; do
+    id=$(echo "$line" | sed 's/.*"id":\([0-9]*\).*/\1/')
+    dir=$(echo "$line" | sed 's/.*"path":"\(.*\)\/[^/]*".*/\1/')
+    echo "{\"jsonrpc\":\"2.0\",\"id\":$id,\"result\":{\"tags\":[\"${dir##*/}\"]}}"
+done"#;
+
+    fn provider(script: &str, timeout_ms: u64) -> SubprocessProvider {
+        SubprocessProvider::new(Provider {
+            name: "test".to_string(),
+            command: vec!["sh".to_string(), "-c".to_string(), script.to_string()],
+            timeout_ms: Some(timeout_ms),
+        })
+    }
+
+    #[test]
+    fn test_provider_tags() -> Result<(), ProviderError> {
+        let mut dirs = provider(DIR_PROVIDER, 5000);
+        assert_eq!(
+            dirs.tags_for(Path::new("/photos/beach.jpg"))?,
+            vec!["photos"]
+        );
+        assert_eq!(dirs.tags_for(Path::new("/music/song.mp3"))?, vec!["music"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_provider_failures() {
+        let mut slow = provider("sleep 5", 100);
+        assert!(matches!(
+            slow.tags_for(Path::new("/a")),
+            Err(ProviderError::Timeout)
+        ));
+
+        let mut exits = provider("read -r line", 5000);
+        assert!(matches!(
+            exits.tags_for(Path::new("/a")),
+            Err(ProviderError::Exited)
+        ));
+
+        let mut fails = provider(
+            r#"read -r line; echo '{"jsonrpc":"2.0","id":1,"error":{"code":-32000,"message":"no exif"}}'"#,
+            5000,
+        );
+        assert!(matches!(
+            fails.tags_for(Path::new("/a")),
+            Err(ProviderError::Failed(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_response() {
+        assert_eq!(
+            parse_response(r#"{"jsonrpc":"2.0","id":3,"result":{"tags":["a","b"]}}"#, 3).unwrap(),
+            vec!["a", "b"]
+        );
+        assert!(parse_response(r#"{"jsonrpc":"2.0","id":2,"result":{"tags":[]}}"#, 3).is_err());
+        assert!(parse_response(r#"{"jsonrpc":"2.0","id":3,"result":{"tags":[1]}}"#, 3).is_err());
+        assert!(parse_response("not json", 3).is_err());
+    }
+}
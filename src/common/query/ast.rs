@@ -0,0 +1,287 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! The tree that an expression parses into.  `!` binds tightest, then `&`, then `|`, and parentheses group as usual,
+//! so `a|b&!c` is `a|(b&(!c))`.
+
+use super::tokenizer::{self, Token};
+use super::QueryError;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Tag(String),
+    Not(Box<Expr>),
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+}
+
+impl Expr {
+    /// Every tag name in the expression, once each, in the order they first appear
+    pub fn tag_names(&self) -> Vec<&str> {
+        let mut names = vec![];
+        self.collect_names(&mut names);
+        names
+    }
+
+    fn collect_names<'a>(&'a self, names: &mut Vec<&'a str>) {
+        match self {
+            Expr::Tag(name) => {
+                if !names.contains(&name.as_str()) {
+                    names.push(name);
+                }
+            }
+            Expr::Not(inner) => inner.collect_names(names),
+            Expr::And(terms) | Expr::Or(terms) => {
+                for term in terms {
+                    term.collect_names(names);
+                }
+            }
+        }
+    }
+
+    /// How many negations the expression has
+    pub fn negations(&self) -> usize {
+        match self {
+            Expr::Tag(_) => 0,
+            Expr::Not(inner) => 1 + inner.negations(),
+            Expr::And(terms) | Expr::Or(terms) => terms.iter().map(Expr::negations).sum(),
+        }
+    }
+
+    /// Renders the expression in words, ie `rust AND (cli OR tui) AND NOT wip`, like `TagCollection::to_query`
+    pub fn to_query(&self) -> String {
+        match self {
+            Expr::Tag(name) => name.to_string(),
+            Expr::Not(inner) => match **inner {
+                Expr::And(_) | Expr::Or(_) => format!("NOT ({})", inner.to_query()),
+                _ => format!("NOT {}", inner.to_query()),
+            },
+            Expr::And(terms) => terms
+                .iter()
+                .map(|term| match term {
+                    Expr::Or(_) => format!("({})", term.to_query()),
+                    _ => term.to_query(),
+                })
+                .collect::<Vec<_>>()
+                .join(" AND "),
+            Expr::Or(terms) => terms
+                .iter()
+                .map(Expr::to_query)
+                .collect::<Vec<_>>()
+                .join(" OR "),
+        }
+    }
+
+    /// The same expression, with every tag name replaced by what `f` returns for it
+    pub fn try_map_tags<E, F>(&self, f: &mut F) -> Result<Expr, E>
+    where
+        F: FnMut(&str) -> Result<String, E>,
+    {
+        Ok(match self {
+            Expr::Tag(name) => Expr::Tag(f(name)?),
+            Expr::Not(inner) => Expr::Not(Box::new(inner.try_map_tags(f)?)),
+            Expr::And(terms) => Expr::And(
+                terms
+                    .iter()
+                    .map(|t| t.try_map_tags(f))
+                    .collect::<Result<_, _>>()?,
+            ),
+            Expr::Or(terms) => Expr::Or(
+                terms
+                    .iter()
+                    .map(|t| t.try_map_tags(f))
+                    .collect::<Result<_, _>>()?,
+            ),
+        })
+    }
+}
+
+/// Renders the expression back into the syntax it was parsed from, with only the parentheses that are needed
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Tag(name) => {
+                if name.chars().any(tokenizer::is_special) || name.trim() != name {
+                    write!(f, "{}{}{}", tokenizer::QUOTE, name, tokenizer::QUOTE)
+                } else {
+                    write!(f, "{}", name)
+                }
+            }
+            Expr::Not(inner) => match **inner {
+                Expr::And(_) | Expr::Or(_) => write!(f, "{}({})", tokenizer::NOT, inner),
+                _ => write!(f, "{}{}", tokenizer::NOT, inner),
+            },
+            Expr::And(terms) => {
+                for (i, term) in terms.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "{}", tokenizer::AND)?;
+                    }
+                    match term {
+                        Expr::Or(_) => write!(f, "({})", term)?,
+                        _ => write!(f, "{}", term)?,
+                    }
+                }
+                Ok(())
+            }
+            Expr::Or(terms) => {
+                let rendered = terms.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+                write!(f, "{}", rendered.join(&tokenizer::OR.to_string()))
+            }
+        }
+    }
+}
+
+/// A recursive descent parser over the tokens of one expression
+struct Parser<'a> {
+    input: &'a str,
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn err(&self, reason: &'static str) -> QueryError {
+        QueryError::new(self.input, reason)
+    }
+
+    fn or(&mut self) -> Result<Expr, QueryError> {
+        let mut terms = vec![self.and()?];
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            terms.push(self.and()?);
+        }
+        Ok(if terms.len() == 1 {
+            terms.remove(0)
+        } else {
+            Expr::Or(terms)
+        })
+    }
+
+    fn and(&mut self) -> Result<Expr, QueryError> {
+        let mut terms = vec![self.unary()?];
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            terms.push(self.unary()?);
+        }
+        Ok(if terms.len() == 1 {
+            terms.remove(0)
+        } else {
+            Expr::And(terms)
+        })
+    }
+
+    fn unary(&mut self) -> Result<Expr, QueryError> {
+        match self.next() {
+            Some(Token::Not) => Ok(Expr::Not(Box::new(self.unary()?))),
+            Some(Token::Open) => {
+                let inner = self.or()?;
+                match self.next() {
+                    Some(Token::Close) => Ok(inner),
+                    _ => Err(self.err("missing ')'")),
+                }
+            }
+            Some(Token::Name(name)) => Ok(Expr::Tag(name)),
+            Some(_) => Err(self.err("expected a tag name, '!' or '('")),
+            None => Err(self.err("ends where a tag name was expected")),
+        }
+    }
+}
+
+pub fn parse_expr(input: &str) -> Result<Expr, QueryError> {
+    let mut parser = Parser {
+        input,
+        tokens: tokenizer::tokenize(input)?,
+        pos: 0,
+    };
+    let expr = parser.or()?;
+    match parser.peek() {
+        None => Ok(expr),
+        Some(Token::Close) => Err(parser.err("unmatched ')'")),
+        Some(_) => Err(parser.err("expected '&' or '|' between terms")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(name: &str) -> Expr {
+        Expr::Tag(name.to_string())
+    }
+
+    #[test]
+    fn test_parse_expr() {
+        assert_eq!(
+            parse_expr("rust&(cli|tui)&!wip").unwrap(),
+            Expr::And(vec![
+                tag("rust"),
+                Expr::Or(vec![tag("cli"), tag("tui")]),
+                Expr::Not(Box::new(tag("wip"))),
+            ])
+        );
+        // precedence, so that these don't need any parentheses
+        assert_eq!(
+            parse_expr("a|b&!c").unwrap(),
+            Expr::Or(vec![
+                tag("a"),
+                Expr::And(vec![tag("b"), Expr::Not(Box::new(tag("c")))]),
+            ])
+        );
+        assert_eq!(parse_expr("((a))").unwrap(), tag("a"));
+
+        for bad in &["", "a&", "&a", "a b&(c", "a)", "(a|b", "a!b", "!"] {
+            assert!(parse_expr(bad).is_err(), "{:?} should be an error", bad);
+        }
+    }
+
+    #[test]
+    fn test_display_round_trip() {
+        for expr in &[
+            "rust&(cli|tui)&!wip",
+            "a|b&!c",
+            "!(a|b)&!(c&d)",
+            r#""a|b"&" c""#,
+        ] {
+            let parsed = parse_expr(expr).unwrap();
+            assert_eq!(parsed.to_string(), *expr);
+            assert_eq!(parse_expr(&parsed.to_string()).unwrap(), parsed);
+        }
+        assert_eq!(parse_expr("( a & b ) | c").unwrap().to_string(), "a&b|c");
+    }
+
+    #[test]
+    fn test_tag_names() {
+        let expr = parse_expr("a&(b|!a)&!!c").unwrap();
+        assert_eq!(expr.tag_names(), vec!["a", "b", "c"]);
+        assert_eq!(expr.negations(), 3);
+        assert_eq!(expr.to_query(), "a AND (b OR NOT a) AND NOT NOT c");
+
+        let mapped: Result<Expr, ()> = expr.try_map_tags(&mut |name| Ok(name.to_uppercase()));
+        assert_eq!(mapped.unwrap().tag_names(), vec!["A", "B", "C"]);
+    }
+}
@@ -0,0 +1,84 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Boolean tag expressions give a single path component the full logic that chained intersections can't express, like
+//! `/?(rust&(cli|tui)&!wip)/`, which holds the files tagged `rust`, with `cli` or `tui`, but not `wip`.  `&` is and,
+//! `|` is or, `!` is not, and parentheses group.  A tag name containing one of those can be written in double quotes.
+
+pub mod ast;
+pub mod tokenizer;
+
+pub use ast::{parse_expr, Expr};
+
+use crate::common::constants::{EXPRESSION_TAG_PREFIX, EXPRESSION_TAG_SUFFIX};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryError {
+    pub expr: String,
+    pub reason: &'static str,
+}
+
+impl QueryError {
+    fn new(expr: &str, reason: &'static str) -> Self {
+        Self {
+            expr: expr.to_string(),
+            reason,
+        }
+    }
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Bad expression {:?}: {}", self.expr, self.reason)
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+/// The expression inside a path component like `?(a|b)`, or None if the component isn't an expression
+pub fn expression_body(component: &str) -> Option<&str> {
+    component
+        .strip_prefix(EXPRESSION_TAG_PREFIX)?
+        .strip_suffix(EXPRESSION_TAG_SUFFIX)
+}
+
+/// Parses a path component like `?(a|b)` into its expression.  A component that only looks like an expression, but
+/// doesn't parse, is an error.
+pub fn parse_component(component: &str) -> Option<Result<Expr, QueryError>> {
+    expression_body(component).map(parse_expr)
+}
+
+/// Renders `expr` as the path component that parses back into it
+pub fn to_component(expr: &Expr) -> String {
+    format!("{}{}{}", EXPRESSION_TAG_PREFIX, expr, EXPRESSION_TAG_SUFFIX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_component() {
+        let expr = parse_component("?(a|!b)").unwrap().unwrap();
+        assert_eq!(to_component(&expr), "?(a|!b)");
+        assert!(parse_component("?(a|)").unwrap().is_err());
+        assert!(parse_component("a|b").is_none());
+        assert!(parse_component("?a").is_none());
+    }
+}
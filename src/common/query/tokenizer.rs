@@ -0,0 +1,127 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Splits the body of an expression into its operators and tag names.  A name is everything between two operators,
+//! with the whitespace around it trimmed, or anything between double quotes, for names that contain an operator.
+
+use super::QueryError;
+
+pub const AND: char = '&';
+pub const OR: char = '|';
+pub const NOT: char = '!';
+pub const OPEN: char = '(';
+pub const CLOSE: char = ')';
+pub const QUOTE: char = '"';
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Token {
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+    Name(String),
+}
+
+/// Whether `c` can't appear in an unquoted name
+pub fn is_special(c: char) -> bool {
+    matches!(c, AND | OR | NOT | OPEN | CLOSE | QUOTE)
+}
+
+pub fn tokenize(input: &str) -> Result<Vec<Token>, QueryError> {
+    let mut tokens = vec![];
+    let mut chars = input.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            AND | OR | NOT | OPEN | CLOSE => {
+                chars.next();
+                tokens.push(match c {
+                    AND => Token::And,
+                    OR => Token::Or,
+                    NOT => Token::Not,
+                    OPEN => Token::Open,
+                    _ => Token::Close,
+                });
+            }
+            QUOTE => {
+                chars.next();
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        Some(QUOTE) => break,
+                        Some(c) => name.push(c),
+                        None => return Err(QueryError::new(input, "unterminated quote")),
+                    }
+                }
+                if name.is_empty() {
+                    return Err(QueryError::new(input, "empty quoted name"));
+                }
+                tokens.push(Token::Name(name));
+            }
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            _ => {
+                let mut name = String::new();
+                while let Some(&c) = chars.peek() {
+                    if is_special(c) {
+                        break;
+                    }
+                    name.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Name(name.trim_end().to_string()));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(n: &str) -> Token {
+        Token::Name(n.to_string())
+    }
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(
+            tokenize("rust & (cli|tui) &!my tag").unwrap(),
+            vec![
+                name("rust"),
+                Token::And,
+                Token::Open,
+                name("cli"),
+                Token::Or,
+                name("tui"),
+                Token::Close,
+                Token::And,
+                Token::Not,
+                name("my tag"),
+            ]
+        );
+        assert_eq!(
+            tokenize(r#""a|b"&c"#).unwrap(),
+            vec![name("a|b"), Token::And, name("c")]
+        );
+        assert!(tokenize(r#""a|b"#).is_err());
+        assert!(tokenize(r#"a&"""#).is_err());
+    }
+}
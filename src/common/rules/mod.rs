@@ -0,0 +1,139 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Tagging rules automatically add tags to files as they're linked, based on the file's name or path.  A collection's
+//! rules live under the `rules` key of its config file, and are re-read whenever that file changes, so they can be
+//! edited while the collection is mounted:
+//!
+//! ```toml
+//! rules = [
+//!     "*.pdf -> documents",
+//!     "path contains /Camera/ -> photos",
+//! ]
+//! ```
+
+pub mod parse;
+
+pub use parse::{parse_rule, Matcher, Rule, RuleError};
+
+use parking_lot::RwLock;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tracing::{debug, info, warn};
+
+const RULES_TAG: &str = "rules";
+
+/// The key in a collection's config file that holds its rules
+pub const RULES_KEY: &str = "rules";
+
+#[derive(Default)]
+pub struct Rules {
+    source: Option<PathBuf>,
+    loaded: RwLock<Loaded>,
+}
+
+/// The parsed rules, along with the mtime and size of the file they were parsed from, so we can tell when it changes
+#[derive(Default)]
+struct Loaded {
+    stamp: Option<(SystemTime, u64)>,
+    rules: Vec<Rule>,
+}
+
+impl Rules {
+    pub fn new(source: PathBuf) -> Self {
+        Self {
+            source: Some(source),
+            loaded: Default::default(),
+        }
+    }
+
+    /// The tags that the rules assign to the file at `path`, in the order the rules were declared
+    pub fn tags_for(&self, path: &Path) -> Vec<String> {
+        self.reload_if_changed();
+
+        let loaded = self.loaded.read();
+        let mut tags: Vec<String> = vec![];
+        for rule in loaded.rules.iter().filter(|r| r.matcher.matches(path)) {
+            debug!(target: RULES_TAG, "{:?} matches {:?}", path, rule);
+            for tag in &rule.tags {
+                if !tags.contains(tag) {
+                    tags.push(tag.clone());
+                }
+            }
+        }
+        tags
+    }
+
+    fn reload_if_changed(&self) {
+        let source = match &self.source {
+            Some(source) => source,
+            None => return,
+        };
+
+        let stamp = std::fs::metadata(source)
+            .ok()
+            .and_then(|md| Some((md.modified().ok()?, md.len())));
+        if self.loaded.read().stamp == stamp {
+            return;
+        }
+
+        let mut loaded = self.loaded.write();
+        // another thread may have reloaded while we were waiting for the lock
+        if loaded.stamp == stamp {
+            return;
+        }
+
+        loaded.rules = match stamp {
+            Some(_) => load_rules(source),
+            None => vec![],
+        };
+        loaded.stamp = stamp;
+        info!(
+            target: RULES_TAG,
+            "Loaded {} rules from {:?}",
+            loaded.rules.len(),
+            source
+        );
+    }
+}
+
+/// Reads the rules from the config file at `source`.  Bad rules are logged and skipped, so that a typo in one rule
+/// doesn't disable the rest.
+pub fn load_rules(source: &Path) -> Vec<Rule> {
+    let mut conf = ::config::Config::new();
+    let raw: Vec<String> = match conf
+        .merge(::config::File::from(source.to_owned()))
+        .and_then(|conf| conf.get(RULES_KEY))
+    {
+        Ok(raw) => raw,
+        Err(::config::ConfigError::NotFound(_)) => vec![],
+        Err(e) => {
+            warn!(target: RULES_TAG, "Couldn't read rules from {:?}: {}", source, e);
+            vec![]
+        }
+    };
+
+    let mut rules = vec![];
+    for rule in raw {
+        match parse_rule(&rule) {
+            Ok(rule) => rules.push(rule),
+            Err(e) => warn!(target: RULES_TAG, "{}, skipping it", e),
+        }
+    }
+    rules
+}
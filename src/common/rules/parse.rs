@@ -0,0 +1,142 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Rules are written one per string, as `<matcher> -> <tag>, <tag>, ...`.  A matcher is either a glob like `*.pdf`,
+//! which is matched against the file's name, or `path contains <text>`, which is matched against the file's full path.
+
+use crate::common::glob_matches;
+use std::fmt;
+use std::path::Path;
+
+const ARROW: &str = "->";
+const PATH_CONTAINS: &str = "path contains";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Matcher {
+    Name(String),
+    PathContains(String),
+}
+
+impl Matcher {
+    pub fn matches(&self, path: &Path) -> bool {
+        match self {
+            Matcher::Name(pattern) => path
+                .file_name()
+                .map(|name| glob_matches(pattern, &name.to_string_lossy()))
+                .unwrap_or(false),
+            Matcher::PathContains(text) => path.to_string_lossy().contains(text.as_str()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    pub matcher: Matcher,
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleError {
+    pub rule: String,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for RuleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Bad rule {:?}: {}", self.rule, self.reason)
+    }
+}
+
+impl std::error::Error for RuleError {}
+
+pub fn parse_rule(rule: &str) -> Result<Rule, RuleError> {
+    let err = |reason| RuleError {
+        rule: rule.to_string(),
+        reason,
+    };
+
+    // split on the last arrow, so that a matcher is free to contain one
+    let idx = rule.rfind(ARROW).ok_or_else(|| err("missing '->'"))?;
+    let lhs = rule[..idx].trim();
+    let rhs = rule[idx + ARROW.len()..].trim();
+
+    let matcher = match lhs.strip_prefix(PATH_CONTAINS) {
+        Some(text) if text.is_empty() || text.starts_with(char::is_whitespace) => {
+            let text = text.trim();
+            if text.is_empty() {
+                return Err(err("'path contains' needs some text to look for"));
+            }
+            Matcher::PathContains(text.to_string())
+        }
+        _ if lhs.is_empty() => return Err(err("missing a pattern before '->'")),
+        _ => Matcher::Name(lhs.to_string()),
+    };
+
+    let mut tags: Vec<String> = vec![];
+    for tag in rhs.split(',').map(str::trim) {
+        if tag.is_empty() || tag.contains('/') {
+            return Err(err("tags must be non-empty and can't contain '/'"));
+        }
+        if !tags.iter().any(|t| t == tag) {
+            tags.push(tag.to_string());
+        }
+    }
+
+    Ok(Rule { matcher, tags })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rule() {
+        assert_eq!(
+            parse_rule("*.pdf -> documents").unwrap(),
+            Rule {
+                matcher: Matcher::Name("*.pdf".to_string()),
+                tags: vec!["documents".to_string()],
+            }
+        );
+        assert_eq!(
+            parse_rule("path contains /Camera/ -> photos, phone, photos").unwrap(),
+            Rule {
+                matcher: Matcher::PathContains("/Camera/".to_string()),
+                tags: vec!["photos".to_string(), "phone".to_string()],
+            }
+        );
+        assert!(parse_rule("*.pdf documents").is_err());
+        assert!(parse_rule("-> documents").is_err());
+        assert!(parse_rule("*.pdf -> ").is_err());
+        assert!(parse_rule("*.pdf -> a/b").is_err());
+        assert!(parse_rule("path contains  -> photos").is_err());
+    }
+
+    #[test]
+    fn test_matches() {
+        let pdf = parse_rule("*.pdf -> documents").unwrap();
+        assert!(pdf.matcher.matches(Path::new("/home/me/taxes.pdf")));
+        assert!(!pdf.matcher.matches(Path::new("/home/me/pdf/taxes.txt")));
+
+        let camera = parse_rule("path contains /Camera/ -> photos").unwrap();
+        assert!(camera
+            .matcher
+            .matches(Path::new("/sdcard/DCIM/Camera/IMG_001.jpg")));
+        assert!(!camera.matcher.matches(Path::new("/sdcard/Cameras.jpg")));
+    }
+}
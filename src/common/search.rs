@@ -0,0 +1,79 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Saved searches are stored as the same terms that `tag search` takes, plus optional extension and age filters.
+//! Their directories always show live results, so a search is run each time its directory is read.
+
+use crate::common::err::STagResult;
+use crate::common::settings::Settings;
+use crate::common::types::UtcDt;
+use crate::sql;
+use crate::sql::types::{SavedSearch, TaggedFile};
+use rusqlite::Connection;
+
+/// Runs `search`, returning the files that match its terms and pass its filters as of `now`
+pub fn run_saved_search(
+    settings: &Settings,
+    conn: &Connection,
+    search: &SavedSearch,
+    now: &UtcDt,
+) -> STagResult<Vec<TaggedFile>> {
+    let terms = search.terms.iter().map(String::as_str).collect::<Vec<_>>();
+    let tags = settings.query_to_tags(&terms)?;
+    Ok(sql::files_tagged_with(conn, &tags)?
+        .into_iter()
+        .filter(|tf| search.filter_matches(tf, now))
+        .collect())
+}
+
+/// Parses an age like `90s`, `30m`, `12h`, `7d`, or `2w` into seconds.  A bare number is taken as seconds.
+pub fn parse_age(age: &str) -> Option<i64> {
+    let age = age.trim();
+    let (num, unit) = match age.find(|c: char| !c.is_ascii_digit()) {
+        Some(idx) => age.split_at(idx),
+        None => (age, "s"),
+    };
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        "w" => 60 * 60 * 24 * 7,
+        _ => return None,
+    };
+    num.parse::<i64>().ok()?.checked_mul(multiplier)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_age() {
+        assert_eq!(parse_age("90"), Some(90));
+        assert_eq!(parse_age("90s"), Some(90));
+        assert_eq!(parse_age("30m"), Some(30 * 60));
+        assert_eq!(parse_age("12h"), Some(12 * 60 * 60));
+        assert_eq!(parse_age("7d"), Some(7 * 24 * 60 * 60));
+        assert_eq!(parse_age("2w"), Some(14 * 24 * 60 * 60));
+        assert_eq!(parse_age(""), None);
+        assert_eq!(parse_age("d"), None);
+        assert_eq!(parse_age("7y"), None);
+        assert_eq!(parse_age("-7d"), None);
+    }
+}
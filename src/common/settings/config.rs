@@ -16,12 +16,16 @@
  * along with this program.  If not, see <http://www.gnu.org/licenses/>.
  */
 use crate::common::constants;
+use crate::common::name_template::{NameTemplate, TemplateChars};
 use crate::common::types::file_perms::Permissions;
 use ::config::{ConfigError, Source, Value};
 use libc::{gid_t, uid_t};
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::path::PathBuf;
+use std::str::FromStr;
+use unicode_normalization::UnicodeNormalization;
 
 #[derive(Debug, Clone)]
 pub struct HashMapSource(pub HashMap<String, config::Value>);
@@ -45,6 +49,51 @@ pub struct Mount {
     pub uid: uid_t,
     pub gid: gid_t,
     pub permissions: Permissions,
+    /// How the entries of tag directories and filedirs are ordered
+    pub sort: Sort,
+    /// Whether mounting first unmounts a supertag mount left at the mountpoint by a daemon that has died
+    pub clean_stale: bool,
+    /// Whether tagged files appear as regular files whose opens, reads and writes go through to their targets,
+    /// instead of as symlinks, for applications that refuse to follow symlinks
+    pub write_through: bool,
+    /// Whether listing a tag directory counts the files of every tagdir in it up front.  Otherwise each one is counted
+    /// when it's stat'd, which is faster for file managers that never look at the sizes
+    pub listing_sizes: bool,
+    /// Whether users other than the one who mounted can use the mount.  The ownership and permissions of each tag are
+    /// then enforced, so that another user's tags can be kept from being listed or modified.  Unless mounting as root,
+    /// this needs `user_allow_other` in /etc/fuse.conf
+    pub allow_other: bool,
+    /// The name that the mounted volume is shown with on macos, instead of the collection name.  Best set in the
+    /// collection's own config.toml, so that each collection gets its own
+    #[serde(default)]
+    pub volname: Option<String>,
+    /// An .icns file for the mounted volume on macos, instead of the default Supertag icon
+    #[serde(default)]
+    pub volicon: Option<PathBuf>,
+    /// A file to record every fuse callback into, for replaying with `tag replay`.  Only for debugging, since it
+    /// slows every operation down and records every path that's looked at
+    #[serde(default)]
+    pub trace: Option<PathBuf>,
+    /// An address, like `127.0.0.1:9858`, to serve Prometheus metrics about the mount on, at `/metrics`
+    #[serde(default)]
+    pub metrics_listen: Option<String>,
+    /// A file to keep rewriting Prometheus metrics about the mount to, for node_exporter's textfile collector
+    #[serde(default)]
+    pub metrics_textfile: Option<PathBuf>,
+}
+
+/// The order that directory listings are in.  Tag groups always come first, by name.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Sort {
+    Name,
+    /// Most recently modified first
+    Mtime,
+    /// Tags with the most files first.  Files, which have no count, are ordered by name.
+    NumFiles,
+    /// The highest rated files first, then the unrated ones.  Tags, which have no rating, are ordered by name.  Rating
+    /// directories, ie `★4+`, are always listed this way.
+    Rating,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -55,12 +104,382 @@ pub struct Symbols {
     pub filedir_str: String,
     pub filedir_cli_str: String,
     pub tag_group_str: String,
+    /// How a file's name is rendered when it has to be told apart from other files with the same name, from the
+    /// fields `{name}`, `{stem}`, `{ext}`, `{device}`, `{inode}`, `{device_char}` and `{inode_char}`
+    pub name_template: NameTemplate,
+}
+
+impl Symbols {
+    /// The symbols that the name template is rendered with
+    pub fn template_chars(&self) -> TemplateChars {
+        TemplateChars {
+            device_char: self.device_char,
+            inode_char: self.inode_char,
+        }
+    }
+}
+
+/// Transforms applied to file names in filedir listings.  They only affect how names are displayed; the real names
+/// remain in the database and continue to resolve.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Display {
+    pub strip_extensions: bool,
+    pub underscores_to_spaces: bool,
+    pub title_case: bool,
+}
+
+impl Display {
+    pub fn is_active(&self) -> bool {
+        self.strip_extensions || self.underscores_to_spaces || self.title_case
+    }
+}
+
+/// Settings for the background cache warm-up that can run after mounting
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Cache {
+    pub warm_on_mount: bool,
+    /// How many of the largest tags to pre-compute intersections for
+    pub warm_top_tags: usize,
+    pub warm_threads: usize,
+    /// How long warmed entries live in the readdir cache
+    pub warm_ttl_s: u64,
+    /// Whether long paths are keyed in the readdir cache by a digest of their tags, instead of by the whole path.
+    /// Paths that only differ in the order of their tags then share entries.
+    pub digest_keys: bool,
+    /// How many bytes a path needs to be before it's keyed by a digest.  Shorter paths are cheaper to hash as they are.
+    pub digest_keys_min_len: usize,
+}
+
+/// Settings for tagged files that live on slow devices, like network shares
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Remote {
+    /// Whether filedir listings show the real files' current mtimes, instead of the mtimes recorded in the database
+    pub refresh_mtimes: bool,
+    /// Devices that average a longer stat than this are only refreshed in the background
+    pub slow_stat_ms: u64,
+    /// How long a refreshed mtime is trusted before it's considered stale
+    pub stale_after_s: u64,
+    pub refresh_batch: usize,
+    pub refresh_interval_ms: u64,
+}
+
+/// What happens to a file's tags when it's moved from one filedir to another
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FileMoveMode {
+    /// The file gains the destination's tags, and keeps all of its own
+    Additive,
+    /// The file gains the destination's tags, and loses the source's tags that the destination doesn't have
+    Move,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Rename {
+    pub file_mode: FileMoveMode,
+    /// Whether the old name of a renamed tag still leads to the tag, for `redirect_days` after the rename, so that
+    /// bookmarks and scripts using the old path keep working.  While an old name redirects, the mount reports it as
+    /// existing, so a new tag can only take it from the cli.
+    pub redirect_tags: bool,
+    pub redirect_days: u64,
+}
+
+/// Automatically places newly created tags whose names match `pattern` into the tag group `group`.  Patterns are globs,
+/// where `*` matches any run of characters and `?` matches exactly one.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GroupRule {
+    pub pattern: String,
+    pub group: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Groups {
+    #[serde(default)]
+    pub rules: Vec<GroupRule>,
+}
+
+impl Groups {
+    /// The distinct groups whose rules match `tag`, in the order the rules were declared.  More than one means the
+    /// rules conflict for this tag.
+    pub fn groups_for(&self, tag: &str) -> Vec<&str> {
+        let mut groups: Vec<&str> = vec![];
+        for rule in &self.rules {
+            if crate::common::glob_matches(&rule.pattern, tag)
+                && !groups.contains(&rule.group.as_str())
+            {
+                groups.push(&rule.group);
+            }
+        }
+        groups
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Shutdown {
+    /// How long an unmount waits for in-progress operations before tearing down anyway
+    pub deadline_ms: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Searches {
+    /// The top-level directory that saved searches appear under.  It hides any tag with the same name.
+    pub dir: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Doctor {
+    /// Where `tag doctor --repair search` looks for files that have moved
+    #[serde(default)]
+    pub search_roots: Vec<PathBuf>,
+}
+
+/// Settings for file managers that want to look inside tagged files
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Thumbnails {
+    /// Whether files with one of `extensions` appear as regular, read-only files instead of symlinks, so that
+    /// thumbnailers which won't follow symlinks out of a mount can still read them
+    pub passthrough: bool,
+    /// The extensions, without the dot, that are passed through.  Matched case-insensitively.
+    pub extensions: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Tagging {
+    /// Whether files that belong to the collection itself, like its database and managed files, can be tagged.
+    /// Tagging them can create cycles and risks corrupting the collection.
+    pub allow_collection_files: bool,
+    /// Whether linking a directory also links every file beneath it, so that they're listed in the intersection on
+    /// their own, as well as inside the directory
+    pub dir_children: bool,
+}
+
+/// Settings for `tag undo`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Undo {
+    /// How many of the most recent destructive operations can be undone.  0 turns the journal off.
+    pub keep: usize,
+}
+
+/// Settings for desktop notifications, which are rate limited so that a script that keeps failing doesn't bury the
+/// desktop in them
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Notifications {
+    /// How many different notifications are shown in each window.  The rest, and repeats, are summarized by kind once
+    /// the window is over, like "37 copy attempts blocked in the last minute"
+    pub max_per_window: usize,
+    pub window_s: u64,
+}
+
+/// Settings for how paths and search expressions are queried
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Query {
+    /// The most negated tags, like `-wip`, that one path or search expression may have.  Long chains of them are
+    /// usually typos, and are refused before they're queried.  0 means no limit.
+    pub max_negations: usize,
+}
+
+/// Settings for the date directories, like `@2021/@2021-07`, that group files by when they were last modified
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Dates {
+    /// Whether date directories are listed and understood in paths.  A collection with tags that start with `@`
+    /// should leave this off, since those tags would otherwise be read as dates.
+    pub enabled: bool,
+}
+
+/// Settings for the tag group, like `filetype+/pdf`, that sorts files by their extension without them being tagged
+/// with it
+#[derive(Serialize, Deserialize, Clone)]
+pub struct FileTypes {
+    /// Whether the file type group is listed and understood in paths
+    pub enabled: bool,
+    /// The name of the file type group.  It hides a real tag group with the same name.
+    pub group: String,
+}
+
+/// Settings for following tagged files that are renamed or moved outside of supertag
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Tracking {
+    /// Whether a symlink whose target is gone is pointed at wherever the file's device/inode turns up
+    pub follow_renames: bool,
+    /// How many levels below each tagged file's directory are indexed for moved files
+    pub index_depth: usize,
+    pub index_interval_s: u64,
+}
+
+/// Settings that identify a collection to tools that work across collections
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct Collection {
+    /// A tag that every file in the collection implicitly has, like `col:photos`.  It's never stored, so it costs
+    /// nothing, but it can be used in search expressions and is recorded in exports.
+    #[serde(default)]
+    pub tag: Option<String>,
+    /// How tagged files are told apart.  Only applies to files linked after it's changed.
+    #[serde(default)]
+    pub identity: Identity,
+    /// Directories whose files are identified by path, whatever `identity` is, like the mountpoints of network
+    /// shares in an otherwise local collection
+    #[serde(default)]
+    pub path_identity_dirs: Vec<PathBuf>,
+    /// Whether the collection's database is encrypted, with a key from the system keyring or asked for on the
+    /// terminal.  It's set by mounting with `--encrypted`, which encrypts the database.
+    #[serde(default)]
+    pub encrypted: bool,
+    /// Whether tags whose names only differ in case, like "Music" and "music", are the same tag.  Tagging with either
+    /// spelling lands on the tag that already exists, and paths find it with either.  Takes effect when the collection
+    /// is next mounted.
+    #[serde(default)]
+    pub case_insensitive_tags: bool,
+    /// The Unicode normalization form that tag names are kept in, so that a name typed on the cli and the same name
+    /// from MacOS's Finder, which decomposes accented characters, are the same tag.  Takes effect when the collection
+    /// is next mounted, which merges the tags whose names are the same once they're in this form.
+    #[serde(default)]
+    pub tag_form: TagForm,
+}
+
+/// A Unicode normalization form for tag names
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TagForm {
+    /// Composed, like most keyboards type
+    Nfc,
+    /// Decomposed, like MacOS's filesystem APIs hand out
+    Nfd,
+    /// Names are kept exactly as they're given
+    None,
+}
+
+impl Default for TagForm {
+    fn default() -> Self {
+        TagForm::Nfc
+    }
+}
+
+impl TagForm {
+    /// `name` in this form, borrowed if it's already in it
+    pub fn normalize(self, name: &str) -> Cow<str> {
+        match self {
+            TagForm::Nfc if !unicode_normalization::is_nfc(name) => {
+                Cow::Owned(name.nfc().collect())
+            }
+            TagForm::Nfd if !unicode_normalization::is_nfd(name) => {
+                Cow::Owned(name.nfd().collect())
+            }
+            _ => Cow::Borrowed(name),
+        }
+    }
+
+    /// How the form is written in the config, and in the database
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TagForm::Nfc => "nfc",
+            TagForm::Nfd => "nfd",
+            TagForm::None => "none",
+        }
+    }
+}
+
+impl FromStr for TagForm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nfc" => Ok(TagForm::Nfc),
+            "nfd" => Ok(TagForm::Nfd),
+            "none" => Ok(TagForm::None),
+            other => Err(format!("Unknown tag form {}", other)),
+        }
+    }
+}
+
+/// What a tagged file's identity is based on
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Identity {
+    /// The device and inode of the real file, which survive renames on local filesystems
+    Inode,
+    /// The canonical path and contents of the real file, for network shares whose device and inode numbers aren't
+    /// stable across remounts
+    Path,
+}
+
+impl Default for Identity {
+    fn default() -> Self {
+        Identity::Inode
+    }
+}
+
+/// Settings for tagging media files with the metadata inside them, which needs the `media-tags` feature
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Media {
+    /// Whether files are tagged with their metadata as they're linked
+    pub enabled: bool,
+    /// Starts the tag for the camera that took a photo, ie `camera:fujifilm-x100v`
+    pub camera_prefix: String,
+    /// Starts the tag for the month a photo was taken in, ie `taken:2021-07`
+    pub date_prefix: String,
+    /// Starts the tag for a song's artist, ie `artist:Radiohead`
+    pub artist_prefix: String,
+    /// Starts the tag for a song's album, ie `album:OK Computer`
+    pub album_prefix: String,
+}
+
+/// Settings for finding the same file tagged more than once, by the hash of its contents
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Duplicates {
+    /// Whether files are hashed as they're linked, and the duplicates directory is shown.  `tag dupes` hashes files
+    /// either way.
+    pub enabled: bool,
+    /// The top-level directory that lists every file whose contents are shared with another file.  It hides any tag
+    /// with the same name.
+    pub dir: String,
+}
+
+/// Settings for the directory of files that have had all of their tags removed
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Untagged {
+    /// The top-level directory that lists every file in the collection without any tags.  It hides any tag with the
+    /// same name.
+    pub dir: String,
+}
+
+/// An external program that contributes tags for files as they're linked, see `common::providers`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Provider {
+    /// Identifies the provider in logs
+    pub name: String,
+    /// The program and its arguments.  It's started once and kept running for as long as the collection is in use.
+    pub command: Vec<String>,
+    /// How long the provider has to answer for one file before it's restarted, in milliseconds
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Config {
     pub symbols: Symbols,
     pub mount: Mount,
+    pub display: Display,
+    pub cache: Cache,
+    pub rename: Rename,
+    pub remote: Remote,
+    pub groups: Groups,
+    pub shutdown: Shutdown,
+    pub searches: Searches,
+    pub doctor: Doctor,
+    pub thumbnails: Thumbnails,
+    pub tracking: Tracking,
+    pub tagging: Tagging,
+    pub undo: Undo,
+    pub notifications: Notifications,
+    pub query: Query,
+    pub dates: Dates,
+    pub filetypes: FileTypes,
+    pub media: Media,
+    pub duplicates: Duplicates,
+    pub untagged: Untagged,
+    #[serde(default)]
+    pub collection: Collection,
+    #[serde(default)]
+    pub providers: Vec<Provider>,
 }
 
 /// Builds a default config based off of our default toml, environment variables, and a specified app toml file
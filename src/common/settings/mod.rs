@@ -18,16 +18,22 @@
 
 use super::constants;
 use super::err::{STagError, STagResult};
+use crate::common::name_template::TemplateChars;
+use crate::common::providers::Providers;
+use crate::common::rules::Rules;
 use crate::common::types::file_perms::UMask;
 use crate::common::types::{DeviceFile, TagType};
-use crate::common::{err, get_filename, strip_ext_prefix};
+use crate::common::{err, get_device_inode, get_filename, identity, strip_ext_prefix};
+use crate::sql::types::TaggedFile;
 use directories as dir;
-use log::{debug, warn};
 use parking_lot::RwLock;
+use std::collections::HashMap;
 use std::io::Write;
 use std::path::Component::{Normal, RootDir};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use tracing::{debug, info, warn};
 
 pub mod config;
 pub mod dirs;
@@ -46,8 +52,29 @@ pub struct Settings {
     merged_config: ::config::Config, // FIXME currently unused
     project_dirs: Arc<dyn dirs::Dirs>,
 
+    /// Device/inode symbol pairs that this collection used to be mounted with, and that we still accept when parsing
+    /// device files.  This is set after the symbol migration has run.
+    legacy_symbols: RwLock<Vec<(char, char)>>,
+
     /// This is set after we're instantiated
     collection: Option<String>,
+
+    /// The collection's tagging rules, which are set along with the collection
+    rules: Rules,
+
+    /// The collection's running tag providers, which are started the first time a file is linked
+    providers: Providers,
+
+    /// Set by `--dry-run`, so that cli operations report what they would change instead of committing it
+    dry_run: bool,
+
+    /// Why the collection's writes are being held off, while it's closed for maintenance like a migration or a backup.
+    /// Only the daemon sets this, through its control socket.
+    write_gate: RwLock<Option<String>>,
+
+    /// Set while the disk holding the collection's database is full, which holds off writes like the write gate does.
+    /// The daemon clears it again once there's room.
+    disk_full: AtomicBool,
 }
 
 #[must_use]
@@ -92,8 +119,14 @@ impl Settings {
         let settings = Settings {
             config: Default::default(),
             project_dirs,
+            legacy_symbols: Default::default(),
             collection: None,
             merged_config: Default::default(),
+            rules: Default::default(),
+            providers: Default::default(),
+            dry_run: false,
+            write_gate: Default::default(),
+            disk_full: Default::default(),
         };
         settings.ensure_config_files()?;
         Ok(settings)
@@ -114,6 +147,7 @@ impl Settings {
     fn ensure_collection_files(&self, col: &str) -> std::io::Result<()> {
         ensure_dir(self.collection_dir(col))?;
         ensure_dir(self.log_dir(col))?;
+        ensure_dir(self.collection_dir(col).join(constants::PROFILES_DIR_NAME))?;
         #[cfg(target_os = "macos")]
         ensure_dir(self.managed_dir(col))?;
         Ok(())
@@ -127,7 +161,13 @@ impl Settings {
         self.merged_config
             .merge(merged_config)
             .expect("Couldn't merge in new config");
-        let frozen = self.merged_config.clone().try_into().unwrap();
+        // a value that doesn't fit its setting, like a name template that can't be parsed, is refused here, when the
+        // config is loaded, rather than wherever the setting happens to be used first
+        let frozen = self
+            .merged_config
+            .clone()
+            .try_into()
+            .unwrap_or_else(|e| panic!("Bad config: {}", e));
         *guard = Some(frozen);
     }
 
@@ -143,6 +183,26 @@ impl Settings {
             .to_string()
     }
 
+    /// Builds separate settings for the collection `col`, starting from our config, so that one process can serve
+    /// several collections that each have their own config.  Since a collection's config is merged into the config it
+    /// starts from, this should be called on settings whose collection config hasn't been merged in yet.
+    pub fn for_collection(&self, col: &str) -> Self {
+        let mut settings = Settings {
+            config: RwLock::new(self.config.read().clone()),
+            merged_config: self.merged_config.clone(),
+            project_dirs: self.project_dirs.clone(),
+            legacy_symbols: Default::default(),
+            collection: None,
+            rules: Default::default(),
+            providers: Default::default(),
+            dry_run: self.dry_run,
+            write_gate: Default::default(),
+            disk_full: Default::default(),
+        };
+        settings.set_collection(col, true);
+        settings
+    }
+
     pub fn set_collection(&mut self, col: &str, set_config: bool) -> Option<String> {
         // part of the bootstrapping process requires set_config to be false
         if set_config {
@@ -154,9 +214,102 @@ impl Settings {
         }
         self.ensure_collection_files(col)
             .expect("Couldn't create collection files");
+        self.rules = Rules::new(self.config_file(col));
         self.collection.replace(col.into())
     }
 
+    pub fn rules(&self) -> &Rules {
+        &self.rules
+    }
+
+    /// The tags that the collection's tag providers give the file at `path`.  Only plain tag names are kept, so that a
+    /// provider can't tag a file with something that would be read as a tag group or a negation.
+    pub fn provider_tags(&self, path: &Path) -> Vec<String> {
+        self.providers
+            .tags_for(&self.get_config().providers, path)
+            .into_iter()
+            .filter(|tag| self.plain_tag(tag, "provided"))
+            .collect()
+    }
+
+    /// The tags that the metadata inside the media file at `path` gives it, if media tagging is enabled.  Like
+    /// provided tags, only plain tag names are kept.
+    pub fn media_tags(&self, path: &Path) -> Vec<String> {
+        crate::common::media::tags_for(&self.get_config().media, path)
+            .into_iter()
+            .filter(|tag| self.plain_tag(tag, "media"))
+            .collect()
+    }
+
+    /// The device/inode that the real file at `path` is tagged as.  In a path identity collection, or under one of
+    /// its `path_identity_dirs`, that's derived from the file's canonical path instead of read from the filesystem.
+    pub fn file_identity(&self, path: &Path) -> STagResult<(u64, u64)> {
+        let collection = self.get_config().collection;
+        let canonical = path.canonicalize()?;
+        if collection.identity == config::Identity::Path
+            || collection
+                .path_identity_dirs
+                .iter()
+                .any(|dir| canonical.starts_with(dir))
+        {
+            Ok(identity::path_identity(&canonical))
+        } else {
+            get_device_inode(path)
+        }
+    }
+
+    /// Whether `tag` would be read as a regular tag, warning about it if not
+    fn plain_tag(&self, tag: &str, source: &str) -> bool {
+        let plain = self.path_to_tags(Path::new(tag)) == [TagType::Regular(tag.to_string())];
+        if !plain {
+            warn!(target: TAG, "Ignoring {} tag {:?}, it isn't a plain tag name", source, tag);
+        }
+        plain
+    }
+
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+    }
+
+    pub fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Holds off writes to the collection, because of `reason`, until `open_write_gate`
+    pub fn close_write_gate(&self, reason: &str) {
+        info!(target: TAG, "Closing the write gate: {}", reason);
+        *self.write_gate.write() = Some(reason.to_string());
+    }
+
+    pub fn open_write_gate(&self) {
+        info!(target: TAG, "Opening the write gate");
+        *self.write_gate.write() = None;
+    }
+
+    /// Why writes are being held off, if they are
+    pub fn write_gate(&self) -> Option<String> {
+        self.write_gate.read().clone()
+    }
+
+    /// Fails with `STagError::Maintenance` while writes are being held off, or with `STagError::DiskFull` while the
+    /// database's disk is full
+    pub fn check_write_gate(&self) -> STagResult<()> {
+        match &*self.write_gate.read() {
+            Some(reason) => Err(STagError::Maintenance(reason.clone())),
+            None if self.disk_full() => Err(STagError::DiskFull),
+            None => Ok(()),
+        }
+    }
+
+    /// Marks the database's disk as full, or as having room again, returning whether it was marked full before
+    pub fn set_disk_full(&self, full: bool) -> bool {
+        self.disk_full.swap(full, Ordering::SeqCst)
+    }
+
+    pub fn disk_full(&self) -> bool {
+        self.disk_full.load(Ordering::SeqCst)
+    }
+
     pub fn suffix_sync_char(&self, path: &Path) -> STagResult<PathBuf> {
         let mut sync_file_name = super::get_filename(path)?.to_owned();
         sync_file_name.push(self.get_config().symbols.sync_char);
@@ -225,23 +378,21 @@ impl Settings {
             "Resolving collection for path {:?}",
             &path.as_ref()
         );
-        match self.collection_from_path(path, true) {
-            Some(collection) => {
-                self.collection = Some(collection.clone());
-                Ok(collection)
-            }
+        let collection = match self.collection_from_path(path, true) {
+            Some(collection) => collection,
             None => {
                 debug!(
                     target: TAG,
                     "Couldn't resolve path to collection, using default primary collection"
                 );
-                let pc = self
-                    .primary_collection()?
-                    .ok_or_else(|| "Couldn't find primary collection")?;
-                self.collection = Some(pc.clone());
-                Ok(pc)
+                self.primary_collection()?
+                    .ok_or_else(|| "Couldn't find primary collection")?
             }
-        }
+        };
+        // the rules belong to the collection, so they have to follow it
+        self.rules = Rules::new(self.config_file(&collection));
+        self.collection = Some(collection.clone());
+        Ok(collection)
     }
 
     fn volicon_path(&self) -> PathBuf {
@@ -249,6 +400,14 @@ impl Settings {
     }
 
     pub fn volicon(&self) -> Option<PathBuf> {
+        if let Some(path) = &self.get_config().mount.volicon {
+            if path.exists() {
+                debug!(target: TAG, "Configured VolumeIcon {} found", path.display());
+                return Some(path.clone());
+            }
+            warn!(target: TAG, "Configured VolumeIcon not found at {}", path.display());
+        }
+
         let path = self.volicon_path();
         if path.exists() {
             debug!(target: TAG, "VolumeIcon {} found", path.display());
@@ -286,6 +445,11 @@ impl Settings {
         self.project_dirs.data_local_dir().to_owned()
     }
 
+    /// Where operations spanning two collections keep their intent files, until they've finished in both
+    pub fn xtx_dir(&self) -> PathBuf {
+        self.data_dir().join("xtx")
+    }
+
     pub fn config_dir(&self) -> PathBuf {
         self.project_dirs.config_dir().to_owned()
     }
@@ -324,10 +488,44 @@ impl Settings {
         self.collection_dir(col).join(format!("{}.db", col))
     }
 
+    /// The tags that were being browsed when the collection was last unmounted, which are warmed first on the next
+    /// mount
+    pub fn warm_snapshot_file(&self, col: &str) -> PathBuf {
+        self.collection_dir(col).join("warm_snapshot")
+    }
+
     pub fn notify_socket_file(&self, col: &str) -> PathBuf {
         self.collection_dir(col).join("notify.sock")
     }
 
+    /// Where the daemon that has the collection mounted takes requests, like closing the write gate
+    pub fn control_socket_file(&self, col: &str) -> PathBuf {
+        self.collection_dir(col).join("control.sock")
+    }
+
+    /// Holds the pid of the daemon that has the collection mounted, so that a mount it leaves behind when it dies can
+    /// be recognized
+    pub fn pid_file(&self, col: &str) -> PathBuf {
+        self.collection_dir(col).join("mount.pid")
+    }
+
+    /// Where a volume name given by renaming the mounted volume is kept
+    pub fn volname_file(&self, col: &str) -> PathBuf {
+        self.collection_dir(col).join("volname")
+    }
+
+    /// The name that `col` is mounted with.  A configured name wins over one that the volume was renamed to, which
+    /// wins over the collection name.
+    pub fn volname(&self, col: &str) -> String {
+        if let Some(name) = &self.get_config().mount.volname {
+            return name.clone();
+        }
+        match std::fs::read_to_string(self.volname_file(col)) {
+            Ok(name) if !name.trim().is_empty() => name.trim().to_string(),
+            _ => col.to_string(),
+        }
+    }
+
     pub fn base_config_file(&self) -> PathBuf {
         let conf_dir = self.config_dir();
         conf_dir.join("config.toml")
@@ -337,6 +535,14 @@ impl Settings {
         self.collection_dir(col).join("config.toml")
     }
 
+    /// A config overlay for `col` that's merged over its config when it's mounted with `--profile name`, so that
+    /// each mount of a shared collection can look different without changing the collection's own config
+    pub fn profile_file(&self, col: &str, name: &str) -> PathBuf {
+        self.collection_dir(col)
+            .join(constants::PROFILES_DIR_NAME)
+            .join(format!("{}.toml", name))
+    }
+
     /// Takes a path and converts it to a collection of TagTypes. This function has to exist on the `Settings` struct
     /// because it uses symbols that can only come from loading the user's settings
     pub fn path_to_tags<P: AsRef<Path>>(&self, path: P) -> Vec<TagType> {
@@ -350,12 +556,27 @@ impl Settings {
                     let tag_str = comp_osstr.to_str().unwrap();
                     let conf = self.get_config();
                     let determined_tag = {
-                        if let Some(trimmed) = super::strip_negative_tag(tag_str) {
-                            TagType::Negation(trimmed.to_owned())
+                        if let Some(Ok(expr)) = super::query::parse_component(tag_str) {
+                            TagType::Expression(expr)
+                        } else if let Some(trimmed) = super::strip_negative_tag(tag_str) {
+                            TagType::Negation(
+                                conf.collection.tag_form.normalize(trimmed).into_owned(),
+                            )
+                        } else if let Some(stars) = super::split_rating_tag(tag_str) {
+                            TagType::Rating(stars)
+                        } else if let Some(trimmed) = tag_str
+                            .strip_suffix(constants::ALL_OF_GROUP_SUFFIX)
+                            .and_then(|group| strip_ext_prefix(group, &conf.symbols.tag_group_str))
+                        {
+                            TagType::GroupAll(trimmed)
                         } else if let Some(trimmed) =
                             strip_ext_prefix(tag_str, &conf.symbols.tag_group_str)
                         {
-                            TagType::Group(trimmed.to_owned())
+                            if conf.filetypes.enabled && trimmed == conf.filetypes.group {
+                                TagType::FileTypeGroup
+                            } else {
+                                TagType::Group(trimmed.to_owned())
+                            }
                         } else if tag_str == conf.symbols.filedir_str
                             || tag_str == conf.symbols.filedir_cli_str
                         {
@@ -364,8 +585,20 @@ impl Settings {
                             TagType::DeviceFileSymlink(df)
                         } else if let Some(TagType::FileDir) = &prev_tag {
                             TagType::Symlink(tag_str.to_owned())
+                        } else if let Some(TagType::FileTypeGroup) = &prev_tag {
+                            TagType::FileType(tag_str.to_owned())
+                        } else if let Some(bucket) =
+                            super::split_date_tag(tag_str).filter(|_| conf.dates.enabled)
+                        {
+                            TagType::Date(bucket)
+                        } else if let Some((key, value)) = super::split_meta_tag(tag_str) {
+                            TagType::Meta(key, value)
+                        } else if let Some(members) = super::split_union_tag(tag_str) {
+                            TagType::Union(members)
                         } else {
-                            TagType::Regular(tag_str.to_owned())
+                            TagType::Regular(
+                                conf.collection.tag_form.normalize(tag_str).into_owned(),
+                            )
                         }
                     };
                     prev_tag = Some(determined_tag.clone());
@@ -377,23 +610,162 @@ impl Settings {
         tags
     }
 
+    /// The tag that every file in the collection implicitly has, if one is configured
+    pub fn collection_tag(&self) -> Option<String> {
+        self.get_config()
+            .collection
+            .tag
+            .filter(|tag| !tag.is_empty())
+    }
+
+    /// Converts a search expression like `rust -wip +projects` into the TagTypes it describes.  Each term uses the
+    /// same syntax as a path component, and may also have a leading `+` to explicitly mark it as required.  Only terms
+    /// that make sense in an intersection are allowed.  The collection's tag matches every file, so excluding it is
+    /// an error.
+    pub fn query_to_tags(&self, terms: &[&str]) -> STagResult<Vec<TagType>> {
+        let collection_tag = self.collection_tag();
+        let is_collection_tag = |name: &str| collection_tag.as_deref() == Some(name);
+        let mut tags = vec![];
+        for term in terms {
+            let term = term.strip_prefix('+').unwrap_or(term);
+            if term.is_empty() || term.contains(std::path::MAIN_SEPARATOR) {
+                return Err(STagError::BadTag(term.to_string()));
+            }
+
+            // a malformed expression would otherwise be taken as a tag name
+            if let Some(Err(e)) = super::query::parse_component(term) {
+                return Err(STagError::BadTag(e.to_string()));
+            }
+
+            // parse each term on its own, so that nothing is interpreted relative to the term before it
+            let mut parsed = self.path_to_tags(Path::new(term));
+            match parsed.pop() {
+                Some(TagType::Regular(name)) if is_collection_tag(&name) => {
+                    tags.push(TagType::CollectionTag(name))
+                }
+                // a union with the collection's tag is every file, whatever else is in it
+                Some(TagType::Union(members)) if members.iter().any(|m| is_collection_tag(m)) => {
+                    tags.push(TagType::CollectionTag(
+                        collection_tag.clone().unwrap_or_default(),
+                    ))
+                }
+                Some(TagType::Negation(name)) if is_collection_tag(&name) => {
+                    return Err(STagError::BadTag(term.to_string()))
+                }
+                Some(tt @ TagType::Regular(_))
+                | Some(tt @ TagType::Negation(_))
+                | Some(tt @ TagType::Group(_))
+                | Some(tt @ TagType::GroupAll(_))
+                | Some(tt @ TagType::Union(_))
+                | Some(tt @ TagType::Meta(_, _))
+                | Some(tt @ TagType::Date(_))
+                | Some(tt @ TagType::Rating(_))
+                | Some(tt @ TagType::Expression(_)) => tags.push(tt),
+                _ => return Err(STagError::BadTag(term.to_string())),
+            }
+        }
+
+        // an intersection needs something to start from, we can't only subtract
+        if !tags.iter().any(|tt| !matches!(tt, TagType::Negation(_))) {
+            return Err(STagError::NotEnoughTags);
+        }
+        self.check_negations(&tags)?;
+        Ok(tags)
+    }
+
+    /// Fails with `STagError::TooManyNegations` if `tags` negate more tags than the config allows.  The negations
+    /// inside an expression count too.
+    pub fn check_negations(&self, tags: &[TagType]) -> STagResult<()> {
+        let max = self.get_config().query.max_negations;
+        let count = tags
+            .iter()
+            .map(|tt| match tt {
+                TagType::Negation(_) => 1,
+                TagType::Expression(expr) => expr.negations(),
+                _ => 0,
+            })
+            .sum::<usize>();
+        if max > 0 && count > max {
+            Err(STagError::TooManyNegations(count, max))
+        } else {
+            Ok(())
+        }
+    }
+
     pub fn inodify_filename(&self, filename: &str, device: u64, inode: u64) -> String {
-        let conf = self.get_config();
-        let mut ifn = String::new();
-        ifn.push_str(filename);
-        ifn.push(conf.symbols.device_char);
-        ifn.push_str(&device.to_string());
-        ifn.push(conf.symbols.inode_char);
-        ifn.push_str(&inode.to_string());
-        ifn
-    }
-
-    /// Takes a path and captures the inode number the filename.  Originally we used a regex, but regex
-    /// is incredibly slow, as reported by perf.  So we'll just do a simple linear search.  We also
-    /// set `is_unlinking` to true if it's a special path that has been passed to us the signify that
-    /// we are in the process of unlinking the filename.  Doing this here is simply an optimization
-    /// so we don't have to do O(2n) where n is num of path characters, for every file in a potentially
-    /// large directory.
+        let syms = self.get_config().symbols;
+        syms.name_template
+            .render(syms.template_chars(), filename, device, inode)
+    }
+
+    /// Renders a file's real name as it should appear in filedir listings, according to the display transforms
+    pub fn display_name(&self, filename: &str) -> String {
+        super::display::display_name(&self.get_config().display, filename)
+    }
+
+    /// The names that `files` are listed under in a filedir, in the same order.  A display name that's shared by more
+    /// than one of them is fully qualified instead, since it must resolve to exactly one file, and the fully qualified
+    /// name always uses the real name.
+    pub fn filedir_names(&self, files: &[TaggedFile]) -> Vec<String> {
+        let display_names: Vec<String> = files
+            .iter()
+            .map(|file| self.display_name(&file.primary_tag))
+            .collect();
+        let mut name_count: HashMap<&str, usize> = HashMap::new();
+        for name in &display_names {
+            *name_count.entry(name).or_insert(0) += 1;
+        }
+        files
+            .iter()
+            .zip(display_names.iter())
+            .map(|(file, name)| {
+                if name_count[name.as_str()] > 1 {
+                    self.inodify_filename(&file.primary_tag, file.device, file.inode)
+                } else {
+                    name.clone()
+                }
+            })
+            .collect()
+    }
+
+    /// Whether the tagged file at `target` is passed through as a regular file, rather than shown as a symlink
+    pub fn passthrough(&self, target: &Path) -> bool {
+        let conf = self.get_config().thumbnails;
+        if !conf.passthrough {
+            return false;
+        }
+        match target.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) => conf
+                .extensions
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(ext)),
+            None => false,
+        }
+    }
+
+    /// Whether tagged files are shown as regular files that can be opened and written through to their targets
+    pub fn write_through(&self) -> bool {
+        self.get_config().mount.write_through
+    }
+
+    /// Whether a tag directory's listing counts the files of its tagdirs, instead of leaving it to getattr
+    pub fn listing_sizes(&self) -> bool {
+        self.get_config().mount.listing_sizes
+    }
+
+    /// Whether other users can use the mount, in which case each tag's ownership and permissions are enforced
+    pub fn allow_other(&self) -> bool {
+        self.get_config().mount.allow_other
+    }
+
+    /// Whether a looked-up, possibly transformed, name refers to a file whose real name is `filename`
+    pub fn display_matches(&self, displayed: &str, filename: &str) -> bool {
+        super::display::matches(&self.get_config().display, displayed, filename)
+    }
+
+    /// Takes a path and captures the device and inode numbers from its filename, by matching it against the name
+    /// template.  Originally we used a regex, but regex is incredibly slow, as reported by perf, so the template is
+    /// matched by hand.
     pub fn path_to_device_file(&self, path: &Path) -> Result<Option<DeviceFile>, err::STagError> {
         let filename = get_filename(path)?;
         self.filename_to_device_file(filename)
@@ -404,53 +776,91 @@ impl Settings {
         filename: &str,
     ) -> Result<Option<DeviceFile>, err::STagError> {
         let syms = &self.get_config().symbols;
-        let mut inode_nums = Vec::new();
-        let mut device_nums = Vec::new();
-        let mut start_device_capture = false;
-        let mut start_inode_capture = false;
-        let mut real_filename_chars = vec![];
-
-        // even though this is iterating over codepoints, it should be fine, since our @ is a single
-        // codepoint, and so are our individual inode numbers
-        for letter in filename.chars() {
-            if letter == syms.device_char {
-                start_device_capture = true;
-                continue;
-            } else if letter == syms.inode_char && start_device_capture {
-                start_inode_capture = true;
-                start_device_capture = false;
-                continue;
-            } else if letter == syms.sync_char {
-                //
-            } else if start_device_capture {
-                device_nums.push(letter);
-            } else if start_inode_capture {
-                inode_nums.push(letter);
-            } else if !start_inode_capture && !start_device_capture {
-                real_filename_chars.push(letter);
-            }
+        // the sync char can be anywhere in the name, and is never part of it
+        let filename: String = filename.chars().filter(|c| *c != syms.sync_char).collect();
+        let found = syms
+            .name_template
+            .parse_name(syms.template_chars(), &filename);
+        if found.is_some() {
+            return Ok(found);
         }
 
-        // no error, but no inode found either
-        if !start_inode_capture {
-            return Ok(None);
+        // the name didn't parse with our current symbols, but it may be a name that was produced before the
+        // symbols were changed
+        for (device_char, inode_char) in self.legacy_symbols.read().iter() {
+            let chars = TemplateChars {
+                device_char: *device_char,
+                inode_char: *inode_char,
+            };
+            let found = syms.name_template.parse_name(chars, &filename);
+            if found.is_some() {
+                debug!(
+                    target: TAG,
+                    "Parsed {} with legacy symbols {} {}", filename, device_char, inode_char
+                );
+                return Ok(found);
+            }
         }
+        Ok(None)
+    }
 
-        let real_filename: String = real_filename_chars.into_iter().collect();
+    /// Sets the legacy device/inode symbol pairs that `filename_to_device_file` should also accept
+    pub fn set_legacy_symbols(&self, symbols: Vec<(char, char)>) {
+        *self.legacy_symbols.write() = symbols;
+    }
+}
 
-        let device_str: String = device_nums.into_iter().collect();
-        let device = device_str
-            .to_string()
-            .parse()
-            .map_err(|_| err::STagError::BadDeviceFile(filename.to_string()))?;
+/// Writes a string `value` for `key` into the `[table]` of the config file at `path`, so that it still applies the
+/// next time the config is loaded.  The rest of the file is left as it was.
+pub fn persist_config_value(
+    path: &Path,
+    table: &str,
+    key: &str,
+    value: &str,
+) -> std::io::Result<()> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+    std::fs::write(path, set_toml_string(&text, table, key, value))
+}
 
-        let inode_str: String = inode_nums.into_iter().collect();
-        let inode = inode_str
-            .parse()
-            .map_err(|_| err::STagError::BadDeviceFile(filename.to_string()))?;
+/// Sets `key` to the string `value` inside `[table]` of the toml `text`, replacing the key's line if it's already
+/// there, and adding the table to the end if it isn't
+fn set_toml_string(text: &str, table: &str, key: &str, value: &str) -> String {
+    let header = format!("[{}]", table);
+    let line = format!(
+        "{} = \"{}\"",
+        key,
+        value.replace('\\', "\\\\").replace('"', "\\\"")
+    );
 
-        Ok(Some(DeviceFile::new(&real_filename, device, inode)))
+    let mut lines: Vec<String> = text.lines().map(String::from).collect();
+    let start = match lines.iter().position(|l| l.trim() == header) {
+        Some(idx) => idx + 1,
+        None => {
+            if lines.last().map_or(false, |l| !l.trim().is_empty()) {
+                lines.push(String::new());
+            }
+            lines.push(header);
+            lines.push(line);
+            return lines.join("\n") + "\n";
+        }
+    };
+    let end = lines[start..]
+        .iter()
+        .position(|l| l.trim_start().starts_with('['))
+        .map_or(lines.len(), |idx| start + idx);
+
+    let existing = lines[start..end]
+        .iter()
+        .position(|l| l.find('=').map_or(false, |idx| l[..idx].trim() == key));
+    match existing {
+        Some(idx) => lines[start + idx] = line,
+        None => lines.insert(start, line),
     }
+    lines.join("\n") + "\n"
 }
 
 impl From<&str> for Settings {
@@ -488,6 +898,30 @@ mod tests {
     use super::*;
     use std::path::{Path, PathBuf};
 
+    #[test]
+    fn test_set_toml_string() {
+        assert_eq!(
+            set_toml_string("", "symbols", "filedir_str", "∩"),
+            "[symbols]\nfiledir_str = \"∩\"\n"
+        );
+
+        let text = "[mount]\nclean_stale = true\n";
+        assert_eq!(
+            set_toml_string(text, "symbols", "tag_group_str", "\"+"),
+            "[mount]\nclean_stale = true\n\n[symbols]\ntag_group_str = \"\\\"+\"\n"
+        );
+
+        let text = "[symbols]\nfiledir_str = \"⋂\"\ntag_group_str = \"+\"\n\n[mount]\nfiledir_str = \"x\"\n";
+        assert_eq!(
+            set_toml_string(text, "symbols", "filedir_str", "∩"),
+            "[symbols]\nfiledir_str = \"∩\"\ntag_group_str = \"+\"\n\n[mount]\nfiledir_str = \"x\"\n"
+        );
+        assert_eq!(
+            set_toml_string(text, "symbols", "sync_char", "~"),
+            "[symbols]\nsync_char = \"~\"\nfiledir_str = \"⋂\"\ntag_group_str = \"+\"\n\n[mount]\nfiledir_str = \"x\"\n"
+        );
+    }
+
     #[test]
     fn test_good_path_to_inode() -> TestResult {
         let settings = Settings::default();
@@ -534,4 +968,275 @@ mod tests {
         assert_eq!(res.unwrap(), DeviceFile::new("some_file", 987, 12345));
         Ok(())
     }
+
+    #[test]
+    fn test_name_template_path_to_inode() -> TestResult {
+        let mut settings = Settings::default();
+        let mut source = config::HashMapSource(Default::default());
+        source.0.insert(
+            "symbols.name_template".to_string(),
+            "{stem} ({device}-{inode}){ext}".into(),
+        );
+        settings.update_config(source);
+
+        let path = settings.inodify_filename("song.mp3", 987, 12345);
+        assert_eq!(path, "song (987-12345).mp3");
+        let res = settings.path_to_device_file(Path::new(&path))?;
+        assert_eq!(res, Some(DeviceFile::new("song.mp3", 987, 12345)));
+
+        // names in the default form are no longer fully-qualified
+        let res = settings.path_to_device_file(Path::new("song.mp3﹫987-12345"))?;
+        assert!(res.is_none());
+        Ok(())
+    }
+
+    #[test]
+    #[should_panic(expected = "Bad config")]
+    /// Tests that a name template that can't be parsed is refused when the config is loaded
+    fn test_bad_name_template() {
+        let mut settings = Settings::default();
+        let mut source = config::HashMapSource(Default::default());
+        source
+            .0
+            .insert("symbols.name_template".to_string(), "{name}-{inode}".into());
+        settings.update_config(source);
+    }
+
+    #[test]
+    fn test_union_path_to_tags() {
+        let settings = Settings::default();
+        let tags = settings.path_to_tags("/music|podcasts/2023");
+        assert_eq!(
+            tags,
+            vec![
+                TagType::Union(vec!["music".to_string(), "podcasts".to_string()]),
+                TagType::Regular("2023".to_string()),
+            ]
+        );
+
+        // empty members don't make a union
+        let tags = settings.path_to_tags("/music|");
+        assert_eq!(tags, vec![TagType::Regular("music|".to_string())]);
+    }
+
+    #[test]
+    fn test_group_path_to_tags() {
+        let settings = Settings::default();
+        let tags = settings.path_to_tags("/genres+/rock");
+        assert_eq!(
+            tags,
+            vec![
+                TagType::Group("genres".to_string()),
+                TagType::Regular("rock".to_string()),
+            ]
+        );
+
+        let tags = settings.path_to_tags("/genres+!/rock");
+        assert_eq!(
+            tags,
+            vec![
+                TagType::GroupAll("genres".to_string()),
+                TagType::Regular("rock".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_collection_digest() {
+        use crate::common::types::TagCollection;
+        let settings = Settings::default();
+        let digest = |path: &str| TagCollection::new(&settings, Path::new(path)).digest(&settings);
+
+        // the order of the intersection doesn't matter, but what's at the end of it does
+        assert_eq!(digest("/a/b/c/⋂/file"), digest("/c/a/b/⋂/file"));
+        assert_ne!(digest("/a/b/⋂/file"), digest("/a/b/⋂/other"));
+        assert_ne!(digest("/a/b/c"), digest("/a/c/b"));
+        assert_ne!(digest("/a/b"), digest("/a/-b"));
+
+        // a tag group only qualifies the tag after it
+        assert_eq!(digest("/+g/a/b/c"), digest("/b/+g/a/c"));
+        assert_ne!(digest("/+g/a/b/c"), digest("/+g/b/a/c"));
+    }
+
+    #[test]
+    fn test_meta_path_to_tags() {
+        let settings = Settings::default();
+        let tags = settings.path_to_tags("/meta:client=acme/invoices");
+        assert_eq!(
+            tags,
+            vec![
+                TagType::Meta("client".to_string(), "acme".to_string()),
+                TagType::Regular("invoices".to_string()),
+            ]
+        );
+
+        // a value may itself contain the separator, but neither side may be empty
+        let tags = settings.path_to_tags("/meta:expr=a=b/meta:=acme/meta:client=");
+        assert_eq!(
+            tags,
+            vec![
+                TagType::Meta("expr".to_string(), "a=b".to_string()),
+                TagType::Regular("meta:=acme".to_string()),
+                TagType::Regular("meta:client=".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_date_path_to_tags() {
+        let mut settings = Settings::default();
+        let path = "/photos/@2021/@2021-07/@2021-13/@21";
+        assert_eq!(
+            settings.path_to_tags(path)[1],
+            TagType::Regular("@2021".to_string())
+        );
+
+        let mut source = crate::common::settings::config::HashMapSource(Default::default());
+        source.0.insert("dates.enabled".to_string(), true.into());
+        settings.update_config(source);
+        assert_eq!(
+            settings.path_to_tags(path),
+            vec![
+                TagType::Regular("photos".to_string()),
+                TagType::Date("2021".to_string()),
+                TagType::Date("2021-07".to_string()),
+                TagType::Regular("@2021-13".to_string()),
+                TagType::Regular("@21".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rating_path_to_tags() {
+        let settings = Settings::default();
+        assert_eq!(
+            settings.path_to_tags("/photos/★4+/★0+/★6+/★4/⋂"),
+            vec![
+                TagType::Regular("photos".to_string()),
+                TagType::Rating(4),
+                TagType::Group("★0".to_string()),
+                TagType::Group("★6".to_string()),
+                TagType::Regular("★4".to_string()),
+                TagType::FileDir,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_filetype_path_to_tags() {
+        let mut settings = Settings::default();
+        let path = "/docs/filetype+/pdf/⋂";
+        assert_eq!(
+            settings.path_to_tags(path)[1..3],
+            [
+                TagType::Group("filetype".to_string()),
+                TagType::Regular("pdf".to_string()),
+            ]
+        );
+
+        let mut source = crate::common::settings::config::HashMapSource(Default::default());
+        source
+            .0
+            .insert("filetypes.enabled".to_string(), true.into());
+        settings.update_config(source);
+        assert_eq!(
+            settings.path_to_tags(path),
+            vec![
+                TagType::Regular("docs".to_string()),
+                TagType::FileTypeGroup,
+                TagType::FileType("pdf".to_string()),
+                TagType::FileDir,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_expression_path_to_tags() -> TestResult {
+        let settings = Settings::default();
+        let tags = settings.path_to_tags("/?(rust&(cli|tui)&!wip)/⋂");
+        let expr = crate::common::query::parse_expr("rust&(cli|tui)&!wip")?;
+        assert_eq!(
+            tags,
+            vec![TagType::Expression(expr.clone()), TagType::FileDir]
+        );
+
+        // a malformed expression is only a strange tag name in a path, but an error in a search
+        let tags = settings.path_to_tags("/?(rust&)");
+        assert_eq!(tags, vec![TagType::Regular("?(rust&)".to_string())]);
+        assert!(settings.query_to_tags(&["?(rust&)"]).is_err());
+        assert_eq!(
+            settings.query_to_tags(&["?(rust&(cli|tui)&!wip)"])?,
+            vec![TagType::Expression(expr)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_query_to_tags() -> TestResult {
+        let settings = Settings::default();
+        let tags = settings.query_to_tags(&["rust", "-wip", "+projects", "a|b"])?;
+        assert_eq!(
+            tags,
+            vec![
+                TagType::Regular("rust".to_string()),
+                TagType::Negation("wip".to_string()),
+                TagType::Regular("projects".to_string()),
+                TagType::Union(vec!["a".to_string(), "b".to_string()]),
+            ]
+        );
+
+        assert!(settings.query_to_tags(&["-wip"]).is_err());
+        let negated = (0..9).map(|i| format!("-t{}", i)).collect::<Vec<_>>();
+        let mut terms = vec!["rust"];
+        terms.extend(negated.iter().map(String::as_str));
+        match settings.query_to_tags(&terms) {
+            Err(STagError::TooManyNegations(9, 8)) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+        assert!(settings.query_to_tags(&["a/b"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_collection_tag_query() -> TestResult {
+        let mut settings = Settings::default();
+        assert!(settings.collection_tag().is_none());
+
+        let mut source = crate::common::settings::config::HashMapSource(Default::default());
+        source
+            .0
+            .insert("collection.tag".to_string(), "col:photos".into());
+        settings.update_config(source);
+
+        let tags = settings.query_to_tags(&["col:photos", "-wip", "a|col:photos"])?;
+        assert_eq!(
+            tags,
+            vec![
+                TagType::CollectionTag("col:photos".to_string()),
+                TagType::Negation("wip".to_string()),
+                TagType::CollectionTag("col:photos".to_string()),
+            ]
+        );
+
+        // nothing can be outside of its own collection
+        assert!(settings.query_to_tags(&["rust", "-col:photos"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_passthrough() {
+        let mut settings = Settings::default();
+        assert!(!settings.passthrough(Path::new("/photos/cat.jpg")));
+
+        let mut source = crate::common::settings::config::HashMapSource(Default::default());
+        source
+            .0
+            .insert("thumbnails.passthrough".to_string(), true.into());
+        settings.update_config(source);
+
+        assert!(settings.passthrough(Path::new("/photos/cat.jpg")));
+        assert!(settings.passthrough(Path::new("/photos/CAT.JPG")));
+        assert!(!settings.passthrough(Path::new("/photos/notes.txt")));
+        assert!(!settings.passthrough(Path::new("/photos/jpg")));
+    }
 }
@@ -0,0 +1,241 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Changing the device/inode symbols in a collection's config changes how every fully-qualified file name is
+//! rendered, and old names would stop parsing.  Nothing in the database needs rewriting, since names are computed, but
+//! we do need to make sure that no existing names contain the new symbols, and we keep accepting the old symbols for a
+//! few releases so that existing scripts and muscle memory don't break immediately.
+
+use crate::common::constants::{LEGACY_SYMBOL_RELEASES, UNION_TAG_SEPARATOR};
+use crate::common::err::{STagError, STagResult};
+use crate::common::settings::config::Symbols;
+use crate::common::settings::Settings;
+use crate::common::{has_ext_prefix, split_union_tag};
+use crate::sql;
+use crate::sql::types::SymbolRecord;
+use rusqlite::Connection;
+use tracing::{info, warn};
+
+const TAG: &str = "symbols";
+
+/// Ensures that no tag, tag group, or file name contains the configured device symbol.  If one did, a path to it
+/// would be parsed as a fully-qualified device file name.
+pub fn verify_symbols(conn: &Connection, symbols: &Symbols) -> STagResult<()> {
+    let conflicts = sql::names_containing(conn, &symbols.device_char.to_string())?;
+    if conflicts.is_empty() {
+        Ok(())
+    } else {
+        Err(STagError::SymbolConflict(conflicts))
+    }
+}
+
+/// Ensures that the configured filedir and tag group symbols can't be mistaken for each other, and that no existing
+/// name would be mistaken for them.  A name that is exactly the filedir symbol would be parsed as a filedir, and a
+/// name that ends in the tag group symbol, before its extension, would be parsed as a tag group.
+pub fn verify_dir_symbols(conn: &Connection, symbols: &Symbols) -> STagResult<()> {
+    let filedir = &symbols.filedir_str;
+    let group = &symbols.tag_group_str;
+    if filedir.is_empty() || group.is_empty() || filedir == group {
+        return Err(STagError::SymbolConflict(vec![
+            filedir.clone(),
+            group.clone(),
+        ]));
+    }
+
+    let mut conflicts: Vec<String> = sql::names_containing(conn, filedir)?
+        .into_iter()
+        .filter(|name| name == filedir)
+        .collect();
+    conflicts.extend(
+        sql::names_containing(conn, group)?
+            .into_iter()
+            .filter(|name| has_ext_prefix(name, group)),
+    );
+
+    if conflicts.is_empty() {
+        Ok(())
+    } else {
+        Err(STagError::SymbolConflict(conflicts))
+    }
+}
+
+/// Finds the names that read as a union of tags, like `a|b`, because they were made before unions were.  Nothing can
+/// reach them anymore, so they have to be renamed.
+pub fn union_names(conn: &Connection) -> STagResult<Vec<String>> {
+    Ok(sql::names_containing(conn, UNION_TAG_SEPARATOR)?
+        .into_iter()
+        .filter(|name| split_union_tag(name).is_some())
+        .collect())
+}
+
+/// Compares the configured symbols against the symbols the collection was last mounted with, and if they've changed,
+/// verifies and records the new ones.
+/// Returns the legacy symbol pairs that should still be accepted by the parser.
+pub fn migrate_symbols(
+    conn: &mut Connection,
+    settings: &Settings,
+    app_version: &str,
+) -> STagResult<Vec<(char, char)>> {
+    let symbols = settings.get_config().symbols;
+    let history = sql::get_symbol_history(conn)?;
+
+    let changed = match history.iter().find(|rec| rec.retired_version.is_none()) {
+        Some(current) => {
+            current.device_char != symbols.device_char || current.inode_char != symbols.inode_char
+        }
+        None => true,
+    };
+
+    if changed {
+        info!(
+            target: TAG,
+            "Symbols changed to {} {}, verifying", symbols.device_char, symbols.inode_char
+        );
+        verify_symbols(conn, &symbols)?;
+
+        let tx = sql::begin_write(conn)?;
+        sql::replace_symbols(&tx, symbols.device_char, symbols.inode_char, app_version)?;
+        tx.commit()?;
+    }
+
+    let legacy = legacy_symbols(&sql::get_symbol_history(conn)?, &symbols, app_version);
+    if !legacy.is_empty() {
+        warn!(
+            target: TAG,
+            "Still accepting legacy symbols {:?}, these will stop working in a future release", legacy
+        );
+    }
+    Ok(legacy)
+}
+
+/// Picks out the retired symbol pairs that were retired fewer than `LEGACY_SYMBOL_RELEASES` releases ago
+fn legacy_symbols(
+    history: &[SymbolRecord],
+    current: &Symbols,
+    app_version: &str,
+) -> Vec<(char, char)> {
+    let now = release_number(app_version);
+    history
+        .iter()
+        .rev()
+        .filter_map(|rec| {
+            let retired = release_number(rec.retired_version.as_ref()?);
+            let is_current =
+                rec.device_char == current.device_char && rec.inode_char == current.inode_char;
+            if !is_current && now.saturating_sub(retired) < LEGACY_SYMBOL_RELEASES {
+                Some((rec.device_char, rec.inode_char))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Collapses a "major.minor.patch" version into a single number that increments by one each minor release
+fn release_number(version: &str) -> u64 {
+    let mut parts = version.split('.').map(|p| p.parse::<u64>().unwrap_or(0));
+    let major = parts.next().unwrap_or(0);
+    let minor = parts.next().unwrap_or(0);
+    major * 1000 + minor
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::name_template::{NameTemplate, DEFAULT_NAME_TEMPLATE};
+
+    fn record(device_char: char, retired_version: Option<&str>) -> SymbolRecord {
+        SymbolRecord {
+            id: 0,
+            device_char,
+            inode_char: '-',
+            first_version: "0.1.0".to_string(),
+            retired_version: retired_version.map(String::from),
+        }
+    }
+
+    fn symbols(filedir_str: &str, tag_group_str: &str) -> Symbols {
+        Symbols {
+            device_char: '@',
+            inode_char: '-',
+            sync_char: '\u{7f}',
+            filedir_str: filedir_str.to_string(),
+            filedir_cli_str: "_".to_string(),
+            tag_group_str: tag_group_str.to_string(),
+            name_template: NameTemplate::parse(DEFAULT_NAME_TEMPLATE).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_verify_dir_symbols() -> STagResult<()> {
+        let mut conn = Connection::open_in_memory()?;
+        sql::migrations::migrate(&mut conn, &crate::common::version_str())?;
+        conn.execute(
+            "INSERT INTO tags (id, tag_name, ts, mtime, uid, gid, permissions)
+            VALUES (1, '∩', 0, 0, 0, 0, 493), (2, 'a∩b', 0, 0, 0, 0, 493), (3, 'c++', 0, 0, 0, 0, 493)",
+            rusqlite::NO_PARAMS,
+        )?;
+
+        verify_dir_symbols(&conn, &symbols("⋂", "+g"))?;
+        // only a name that is exactly the filedir symbol conflicts with it
+        match verify_dir_symbols(&conn, &symbols("∩", "+g")) {
+            Err(STagError::SymbolConflict(names)) => assert_eq!(names, vec!["∩".to_string()]),
+            res => panic!("Wrong result {:?}", res),
+        }
+        match verify_dir_symbols(&conn, &symbols("⋂", "+")) {
+            Err(STagError::SymbolConflict(names)) => assert_eq!(names, vec!["c++".to_string()]),
+            res => panic!("Wrong result {:?}", res),
+        }
+        assert!(verify_dir_symbols(&conn, &symbols("+", "+")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_union_names() -> STagResult<()> {
+        let mut conn = Connection::open_in_memory()?;
+        sql::migrations::migrate(&mut conn, &crate::common::version_str())?;
+        conn.execute(
+            "INSERT INTO tags (id, tag_name, ts, mtime, uid, gid, permissions)
+            VALUES (1, 'rock|pop', 0, 0, 0, 0, 493), (2, 'pipe|', 0, 0, 0, 0, 493), (3, 'jazz', 0, 0, 0, 0, 493)",
+            rusqlite::NO_PARAMS,
+        )?;
+
+        // a name with an empty side isn't a union, so it can still be reached
+        assert_eq!(union_names(&conn)?, vec!["rock|pop".to_string()]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_legacy_window() {
+        let current = symbols("⋂", "+");
+        let history = vec![
+            record('#', Some("0.1.0")),
+            record('﹫', Some("0.3.0")),
+            record('@', None),
+        ];
+        assert_eq!(
+            legacy_symbols(&history, &current, "0.3.5"),
+            vec![('﹫', '-')]
+        );
+        assert_eq!(
+            legacy_symbols(&history, &current, "0.2.0"),
+            vec![('﹫', '-'), ('#', '-')]
+        );
+        assert!(legacy_symbols(&history, &current, "0.6.0").is_empty());
+    }
+}
@@ -0,0 +1,143 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+//! Keeps the same collection in step on two machines, for `tag sync`.  Each side sends the other its state, every file
+//! with its tags and when they last changed, plus the files it has removed, as one line of JSON.  Both sides take
+//! their own state before applying the other's, so that applying it the same way on each side leaves them agreeing.
+//!
+//! The side that runs `tag sync` reaches the other over ssh, running `tag sync --serve` there, which speaks the
+//! protocol on its stdin and stdout.  Files are matched up by their path, after any remaps, or otherwise by their
+//! contents, since device/inode numbers mean nothing on another machine.  Whichever side last changed a file's tags
+//! wins.
+
+use crate::common::err::{STagError, STagResult};
+use crate::sql;
+use crate::sql::sync::{SyncFile, Tombstone};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::io::{BufRead, Write};
+use tracing::debug;
+
+/// Bumped whenever `SyncState` changes, since both sides have to agree on it
+pub const SYNC_PROTOCOL_VERSION: u32 = 1;
+
+const TAG: &str = "sync";
+
+/// Everything one side of a sync tells the other about its collection
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SyncState {
+    pub version: u32,
+    pub files: Vec<SyncFile>,
+    pub tombstones: Vec<Tombstone>,
+}
+
+impl SyncState {
+    pub fn load(conn: &Connection) -> STagResult<Self> {
+        Ok(Self {
+            version: SYNC_PROTOCOL_VERSION,
+            files: sql::sync::sync_files(conn)?,
+            tombstones: sql::sync::tombstones(conn)?,
+        })
+    }
+}
+
+/// What applying the other side's state changed here
+#[derive(Default, Debug)]
+pub struct SyncSummary {
+    /// Files whose tags were replaced with the other side's
+    pub retagged: usize,
+    /// Files that were only tagged on the other side
+    pub added: usize,
+    /// Files that the other side removed
+    pub removed: usize,
+    /// Files that were only tagged on the other side, but don't exist here
+    pub missing: usize,
+    /// Every tag that gained or lost a file
+    pub tags: BTreeSet<String>,
+}
+
+/// Sends `state` to the other side
+pub fn send_state<W: Write>(mut writer: W, state: &SyncState) -> STagResult<()> {
+    debug!(target: TAG, "Sending {} files", state.files.len());
+    let line = serde_json::to_string(state).map_err(|e| STagError::Other(Box::new(e)))?;
+    writeln!(writer, "{}", line)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads the other side's state, which has to be from the same version of the protocol
+pub fn recv_state<R: BufRead>(mut reader: R) -> STagResult<SyncState> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(STagError::Other(
+            "The other side hung up without syncing".into(),
+        ));
+    }
+    let state: SyncState =
+        serde_json::from_str(&line).map_err(|e| STagError::Other(Box::new(e)))?;
+    if state.version != SYNC_PROTOCOL_VERSION {
+        return Err(STagError::Other(
+            format!(
+                "The other side speaks sync protocol {}, but we speak {}",
+                state.version, SYNC_PROTOCOL_VERSION
+            )
+            .into(),
+        ));
+    }
+    debug!(target: TAG, "Received {} files", state.files.len());
+    Ok(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_roundtrip() -> STagResult<()> {
+        let state = SyncState {
+            version: SYNC_PROTOCOL_VERSION,
+            files: vec![SyncFile {
+                id: 1,
+                device: 2,
+                inode: 3,
+                path: "/home/amy/a.txt".to_string(),
+                content_hash: None,
+                primary_tag: "a.txt".to_string(),
+                tags: vec!["t1".to_string(), "t2".to_string()],
+                tags_mtime: 10.5,
+            }],
+            tombstones: vec![Tombstone {
+                path: "/home/amy/b.txt".to_string(),
+                content_hash: Some("abc".to_string()),
+                ts: 11.0,
+            }],
+        };
+        let mut sent = vec![];
+        send_state(&mut sent, &state)?;
+        assert_eq!(recv_state(sent.as_slice())?, state);
+
+        let mut old = state.clone();
+        old.version = SYNC_PROTOCOL_VERSION + 1;
+        let mut sent = vec![];
+        send_state(&mut sent, &old)?;
+        assert!(recv_state(sent.as_slice()).is_err());
+
+        assert!(recv_state(&b""[..]).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,287 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Just enough of the ustar format to bundle regular files, like a diagnostics report or an exported collection, into
+//! an archive that any archive tool can open, and to read those archives back.  Names that don't fit in a ustar
+//! header are carried in a pax `path` record before their entry.
+
+use std::io::{Error, ErrorKind, Result, Write};
+
+const BLOCK: usize = 512;
+const NAME_LEN: usize = 100;
+const PREFIX_LEN: usize = 155;
+const REGULAR: u8 = b'0';
+const PAX_HEADER: u8 = b'x';
+/// What the pax header entries are called, for tools that don't know pax and extract them as files
+const PAX_HEADER_NAME: &str = "././@PaxHeader";
+
+/// Writes `value` as a NUL-terminated octal number filling `field`
+fn octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[digits.len()] = 0;
+}
+
+/// Splits a long name at a slash into the prefix and name fields, the way ustar allows.  Names that can't be split
+/// don't fit in a ustar header at all.
+fn split_name(name: &str) -> Option<(&str, &str)> {
+    if name.len() <= NAME_LEN {
+        return Some(("", name));
+    }
+    name.match_indices('/')
+        .map(|(idx, _)| (&name[..idx], &name[idx + 1..]))
+        .find(|(prefix, rest)| prefix.len() <= PREFIX_LEN && rest.len() <= NAME_LEN)
+}
+
+/// A pax extended header record, which starts with its own length in bytes, counting the digits of that length
+fn pax_record(key: &str, value: &str) -> String {
+    let rest = format!(" {}={}\n", key, value);
+    let mut len = rest.len();
+    while (len.to_string().len() + rest.len()) != len {
+        len = len.to_string().len() + rest.len();
+    }
+    format!("{}{}", len, rest)
+}
+
+/// The longest prefix of `name` that is at most `max` bytes, without splitting a character
+fn truncated(name: &str, max: usize) -> &str {
+    let mut end = name.len().min(max);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    &name[..end]
+}
+
+/// A header for `name`, which must already fit in a ustar header if it's longer than `NAME_LEN`
+fn header(name: &str, kind: u8, size: u64, mtime: u64) -> [u8; BLOCK] {
+    let mut block = [0u8; BLOCK];
+    let (prefix, name) = split_name(name).unwrap_or(("", truncated(name, NAME_LEN)));
+    block[..name.len()].copy_from_slice(name.as_bytes());
+    octal(&mut block[100..108], 0o644);
+    octal(&mut block[108..116], 0);
+    octal(&mut block[116..124], 0);
+    octal(&mut block[124..136], size);
+    octal(&mut block[136..148], mtime);
+    block[156] = kind;
+    block[257..263].copy_from_slice(b"ustar\0");
+    block[263..265].copy_from_slice(b"00");
+    block[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+    // the checksum is computed as if its own field were spaces
+    block[148..156].copy_from_slice(b"        ");
+    let checksum: u64 = block.iter().map(|b| *b as u64).sum();
+    let digits = format!("{:06o}\0 ", checksum);
+    block[148..156].copy_from_slice(digits.as_bytes());
+    block
+}
+
+fn append_entry<W: Write>(out: &mut W, header: &[u8; BLOCK], contents: &[u8]) -> Result<()> {
+    out.write_all(header)?;
+    out.write_all(contents)?;
+    let padding = (BLOCK - contents.len() % BLOCK) % BLOCK;
+    out.write_all(&vec![0u8; padding])
+}
+
+/// Appends a regular file to the archive.  Names longer than 100 bytes are split at a slash if they can be.  If they
+/// can't, the whole name goes in a pax header entry before the file, and the file's own header gets a truncated
+/// name, which is only seen by tools that don't know pax.
+pub fn append<W: Write>(out: &mut W, name: &str, contents: &[u8], mtime: u64) -> Result<()> {
+    if split_name(name).is_none() {
+        let record = pax_record("path", name);
+        let pax = header(PAX_HEADER_NAME, PAX_HEADER, record.len() as u64, mtime);
+        append_entry(out, &pax, record.as_bytes())?;
+    }
+    append_entry(
+        out,
+        &header(name, REGULAR, contents.len() as u64, mtime),
+        contents,
+    )
+}
+
+/// Ends the archive.  Nothing may be appended afterwards.
+pub fn finish<W: Write>(out: &mut W) -> Result<()> {
+    out.write_all(&[0u8; BLOCK * 2])
+}
+
+/// A NUL-terminated string field
+fn field_str(field: &[u8]) -> Result<&str> {
+    let end = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    std::str::from_utf8(&field[..end]).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+/// A NUL or space terminated octal number field
+fn field_octal(field: &[u8]) -> Result<u64> {
+    let digits = field_str(field)?.trim();
+    u64::from_str_radix(digits, 8).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+/// The `path` from the records of a pax extended header, if it has one
+fn pax_path(mut records: &[u8]) -> Result<Option<String>> {
+    let bad = || Error::new(ErrorKind::InvalidData, "Bad pax header record");
+    let mut path = None;
+    while !records.is_empty() {
+        let space = records.iter().position(|b| *b == b' ').ok_or_else(bad)?;
+        let len: usize = std::str::from_utf8(&records[..space])
+            .ok()
+            .and_then(|len| len.parse().ok())
+            .filter(|len| *len > space + 1 && *len <= records.len())
+            .ok_or_else(bad)?;
+        let record = std::str::from_utf8(&records[space + 1..len - 1])
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        if let Some(value) = record.strip_prefix("path=") {
+            path = Some(value.to_string());
+        }
+        records = &records[len..];
+    }
+    Ok(path)
+}
+
+/// Reads every regular file out of an archive, as (name, contents) pairs.  Anything that isn't a regular file, like a
+/// directory, is skipped.
+pub fn entries(data: &[u8]) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut entries = vec![];
+    let mut offset = 0;
+    // the name that the last pax header gave to the entry after it
+    let mut pax_name = None;
+    while offset + BLOCK <= data.len() {
+        let block = &data[offset..offset + BLOCK];
+        if block.iter().all(|b| *b == 0) {
+            break;
+        }
+        if &block[257..262] != b"ustar" {
+            return Err(Error::new(ErrorKind::InvalidData, "Not a ustar archive"));
+        }
+
+        let name = field_str(&block[..NAME_LEN])?;
+        let prefix = field_str(&block[345..345 + PREFIX_LEN])?;
+        let size = field_octal(&block[124..136])? as usize;
+        let start = offset + BLOCK;
+        let end = start + size;
+        if end > data.len() {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "Archive is truncated"));
+        }
+
+        match block[156] {
+            PAX_HEADER => pax_name = pax_path(&data[start..end])?,
+            REGULAR | 0 => {
+                let full_name = match pax_name.take() {
+                    Some(full_name) => full_name,
+                    None if prefix.is_empty() => name.to_string(),
+                    None => format!("{}/{}", prefix, name),
+                };
+                entries.push((full_name, data[start..end].to_vec()));
+            }
+            _ => pax_name = None,
+        }
+        offset = start + (size + BLOCK - 1) / BLOCK * BLOCK;
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_archive_layout() -> Result<()> {
+        let mut out = vec![];
+        append(&mut out, "report/version.txt", b"0.1.4\n", 1_600_000_000)?;
+        append(&mut out, "report/empty.txt", b"", 1_600_000_000)?;
+        finish(&mut out)?;
+
+        // a header and a padded block of contents, a bare header, then the two terminating blocks
+        assert_eq!(out.len(), BLOCK * 5);
+        assert_eq!(&out[..18], b"report/version.txt");
+        assert_eq!(&out[124..136], b"00000000006\0");
+        assert_eq!(&out[257..263], b"ustar\0");
+        assert_eq!(&out[BLOCK..BLOCK + 6], b"0.1.4\n");
+        assert!(out[BLOCK * 3..].iter().all(|b| *b == 0));
+
+        let mut blank = out[..BLOCK].to_vec();
+        blank[148..156].copy_from_slice(b"        ");
+        let expected: u64 = blank.iter().map(|b| *b as u64).sum();
+        let recorded = std::str::from_utf8(&out[148..154]).unwrap();
+        assert_eq!(u64::from_str_radix(recorded, 8).unwrap(), expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_round_trip() -> Result<()> {
+        let long_name = format!("managed_files/{}/{}", "ab/".repeat(16), "c".repeat(32));
+        assert!(long_name.len() > NAME_LEN);
+
+        let mut out = vec![];
+        append(&mut out, "tables/tags.json", b"[]", 1_600_000_000)?;
+        append(&mut out, &long_name, &[7u8; 600], 1_600_000_000)?;
+        append(&mut out, "empty", b"", 1_600_000_000)?;
+        finish(&mut out)?;
+
+        let read = entries(&out)?;
+        assert_eq!(read.len(), 3);
+        assert_eq!(read[0], ("tables/tags.json".to_string(), b"[]".to_vec()));
+        assert_eq!(read[1], (long_name, vec![7u8; 600]));
+        assert_eq!(read[2], ("empty".to_string(), vec![]));
+
+        assert!(entries(&[1u8; BLOCK]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that names that can't be split into a ustar prefix and name survive the round trip whole, instead of
+    /// being truncated
+    fn test_round_trip_unsplittable() -> Result<()> {
+        // a final component longer than the name field
+        let long_leaf = format!("managed_files/{}", "é".repeat(80));
+        // a directory longer than the prefix field
+        let long_dir = format!("{}/{}", "d".repeat(200), "f".repeat(10));
+        // which would both be truncated to this, colliding
+        let collides = format!("managed_files/{}x", "é".repeat(80));
+        for name in &[&long_leaf, &long_dir, &collides] {
+            assert!(split_name(name).is_none());
+        }
+
+        let mut out = vec![];
+        append(&mut out, &long_leaf, b"leaf", 1_600_000_000)?;
+        append(&mut out, "short", b"short", 1_600_000_000)?;
+        append(&mut out, &long_dir, b"dir", 1_600_000_000)?;
+        append(&mut out, &collides, b"collides", 1_600_000_000)?;
+        finish(&mut out)?;
+
+        let read = entries(&out)?;
+        assert_eq!(
+            read,
+            vec![
+                (long_leaf, b"leaf".to_vec()),
+                ("short".to_string(), b"short".to_vec()),
+                (long_dir, b"dir".to_vec()),
+                (collides, b"collides".to_vec()),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_pax_record() {
+        // the length counts its own digits, which can carry it over into another digit
+        assert_eq!(pax_record("path", "a"), "9 path=a\n");
+        let record = pax_record("path", &"a".repeat(91));
+        assert_eq!(record.len(), 101);
+        assert!(record.starts_with("101 "));
+        assert_eq!(pax_path(record.as_bytes()).unwrap(), Some("a".repeat(91)));
+    }
+}
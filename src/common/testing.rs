@@ -0,0 +1,103 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! A collection for unit tests to work against, without having to mount it
+
+use crate::common::settings::config::{self, HashMapSource};
+use crate::common::settings::dirs::Dirs;
+use crate::common::settings::Settings;
+use crate::common::types::file_perms::UMask;
+use crate::sql;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub(crate) const COLLECTION: &str = "test";
+
+/// Every project dir under one temporary dir
+struct TempDirs {
+    project: PathBuf,
+    cache: PathBuf,
+    config: PathBuf,
+    data: PathBuf,
+    data_local: PathBuf,
+    mount: PathBuf,
+}
+
+impl Dirs for TempDirs {
+    fn project_path(&self) -> &Path {
+        &self.project
+    }
+
+    fn cache_dir(&self) -> &Path {
+        &self.cache
+    }
+
+    fn config_dir(&self) -> &Path {
+        &self.config
+    }
+
+    fn data_dir(&self) -> &Path {
+        &self.data
+    }
+
+    fn data_local_dir(&self) -> &Path {
+        &self.data_local
+    }
+
+    fn mount_dir(&self) -> PathBuf {
+        self.mount.clone()
+    }
+}
+
+/// Settings for `COLLECTION`, with its files and a migrated database under `dir`.  `overrides` are config values, like
+/// `("shutdown.deadline_ms", 100)`.
+pub(crate) fn settings(dir: &Path, overrides: &[(&str, i64)]) -> Arc<Settings> {
+    let mut source = HashMapSource(Default::default());
+    source.0.insert(
+        "mount.uid".to_string(),
+        (unsafe { libc::getuid() } as i64).into(),
+    );
+    source.0.insert(
+        "mount.gid".to_string(),
+        (unsafe { libc::getgid() } as i64).into(),
+    );
+    source.0.insert(
+        "mount.permissions".to_string(),
+        UMask::default().dir_perms().octal_string().into(),
+    );
+    for (key, value) in overrides {
+        source.0.insert(key.to_string(), (*value).into());
+    }
+
+    let dirs = Arc::new(TempDirs {
+        project: dir.join("project"),
+        cache: dir.join("cache"),
+        config: dir.join("config"),
+        data: dir.join("data"),
+        data_local: dir.join("data_local"),
+        mount: dir.join("mount"),
+    });
+    let conf = config::build(source, &*dirs);
+    let mut settings = Settings::new(dirs).unwrap();
+    settings.update_config(conf);
+    settings.set_collection(COLLECTION, true);
+
+    let mut conn = sql::get_conn(settings.db_file(COLLECTION)).unwrap();
+    sql::migrations::migrate(&mut conn, &crate::common::version_str()).unwrap();
+    Arc::new(settings)
+}
@@ -21,6 +21,15 @@ use std::path::PathBuf;
 
 pub(crate) enum CliError {
     InvalidMountDir(PathBuf),
+    MissingProfile(PathBuf),
+    /// The collection didn't come back up at its new mountpoint, and was moved back to the old one
+    RemountFailed(PathBuf, PathBuf),
+    /// An encrypted collection was asked for, but this build can't open one
+    EncryptionUnsupported,
+    /// A supervised mount couldn't mount at all, so it wasn't restarted
+    MountFailed,
+    /// The database didn't pass `PRAGMA quick_check` after a crash, with these problems
+    CorruptDatabase(PathBuf, Vec<String>),
 }
 
 impl Display for CliError {
@@ -37,6 +46,30 @@ impl Debug for CliError {
                 "Mount directory {:?} missing. Please create it first before mounting.",
                 path
             ),
+            CliError::MissingProfile(path) => write!(
+                f,
+                "Mount profile {:?} missing. Please create it with the settings for this mount first.",
+                path
+            ),
+            CliError::RemountFailed(new, old) => write!(
+                f,
+                "Collection didn't mount at {:?}, so it was moved back to {:?}.",
+                new, old
+            ),
+            CliError::EncryptionUnsupported => write!(
+                f,
+                "Encrypted collections need supertag built with the 'encryption' feature."
+            ),
+            CliError::MountFailed => write!(
+                f,
+                "The mount failed, so it wasn't restarted.  See the collection's log for why."
+            ),
+            CliError::CorruptDatabase(path, problems) => write!(
+                f,
+                "Database {:?} is damaged, so it wasn't mounted again: {}",
+                path,
+                problems.join("; ")
+            ),
         }
     }
 }
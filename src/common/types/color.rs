@@ -0,0 +1,120 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+/// The colors a tag can have.  They're the same colors that Finder offers as labels, so that they can be shown there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TagColor {
+    Gray,
+    Green,
+    Purple,
+    Blue,
+    Yellow,
+    Red,
+    Orange,
+}
+
+impl TagColor {
+    pub const ALL: &'static [TagColor] = &[
+        TagColor::Gray,
+        TagColor::Green,
+        TagColor::Purple,
+        TagColor::Blue,
+        TagColor::Yellow,
+        TagColor::Red,
+        TagColor::Orange,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TagColor::Gray => "gray",
+            TagColor::Green => "green",
+            TagColor::Purple => "purple",
+            TagColor::Blue => "blue",
+            TagColor::Yellow => "yellow",
+            TagColor::Red => "red",
+            TagColor::Orange => "orange",
+        }
+    }
+
+    /// The color's index in the label bits of a Finder info record
+    pub fn finder_label(&self) -> u8 {
+        match self {
+            TagColor::Gray => 1,
+            TagColor::Green => 2,
+            TagColor::Purple => 3,
+            TagColor::Blue => 4,
+            TagColor::Yellow => 5,
+            TagColor::Red => 6,
+            TagColor::Orange => 7,
+        }
+    }
+
+    /// A Finder info record, as stored in the `com.apple.FinderInfo` xattr, that labels a directory with this color
+    pub fn finder_info(&self) -> Vec<u8> {
+        let mut info = vec![0u8; 32];
+        // the label lives in bits 1-3 of the low byte of the big-endian finder flags, which start at byte 8
+        info[9] = self.finder_label() << 1;
+        info
+    }
+}
+
+impl Display for TagColor {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for TagColor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lower = s.to_lowercase();
+        let lower = if lower == "grey" { "gray" } else { &lower };
+        TagColor::ALL
+            .iter()
+            .find(|color| color.as_str() == lower)
+            .copied()
+            .ok_or_else(|| format!("Unknown color {}", s))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("Red".parse::<TagColor>(), Ok(TagColor::Red));
+        assert_eq!("grey".parse::<TagColor>(), Ok(TagColor::Gray));
+        assert!("mauve".parse::<TagColor>().is_err());
+        for color in TagColor::ALL {
+            assert_eq!(color.as_str().parse::<TagColor>().as_ref(), Ok(color));
+        }
+    }
+
+    #[test]
+    fn test_finder_info() {
+        let info = TagColor::Red.finder_info();
+        assert_eq!(info.len(), 32);
+        assert_eq!((info[9] >> 1) & 0b111, 6);
+        assert!(info.iter().enumerate().all(|(i, b)| i == 9 || *b == 0));
+    }
+}
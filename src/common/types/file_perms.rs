@@ -18,7 +18,7 @@
 
 use crate::common::err::ParseOctalError;
 use core::fmt;
-use libc::mode_t;
+use libc::{gid_t, mode_t, uid_t};
 use rusqlite::types::ToSqlOutput;
 use rusqlite::{Error, ToSql};
 use serde::de::Visitor;
@@ -135,6 +135,15 @@ impl From<mode_t> for ClassPerms {
     }
 }
 
+/// What a user wants to do with something, checked against the matching bit of its permissions.  For a directory,
+/// `Execute` is searching it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
 #[derive(Clone, PartialEq, Eq, Hash)]
 pub struct Permissions {
     owner: ClassPerms,
@@ -167,6 +176,34 @@ impl Permissions {
     pub fn octal_string(&self) -> String {
         format!("{:03o}", self.mode())
     }
+
+    /// Whether the user `uid` in group `gid` may `access` something owned by `owner_uid` and `owner_gid` with these
+    /// permissions.  Like a real filesystem, only the most specific class that the user falls into counts, and root
+    /// may do anything.
+    pub fn permits(
+        &self,
+        owner_uid: uid_t,
+        owner_gid: gid_t,
+        uid: uid_t,
+        gid: gid_t,
+        access: Access,
+    ) -> bool {
+        if uid == 0 {
+            return true;
+        }
+        let class = if uid == owner_uid {
+            &self.owner
+        } else if gid == owner_gid {
+            &self.group
+        } else {
+            &self.others
+        };
+        match access {
+            Access::Read => class.read,
+            Access::Write => class.write,
+            Access::Execute => class.execute,
+        }
+    }
 }
 
 /// Conversion from octal
@@ -264,6 +301,20 @@ mod tests {
         assert_eq!(perms.mode(), 0o664);
     }
 
+    #[test]
+    fn test_permits() {
+        let perms: Permissions = 0o750.into();
+        assert!(perms.permits(1000, 100, 1000, 100, Access::Write));
+        assert!(perms.permits(1000, 100, 1001, 100, Access::Read));
+        assert!(!perms.permits(1000, 100, 1001, 100, Access::Write));
+        assert!(!perms.permits(1000, 100, 1001, 101, Access::Read));
+        assert!(perms.permits(1000, 100, 0, 0, Access::Write));
+
+        // the owner doesn't fall back to the group's bits
+        let perms: Permissions = 0o070.into();
+        assert!(!perms.permits(1000, 100, 1000, 100, Access::Read));
+    }
+
     #[test]
     fn test_perm_from_int() {
         let perms: Permissions = 0o755.into();
@@ -0,0 +1,54 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// A snapshot of how a mount daemon is doing, for `tag status`
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+pub struct Health {
+    pub pid: u32,
+    pub collection: String,
+    pub db_path: PathBuf,
+    pub mountpoint: PathBuf,
+    pub uptime_secs: u64,
+    pub num_tags: i64,
+    pub num_files: i64,
+    /// How many fuse requests the daemon has served since it started, for every collection it has mounted
+    pub requests: usize,
+    /// Why writes are being held off, if they are
+    pub write_gate: Option<String>,
+    /// Whether writes are being held off because the database's disk is full
+    #[serde(default)]
+    pub disk_full: bool,
+    pub cache: CacheSizes,
+}
+
+/// How many live entries each of the operation caches holds
+#[derive(PartialEq, Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CacheSizes {
+    pub readdir: usize,
+    pub readdir_pages: usize,
+    pub symlinks: usize,
+    pub aliases: usize,
+    pub unlink_canaries: usize,
+    pub rename_deletes: usize,
+    /// Tags looked up since the last write, see `fuse::tagcache`
+    #[serde(default)]
+    pub tags: usize,
+}
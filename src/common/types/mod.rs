@@ -16,11 +16,16 @@
  * along with this program.  If not, see <http://www.gnu.org/licenses/>.
  */
 
-use crate::common::constants::NEGATIVE_TAG_PREFIX;
+use crate::common::constants::{
+    ALL_OF_GROUP_SUFFIX, DATE_TAG_PREFIX, META_TAG_PREFIX, META_TAG_SEPARATOR, NEGATIVE_TAG_PREFIX,
+    RATING_TAG_PREFIX, RATING_TAG_SUFFIX, UNION_TAG_SEPARATOR,
+};
 use crate::common::err::{STagError, STagResult};
+use crate::common::query::{self, Expr};
 use crate::common::set_ext_prefix;
 use crate::common::settings::Settings;
 use crate::sql::types::TaggedFile;
+use std::convert::Infallible;
 use std::fmt::Debug;
 use std::path::{Path, PathBuf};
 use std::slice::Iter;
@@ -28,7 +33,9 @@ use std::slice::Iter;
 pub type UtcDt = chrono::DateTime<chrono::Utc>;
 
 pub mod cli;
+pub mod color;
 pub mod file_perms;
+pub mod health;
 pub mod note;
 
 #[derive(PartialEq, Eq, Debug, Clone)]
@@ -94,9 +101,28 @@ pub enum TagType {
     Regular(String),
     Negation(String),
     Group(String),
+    /// A tag group that matches files tagged with every tag in it, instead of any of them, ie `a_tags+!`
+    GroupAll(String),
+    /// Matches files tagged with any of its members, ie `music|podcasts`
+    Union(Vec<String>),
+    /// Matches files whose metadata `key` is `value`, ie `meta:client=acme`
+    Meta(String, String),
+    /// Matches files by a boolean expression of tags, ie `?(rust&(cli|tui)&!wip)`
+    Expression(Expr),
+    /// Matches files last modified in a year or a month, ie `@2021` or `@2021-07`
+    Date(String),
+    /// Matches files rated at least so many stars, ie `★4+`
+    Rating(u8),
+    /// The virtual tag group of file extensions, ie `filetype+`, which is listed with the extensions of the files in
+    /// the intersection before it
+    FileTypeGroup,
+    /// Matches files with an extension, ignoring its case, ie the `pdf` of `filetype+/pdf`
+    FileType(String),
     FileDir,
     DeviceFileSymlink(DeviceFile),
     Symlink(String),
+    /// The collection's own tag, which every file in the collection has without being linked to it
+    CollectionTag(String),
 }
 
 impl TagType {
@@ -106,9 +132,28 @@ impl TagType {
             TagType::Regular(tag) => tag.to_string(),
             TagType::Negation(tag) => format!("{}{}", NEGATIVE_TAG_PREFIX, tag),
             TagType::Group(tag) => set_ext_prefix(&tag, &syms.tag_group_str),
+            TagType::GroupAll(tag) => format!(
+                "{}{}",
+                set_ext_prefix(&tag, &syms.tag_group_str),
+                ALL_OF_GROUP_SUFFIX
+            ),
+            TagType::Union(tags) => tags.join(UNION_TAG_SEPARATOR),
+            TagType::Meta(key, value) => {
+                format!("{}{}{}{}", META_TAG_PREFIX, key, META_TAG_SEPARATOR, value)
+            }
+            TagType::Expression(expr) => query::to_component(expr),
+            TagType::Date(bucket) => format!("{}{}", DATE_TAG_PREFIX, bucket),
+            TagType::Rating(stars) => {
+                format!("{}{}{}", RATING_TAG_PREFIX, stars, RATING_TAG_SUFFIX)
+            }
+            TagType::FileTypeGroup => {
+                set_ext_prefix(&settings.get_config().filetypes.group, &syms.tag_group_str)
+            }
+            TagType::FileType(ext) => ext.to_string(),
             TagType::FileDir => syms.filedir_str.to_string(),
             TagType::DeviceFileSymlink(df) => df.inodify(settings),
             TagType::Symlink(f) => f.to_string(),
+            TagType::CollectionTag(tag) => tag.to_string(),
         }
     }
 }
@@ -119,19 +164,56 @@ impl std::fmt::Display for TagType {
             TagType::Regular(tag) => write!(f, "Regular({})", tag),
             TagType::Negation(tag) => write!(f, "Negation({})", tag),
             TagType::Group(tag) => write!(f, "Group({})", tag),
+            TagType::GroupAll(tag) => write!(f, "GroupAll({})", tag),
+            TagType::Union(tags) => write!(f, "Union({})", tags.join(", ")),
+            TagType::Meta(key, value) => write!(f, "Meta({}={})", key, value),
+            TagType::Expression(expr) => write!(f, "Expression({})", expr),
+            TagType::Date(bucket) => write!(f, "Date({})", bucket),
+            TagType::Rating(stars) => write!(f, "Rating({})", stars),
+            TagType::FileTypeGroup => write!(f, "FileTypeGroup"),
+            TagType::FileType(ext) => write!(f, "FileType({})", ext),
             TagType::FileDir => write!(f, "FileDir"),
             TagType::DeviceFileSymlink(df) => write!(f, "{}", df),
             TagType::Symlink(fl) => write!(f, "Symlink({})", fl),
+            TagType::CollectionTag(tag) => write!(f, "CollectionTag({})", tag),
+        }
+    }
+}
+
+/// How to settle a filename collision when merging one tag into another, where a file coming from the source tag has
+/// the same name as a file already in the destination
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum MergeResolution {
+    /// Both files are merged, and are told apart by their device/inode names
+    KeepBoth,
+    /// Whichever file was modified most recently wins
+    Newest,
+    /// The source file wins, and the destination file loses the destination's tags
+    Source,
+    /// The destination file wins, and the source file stays behind in the source tag
+    Dest,
+}
+
+impl MergeResolution {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "keep-both" => Some(MergeResolution::KeepBoth),
+            "newest" => Some(MergeResolution::Newest),
+            "source" => Some(MergeResolution::Source),
+            "dest" => Some(MergeResolution::Dest),
+            _ => None,
         }
     }
 }
 
 pub trait TagCollectible<'a> {
     fn collect_regular_names(self) -> Vec<&'a str>;
+    fn collect_union_names(self) -> Vec<&'a str>;
     fn collect_regular(self) -> Vec<TagType>;
     fn collect_pinnable(self) -> Vec<TagType>;
     fn collect_tags_and_groups(self) -> Vec<TagType>;
     fn taggroup_pairs(self) -> Vec<(&'a str, &'a str)>;
+    fn nested_group_pairs(self) -> Vec<(&'a str, &'a str)>;
 }
 
 impl<'a, T> TagCollectible<'a> for T
@@ -149,6 +231,18 @@ where
         .collect::<Vec<_>>()
     }
 
+    fn collect_union_names(self) -> Vec<&'a str> {
+        self.filter_map(|tt| {
+            if let TagType::Union(tags) = tt {
+                Some(tags.iter().map(|t| t.as_str()))
+            } else {
+                None
+            }
+        })
+        .flatten()
+        .collect::<Vec<_>>()
+    }
+
     fn collect_regular(self) -> Vec<TagType> {
         self.filter_map(|tt| {
             if let TagType::Regular(_tag) = tt {
@@ -161,29 +255,11 @@ where
     }
 
     /// Collect together all the tagtypes that can be considered valid for pinning.
-    /// This filters out consecutive tag groups, as it doesn't make sense (does it?)
+    /// Consecutive tag groups are kept, since the second is nested in the first
     fn collect_pinnable(self) -> Vec<TagType> {
-        let mut pinnable = vec![];
-        let mut last_was_group = false;
-        for tt in self {
-            match tt {
-                TagType::Regular(_tag) => {
-                    pinnable.push(tt.to_owned());
-                    last_was_group = false;
-                }
-                TagType::Group(_tag) => {
-                    if last_was_group {
-                        continue;
-                    }
-                    pinnable.push(tt.to_owned());
-                    last_was_group = true;
-                }
-                _ => {
-                    last_was_group = false;
-                }
-            }
-        }
-        pinnable
+        self.filter(|tt| matches!(tt, TagType::Regular(_) | TagType::Group(_)))
+            .map(|tt| tt.to_owned())
+            .collect()
     }
 
     fn collect_tags_and_groups(self) -> Vec<TagType> {
@@ -197,15 +273,27 @@ where
     fn taggroup_pairs(self) -> Vec<(&'a str, &'a str)> {
         self.collect::<Vec<_>>()
             .windows(2)
-            .filter_map(|el| {
-                if let (TagType::Group(group), TagType::Regular(tag)) = (el[0], el[1]) {
+            .filter_map(|el| match (el[0], el[1]) {
+                (TagType::Group(group), TagType::Regular(tag))
+                | (TagType::GroupAll(group), TagType::Regular(tag)) => {
                     Some((group.as_str(), tag.as_str()))
-                } else {
-                    None
                 }
+                _ => None,
             })
             .collect::<Vec<_>>()
     }
+
+    /// (parent, child) for every tag group that directly follows another tag group, which must be nested in it
+    fn nested_group_pairs(self) -> Vec<(&'a str, &'a str)> {
+        let group_name = |tt: &'a TagType| match tt {
+            TagType::Group(group) | TagType::GroupAll(group) => Some(group.as_str()),
+            _ => None,
+        };
+        self.collect::<Vec<_>>()
+            .windows(2)
+            .filter_map(|el| Some((group_name(el[0])?, group_name(el[1])?)))
+            .collect::<Vec<_>>()
+    }
 }
 
 #[derive(Debug)]
@@ -238,6 +326,44 @@ impl TagCollection {
         self.tags.pop()
     }
 
+    /// Renames every tag, wherever it appears, to what `canonical_tag` says it's really called, and every tag group to
+    /// what `canonical_group` says, if they say anything
+    pub fn canonicalize<T, G>(&mut self, mut canonical_tag: T, mut canonical_group: G)
+    where
+        T: FnMut(&str) -> Option<String>,
+        G: FnMut(&str) -> Option<String>,
+    {
+        fn rename(name: &mut String, canonical: &mut dyn FnMut(&str) -> Option<String>) {
+            if let Some(renamed) = canonical(name) {
+                *name = renamed;
+            }
+        }
+        for tt in self.tags.iter_mut() {
+            match tt {
+                TagType::Regular(name) | TagType::Negation(name) => {
+                    rename(name, &mut canonical_tag)
+                }
+                TagType::Union(names) => {
+                    for name in names.iter_mut() {
+                        rename(name, &mut canonical_tag);
+                    }
+                }
+                TagType::Expression(expr) => {
+                    let renamed = expr.try_map_tags(&mut |name| {
+                        Ok::<_, Infallible>(canonical_tag(name).unwrap_or_else(|| name.to_string()))
+                    });
+                    if let Ok(renamed) = renamed {
+                        *expr = renamed;
+                    }
+                }
+                TagType::Group(name) | TagType::GroupAll(name) => {
+                    rename(name, &mut canonical_group)
+                }
+                _ => (),
+            }
+        }
+    }
+
     pub fn push(&mut self, val: TagType) {
         self.tags.push(val)
     }
@@ -270,6 +396,34 @@ impl TagCollection {
             .into()
     }
 
+    /// A compact digest of the entry this collection points to.  The tags leading up to the last one are sorted
+    /// first, so every ordering of the same intersection has the same digest.  A tag group stays attached to whatever
+    /// follows it, since it only qualifies that, and the last tag is kept exactly as it appears in the path.
+    pub fn digest(&self, settings: &Settings) -> [u8; 16] {
+        let context = &self.tags[..self.tags.len().saturating_sub(1)];
+        let mut terms = vec![];
+        let mut parts = context.iter();
+        while let Some(tag) = parts.next() {
+            let mut term = tag.to_path_part(settings);
+            if let TagType::Group(_) | TagType::GroupAll(_) = tag {
+                if let Some(next) = parts.next() {
+                    term.push(std::path::MAIN_SEPARATOR);
+                    term.push_str(&next.to_path_part(settings));
+                }
+            }
+            terms.push(term);
+        }
+        terms.sort();
+
+        if let Some(last) = self.last() {
+            terms.push(last.to_string());
+        }
+        if let Some(name) = self.path.file_name() {
+            terms.push(name.to_string_lossy().into_owned());
+        }
+        md5::compute(terms.join("\0")).0
+    }
+
     pub fn all_but_last(&self) -> Iter<TagType> {
         self.iter().as_slice()[..self.len() - 1].iter()
     }
@@ -286,4 +440,53 @@ impl TagCollection {
     pub fn primary_type(&self) -> STagResult<&TagType> {
         self.last().ok_or(STagError::NotEnoughTags)
     }
+
+    /// Renders the boolean expression that this collection represents, ie `a AND (b OR c) AND NOT d`.  The ordering of
+    /// the terms is normalized, so that two paths that describe the same intersection produce the same expression.
+    pub fn to_query(&self) -> String {
+        let mut regulars = vec![];
+        let mut negations = vec![];
+        for tag in self.iter() {
+            match tag {
+                TagType::Regular(name) => regulars.push(name.to_string()),
+                TagType::Union(names) => {
+                    let mut names = names.clone();
+                    names.sort();
+                    regulars.push(format!("({})", names.join(" OR ")));
+                }
+                TagType::Negation(name) => negations.push(format!("NOT {}", name)),
+                TagType::Expression(expr) => regulars.push(format!("({})", expr.to_query())),
+                TagType::Meta(key, value) => regulars.push(format!(
+                    "{}{}{}{}",
+                    META_TAG_PREFIX, key, META_TAG_SEPARATOR, value
+                )),
+                TagType::Date(bucket) => regulars.push(format!("{}{}", DATE_TAG_PREFIX, bucket)),
+                TagType::Rating(stars) => regulars.push(format!(
+                    "{}{}{}",
+                    RATING_TAG_PREFIX, stars, RATING_TAG_SUFFIX
+                )),
+                TagType::FileType(ext) => regulars.push(format!("*.{}", ext.to_lowercase())),
+                _ => {}
+            }
+        }
+        regulars.sort();
+        regulars.dedup();
+        negations.sort();
+        negations.dedup();
+
+        // only a trailing tag group actually participates in the intersection, so only it makes it into the query
+        let mut groups = vec![];
+        match self.last() {
+            Some(TagType::Group(name)) => groups.push(format!("ANY({})", name)),
+            Some(TagType::GroupAll(name)) => groups.push(format!("ALL({})", name)),
+            _ => {}
+        }
+
+        regulars
+            .into_iter()
+            .chain(groups.into_iter())
+            .chain(negations.into_iter())
+            .collect::<Vec<_>>()
+            .join(" AND ")
+    }
 }
@@ -27,4 +27,16 @@ pub enum Note {
     DraggedToRoot,
     Unlink(PathBuf),
     TagToTagGroup(String),
+    /// Progress of the cache warm-up after mounting, as (done, total) units of work
+    WarmProgress(usize, usize),
+    /// A file that belongs to the collection itself, like its database, was almost tagged
+    ProtectedSource(PathBuf),
+    /// An operation on a path failed, with a human-readable reason
+    OpFailed(PathBuf, String),
+    /// A tag or tag group was merged into another, as (source, destination, number of files moved)
+    Merged(String, String, usize),
+    /// A file's tags were changed in one step, as (file, tags added, tags removed)
+    Retagged(PathBuf, Vec<String>, Vec<String>),
+    /// The disk holding the collection's database filled up, as (database path, bytes still free)
+    DiskFull(PathBuf, u64),
 }
@@ -16,9 +16,9 @@
  * along with this program.  If not, see <http://www.gnu.org/licenses/>.
  */
 
-use log::{debug, info};
 use std::collections::HashMap;
 use std::path::Path;
+use tracing::{debug, info};
 
 /// Renames a file and preserves xattrs
 pub fn rename<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> std::io::Result<()> {
@@ -0,0 +1,279 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Best-effort atomicity for operations that change two collections at once, like moving a file from one collection to
+//! another.  Sqlite can't commit two databases together, so `run` stages the changes in both, then commits the
+//! destination before the source, and keeps an intent file around until both have committed.
+//!
+//! The destination's changes are journaled like any destructive operation, so that they can be undone if the source
+//! never commits.  Each database also records the operation's id in `xtx_intents` as part of its commit.  If we're
+//! interrupted, `recover` finds the intent file later, and looks in the source's database to see how far we got: if the
+//! source committed, the operation is finished, otherwise the destination is rolled back.
+
+use crate::common::err::{STagError, STagResult};
+use crate::common::settings::Settings;
+use crate::{common, sql};
+use nix::fcntl::{flock, FlockArg};
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use tracing::{debug, error, info, warn};
+
+const TAG: &str = "xtx";
+
+/// An operation in progress across two collections, as written to its intent file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Intent {
+    pub id: String,
+    pub op: String,
+    pub src: String,
+    pub dst: String,
+    pub ts: f64,
+}
+
+/// One collection's half of an operation, as seen by the function staging its changes
+pub struct Side<'a> {
+    pub col: &'a str,
+    pub settings: &'a Settings,
+    pub tx: &'a Transaction<'a>,
+}
+
+fn intent_file(settings: &Settings, id: &str) -> PathBuf {
+    settings.xtx_dir().join(format!("{}.json", id))
+}
+
+/// Writes the intent file and returns it, locked.  The lock is held until the operation is done, so that `recover` in
+/// another process leaves it alone.  A process that dies releases its locks, which is how `recover` knows an intent was
+/// interrupted.
+fn write_intent(settings: &Settings, intent: &Intent) -> STagResult<std::fs::File> {
+    std::fs::create_dir_all(settings.xtx_dir())?;
+    let mut file = std::fs::File::create(intent_file(settings, &intent.id))?;
+    flock(file.as_raw_fd(), FlockArg::LockExclusive)?;
+    file.write_all(&serde_json::to_vec(intent).map_err(|e| STagError::Other(Box::new(e)))?)?;
+    // the intent has to be on disk before the destination commits, or there'd be nothing to recover from
+    file.sync_all()?;
+    Ok(file)
+}
+
+fn open(settings: &Settings, col: &str) -> STagResult<Connection> {
+    let mut conn = sql::db_for_collection(settings, col)?;
+    sql::migrations::migrate(&mut conn, &common::version_str())?;
+    Ok(conn)
+}
+
+fn record_intent(
+    tx: &Transaction,
+    intent: &Intent,
+    peer: &str,
+    undo_op_id: Option<i64>,
+) -> STagResult<()> {
+    tx.execute(
+        "INSERT INTO xtx_intents (id, op, peer, undo_op_id, ts) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![intent.id, intent.op, peer, undo_op_id, intent.ts],
+    )?;
+    Ok(())
+}
+
+fn clear_intent(conn: &Connection, id: &str) -> STagResult<()> {
+    conn.execute("DELETE FROM xtx_intents WHERE id=?1", params![id])?;
+    Ok(())
+}
+
+/// Stages an operation `op` in the `src` and `dst` collections with `stage`, then commits it in both.  If the source
+/// fails to commit after the destination has, the destination is rolled back before returning the error.
+pub fn run<T, F>(settings: &Settings, op: &str, src: &str, dst: &str, stage: F) -> STagResult<T>
+where
+    F: FnOnce(&Side, &Side) -> STagResult<T>,
+{
+    if src == dst {
+        return Err(STagError::Other(
+            format!("{} is within one collection, {}", op, src).into(),
+        ));
+    }
+    // anything left over from an earlier interruption has to be settled first, so it can't be confused with this
+    recover(settings)?;
+
+    let intent = Intent {
+        id: uuid::Uuid::new_v4().to_hyphenated().to_string(),
+        op: op.to_string(),
+        src: src.to_string(),
+        dst: dst.to_string(),
+        ts: sql::get_now_secs(),
+    };
+    info!(target: TAG, "Starting {} {} from {} to {}", op, intent.id, src, dst);
+
+    let src_settings = settings.for_collection(src);
+    let dst_settings = settings.for_collection(dst);
+    let mut src_conn = open(&src_settings, src)?;
+    let mut dst_conn = open(&dst_settings, dst)?;
+
+    // two of these running in opposite directions could deadlock if they didn't lock the databases in the same order
+    let (src_tx, dst_tx) = if src < dst {
+        let src_tx = sql::begin_write(&mut src_conn)?;
+        (src_tx, sql::begin_write(&mut dst_conn)?)
+    } else {
+        let dst_tx = sql::begin_write(&mut dst_conn)?;
+        (sql::begin_write(&mut src_conn)?, dst_tx)
+    };
+
+    // the destination commits first, so its changes must be journaled, no matter what `undo.keep` says
+    let keep = dst_settings.get_config().undo.keep.max(1);
+    let detail = format!("{} {} from {}", op, intent.id, src);
+    sql::undo::journal_op(&dst_tx, "xtx", &detail, keep, intent.ts)?;
+    let undo_op_id = sql::undo::current_op_id(&dst_tx)?;
+
+    let res = stage(
+        &Side {
+            col: src,
+            settings: &src_settings,
+            tx: &src_tx,
+        },
+        &Side {
+            col: dst,
+            settings: &dst_settings,
+            tx: &dst_tx,
+        },
+    )?;
+    record_intent(&src_tx, &intent, dst, None)?;
+    record_intent(&dst_tx, &intent, src, undo_op_id)?;
+
+    let _lock = write_intent(settings, &intent)?;
+    if let Err(e) = dst_tx.commit() {
+        let _ = std::fs::remove_file(intent_file(settings, &intent.id));
+        return Err(e.into());
+    }
+    debug!(target: TAG, "{} committed in {}", intent.id, dst);
+
+    if let Err(e) = src_tx.commit() {
+        error!(
+            target: TAG,
+            "{} committed in {} but not {}, rolling it back: {}", intent.id, dst, src, e
+        );
+        // the intent file stays if this fails too, so that `recover` can try again
+        finish(settings, &intent)?;
+        return Err(e.into());
+    }
+    debug!(target: TAG, "{} committed in {}", intent.id, src);
+
+    finish(settings, &intent)?;
+    Ok(res)
+}
+
+/// Finishes or rolls back an interrupted operation, depending on whether its source committed, and removes its
+/// intent file.  Returns true if it was finished, false if it was rolled back.
+fn finish(settings: &Settings, intent: &Intent) -> STagResult<bool> {
+    let mut src_conn = open(&settings.for_collection(&intent.src), &intent.src)?;
+    let mut dst_conn = open(&settings.for_collection(&intent.dst), &intent.dst)?;
+
+    let src_committed = src_conn
+        .query_row(
+            "SELECT 1 FROM xtx_intents WHERE id=?1",
+            params![intent.id],
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some();
+
+    if src_committed {
+        debug!(target: TAG, "{} committed in both, finishing", intent.id);
+        clear_intent(&src_conn, &intent.id)?;
+        clear_intent(&dst_conn, &intent.id)?;
+    } else {
+        let undo_op_id: Option<Option<i64>> = dst_conn
+            .query_row(
+                "SELECT undo_op_id FROM xtx_intents WHERE id=?1",
+                params![intent.id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        match undo_op_id {
+            // the destination never committed, so there's nothing to roll back
+            None => debug!(target: TAG, "{} committed nowhere", intent.id),
+            Some(undo_op_id) => {
+                warn!(
+                    target: TAG,
+                    "{} committed in {} but not {}, rolling back", intent.id, intent.dst, intent.src
+                );
+                let tx = sql::begin_write(&mut dst_conn)?;
+                if let Some(undo_op_id) = undo_op_id {
+                    sql::undo::undo_op(&tx, undo_op_id)?;
+                }
+                clear_intent(&tx, &intent.id)?;
+                tx.commit()?;
+            }
+        }
+    }
+
+    std::fs::remove_file(intent_file(settings, &intent.id))?;
+    Ok(src_committed)
+}
+
+/// Settles every operation that was interrupted before it finished in both of its collections.  Returns the ones that
+/// were finished and the ones that were rolled back.
+pub fn recover(settings: &Settings) -> STagResult<(Vec<Intent>, Vec<Intent>)> {
+    let mut finished = vec![];
+    let mut rolled_back = vec![];
+    let dir = settings.xtx_dir();
+    if !dir.exists() {
+        return Ok((finished, rolled_back));
+    }
+
+    let mut files = std::fs::read_dir(&dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<std::io::Result<Vec<PathBuf>>>()?;
+    files.sort();
+    for file in files {
+        if file.extension() != Some("json".as_ref()) {
+            continue;
+        }
+        // an intent that's still locked belongs to an operation that's still running
+        let locked = std::fs::File::open(&file)?;
+        if flock(locked.as_raw_fd(), FlockArg::LockExclusiveNonblock).is_err() {
+            debug!(target: TAG, "{:?} is still in progress, skipping", file);
+            continue;
+        }
+        let intent = match read_intent(&file) {
+            Some(intent) => intent,
+            None => {
+                warn!(target: TAG, "Skipping unreadable intent file {:?}", file);
+                continue;
+            }
+        };
+
+        info!(
+            target: TAG,
+            "Recovering interrupted {} {}", intent.op, intent.id
+        );
+        // one that can't be settled shouldn't stop the others, or every operation after it
+        match finish(settings, &intent) {
+            Ok(true) => finished.push(intent),
+            Ok(false) => rolled_back.push(intent),
+            Err(e) => error!(
+                target: TAG,
+                "Couldn't recover {} {}: {}", intent.op, intent.id, e
+            ),
+        }
+    }
+    Ok((finished, rolled_back))
+}
+
+fn read_intent(file: &Path) -> Option<Intent> {
+    serde_json::from_slice(&std::fs::read(file).ok()?).ok()
+}
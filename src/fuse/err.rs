@@ -16,7 +16,7 @@
  * along with this program.  If not, see <http://www.gnu.org/licenses/>.
  */
 
-use crate::common::err::STagError;
+use crate::common::err::{sqlite_errno, STagError};
 use core::fmt;
 #[cfg(target_os = "macos")]
 use core_foundation::error::CFError;
@@ -87,7 +87,7 @@ impl From<std::io::Error> for SupertagShimError {
 impl From<SqlError> for SupertagShimError {
     fn from(e: SqlError) -> Self {
         Self {
-            errno: Errno::EIO,
+            errno: sqlite_errno(&e),
             original: Some(Box::new(e)),
         }
     }
@@ -113,12 +113,8 @@ impl From<Box<dyn Error>> for SupertagShimError {
 
 impl From<STagError> for SupertagShimError {
     fn from(e: STagError) -> Self {
-        let new_err = match &e {
-            STagError::PathExists(_p) => Errno::EEXIST,
-            _ => Errno::EIO,
-        };
         Self {
-            errno: new_err,
+            errno: e.errno(),
             original: Some(Box::new(e)),
         }
     }
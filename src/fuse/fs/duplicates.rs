@@ -0,0 +1,180 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! With duplicate detection enabled, every tagged file whose contents are the same as another tagged file's is listed
+//! in a single top-level directory, with the copies of each file next to each other.  Like the saved searches
+//! directory, it's recognized before any tag parsing happens, and it's read-only.
+
+use super::super::err::SupertagShimError;
+use super::super::util;
+use super::TagFilesystem;
+use super::OP_TAG;
+use crate::common;
+use crate::common::types::file_perms::UMask;
+use crate::common::types::UtcDt;
+use crate::fuse::opcache::ReaddirCacheEntry;
+use crate::sql;
+use crate::sql::types::TaggedFile;
+use fuse_sys::err::FuseErrno;
+use fuse_sys::{stat, FileEntry, FuseResult, Request};
+use nix::errno::Errno::{ENOENT, ENOTDIR, EPERM};
+use rusqlite::Connection;
+use std::path::{Component, Path, PathBuf};
+use tracing::debug;
+
+/// Where a path falls under the duplicates directory
+pub(super) enum DuplicatesPath {
+    /// The duplicates directory itself
+    Root,
+    /// One of the duplicated files
+    File(String),
+    /// Anything nested deeper, which never exists
+    Invalid,
+}
+
+impl<N> TagFilesystem<N>
+where
+    N: common::notify::Notifier,
+{
+    /// Determines whether `path` is under the duplicates directory, if it's enabled
+    pub(super) fn duplicates_path(&self, path: &Path) -> Option<DuplicatesPath> {
+        let conf = self.settings.get_config().duplicates;
+        if !conf.enabled {
+            return None;
+        }
+
+        let mut parts = path.components().filter_map(|comp| match comp {
+            Component::Normal(part) => Some(part.to_string_lossy().to_string()),
+            _ => None,
+        });
+
+        if parts.next()? != conf.dir {
+            return None;
+        }
+        match (parts.next(), parts.next()) {
+            (None, _) => Some(DuplicatesPath::Root),
+            (Some(file), None) => Some(DuplicatesPath::File(file)),
+            _ => Some(DuplicatesPath::Invalid),
+        }
+    }
+
+    /// The duplicates directory is read-only, so any path under it refuses modification
+    pub(super) fn reject_duplicates_paths(&self, paths: &[&Path]) -> FuseResult<()> {
+        if paths.iter().any(|p| self.duplicates_path(p).is_some()) {
+            debug!(target: OP_TAG, "Refusing to modify duplicates paths {:?}", paths);
+            Err(EPERM.into())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Finds the duplicated file named `filename`
+    fn duplicate_file(&self, path: &Path, filename: &str) -> FuseResult<TaggedFile> {
+        if let Some(ReaddirCacheEntry::File(cached_file)) = self.op_cache.check_readdir_entry(path)
+        {
+            return Ok(cached_file);
+        }
+
+        let conn_lock = self.conn_pool.get_conn();
+        let conn = conn_lock.lock();
+        let real_conn = &(*conn).borrow_mut();
+
+        let file = self
+            .duplicate_files(real_conn)?
+            .into_iter()
+            .find(|(fname, _)| fname == filename)
+            .map(|(_, file)| file)
+            .ok_or_else(|| FuseErrno::from(ENOENT))?;
+
+        self.op_cache
+            .add_readdir_entry(path, ReaddirCacheEntry::File(file.clone()));
+        Ok(file)
+    }
+
+    /// Every duplicated file, named the way a filedir would name it
+    fn duplicate_files(&self, conn: &Connection) -> FuseResult<Vec<(String, TaggedFile)>> {
+        let files = sql::get_duplicate_files(conn)
+            .map_err(SupertagShimError::from)?
+            .into_iter()
+            .map(|(_hash, file)| file)
+            .collect();
+        Ok(self.filedir_names(files))
+    }
+
+    pub(super) fn getattr_duplicates(
+        &self,
+        req: &Request,
+        path: &Path,
+        dupes_path: DuplicatesPath,
+        root_mtime: &UtcDt,
+    ) -> FuseResult<stat> {
+        match dupes_path {
+            DuplicatesPath::Root => {
+                let dir_perms = UMask::from(req.umask).dir_perms();
+                Ok(util::new_dir(root_mtime, req.uid, req.gid, &dir_perms, 0))
+            }
+            DuplicatesPath::File(filename) => {
+                Ok(util::new_statfile(self.duplicate_file(path, &filename)?))
+            }
+            DuplicatesPath::Invalid => Err(ENOENT.into()),
+        }
+    }
+
+    pub(super) fn readdir_duplicates(
+        &self,
+        conn: &Connection,
+        path: &Path,
+        dupes_path: DuplicatesPath,
+    ) -> FuseResult<Box<dyn Iterator<Item = FileEntry>>> {
+        match dupes_path {
+            DuplicatesPath::Root => {
+                debug!(target: OP_TAG, "Listing duplicated files");
+                let entries = self
+                    .duplicate_files(conn)?
+                    .into_iter()
+                    .map(|(filename, file)| {
+                        self.op_cache.add_readdir_entry(
+                            &path.join(&filename),
+                            ReaddirCacheEntry::File(file.clone()),
+                        );
+                        FileEntry {
+                            name: filename,
+                            mtime: file.mtime,
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                Ok(Box::new(entries.into_iter()))
+            }
+            DuplicatesPath::File(_) => Err(ENOTDIR.into()),
+            DuplicatesPath::Invalid => Err(ENOENT.into()),
+        }
+    }
+
+    pub(super) fn readlink_duplicates(
+        &self,
+        path: &Path,
+        dupes_path: DuplicatesPath,
+    ) -> FuseResult<PathBuf> {
+        match dupes_path {
+            DuplicatesPath::File(filename) => {
+                Ok(self.duplicate_file(path, &filename)?.resolve_path())
+            }
+            _ => Err(ENOENT.into()),
+        }
+    }
+}
@@ -21,16 +21,17 @@ use super::super::util;
 use super::TagFilesystem;
 use super::OP_TAG;
 use crate::common::constants;
-use crate::common::types::file_perms::UMask;
-use crate::common::types::{TagCollectible, TagCollection, TagType, UtcDt};
+use crate::common::query::Expr;
+use crate::common::types::file_perms::{Access, UMask};
+use crate::common::types::{TagCollectible, TagType, UtcDt};
 use crate::fuse::opcache;
-use crate::sql::types::TaggedFile;
+use crate::sql::types::{Tag, TaggedFile};
 use crate::{common, sql};
 use fuse_sys::stat;
 use fuse_sys::{FuseResult, Request};
-use log::{debug, info, warn};
 use nix::errno::Errno::ENOENT;
 use std::path::Path;
+use tracing::{debug, info, warn};
 
 impl<N> TagFilesystem<N>
 where
@@ -49,9 +50,203 @@ where
         }
     }
 
+    /// Stats a union directory like `/music|podcasts`.  Every member has to be an existing tag, and if the union
+    /// is intersected with other tags, the intersection must contain at least one file.  `tags` may end in a
+    /// filedir, in which case we're stating the filedir underneath the union.
+    fn getattr_union(&self, path: &Path, tags: &[TagType], members: &[String]) -> FuseResult<stat> {
+        if let Some(opcache::ReaddirCacheEntry::Tag(cached_tag)) =
+            self.op_cache.check_readdir_entry(path)
+        {
+            return Ok(util::new_dir(
+                &cached_tag.mtime,
+                cached_tag.uid,
+                cached_tag.gid,
+                &cached_tag.permissions,
+                cached_tag.num_files,
+            ));
+        }
+
+        let conn_lock = self.conn_pool.get_conn();
+        let conn = conn_lock.lock();
+
+        let mut found = vec![];
+        for member in members {
+            match self
+                .tag_cache
+                .get_tag(&(*conn).borrow_mut(), member)
+                .map_err(SupertagShimError::from)?
+            {
+                Some(tag) => found.push(tag),
+                None => {
+                    debug!(target: OP_TAG, "Union member {:?} wasn't found", member);
+                    return Err(ENOENT.into());
+                }
+            }
+        }
+
+        let num_files = sql::get_num_files(&(*conn).borrow_mut(), tags)
+            .map_err(SupertagShimError::from)? as i64;
+
+        let num_dirs = tags.iter().filter(|tt| **tt != TagType::FileDir).count();
+        if num_dirs > 1 && num_files == 0 {
+            debug!(target: OP_TAG, "{:?} has no files in its intersection", path);
+            return Err(ENOENT.into());
+        }
+
+        // the union directory takes its ownership from its first member, and its mtime from its newest
+        let mut union_tag = found[0].clone();
+        union_tag.name = members.join(constants::UNION_TAG_SEPARATOR);
+        union_tag.num_files = num_files;
+        if let Some(newest) = found.iter().map(|t| t.mtime).max() {
+            union_tag.mtime = newest;
+        }
+
+        self.op_cache
+            .add_readdir_entry(&path, opcache::ReaddirCacheEntry::Tag(union_tag.clone()));
+
+        Ok(util::new_dir(
+            &union_tag.mtime,
+            union_tag.uid,
+            union_tag.gid,
+            &union_tag.permissions,
+            union_tag.num_files,
+        ))
+    }
+
+    /// Stats an expression directory like `/?(rust&!wip)`.  Every tag it names has to exist, so that a typo doesn't
+    /// quietly match nothing, and like a union, it must contain a file if it's intersected with other tags.  It takes
+    /// its ownership from the mount, and its mtime from its newest tag.
+    fn getattr_expression(&self, path: &Path, tags: &[TagType], expr: &Expr) -> FuseResult<stat> {
+        let conn_lock = self.conn_pool.get_conn();
+        let conn = conn_lock.lock();
+        let real_conn = &(*conn).borrow_mut();
+
+        let mut mtime = None;
+        for name in expr.tag_names() {
+            match self
+                .tag_cache
+                .get_tag(real_conn, name)
+                .map_err(SupertagShimError::from)?
+            {
+                Some(tag) => mtime = mtime.max(Some(tag.mtime)),
+                None => {
+                    debug!(target: OP_TAG, "Expression tag {:?} wasn't found", name);
+                    return Err(ENOENT.into());
+                }
+            }
+        }
+
+        let num_files =
+            sql::get_num_files(real_conn, tags).map_err(SupertagShimError::from)? as i64;
+        let num_dirs = tags.iter().filter(|tt| **tt != TagType::FileDir).count();
+        if num_dirs > 1 && num_files == 0 {
+            debug!(target: OP_TAG, "{:?} has no files in its intersection", path);
+            return Err(ENOENT.into());
+        }
+
+        let conf = self.settings.get_config();
+        Ok(util::new_dir(
+            &mtime.expect("Expressions have at least one tag"),
+            conf.mount.uid,
+            conf.mount.gid,
+            &conf.mount.permissions,
+            num_files,
+        ))
+    }
+
+    /// Metadata, date and file type tagdirs have no tag rows of their own, so they exist exactly when they have files
+    fn getattr_meta(&self, path: &Path, tags: &[TagType]) -> FuseResult<stat> {
+        let conn_lock = self.conn_pool.get_conn();
+        let conn = conn_lock.lock();
+        let real_conn = &(*conn).borrow_mut();
+
+        let num_files =
+            sql::get_num_files(real_conn, tags).map_err(SupertagShimError::from)? as i64;
+        if num_files == 0 {
+            debug!(target: OP_TAG, "{:?} has no files with its metadata, date, rating or file type", path);
+            return Err(ENOENT.into());
+        }
+
+        let mtime = sql::get_root_mtime(real_conn).map_err(SupertagShimError::from)?;
+        let conf = self.settings.get_config();
+        Ok(util::new_dir(
+            &mtime,
+            conf.mount.uid,
+            conf.mount.gid,
+            &conf.mount.permissions,
+            num_files,
+        ))
+    }
+
+    /// Readdir may cache a tagdir without counting its files, so this fills in its count and intersection mtime the
+    /// first time it's stat'd, and caches it again counted
+    fn count_cached_tag(&self, path: &Path, mut tag: Tag) -> FuseResult<Tag> {
+        if tag.is_counted() {
+            return Ok(tag);
+        }
+
+        let query_tags = self.settings.path_to_tags(path);
+        let parents = &query_tags.as_slice()[..query_tags.len().saturating_sub(1)];
+
+        let conn_lock = self.conn_pool.get_conn();
+        let conn = conn_lock.lock();
+        let (num_files, mtime) =
+            sql::tag_intersection_stats(&(*conn).borrow_mut(), parents, tag.id)
+                .map_err(SupertagShimError::from)?;
+        debug!(
+            target: OP_TAG,
+            "Counted {} files for cached tagdir {:?}", num_files, path
+        );
+
+        tag.num_files = num_files;
+        if let Some(mtime) = mtime {
+            tag.mtime = mtime;
+        }
+        self.op_cache
+            .add_readdir_entry(&path, opcache::ReaddirCacheEntry::Tag(tag.clone()));
+        Ok(tag)
+    }
+
+    /// Gives `tf` the same mtime that a listing would show for it, which may be the target's last known mtime from the
+    /// database if its device is slow or offline
+    fn with_target_mtime(&self, mut tf: TaggedFile) -> TaggedFile {
+        if self.settings.get_config().remote.refresh_mtimes {
+            tf.mtime = self.remote.mtime_for(&tf).0;
+        }
+        tf
+    }
+
+    /// Stats a tagged file's entry, which is a symlink unless the file is passed through for thumbnailers, or every
+    /// file is written through.  A tagged directory is a directory.
+    fn stat_file(&self, mut tf: TaggedFile) -> stat {
+        if tf.is_dir {
+            return util::new_tagged_dir(&tf);
+        }
+        let write_through = self.settings.write_through();
+        if tf.alias_file.is_none()
+            && (write_through || self.settings.passthrough(Path::new(&tf.path)))
+        {
+            let md = std::fs::metadata(&tf.path).ok();
+            // writes go straight to the target, so it's the only place with an up to date mtime
+            if write_through {
+                if let Some(modified) = md.as_ref().and_then(|md| md.modified().ok()) {
+                    tf.mtime = modified.into();
+                }
+            }
+            let size = md.map(|md| md.len()).or(tf.target_size).unwrap_or(0);
+            util::new_passthrough(&tf, size, write_through)
+        } else {
+            util::new_statfile(tf)
+        }
+    }
+
     pub fn getattr_impl(&self, req: &Request, path: &Path) -> FuseResult<stat> {
         info!(target: OP_TAG, "Stating {:?} from PID {}", path, req.pid);
 
+        // reaching anything means searching every tag above it
+        if let Some(parent) = path.parent() {
+            self.check_tag_access(None, req, parent, Access::Execute)?;
+        }
         let root_mtime = self.get_root_mtime(None)?;
 
         #[cfg(target_os = "macos")]
@@ -128,6 +323,24 @@ where
             return self.getattr_supertag_root_conf(req, path, &root_mtime);
         }
 
+        if let Some(search_path) = self.saved_search_path(path) {
+            return self.getattr_saved_search(req, path, search_path, &root_mtime);
+        }
+        if let Some(dupes_path) = self.duplicates_path(path) {
+            return self.getattr_duplicates(req, path, dupes_path, &root_mtime);
+        }
+        if let Some(untagged_path) = self.untagged_path(path) {
+            return self.getattr_untagged(req, path, untagged_path, &root_mtime);
+        }
+        if let Some(entry_path) = self
+            .filedir_entry_path(path)
+            .filter(|entry_path| entry_path.rest != Path::new(""))
+        {
+            let conn_lock = self.conn_pool.get_conn();
+            let conn = conn_lock.lock();
+            return self.getattr_beneath_tagged_dir(&(*conn).borrow_mut(), &entry_path);
+        }
+
         #[cfg(target_os = "macos")]
         {
             if let Some(alias_rc) = self.op_cache.check_alias_entry(path) {
@@ -169,8 +382,15 @@ where
             ));
         }
 
-        let tags = TagCollection::new(&self.settings, path);
+        let tags = {
+            let conn_lock = self.conn_pool.get_conn();
+            let conn = conn_lock.lock();
+            self.path_tags(&(*conn).borrow_mut(), path)
+        };
         let pt = tags.primary_type().map_err(SupertagShimError::from)?;
+        self.settings
+            .check_negations(tags.as_slice())
+            .map_err(|e| self.op_error(path, e))?;
 
         {
             let conn_lock = self.conn_pool.get_conn();
@@ -188,6 +408,18 @@ where
                     return Err(ENOENT.into());
                 }
             }
+            // and the same for a tag group followed by another tag group, which must be nested in it
+            for (parent, child) in tags.iter().nested_group_pairs() {
+                if !sql::group_is_in_group(real_conn, parent, child)
+                    .map_err(SupertagShimError::from)?
+                {
+                    debug!(
+                        target: OP_TAG,
+                        "Tag group {} isn't nested in tag group {}", child, parent
+                    );
+                    return Err(ENOENT.into());
+                }
+            }
         }
 
         match pt {
@@ -199,6 +431,7 @@ where
                 if let Some(stripped) = self.strip_sync_char(path) {
                     self.flush_readdir_cache(&stripped);
                 }
+                self.catch_up_changed_tags();
                 Err(ENOENT.into())
             }
 
@@ -210,6 +443,7 @@ where
                 if let Some(stripped) = self.strip_sync_char(path) {
                     self.flush_readdir_cache(&stripped);
                 }
+                self.catch_up_changed_tags();
                 Err(ENOENT.into())
             }
 
@@ -221,6 +455,7 @@ where
                 if let Some(stripped) = self.strip_sync_char(path) {
                     self.flush_readdir_cache(&stripped);
                 }
+                self.catch_up_changed_tags();
                 Err(ENOENT.into())
             }
 
@@ -228,7 +463,7 @@ where
                 if let Some(opcache::ReaddirCacheEntry::File(cached_file)) =
                     self.op_cache.check_readdir_entry(path)
                 {
-                    return Ok(util::new_statfile(cached_file));
+                    return Ok(self.stat_file(cached_file));
                 }
 
                 debug!(
@@ -240,15 +475,15 @@ where
                 let conn_lock = self.conn_pool.get_conn();
                 let conn = conn_lock.lock();
 
-                if let Some(match_file) = sql::contains_file(
+                if let Some(match_file) = sql::contains_device_file(
                     &(*conn).borrow_mut(),
                     tags.all_but_last().as_slice(),
-                    |tf| device_file.matches(tf),
+                    device_file,
                 )
                 .map_err(SupertagShimError::from)?
                 {
                     debug!(target: OP_TAG, "{:?} exists at the intersection", path);
-                    return Ok(util::new_statfile(match_file));
+                    return Ok(self.stat_file(self.with_target_mtime(match_file)));
                 }
 
                 debug!(target: OP_TAG, "{:?} doesn't exist", path);
@@ -259,7 +494,7 @@ where
                 if let Some(opcache::ReaddirCacheEntry::File(cached_file)) =
                     self.op_cache.check_readdir_entry(path)
                 {
-                    return Ok(util::new_statfile(cached_file));
+                    return Ok(self.stat_file(cached_file));
                 }
 
                 debug!(
@@ -278,20 +513,19 @@ where
                 // then lets filter out the ones that don't match by name
                 let matches: Vec<TaggedFile> = ifiles
                     .into_iter()
-                    .filter(|tf| sfile == &tf.primary_tag)
+                    .filter(|tf| self.settings.display_matches(sfile, &tf.primary_tag))
                     .collect();
 
                 // and only if we have a single match do we say that everything is fine.  if we have multiple matches,
                 // that indicates that we attempted to stat a file that did not have an device/inode in the name, and
                 // there's no way to distinguish that file from other files with the same tags and same name
                 if matches.len() == 1 {
-                    self.op_cache.add_readdir_entry(
-                        &path,
-                        opcache::ReaddirCacheEntry::File(matches[0].clone()),
-                    );
+                    let tf = self.with_target_mtime(matches[0].clone());
+                    self.op_cache
+                        .add_readdir_entry(&path, opcache::ReaddirCacheEntry::File(tf.clone()));
 
                     debug!(target: OP_TAG, "{:?} exists at the intersection", path);
-                    return Ok(util::new_statfile(matches[0].clone()));
+                    return Ok(self.stat_file(tf));
                 }
 
                 Err(ENOENT.into())
@@ -305,10 +539,11 @@ where
                 if let Some(stripped) = self.strip_sync_char(path) {
                     self.flush_readdir_cache(&stripped);
                 }
+                self.catch_up_changed_tags();
                 Err(ENOENT.into())
             }
 
-            TagType::Group(tag_group) => {
+            TagType::Group(tag_group) | TagType::GroupAll(tag_group) => {
                 // here we're checking if it's an entry already in the readdir cache, which will
                 // allow us to quickly say it's present
                 // TODO check that this is working
@@ -336,8 +571,13 @@ where
                     if let Some(mut tg) = sql::get_tag_group(real_conn, &tag_group)
                         .map_err(SupertagShimError::from)?
                     {
-                        let num_files = sql::num_files_for_tag_group(real_conn, &tag_group)
-                            .map_err(SupertagShimError::from)?;
+                        // an all-of group only has the files with every tag in it
+                        let num_files = match pt {
+                            TagType::GroupAll(_) => sql::get_num_files(real_conn, tags.as_slice()),
+                            _ => sql::num_files_for_tag_group(real_conn, &tag_group)
+                                .map(|num| num as usize),
+                        }
+                        .map_err(SupertagShimError::from)?;
                         debug!(
                             target: OP_TAG,
                             "Adjusting tag group num_files to {}", num_files
@@ -360,21 +600,6 @@ where
                 }
                 // but if there are more parts in the path, we need to check tag intersections
                 else {
-                    // if there are two consecutive tag groups, it's an error
-                    let mut last_was_group = false;
-                    for qt in tags.iter() {
-                        match qt {
-                            TagType::Group(_) => {
-                                if last_was_group {
-                                    return Err(ENOENT.into());
-                                } else {
-                                    last_was_group = true;
-                                }
-                            }
-                            _ => last_was_group = false,
-                        }
-                    }
-
                     // here we'll get all of the possible tag groups for the tag intersection
                     let tag_groups =
                         sql::tag_group_intersections(&(*conn).borrow_mut(), tags.as_slice())
@@ -428,7 +653,9 @@ where
                         let conn_lock = self.conn_pool.get_conn();
                         let conn = conn_lock.lock();
 
-                        let maybe_tag = sql::get_tag(&(*conn).borrow_mut(), parent_tag)
+                        let maybe_tag = self
+                            .tag_cache
+                            .get_tag(&(*conn).borrow_mut(), parent_tag)
                             .map_err(SupertagShimError::from)?;
 
                         // we can't use the parent_tag's num_files because it does not accurately reflect the num_files
@@ -462,10 +689,53 @@ where
                             }
                         };
                     }
+                    Some(TagType::Union(members)) => {
+                        self.getattr_union(path, tags.as_slice(), members)
+                    }
+                    Some(TagType::Meta(_, _))
+                    | Some(TagType::Date(_))
+                    | Some(TagType::Rating(_))
+                    | Some(TagType::FileType(_)) => self.getattr_meta(path, tags.as_slice()),
+                    Some(TagType::Expression(expr)) => {
+                        self.getattr_expression(path, tags.as_slice(), expr)
+                    }
                     _ => Err(ENOENT.into()),
                 }
             }
 
+            TagType::Union(members) => {
+                debug!(target: OP_TAG, "{:?} is a union tagdir", path);
+                self.getattr_union(path, tags.as_slice(), members)
+            }
+
+            TagType::Meta(_, _) => {
+                debug!(target: OP_TAG, "{:?} is a meta tagdir", path);
+                self.getattr_meta(path, tags.as_slice())
+            }
+
+            TagType::Date(_) => {
+                debug!(target: OP_TAG, "{:?} is a date tagdir", path);
+                self.getattr_meta(path, tags.as_slice())
+            }
+
+            TagType::Rating(_) => {
+                debug!(target: OP_TAG, "{:?} is a rating tagdir", path);
+                self.getattr_meta(path, tags.as_slice())
+            }
+
+            TagType::FileTypeGroup | TagType::FileType(_) => {
+                debug!(target: OP_TAG, "{:?} is a file type tagdir", path);
+                self.getattr_meta(path, tags.as_slice())
+            }
+
+            TagType::Expression(expr) => {
+                debug!(target: OP_TAG, "{:?} is an expression tagdir", path);
+                self.getattr_expression(path, tags.as_slice(), expr)
+            }
+
+            // only search expressions produce this, never paths
+            TagType::CollectionTag(_) => Err(ENOENT.into()),
+
             TagType::Regular(tag) | TagType::Negation(tag) => {
                 debug!(target: OP_TAG, "{:?} is a tagdir", path);
                 // here we're checking if it's an entry already in the readdir cache, which will
@@ -473,6 +743,7 @@ where
                 if let Some(opcache::ReaddirCacheEntry::Tag(cached_tag)) =
                     self.op_cache.check_readdir_entry(path)
                 {
+                    let cached_tag = self.count_cached_tag(path, cached_tag)?;
                     return Ok(util::new_dir(
                         &cached_tag.mtime,
                         cached_tag.uid,
@@ -595,7 +866,9 @@ where
                         match last_tt {
                             TagType::Regular(last_tag) => {
                                 debug!(target: OP_TAG, "Last record is a regular tag");
-                                let record = sql::get_tag(&(*conn).borrow_mut(), &last_tag)
+                                let record = self
+                                    .tag_cache
+                                    .get_tag(&(*conn).borrow_mut(), &last_tag)
                                     .map_err(SupertagShimError::from)?;
 
                                 if let Some(tag) = record {
@@ -642,7 +915,9 @@ where
                     // symlink to long tag paths of non-existant tags, but we're choosing
                     // not to do that.  i can't remember exactly why FIXME
 
-                    if let Some(found_tag) = sql::get_tag(&(*conn).borrow_mut(), &tag)
+                    if let Some(found_tag) = self
+                        .tag_cache
+                        .get_tag(&(*conn).borrow_mut(), &tag)
                         .map_err(SupertagShimError::from)?
                     {
                         debug!(target: OP_TAG, "It does exist");
@@ -18,38 +18,55 @@
 
 use super::err::SupertagShimError;
 use crate::common::err::{STagError, STagResult};
+use crate::common::metrics::METRICS;
+use crate::common::notify::HealthSource;
 use crate::common::settings::Settings;
-use crate::common::types::{TagCollection, TagType, UtcDt};
+use crate::common::types::health::{CacheSizes, Health};
+use crate::common::types::{MergeResolution, TagCollectible, TagCollection, TagType, UtcDt};
 use crate::common::{constants, get_filename};
 use crate::fuse::opcache;
 use crate::fuse::opcache::ReaddirCacheEntry;
+use crate::fuse::pathlock::{PathGuard, PathLocks};
+use crate::fuse::remote::RemoteFiles;
+use crate::fuse::shutdown::Shutdown;
+use crate::fuse::tagcache::TagCache;
+use crate::fuse::tracker::RenameTracker;
 use crate::fuse::util::open_opts_from_mode;
+use crate::fuse::warm::Warmer;
 use crate::sql::tpool::ThreadConnPool;
+use crate::sql::types::{Tag, TaggedFile};
 use crate::{common, sql};
-use common::types::file_perms::Permissions;
+use common::types::file_perms::{Access, Permissions};
 use fuse_sys::err::FuseErrno;
-use fuse_sys::{fuse_file_info, mode_t, new_statvfs, off_t, stat, statvfs};
+use fuse_sys::{fuse_bufvec, fuse_file_info, mode_t, new_statvfs, off_t, stat, statvfs};
 use fuse_sys::{FileEntry, Filesystem, FuseHandle, FuseResult, Request};
-use log::{debug, error, info, warn};
-use nix::errno::Errno::{EIO, ENOENT, ENOSYS, EPERM};
+use libc::c_int;
+use nix::errno::Errno::{
+    EACCES, EBUSY, EDQUOT, EEXIST, EIO, ENAMETOOLONG, ENOENT, ENOSYS, EPERM, EROFS, EXDEV,
+};
 use parking_lot::Mutex;
-use rusqlite::{Connection, TransactionBehavior};
-use std::borrow::Borrow;
+use rusqlite::Connection;
+use std::borrow::{Borrow, Cow};
+use std::collections::HashSet;
 use std::convert::TryInto;
 use std::fs::OpenOptions;
 #[cfg(target_os = "macos")]
 use std::os::unix::io::AsRawFd;
-use std::os::unix::io::{IntoRawFd, RawFd};
+use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
 use std::path::{Component, Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
 
 const OP_TAG: &str = "supertag_op";
 
+mod duplicates;
 mod getattr;
 mod readdir;
-
-#[cfg(target_os = "macos")]
+mod searches;
+mod tagged_dirs;
+mod untagged;
 mod xattr;
 
 pub struct TagFilesystem<N>
@@ -58,9 +75,16 @@ where
 {
     conn_pool: Arc<ThreadConnPool>,
     op_cache: Arc<opcache::OpCache>,
+    tag_cache: Arc<TagCache>,
     settings: Arc<Settings>,
+    path_locks: Arc<PathLocks>,
+    remote: Arc<RemoteFiles>,
+    tracker: Arc<RenameTracker>,
+    shutdown: Arc<Shutdown<N>>,
     handle: Option<Arc<FuseHandle>>,
     notifier: Arc<Mutex<N>>,
+    // handles we opened on passthrough files' targets, which we're responsible for closing
+    passthrough_fds: Mutex<HashSet<u64>>,
 
     // we'll use this as a weak reference in our infinite-loop threads, so they can exit when TagFilesystem is dropped
     #[allow(dead_code)]
@@ -90,15 +114,279 @@ where
     ) -> TagFilesystem<N> {
         let conn_pool_arc = Arc::new(conn_pool);
         let op_cache = Arc::new(opcache::OpCache::new(settings.clone()));
-        let threads_done = Arc::new(AtomicBool::new(false));
+        {
+            let conn_lock = conn_pool_arc.get_conn();
+            let conn = conn_lock.lock();
+            let mut real_conn = (*conn).borrow_mut();
+            match sql::latest_event_id(&real_conn) {
+                Ok(cursor) => op_cache.set_events_cursor(cursor),
+                Err(e) => warn!(target: OP_TAG, "Couldn't read the events feed: {}", e),
+            }
+
+            match sql::set_tag_form(&mut real_conn, settings.get_config().collection.tag_form) {
+                Ok(0) => {}
+                Ok(merged) => info!(
+                    target: OP_TAG,
+                    "Merged {} tags whose names only differed in their unicode normalization", merged
+                ),
+                Err(e) => warn!(target: OP_TAG, "Couldn't normalize tag names: {}", e),
+            }
 
-        TagFilesystem {
+            let nocase = settings.get_config().collection.case_insensitive_tags;
+            if let Err(e) = sql::set_tags_nocase(&real_conn, nocase) {
+                warn!(target: OP_TAG, "Couldn't set case-insensitive tags: {}", e);
+            }
+            if nocase {
+                for (a, b) in sql::case_collisions(&real_conn).unwrap_or_default() {
+                    warn!(
+                        target: OP_TAG,
+                        "Tags {} and {} only differ in case, but stay separate until they're merged", a, b
+                    );
+                }
+            }
+        }
+        let threads_done = Arc::new(AtomicBool::new(false));
+        let remote = Arc::new(RemoteFiles::new(settings.clone()));
+        let tracker = Arc::new(RenameTracker::new(settings.clone()));
+        let path_locks = Arc::new(PathLocks::default());
+        let shutdown = Arc::new(Shutdown::new(
+            settings.clone(),
+            conn_pool_arc.clone(),
+            op_cache.clone(),
+            path_locks.clone(),
+            notifier.clone(),
+            threads_done.clone(),
+        ));
+
+        let fs = TagFilesystem {
             conn_pool: conn_pool_arc,
             op_cache,
+            tag_cache: Arc::new(TagCache::new()),
             settings,
+            path_locks,
+            remote,
+            tracker,
+            shutdown,
             handle: None,
             notifier,
+            passthrough_fds: Mutex::new(HashSet::new()),
             threads_done,
+        };
+        fs.notifier.lock().serve_health(fs.health_source());
+        fs
+    }
+
+    /// Builds our health report for the notifier to serve.  It only holds onto what it needs, since the notifier can
+    /// outlive us.  The counts come from a connection of its own, because the pool's connections are tied to the
+    /// threads that fuse calls us on.
+    fn health_source(&self) -> HealthSource {
+        let settings = self.settings.clone();
+        let op_cache = self.op_cache.clone();
+        let tag_cache = self.tag_cache.clone();
+        let started = Instant::now();
+        Arc::new(move || {
+            let col = settings.get_collection();
+            let db_path = settings.db_file(&col);
+            let (num_tags, num_files) = sql::stats::totals(&sql::get_conn(&db_path)?)?;
+            Ok(Health {
+                pid: std::process::id(),
+                mountpoint: settings.mountpoint(&col),
+                collection: col,
+                db_path,
+                uptime_secs: started.elapsed().as_secs(),
+                num_tags,
+                num_files,
+                requests: common::log::REQ_COUNTER.load(Ordering::Relaxed),
+                write_gate: settings.write_gate(),
+                disk_full: settings.disk_full(),
+                cache: CacheSizes {
+                    tags: tag_cache.size(),
+                    ..op_cache.sizes()
+                },
+            })
+        })
+    }
+
+    /// The coordinator for tearing this filesystem down.  Grab it before mounting, since mounting takes ownership of
+    /// the filesystem.
+    pub fn shutdown_handle(&self) -> Arc<Shutdown<N>> {
+        self.shutdown.clone()
+    }
+
+    /// Normalizes `path` into the key that identifies its logical entry for the purposes of operation sequencing.
+    /// The sync char is stripped and both filedir symbols collapse to the same symbol, and the tags leading up to the
+    /// entry are sorted, since they're an intersection, so that every spelling of an entry serializes against every
+    /// other spelling.  A tag group stays attached to the tag after it, which it qualifies.
+    fn canonical_op_path(&self, path: &Path) -> PathBuf {
+        let conf = self.settings.get_config();
+        let names: Vec<String> = path
+            .components()
+            .filter_map(|comp| match comp {
+                Component::Normal(name) => {
+                    let name = name.to_string_lossy();
+                    let name = name.trim_end_matches(conf.symbols.sync_char);
+                    if name == conf.symbols.filedir_cli_str {
+                        Some(conf.symbols.filedir_str.clone())
+                    } else {
+                        Some(name.to_string())
+                    }
+                }
+                _ => None,
+            })
+            .collect();
+
+        // the tags before the filedir, or else before the entry itself
+        let num_tags = names
+            .iter()
+            .position(|name| *name == conf.symbols.filedir_str)
+            .unwrap_or_else(|| names.len().saturating_sub(1));
+        let mut terms = vec![];
+        let mut tags = names[..num_tags].iter();
+        while let Some(name) = tags.next() {
+            let mut term = PathBuf::from(name);
+            if let [TagType::Group(_)] | [TagType::GroupAll(_)] =
+                self.settings.path_to_tags(name).as_slice()
+            {
+                if let Some(next) = tags.next() {
+                    term.push(next);
+                }
+            }
+            terms.push(term);
+        }
+        terms.sort();
+
+        let mut canonical = PathBuf::from(std::path::MAIN_SEPARATOR.to_string());
+        canonical.extend(terms);
+        canonical.extend(&names[num_tags..]);
+        canonical
+    }
+
+    /// Serializes mutations on the logical entries at `paths`, while leaving unrelated paths free to proceed
+    fn lock_paths(&self, paths: &[&Path]) -> PathGuard {
+        let canonical: Vec<PathBuf> = paths.iter().map(|p| self.canonical_op_path(p)).collect();
+        let refs: Vec<&Path> = canonical.iter().map(|p| p.as_path()).collect();
+        self.path_locks.lock_many(&refs)
+    }
+
+    /// Converts a failed operation on `path` into its best-fit errno.  The errnos that a file manager can only show
+    /// as a terse, generic dialog also get a note that explains what actually went wrong.
+    fn op_error(&self, path: &Path, e: STagError) -> SupertagShimError {
+        if e.is_disk_full() {
+            self.enter_disk_full();
+        }
+        match e.errno() {
+            ENAMETOOLONG | EXDEV | EEXIST | EDQUOT | EROFS | EBUSY => {
+                warn!(target: OP_TAG, "{} failed: {}", path.display(), e);
+                let full_path = self.settings.abs_mountpoint(path);
+                let _ = self.notifier.lock().op_failed(&full_path, &e.to_string());
+            }
+            _ => {}
+        }
+        SupertagShimError::from(e)
+    }
+
+    /// The database's disk has filled up, so the collection goes read-only, and the user is told why their writes are
+    /// failing.  A background thread takes writes again once enough space has been freed.
+    fn enter_disk_full(&self) {
+        if self.settings.set_disk_full(true) {
+            return;
+        }
+        let db_path = self.settings.db_file(&self.settings.get_collection());
+        let free = free_bytes(&db_path).unwrap_or(0);
+        warn!(
+            target: OP_TAG,
+            "The disk holding {} is full ({} bytes free), holding off writes",
+            db_path.display(),
+            free
+        );
+        let _ = self.notifier.lock().disk_full(&db_path, free);
+
+        let settings = self.settings.clone();
+        let threads_done = self.threads_done.clone();
+        let spawned = std::thread::Builder::new()
+            .name("disk_full".to_string())
+            .spawn(move || {
+                let interval = Duration::from_secs(constants::DISK_FULL_POLL_SECS);
+                while !threads_done.load(Ordering::Relaxed) {
+                    std::thread::sleep(interval);
+                    match free_bytes(&db_path) {
+                        Ok(free) if free >= constants::DISK_FULL_RESUME_BYTES => {
+                            info!(
+                                target: OP_TAG,
+                                "{} bytes free on the disk holding {}, taking writes again",
+                                free,
+                                db_path.display()
+                            );
+                            settings.set_disk_full(false);
+                            return;
+                        }
+                        Ok(_) => {}
+                        Err(e) => warn!(
+                            target: OP_TAG,
+                            "Couldn't statvfs {}: {}",
+                            db_path.display(),
+                            e
+                        ),
+                    }
+                }
+            });
+        if let Err(e) = spawned {
+            error!(target: OP_TAG, "Couldn't start disk space watcher: {:?}", e);
+            // nothing else would ever take writes again
+            self.settings.set_disk_full(false);
+        }
+    }
+
+    /// Saved searches, duplicates, and untagged files are read-only views of the collection, so nothing under their
+    /// directories can be modified, and neither can anything beneath a tagged directory
+    fn reject_virtual_paths(&self, paths: &[&Path]) -> FuseResult<()> {
+        self.reject_saved_search_paths(paths)?;
+        self.reject_duplicates_paths(paths)?;
+        self.reject_untagged_paths(paths)?;
+        self.reject_tagged_dir_paths(paths)
+    }
+
+    /// With `rename.redirect_tags` on, rewrites each component of `path` that a tag was recently renamed from to the
+    /// tag's current name, so that bookmarks and scripts using the old path keep working
+    fn redirect_renamed<'a>(&self, path: &'a Path) -> Cow<'a, Path> {
+        let conf = self.settings.get_config();
+        if !conf.rename.redirect_tags {
+            return Cow::Borrowed(path);
+        }
+        let since = sql::get_now_secs() - (conf.rename.redirect_days * 24 * 60 * 60) as f64;
+
+        let conn_lock = self.conn_pool.get_conn();
+        let conn = conn_lock.lock();
+        let real_conn = (*conn).borrow_mut();
+
+        let mut redirected = PathBuf::new();
+        let mut changed = false;
+        for component in path.components() {
+            let name = match component {
+                Component::Normal(name) => name.to_string_lossy(),
+                _ => {
+                    redirected.push(component);
+                    continue;
+                }
+            };
+            match sql::renamed_tag(&real_conn, &name, since) {
+                Ok(Some(new_name)) => {
+                    debug!(target: OP_TAG, "Redirecting renamed tag {} to {}", name, new_name);
+                    redirected.push(new_name);
+                    changed = true;
+                }
+                Ok(None) => redirected.push(component),
+                Err(e) => {
+                    warn!(target: OP_TAG, "Couldn't look up renames of {}: {}", name, e);
+                    redirected.push(component);
+                }
+            }
+        }
+
+        if changed {
+            Cow::Owned(redirected)
+        } else {
+            Cow::Borrowed(path)
         }
     }
 
@@ -116,15 +404,57 @@ where
             .clear_readdir_entry(&path.join(&conf.symbols.filedir_cli_str));
     }
 
-    /// For every tag in `path`, flush it
-    fn flush_paths_tags(&self, path: &Path) {
-        // this flushes all of the tags that contain the file removed. this is necessary because these tags
-        // may exist in the readdir cache with the wrong size/num_files count now
-        for comp in path.components() {
-            if let Component::Normal(name) = comp {
-                let to_flush = PathBuf::from(std::path::MAIN_SEPARATOR.to_string()).join(name);
-                self.flush_readdir_cache(&to_flush);
-            }
+    /// The tags in `path`.  If tags are case-insensitive, its tags and tag groups are spelled the way they're stored,
+    /// wherever they appear, so that a path can name them in any case.
+    fn path_tags(&self, conn: &Connection, path: &Path) -> TagCollection {
+        let mut tags = TagCollection::new(&self.settings, path);
+        if self.settings.get_config().collection.case_insensitive_tags {
+            tags.canonicalize(
+                |name| match self.tag_cache.get_tag(conn, name) {
+                    Ok(tag) => tag.map(|t| t.name).filter(|stored| stored != name),
+                    Err(e) => {
+                        warn!(target: OP_TAG, "Couldn't look up tag {}: {}", name, e);
+                        None
+                    }
+                },
+                |name| match sql::get_tag_group(conn, name) {
+                    Ok(group) => group.map(|g| g.name).filter(|stored| stored != name),
+                    Err(e) => {
+                        warn!(target: OP_TAG, "Couldn't look up tag group {}: {}", name, e);
+                        None
+                    }
+                },
+            );
+        }
+        tags
+    }
+
+    /// Asks the kernel to forget whatever it cached for `paths` and everything beneath them.  The handle batches these,
+    /// so a big operation can hand over all of its paths at once without waiting on the kernel.
+    fn invalidate_paths(&self, paths: &[&Path]) {
+        if let Some(handle) = &self.handle {
+            let paths: Vec<PathBuf> = paths.iter().map(|p| p.to_path_buf()).collect();
+            handle.invalidate_many(&paths);
+        }
+    }
+
+    /// A sync from the CLI means it just changed the database behind our back, so flush whatever it changed.  Unlike
+    /// `flush_changed_tags`, this is for when we aren't already holding a connection.
+    fn catch_up_changed_tags(&self) {
+        let conn_lock = self.conn_pool.get_conn();
+        let conn = conn_lock.lock();
+        let real_conn = (*conn).borrow_mut();
+        self.flush_changed_tags(&real_conn);
+    }
+
+    /// Flushes every readdir cache entry that depends on a tag changed since the last flush, and every cached tag.  This
+    /// is necessary after any mutation, because those entries may have the wrong size/num_files count now, or may no
+    /// longer exist at all.
+    fn flush_changed_tags(&self, conn: &Connection) {
+        self.tag_cache.bump();
+        if let Err(e) = self.op_cache.invalidate_from_events(conn) {
+            // the entries will still expire on their own shortly
+            warn!(target: OP_TAG, "Couldn't invalidate changed tags: {}", e);
         }
     }
 
@@ -151,6 +481,71 @@ where
         }
     }
 
+    /// The stats of the filesystem that the collection's database is on
+    fn db_statvfs(&self) -> FuseResult<nix::sys::statvfs::Statvfs> {
+        let db_path = self.settings.db_file(&self.settings.get_collection());
+        nix::sys::statvfs::statvfs(&db_path).map_err(|e| {
+            error!(target: OP_TAG, "Couldn't statvfs {}: {}", db_path.display(), e);
+            e.as_errno().unwrap_or(EIO).into()
+        })
+    }
+
+    /// Fails with EACCES unless `req` may `access` every existing tag in `path`.  This is only enforced when other users
+    /// can use the mount, since otherwise every request comes from the user who mounted it.
+    fn check_tag_access(
+        &self,
+        default_conn: Option<&Connection>,
+        req: &Request,
+        path: &Path,
+        access: Access,
+    ) -> FuseResult<()> {
+        if !self.settings.allow_other() || req.uid == 0 {
+            return Ok(());
+        }
+        let tags = TagCollection::new(&self.settings, path);
+        let mut names = tags.iter().collect_regular_names();
+        // an expression reveals the files of every tag it names, even negated ones
+        for tt in tags.iter() {
+            if let TagType::Expression(expr) = tt {
+                names.extend(expr.tag_names());
+            }
+        }
+        if names.is_empty() {
+            return Ok(());
+        }
+
+        let lookup = |conn: &Connection| -> STagResult<Vec<Tag>> {
+            names
+                .iter()
+                .filter_map(|name| self.tag_cache.get_tag(conn, name).transpose())
+                .collect::<rusqlite::Result<Vec<Tag>>>()
+                .map_err(STagError::from)
+        };
+        let found = match default_conn {
+            Some(conn) => lookup(conn)?,
+            None => {
+                let conn_lock = self.conn_pool.get_conn();
+                let conn = conn_lock.lock();
+                let real_conn = (*conn).borrow_mut();
+                lookup(&real_conn)?
+            }
+        };
+
+        for tag in found {
+            if !tag
+                .permissions
+                .permits(tag.uid, tag.gid, req.uid, req.gid, access)
+            {
+                info!(
+                    target: OP_TAG,
+                    "Denying {:?} on tag {} to uid {}", access, tag.name, req.uid
+                );
+                return Err(EACCES.into());
+            }
+        }
+        Ok(())
+    }
+
     /// Processes an alias record that has been flushed or released
     #[cfg(target_os = "macos")]
     fn process_alias(&self, path: &Path) -> FuseResult<()> {
@@ -186,6 +581,14 @@ where
                                 .map_err(SupertagShimError::from)?
                                 .canonicalize()?;
 
+                            // an alias to one of the collection's own files would link the collection into itself
+                            if let Err(e) =
+                                common::fsops::check_source(&self.settings, &alias_target)
+                            {
+                                let _ = self.notifier.lock().protected_source(&alias_target);
+                                return Err(e.into());
+                            }
+
                             // a heuristic to check if we're creating an alias in the root directory.
                             if alias_target.is_file() && tags.len() == 0 {
                                 let _ = self.notifier.lock().dragged_to_root();
@@ -240,9 +643,8 @@ where
                         let conn_lock = self.conn_pool.get_conn();
                         let conn = conn_lock.lock();
                         let mut real_conn = (*conn).borrow_mut();
-                        let tx = real_conn
-                            .transaction_with_behavior(TransactionBehavior::Exclusive)
-                            .map_err(SupertagShimError::from)?;
+                        let tx = sql::begin_write(&mut real_conn)
+                            .map_err(|e| self.op_error(path, e.into()))?;
 
                         let primary_tag = get_filename(&alias_target)?;
 
@@ -258,9 +660,9 @@ where
                             Some(&alias_file),
                             &*(self.notifier.lock()),
                         )
-                        .map_err(SupertagShimError::from)?;
+                        .map_err(|e| self.op_error(path, e.into()))?;
 
-                        tx.commit().map_err(SupertagShimError::from)?;
+                        tx.commit().map_err(|e| self.op_error(path, e.into()))?;
                         alias.linked = true;
 
                         // here we update the managed file to be the final file location. this only really changes on
@@ -269,7 +671,7 @@ where
                         // need those settings on the final file, not the intermediate managed file
                         alias.managed_file = alias_file;
 
-                        self.flush_paths_tags(path);
+                        self.flush_changed_tags(&real_conn);
                     }
                 }
             }
@@ -288,7 +690,7 @@ where
             "Attempting to resolve to {} to a managed file",
             path.display()
         );
-        let tags = TagCollection::new(&self.settings, path);
+        let tags = self.path_tags(conn, path);
 
         // it's not necessarily an error if there's only the root tag
         let maybe_pt = tags.primary_type();
@@ -346,14 +748,12 @@ where
 
         let found = match pt {
             TagType::DeviceFileSymlink(device_file) => {
-                sql::contains_file(conn, tags.all_but_last().as_slice(), |tf| {
-                    device_file.matches(tf)
-                })
-                .map_err(SupertagShimError::from)?
+                sql::contains_device_file(conn, tags.all_but_last().as_slice(), device_file)
+                    .map_err(SupertagShimError::from)?
             }
             TagType::Symlink(primary_tag) => {
                 sql::contains_file(conn, tags.all_but_last().as_slice(), |tf| {
-                    &tf.primary_tag == primary_tag
+                    self.settings.display_matches(primary_tag, &tf.primary_tag)
                 })
                 .map_err(SupertagShimError::from)?
             }
@@ -371,6 +771,63 @@ where
             _ => Ok(None),
         }
     }
+
+    /// Where the symlink at `path`, for `tf`, points.  With rename tracking on, a target that has gone missing is looked
+    /// for by its device/inode, and if it turns up somewhere, the file is relocated there in the database.
+    fn link_target(&self, path: &Path, mut tf: TaggedFile) -> FuseResult<PathBuf> {
+        let target = tf.resolve_path();
+        if !self.settings.get_config().tracking.follow_renames
+            || tf.alias_file.is_some()
+            || target.symlink_metadata().is_ok()
+        {
+            return Ok(target);
+        }
+
+        let moved = match self.tracker.find(tf.device, tf.inode, &target) {
+            Some(moved) => moved,
+            None => return Ok(target),
+        };
+        info!(target: OP_TAG, "Following {:?} to {:?}", target, moved);
+
+        {
+            let conn_lock = self.conn_pool.get_conn();
+            let conn = conn_lock.lock();
+            let mut real_conn = (*conn).borrow_mut();
+            let tx = sql::begin_write(&mut real_conn).map_err(|e| self.op_error(path, e.into()))?;
+            sql::relocate_file(
+                &tx,
+                tf.id,
+                tf.device,
+                tf.inode,
+                &moved.to_string_lossy(),
+                sql::get_now_secs(),
+            )
+            .map_err(|e| self.op_error(path, e.into()))?;
+            tx.commit().map_err(|e| self.op_error(path, e.into()))?;
+        }
+
+        tf.path = moved.to_string_lossy().to_string();
+        self.op_cache
+            .add_readdir_entry(path, ReaddirCacheEntry::File(tf));
+        Ok(moved)
+    }
+
+    /// Takes a path and resolves it to the target of a tagged file, but only if that file is passed through as a
+    /// regular file, either for thumbnailers or because every file is written through
+    fn resolve_to_passthrough_file(
+        &self,
+        conn: &Connection,
+        path: &Path,
+    ) -> FuseResult<Option<PathBuf>> {
+        Ok(self
+            .file_entry(conn, path)?
+            .filter(|tf| {
+                tf.alias_file.is_none()
+                    && (self.settings.write_through()
+                        || self.settings.passthrough(Path::new(&tf.path)))
+            })
+            .map(|tf| PathBuf::from(tf.path)))
+    }
 }
 
 impl<N> Filesystem for TagFilesystem<N>
@@ -378,23 +835,29 @@ where
     N: common::notify::Notifier + 'static,
 {
     /// Sets up our thread-local request id based on a global atomic request counter
-    fn init_request_id(&self) {
+    fn init_request_id(&self) -> usize {
+        let req_id = common::log::REQ_COUNTER.fetch_add(1, Ordering::SeqCst);
         common::log::REQUEST_ID.with(|f| {
-            let req_id = common::log::REQ_COUNTER.fetch_add(1, Ordering::SeqCst);
             *f.borrow_mut() = req_id;
         });
+        req_id
+    }
+
+    fn op_done(&self, op: &'static str, elapsed: Duration, result: c_int) {
+        METRICS.record_op(op, elapsed, result < 0);
     }
 
     fn getattr(&self, req: &Request, path: &Path) -> FuseResult<stat> {
-        self.getattr_impl(req, path)
+        self.getattr_impl(req, &self.redirect_renamed(path))
     }
 
     fn readdir(
         &self,
         req: &Request,
         path: &Path,
+        offset: u64,
     ) -> FuseResult<Box<dyn Iterator<Item = FileEntry>>> {
-        self.readdir_impl(req, path)
+        self.readdir_impl(req, &self.redirect_renamed(path), offset as usize)
     }
 
     fn readdir_common(
@@ -402,45 +865,75 @@ where
         req: &Request,
         path: &Path,
     ) -> FuseResult<Box<dyn Iterator<Item = FileEntry>>> {
-        self.readdir_common_impl(req, path)
+        self.readdir_common_impl(req, &self.redirect_renamed(path))
     }
 
     fn readlink(&self, _req: &Request, path: &Path) -> FuseResult<PathBuf> {
+        let redirected = self.redirect_renamed(path);
+        let path = redirected.as_ref();
+        if let Some(search_path) = self.saved_search_path(path) {
+            return self.readlink_saved_search(path, search_path);
+        }
+        if let Some(dupes_path) = self.duplicates_path(path) {
+            return self.readlink_duplicates(path, dupes_path);
+        }
+        if let Some(untagged_path) = self.untagged_path(path) {
+            return self.readlink_untagged(path, untagged_path);
+        }
+        if let Some(entry_path) = self
+            .filedir_entry_path(path)
+            .filter(|entry_path| entry_path.rest != Path::new(""))
+        {
+            let conn_lock = self.conn_pool.get_conn();
+            let conn = conn_lock.lock();
+            return self.readlink_beneath_tagged_dir(&(*conn).borrow_mut(), &entry_path);
+        }
+
         let tags = TagCollection::new(&self.settings, path);
 
         let pt = tags.primary_type().map_err(SupertagShimError::from)?;
 
         if let Some(opcache::ReaddirCacheEntry::File(tf)) = self.op_cache.check_readdir_entry(path)
         {
-            Ok(tf.resolve_path())
+            self.link_target(path, tf)
         } else {
             if let TagType::DeviceFileSymlink(device_file) = pt {
-                let conn_lock = self.conn_pool.get_conn();
-                let conn_guard = conn_lock.lock();
-                let conn = (*conn_guard).borrow_mut();
-
-                match sql::contains_file(&conn, tags.as_slice(), |tf| device_file.matches(tf))
+                let found = {
+                    let conn_lock = self.conn_pool.get_conn();
+                    let conn_guard = conn_lock.lock();
+                    let conn = (*conn_guard).borrow_mut();
+                    sql::contains_device_file(
+                        &conn,
+                        self.path_tags(&conn, path).as_slice(),
+                        device_file,
+                    )
                     .map_err(SupertagShimError::from)?
-                {
+                };
+
+                match found {
                     Some(tf) => {
                         let entry = ReaddirCacheEntry::File(tf.clone());
                         self.op_cache.add_readdir_entry(path, entry);
-                        Ok(tf.resolve_path())
+                        self.link_target(path, tf)
                     }
                     None => Err(ENOENT.into()),
                 }
             } else if let TagType::Symlink(filename) = pt {
-                let conn_lock = self.conn_pool.get_conn();
-                let conn_guard = conn_lock.lock();
-                let conn = (*conn_guard).borrow_mut();
-
-                match sql::contains_file(&conn, tags.as_slice(), |tf| &tf.primary_tag == filename)
+                let found = {
+                    let conn_lock = self.conn_pool.get_conn();
+                    let conn_guard = conn_lock.lock();
+                    let conn = (*conn_guard).borrow_mut();
+                    sql::contains_file(&conn, self.path_tags(&conn, path).as_slice(), |tf| {
+                        self.settings.display_matches(filename, &tf.primary_tag)
+                    })
                     .map_err(SupertagShimError::from)?
-                {
+                };
+
+                match found {
                     Some(tf) => {
                         let entry = ReaddirCacheEntry::File(tf.clone());
                         self.op_cache.add_readdir_entry(path, entry);
-                        Ok(tf.resolve_path())
+                        self.link_target(path, tf)
                     }
                     None => Err(ENOENT.into()),
                 }
@@ -454,6 +947,8 @@ where
     }
 
     fn symlink(&self, req: &Request, src: &Path, dst: &Path) -> FuseResult<()> {
+        self.reject_virtual_paths(&[dst])?;
+        let _path_guard = self.lock_paths(&[src, dst]);
         let mut tags = TagCollection::new(&self.settings, dst);
 
         // dst will always have the filename in the path, so pop that off
@@ -469,13 +964,17 @@ where
         // will call readlink and deadlock otherwise
         let abs_src = std::fs::canonicalize(src)?;
         let primary_tag = get_filename(&abs_src)?;
+        // and hashing reads the whole file, which shouldn't hold up everything else waiting to write
+        let hashes = common::fsops::hash_targets(&self.settings, std::slice::from_ref(&abs_src));
 
         let conn_lock = self.conn_pool.get_conn();
         let conn = conn_lock.lock();
         let mut real_conn = (*conn).borrow_mut();
-        let tx = real_conn
-            .transaction_with_behavior(TransactionBehavior::Exclusive)
-            .map_err(SupertagShimError::from)?;
+        // tagging a file modifies every tag that it's tagged with
+        if let Some(parent) = dst.parent() {
+            self.check_tag_access(Some(&real_conn), req, parent, Access::Write)?;
+        }
+        let tx = sql::begin_write(&mut real_conn).map_err(|e| self.op_error(dst, e.into()))?;
 
         let res = common::fsops::ln(
             self.settings.borrow(),
@@ -489,8 +988,10 @@ where
             None,
             &*(self.notifier.lock()),
         )
-        .map_err(SupertagShimError::from)?;
-        tx.commit().map_err(SupertagShimError::from)?;
+        .map_err(|e| self.op_error(dst, e))?;
+        common::fsops::record_hashes(&self.settings, &tx, &hashes)
+            .map_err(|e| self.op_error(dst, e))?;
+        tx.commit().map_err(|e| self.op_error(dst, e.into()))?;
 
         info!(target: OP_TAG, "Tagged successfully");
 
@@ -498,7 +999,7 @@ where
             self.op_cache.add_symlink(req, dst, tf);
         }
 
-        self.flush_paths_tags(dst);
+        self.flush_changed_tags(&real_conn);
 
         Ok(())
     }
@@ -511,6 +1012,7 @@ where
                 "Creating potential macos alias file at {}",
                 _path.display()
             );
+            let _path_guard = self.lock_paths(&[_path]);
 
             // we used to do the drag_to_root check here, but we don't anymore, because we need to let users drag a
             // folder in a finder window
@@ -556,6 +1058,17 @@ where
             let mut opts = OpenOptions::new();
             let handle = open_opts_from_mode(&mut opts, flags).open(&file_path)?;
             Ok(handle.into_raw_fd())
+        } else if let Some(target) = self.resolve_to_passthrough_file(&real_conn, path)? {
+            if flags & libc::O_ACCMODE != libc::O_RDONLY && !self.settings.write_through() {
+                return Err(EROFS.into());
+            }
+            debug!(target: OP_TAG, "Passing {:?} through to {:?}", path, target);
+            let mut opts = OpenOptions::new();
+            let fd = open_opts_from_mode(&mut opts, flags)
+                .open(&target)?
+                .into_raw_fd();
+            self.passthrough_fds.lock().insert(fd as u64);
+            Ok(fd)
         } else {
             Err(ENOENT.into())
         }
@@ -594,15 +1107,55 @@ where
         }
     }
 
+    fn read_buf(
+        &self,
+        _req: &Request,
+        _path: &Path,
+        _size: usize,
+        _offset: off_t,
+        fi: *const fuse_file_info,
+    ) -> FuseResult<Option<RawFd>> {
+        // every handle we open is read straight from, so fuse can splice from it instead of going through `read`
+        Ok(Some((unsafe { *fi }).fh as RawFd))
+    }
+
     fn write(
         &self,
         _req: &Request,
         path: &Path,
         data: &[u8],
         offset: off_t,
-        _fi: *const fuse_file_info,
+        fi: *const fuse_file_info,
     ) -> FuseResult<usize> {
-        // we're only allowing writing to alias entries, which is why we don't use `self.resolve_mf_path` here
+        // we're only allowing writing to alias entries and written through files, which is why we don't use
+        // `self.resolve_mf_path` here
+        let _path_guard = self.lock_paths(&[path]);
+
+        // only write through opens a passthrough handle for writing, so the handle can only be written if it was
+        let handle = (unsafe { *fi }).fh;
+        if self.passthrough_fds.lock().contains(&handle) {
+            debug!(
+                target: OP_TAG,
+                "Writing {} bytes through to fd {}, offset {}",
+                data.len(),
+                handle,
+                offset
+            );
+            let written = unsafe {
+                libc::pwrite(
+                    handle as i32,
+                    data.as_ptr() as *const ::std::os::raw::c_void,
+                    data.len(),
+                    offset,
+                )
+            };
+            return if written == -1 {
+                Err(std::io::Error::last_os_error().into())
+            } else {
+                Ok(written as usize)
+            };
+        }
+
         match self.op_cache.check_alias_entry(path) {
             // if it's a known alias entry, use alias.write, because it will do validaton on the bytes being
             // written
@@ -626,9 +1179,55 @@ where
         }
     }
 
+    fn write_buf(
+        &self,
+        req: &Request,
+        path: &Path,
+        buf: *mut fuse_bufvec,
+        offset: off_t,
+        fi: *const fuse_file_info,
+    ) -> FuseResult<usize> {
+        let handle = (unsafe { *fi }).fh;
+        if self.passthrough_fds.lock().contains(&handle) {
+            let _path_guard = self.lock_paths(&[path]);
+            debug!(
+                target: OP_TAG,
+                "Writing buffers through to fd {}, offset {}", handle, offset
+            );
+            return fuse_sys::write_bufvec_to_fd(buf, handle as RawFd, offset);
+        }
+
+        // alias entries validate their bytes as they're written, so they need them in memory
+        let data = fuse_sys::bufvec_to_vec(buf)?;
+        self.write(req, path, &data, offset, fi)
+    }
+
+    fn fallocate(
+        &self,
+        _req: &Request,
+        path: &Path,
+        mode: i32,
+        offset: off_t,
+        len: off_t,
+        fi: *const fuse_file_info,
+    ) -> FuseResult<()> {
+        // only written through files are real files that we write to directly
+        let handle = (unsafe { *fi }).fh;
+        if !self.passthrough_fds.lock().contains(&handle) {
+            return Err(ENOSYS.into());
+        }
+        let _path_guard = self.lock_paths(&[path]);
+        info!(
+            target: OP_TAG,
+            "Allocating {} bytes at offset {} of {:?}", len, offset, path
+        );
+        fuse_sys::fallocate_fd_range(handle as RawFd, mode, offset, len)
+    }
+
     fn flush(&self, _req: &Request, path: &Path, fi: *const fuse_file_info) -> FuseResult<()> {
         let handle = (unsafe { *fi }).fh;
         info!(target: OP_TAG, "Flushing {:?} at fd {}", path, handle);
+        let _path_guard = self.lock_paths(&[path]);
         #[cfg(target_os = "macos")]
         {
             self.process_alias(path)
@@ -641,6 +1240,7 @@ where
 
     fn truncate(&self, _req: &Request, path: &Path, offset: off_t) -> FuseResult<()> {
         info!(target: OP_TAG, "Truncating {:?}, offset: {}", path, offset);
+        let _path_guard = self.lock_paths(&[path]);
 
         let conn_lock = self.conn_pool.get_conn();
         let conn = conn_lock.lock();
@@ -655,6 +1255,13 @@ where
                 guard.written = 0;
             }
             Ok(())
+        } else if let Some(target) = self.resolve_to_passthrough_file(&real_conn, path)? {
+            if !self.settings.write_through() {
+                return Err(EROFS.into());
+            }
+            debug!(target: OP_TAG, "Truncating {:?} through to {:?}", path, target);
+            super::util::truncate(&target, offset).map_err(FuseErrno::from)?;
+            Ok(())
         } else {
             Err(ENOENT.into())
         }
@@ -662,16 +1269,25 @@ where
 
     /// Important: do not do an actual close on the fd here. That is not our job, it's the kernel's job. We're just
     /// being notified that all handles to a fd have been closed.
-    fn release(&self, _req: &Request, _path: &Path, _fi: *const fuse_file_info) -> FuseResult<()> {
+    fn release(&self, _req: &Request, _path: &Path, fi: *const fuse_file_info) -> FuseResult<()> {
+        let handle = (unsafe { *fi }).fh;
+
+        // unlike alias handles, we opened passthrough handles ourselves, so nothing else will close them
+        if self.passthrough_fds.lock().remove(&handle) {
+            debug!(target: OP_TAG, "Closing passthrough fd {}", handle);
+            drop(unsafe { std::fs::File::from_raw_fd(handle as RawFd) });
+            return Ok(());
+        }
+
         #[cfg(target_os = "macos")]
         {
-            let handle = (unsafe { *_fi }).fh;
             info!(
                 target: OP_TAG,
                 "Releasing to {} at fd {}",
                 _path.display(),
                 handle
             );
+            let _path_guard = self.lock_paths(&[_path]);
             self.process_alias(_path)
         }
 
@@ -681,8 +1297,11 @@ where
         }
     }
 
-    fn rmdir(&self, _req: &Request, path: &Path) -> FuseResult<()> {
+    fn rmdir(&self, req: &Request, path: &Path) -> FuseResult<()> {
         info!(target: OP_TAG, "Removing tag dir {}", path.display());
+        self.reject_virtual_paths(&[path])?;
+        let _path_guard = self.lock_paths(&[path]);
+        self.check_tag_access(None, req, path, Access::Write)?;
 
         let tags = TagCollection::new(&self.settings, path);
         let pt = tags.primary_type()?;
@@ -701,6 +1320,11 @@ where
 
     fn unlink(&self, req: &Request, path: &Path) -> FuseResult<()> {
         info!(target: OP_TAG, "Unlinking symlink {}", path.display());
+        if let Some(untagged_path) = self.untagged_path(path) {
+            return self.unlink_untagged(path, untagged_path);
+        }
+        self.reject_virtual_paths(&[path])?;
+        let _path_guard = self.lock_paths(&[path]);
 
         // if this is a pid that we're already blocking from working, report an error
         if self.op_cache.check_delete_pid(req.pid) {
@@ -722,17 +1346,16 @@ where
             let conn_lock = self.conn_pool.get_conn();
             let conn = conn_lock.lock();
             let mut real_conn = (*conn).borrow_mut();
+            self.check_tag_access(Some(&real_conn), req, path, Access::Write)?;
 
-            let tx = real_conn
-                .transaction_with_behavior(TransactionBehavior::Exclusive)
-                .map_err(SupertagShimError::from)?;
+            let tx = sql::begin_write(&mut real_conn).map_err(|e| self.op_error(path, e.into()))?;
 
-            common::fsops::rm(&self.settings, &tx, path)?;
+            common::fsops::rm(&self.settings, &tx, path).map_err(|e| self.op_error(path, e))?;
 
-            tx.commit().map_err(SupertagShimError::from)?;
+            tx.commit().map_err(|e| self.op_error(path, e.into()))?;
 
             self.op_cache.clear_alias(path);
-            self.flush_paths_tags(path);
+            self.flush_changed_tags(&real_conn);
             self.flush_readdir_cache(path);
             Ok(())
         }
@@ -740,14 +1363,17 @@ where
 
     fn mkdir(&self, req: &Request, path: &Path, mode: mode_t) -> FuseResult<()> {
         info!(target: OP_TAG, "Making tag dir {}", path.display());
+        self.reject_virtual_paths(&[path])?;
+        let _path_guard = self.lock_paths(&[path]);
 
         let conn_lock = self.conn_pool.get_conn();
         let conn = conn_lock.lock();
         let mut real_conn = (*conn).borrow_mut();
+        if let Some(parent) = path.parent() {
+            self.check_tag_access(Some(&real_conn), req, parent, Access::Write)?;
+        }
 
-        let tx = real_conn
-            .transaction_with_behavior(TransactionBehavior::Exclusive)
-            .map_err(SupertagShimError::from)?;
+        let tx = sql::begin_write(&mut real_conn).map_err(|e| self.op_error(path, e.into()))?;
 
         common::fsops::mkdir(
             &self.settings,
@@ -757,8 +1383,9 @@ where
             req.gid,
             &Permissions::from(mode),
         )
-        .map_err(SupertagShimError::from)?;
-        tx.commit().map_err(SupertagShimError::from)?;
+        .map_err(|e| self.op_error(path, e))?;
+        tx.commit().map_err(|e| self.op_error(path, e.into()))?;
+        self.flush_changed_tags(&real_conn);
         Ok(())
     }
 
@@ -769,14 +1396,17 @@ where
             src.display(),
             dst.display()
         );
+        self.reject_virtual_paths(&[src, dst])?;
+        let _path_guard = self.lock_paths(&[src, dst]);
 
         let conn_lock = self.conn_pool.get_conn();
         let conn = conn_lock.lock();
         let mut real_conn = (*conn).borrow_mut();
+        // a rename takes the file out of the source tags and puts it in the destination tags, so it writes to both
+        self.check_tag_access(Some(&real_conn), req, src, Access::Write)?;
+        self.check_tag_access(Some(&real_conn), req, dst, Access::Write)?;
 
-        let tx = real_conn
-            .transaction_with_behavior(TransactionBehavior::Exclusive)
-            .map_err(SupertagShimError::from)?;
+        let tx = sql::begin_write(&mut real_conn).map_err(|e| self.op_error(dst, e.into()))?;
 
         let dst_name = get_filename(dst)?;
         if common::should_unlink(dst_name) {
@@ -788,11 +1418,12 @@ where
             let tags = TagCollection::new(&self.settings, src);
             match tags.primary_type()? {
                 TagType::DeviceFileSymlink(_) | TagType::Symlink(_) => {
-                    common::fsops::rm(&self.settings, &tx, src)?;
-                    self.flush_paths_tags(src);
+                    common::fsops::rm(&self.settings, &tx, src)
+                        .map_err(|e| self.op_error(src, e))?;
                 }
                 TagType::Regular(_) | TagType::Group(_) => {
-                    common::fsops::rmdir(&self.settings, &tx, src)?;
+                    common::fsops::rmdir(&self.settings, &tx, src)
+                        .map_err(|e| self.op_error(src, e))?;
                     self.op_cache.add_rename_delete_entry(dst);
                 }
                 _ => {
@@ -810,10 +1441,13 @@ where
                 req.gid,
                 &req.umask.into(),
                 &*(self.notifier.lock()),
-            )?;
+                // a file browser can't ask, so collisions fall back to device/inode names
+                |_| Ok(MergeResolution::KeepBoth),
+            )
+            .map_err(|e| self.op_error(dst, e))?;
         }
 
-        tx.commit().map_err(SupertagShimError::from)?;
+        tx.commit().map_err(|e| self.op_error(dst, e.into()))?;
 
         // now that our tagdir has been renamed, we need to flush it from our readdir cache, so
         // that it doesn't get reported as existing
@@ -824,39 +1458,112 @@ where
         // naming. so we need to flush the readdir cache on it, so the old unqualified name doesn't appear.
         self.flush_readdir_cache(dst);
 
-        self.flush_paths_tags(dst);
+        self.flush_changed_tags(&real_conn);
+        self.invalidate_paths(&[src, dst]);
 
         Ok(())
     }
 
-    fn statfs(&self, _req: &Request, _path: &Path) -> FuseResult<statvfs> {
-        let mut res = new_statvfs();
-        res.f_bsize = 4096;
-        res.f_frsize = 4096;
+    /// Only tag directories can trade places, by swapping their names.  Files can't, since a file's name is shared by
+    /// every tag directory it appears in.
+    fn exchange(&self, req: &Request, a: &Path, b: &Path) -> FuseResult<()> {
+        info!(target: OP_TAG, "Exchanging {} and {}", a.display(), b.display());
+        self.reject_virtual_paths(&[a, b])?;
+        let _path_guard = self.lock_paths(&[a, b]);
 
-        // 100 GB worth of blocks
-        #[cfg(target_os = "macos")]
-        {
-            res.f_blocks = ((100 * 1024u64.pow(3u32)) / res.f_bsize) as u32;
-        }
+        let conn_lock = self.conn_pool.get_conn();
+        let conn = conn_lock.lock();
+        let mut real_conn = (*conn).borrow_mut();
+        self.check_tag_access(Some(&real_conn), req, a, Access::Write)?;
+        self.check_tag_access(Some(&real_conn), req, b, Access::Write)?;
 
-        #[cfg(not(target_os = "macos"))]
-        {
-            res.f_blocks = (100 * 1024u64.pow(3u32)) / res.f_bsize;
-        }
+        let tx = sql::begin_write(&mut real_conn).map_err(|e| self.op_error(a, e.into()))?;
+        common::fsops::swap(&self.settings, &tx, a, b).map_err(|e| self.op_error(a, e))?;
+        tx.commit().map_err(|e| self.op_error(a, e.into()))?;
 
-        // FIXME make all these represent the filesystem that the sqlite db is hosted on
-        res.f_bfree = res.f_blocks;
-        res.f_bavail = res.f_blocks;
-        res.f_files = 100; // FIXME
-        res.f_ffree = 10_000;
-        res.f_favail = res.f_ffree;
+        self.flush_readdir_cache(a);
+        self.flush_readdir_cache(b);
+        self.flush_changed_tags(&real_conn);
+        self.invalidate_paths(&[a, b]);
+
+        Ok(())
+    }
+
+    fn statfs(&self, _req: &Request, _path: &Path) -> FuseResult<statvfs> {
+        // the files we hold are really the database, so our capacity is that of the filesystem it lives on
+        let vfs = self.db_statvfs()?;
+        let mut res = new_statvfs();
+        res.f_bsize = vfs.block_size() as _;
+        res.f_frsize = vfs.fragment_size() as _;
+        res.f_blocks = vfs.blocks() as _;
+        res.f_bfree = vfs.blocks_free() as _;
+        res.f_bavail = vfs.blocks_available() as _;
+        res.f_files = vfs.files() as _;
+        res.f_ffree = vfs.files_free() as _;
+        res.f_favail = vfs.files_available() as _;
+        res.f_namemax = vfs.name_max() as _;
         Ok(res)
     }
 
+    #[cfg(target_os = "macos")]
+    fn statfs_x(
+        &self,
+        _req: &Request,
+        _path: &Path,
+        stbuf: &mut fuse_sys::statfs,
+    ) -> FuseResult<()> {
+        let vfs = self.db_statvfs()?;
+        stbuf.f_bsize = vfs.fragment_size() as u32;
+        stbuf.f_iosize = vfs.block_size() as i32;
+        stbuf.f_blocks = vfs.blocks() as u64;
+        stbuf.f_bfree = vfs.blocks_free() as u64;
+        stbuf.f_bavail = vfs.blocks_available() as u64;
+        stbuf.f_files = vfs.files() as u64;
+        stbuf.f_ffree = vfs.files_free() as u64;
+        Ok(())
+    }
+
+    /// Finder renames the volume through here.  We can't change the name that the mount was made with, so the new
+    /// name is saved for the collection's next mount.
+    #[cfg(target_os = "macos")]
+    fn setvolname(&self, _req: &Request, name: &str) -> FuseResult<()> {
+        let col = self.settings.get_collection();
+        info!(target: OP_TAG, "Renaming volume of {} to {}", col, name);
+        std::fs::write(self.settings.volname_file(&col), name)?;
+        Ok(())
+    }
+
     fn set_handle(&mut self, handle: Arc<FuseHandle>) {
         debug!(target: OP_TAG, "Setting fuse handle");
         self.handle = Some(handle);
+
+        // the handle is only set once we've been mounted, which makes this the place to kick off the cache warm-up
+        if self.settings.get_config().cache.warm_on_mount {
+            Warmer {
+                settings: self.settings.clone(),
+                conn_pool: self.conn_pool.clone(),
+                op_cache: self.op_cache.clone(),
+                notifier: self.notifier.clone(),
+                threads_done: self.threads_done.clone(),
+            }
+            .spawn();
+        }
+
+        if self.settings.get_config().remote.refresh_mtimes {
+            RemoteFiles::spawn_refresher(
+                self.remote.clone(),
+                self.conn_pool.clone(),
+                self.threads_done.clone(),
+            );
+        }
+
+        if self.settings.get_config().tracking.follow_renames {
+            RenameTracker::spawn_indexer(
+                self.tracker.clone(),
+                self.conn_pool.clone(),
+                self.threads_done.clone(),
+            );
+        }
     }
 
     #[cfg(target_os = "macos")]
@@ -872,7 +1579,6 @@ where
         self.setxattr_impl(req, path, name, value, position, flags)
     }
 
-    #[cfg(target_os = "macos")]
     fn getxattr(
         &self,
         req: &Request,
@@ -883,7 +1589,6 @@ where
         self.getxattr_impl(req, path, name, position)
     }
 
-    #[cfg(target_os = "macos")]
     fn listxattr(&self, req: &Request, path: &Path, options: i32) -> FuseResult<Vec<String>> {
         self.listxattr_impl(req, path, options)
     }
@@ -893,3 +1598,121 @@ where
         self.removexattr_impl(req, path, name, options)
     }
 }
+
+/// The bytes we can still write to the filesystem holding `path`
+fn free_bytes(path: &Path) -> nix::Result<u64> {
+    let vfs = nix::sys::statvfs::statvfs(path)?;
+    Ok(vfs.blocks_available() as u64 * vfs.fragment_size() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::notify::desktop::DesktopNotifier;
+    use crate::common::testing::{self, COLLECTION};
+    use crate::common::types::file_perms::UMask;
+
+    fn filesystem(settings: &Arc<Settings>) -> TagFilesystem<DesktopNotifier> {
+        let notifier = Arc::new(Mutex::new(DesktopNotifier::from_settings(settings)));
+        let conn_pool = ThreadConnPool::new(settings.db_file(COLLECTION));
+        TagFilesystem::new(settings.clone(), conn_pool, notifier)
+    }
+
+    #[test]
+    fn test_canonical_op_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let settings = testing::settings(dir.path(), &[]);
+        let fs = filesystem(&settings);
+        let syms = settings.get_config().symbols;
+        let key = |path: String| fs.canonical_op_path(Path::new(&path));
+        let (fd, fd_cli, sync) = (&syms.filedir_str, &syms.filedir_cli_str, syms.sync_char);
+
+        assert_eq!(key(format!("/a/b/{}/f", fd)), key(format!("/b/a/{}/f", fd)));
+        assert_eq!(
+            key(format!("/a/b/{}/f", fd)),
+            key(format!("/b{}/a/{}/f", sync, fd_cli))
+        );
+        assert_eq!(key(format!("/a/b/{}", fd)), key(format!("/b/a/{}", fd)));
+
+        // a tag group moves with the tag it qualifies
+        assert_eq!(
+            key(format!("/g+/a/b/{}/f", fd)),
+            key(format!("/b/g+/a/{}/f", fd))
+        );
+        assert_ne!(
+            key(format!("/g+/a/b/{}/f", fd)),
+            key(format!("/g+/b/a/{}/f", fd))
+        );
+
+        // without a filedir, the last part is the entry itself, so it stays put
+        assert_eq!(key("/a/b/c".to_string()), key("/b/a/c".to_string()));
+        assert_ne!(key("/a/b".to_string()), key("/b/a".to_string()));
+    }
+
+    #[test]
+    /// Tests that a mutation waits on one that's in progress on the same file, when it names the file's tags in
+    /// another order
+    fn test_conflicting_ops_serialize() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let settings = testing::settings(dir.path(), &[]);
+        let src = dir.path().join("f.txt");
+        std::fs::write(&src, b"")?;
+        {
+            let mut conn = sql::get_conn(settings.db_file(COLLECTION))?;
+            let tx = sql::begin_write(&mut conn)?;
+            common::fsops::ln(
+                &settings,
+                &tx,
+                &src,
+                Path::new("a/b"),
+                "f.txt",
+                0,
+                0,
+                &UMask::default(),
+                None,
+                &DesktopNotifier::from_settings(&settings),
+            )?;
+            tx.commit()?;
+        }
+
+        let fs = filesystem(&settings);
+        let filedir = settings.get_config().symbols.filedir_str;
+        let held = PathBuf::from(format!("/a/b/{}/f.txt", filedir));
+        let unlinked = PathBuf::from(format!("/b/a/{}/f.txt", filedir));
+        let req = Request {
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+            pid: 0,
+            umask: 0o022,
+        };
+        let done = AtomicBool::new(false);
+
+        let res = crossbeam::scope(|scope| {
+            // stands in for an operation on the first spelling, like a rename, that's still in progress
+            let guard = fs.lock_paths(&[&held]);
+            let unlinking = scope.spawn(|_| {
+                let res = fs.unlink(&req, &unlinked);
+                done.store(true, Ordering::SeqCst);
+                res
+            });
+            std::thread::sleep(Duration::from_millis(300));
+            assert!(
+                !done.load(Ordering::SeqCst),
+                "The unlink didn't wait for the operation on {:?}",
+                held
+            );
+            drop(guard);
+            unlinking.join().unwrap()
+        })
+        .unwrap();
+        assert!(res.is_ok());
+        assert!(done.load(Ordering::SeqCst));
+
+        let conn = sql::get_conn(settings.db_file(COLLECTION))?;
+        let tagged =
+            |tag: &str| sql::files_tagged_with(&conn, &[TagType::Regular(tag.to_string())]);
+        assert!(tagged("a")?.is_empty());
+        assert_eq!(tagged("b")?.len(), 1);
+        Ok(())
+    }
+}
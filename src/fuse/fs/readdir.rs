@@ -20,6 +20,8 @@ use super::TagFilesystem;
 use super::OP_TAG;
 use crate::common::constants;
 use crate::common::err::STagResult;
+use crate::common::settings::config::Sort;
+use crate::common::types::file_perms::Access;
 use crate::common::types::{TagCollectible, TagCollection, TagType, UtcDt};
 use crate::fuse::err::SupertagShimError;
 use crate::fuse::opcache;
@@ -27,32 +29,60 @@ use crate::sql::types::{Tag, TagOrTagGroup};
 use crate::{common, sql};
 use fuse_sys::err::FuseErrno;
 use fuse_sys::{FileEntry, FuseResult, Request};
-use log::{debug, error, info, trace};
 use nix::errno::Errno::ENOENT;
 use rusqlite::Connection;
 use std::cell::RefCell;
 use std::collections::{HashMap, HashSet};
 use std::path::Path;
 use std::sync::Arc;
+use tracing::{debug, error, info, trace};
 
 impl<N> TagFilesystem<N>
 where
     N: common::notify::Notifier,
 {
     // FIXME see https://users.rust-lang.org/t/internal-visibility-for-trait-methods/15596/2 for a better way
+    /// Lists the entries of `path`, starting with the `offset`th.  Filedirs are listed a page at a time, so the
+    /// iterator may end before the listing does; the kernel calls again for the rest.
     pub fn readdir_impl(
         &self,
-        _req: &Request,
+        req: &Request,
         path: &Path,
+        offset: usize,
     ) -> FuseResult<Box<dyn Iterator<Item = FileEntry>>> {
-        info!(target: OP_TAG, "Listing directory {:?}", path);
+        info!(
+            target: OP_TAG,
+            "Listing directory {:?} from offset {}", path, offset
+        );
 
         let conn_lock = self.conn_pool.get_conn();
         let conn = conn_lock.lock();
         let real_conn = &(*conn).borrow_mut();
         let root_mtime = self.get_root_mtime(Some(&real_conn))?;
+        // listing an intersection reveals the files of each of its tags
+        self.check_tag_access(Some(&real_conn), req, path, Access::Read)?;
 
-        let query_tags = TagCollection::new(&self.settings, path);
+        if let Some(search_path) = self.saved_search_path(path) {
+            let entries = self.readdir_saved_search(real_conn, path, search_path)?;
+            return Ok(Box::new(entries.skip(offset)));
+        }
+        if let Some(dupes_path) = self.duplicates_path(path) {
+            let entries = self.readdir_duplicates(real_conn, path, dupes_path)?;
+            return Ok(Box::new(entries.skip(offset)));
+        }
+        if let Some(untagged_path) = self.untagged_path(path) {
+            let entries = self.readdir_untagged(real_conn, path, untagged_path)?;
+            return Ok(Box::new(entries.skip(offset)));
+        }
+        if let Some(entry_path) = self.filedir_entry_path(path) {
+            if let Some(real) = self.tagged_dir_target(real_conn, &entry_path)? {
+                let entries = self.readdir_tagged_dir(&real)?;
+                return Ok(Box::new(entries.into_iter().skip(offset)));
+            }
+        }
+
+        let query_tags = self.path_tags(real_conn, path);
+        let sort = self.settings.get_config().mount.sort;
 
         match query_tags.len() {
             // just the root dir?  display all the tags
@@ -61,7 +91,8 @@ where
                     target: OP_TAG,
                     "It's a root directory, so listing all tags and tag groups"
                 );
-                let tags = sql::get_all_tags(real_conn).map_err(SupertagShimError::from)?;
+                let tags =
+                    sql::get_all_tags_sorted(real_conn, sort).map_err(SupertagShimError::from)?;
                 let tag_groups =
                     sql::get_all_tag_groups(real_conn).map_err(SupertagShimError::from)?;
                 debug!(
@@ -92,7 +123,9 @@ where
 
                 let has_taggroup_closure1 = has_taggroup.clone();
                 let closure_settings = self.settings.clone();
-                let extra = self.extra_root_entries(&root_mtime);
+                let mut extra = self.extra_root_entries(real_conn, &root_mtime);
+                extra.extend(self.date_entries(real_conn, &[]));
+                extra.extend(self.filetype_entries(real_conn, &[], &root_mtime));
 
                 let entry_iter = tags
                     .into_iter()
@@ -110,7 +143,7 @@ where
                     )
                     .chain(extra);
 
-                Ok(Box::new(entry_iter))
+                Ok(Box::new(entry_iter.skip(offset)))
             }
             // we're in a subdirectory, find the intersecting tags and associated files
             _ => {
@@ -122,7 +155,7 @@ where
                 if path == Path::new(constants::STAG_ROOT_CONF_PATH) {
                     debug!(target: OP_TAG, "readdir on supertag conf path");
                     let conf_iter = self.readdir_supertag_root_conf(root_mtime).into_iter();
-                    return Ok(Box::new(conf_iter));
+                    return Ok(Box::new(conf_iter.skip(offset)));
                 } else if path
                     == Path::new(&format!(
                         "/{}",
@@ -130,9 +163,10 @@ where
                     ))
                 {
                     debug!(target: OP_TAG, "readdir on root filedir with all tags");
-                    return self
+                    let entries = self
                         .readdir_root_filedir(&real_conn)
-                        .map_err(FuseErrno::from);
+                        .map_err(FuseErrno::from)?;
+                    return Ok(Box::new(entries.skip(offset)));
                 }
 
                 // we need to validate tag group pairs, which ensure that if a tag group is followed by a regular
@@ -148,46 +182,65 @@ where
                         return Err(ENOENT.into());
                     }
                 }
+                // and the same for a tag group followed by another tag group, which must be nested in it
+                for (parent, child) in query_tags.iter().nested_group_pairs() {
+                    if !sql::group_is_in_group(real_conn, parent, child)
+                        .map_err(SupertagShimError::from)?
+                    {
+                        error!(
+                            target: OP_TAG,
+                            "Tag group {} isn't nested in tag group {}", child, parent
+                        );
+                        return Err(ENOENT.into());
+                    }
+                }
 
                 let primary_type = query_tags.primary_type()?;
 
                 match primary_type {
+                    // the file type group only holds the extensions of the files before it
+                    TagType::FileTypeGroup => {
+                        let entries =
+                            self.filetype_entries(real_conn, query_tags.as_slice(), &root_mtime);
+                        Ok(Box::new(entries.into_iter().skip(offset)))
+                    }
                     // are we in the directory designated for file intersections?  list the intersecting
                     // files
                     TagType::FileDir => {
                         let extra = self.extra_filedir_entries(&root_mtime);
-
-                        let intersect_files =
-                            sql::files_tagged_with(real_conn, query_tags.as_slice())
-                                .map_err(SupertagShimError::from)?;
-
-                        // we need to compute duplicate names, so first we'll build up a hashmap of names and their
-                        // count in the result set.  later we'll use this map to determine if we have a duplicate and
-                        // need to render the name with inodify
-                        let mut name_count = HashMap::new();
-                        for ifile in intersect_files.iter() {
-                            *name_count.entry(ifile.primary_tag.to_string()).or_insert(0) += 1;
-                        }
+                        let file_offset = offset.saturating_sub(extra.len());
+                        let page =
+                            self.filedir_page(real_conn, path, &query_tags, sort, file_offset)?;
+                        let intersect_files = page.files[file_offset - page.offset..].to_vec();
 
                         let opcache = self.op_cache.clone();
                         let path = path.to_owned();
 
                         let settings_closure = self.settings.clone();
-                        let intersect_iter = intersect_files.into_iter().map(move |file| {
+                        let remote = self.remote.clone();
+                        let refresh_mtimes = self.settings.get_config().remote.refresh_mtimes;
+                        let intersect_iter = intersect_files.into_iter().map(move |mut file| {
                             // here we're deciding how we want to render the filename.  if there's duplicates for that
                             // name, we need to fully qualify the name with inodify.  otherwise, we can just use the
-                            // name as-is
+                            // display name.  the fully qualified name always uses the real name, since it must
+                            // resolve to exactly one file
+                            let display_name = settings_closure.display_name(&file.primary_tag);
                             let ifilename = {
-                                if name_count[&file.primary_tag] > 1 {
+                                if page.shared_names.contains(&display_name) {
                                     settings_closure.inodify_filename(
                                         &file.primary_tag,
                                         file.device,
                                         file.inode,
                                     )
                                 } else {
-                                    file.primary_tag.to_string()
+                                    display_name
                                 }
                             };
+                            // files on slow devices keep their last known mtime, so that listing them doesn't
+                            // stall on a stat
+                            if refresh_mtimes {
+                                file.mtime = remote.mtime_for(&file).0;
+                            }
                             let full_path = path.join(&ifilename);
                             let cache_entry = opcache::ReaddirCacheEntry::File(file.clone());
                             opcache.add_readdir_entry(&full_path, cache_entry);
@@ -197,21 +250,42 @@ where
                             }
                         });
 
-                        Ok(Box::new(extra.into_iter().chain(intersect_iter)))
+                        Ok(Box::new(
+                            extra.into_iter().skip(offset).chain(intersect_iter),
+                        ))
                     }
                     // otherwise we're only supposed to list our intersecting tagdirs and tag groups
                     _ => {
-                        // get all of our tags that intersect with `query_tags`
-                        let intersect_tags =
-                            sql::intersect_tag(real_conn, query_tags.as_slice(), true)
-                                .map_err(SupertagShimError::from)?;
+                        // get all of our tags that intersect with `query_tags`.  counting their files is left to
+                        // getattr, unless we need the counts or the intersection mtimes to sort by
+                        let count = self.settings.listing_sizes() || sort != Sort::Name;
+                        let intersect_tags = if count {
+                            sql::intersect_tag_sorted(real_conn, query_tags.as_slice(), true, sort)
+                        } else {
+                            sql::intersect_tag_uncounted_sorted(
+                                real_conn,
+                                query_tags.as_slice(),
+                                true,
+                                sort,
+                            )
+                        }
+                        .map_err(SupertagShimError::from)?;
 
-                        // for every tag in our intersection, find all of the tag groups that they should be grouped into
+                        // for every tag in our intersection, find all of the tag groups that they should be grouped
+                        // into.  inside of a tag group dir, those are the groups nested in it
                         let all_tag_ids =
                             intersect_tags.iter().map(|tag| tag.id).collect::<Vec<_>>();
-                        let tag_groups =
-                            sql::tag_groups_for_tags(real_conn, all_tag_ids.as_slice())
-                                .map_err(SupertagShimError::from)?;
+                        let tag_groups = match primary_type {
+                            TagType::Group(parent_group) | TagType::GroupAll(parent_group) => {
+                                sql::subgroups_for_tags(
+                                    real_conn,
+                                    parent_group,
+                                    all_tag_ids.as_slice(),
+                                )
+                            }
+                            _ => sql::tag_groups_for_tags(real_conn, all_tag_ids.as_slice()),
+                        }
+                        .map_err(SupertagShimError::from)?;
 
                         // this will serve to ignore a tagdir if we find that it has a tag group that would be displayed
                         // here instead
@@ -219,48 +293,46 @@ where
                             Arc::new(RefCell::new(HashSet::new()));
                         let tag = query_tags.last().unwrap();
 
-                        // if we're currently listing a tag group dir, do not collapse any `intersect_tags` into
-                        // further tag groups.
-                        let mut in_a_taggroup = false;
-                        if let TagType::Group(_tag_group) = primary_type {
-                            debug!(target: OP_TAG, "Skipping tag group exclusions",);
-                            in_a_taggroup = true;
-                        } else {
-                            // fill has_taggroup with all of the tagdirs that we should skip listing.  notice that we're only
-                            // doing this if the discovered tag group doesn't match our parent tag group (if exists).
-                            {
-                                let mut has_tg_mut = has_taggroup.borrow_mut();
-                                for tg in tag_groups.iter() {
-                                    if let TagType::Group(parent_group) = tag {
-                                        if &tg.name != parent_group {
-                                            has_tg_mut.extend(tg.tag_ids.iter());
-                                        }
-                                    } else {
+                        // fill has_taggroup with all of the tagdirs that we should skip listing.  notice that we're
+                        // only doing this if the discovered tag group doesn't match our parent tag group (if exists).
+                        // in a tag group dir, these are the tags of the groups nested in it, which are listed there
+                        {
+                            let mut has_tg_mut = has_taggroup.borrow_mut();
+                            for tg in tag_groups.iter() {
+                                if let TagType::Group(parent_group)
+                                | TagType::GroupAll(parent_group) = tag
+                                {
+                                    if &tg.name != parent_group {
                                         has_tg_mut.extend(tg.tag_ids.iter());
                                     }
+                                } else {
+                                    has_tg_mut.extend(tg.tag_ids.iter());
                                 }
                             }
-                            debug!(
-                                target: OP_TAG,
-                                "Excluding tagdirs {:?} from listing because they have tag groups",
-                                has_taggroup.borrow()
-                            );
                         }
+                        debug!(
+                            target: OP_TAG,
+                            "Excluding tagdirs {:?} from listing because they have tag groups",
+                            has_taggroup.borrow()
+                        );
 
-                        // this iter will either render out tag groups as file entries, or not, based on if we're
-                        // already in a tag group, so we don't end up with things like /+a_tags/+a_tags
+                        // this iter renders out tag groups as file entries.  in a tag group dir, they're only the
+                        // groups nested in it, so we don't end up with things like /+a_tags/+a_tags
                         let settings_c1 = self.settings.clone();
                         let path2 = path.to_owned();
                         let op_cache2 = self.op_cache.clone();
+                        // a nested tag group made with mkdir is pinned too, so this keeps it from being listed twice
+                        let seen_groups: Arc<RefCell<HashSet<i64>>> =
+                            Arc::new(RefCell::new(HashSet::new()));
+                        let seen_groups1 = seen_groups.clone();
                         let tag_groups_iter = tag_groups
                             .into_iter()
-                            // if we're in a tag group, we shouldn't render any tag groups
-                            .filter(move |tg| {
+                            .inspect(move |tg| {
+                                seen_groups1.borrow_mut().insert(tg.id);
                                 op_cache2.add_readdir_entry(
                                     &path2.join(&tg.name),
                                     opcache::ReaddirCacheEntry::TagGroup(tg.to_owned()),
                                 );
-                                !in_a_taggroup
                             })
                             .map(move |tg| tg.to_fileentry(&settings_c1))
                             .inspect(|fe| {
@@ -328,14 +400,34 @@ where
                                     }
                                 }
                                 TagOrTagGroup::Group(group) => {
-                                    Some(group.to_fileentry(&settings_c2))
+                                    if seen_groups.borrow().contains(&group.id) {
+                                        None
+                                    } else {
+                                        Some(group.to_fileentry(&settings_c2))
+                                    }
                                 }
                             })
                             .inspect(|fe| trace!(target: OP_TAG, "Yielding {:?} from pins", fe));
 
-                        let final_iter = tag_groups_iter.chain(tag_intersect_iter).chain(pin_iter);
+                        // a tag group only lists its own tags, so the dates and file types are left out of it
+                        let in_a_taggroup =
+                            matches!(primary_type, TagType::Group(_) | TagType::GroupAll(_));
+                        let mut computed = vec![];
+                        if !in_a_taggroup {
+                            computed.extend(self.date_entries(real_conn, query_tags.as_slice()));
+                            computed.extend(self.filetype_entries(
+                                real_conn,
+                                query_tags.as_slice(),
+                                &root_mtime,
+                            ));
+                        }
+
+                        let final_iter = tag_groups_iter
+                            .chain(tag_intersect_iter)
+                            .chain(pin_iter)
+                            .chain(computed);
 
-                        Ok(Box::new(final_iter))
+                        Ok(Box::new(final_iter.skip(offset)))
                     }
                 }
             }
@@ -358,13 +450,18 @@ where
             mtime: now,
         });
 
+        // a tagged directory only has what's in the real directory
+        if self.filedir_entry_path(path).is_some() {
+            return Ok(Box::new(common.into_iter()));
+        }
+
         let tags = TagCollection::new(&self.settings, path);
         let is_root = tags.len() == 0;
         if !is_root {
             let pt = tags.primary_type()?;
             let is_filedir = pt == &TagType::FileDir;
             let is_tag_group = match pt {
-                TagType::Group(_) => true,
+                TagType::Group(_) | TagType::GroupAll(_) | TagType::FileTypeGroup => true,
                 _ => false,
             };
 
@@ -373,10 +470,10 @@ where
                 let conn = conn_lock.lock();
                 let real_conn = &(*conn).borrow_mut();
 
-                let intersect_files = sql::files_tagged_with(real_conn, tags.as_slice())
-                    .map_err(SupertagShimError::from)?;
+                let has_files =
+                    sql::has_files(real_conn, tags.as_slice()).map_err(SupertagShimError::from)?;
 
-                if !intersect_files.is_empty() {
+                if has_files {
                     common.push(FileEntry {
                         name: self.settings.get_config().symbols.filedir_str.clone(),
                         mtime: now,
@@ -388,6 +485,64 @@ where
         Ok(Box::new(common.into_iter()))
     }
 
+    /// The page of the filedir listing at `path` that holds its `offset`th file.  Pages are cached while a listing
+    /// is in progress, but a listing that starts over, at offset 0, always starts from a fresh page.
+    fn filedir_page(
+        &self,
+        conn: &Connection,
+        path: &Path,
+        tags: &TagCollection,
+        sort: Sort,
+        offset: usize,
+    ) -> FuseResult<Arc<opcache::ReaddirPage>> {
+        // a rating directory lists the best rated files first, whatever the mount's sort is
+        let sort = if tags.iter().any(|tt| matches!(tt, TagType::Rating(_))) {
+            Sort::Rating
+        } else {
+            sort
+        };
+
+        if offset > 0 {
+            if let Some(page) = self.op_cache.check_readdir_page(path, offset) {
+                return Ok(page);
+            }
+        }
+
+        let files = sql::files_tagged_with_page(
+            conn,
+            tags.as_slice(),
+            sort,
+            offset,
+            constants::READDIR_PAGE_SIZE,
+        )
+        .map_err(SupertagShimError::from)?;
+
+        // a name needs inodify if more than one file in the whole listing, not just this page, has it.  the count is
+        // by display name, since two different real names can be transformed into the same displayed name.  when
+        // names aren't transformed, only the names that the database already knows are shared need fetching
+        let only_shared = !self.settings.get_config().display.is_active();
+        let mut name_count = HashMap::new();
+        for (name, count) in sql::primary_tag_counts(conn, tags.as_slice(), only_shared)
+            .map_err(SupertagShimError::from)?
+        {
+            *name_count
+                .entry(self.settings.display_name(&name))
+                .or_insert(0) += count;
+        }
+        let shared_names = name_count
+            .into_iter()
+            .filter(|(_, count)| *count > 1)
+            .map(|(name, _)| name)
+            .collect();
+
+        let page = opcache::ReaddirPage {
+            offset,
+            files,
+            shared_names,
+        };
+        Ok(self.op_cache.add_readdir_page(path, page))
+    }
+
     fn readdir_supertag_root_conf(&self, now: UtcDt) -> Vec<FileEntry> {
         let mut entries = vec![];
         entries.push(FileEntry {
@@ -402,7 +557,7 @@ where
         &self,
         conn: &Connection,
     ) -> STagResult<Box<dyn Iterator<Item = FileEntry>>> {
-        let tags = sql::get_all_tags(conn)?;
+        let tags = sql::get_all_tags_sorted(conn, self.settings.get_config().mount.sort)?;
 
         let tag_iter = tags
             .into_iter()
@@ -425,8 +580,115 @@ where
         entries
     }
 
-    fn extra_root_entries(&self, _mtime: &UtcDt) -> Vec<FileEntry> {
-        let entries = vec![];
+    /// The date directories to list alongside the tagdirs of `tags`, if dates are enabled.  They're the years that the
+    /// intersection's files were modified in, or the months of a year once the path is in one.
+    fn date_entries(&self, conn: &Connection, tags: &[TagType]) -> Vec<FileEntry> {
+        if !self.settings.get_config().dates.enabled {
+            return vec![];
+        }
+
+        let last_date = tags.iter().rev().find_map(|tt| match tt {
+            TagType::Date(bucket) => Some(bucket),
+            _ => None,
+        });
+        let months = match last_date {
+            None => false,
+            Some(bucket) if !bucket.contains('-') => true,
+            // a month is as deep as dates go
+            Some(_) => return vec![],
+        };
+
+        match sql::date_buckets(conn, tags, months) {
+            Ok(buckets) => buckets
+                .into_iter()
+                .map(|(bucket, mtime)| FileEntry {
+                    name: format!("{}{}", constants::DATE_TAG_PREFIX, bucket),
+                    mtime,
+                })
+                .collect(),
+            Err(e) => {
+                error!(target: OP_TAG, "Couldn't list dates for {:?}: {:?}", tags, e);
+                vec![]
+            }
+        }
+    }
+
+    /// The file type entries to list in the directory of `tags`, if file types are enabled.  Inside the file type
+    /// group, they're the extensions of the intersection's files, and elsewhere, it's the group itself, until the path
+    /// has gone through it.
+    fn filetype_entries(
+        &self,
+        conn: &Connection,
+        tags: &[TagType],
+        mtime: &UtcDt,
+    ) -> Vec<FileEntry> {
+        let conf = self.settings.get_config();
+        if !conf.filetypes.enabled {
+            return vec![];
+        }
+
+        let listed = if let Some(TagType::FileTypeGroup) = tags.last() {
+            sql::file_extensions(conn, tags)
+        } else if tags
+            .iter()
+            .any(|tt| matches!(tt, TagType::FileTypeGroup | TagType::FileType(_)))
+        {
+            return vec![];
+        } else {
+            let mut group_tags = tags.to_vec();
+            group_tags.push(TagType::FileTypeGroup);
+            let group = common::set_ext_prefix(&conf.filetypes.group, &conf.symbols.tag_group_str);
+            sql::has_files(conn, &group_tags)
+                .map(|has_files| if has_files { vec![group] } else { vec![] })
+        };
+
+        match listed {
+            Ok(names) => names
+                .into_iter()
+                .map(|name| FileEntry { name, mtime: *mtime })
+                .collect(),
+            Err(e) => {
+                error!(target: OP_TAG, "Couldn't list file types for {:?}: {:?}", tags, e);
+                vec![]
+            }
+        }
+    }
+
+    fn extra_root_entries(&self, conn: &Connection, mtime: &UtcDt) -> Vec<FileEntry> {
+        let mut entries = vec![];
+
+        // the saved searches directory only shows up once there's something in it
+        match sql::get_saved_searches(conn) {
+            Ok(searches) if !searches.is_empty() => entries.push(FileEntry {
+                name: self.settings.get_config().searches.dir,
+                mtime: *mtime,
+            }),
+            Ok(_) => {}
+            Err(e) => error!(target: OP_TAG, "Couldn't list saved searches: {:?}", e),
+        }
+
+        // and so does the duplicates directory, once there are duplicates
+        let dupes_conf = self.settings.get_config().duplicates;
+        if dupes_conf.enabled {
+            match sql::has_duplicate_files(conn) {
+                Ok(true) => entries.push(FileEntry {
+                    name: dupes_conf.dir,
+                    mtime: *mtime,
+                }),
+                Ok(false) => {}
+                Err(e) => error!(target: OP_TAG, "Couldn't list duplicates: {:?}", e),
+            }
+        }
+
+        // and the untagged directory, once a file has had all of its tags removed
+        match sql::has_untagged_files(conn) {
+            Ok(true) => entries.push(FileEntry {
+                name: self.settings.get_config().untagged.dir,
+                mtime: *mtime,
+            }),
+            Ok(false) => {}
+            Err(e) => error!(target: OP_TAG, "Couldn't list untagged files: {:?}", e),
+        }
 
         // entries.push(FileEntry {
         //     name: constants::STAG_ROOT_CONF_NAME.to_string(),
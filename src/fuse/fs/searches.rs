@@ -0,0 +1,228 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Saved searches live under a single top-level directory, where each search is a directory of symlinks to the files
+//! it currently matches.  These paths aren't tag paths, so they're recognized before any tag parsing happens, and
+//! they're read-only.
+
+use super::super::err::SupertagShimError;
+use super::super::util;
+use super::TagFilesystem;
+use super::OP_TAG;
+use crate::common;
+use crate::common::types::file_perms::UMask;
+use crate::common::types::UtcDt;
+use crate::fuse::opcache::ReaddirCacheEntry;
+use crate::sql;
+use crate::sql::types::TaggedFile;
+use fuse_sys::err::FuseErrno;
+use fuse_sys::{stat, FileEntry, FuseResult, Request};
+use nix::errno::Errno::{ENOENT, ENOTDIR, EPERM};
+use rusqlite::Connection;
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use tracing::{debug, info};
+
+/// Where a path falls under the saved searches directory
+pub(super) enum SearchPath {
+    /// The saved searches directory itself
+    Root,
+    /// The directory of a single saved search
+    Search(String),
+    /// A file in a saved search's results
+    File(String, String),
+    /// Anything nested deeper, which never exists
+    Invalid,
+}
+
+impl<N> TagFilesystem<N>
+where
+    N: common::notify::Notifier,
+{
+    /// Determines whether `path` is under the saved searches directory
+    pub(super) fn saved_search_path(&self, path: &Path) -> Option<SearchPath> {
+        let mut parts = path.components().filter_map(|comp| match comp {
+            Component::Normal(part) => Some(part.to_string_lossy().to_string()),
+            _ => None,
+        });
+
+        if parts.next()? != self.settings.get_config().searches.dir {
+            return None;
+        }
+        match (parts.next(), parts.next(), parts.next()) {
+            (None, _, _) => Some(SearchPath::Root),
+            (Some(name), None, _) => Some(SearchPath::Search(name)),
+            (Some(name), Some(file), None) => Some(SearchPath::File(name, file)),
+            _ => Some(SearchPath::Invalid),
+        }
+    }
+
+    /// Saved search results are read-only, so any path under the saved searches directory refuses modification
+    pub(super) fn reject_saved_search_paths(&self, paths: &[&Path]) -> FuseResult<()> {
+        if paths.iter().any(|p| self.saved_search_path(p).is_some()) {
+            debug!(target: OP_TAG, "Refusing to modify saved search paths {:?}", paths);
+            Err(EPERM.into())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Runs the saved search `name`, if it exists, and names each of its files the way a filedir would
+    fn saved_search_files(
+        &self,
+        conn: &Connection,
+        name: &str,
+    ) -> FuseResult<Option<Vec<(String, TaggedFile)>>> {
+        let search = match sql::get_saved_search(conn, name).map_err(SupertagShimError::from)? {
+            Some(search) => search,
+            None => return Ok(None),
+        };
+
+        let files =
+            common::search::run_saved_search(&self.settings, conn, &search, &chrono::Utc::now())
+                .map_err(SupertagShimError::from)?;
+        Ok(Some(self.filedir_names(files)))
+    }
+
+    /// Names each of `files` the way a filedir would, fully qualifying any names that would otherwise be duplicated
+    pub(super) fn filedir_names(&self, files: Vec<TaggedFile>) -> Vec<(String, TaggedFile)> {
+        let mut name_count = HashMap::new();
+        for file in files.iter() {
+            *name_count
+                .entry(self.settings.display_name(&file.primary_tag))
+                .or_insert(0) += 1;
+        }
+
+        files
+            .into_iter()
+            .map(|file| {
+                let display_name = self.settings.display_name(&file.primary_tag);
+                let filename = if name_count[&display_name] > 1 {
+                    self.settings
+                        .inodify_filename(&file.primary_tag, file.device, file.inode)
+                } else {
+                    display_name
+                };
+                (filename, file)
+            })
+            .collect()
+    }
+
+    /// Finds the file named `filename` in the current results of the saved search `name`
+    fn saved_search_file(&self, path: &Path, name: &str, filename: &str) -> FuseResult<TaggedFile> {
+        if let Some(ReaddirCacheEntry::File(cached_file)) = self.op_cache.check_readdir_entry(path)
+        {
+            return Ok(cached_file);
+        }
+
+        let conn_lock = self.conn_pool.get_conn();
+        let conn = conn_lock.lock();
+        let real_conn = &(*conn).borrow_mut();
+
+        let file = self
+            .saved_search_files(real_conn, name)?
+            .unwrap_or_default()
+            .into_iter()
+            .find(|(fname, _)| fname == filename)
+            .map(|(_, file)| file)
+            .ok_or_else(|| FuseErrno::from(ENOENT))?;
+
+        self.op_cache
+            .add_readdir_entry(path, ReaddirCacheEntry::File(file.clone()));
+        Ok(file)
+    }
+
+    pub(super) fn getattr_saved_search(
+        &self,
+        req: &Request,
+        path: &Path,
+        search_path: SearchPath,
+        root_mtime: &UtcDt,
+    ) -> FuseResult<stat> {
+        let dir_perms = UMask::from(req.umask).dir_perms();
+        match search_path {
+            SearchPath::Root => Ok(util::new_dir(root_mtime, req.uid, req.gid, &dir_perms, 0)),
+            SearchPath::Search(name) => {
+                let conn_lock = self.conn_pool.get_conn();
+                let conn = conn_lock.lock();
+                let search = sql::get_saved_search(&(*conn).borrow_mut(), &name)
+                    .map_err(SupertagShimError::from)?
+                    .ok_or_else(|| FuseErrno::from(ENOENT))?;
+                Ok(util::new_dir(&search.ts, req.uid, req.gid, &dir_perms, 0))
+            }
+            SearchPath::File(name, filename) => Ok(util::new_statfile(
+                self.saved_search_file(path, &name, &filename)?,
+            )),
+            SearchPath::Invalid => Err(ENOENT.into()),
+        }
+    }
+
+    pub(super) fn readdir_saved_search(
+        &self,
+        conn: &Connection,
+        path: &Path,
+        search_path: SearchPath,
+    ) -> FuseResult<Box<dyn Iterator<Item = FileEntry>>> {
+        match search_path {
+            SearchPath::Root => {
+                debug!(target: OP_TAG, "Listing all saved searches");
+                let searches = sql::get_saved_searches(conn).map_err(SupertagShimError::from)?;
+                Ok(Box::new(searches.into_iter().map(|search| FileEntry {
+                    name: search.name,
+                    mtime: search.ts,
+                })))
+            }
+            SearchPath::Search(name) => {
+                info!(target: OP_TAG, "Running saved search {}", name);
+                let files = self
+                    .saved_search_files(conn, &name)?
+                    .ok_or_else(|| FuseErrno::from(ENOENT))?;
+
+                let entries = files
+                    .into_iter()
+                    .map(|(filename, file)| {
+                        self.op_cache.add_readdir_entry(
+                            &path.join(&filename),
+                            ReaddirCacheEntry::File(file.clone()),
+                        );
+                        FileEntry {
+                            name: filename,
+                            mtime: file.mtime,
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                Ok(Box::new(entries.into_iter()))
+            }
+            SearchPath::File(..) => Err(ENOTDIR.into()),
+            SearchPath::Invalid => Err(ENOENT.into()),
+        }
+    }
+
+    pub(super) fn readlink_saved_search(
+        &self,
+        path: &Path,
+        search_path: SearchPath,
+    ) -> FuseResult<PathBuf> {
+        match search_path {
+            SearchPath::File(name, filename) => Ok(self
+                .saved_search_file(path, &name, &filename)?
+                .resolve_path()),
+            _ => Err(ENOENT.into()),
+        }
+    }
+}
@@ -0,0 +1,150 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! A tagged directory is listed in filedirs like any other tagged file, but as a directory instead of a symlink, so
+//! that it can be browsed in place.  Everything beneath it is passed through to the real directory: directories are
+//! stat'd and listed from the real filesystem, and files are symlinks to the real files, like every other file in the
+//! mount.  Nothing is written through, so it's all read-only.
+
+use super::super::err::SupertagShimError;
+use super::super::util;
+use super::TagFilesystem;
+use super::OP_TAG;
+use crate::common;
+use fuse_sys::err::FuseErrno;
+use fuse_sys::{stat, FileEntry, FuseResult};
+use nix::errno::Errno::{EINVAL, ENOENT, ENOTDIR, EPERM};
+use rusqlite::Connection;
+use std::ffi::OsStr;
+use std::path::{Component, Path, PathBuf};
+use tracing::debug;
+
+/// A path at or beneath a filedir entry, ie `docs/⋂/projects/2021/notes.txt`
+pub(super) struct EntryPath {
+    /// The filedir entry, ie `docs/⋂/projects`
+    pub entry: PathBuf,
+    /// The rest of the path, relative to the entry, ie `2021/notes.txt`
+    pub rest: PathBuf,
+}
+
+impl<N> TagFilesystem<N>
+where
+    N: common::notify::Notifier,
+{
+    /// Splits `path` at its filedir entry, if it has one.  Nothing but a tagged directory has anything beneath it, so
+    /// this is decided from the path alone.
+    pub(super) fn filedir_entry_path(&self, path: &Path) -> Option<EntryPath> {
+        let syms = self.settings.get_config().symbols;
+        let is_filedir = |part: &OsStr| {
+            part == OsStr::new(&syms.filedir_str) || part == OsStr::new(&syms.filedir_cli_str)
+        };
+        let mut entry = PathBuf::from(std::path::MAIN_SEPARATOR.to_string());
+        let mut components = path.components().filter_map(|comp| match comp {
+            Component::Normal(part) => Some(part),
+            _ => None,
+        });
+        while let Some(part) = components.next() {
+            entry.push(part);
+            if is_filedir(part) {
+                entry.push(components.next()?);
+                return Some(EntryPath {
+                    entry,
+                    rest: components.collect(),
+                });
+            }
+        }
+        None
+    }
+
+    /// Nothing beneath a tagged directory can be modified through the mount
+    pub(super) fn reject_tagged_dir_paths(&self, paths: &[&Path]) -> FuseResult<()> {
+        let beneath = |path: &&Path| {
+            self.filedir_entry_path(path)
+                .map_or(false, |entry_path| entry_path.rest != Path::new(""))
+        };
+        if paths.iter().any(beneath) {
+            debug!(target: OP_TAG, "Refusing to modify tagged directory paths {:?}", paths);
+            Err(EPERM.into())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The real path that `entry_path` refers to, if its entry is a tagged directory
+    pub(super) fn tagged_dir_target(
+        &self,
+        conn: &Connection,
+        entry_path: &EntryPath,
+    ) -> FuseResult<Option<PathBuf>> {
+        match self.file_entry(conn, &entry_path.entry)? {
+            Some(tf) if tf.is_dir => Ok(Some(Path::new(&tf.path).join(&entry_path.rest))),
+            Some(_) if entry_path.rest != Path::new("") => Err(ENOTDIR.into()),
+            Some(_) => Ok(None),
+            None => Err(ENOENT.into()),
+        }
+    }
+
+    /// Stats something beneath a tagged directory
+    pub(super) fn getattr_beneath_tagged_dir(
+        &self,
+        conn: &Connection,
+        entry_path: &EntryPath,
+    ) -> FuseResult<stat> {
+        let real = self
+            .tagged_dir_target(conn, entry_path)?
+            .ok_or_else(|| FuseErrno::from(ENOTDIR))?;
+        let md = std::fs::symlink_metadata(&real).map_err(SupertagShimError::from)?;
+        Ok(util::new_beneath_tagged_dir(&real, &md))
+    }
+
+    /// Lists a tagged directory, or a directory beneath one, from the real directory
+    pub(super) fn readdir_tagged_dir(&self, real: &Path) -> FuseResult<Vec<FileEntry>> {
+        debug!(target: OP_TAG, "Listing real directory {:?}", real);
+        let mut entries = vec![];
+        for entry in std::fs::read_dir(real).map_err(SupertagShimError::from)? {
+            let entry = entry.map_err(SupertagShimError::from)?;
+            let mtime = entry
+                .metadata()
+                .and_then(|md| md.modified())
+                .map(Into::into)
+                .unwrap_or_else(|_| chrono::Utc::now());
+            entries.push(FileEntry {
+                name: entry.file_name().to_string_lossy().to_string(),
+                mtime,
+            });
+        }
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(entries)
+    }
+
+    /// Where a file beneath a tagged directory links to
+    pub(super) fn readlink_beneath_tagged_dir(
+        &self,
+        conn: &Connection,
+        entry_path: &EntryPath,
+    ) -> FuseResult<PathBuf> {
+        let real = self
+            .tagged_dir_target(conn, entry_path)?
+            .ok_or_else(|| FuseErrno::from(ENOTDIR))?;
+        if real.is_dir() {
+            Err(EINVAL.into())
+        } else {
+            Ok(real)
+        }
+    }
+}
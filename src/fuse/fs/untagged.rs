@@ -0,0 +1,203 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Removing a file's last tag leaves it in the collection, but nowhere in the mount.  Every such file is listed in a
+//! single top-level directory, so that it can be found and tagged again, or unlinked there to purge it from the
+//! collection.  Like the duplicates directory, it's recognized before any tag parsing happens, and nothing else under
+//! it can be modified.
+
+use super::super::err::SupertagShimError;
+use super::super::util;
+use super::TagFilesystem;
+use super::OP_TAG;
+use crate::common;
+use crate::common::types::file_perms::UMask;
+use crate::common::types::{DeviceFile, UtcDt};
+use crate::fuse::opcache::ReaddirCacheEntry;
+use crate::sql;
+use crate::sql::types::TaggedFile;
+use fuse_sys::err::FuseErrno;
+use fuse_sys::{stat, FileEntry, FuseResult, Request};
+use nix::errno::Errno::{ENOENT, ENOTDIR, EPERM};
+use rusqlite::Connection;
+use std::path::{Component, Path, PathBuf};
+use tracing::{debug, info};
+
+/// Where a path falls under the untagged directory
+pub(super) enum UntaggedPath {
+    /// The untagged directory itself
+    Root,
+    /// One of the untagged files
+    File(String),
+    /// Anything nested deeper, which never exists
+    Invalid,
+}
+
+impl<N> TagFilesystem<N>
+where
+    N: common::notify::Notifier,
+{
+    /// Determines whether `path` is under the untagged directory
+    pub(super) fn untagged_path(&self, path: &Path) -> Option<UntaggedPath> {
+        let mut parts = path.components().filter_map(|comp| match comp {
+            Component::Normal(part) => Some(part.to_string_lossy().to_string()),
+            _ => None,
+        });
+
+        if parts.next()? != self.settings.get_config().untagged.dir {
+            return None;
+        }
+        match (parts.next(), parts.next()) {
+            (None, _) => Some(UntaggedPath::Root),
+            (Some(file), None) => Some(UntaggedPath::File(file)),
+            _ => Some(UntaggedPath::Invalid),
+        }
+    }
+
+    /// Only unlinking an untagged file is allowed, by `unlink_untagged`, so any other path under the untagged directory
+    /// refuses modification
+    pub(super) fn reject_untagged_paths(&self, paths: &[&Path]) -> FuseResult<()> {
+        if paths.iter().any(|p| self.untagged_path(p).is_some()) {
+            debug!(target: OP_TAG, "Refusing to modify untagged paths {:?}", paths);
+            Err(EPERM.into())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Finds the untagged file named `filename`
+    fn untagged_file(&self, path: &Path, filename: &str) -> FuseResult<TaggedFile> {
+        if let Some(ReaddirCacheEntry::File(cached_file)) = self.op_cache.check_readdir_entry(path)
+        {
+            return Ok(cached_file);
+        }
+
+        let conn_lock = self.conn_pool.get_conn();
+        let conn = conn_lock.lock();
+        let real_conn = &(*conn).borrow_mut();
+
+        let file = self
+            .untagged_files(real_conn)?
+            .into_iter()
+            .find(|(fname, _)| fname == filename)
+            .map(|(_, file)| file)
+            .ok_or_else(|| FuseErrno::from(ENOENT))?;
+
+        self.op_cache
+            .add_readdir_entry(path, ReaddirCacheEntry::File(file.clone()));
+        Ok(file)
+    }
+
+    /// Every untagged file, named the way a filedir would name it
+    fn untagged_files(&self, conn: &Connection) -> FuseResult<Vec<(String, TaggedFile)>> {
+        let files = sql::get_untagged_files(conn).map_err(SupertagShimError::from)?;
+        Ok(self.filedir_names(files))
+    }
+
+    pub(super) fn getattr_untagged(
+        &self,
+        req: &Request,
+        path: &Path,
+        untagged_path: UntaggedPath,
+        root_mtime: &UtcDt,
+    ) -> FuseResult<stat> {
+        match untagged_path {
+            UntaggedPath::Root => {
+                let dir_perms = UMask::from(req.umask).dir_perms();
+                Ok(util::new_dir(root_mtime, req.uid, req.gid, &dir_perms, 0))
+            }
+            UntaggedPath::File(filename) => {
+                // there are no tag links left to own the file, so it belongs to whoever is looking at it
+                let mut file = self.untagged_file(path, &filename)?;
+                file.uid = req.uid;
+                file.gid = req.gid;
+                file.permissions = UMask::from(req.umask).file_perms();
+                Ok(util::new_statfile(file))
+            }
+            UntaggedPath::Invalid => Err(ENOENT.into()),
+        }
+    }
+
+    pub(super) fn readdir_untagged(
+        &self,
+        conn: &Connection,
+        path: &Path,
+        untagged_path: UntaggedPath,
+    ) -> FuseResult<Box<dyn Iterator<Item = FileEntry>>> {
+        match untagged_path {
+            UntaggedPath::Root => {
+                debug!(target: OP_TAG, "Listing untagged files");
+                let entries = self
+                    .untagged_files(conn)?
+                    .into_iter()
+                    .map(|(filename, file)| {
+                        self.op_cache.add_readdir_entry(
+                            &path.join(&filename),
+                            ReaddirCacheEntry::File(file.clone()),
+                        );
+                        FileEntry {
+                            name: filename,
+                            mtime: file.mtime,
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                Ok(Box::new(entries.into_iter()))
+            }
+            UntaggedPath::File(_) => Err(ENOTDIR.into()),
+            UntaggedPath::Invalid => Err(ENOENT.into()),
+        }
+    }
+
+    pub(super) fn readlink_untagged(
+        &self,
+        path: &Path,
+        untagged_path: UntaggedPath,
+    ) -> FuseResult<PathBuf> {
+        match untagged_path {
+            UntaggedPath::File(filename) => Ok(self.untagged_file(path, &filename)?.resolve_path()),
+            _ => Err(ENOENT.into()),
+        }
+    }
+
+    /// Unlinking an untagged file purges it from the collection
+    pub(super) fn unlink_untagged(
+        &self,
+        path: &Path,
+        untagged_path: UntaggedPath,
+    ) -> FuseResult<()> {
+        let filename = match untagged_path {
+            UntaggedPath::File(filename) => filename,
+            _ => return Err(EPERM.into()),
+        };
+        let _path_guard = self.lock_paths(&[path]);
+        let file = self.untagged_file(path, &filename)?;
+        info!(target: OP_TAG, "Purging untagged file {}", file.path);
+
+        let conn_lock = self.conn_pool.get_conn();
+        let conn = conn_lock.lock();
+        let mut real_conn = (*conn).borrow_mut();
+
+        let tx = sql::begin_write(&mut real_conn).map_err(|e| self.op_error(path, e.into()))?;
+        common::fsops::purge_untagged(&self.settings, &tx, &DeviceFile::from(file))
+            .map_err(|e| self.op_error(path, e))?;
+        tx.commit().map_err(|e| self.op_error(path, e.into()))?;
+
+        self.flush_readdir_cache(path);
+        Ok(())
+    }
+}
@@ -15,23 +15,300 @@
  * You should have received a copy of the GNU Affero General Public License
  * along with this program.  If not, see <http://www.gnu.org/licenses/>.
  */
+use super::super::err::SupertagShimError;
 use super::super::util;
 use super::TagFilesystem;
 use super::OP_TAG;
-use crate::common;
+use crate::common::constants;
+use crate::common::types::file_perms::Access;
+use crate::common::types::{TagCollectible, TagCollection, TagType};
+use crate::fuse::opcache::ReaddirCacheEntry;
+use crate::sql::types::{Tag, TagMeta, TaggedFile};
+use crate::{common, sql};
 use fuse_sys::err::FuseErrno;
 use fuse_sys::{FuseResult, Request};
-use log::info;
-use nix::errno::Errno::{ENOATTR, ENOENT};
-use std::path::Path;
+#[cfg(target_os = "macos")]
+use nix::errno::Errno::ENOATTR;
+#[cfg(target_os = "linux")]
+use nix::errno::Errno::ENODATA;
+use nix::errno::Errno::{EINVAL, ENOENT, EPERM};
+use rusqlite::Connection;
+use std::path::{Path, PathBuf};
+use tracing::{debug, info};
+
+/// The computed xattrs that we expose on every tag directory
+const TAG_DIR_XATTRS: &[&str] = &[
+    constants::XATTR_NUM_FILES,
+    constants::XATTR_TAGS,
+    constants::XATTR_QUERY,
+];
 
 impl<N> TagFilesystem<N>
 where
     N: common::notify::Notifier,
 {
+    /// Returns the tags of `path` if it refers to something that behaves like a tag directory, ie a tag, a negated
+    /// tag, a tag group, a union, an expression, a metadata match, a date, a rating, a file type, or a filedir.
+    /// Symlinks and the root directory yield None.
+    fn tag_dir_collection(&self, path: &Path) -> Option<TagCollection> {
+        let tags = TagCollection::new(&self.settings, path);
+        match tags.primary_type() {
+            Ok(TagType::Regular(_))
+            | Ok(TagType::Negation(_))
+            | Ok(TagType::Group(_))
+            | Ok(TagType::GroupAll(_))
+            | Ok(TagType::Union(_))
+            | Ok(TagType::Expression(_))
+            | Ok(TagType::Meta(_, _))
+            | Ok(TagType::Date(_))
+            | Ok(TagType::Rating(_))
+            | Ok(TagType::FileTypeGroup)
+            | Ok(TagType::FileType(_))
+            | Ok(TagType::FileDir) => Some(tags),
+            _ => None,
+        }
+    }
+
+    /// Finds the tagged file that a filedir entry refers to, if `path` is one
+    pub(super) fn file_entry(
+        &self,
+        conn: &Connection,
+        path: &Path,
+    ) -> FuseResult<Option<TaggedFile>> {
+        if let Some(ReaddirCacheEntry::File(file)) = self.op_cache.check_readdir_entry(path) {
+            return Ok(Some(file));
+        }
+
+        let tags = self.path_tags(conn, path);
+        let found = match tags.primary_type() {
+            Ok(TagType::DeviceFileSymlink(device_file)) => {
+                sql::contains_device_file(conn, tags.all_but_last().as_slice(), device_file)
+            }
+            Ok(TagType::Symlink(name)) => {
+                sql::contains_file(conn, tags.all_but_last().as_slice(), |tf| {
+                    self.settings.display_matches(name, &tf.primary_tag)
+                })
+            }
+            _ => return Ok(None),
+        };
+        Ok(found.map_err(SupertagShimError::from)?)
+    }
+
+    /// The file that a filedir entry refers to, and the tag that the entry's directory is named after, ie `music` for
+    /// `jazz/music/⋂/song.mp3`, if `path` is one
+    fn file_tag_entry(
+        &self,
+        conn: &Connection,
+        path: &Path,
+    ) -> FuseResult<Option<(TaggedFile, Tag)>> {
+        let file = match self.file_entry(conn, path)? {
+            Some(file) => file,
+            None => return Ok(None),
+        };
+        let tags = self.path_tags(conn, path);
+        let name = tags.all_but_last().rev().find_map(|tt| match tt {
+            TagType::Regular(name) => Some(name),
+            _ => None,
+        });
+        let tag = match name {
+            Some(name) => self
+                .tag_cache
+                .get_tag(conn, name)
+                .map_err(SupertagShimError::from)?,
+            None => None,
+        };
+        Ok(tag.map(|tag| (file, tag)))
+    }
+
+    /// The color, icon and description of the tag that `tags` is a directory of, if it's a regular tag
+    fn tag_dir_meta(&self, conn: &Connection, tags: &TagCollection) -> FuseResult<TagMeta> {
+        match tags.primary_type() {
+            Ok(TagType::Regular(name)) => Ok(sql::get_tag_meta(conn, &name)
+                .map_err(SupertagShimError::from)?
+                .unwrap_or_default()),
+            _ => Ok(TagMeta::default()),
+        }
+    }
+
+    /// The names of the xattrs that `meta` gives a tag directory
+    fn tag_meta_xattrs(meta: &TagMeta) -> Vec<String> {
+        let mut names = vec![];
+        if meta.color.is_some() {
+            names.push(constants::XATTR_COLOR.to_string());
+            // finder reads a directory's label from its finder info
+            #[cfg(target_os = "macos")]
+            names.push(constants::XATTR_FINDER_INFO.to_string());
+        }
+        if meta.icon.is_some() {
+            names.push(constants::XATTR_ICON.to_string());
+        }
+        if meta.description.is_some() {
+            names.push(constants::XATTR_DESCRIPTION.to_string());
+        }
+        names
+    }
+
+    /// Looks up the value of one of the xattrs from `tag edit` on a tag directory
+    fn tag_meta_xattr(meta: &TagMeta, name: &str) -> Option<Vec<u8>> {
+        let value = match name {
+            constants::XATTR_COLOR => meta.color.map(|c| c.as_str().to_string()),
+            constants::XATTR_ICON => meta.icon.clone(),
+            constants::XATTR_DESCRIPTION => meta.description.clone(),
+            #[cfg(target_os = "macos")]
+            constants::XATTR_FINDER_INFO => return meta.color.map(|c| c.finder_info()),
+            _ => None,
+        };
+        value.map(String::into_bytes)
+    }
+
+    /// Computes the value of one of our read-only tag directory xattrs
+    fn tag_dir_xattr(
+        &self,
+        conn: &Connection,
+        tags: &TagCollection,
+        name: &str,
+    ) -> FuseResult<Option<Vec<u8>>> {
+        let value = match name {
+            constants::XATTR_NUM_FILES => {
+                let num_files =
+                    sql::get_num_files(conn, tags.as_slice()).map_err(SupertagShimError::from)?;
+                num_files.to_string()
+            }
+            constants::XATTR_TAGS => tags.iter().collect_regular_names().join("\n"),
+            constants::XATTR_QUERY => tags.to_query(),
+            _ => return Ok(None),
+        };
+        debug!(target: OP_TAG, "Computed xattr {} as {:?}", name, value);
+        Ok(Some(value.into_bytes()))
+    }
+
+    /// Replaces the tags of the file at `path` with the tags listed in `value`.  Linux only allows `user.` xattrs on
+    /// regular files and directories, so there this is reached through entries that are shown as regular files, which
+    /// is every entry with `mount.write_through`, and media files with `thumbnails.passthrough`.  Symlink entries only
+    /// take it on macos.
+    fn set_file_tags(&self, req: &Request, path: &Path, value: &[u8]) -> FuseResult<()> {
+        let value = std::str::from_utf8(value).map_err(|_| FuseErrno::from(EINVAL))?;
+        let tags = common::fsops::parse_tag_list(value);
+        let _path_guard = self.lock_paths(&[path]);
+
+        let conn_lock = self.conn_pool.get_conn();
+        let conn = conn_lock.lock();
+        let mut real_conn = (*conn).borrow_mut();
+
+        let file = match self.file_entry(&real_conn, path)? {
+            Some(file) => file,
+            None => return Err(ENOENT.into()),
+        };
+
+        // retagging writes to every tag that the file leaves, as well as every tag that it joins
+        let current =
+            sql::tags_for_file(&real_conn, file.id).map_err(|e| self.op_error(path, e.into()))?;
+        let touched: PathBuf = current
+            .iter()
+            .map(String::as_str)
+            .chain(tags.iter().copied())
+            .collect();
+        self.check_tag_access(Some(&real_conn), req, &touched, Access::Write)?;
+
+        let tx = sql::begin_write(&mut real_conn).map_err(|e| self.op_error(path, e.into()))?;
+        common::fsops::retag(
+            &self.settings,
+            &tx,
+            &file,
+            &tags,
+            req.uid,
+            req.gid,
+            &req.umask.into(),
+        )
+        .map_err(|e| self.op_error(path, e.into()))?;
+        tx.commit().map_err(|e| self.op_error(path, e.into()))?;
+
+        // the file may no longer exist at `path`, and every tag it gained or lost has a new file count
+        self.op_cache.clear_readdir_entry(path);
+        self.flush_changed_tags(&real_conn);
+        Ok(())
+    }
+
+    /// Sets the file at `path`'s value for the metadata `key`, or removes it if `value` is None.  Returns whether
+    /// anything changed.
+    fn write_file_meta(&self, path: &Path, key: &str, value: Option<&[u8]>) -> FuseResult<bool> {
+        let value = match value {
+            Some(value) => Some(std::str::from_utf8(value).map_err(|_| FuseErrno::from(EINVAL))?),
+            None => None,
+        };
+        if key.is_empty() || value == Some("") {
+            return Err(EINVAL.into());
+        }
+        let _path_guard = self.lock_paths(&[path]);
+
+        let conn_lock = self.conn_pool.get_conn();
+        let conn = conn_lock.lock();
+        let mut real_conn = (*conn).borrow_mut();
+
+        let file = match self.file_entry(&real_conn, path)? {
+            Some(file) => file,
+            None => return Err(ENOENT.into()),
+        };
+
+        let tx = sql::begin_write(&mut real_conn).map_err(|e| self.op_error(path, e.into()))?;
+        let changed = match value {
+            Some(value) => {
+                sql::set_file_meta(&tx, file.id, key, value, sql::get_now_secs())
+                    .map_err(|e| self.op_error(path, e.into()))?;
+                true
+            }
+            None => sql::remove_file_meta(&tx, file.id, key)
+                .map_err(|e| self.op_error(path, e.into()))?,
+        };
+        tx.commit().map_err(|e| self.op_error(path, e.into()))?;
+
+        // listings under `meta:` directories aren't indexed by tag, so they're the ones that may now be wrong
+        self.op_cache.invalidate_tags(&[]);
+        Ok(changed)
+    }
+
+    /// Sets the value for `key` on the tagging of the file at `path` with the tag its directory is named after, or
+    /// removes it if `value` is None.  Returns whether anything changed.
+    fn write_file_tag_meta(
+        &self,
+        path: &Path,
+        key: &str,
+        value: Option<&[u8]>,
+    ) -> FuseResult<bool> {
+        let value = match value {
+            Some(value) => Some(std::str::from_utf8(value).map_err(|_| FuseErrno::from(EINVAL))?),
+            None => None,
+        };
+        if key.is_empty() || value == Some("") {
+            return Err(EINVAL.into());
+        }
+        let _path_guard = self.lock_paths(&[path]);
+
+        let conn_lock = self.conn_pool.get_conn();
+        let conn = conn_lock.lock();
+        let mut real_conn = (*conn).borrow_mut();
+
+        let (file, tag) = match self.file_tag_entry(&real_conn, path)? {
+            Some(found) => found,
+            None => return Err(ENOENT.into()),
+        };
+
+        let tx = sql::begin_write(&mut real_conn).map_err(|e| self.op_error(path, e.into()))?;
+        let changed = match value {
+            Some(value) => {
+                sql::set_file_tag_meta(&tx, file.id, tag.id, key, value, sql::get_now_secs())
+                    .map_err(|e| self.op_error(path, e.into()))?
+            }
+            None => sql::remove_file_tag_meta(&tx, file.id, tag.id, key)
+                .map_err(|e| self.op_error(path, e.into()))?,
+        };
+        tx.commit().map_err(|e| self.op_error(path, e.into()))?;
+        Ok(changed)
+    }
+
     pub fn setxattr_impl(
         &self,
-        _req: &Request,
+        req: &Request,
         path: &Path,
         name: &str,
         value: &[u8],
@@ -44,6 +321,33 @@ where
             path.display(),
             name
         );
+        self.check_tag_access(None, req, path, Access::Write)?;
+
+        if name == constants::XATTR_TAGS {
+            // a tag directory's tags are its path, so they can only be changed by moving it
+            if self.tag_dir_collection(path).is_some() {
+                return Err(EPERM.into());
+            }
+            return self.set_file_tags(req, path, value);
+        }
+
+        if let Some(key) = name.strip_prefix(constants::XATTR_META_PREFIX) {
+            // metadata belongs to files, not to intersections
+            if self.tag_dir_collection(path).is_some() {
+                return Err(EPERM.into());
+            }
+            return self.write_file_meta(path, key, Some(value)).map(|_| ());
+        }
+
+        if let Some(key) = name.strip_prefix(constants::XATTR_TAG_META_PREFIX) {
+            if self.tag_dir_collection(path).is_some() {
+                return Err(EPERM.into());
+            }
+            if self.write_file_tag_meta(path, key, Some(value))? {
+                return Ok(());
+            }
+            return Err(ENOENT.into());
+        }
 
         let conn_lock = self.conn_pool.get_conn();
         let conn = conn_lock.lock();
@@ -99,6 +403,53 @@ where
         let conn = conn_lock.lock();
         let real_conn = (*conn).borrow_mut();
 
+        if let Some(tags) = self.tag_dir_collection(path) {
+            if let Some(value) = self.tag_dir_xattr(&real_conn, &tags, name)? {
+                return Ok(value);
+            }
+            let meta = self.tag_dir_meta(&real_conn, &tags)?;
+            return match Self::tag_meta_xattr(&meta, name) {
+                Some(value) => Ok(value),
+                None => noattr_err,
+            };
+        }
+
+        if name == constants::XATTR_FRESHNESS && self.settings.get_config().remote.refresh_mtimes {
+            if let Some(tf) = self.file_entry(&real_conn, path)? {
+                return Ok(self.remote.freshness(&tf).as_str().as_bytes().to_vec());
+            }
+        }
+
+        if name == constants::XATTR_TAGS {
+            if let Some(tf) = self.file_entry(&real_conn, path)? {
+                let tags =
+                    sql::tags_for_file(&real_conn, tf.id).map_err(SupertagShimError::from)?;
+                return Ok(tags.join("\n").into_bytes());
+            }
+        }
+
+        if let Some(key) = name.strip_prefix(constants::XATTR_META_PREFIX) {
+            if let Some(tf) = self.file_entry(&real_conn, path)? {
+                let meta =
+                    sql::get_file_meta(&real_conn, tf.id).map_err(SupertagShimError::from)?;
+                return match meta.into_iter().find(|(k, _)| k == key) {
+                    Some((_, value)) => Ok(value.into_bytes()),
+                    None => noattr_err,
+                };
+            }
+        }
+
+        if let Some(key) = name.strip_prefix(constants::XATTR_TAG_META_PREFIX) {
+            if let Some((tf, tag)) = self.file_tag_entry(&real_conn, path)? {
+                let meta = sql::get_file_tag_meta(&real_conn, tf.id, tag.id)
+                    .map_err(SupertagShimError::from)?;
+                return match meta.into_iter().find(|(k, _)| k == key) {
+                    Some((_, value)) => Ok(value.into_bytes()),
+                    None => noattr_err,
+                };
+            }
+        }
+
         match self.resolve_to_alias_file(&real_conn, path)? {
             Some(file_path) => {
                 Ok(util::getxattr(&file_path, name, position).map_err(FuseErrno::from)?)
@@ -129,16 +480,44 @@ where
         let conn = conn_lock.lock();
         let real_conn = (*conn).borrow_mut();
 
-        if let Some(file_path) = self.resolve_to_alias_file(&real_conn, path)? {
-            return Ok(util::listxattr(&file_path, options).map_err(FuseErrno::from)?);
+        if let Some(tags) = self.tag_dir_collection(path) {
+            let mut names: Vec<String> = TAG_DIR_XATTRS.iter().map(|xa| xa.to_string()).collect();
+            let meta = self.tag_dir_meta(&real_conn, &tags)?;
+            names.extend(Self::tag_meta_xattrs(&meta));
+            return Ok(names);
         }
 
-        Ok(vec![])
+        let mut names = match self.resolve_to_alias_file(&real_conn, path)? {
+            Some(file_path) => util::listxattr(&file_path, options).map_err(FuseErrno::from)?,
+            None => vec![],
+        };
+
+        if let Some(tf) = self.file_entry(&real_conn, path)? {
+            names.push(constants::XATTR_TAGS.to_string());
+            if self.settings.get_config().remote.refresh_mtimes {
+                names.push(constants::XATTR_FRESHNESS.to_string());
+            }
+            let meta = sql::get_file_meta(&real_conn, tf.id).map_err(SupertagShimError::from)?;
+            names.extend(
+                meta.into_iter()
+                    .map(|(key, _)| format!("{}{}", constants::XATTR_META_PREFIX, key)),
+            );
+        }
+        if let Some((tf, tag)) = self.file_tag_entry(&real_conn, path)? {
+            let meta = sql::get_file_tag_meta(&real_conn, tf.id, tag.id)
+                .map_err(SupertagShimError::from)?;
+            names.extend(
+                meta.into_iter()
+                    .map(|(key, _)| format!("{}{}", constants::XATTR_TAG_META_PREFIX, key)),
+            );
+        }
+
+        Ok(names)
     }
 
     pub fn removexattr_impl(
         &self,
-        _req: &Request,
+        req: &Request,
         path: &Path,
         name: &str,
         options: i32,
@@ -149,6 +528,33 @@ where
             path.display(),
             name
         );
+        self.check_tag_access(None, req, path, Access::Write)?;
+
+        if let Some(key) = name.strip_prefix(constants::XATTR_META_PREFIX) {
+            if self.tag_dir_collection(path).is_some() {
+                return Err(EPERM.into());
+            }
+            if self.write_file_meta(path, key, None)? {
+                return Ok(());
+            }
+            #[cfg(target_os = "macos")]
+            return Err(ENOATTR.into());
+            #[cfg(target_os = "linux")]
+            return Err(ENODATA.into());
+        }
+
+        if let Some(key) = name.strip_prefix(constants::XATTR_TAG_META_PREFIX) {
+            if self.tag_dir_collection(path).is_some() {
+                return Err(EPERM.into());
+            }
+            if self.write_file_tag_meta(path, key, None)? {
+                return Ok(());
+            }
+            #[cfg(target_os = "macos")]
+            return Err(ENOATTR.into());
+            #[cfg(target_os = "linux")]
+            return Err(ENODATA.into());
+        }
 
         let conn_lock = self.conn_pool.get_conn();
         let conn = conn_lock.lock();
@@ -19,6 +19,14 @@
 mod err;
 mod fs;
 pub mod opcache;
+mod pathlock;
+mod remote;
+mod shutdown;
+mod tagcache;
+pub mod trace;
+mod tracker;
 pub mod util;
+mod warm;
 
 pub use fs::TagFilesystem;
+pub use shutdown::{Shutdown, ShutdownState};
@@ -22,20 +22,24 @@
 //! filesystem
 
 use crate::common::constants::ALIAS_HEADER;
+use crate::common::metrics::METRICS;
 use crate::common::settings::Settings;
 use crate::common::types::file_perms::UMask;
+use crate::common::types::health::CacheSizes;
 use crate::common::types::{TagCollection, TagType, UtcDt};
 use crate::sql;
 use fuse_sys::{gid_t, mode_t, pid_t, uid_t, Request};
-use log::{debug, info, trace, warn};
 use parking_lot::{Mutex, RwLock};
+use rusqlite::Connection;
+use std::collections::{HashMap, HashSet};
 use std::fs::{File, OpenOptions};
 use std::hash::Hash;
 use std::io::{Seek, SeekFrom, Write};
 use std::os::unix::fs::OpenOptionsExt;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
+use tracing::{debug, info, trace, warn};
 use ttl_cache::TtlCache;
 
 pub const SYMLINK_EXPIRE_MS: u64 = 500;
@@ -49,9 +53,12 @@ struct SymlinkRequest {
     path: PathBuf,
 }
 
-#[derive(Hash, Ord, PartialOrd, Eq, PartialEq, Clone)]
-struct ReaddirKey {
-    path: PathBuf,
+/// Paths are keyed by themselves, unless they're long enough to be keyed by a digest of their tags, see
+/// `TagCollection::digest`
+#[derive(Hash, Ord, PartialOrd, Eq, PartialEq, Clone, Debug)]
+enum ReaddirKey {
+    Path(PathBuf),
+    Digest([u8; 16]),
 }
 
 #[derive(Hash, Ord, PartialOrd, Eq, PartialEq, Clone)]
@@ -59,6 +66,17 @@ struct AliasKey {
     path: PathBuf,
 }
 
+/// The reverse index from tag ids to the readdir cache paths that depend on them
+#[derive(Default)]
+struct TagIndex {
+    // tag names we've seen in readdir entries, so that the tags in a path can be turned into ids without a query
+    names: HashMap<String, i64>,
+    paths: HashMap<i64, HashSet<ReaddirKey>>,
+    // paths with a tag we couldn't find an id for.  these are invalidated by every mutation, to be safe
+    unindexed: HashSet<ReaddirKey>,
+    size: usize,
+}
+
 #[derive(Clone, Debug)]
 pub enum ReaddirCacheEntry {
     File(sql::types::TaggedFile),
@@ -66,6 +84,23 @@ pub enum ReaddirCacheEntry {
     TagGroup(sql::types::TagGroup),
 }
 
+/// One page of a filedir listing, which is fetched from the database in one query and then handed out over however many
+/// readdir calls it takes the kernel to consume it
+#[derive(Debug)]
+pub struct ReaddirPage {
+    /// The position of the page's first file in the whole listing
+    pub offset: usize,
+    pub files: Vec<sql::types::TaggedFile>,
+    /// Display names that more than one file in the whole listing has, which must be disambiguated with inodify
+    pub shared_names: HashSet<String>,
+}
+
+impl ReaddirPage {
+    fn contains(&self, offset: usize) -> bool {
+        offset >= self.offset && offset < self.offset + self.files.len()
+    }
+}
+
 #[derive(Hash, Ord, PartialOrd, Eq, PartialEq, Clone)]
 struct DeleteKey {
     path: PathBuf,
@@ -237,6 +272,10 @@ impl Alias {
 pub(super) struct OpCache {
     settings: Arc<Settings>,
 
+    // paths at least this long are keyed in the readdir caches by their digest.  read once up front, since getting the
+    // config clones it
+    digest_keys_min_len: Option<usize>,
+
     // this cache stores
     symlink_cache: RwLock<TtlCache<SymlinkRequest, sql::types::TaggedFile>>,
 
@@ -250,6 +289,18 @@ pub(super) struct OpCache {
     // operations to incorrectly report as existing
     readdir_cache: RwLock<TtlCache<ReaddirKey, ReaddirCacheEntry>>,
 
+    // the page of each filedir that is currently being listed.  the kernel only asks for a few dozen entries per
+    // readdir, so without this, every one of those calls would run the intersection query again
+    readdir_page_cache: RwLock<TtlCache<ReaddirKey, Arc<ReaddirPage>>>,
+
+    // maps the tags that each readdir cache entry depends on back to its path, so that a mutation can invalidate
+    // every view of the tags it touched, in any order, union, or tag group, without having to guess their paths.
+    // always locked after `readdir_cache`, never before
+    tag_index: RwLock<TagIndex>,
+
+    // the id of the last event in the events feed that we've invalidated for
+    events_cursor: Mutex<i64>,
+
     // these buffers are so we can look up a alias by fd and by path, respectively.  the latter occurs when we do
     // a getattr.  the former occurs during creates, writes, and releases.  these two buffers are for Aliases, which
     // only exist on macos.  symlinks work on macos, but you cannot drag and drop a symlink in Finder.  only Aliases
@@ -281,21 +332,46 @@ const OPCACHE_TAG: &str = "opcache";
 const ALIAS_TAG: &str = "alias";
 const MAX_SYMLINK_ENTRIES: usize = 10_000;
 const MAX_READDIR_ENTRIES: usize = 100_000;
+const MAX_READDIR_PAGES: usize = 64;
 const MAX_CREATE_ENTRIES: usize = 10_000;
 const MAX_RM_ENTRIES: usize = 100_000;
 
 impl OpCache {
     pub fn new(settings: Arc<Settings>) -> Self {
+        let cache_conf = settings.get_config().cache;
+        let digest_keys_min_len = if cache_conf.digest_keys {
+            Some(cache_conf.digest_keys_min_len)
+        } else {
+            None
+        };
         Self {
             settings,
+            digest_keys_min_len,
             symlink_cache: RwLock::new(TtlCache::new(MAX_SYMLINK_ENTRIES)),
             readdir_cache: RwLock::new(TtlCache::new(MAX_READDIR_ENTRIES)),
+            readdir_page_cache: RwLock::new(TtlCache::new(MAX_READDIR_PAGES)),
+            tag_index: RwLock::new(TagIndex::default()),
+            events_cursor: Mutex::new(0),
             alias_cache: RwLock::new(TtlCache::new(MAX_CREATE_ENTRIES)),
             unlink_canary_cache: RwLock::new(TtlCache::new(MAX_RM_ENTRIES)),
             rename_delete_cache: RwLock::new(TtlCache::new(MAX_RM_ENTRIES)),
         }
     }
 
+    /// The readdir cache key for `path`.  `tags` are the path's tags, if the caller has already parsed them.
+    fn readdir_key(&self, path: &Path, tags: Option<&TagCollection>) -> ReaddirKey {
+        match self.digest_keys_min_len {
+            Some(min_len) if path.as_os_str().len() >= min_len => {
+                let digest = match tags {
+                    Some(tags) => tags.digest(&self.settings),
+                    None => TagCollection::new(self.settings.as_ref(), path).digest(&self.settings),
+                };
+                ReaddirKey::Digest(digest)
+            }
+            _ => ReaddirKey::Path(path.to_owned()),
+        }
+    }
+
     /// Takes a path and turns ensures it has a filedir in it.
     /// This function, and it's sister function below, are necessary because we don't know what kind of path will get
     /// put into the alias cache...if the user drags a file onto a filedir or a tagdir. But we need to be able to clear
@@ -432,6 +508,7 @@ impl OpCache {
                     target: OPCACHE_TAG,
                     "Cache hit! Found {:?} in the alias cache", path
                 );
+                METRICS.cache_lookup("alias", true);
                 Some(alias_rc.clone())
             }
             None => {
@@ -439,6 +516,7 @@ impl OpCache {
                     target: OPCACHE_TAG,
                     "Cache miss! Didn't find {:?} in the alias cache", path
                 );
+                METRICS.cache_lookup("alias", false);
                 None
             }
         }
@@ -461,7 +539,12 @@ impl OpCache {
     }
 
     pub fn add_readdir_entry(&self, path: &Path, entry: ReaddirCacheEntry) {
-        let ttl = Duration::from_secs(READDIR_EXPIRE_S);
+        self.add_readdir_entry_ttl(path, entry, Duration::from_secs(READDIR_EXPIRE_S))
+    }
+
+    /// Like `add_readdir_entry`, but for entries that should outlive the usual short ttl, like the ones produced by
+    /// the cache warm-up
+    pub fn add_readdir_entry_ttl(&self, path: &Path, entry: ReaddirCacheEntry, ttl: Duration) {
         info!(
             target: OPCACHE_TAG,
             "Adding entry to the readdir cache {:?} at {} with ttl {:?}",
@@ -470,26 +553,155 @@ impl OpCache {
             ttl
         );
 
-        let mut guard = self.readdir_cache.write();
+        // the path's tags are needed for both the key and the index, so they're only parsed once
+        let tags = TagCollection::new(self.settings.as_ref(), path);
+        let key = self.readdir_key(path, Some(&tags));
 
-        let key = ReaddirKey {
-            path: path.to_owned(),
-        };
+        let mut guard = self.readdir_cache.write();
+        self.index_entry(&*guard, &key, &tags, &entry);
         (*guard).insert(key, entry, ttl);
     }
 
+    /// Records which tags the entry at `key` depends on: the tags in its path, and the tag or tag group it is
+    fn index_entry(
+        &self,
+        cache: &TtlCache<ReaddirKey, ReaddirCacheEntry>,
+        key: &ReaddirKey,
+        tags: &TagCollection,
+        entry: &ReaddirCacheEntry,
+    ) {
+        let mut index = self.tag_index.write();
+
+        let mut ids = vec![];
+        match entry {
+            ReaddirCacheEntry::Tag(tag) => {
+                if tags.last() == Some(&TagType::Regular(tag.name.clone())) {
+                    index.names.insert(tag.name.clone(), tag.id);
+                }
+                ids.push(tag.id);
+            }
+            ReaddirCacheEntry::TagGroup(tg) => ids.extend(&tg.tag_ids),
+            ReaddirCacheEntry::File(_) => {}
+        }
+
+        let mut names: Vec<&str> = vec![];
+        for tag in tags.iter() {
+            match tag {
+                TagType::Regular(name) | TagType::Negation(name) => names.push(name),
+                TagType::Union(members) => names.extend(members.iter().map(String::as_str)),
+                TagType::Expression(expr) => names.extend(expr.tag_names()),
+                // metadata, mtime, rating and name changes aren't tag changes, so there's no tag id that could
+                // invalidate this entry
+                TagType::Meta(_, _)
+                | TagType::Date(_)
+                | TagType::Rating(_)
+                | TagType::FileTypeGroup
+                | TagType::FileType(_) => {
+                    index.unindexed.insert(key.clone());
+                }
+                _ => {}
+            }
+        }
+        for name in names {
+            match index.names.get(name) {
+                Some(id) => ids.push(*id),
+                None => {
+                    trace!(
+                        target: OPCACHE_TAG,
+                        "No id for tag {} in {:?}, leaving it unindexed",
+                        name,
+                        key
+                    );
+                    index.unindexed.insert(key.clone());
+                }
+            }
+        }
+
+        for id in ids {
+            if index.paths.entry(id).or_default().insert(key.clone()) {
+                index.size += 1;
+            }
+        }
+
+        // expired cache entries are never invalidated, so every so often we drop their paths from the index
+        if index.size > MAX_READDIR_ENTRIES * 2 {
+            let TagIndex {
+                paths,
+                unindexed,
+                size,
+                ..
+            } = &mut *index;
+            *size = 0;
+            for keys in paths.values_mut() {
+                keys.retain(|k| cache.contains_key(k));
+                *size += keys.len();
+            }
+            paths.retain(|_, keys| !keys.is_empty());
+            unindexed.retain(|k| cache.contains_key(k));
+            debug!(
+                target: OPCACHE_TAG,
+                "Pruned the readdir tag index down to {} paths", size
+            );
+        }
+    }
+
+    /// Clears every readdir cache entry that depends on any of `tag_ids`, each exactly once.  Returns how many were
+    /// cleared.
+    pub fn invalidate_tags(&self, tag_ids: &[i64]) -> usize {
+        let mut guard = self.readdir_cache.write();
+        let mut index = self.tag_index.write();
+
+        let mut to_clear: HashSet<ReaddirKey> = index.unindexed.drain().collect();
+        for id in tag_ids {
+            if let Some(keys) = index.paths.remove(id) {
+                index.size -= keys.len();
+                to_clear.extend(keys);
+            }
+        }
+        // the tag may have been renamed or deleted, so its name can't be trusted anymore
+        index.names.retain(|_, id| !tag_ids.contains(id));
+
+        let mut cleared = 0;
+        for key in to_clear {
+            if (*guard).remove(&key).is_some() {
+                cleared += 1;
+            }
+        }
+        info!(
+            target: OPCACHE_TAG,
+            "Invalidated {} readdir cache entries for tag ids {:?}", cleared, tag_ids
+        );
+        cleared
+    }
+
+    /// Starts following the events feed from `cursor`, which should be its newest event when we mount
+    pub fn set_events_cursor(&self, cursor: i64) {
+        *self.events_cursor.lock() = cursor;
+    }
+
+    /// Invalidates the readdir cache for every tag touched by the events since we last looked, which covers changes
+    /// from the CLI as well as our own.  Call this after committing a mutation.
+    pub fn invalidate_from_events(&self, conn: &Connection) -> rusqlite::Result<usize> {
+        let mut cursor = self.events_cursor.lock();
+        let (tag_ids, next) = sql::tags_touched_since(conn, *cursor)?;
+        *cursor = next;
+        if tag_ids.is_empty() {
+            return Ok(0);
+        }
+        Ok(self.invalidate_tags(&tag_ids))
+    }
+
     pub fn check_readdir_entry(&self, path: &Path) -> Option<ReaddirCacheEntry> {
         info!(target: OPCACHE_TAG, "Checking readdir cache for {:?}", path);
         let guard = self.readdir_cache.read();
-        let key = ReaddirKey {
-            path: path.to_owned(),
-        };
+        let key = self.readdir_key(path, None);
         match (*guard).get(&key) {
             Some(value) => {
                 debug!(
                     target: OPCACHE_TAG,
                     "Cache hit! Found {:?} in the readdir cache", path
                 );
+                METRICS.cache_lookup("readdir", true);
                 Some((*value).clone())
             }
             None => {
@@ -497,6 +709,7 @@ impl OpCache {
                     target: OPCACHE_TAG,
                     "Cache miss. Didn't find {:?} in the readdir cache", path
                 );
+                METRICS.cache_lookup("readdir", false);
                 None
             }
         }
@@ -507,9 +720,7 @@ impl OpCache {
             target: OPCACHE_TAG,
             "Clearing {:?} from readdir cache", path
         );
-        let key = ReaddirKey {
-            path: path.to_owned(),
-        };
+        let key = self.readdir_key(path, None);
         let mut guard = self.readdir_cache.write();
         let maybe_entry = (*guard).remove(&key);
         if maybe_entry.is_some() {
@@ -520,6 +731,73 @@ impl OpCache {
         maybe_entry
     }
 
+    /// Remembers `page` as the page of the listing of `path` that's being handed out, replacing any previous page
+    pub fn add_readdir_page(&self, path: &Path, page: ReaddirPage) -> Arc<ReaddirPage> {
+        debug!(
+            target: OPCACHE_TAG,
+            "Adding page of {} files at offset {} of {}",
+            page.files.len(),
+            page.offset,
+            path.display()
+        );
+        let page = Arc::new(page);
+        let key = self.readdir_key(path, None);
+        self.readdir_page_cache.write().insert(
+            key,
+            page.clone(),
+            Duration::from_secs(READDIR_EXPIRE_S),
+        );
+        page
+    }
+
+    /// The cached page of the listing of `path` that holds the file at `offset`, if there is one
+    pub fn check_readdir_page(&self, path: &Path, offset: usize) -> Option<Arc<ReaddirPage>> {
+        let key = self.readdir_key(path, None);
+        match self.readdir_page_cache.read().get(&key) {
+            Some(page) if page.contains(offset) => Some(page.clone()),
+            _ => None,
+        }
+    }
+
+    /// The top-level tags that have entries beneath them in the readdir cache, which is roughly the set of tags that
+    /// have recently been browsed
+    pub fn cached_tags(&self) -> Vec<String> {
+        let mut guard = self.readdir_cache.write();
+        let mut tags: Vec<String> = vec![];
+        for (key, _) in (*guard).iter() {
+            // digested paths can't be read back, but they're long intersections whose top-level tag was browsed, and
+            // cached, on the way to them
+            let path = match key {
+                ReaddirKey::Path(path) => path,
+                ReaddirKey::Digest(_) => continue,
+            };
+            let mut names = path.components().filter_map(|comp| match comp {
+                Component::Normal(name) => Some(name),
+                _ => None,
+            });
+            if let (Some(tag), Some(_)) = (names.next(), names.next()) {
+                let tag = tag.to_string_lossy().into_owned();
+                if !tags.contains(&tag) {
+                    tags.push(tag);
+                }
+            }
+        }
+        tags
+    }
+
+    /// How many live entries each cache holds, for the daemon's health report.  Iterating a cache skips its expired
+    /// entries, which needs each cache's write lock in turn.
+    pub fn sizes(&self) -> CacheSizes {
+        CacheSizes {
+            readdir: self.readdir_cache.write().iter().count(),
+            readdir_pages: self.readdir_page_cache.write().iter().count(),
+            symlinks: self.symlink_cache.write().iter().count(),
+            aliases: self.alias_cache.write().iter().count(),
+            unlink_canaries: self.unlink_canary_cache.write().iter().count(),
+            rename_deletes: self.rename_delete_cache.write().iter().count(),
+        }
+    }
+
     pub fn add_symlink(&self, req: &Request, path: &Path, tagged_file: sql::types::TaggedFile) {
         info!(
             target: OPCACHE_TAG,
@@ -0,0 +1,145 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! A striped lock manager for serializing FUSE mutations that touch the same logical entry.  File managers will often
+//! perform multi-step sequences (create, write, rename, etc) on a single path, and with `fuse_loop_mt` those steps
+//! can be dispatched on different threads and interleave.  Rather than a single global lock, which would serialize
+//! all operations, we hash each path into one of a fixed number of stripes, so unrelated paths remain parallel.
+
+use parking_lot::{Mutex, MutexGuard};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+use std::time::Instant;
+use tracing::trace;
+
+const LOCK_TAG: &str = "pathlock";
+pub const DEFAULT_STRIPES: usize = 64;
+
+pub struct PathLocks {
+    stripes: Vec<Mutex<()>>,
+}
+
+/// Holds the stripes for one or more paths.  They are released when this is dropped.
+pub struct PathGuard<'a> {
+    _guards: Vec<MutexGuard<'a, ()>>,
+}
+
+impl PathLocks {
+    pub fn new(num_stripes: usize) -> Self {
+        let num_stripes = std::cmp::max(num_stripes, 1);
+        Self {
+            stripes: (0..num_stripes).map(|_| Mutex::new(())).collect(),
+        }
+    }
+
+    fn stripe_idx(&self, path: &Path) -> usize {
+        let mut hasher = DefaultHasher::new();
+        path.hash(&mut hasher);
+        (hasher.finish() % self.stripes.len() as u64) as usize
+    }
+
+    /// Locks the stripe for a single path
+    pub fn lock(&self, path: &Path) -> PathGuard {
+        self.lock_many(&[path])
+    }
+
+    /// Locks the stripes for all `paths`.  Stripes are always acquired in ascending order, and each stripe only
+    /// once, so that two operations locking overlapping sets of paths (like a rename of a => b and b => a) can't
+    /// deadlock.
+    pub fn lock_many(&self, paths: &[&Path]) -> PathGuard {
+        let mut idxs: Vec<usize> = paths.iter().map(|p| self.stripe_idx(p)).collect();
+        idxs.sort_unstable();
+        idxs.dedup();
+
+        trace!(target: LOCK_TAG, "Locking stripes {:?} for {:?}", idxs, paths);
+        PathGuard {
+            _guards: idxs
+                .into_iter()
+                .map(|idx| self.stripes[idx].lock())
+                .collect(),
+        }
+    }
+
+    /// Locks every stripe, which waits for all in-progress mutations to finish and holds off any new ones.  Gives up
+    /// and returns `None` if that can't be done by `deadline`.
+    pub fn lock_all_until(&self, deadline: Instant) -> Option<PathGuard> {
+        trace!(target: LOCK_TAG, "Locking all {} stripes", self.stripes.len());
+        let mut guards = Vec::with_capacity(self.stripes.len());
+        for stripe in self.stripes.iter() {
+            guards.push(stripe.try_lock_until(deadline)?);
+        }
+        Some(PathGuard { _guards: guards })
+    }
+}
+
+impl Default for PathLocks {
+    fn default() -> Self {
+        Self::new(DEFAULT_STRIPES)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_overlapping_locks_dont_deadlock() {
+        let locks = Arc::new(PathLocks::new(4));
+        let mut handles = vec![];
+        for i in 0..8 {
+            let locks = locks.clone();
+            handles.push(std::thread::spawn(move || {
+                for _ in 0..1000 {
+                    let (a, b) = if i % 2 == 0 {
+                        ("/a", "/b")
+                    } else {
+                        ("/b", "/a")
+                    };
+                    let _guard = locks.lock_many(&[Path::new(a), Path::new(b)]);
+                }
+            }));
+        }
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_lock_all_waits_for_held_stripes() {
+        let locks = PathLocks::new(4);
+        let soon = || Instant::now() + std::time::Duration::from_millis(50);
+
+        let held = locks.lock(Path::new("/a"));
+        assert!(locks.lock_all_until(soon()).is_none());
+        drop(held);
+
+        let all = locks.lock_all_until(soon());
+        assert!(all.is_some());
+    }
+
+    #[test]
+    fn test_same_path_same_stripe() {
+        let locks = PathLocks::new(DEFAULT_STRIPES);
+        assert_eq!(
+            locks.stripe_idx(Path::new("/t1/foo")),
+            locks.stripe_idx(Path::new("/t1/foo"))
+        );
+    }
+}
@@ -0,0 +1,325 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Keeps filedir listings responsive when tagged files live on slow devices, like network shares.  We time every stat
+//! of a real file and keep a running average per device.  Files on fast devices have their mtimes refreshed inline
+//! while listing, while files on slow devices are listed with the last mtime we know of, and are queued to be
+//! refreshed in batches by a background thread.  What we learn from each successful stat is also written back to the
+//! database, so that a device that goes offline is still listed with its files' last known mtimes.
+
+use crate::common::settings::Settings;
+use crate::common::types::UtcDt;
+use crate::sql;
+use crate::sql::tpool::ThreadConnPool;
+use crate::sql::types::TaggedFile;
+use parking_lot::{Mutex, RwLock};
+use rusqlite::Connection;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
+
+const REMOTE_TAG: &str = "remote";
+
+/// How much a single new stat timing moves a device's running average, as 1/N
+const LATENCY_SMOOTHING: u32 = 8;
+
+/// How trustworthy the mtime of a listed file is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Freshness {
+    /// We've stat'd the real file recently
+    Cached,
+    /// We're using an old value, either from the database or from a stat that has expired, and a refresh is pending
+    Stale,
+}
+
+impl Freshness {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Freshness::Cached => "cached",
+            Freshness::Stale => "stale",
+        }
+    }
+}
+
+struct Refreshed {
+    mtime: UtcDt,
+    at: Instant,
+}
+
+type FileKey = (u64, u64);
+
+pub struct RemoteFiles {
+    settings: Arc<Settings>,
+    /// Running average of stat times, keyed by device
+    latencies: RwLock<HashMap<u64, Duration>>,
+    refreshed: RwLock<HashMap<FileKey, Refreshed>>,
+    pending: Mutex<HashMap<FileKey, PathBuf>>,
+    /// Sizes and mtimes from successful stats that haven't been written to the database yet
+    observed: Mutex<HashMap<FileKey, (u64, UtcDt)>>,
+}
+
+impl RemoteFiles {
+    pub fn new(settings: Arc<Settings>) -> Self {
+        Self {
+            settings,
+            latencies: RwLock::new(HashMap::new()),
+            refreshed: RwLock::new(HashMap::new()),
+            pending: Mutex::new(HashMap::new()),
+            observed: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn slow_threshold(&self) -> Duration {
+        Duration::from_millis(self.settings.get_config().remote.slow_stat_ms)
+    }
+
+    fn stale_after(&self) -> Duration {
+        Duration::from_secs(self.settings.get_config().remote.stale_after_s)
+    }
+
+    /// Whether stats on `device` have been slow enough that we shouldn't do them while listing.  A device we've never
+    /// stat'd is assumed to be fast, so that the first listing measures it.
+    pub fn is_slow(&self, device: u64) -> bool {
+        self.latencies
+            .read()
+            .get(&device)
+            .map_or(false, |avg| *avg > self.slow_threshold())
+    }
+
+    fn record(&self, device: u64, elapsed: Duration) {
+        let threshold = self.slow_threshold();
+        let mut latencies = self.latencies.write();
+        let (was_slow, avg) = match latencies.get(&device) {
+            Some(old) => (
+                *old > threshold,
+                (*old * (LATENCY_SMOOTHING - 1) + elapsed) / LATENCY_SMOOTHING,
+            ),
+            None => (false, elapsed),
+        };
+        latencies.insert(device, avg);
+
+        let is_slow = avg > threshold;
+        if is_slow != was_slow {
+            info!(
+                target: REMOTE_TAG,
+                "Device {} is now {}, averaging {:?} per stat",
+                device,
+                if is_slow { "slow" } else { "fast" },
+                avg
+            );
+        }
+    }
+
+    /// Stats the real file, recording how long it took against its device
+    fn stat_mtime(&self, key: FileKey, path: &Path) -> Option<UtcDt> {
+        let start = Instant::now();
+        let md = std::fs::metadata(path);
+        self.record(key.0, start.elapsed());
+
+        match md.and_then(|md| Ok((md.len(), md.modified()?))) {
+            Ok((size, modified)) => {
+                let mtime = UtcDt::from(modified);
+                self.observed.lock().insert(key, (size, mtime));
+                self.refreshed.write().insert(
+                    key,
+                    Refreshed {
+                        mtime,
+                        at: Instant::now(),
+                    },
+                );
+                Some(mtime)
+            }
+            Err(e) => {
+                warn!(target: REMOTE_TAG, "Couldn't stat {:?}: {:?}", path, e);
+                None
+            }
+        }
+    }
+
+    fn recent(&self, key: &FileKey) -> Option<UtcDt> {
+        let stale_after = self.stale_after();
+        self.refreshed
+            .read()
+            .get(key)
+            .filter(|r| r.at.elapsed() < stale_after)
+            .map(|r| r.mtime)
+    }
+
+    /// The mtime that a listing should show for `tf`.  This only stats the real file if its device is fast, otherwise
+    /// it falls back to the last mtime we know of and queues the file for a background refresh.
+    pub fn mtime_for(&self, tf: &TaggedFile) -> (UtcDt, Freshness) {
+        let key = (tf.device, tf.inode);
+        if let Some(mtime) = self.recent(&key) {
+            return (mtime, Freshness::Cached);
+        }
+
+        if !self.is_slow(tf.device) {
+            if let Some(mtime) = self.stat_mtime(key, &tf.resolve_path()) {
+                return (mtime, Freshness::Cached);
+            }
+        } else {
+            self.pending.lock().insert(key, tf.resolve_path());
+        }
+
+        // an expired refresh is still closer to the truth than the database, and the target's mtime from the database is
+        // closer than the symlink's own
+        let last_known = self.refreshed.read().get(&key).map(|r| r.mtime);
+        (
+            last_known.unwrap_or_else(|| tf.target_mtime.unwrap_or(tf.mtime)),
+            Freshness::Stale,
+        )
+    }
+
+    pub fn freshness(&self, tf: &TaggedFile) -> Freshness {
+        match self.recent(&(tf.device, tf.inode)) {
+            Some(_) => Freshness::Cached,
+            None => Freshness::Stale,
+        }
+    }
+
+    /// Stats up to one batch of the files queued by `mtime_for`, returning how many were refreshed
+    pub fn refresh_pending(&self) -> usize {
+        let batch_size = self.settings.get_config().remote.refresh_batch.max(1);
+        let batch = {
+            let mut pending = self.pending.lock();
+            let keys = pending.keys().take(batch_size).cloned().collect::<Vec<_>>();
+            keys.into_iter()
+                .filter_map(|key| pending.remove(&key).map(|path| (key, path)))
+                .collect::<Vec<_>>()
+        };
+
+        for (key, path) in batch.iter() {
+            self.stat_mtime(*key, path);
+        }
+        batch.len()
+    }
+
+    /// Writes the sizes and mtimes of everything we've stat'd since the last call to the database, returning how many
+    /// files were updated
+    pub fn persist_observed(&self, conn: &mut Connection) -> rusqlite::Result<usize> {
+        let observed = std::mem::take(&mut *self.observed.lock());
+        if observed.is_empty() {
+            return Ok(0);
+        }
+
+        let tx = sql::begin_write(conn)?;
+        let mut updated = 0;
+        for ((device, inode), (size, mtime)) in observed.iter() {
+            updated += sql::set_target_metadata(&tx, *device, *inode, *size, mtime)?;
+        }
+        tx.commit()?;
+        Ok(updated)
+    }
+
+    /// Runs `refresh_pending` periodically in a background thread, persisting what it learns, until `threads_done` is
+    /// set
+    pub fn spawn_refresher(
+        remote: Arc<Self>,
+        conn_pool: Arc<ThreadConnPool>,
+        threads_done: Arc<AtomicBool>,
+    ) {
+        let interval =
+            Duration::from_millis(remote.settings.get_config().remote.refresh_interval_ms);
+        let spawned = std::thread::Builder::new()
+            .name("remote_refresh".to_string())
+            .spawn(move || {
+                while !threads_done.load(Ordering::Relaxed) {
+                    std::thread::sleep(interval);
+                    let refreshed = remote.refresh_pending();
+                    if refreshed > 0 {
+                        debug!(target: REMOTE_TAG, "Refreshed {} slow files", refreshed);
+                    }
+
+                    let conn_lock = conn_pool.get_conn();
+                    let conn = conn_lock.lock();
+                    let mut real_conn = (*conn).borrow_mut();
+                    if let Err(e) = remote.persist_observed(&mut real_conn) {
+                        warn!(target: REMOTE_TAG, "Couldn't persist target metadata: {:?}", e);
+                    }
+                }
+                debug!(target: REMOTE_TAG, "Filesystem is going away, stopping");
+            });
+        if let Err(e) = spawned {
+            error!(target: REMOTE_TAG, "Couldn't start background refresher: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency_classification() {
+        let mut settings = Settings::default();
+        let mut source = crate::common::settings::config::HashMapSource(Default::default());
+        source
+            .0
+            .insert("remote.slow_stat_ms".to_string(), 10.into());
+        settings.update_config(source);
+        let remote = RemoteFiles::new(Arc::new(settings));
+
+        assert!(!remote.is_slow(1));
+
+        remote.record(1, Duration::from_millis(1));
+        remote.record(2, Duration::from_millis(100));
+        assert!(!remote.is_slow(1));
+        assert!(remote.is_slow(2));
+
+        // a single slow stat doesn't reclassify a fast device
+        remote.record(1, Duration::from_millis(50));
+        assert!(!remote.is_slow(1));
+    }
+
+    #[test]
+    fn test_stale_falls_back_to_target_mtime() {
+        let mut settings = Settings::default();
+        let mut source = crate::common::settings::config::HashMapSource(Default::default());
+        source
+            .0
+            .insert("remote.slow_stat_ms".to_string(), 10.into());
+        settings.update_config(source);
+        let remote = RemoteFiles::new(Arc::new(settings));
+        remote.record(1, Duration::from_millis(100));
+
+        let symlink_mtime = chrono::Utc::now();
+        let target_mtime = symlink_mtime - chrono::Duration::days(1);
+        let mut tf = TaggedFile {
+            id: 1,
+            inode: 1,
+            device: 1,
+            path: "/nowhere/file.txt".to_string(),
+            primary_tag: "a".to_string(),
+            mtime: symlink_mtime,
+            uid: 0,
+            gid: 0,
+            permissions: crate::common::types::file_perms::Permissions::from(0o755),
+            alias_file: None,
+            target_size: None,
+            target_mtime: None,
+            is_dir: false,
+        };
+
+        assert_eq!(remote.mtime_for(&tf), (symlink_mtime, Freshness::Stale));
+        tf.target_mtime = Some(target_mtime);
+        assert_eq!(remote.mtime_for(&tf), (target_mtime, Freshness::Stale));
+    }
+}
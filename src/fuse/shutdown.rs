@@ -0,0 +1,262 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Tears down a mounted collection in a fixed order, instead of relying on the order things happen to be dropped in.
+//! In-progress mutations are allowed to finish, then the state worth keeping is saved, then the filesystem is
+//! unmounted.  The whole sequence is bounded by a deadline, past which we skip straight to unmounting.
+
+use super::opcache::OpCache;
+use super::pathlock::PathLocks;
+use super::warm;
+use crate::common::notify::Notifier;
+use crate::common::settings::Settings;
+use crate::sql::tpool::ThreadConnPool;
+use fuse_sys::MountHandle;
+use parking_lot::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+const SHUTDOWN_TAG: &str = "shutdown";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownState {
+    Running,
+    /// Waiting for in-progress mutations to finish, while holding off new ones
+    Draining,
+    /// Saving the warm cache snapshot, closing database connections, and shutting down the notifier
+    Flushing,
+    Unmounting,
+    Done,
+}
+
+pub struct Shutdown<N: Notifier + 'static> {
+    state: Mutex<ShutdownState>,
+    settings: Arc<Settings>,
+    conn_pool: Arc<ThreadConnPool>,
+    op_cache: Arc<OpCache>,
+    path_locks: Arc<PathLocks>,
+    notifier: Arc<Mutex<N>>,
+    threads_done: Arc<AtomicBool>,
+}
+
+impl<N: Notifier + 'static> Shutdown<N> {
+    pub(super) fn new(
+        settings: Arc<Settings>,
+        conn_pool: Arc<ThreadConnPool>,
+        op_cache: Arc<OpCache>,
+        path_locks: Arc<PathLocks>,
+        notifier: Arc<Mutex<N>>,
+        threads_done: Arc<AtomicBool>,
+    ) -> Self {
+        Self {
+            state: Mutex::new(ShutdownState::Running),
+            settings,
+            conn_pool,
+            op_cache,
+            path_locks,
+            notifier,
+            threads_done,
+        }
+    }
+
+    pub fn state(&self) -> ShutdownState {
+        *self.state.lock()
+    }
+
+    fn advance(&self, next: ShutdownState) {
+        let mut state = self.state.lock();
+        info!(target: SHUTDOWN_TAG, "{:?} -> {:?}", *state, next);
+        *state = next;
+    }
+
+    /// Runs the shutdown sequence and unmounts `mount_handle`.  Only the first call does anything, so it's safe to
+    /// call from more than one place.
+    pub fn run(&self, mount_handle: &Mutex<MountHandle>) {
+        self.run_with_unmount(|| mount_handle.lock().unmount());
+    }
+
+    /// The shutdown sequence, with `unmount` doing the unmounting
+    fn run_with_unmount<F: FnOnce()>(&self, unmount: F) {
+        if self.state() != ShutdownState::Running {
+            return;
+        }
+        let deadline =
+            Instant::now() + Duration::from_millis(self.settings.get_config().shutdown.deadline_ms);
+
+        self.advance(ShutdownState::Draining);
+        // our background threads check this between units of work, so they'll stop on their own
+        self.threads_done.store(true, Ordering::Relaxed);
+        let drained = self.path_locks.lock_all_until(deadline);
+        if drained.is_none() {
+            warn!(
+                target: SHUTDOWN_TAG,
+                "Operations still running at the deadline, unmounting without waiting on them"
+            );
+        }
+
+        self.advance(ShutdownState::Flushing);
+        self.save_warm_snapshot();
+        // a connection in the middle of a transaction can't be closed, so only close them if everything drained
+        if drained.is_some() {
+            let closed = self.conn_pool.close_all();
+            info!(target: SHUTDOWN_TAG, "Closed {} db connections", closed);
+        }
+        if let Err(e) = self.notifier.lock().shutdown() {
+            error!(target: SHUTDOWN_TAG, "Couldn't shut down notifier: {:?}", e);
+        }
+
+        // fuse_loop can't finish while a request is blocked on one of our stripes, so they have to be released
+        // before unmounting
+        drop(drained);
+        self.advance(ShutdownState::Unmounting);
+        unmount();
+
+        self.advance(ShutdownState::Done);
+    }
+
+    fn save_warm_snapshot(&self) {
+        let path = self
+            .settings
+            .warm_snapshot_file(&self.settings.get_collection());
+        let tags = self.op_cache.cached_tags();
+        if let Err(e) = warm::save_snapshot(&path, &tags) {
+            error!(
+                target: SHUTDOWN_TAG,
+                "Couldn't save warm cache snapshot to {:?}: {:?}", path, e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::notify::desktop::DesktopNotifier;
+    use crate::common::types::file_perms::UMask;
+    use crate::common::types::UtcDt;
+    use crate::fuse::opcache::ReaddirCacheEntry;
+    use crate::common::testing;
+    use crate::sql::types::Tag;
+    use std::path::Path;
+
+    struct Parts {
+        settings: Arc<Settings>,
+        conn_pool: Arc<ThreadConnPool>,
+        op_cache: Arc<OpCache>,
+        path_locks: Arc<PathLocks>,
+        threads_done: Arc<AtomicBool>,
+        shutdown: Arc<Shutdown<DesktopNotifier>>,
+    }
+
+    fn parts(dir: &Path, deadline_ms: i64) -> Parts {
+        let settings = testing::settings(dir, &[("shutdown.deadline_ms", deadline_ms)]);
+        let conn_pool = Arc::new(ThreadConnPool::new(settings.db_file(testing::COLLECTION)));
+        let op_cache = Arc::new(OpCache::new(settings.clone()));
+        let path_locks = Arc::new(PathLocks::default());
+        let threads_done = Arc::new(AtomicBool::new(false));
+        let notifier = Arc::new(Mutex::new(DesktopNotifier::from_settings(&settings)));
+        let shutdown = Arc::new(Shutdown::new(
+            settings.clone(),
+            conn_pool.clone(),
+            op_cache.clone(),
+            path_locks.clone(),
+            notifier,
+            threads_done.clone(),
+        ));
+        Parts {
+            settings,
+            conn_pool,
+            op_cache,
+            path_locks,
+            threads_done,
+            shutdown,
+        }
+    }
+
+    #[test]
+    fn test_run_drained() {
+        let dir = tempfile::tempdir().unwrap();
+        let parts = parts(dir.path(), 1000);
+        drop(parts.conn_pool.get_conn());
+
+        // having browsed beneath t1 is what the snapshot remembers
+        let tag = Tag {
+            id: 1,
+            name: "t2".to_string(),
+            mtime: UtcDt::from(std::time::SystemTime::now()),
+            uid: 0,
+            gid: 0,
+            permissions: UMask::default().dir_perms(),
+            num_files: 1,
+        };
+        parts
+            .op_cache
+            .add_readdir_entry(Path::new("/t1/t2"), ReaddirCacheEntry::Tag(tag));
+
+        let mut unmounted = false;
+        parts.shutdown.run_with_unmount(|| unmounted = true);
+        assert!(unmounted);
+        assert_eq!(parts.shutdown.state(), ShutdownState::Done);
+        assert!(parts.threads_done.load(Ordering::Relaxed));
+
+        // everything drained, so the pool's connections were closed
+        assert_eq!(parts.conn_pool.close_all(), 0);
+        let snapshot_file = parts.settings.warm_snapshot_file(testing::COLLECTION);
+        assert_eq!(std::fs::read_to_string(snapshot_file).unwrap(), "t1\n");
+
+        // only the first run does anything
+        parts
+            .shutdown
+            .run_with_unmount(|| panic!("Unmounted a second time"));
+    }
+
+    #[test]
+    fn test_run_past_deadline() {
+        let dir = tempfile::tempdir().unwrap();
+        let parts = parts(dir.path(), 300);
+        drop(parts.conn_pool.get_conn());
+
+        let stuck = parts.path_locks.lock(Path::new("/t1"));
+        let started = Instant::now();
+        let shutdown = parts.shutdown.clone();
+        let running = std::thread::spawn(move || {
+            let mut unmounted_in = None;
+            shutdown.run_with_unmount(|| unmounted_in = Some(shutdown.state()));
+            unmounted_in
+        });
+
+        while parts.shutdown.state() == ShutdownState::Running {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(parts.shutdown.state(), ShutdownState::Draining);
+        // it's waiting on the stripe we hold
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(parts.shutdown.state(), ShutdownState::Draining);
+
+        // and unmounts anyway once the deadline passes
+        assert_eq!(running.join().unwrap(), Some(ShutdownState::Unmounting));
+        assert!(started.elapsed() >= Duration::from_millis(300));
+        assert_eq!(parts.shutdown.state(), ShutdownState::Done);
+
+        // a connection could have been in the middle of a transaction, so they were left alone
+        assert_eq!(parts.conn_pool.close_all(), 1);
+        drop(stuck);
+    }
+}
@@ -0,0 +1,196 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! A shared, in-memory copy of the rows of the tags table that have been looked up, so that hot paths like `getattr`
+//! on a tag directory don't go to sqlite for a tag they've seen before.  Lookups that found nothing are kept too,
+//! because file managers probe for plenty of names that aren't tags.
+//!
+//! The cache is only trusted for the write transaction it was filled under.  Each lookup reads the collection's newest
+//! write transaction id, which is one row, and empties the cache if it has moved on, so writes from the CLI, the socket
+//! and sync invalidate it as well as the daemon's own.  The daemon's own writes also bump the cache's generation, and a
+//! lookup that raced with either, and so may have read the tags from before it, isn't kept.  The form that tag names
+//! are kept in is read again whenever the cache is emptied, instead of for every lookup.
+
+use crate::common::metrics::METRICS;
+use crate::common::settings::config::TagForm;
+use crate::sql;
+use crate::sql::types::Tag;
+use parking_lot::RwLock;
+use rusqlite::Connection;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::trace;
+
+const TAG: &str = "tagcache";
+
+/// Past this, the cache is emptied instead of growing, since it's mostly names that aren't tags by then
+const MAX_ENTRIES: usize = 100_000;
+
+#[derive(Default)]
+struct Entries {
+    /// The write transaction that the tags were read under, or `None` if the cache hasn't been filled since a bump
+    tx_id: Option<i64>,
+    /// The form that tag names were kept in as of `tx_id`
+    form: Option<TagForm>,
+    tags: HashMap<String, Option<Tag>>,
+}
+
+#[derive(Default)]
+pub struct TagCache {
+    generation: AtomicU64,
+    entries: RwLock<Entries>,
+}
+
+impl TagCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// Forgets every tag, because a write may have changed any of them
+    pub fn bump(&self) {
+        let mut entries = self.entries.write();
+        let generation = self.generation.fetch_add(1, Ordering::AcqRel) + 1;
+        trace!(target: TAG, "Emptying {} tags for generation {}", entries.tags.len(), generation);
+        *entries = Entries::default();
+    }
+
+    /// The tag named `name`, from the cache if it's there and nothing has been written since, and otherwise from `conn`
+    pub fn get_tag(&self, conn: &Connection, name: &str) -> rusqlite::Result<Option<Tag>> {
+        let generation = self.generation();
+        let tx_id = sql::write_tx_id(conn)?;
+        let form = {
+            let entries = self.entries.read();
+            if entries.tx_id == Some(tx_id) {
+                if let Some(tag) = entries.tags.get(name) {
+                    trace!(target: TAG, "Hit for {}", name);
+                    METRICS.cache_lookup("tag", true);
+                    return Ok(tag.clone());
+                }
+                entries.form
+            } else {
+                None
+            }
+        };
+        METRICS.cache_lookup("tag", false);
+
+        let form = match form {
+            Some(form) => form,
+            None => sql::tag_form(conn)?,
+        };
+        let tag = sql::get_tag_in_form(conn, form, name)?;
+        let mut entries = self.entries.write();
+        // a bump since we started means our read may be from before the write that caused it
+        if self.generation() != generation {
+            return Ok(tag);
+        }
+        if entries.tx_id != Some(tx_id) {
+            trace!(target: TAG, "Emptying {} tags for write transaction {}", entries.tags.len(), tx_id);
+            *entries = Entries {
+                tx_id: Some(tx_id),
+                form: Some(form),
+                tags: HashMap::new(),
+            };
+        }
+        if entries.tags.len() >= MAX_ENTRIES {
+            entries.tags.clear();
+        }
+        entries.tags.insert(name.to_string(), tag.clone());
+        Ok(tag)
+    }
+
+    pub fn size(&self) -> usize {
+        self.entries.read().tags.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::{params, NO_PARAMS};
+
+    fn add_tag(conn: &mut Connection, name: &str) -> rusqlite::Result<()> {
+        let tx = sql::begin_write(conn)?;
+        tx.execute(
+            "INSERT INTO tags (tag_name, ts, mtime, uid, gid, permissions) VALUES (?1, 0, 0, 0, 0, 493)",
+            params![name],
+        )?;
+        tx.commit()
+    }
+
+    #[test]
+    fn test_bump_forgets_tags() -> rusqlite::Result<()> {
+        let mut conn = Connection::open_in_memory()?;
+        sql::migrations::migrate(&mut conn, &crate::common::version_str())?;
+        let cache = TagCache::new();
+
+        add_tag(&mut conn, "t1")?;
+        assert_eq!(
+            cache.get_tag(&conn, "t1")?.map(|t| t.name),
+            Some("t1".to_string())
+        );
+        assert!(cache.get_tag(&conn, "t2")?.is_none());
+        assert_eq!(cache.size(), 2);
+
+        // without a bump or a write transaction, the cache doesn't see changes
+        conn.execute("DELETE FROM tags WHERE tag_name='t1'", NO_PARAMS)?;
+        conn.execute(
+            "INSERT INTO tags (tag_name, ts, mtime, uid, gid, permissions) VALUES ('t2', 0, 0, 0, 0, 493)",
+            NO_PARAMS,
+        )?;
+        assert!(cache.get_tag(&conn, "t1")?.is_some());
+        assert!(cache.get_tag(&conn, "t2")?.is_none());
+
+        cache.bump();
+        assert_eq!(cache.generation(), 1);
+        assert_eq!(cache.size(), 0);
+        assert!(cache.get_tag(&conn, "t1")?.is_none());
+        assert!(cache.get_tag(&conn, "t2")?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_other_connections_writes() -> rusqlite::Result<()> {
+        let dir = tempfile::tempdir().unwrap();
+        let db_file = dir.path().join("db.sqlite3");
+        let conn = sql::get_conn(&db_file)?;
+        let mut other = sql::get_conn(&db_file)?;
+        sql::migrations::migrate(&mut other, &crate::common::version_str())?;
+        let cache = TagCache::new();
+
+        add_tag(&mut other, "t1")?;
+        assert!(cache.get_tag(&conn, "t1")?.is_some());
+        assert!(cache.get_tag(&conn, "t2")?.is_none());
+        assert!(cache.get_tag(&conn, "t1")?.is_some());
+        assert_eq!(cache.size(), 2);
+
+        // a write from elsewhere, like the cli, moves the transaction id on without a bump
+        let tx = sql::begin_write(&mut other)?;
+        tx.execute("DELETE FROM tags WHERE tag_name='t1'", NO_PARAMS)?;
+        tx.commit()?;
+        add_tag(&mut other, "t2")?;
+        assert!(cache.get_tag(&conn, "t1")?.is_none());
+        assert!(cache.get_tag(&conn, "t2")?.is_some());
+        assert_eq!(cache.size(), 2);
+        assert_eq!(cache.generation(), 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,1014 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+//! Capturing the exact sequence of fuse callbacks that a filesystem gets, so that it can be replayed later.  A file
+//! manager does its own dance of stats, listings, creates and renames for what the user sees as one drag and drop,
+//! and a captured dance reproduces a bug in it deterministically, without the file manager.
+//!
+//! A trace is a file of `TraceEntry`s as JSON, one per line, in the order that the callbacks finished.
+
+use fuse_sys::err::FuseErrno;
+use fuse_sys::{
+    fuse_buf_size, fuse_bufvec, fuse_file_info, gid_t, mode_t, off_t, stat, statvfs, uid_t,
+};
+use fuse_sys::{FileEntry, Filesystem, FuseHandle, FuseResult, Request};
+use libc::c_int;
+use nix::errno::Errno::EBADF;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader, LineWriter, Write};
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+const TRACE_TAG: &str = "trace";
+
+/// A fuse callback, with the arguments needed to make it again.  Open file handles are the ones the filesystem gave
+/// out when the trace was captured, and replaying maps them to the ones it gives out then.  Writes only record their
+/// size, so that traces don't carry the contents of files, and replaying them writes zeros.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum TraceOp {
+    Getattr {
+        path: PathBuf,
+    },
+    Readdir {
+        path: PathBuf,
+        offset: u64,
+    },
+    Readlink {
+        path: PathBuf,
+    },
+    Symlink {
+        src: PathBuf,
+        dst: PathBuf,
+    },
+    Create {
+        path: PathBuf,
+        mode: mode_t,
+        fh: Option<u64>,
+    },
+    Open {
+        path: PathBuf,
+        flags: i32,
+        fh: Option<u64>,
+    },
+    Read {
+        path: PathBuf,
+        fh: u64,
+        offset: off_t,
+        size: usize,
+    },
+    Write {
+        path: PathBuf,
+        fh: u64,
+        offset: off_t,
+        size: usize,
+    },
+    Flush {
+        path: PathBuf,
+        fh: u64,
+    },
+    Truncate {
+        path: PathBuf,
+        offset: off_t,
+    },
+    Fallocate {
+        path: PathBuf,
+        fh: u64,
+        mode: i32,
+        offset: off_t,
+        len: off_t,
+    },
+    Fsync {
+        path: PathBuf,
+        fh: u64,
+        datasync: i32,
+    },
+    Release {
+        path: PathBuf,
+        fh: u64,
+    },
+    Rmdir {
+        path: PathBuf,
+    },
+    Unlink {
+        path: PathBuf,
+    },
+    Mkdir {
+        path: PathBuf,
+        mode: mode_t,
+    },
+    Rename {
+        src: PathBuf,
+        dst: PathBuf,
+    },
+    Exchange {
+        a: PathBuf,
+        b: PathBuf,
+    },
+    Statfs {
+        path: PathBuf,
+    },
+    Chmod {
+        path: PathBuf,
+        mode: mode_t,
+    },
+    Chown {
+        path: PathBuf,
+        uid: uid_t,
+        gid: gid_t,
+    },
+    Setxattr {
+        path: PathBuf,
+        name: String,
+        value: Vec<u8>,
+        position: u32,
+        flags: i32,
+    },
+    Getxattr {
+        path: PathBuf,
+        name: String,
+        position: u32,
+    },
+    Listxattr {
+        path: PathBuf,
+        options: i32,
+    },
+    Removexattr {
+        path: PathBuf,
+        name: String,
+        options: i32,
+    },
+    Setvolname {
+        name: String,
+    },
+}
+
+/// A callback that was made, who made it, and how it went
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TraceEntry {
+    pub pid: i32,
+    pub op: TraceOp,
+    /// The errno the callback failed with, or None if it succeeded
+    pub errno: Option<i32>,
+}
+
+fn errno_of<T>(res: &FuseResult<T>) -> Option<i32> {
+    res.as_ref().err().map(|e: &FuseErrno| e.errno as i32)
+}
+
+fn fh_of(fi: *const fuse_file_info) -> u64 {
+    unsafe { (*fi).fh }
+}
+
+/// Wraps a filesystem, recording every callback it gets into a trace file.  `readdir_common`, `statfs_x` and
+/// `setattr_x` aren't recorded, since replaying their neighbors covers them.
+pub struct TracingFilesystem<F> {
+    inner: F,
+    out: Mutex<LineWriter<File>>,
+}
+
+impl<F: Filesystem> TracingFilesystem<F> {
+    pub fn new(inner: F, trace_file: &Path) -> std::io::Result<Self> {
+        info!(
+            target: TRACE_TAG,
+            "Tracing fuse callbacks to {}",
+            trace_file.display()
+        );
+        let out = LineWriter::new(File::create(trace_file)?);
+        Ok(Self {
+            inner,
+            out: Mutex::new(out),
+        })
+    }
+
+    fn record<T>(&self, req: &Request, op: TraceOp, res: &FuseResult<T>) {
+        let entry = TraceEntry {
+            pid: req.pid,
+            op,
+            errno: errno_of(res),
+        };
+        let mut blob = match serde_json::to_vec(&entry) {
+            Ok(blob) => blob,
+            Err(e) => {
+                warn!(target: TRACE_TAG, "Couldn't serialize {:?}: {}", entry, e);
+                return;
+            }
+        };
+        blob.push(b'\n');
+        if let Err(e) = self.out.lock().write_all(&blob) {
+            warn!(target: TRACE_TAG, "Couldn't record {:?}: {}", entry, e);
+        }
+    }
+}
+
+impl<F: Filesystem> Filesystem for TracingFilesystem<F> {
+    fn init_request_id(&self) -> usize {
+        self.inner.init_request_id()
+    }
+
+    fn op_done(&self, op: &'static str, elapsed: Duration, result: c_int) {
+        self.inner.op_done(op, elapsed, result)
+    }
+
+    fn getattr(&self, req: &Request, path: &Path) -> FuseResult<stat> {
+        let res = self.inner.getattr(req, path);
+        let op = TraceOp::Getattr {
+            path: path.to_owned(),
+        };
+        self.record(req, op, &res);
+        res
+    }
+
+    fn readdir(
+        &self,
+        req: &Request,
+        path: &Path,
+        offset: u64,
+    ) -> FuseResult<Box<dyn Iterator<Item = FileEntry>>> {
+        let res = self.inner.readdir(req, path, offset);
+        let op = TraceOp::Readdir {
+            path: path.to_owned(),
+            offset,
+        };
+        self.record(req, op, &res);
+        res
+    }
+
+    fn readdir_common(
+        &self,
+        req: &Request,
+        path: &Path,
+    ) -> FuseResult<Box<dyn Iterator<Item = FileEntry>>> {
+        self.inner.readdir_common(req, path)
+    }
+
+    fn readlink(&self, req: &Request, path: &Path) -> FuseResult<PathBuf> {
+        let res = self.inner.readlink(req, path);
+        let op = TraceOp::Readlink {
+            path: path.to_owned(),
+        };
+        self.record(req, op, &res);
+        res
+    }
+
+    fn symlink(&self, req: &Request, src: &Path, dst: &Path) -> FuseResult<()> {
+        let res = self.inner.symlink(req, src, dst);
+        let op = TraceOp::Symlink {
+            src: src.to_owned(),
+            dst: dst.to_owned(),
+        };
+        self.record(req, op, &res);
+        res
+    }
+
+    fn create(&self, req: &Request, path: &Path, mode: mode_t) -> FuseResult<RawFd> {
+        let res = self.inner.create(req, path, mode);
+        let op = TraceOp::Create {
+            path: path.to_owned(),
+            mode,
+            fh: res.as_ref().ok().map(|fd| *fd as u64),
+        };
+        self.record(req, op, &res);
+        res
+    }
+
+    fn open(&self, req: &Request, path: &Path, fi: *const fuse_file_info) -> FuseResult<RawFd> {
+        let res = self.inner.open(req, path, fi);
+        let op = TraceOp::Open {
+            path: path.to_owned(),
+            flags: unsafe { (*fi).flags },
+            fh: res.as_ref().ok().map(|fd| *fd as u64),
+        };
+        self.record(req, op, &res);
+        res
+    }
+
+    fn read(
+        &self,
+        req: &Request,
+        path: &Path,
+        buf: &mut [u8],
+        offset: off_t,
+        fi: *const fuse_file_info,
+    ) -> FuseResult<usize> {
+        let size = buf.len();
+        let res = self.inner.read(req, path, buf, offset, fi);
+        let op = TraceOp::Read {
+            path: path.to_owned(),
+            fh: fh_of(fi),
+            offset,
+            size,
+        };
+        self.record(req, op, &res);
+        res
+    }
+
+    /// Only recorded when it serves the read, because otherwise fuse falls back to `read`, which is recorded itself
+    fn read_buf(
+        &self,
+        req: &Request,
+        path: &Path,
+        size: usize,
+        offset: off_t,
+        fi: *const fuse_file_info,
+    ) -> FuseResult<Option<RawFd>> {
+        let res = self.inner.read_buf(req, path, size, offset, fi);
+        if let Ok(Some(_)) = res {
+            let op = TraceOp::Read {
+                path: path.to_owned(),
+                fh: fh_of(fi),
+                offset,
+                size,
+            };
+            self.record(req, op, &res);
+        }
+        res
+    }
+
+    fn write(
+        &self,
+        req: &Request,
+        path: &Path,
+        data: &[u8],
+        offset: off_t,
+        fi: *const fuse_file_info,
+    ) -> FuseResult<usize> {
+        let res = self.inner.write(req, path, data, offset, fi);
+        let op = TraceOp::Write {
+            path: path.to_owned(),
+            fh: fh_of(fi),
+            offset,
+            size: data.len(),
+        };
+        self.record(req, op, &res);
+        res
+    }
+
+    fn write_buf(
+        &self,
+        req: &Request,
+        path: &Path,
+        buf: *mut fuse_bufvec,
+        offset: off_t,
+        fi: *const fuse_file_info,
+    ) -> FuseResult<usize> {
+        let size = unsafe { fuse_buf_size(buf) };
+        let res = self.inner.write_buf(req, path, buf, offset, fi);
+        let op = TraceOp::Write {
+            path: path.to_owned(),
+            fh: fh_of(fi),
+            offset,
+            size,
+        };
+        self.record(req, op, &res);
+        res
+    }
+
+    fn flush(&self, req: &Request, path: &Path, fi: *const fuse_file_info) -> FuseResult<()> {
+        let res = self.inner.flush(req, path, fi);
+        let op = TraceOp::Flush {
+            path: path.to_owned(),
+            fh: fh_of(fi),
+        };
+        self.record(req, op, &res);
+        res
+    }
+
+    fn truncate(&self, req: &Request, path: &Path, offset: off_t) -> FuseResult<()> {
+        let res = self.inner.truncate(req, path, offset);
+        let op = TraceOp::Truncate {
+            path: path.to_owned(),
+            offset,
+        };
+        self.record(req, op, &res);
+        res
+    }
+
+    fn fallocate(
+        &self,
+        req: &Request,
+        path: &Path,
+        mode: i32,
+        offset: off_t,
+        len: off_t,
+        fi: *const fuse_file_info,
+    ) -> FuseResult<()> {
+        let res = self.inner.fallocate(req, path, mode, offset, len, fi);
+        let op = TraceOp::Fallocate {
+            path: path.to_owned(),
+            fh: fh_of(fi),
+            mode,
+            offset,
+            len,
+        };
+        self.record(req, op, &res);
+        res
+    }
+
+    fn fsync(
+        &self,
+        req: &Request,
+        path: &Path,
+        datasync: i32,
+        fi: *const fuse_file_info,
+    ) -> FuseResult<()> {
+        let res = self.inner.fsync(req, path, datasync, fi);
+        let op = TraceOp::Fsync {
+            path: path.to_owned(),
+            fh: fh_of(fi),
+            datasync,
+        };
+        self.record(req, op, &res);
+        res
+    }
+
+    fn release(&self, req: &Request, path: &Path, fi: *const fuse_file_info) -> FuseResult<()> {
+        let fh = fh_of(fi);
+        let res = self.inner.release(req, path, fi);
+        let op = TraceOp::Release {
+            path: path.to_owned(),
+            fh,
+        };
+        self.record(req, op, &res);
+        res
+    }
+
+    fn rmdir(&self, req: &Request, path: &Path) -> FuseResult<()> {
+        let res = self.inner.rmdir(req, path);
+        let op = TraceOp::Rmdir {
+            path: path.to_owned(),
+        };
+        self.record(req, op, &res);
+        res
+    }
+
+    fn unlink(&self, req: &Request, path: &Path) -> FuseResult<()> {
+        let res = self.inner.unlink(req, path);
+        let op = TraceOp::Unlink {
+            path: path.to_owned(),
+        };
+        self.record(req, op, &res);
+        res
+    }
+
+    fn mkdir(&self, req: &Request, path: &Path, mode: mode_t) -> FuseResult<()> {
+        let res = self.inner.mkdir(req, path, mode);
+        let op = TraceOp::Mkdir {
+            path: path.to_owned(),
+            mode,
+        };
+        self.record(req, op, &res);
+        res
+    }
+
+    fn rename(&self, req: &Request, src: &Path, dst: &Path) -> FuseResult<()> {
+        let res = self.inner.rename(req, src, dst);
+        let op = TraceOp::Rename {
+            src: src.to_owned(),
+            dst: dst.to_owned(),
+        };
+        self.record(req, op, &res);
+        res
+    }
+
+    fn exchange(&self, req: &Request, a: &Path, b: &Path) -> FuseResult<()> {
+        let res = self.inner.exchange(req, a, b);
+        let op = TraceOp::Exchange {
+            a: a.to_owned(),
+            b: b.to_owned(),
+        };
+        self.record(req, op, &res);
+        res
+    }
+
+    fn statfs(&self, req: &Request, path: &Path) -> FuseResult<statvfs> {
+        let res = self.inner.statfs(req, path);
+        let op = TraceOp::Statfs {
+            path: path.to_owned(),
+        };
+        self.record(req, op, &res);
+        res
+    }
+
+    #[cfg(target_os = "macos")]
+    fn statfs_x(&self, req: &Request, path: &Path, stbuf: &mut fuse_sys::statfs) -> FuseResult<()> {
+        self.inner.statfs_x(req, path, stbuf)
+    }
+
+    #[cfg(target_os = "macos")]
+    fn setvolname(&self, req: &Request, name: &str) -> FuseResult<()> {
+        let res = self.inner.setvolname(req, name);
+        let op = TraceOp::Setvolname {
+            name: name.to_owned(),
+        };
+        self.record(req, op, &res);
+        res
+    }
+
+    fn set_handle(&mut self, handle: Arc<FuseHandle>) {
+        self.inner.set_handle(handle)
+    }
+
+    fn chmod(&self, req: &Request, path: &Path, mode: mode_t) -> FuseResult<()> {
+        let res = self.inner.chmod(req, path, mode);
+        let op = TraceOp::Chmod {
+            path: path.to_owned(),
+            mode,
+        };
+        self.record(req, op, &res);
+        res
+    }
+
+    fn chown(&self, req: &Request, path: &Path, uid: uid_t, gid: gid_t) -> FuseResult<()> {
+        let res = self.inner.chown(req, path, uid, gid);
+        let op = TraceOp::Chown {
+            path: path.to_owned(),
+            uid,
+            gid,
+        };
+        self.record(req, op, &res);
+        res
+    }
+
+    #[cfg(target_os = "macos")]
+    fn setattr_x(
+        &self,
+        req: &Request,
+        path: &Path,
+        attrs: *const fuse_sys::setattr_x,
+    ) -> FuseResult<()> {
+        self.inner.setattr_x(req, path, attrs)
+    }
+
+    fn setxattr(
+        &self,
+        req: &Request,
+        path: &Path,
+        name: &str,
+        value: &[u8],
+        position: u32,
+        flags: i32,
+    ) -> FuseResult<()> {
+        let res = self.inner.setxattr(req, path, name, value, position, flags);
+        let op = TraceOp::Setxattr {
+            path: path.to_owned(),
+            name: name.to_owned(),
+            value: value.to_vec(),
+            position,
+            flags,
+        };
+        self.record(req, op, &res);
+        res
+    }
+
+    fn getxattr(
+        &self,
+        req: &Request,
+        path: &Path,
+        name: &str,
+        position: u32,
+    ) -> FuseResult<Vec<u8>> {
+        let res = self.inner.getxattr(req, path, name, position);
+        let op = TraceOp::Getxattr {
+            path: path.to_owned(),
+            name: name.to_owned(),
+            position,
+        };
+        self.record(req, op, &res);
+        res
+    }
+
+    fn listxattr(&self, req: &Request, path: &Path, options: i32) -> FuseResult<Vec<String>> {
+        let res = self.inner.listxattr(req, path, options);
+        let op = TraceOp::Listxattr {
+            path: path.to_owned(),
+            options,
+        };
+        self.record(req, op, &res);
+        res
+    }
+
+    fn removexattr(&self, req: &Request, path: &Path, name: &str, options: i32) -> FuseResult<()> {
+        let res = self.inner.removexattr(req, path, name, options);
+        let op = TraceOp::Removexattr {
+            path: path.to_owned(),
+            name: name.to_owned(),
+            options,
+        };
+        self.record(req, op, &res);
+        res
+    }
+}
+
+/// Reads the entries of a trace file
+pub fn read_trace(trace_file: &Path) -> Result<Vec<TraceEntry>, Box<dyn Error>> {
+    let reader = BufReader::new(File::open(trace_file)?);
+    let mut entries = vec![];
+    for (idx, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry = serde_json::from_str(&line)
+            .map_err(|e| format!("Bad trace entry on line {}: {}", idx + 1, e))?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// A replayed callback whose result wasn't the one in the trace
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    /// Which entry of the trace, counting from 1
+    pub index: usize,
+    pub op: TraceOp,
+    pub expected: Option<i32>,
+    pub got: Option<i32>,
+}
+
+fn file_info(flags: i32, fh: u64) -> fuse_file_info {
+    let mut fi: fuse_file_info = unsafe { std::mem::zeroed() };
+    fi.flags = flags;
+    fi.fh = fh;
+    fi
+}
+
+/// Calls `f` with a file info for the handle that `fs` gave out in place of `fh`.  A handle that `fs` never gave out
+/// fails with EBADF, instead of being used, because it could be any of our own descriptors.
+fn with_handle<F>(handles: &HashMap<u64, u64>, fh: u64, f: F) -> Option<i32>
+where
+    F: FnOnce(&fuse_file_info) -> Option<i32>,
+{
+    match handles.get(&fh) {
+        Some(ours) => f(&file_info(0, *ours)),
+        None => Some(EBADF as i32),
+    }
+}
+
+/// Makes one callback of a trace, returning the errno it failed with.  `handles` maps the file handles of the
+/// trace to the ones `fs` gave out.
+fn replay_op<F: Filesystem + ?Sized>(
+    fs: &F,
+    req: &Request,
+    op: &TraceOp,
+    handles: &mut HashMap<u64, u64>,
+) -> Option<i32> {
+    match op {
+        TraceOp::Getattr { path } => errno_of(&fs.getattr(req, path)),
+        TraceOp::Readdir { path, offset } => {
+            // a listing is only done once it's been iterated
+            errno_of(
+                &fs.readdir(req, path, *offset)
+                    .map(|entries| entries.count()),
+            )
+        }
+        TraceOp::Readlink { path } => errno_of(&fs.readlink(req, path)),
+        TraceOp::Symlink { src, dst } => errno_of(&fs.symlink(req, src, dst)),
+        TraceOp::Create { path, mode, fh } => {
+            let res = fs.create(req, path, *mode);
+            if let (Some(theirs), Ok(ours)) = (fh, &res) {
+                handles.insert(*theirs, *ours as u64);
+            }
+            errno_of(&res)
+        }
+        TraceOp::Open { path, flags, fh } => {
+            let res = fs.open(req, path, &file_info(*flags, 0));
+            if let (Some(theirs), Ok(ours)) = (fh, &res) {
+                handles.insert(*theirs, *ours as u64);
+            }
+            errno_of(&res)
+        }
+        TraceOp::Read {
+            path,
+            fh,
+            offset,
+            size,
+        } => with_handle(handles, *fh, |fi| {
+            let mut buf = vec![0u8; *size];
+            errno_of(&fs.read(req, path, &mut buf, *offset, fi))
+        }),
+        TraceOp::Write {
+            path,
+            fh,
+            offset,
+            size,
+        } => with_handle(handles, *fh, |fi| {
+            let data = vec![0u8; *size];
+            errno_of(&fs.write(req, path, &data, *offset, fi))
+        }),
+        TraceOp::Flush { path, fh } => {
+            with_handle(handles, *fh, |fi| errno_of(&fs.flush(req, path, fi)))
+        }
+        TraceOp::Truncate { path, offset } => errno_of(&fs.truncate(req, path, *offset)),
+        TraceOp::Fallocate {
+            path,
+            fh,
+            mode,
+            offset,
+            len,
+        } => with_handle(handles, *fh, |fi| {
+            errno_of(&fs.fallocate(req, path, *mode, *offset, *len, fi))
+        }),
+        TraceOp::Fsync { path, fh, datasync } => with_handle(handles, *fh, |fi| {
+            errno_of(&fs.fsync(req, path, *datasync, fi))
+        }),
+        TraceOp::Release { path, fh } => {
+            let errno = with_handle(handles, *fh, |fi| errno_of(&fs.release(req, path, fi)));
+            handles.remove(fh);
+            errno
+        }
+        TraceOp::Rmdir { path } => errno_of(&fs.rmdir(req, path)),
+        TraceOp::Unlink { path } => errno_of(&fs.unlink(req, path)),
+        TraceOp::Mkdir { path, mode } => errno_of(&fs.mkdir(req, path, *mode)),
+        TraceOp::Rename { src, dst } => errno_of(&fs.rename(req, src, dst)),
+        TraceOp::Exchange { a, b } => errno_of(&fs.exchange(req, a, b)),
+        TraceOp::Statfs { path } => errno_of(&fs.statfs(req, path)),
+        TraceOp::Chmod { path, mode } => errno_of(&fs.chmod(req, path, *mode)),
+        TraceOp::Chown { path, uid, gid } => errno_of(&fs.chown(req, path, *uid, *gid)),
+        TraceOp::Setxattr {
+            path,
+            name,
+            value,
+            position,
+            flags,
+        } => errno_of(&fs.setxattr(req, path, name, value, *position, *flags)),
+        TraceOp::Getxattr {
+            path,
+            name,
+            position,
+        } => errno_of(&fs.getxattr(req, path, name, *position)),
+        TraceOp::Listxattr { path, options } => errno_of(&fs.listxattr(req, path, *options)),
+        TraceOp::Removexattr {
+            path,
+            name,
+            options,
+        } => errno_of(&fs.removexattr(req, path, name, *options)),
+        #[cfg(target_os = "macos")]
+        TraceOp::Setvolname { name } => errno_of(&fs.setvolname(req, name)),
+        #[cfg(not(target_os = "macos"))]
+        TraceOp::Setvolname { .. } => Some(nix::errno::Errno::ENOSYS as i32),
+    }
+}
+
+/// Makes the callbacks of a trace against `fs`, one at a time and in order, as the user running us.  Returns the
+/// callbacks whose results differed from the trace, which is empty if the trace was reproduced.
+pub fn replay<F: Filesystem + ?Sized>(fs: &F, entries: &[TraceEntry]) -> Vec<Divergence> {
+    let uid = nix::unistd::getuid().as_raw();
+    let gid = nix::unistd::getgid().as_raw();
+    let mut handles = HashMap::new();
+    let mut divergences = vec![];
+
+    for (idx, entry) in entries.iter().enumerate() {
+        let req = Request {
+            uid,
+            gid,
+            pid: entry.pid,
+            umask: 0o022,
+        };
+        debug!(target: TRACE_TAG, "Replaying {:?}", entry.op);
+        let got = replay_op(fs, &req, &entry.op, &mut handles);
+        if got != entry.errno {
+            warn!(
+                target: TRACE_TAG,
+                "{:?} diverged: expected {:?}, got {:?}", entry.op, entry.errno, got
+            );
+            divergences.push(Divergence {
+                index: idx + 1,
+                op: entry.op.clone(),
+                expected: entry.errno,
+                got,
+            });
+        }
+    }
+    divergences
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::errno::Errno::{ENOENT, ENOSYS};
+
+    const OUR_FH: RawFd = 100;
+
+    /// Has a single file, `/file`, whose handles are always `OUR_FH`
+    struct OneFileFs;
+
+    impl Filesystem for OneFileFs {
+        fn init_request_id(&self) -> usize {
+            0
+        }
+
+        fn getattr(&self, _req: &Request, path: &Path) -> FuseResult<stat> {
+            if path == Path::new("/file") {
+                Ok(unsafe { std::mem::zeroed() })
+            } else {
+                Err(ENOENT.into())
+            }
+        }
+
+        fn readdir(
+            &self,
+            _req: &Request,
+            _path: &Path,
+            _offset: u64,
+        ) -> FuseResult<Box<dyn Iterator<Item = FileEntry>>> {
+            Ok(Box::new(std::iter::empty()))
+        }
+
+        fn readlink(&self, _req: &Request, _path: &Path) -> FuseResult<PathBuf> {
+            Err(ENOENT.into())
+        }
+
+        fn symlink(&self, _req: &Request, _src: &Path, _dst: &Path) -> FuseResult<()> {
+            Err(ENOSYS.into())
+        }
+
+        fn create(&self, _req: &Request, _path: &Path, _mode: mode_t) -> FuseResult<RawFd> {
+            Err(ENOSYS.into())
+        }
+
+        fn open(
+            &self,
+            req: &Request,
+            path: &Path,
+            _fi: *const fuse_file_info,
+        ) -> FuseResult<RawFd> {
+            self.getattr(req, path).map(|_| OUR_FH)
+        }
+
+        fn read(
+            &self,
+            _req: &Request,
+            _path: &Path,
+            buf: &mut [u8],
+            _offset: off_t,
+            fi: *const fuse_file_info,
+        ) -> FuseResult<usize> {
+            if fh_of(fi) == OUR_FH as u64 {
+                Ok(buf.len())
+            } else {
+                Err(EBADF.into())
+            }
+        }
+
+        fn release(
+            &self,
+            _req: &Request,
+            _path: &Path,
+            _fi: *const fuse_file_info,
+        ) -> FuseResult<()> {
+            Ok(())
+        }
+
+        fn rmdir(&self, _req: &Request, _path: &Path) -> FuseResult<()> {
+            Err(ENOSYS.into())
+        }
+
+        fn unlink(&self, _req: &Request, _path: &Path) -> FuseResult<()> {
+            Err(ENOSYS.into())
+        }
+
+        fn mkdir(&self, _req: &Request, _path: &Path, _mode: mode_t) -> FuseResult<()> {
+            Err(ENOSYS.into())
+        }
+
+        fn rename(&self, _req: &Request, _src: &Path, _dst: &Path) -> FuseResult<()> {
+            Err(ENOSYS.into())
+        }
+
+        fn statfs(&self, _req: &Request, _path: &Path) -> FuseResult<statvfs> {
+            Err(ENOSYS.into())
+        }
+    }
+
+    fn entry(op: TraceOp, errno: Option<i32>) -> TraceEntry {
+        TraceEntry { pid: 1, op, errno }
+    }
+
+    #[test]
+    fn test_capture_replay() -> Result<(), Box<dyn Error>> {
+        let trace_file = tempfile::NamedTempFile::new()?;
+        let fs = TracingFilesystem::new(OneFileFs, trace_file.path())?;
+        let req = Request {
+            uid: 0,
+            gid: 0,
+            pid: 1,
+            umask: 0o022,
+        };
+
+        let file = Path::new("/file");
+        assert!(fs.getattr(&req, Path::new("/missing")).is_err());
+        let fh = fs.open(&req, file, &file_info(libc::O_RDONLY, 0))?;
+        let fi = file_info(0, fh as u64);
+        fs.read(&req, file, &mut [0u8; 16], 0, &fi)?;
+        fs.release(&req, file, &fi)?;
+
+        let entries = read_trace(trace_file.path())?;
+        assert_eq!(
+            entries,
+            vec![
+                entry(
+                    TraceOp::Getattr {
+                        path: "/missing".into()
+                    },
+                    Some(ENOENT as i32)
+                ),
+                entry(
+                    TraceOp::Open {
+                        path: file.into(),
+                        flags: libc::O_RDONLY,
+                        fh: Some(OUR_FH as u64)
+                    },
+                    None
+                ),
+                entry(
+                    TraceOp::Read {
+                        path: file.into(),
+                        fh: OUR_FH as u64,
+                        offset: 0,
+                        size: 16
+                    },
+                    None
+                ),
+                entry(
+                    TraceOp::Release {
+                        path: file.into(),
+                        fh: OUR_FH as u64
+                    },
+                    None
+                ),
+            ]
+        );
+        assert!(replay(&OneFileFs, &entries).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_replay_divergence() {
+        let file = PathBuf::from("/file");
+        let entries = vec![
+            // the trace's handle differs from ours, and reads through it should be mapped
+            entry(
+                TraceOp::Open {
+                    path: file.clone(),
+                    flags: libc::O_RDONLY,
+                    fh: Some(7),
+                },
+                None,
+            ),
+            entry(
+                TraceOp::Read {
+                    path: file.clone(),
+                    fh: 7,
+                    offset: 0,
+                    size: 16,
+                },
+                None,
+            ),
+            // a handle that was never opened is never used
+            entry(
+                TraceOp::Read {
+                    path: file.clone(),
+                    fh: 8,
+                    offset: 0,
+                    size: 16,
+                },
+                None,
+            ),
+            entry(TraceOp::Getattr { path: file }, Some(ENOENT as i32)),
+        ];
+
+        let divergences = replay(&OneFileFs, &entries);
+        assert_eq!(
+            divergences
+                .iter()
+                .map(|d| (d.index, d.expected, d.got))
+                .collect::<Vec<_>>(),
+            vec![
+                (3, None, Some(EBADF as i32)),
+                (4, Some(ENOENT as i32), None)
+            ]
+        );
+    }
+}
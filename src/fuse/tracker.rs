@@ -0,0 +1,201 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Follows tagged files that were renamed or moved on the underlying filesystem.  A file's device/inode survive a
+//! rename, so when a symlink's target is gone, we look for something with the same device/inode.  A rename in place
+//! is found by checking the target's old directory.  Anything further is found in an index of the directories that
+//! tagged files live in, which a background thread rebuilds periodically.
+
+use crate::common::get_device_inode;
+use crate::common::settings::Settings;
+use crate::sql;
+use crate::sql::tpool::ThreadConnPool;
+use parking_lot::RwLock;
+use rusqlite::Connection;
+use std::collections::{HashMap, HashSet};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
+
+const TRACKER_TAG: &str = "tracker";
+
+type FileKey = (u64, u64);
+
+pub struct RenameTracker {
+    settings: Arc<Settings>,
+    /// Every regular file under the indexed directories, by device/inode
+    index: RwLock<HashMap<FileKey, PathBuf>>,
+}
+
+/// Every regular file under `roots`, at most `depth` levels down, by device/inode
+fn index_roots(roots: &[PathBuf], depth: usize) -> HashMap<FileKey, PathBuf> {
+    let mut index = HashMap::new();
+    for root in roots {
+        for entry in walkdir::WalkDir::new(root)
+            .max_depth(depth)
+            .follow_links(false)
+        {
+            match entry {
+                Ok(entry) if entry.file_type().is_file() => {
+                    if let Ok(md) = entry.metadata() {
+                        index.insert((md.dev(), md.ino()), entry.into_path());
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => debug!(target: TRACKER_TAG, "Couldn't index under {:?}: {:?}", root, e),
+            }
+        }
+    }
+    index
+}
+
+/// Whether `path` is currently the file identified by `key`
+fn is_file(path: &Path, key: FileKey) -> bool {
+    matches!(get_device_inode(path), Ok(found) if found == key)
+}
+
+impl RenameTracker {
+    pub fn new(settings: Arc<Settings>) -> Self {
+        Self {
+            settings,
+            index: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Rebuilds the index from the directories that tagged files currently live in
+    pub fn rebuild(&self, conn: &Connection) -> rusqlite::Result<usize> {
+        let roots: HashSet<PathBuf> = sql::get_all_files(conn)?
+            .into_iter()
+            .filter_map(|file| Path::new(&file.path).parent().map(Path::to_path_buf))
+            .collect();
+        let roots: Vec<PathBuf> = roots.into_iter().collect();
+
+        let depth = self.settings.get_config().tracking.index_depth;
+        let index = index_roots(&roots, depth);
+        let indexed = index.len();
+        *self.index.write() = index;
+        Ok(indexed)
+    }
+
+    /// Finds where the file identified by `device`/`inode`, last seen at `old_path`, is now
+    pub fn find(&self, device: u64, inode: u64, old_path: &Path) -> Option<PathBuf> {
+        let key = (device, inode);
+
+        if let Some(dir) = old_path.parent() {
+            if let Ok(entries) = std::fs::read_dir(dir) {
+                let renamed = entries
+                    .filter_map(Result::ok)
+                    .map(|entry| entry.path())
+                    .find(|path| is_file(path, key));
+                if renamed.is_some() {
+                    debug!(target: TRACKER_TAG, "{:?} was renamed to {:?}", old_path, renamed);
+                    return renamed;
+                }
+            }
+        }
+
+        // the index may be out of date, so whatever it says has to be double-checked
+        let indexed = self.index.read().get(&key).cloned();
+        match indexed {
+            Some(path) if is_file(&path, key) => {
+                debug!(target: TRACKER_TAG, "{:?} was moved to {:?}", old_path, path);
+                Some(path)
+            }
+            _ => None,
+        }
+    }
+
+    /// Runs `rebuild` periodically in a background thread, until `threads_done` is set
+    pub fn spawn_indexer(
+        tracker: Arc<Self>,
+        conn_pool: Arc<ThreadConnPool>,
+        threads_done: Arc<AtomicBool>,
+    ) {
+        let interval = Duration::from_secs(tracker.settings.get_config().tracking.index_interval_s);
+        let spawned = std::thread::Builder::new()
+            .name("rename_indexer".to_string())
+            .spawn(move || {
+                while !threads_done.load(Ordering::Relaxed) {
+                    let start = Instant::now();
+                    let rebuilt = {
+                        let conn_lock = conn_pool.get_conn();
+                        let conn = conn_lock.lock();
+                        let real_conn = (*conn).borrow_mut();
+                        tracker.rebuild(&real_conn)
+                    };
+                    match rebuilt {
+                        Ok(indexed) => info!(
+                            target: TRACKER_TAG,
+                            "Indexed {} files in {:?}",
+                            indexed,
+                            start.elapsed()
+                        ),
+                        Err(e) => warn!(target: TRACKER_TAG, "Couldn't rebuild the index: {:?}", e),
+                    }
+
+                    // wake up regularly, so that we notice the filesystem going away
+                    while start.elapsed() < interval && !threads_done.load(Ordering::Relaxed) {
+                        std::thread::sleep(Duration::from_secs(1));
+                    }
+                }
+                debug!(target: TRACKER_TAG, "Filesystem is going away, stopping");
+            });
+        if let Err(e) = spawned {
+            error!(target: TRACKER_TAG, "Couldn't start background indexer: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find() -> Result<(), Box<dyn std::error::Error>> {
+        let root = tempfile::tempdir()?;
+        let root_path = root.path().canonicalize()?;
+        std::fs::create_dir_all(root_path.join("sub/deeper"))?;
+        let original = root_path.join("a.txt");
+        std::fs::write(&original, b"hello")?;
+        let (device, inode) = get_device_inode(&original)?;
+        let tracker = RenameTracker::new(Arc::new(Settings::default()));
+
+        // renamed in place, found without an index
+        let renamed = root_path.join("b.txt");
+        std::fs::rename(&original, &renamed)?;
+        assert_eq!(
+            tracker.find(device, inode, &original),
+            Some(renamed.clone())
+        );
+
+        // moved somewhere else, only found once it's indexed
+        let moved = root_path.join("sub/deeper/b.txt");
+        std::fs::rename(&renamed, &moved)?;
+        assert_eq!(tracker.find(device, inode, &original), None);
+        *tracker.index.write() = index_roots(&[root_path.clone()], 3);
+        assert_eq!(tracker.find(device, inode, &original), Some(moved.clone()));
+
+        // a stale index entry isn't trusted
+        std::fs::remove_file(&moved)?;
+        assert_eq!(tracker.find(device, inode, &original), None);
+        Ok(())
+    }
+}
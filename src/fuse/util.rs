@@ -23,14 +23,15 @@ use crate::sql::types::TaggedFile;
 use fuse_sys::conf::{FuseConfig, MountConfig};
 use fuse_sys::{stat, timespec, O_RDWR, O_WRONLY};
 use libc::{mode_t, S_IFDIR, S_IFLNK, S_IFREG};
-use log::{debug, info};
 use std::convert::TryInto;
 use std::ffi::CString;
 use std::fs::OpenOptions;
 #[cfg(target_os = "macos")]
 use std::hash::Hasher;
 use std::os::raw::{c_char, c_void};
+use std::os::unix::fs::MetadataExt;
 use std::path::Path;
+use tracing::{debug, info};
 
 const UTIL_TAG: &str = "util";
 
@@ -125,6 +126,39 @@ pub fn new_statfile(tf: TaggedFile) -> stat {
     //}
 }
 
+/// A tagged file that is passed through as a regular file, taking its `size` from the target.  Unless `writable`,
+/// writes aren't passed through, so it's read-only.
+pub fn new_passthrough(tf: &TaggedFile, size: u64, writable: bool) -> stat {
+    let perms = if writable {
+        tf.permissions.clone()
+    } else {
+        Permissions::from(tf.permissions.mode() & !0o222)
+    };
+    new_regfile(&tf.mtime, tf.uid, tf.gid, &perms, size as usize)
+}
+
+/// A tagged directory, which can be browsed in place.  Nothing is written through to it, so it's read-only, and it can
+/// be searched wherever it can be read.
+pub fn new_tagged_dir(tf: &TaggedFile) -> stat {
+    let mode = tf.permissions.mode() & !0o222;
+    let perms = Permissions::from(mode | ((mode & 0o444) >> 2));
+    new_dir(&tf.mtime, tf.uid, tf.gid, &perms, 0)
+}
+
+/// Something beneath a tagged directory, which is the real directory, read-only, or a symlink to the real file
+pub fn new_beneath_tagged_dir(real: &Path, md: &std::fs::Metadata) -> stat {
+    let mtime = md
+        .modified()
+        .map(UtcDt::from)
+        .unwrap_or_else(|_| chrono::Utc::now());
+    let perms = Permissions::from((md.mode() & 0o555) as mode_t);
+    if md.is_dir() {
+        new_dir(&mtime, md.uid(), md.gid(), &perms, 0)
+    } else {
+        new_link(&mtime, md.uid(), md.gid(), &perms, real.as_os_str().len())
+    }
+}
+
 pub fn new_link(mtime: &UtcDt, uid: u32, gid: u32, perm: &Permissions, size: usize) -> stat {
     let ts = utcdt_to_timespec(mtime);
     Stat {
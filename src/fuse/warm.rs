@@ -0,0 +1,287 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Pre-computes the root listing and the intersections of the largest tags into the opcache after mounting, so the
+//! first interactive browse of a large collection doesn't have to wait on the database.
+
+use super::opcache::{OpCache, ReaddirCacheEntry};
+use crate::common::notify::Notifier;
+use crate::common::settings::Settings;
+use crate::common::types::TagType;
+use crate::sql;
+use crate::sql::tpool::ThreadConnPool;
+use crate::sql::types::TagOrTagGroup;
+use parking_lot::Mutex;
+use rusqlite::Connection;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, error, info};
+
+const WARM_TAG: &str = "cache_warm";
+
+pub(super) struct Warmer<N: Notifier + 'static> {
+    pub settings: Arc<Settings>,
+    pub conn_pool: Arc<ThreadConnPool>,
+    pub op_cache: Arc<OpCache>,
+    pub notifier: Arc<Mutex<N>>,
+    pub threads_done: Arc<AtomicBool>,
+}
+
+impl<N: Notifier + 'static> Warmer<N> {
+    /// Runs the warm-up in a background thread, which itself fans the work out to a bounded number of workers
+    pub fn spawn(self) {
+        let spawned = std::thread::Builder::new()
+            .name("cache_warm".to_string())
+            .spawn(move || {
+                if let Err(e) = self.run() {
+                    error!(target: WARM_TAG, "Cache warm-up failed: {:?}", e);
+                }
+            });
+        if let Err(e) = spawned {
+            error!(target: WARM_TAG, "Couldn't start cache warm-up: {:?}", e);
+        }
+    }
+
+    fn ttl(&self) -> Duration {
+        Duration::from_secs(self.settings.get_config().cache.warm_ttl_s)
+    }
+
+    fn progress(&self, done: usize, total: usize) {
+        if let Err(e) = self.notifier.lock().warm_progress(done, total) {
+            error!(target: WARM_TAG, "Couldn't report progress: {:?}", e);
+        }
+    }
+
+    fn run(self) -> rusqlite::Result<()> {
+        let conf = self.settings.get_config().cache;
+        info!(
+            target: WARM_TAG,
+            "Warming the cache with the top {} tags on {} threads",
+            conf.warm_top_tags,
+            conf.warm_threads
+        );
+
+        let conn = self.conn_pool.raw_conn();
+        let mut all_tags = sql::get_all_tags(&conn)?;
+        all_tags.sort_by(|a, b| b.num_files.cmp(&a.num_files));
+
+        // the tags that were being browsed when we last unmounted go first, then the largest tags
+        let snapshot = load_snapshot(
+            &self
+                .settings
+                .warm_snapshot_file(&self.settings.get_collection()),
+        );
+        let mut tags: Vec<String> = snapshot
+            .into_iter()
+            .filter(|name| all_tags.iter().any(|t| &t.name == name))
+            .collect();
+        for tag in all_tags {
+            if !tags.contains(&tag.name) {
+                tags.push(tag.name);
+            }
+        }
+        tags.truncate(conf.warm_top_tags);
+
+        // the root listing counts as one unit of work, plus one per tag
+        let total = tags.len() + 1;
+        self.warm_root(&conn)?;
+        self.progress(1, total);
+
+        let (tx, rx) = crossbeam::channel::bounded::<String>(conf.warm_threads.max(1));
+        let done = AtomicUsize::new(1);
+        let this = &self;
+        let scoped = crossbeam::scope(|scope| {
+            for _ in 0..conf.warm_threads.max(1) {
+                let rx = rx.clone();
+                let done = &done;
+                scope.spawn(move |_| {
+                    let conn = this.conn_pool.raw_conn();
+                    for tag in rx {
+                        if this.threads_done.load(Ordering::Relaxed) {
+                            debug!(target: WARM_TAG, "Filesystem is going away, stopping");
+                            break;
+                        }
+                        if let Err(e) = this.warm_tag(&conn, &tag) {
+                            error!(target: WARM_TAG, "Couldn't warm tag {}: {:?}", tag, e);
+                        }
+                        let finished = done.fetch_add(1, Ordering::SeqCst) + 1;
+                        this.progress(finished, total);
+                    }
+                });
+            }
+
+            for tag in tags {
+                if tx.send(tag).is_err() {
+                    break;
+                }
+            }
+            drop(tx);
+        });
+        // a worker that panicked only leaves its share of the tags cold, which the first browse of them fills in
+        if let Err(e) = scoped {
+            error!(target: WARM_TAG, "A cache warm-up worker panicked: {:?}", e);
+        }
+
+        info!(target: WARM_TAG, "Done warming the cache");
+        Ok(())
+    }
+
+    fn warm_root(&self, conn: &Connection) -> rusqlite::Result<()> {
+        let root = PathBuf::from(std::path::MAIN_SEPARATOR.to_string());
+        for tag in sql::get_all_tags(conn)? {
+            self.op_cache.add_readdir_entry_ttl(
+                &root.join(&tag.name),
+                ReaddirCacheEntry::Tag(tag),
+                self.ttl(),
+            );
+        }
+        for tg in sql::get_all_tag_groups(conn)? {
+            let name = tg.to_fileentry(&self.settings).name;
+            self.op_cache.add_readdir_entry_ttl(
+                &root.join(name),
+                ReaddirCacheEntry::TagGroup(tg),
+                self.ttl(),
+            );
+        }
+        Ok(())
+    }
+
+    /// Caches the subdirectories, pins, and files that are directly under a single tag
+    fn warm_tag(&self, conn: &Connection, tag: &str) -> rusqlite::Result<()> {
+        debug!(target: WARM_TAG, "Warming tag {}", tag);
+        let path = Path::new(&std::path::MAIN_SEPARATOR.to_string()).join(tag);
+        let tags = vec![TagType::Regular(tag.to_string())];
+        let ttl = self.ttl();
+
+        for subtag in sql::intersect_tag(conn, &tags, true)? {
+            let sub_path = path.join(&subtag.name);
+            self.op_cache
+                .add_readdir_entry_ttl(&sub_path, ReaddirCacheEntry::Tag(subtag), ttl);
+        }
+
+        for pinned in sql::pinned_subdirs(conn, &tags)? {
+            if let TagOrTagGroup::Tag(subtag) = pinned {
+                let sub_path = path.join(&subtag.name);
+                self.op_cache
+                    .add_readdir_entry_ttl(&sub_path, ReaddirCacheEntry::Tag(subtag), ttl);
+            }
+        }
+
+        // this mirrors the naming in readdir, so that the cached entries are found under the same names
+        let filedir = path.join(&self.settings.get_config().symbols.filedir_str);
+        let files = sql::files_tagged_with(conn, &tags)?;
+        let names = self.settings.filedir_names(&files);
+        for (file, name) in files.into_iter().zip(names) {
+            self.op_cache.add_readdir_entry_ttl(
+                &filedir.join(name),
+                ReaddirCacheEntry::File(file),
+                ttl,
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Saves `tags` as the snapshot that the next mount's warm-up starts with
+pub(super) fn save_snapshot(path: &Path, tags: &[String]) -> std::io::Result<()> {
+    debug!(target: WARM_TAG, "Saving {} tags to {:?}", tags.len(), path);
+    let mut contents = tags.join("\n");
+    contents.push('\n');
+    std::fs::write(path, contents)
+}
+
+fn load_snapshot(path: &Path) -> Vec<String> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => contents
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(String::from)
+            .collect(),
+        Err(_) => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::fsops;
+    use crate::common::notify::desktop::DesktopNotifier;
+    use crate::common::testing::{self, COLLECTION};
+    use crate::common::types::file_perms::UMask;
+
+    #[test]
+    fn test_warm() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let settings = testing::settings(dir.path(), &[]);
+        let notifier = DesktopNotifier::from_settings(&settings);
+
+        let files = dir.path().join("files");
+        std::fs::create_dir(&files)?;
+        {
+            let mut conn = sql::get_conn(settings.db_file(COLLECTION))?;
+            let tx = sql::begin_write(&mut conn)?;
+            for (name, tags) in [("a.txt", "t1/t2"), ("b.txt", "t1")].iter() {
+                let src = files.join(name);
+                std::fs::write(&src, name)?;
+                fsops::ln(
+                    &settings,
+                    &tx,
+                    &src,
+                    Path::new(tags),
+                    name,
+                    0,
+                    0,
+                    &UMask::default(),
+                    None,
+                    &notifier,
+                )?;
+            }
+            tx.commit()?;
+        }
+
+        let op_cache = Arc::new(OpCache::new(settings.clone()));
+        let warmer = Warmer {
+            settings: settings.clone(),
+            conn_pool: Arc::new(ThreadConnPool::new(settings.db_file(COLLECTION))),
+            op_cache: op_cache.clone(),
+            notifier: Arc::new(Mutex::new(notifier)),
+            threads_done: Arc::new(AtomicBool::new(false)),
+        };
+        warmer.run()?;
+
+        let t1 = Path::new("/t1");
+        assert!(matches!(
+            op_cache.check_readdir_entry(t1),
+            Some(ReaddirCacheEntry::Tag(_))
+        ));
+        assert!(matches!(
+            op_cache.check_readdir_entry(&t1.join("t2")),
+            Some(ReaddirCacheEntry::Tag(_))
+        ));
+        let filedir = t1.join(&settings.get_config().symbols.filedir_str);
+        for name in &["a.txt", "b.txt"] {
+            assert!(matches!(
+                op_cache.check_readdir_entry(&filedir.join(name)),
+                Some(ReaddirCacheEntry::File(_))
+            ));
+        }
+        Ok(())
+    }
+}
@@ -43,8 +43,16 @@ pub mod common;
 pub mod fuse;
 pub mod platform;
 pub mod sql;
+pub mod watch;
 
+pub use cli::demo::demo;
+pub use cli::import::import;
 pub use cli::ln::ln;
+pub use cli::materialize::materialize;
+pub use cli::merge::merge;
 pub use cli::rename::rename;
+pub use cli::retag::retag;
 pub use cli::rm::rm;
 pub use cli::rmdir::rmdir;
+pub use cli::swap::swap;
+pub use watch::watch;
@@ -16,16 +16,253 @@
  * along with this program.  If not, see <http://www.gnu.org/licenses/>.
  */
 
-use std::path::Path;
+use super::MountEntry;
+use std::path::{Path, PathBuf};
 
 pub fn mountdir() -> std::path::PathBuf {
     "/mnt".into()
 }
 
+/// Everything that's mounted, as our mount namespace sees it
+pub fn mount_table() -> Result<Vec<MountEntry>, std::io::Error> {
+    let contents = std::fs::read_to_string("/proc/self/mountinfo")?;
+    Ok(parse_mountinfo(&contents))
+}
+
+/// Parses the lines of /proc/self/mountinfo, which look like:
+///
+/// ```text
+/// 36 35 98:0 /mnt1 /mnt2 rw,noatime master:1 - ext3 /dev/root rw,errors=continue
+/// ```
+///
+/// where the mountpoint is the 5th field, and the fstype and source follow the "-" that ends the optional fields
+fn parse_mountinfo(contents: &str) -> Vec<MountEntry> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(' ');
+            let mountpoint = fields.nth(4)?;
+            let mut rest = fields.skip_while(|f| *f != "-").skip(1);
+            let fstype = rest.next()?;
+            let source = rest.next()?;
+            Some(MountEntry {
+                source: unescape_octal(source),
+                mountpoint: PathBuf::from(unescape_octal(mountpoint)),
+                fstype: unescape_octal(fstype),
+            })
+        })
+        .collect()
+}
+
+/// The kernel escapes spaces, tabs, newlines and backslashes in mountinfo as 3 octal digits, like "\040"
+fn unescape_octal(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 3 < bytes.len() {
+            let digits = std::str::from_utf8(&bytes[i + 1..i + 4]).ok();
+            if let Some(byte) = digits.and_then(|d| u8::from_str_radix(d, 8).ok()) {
+                out.push(byte);
+                i += 4;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// The secret stored for `account` under `service` in the desktop's keyring, looked up with libsecret's
+/// `secret-tool`.  There's none if it isn't stored, or if there's no keyring to ask.
+pub fn keyring_secret(service: &str, account: &str) -> Option<String> {
+    let output = std::process::Command::new("secret-tool")
+        .args(&["lookup", "service", service, "account", account])
+        .output()
+        .ok()?;
+    let secret = String::from_utf8(output.stdout).ok()?;
+    if output.status.success() && !secret.is_empty() {
+        Some(secret.trim_end_matches('\n').to_string())
+    } else {
+        None
+    }
+}
+
+/// Opens `path` with the user's preferred application for it
+pub fn open_file(path: &Path) -> Result<(), std::io::Error> {
+    let status = std::process::Command::new("xdg-open").arg(path).status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("xdg-open exited with {}", status),
+        ))
+    }
+}
+
+/// Unmounts `path`.  Only root can unmount directly, everyone else has to go through the setuid fusermount
 pub fn unmount(path: &Path) -> Result<(), std::io::Error> {
+    if nix::unistd::geteuid().is_root() {
+        return nix::mount::umount(path).map_err(to_io);
+    }
     std::process::Command::new("fusermount")
         .arg("-u")
         .arg(path)
         .spawn()
         .map(|_| ())
 }
+
+/// Unmounts a mount whose daemon is gone.  The unmount is lazy, so that it succeeds even if something still has the
+/// dead mount open, and it's waited on, so that the mountpoint is usable when this returns
+pub fn force_unmount(path: &Path) -> Result<(), std::io::Error> {
+    if nix::unistd::geteuid().is_root() {
+        return nix::mount::umount2(path, nix::mount::MntFlags::MNT_DETACH).map_err(to_io);
+    }
+    let status = std::process::Command::new("fusermount")
+        .arg("-u")
+        .arg("-z")
+        .arg(path)
+        .status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("fusermount exited with {}", status),
+        ))
+    }
+}
+
+/// The systemd user unit that mounts collection `col` at login
+pub fn service_file(col: &str) -> Result<PathBuf, std::io::Error> {
+    let base = directories::BaseDirs::new()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "No home directory"))?;
+    Ok(base
+        .config_dir()
+        .join("systemd")
+        .join("user")
+        .join(format!("supertag-{}.service", col)))
+}
+
+/// Quotes `arg` for a systemd `Exec` line, where `%` starts a specifier
+fn systemd_quote(arg: &str) -> String {
+    let escaped = arg
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%");
+    format!("\"{}\"", escaped)
+}
+
+/// A systemd user unit that runs the `tag` binary at `exe` to mount collection `col`.  The mount stays in the
+/// foreground, so that systemd can tell when it stops, and restart it if it failed.
+pub fn service_contents(exe: &Path, col: &str) -> String {
+    let exe = systemd_quote(&exe.to_string_lossy());
+    let col_arg = systemd_quote(col);
+    format!(
+        "[Unit]
+Description=Supertag collection {col}
+
+[Service]
+Type=simple
+ExecStart={exe} mount --foreground {col_arg}
+ExecStop={exe} unmount {col_arg}
+Restart=on-failure
+
+[Install]
+WantedBy=default.target
+",
+        col = col.replace('%', "%%"),
+        exe = exe,
+        col_arg = col_arg,
+    )
+}
+
+fn systemctl(args: &[&str]) -> Result<(), std::io::Error> {
+    let status = std::process::Command::new("systemctl")
+        .arg("--user")
+        .args(args)
+        .status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("systemctl {} exited with {}", args.join(" "), status),
+        ))
+    }
+}
+
+fn unit_name(file: &Path) -> String {
+    file.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Writes `contents` to the unit `file`, and enables and starts it.  Installing it again replaces it, and restarts
+/// it if it's running.
+pub fn install_service(file: &Path, contents: &str) -> Result<(), std::io::Error> {
+    if let Some(dir) = file.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(file, contents)?;
+    let name = unit_name(file);
+    systemctl(&["daemon-reload"])?;
+    systemctl(&["enable", &name])?;
+    systemctl(&["restart", &name])
+}
+
+/// Stops and disables the unit `file`, and removes it.  A unit that isn't installed is left alone.
+pub fn uninstall_service(file: &Path) -> Result<(), std::io::Error> {
+    if !file.exists() {
+        return Ok(());
+    }
+    systemctl(&["disable", "--now", &unit_name(file)])?;
+    std::fs::remove_file(file)?;
+    systemctl(&["daemon-reload"])
+}
+
+fn to_io(e: nix::Error) -> std::io::Error {
+    match e.as_errno() {
+        Some(errno) => std::io::Error::from_raw_os_error(errno as i32),
+        None => std::io::Error::new(std::io::ErrorKind::Other, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mountinfo() {
+        let contents = "\
+22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+41 22 0:36 / /mnt/supertag/my\\040docs rw,nosuid,nodev,relatime shared:21 - fuse.manifold supertag:my_docs rw,user_id=1000
+42 22 0:37 / /mnt/bare rw - tmpfs tmpfs rw
+truncated line";
+        let entries = parse_mountinfo(contents);
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].mountpoint, PathBuf::from("/"));
+        assert_eq!(entries[0].fstype, "ext4");
+        assert_eq!(entries[0].source, "/dev/sda1");
+
+        assert_eq!(
+            entries[1].mountpoint,
+            PathBuf::from("/mnt/supertag/my docs")
+        );
+        assert_eq!(entries[1].fstype, "fuse.manifold");
+        assert_eq!(entries[1].source, "supertag:my_docs");
+
+        assert_eq!(entries[2].source, "tmpfs");
+    }
+
+    #[test]
+    fn test_service_contents() {
+        let contents = service_contents(Path::new("/opt/my tools/tag"), "100%_docs");
+        assert!(contents.contains("Description=Supertag collection 100%%_docs\n"));
+        assert!(contents
+            .contains("ExecStart=\"/opt/my tools/tag\" mount --foreground \"100%%_docs\"\n"));
+        assert!(contents.contains("ExecStop=\"/opt/my tools/tag\" unmount \"100%%_docs\"\n"));
+    }
+}
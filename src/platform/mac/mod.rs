@@ -16,7 +16,10 @@
  * along with this program.  If not, see <http://www.gnu.org/licenses/>.
  */
 
-use std::path::Path;
+use super::MountEntry;
+use std::ffi::{CStr, CString};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
 pub mod alias;
@@ -26,6 +29,164 @@ pub fn mountdir() -> std::path::PathBuf {
     "/Volumes".into()
 }
 
+/// Everything that's mounted
+pub fn mount_table() -> Result<Vec<MountEntry>, std::io::Error> {
+    let mut mounts: *mut libc::statfs = std::ptr::null_mut();
+    // the buffer belongs to getmntinfo, and is reused by its next call, so everything is copied out of it
+    let num = unsafe { libc::getmntinfo(&mut mounts, libc::MNT_NOWAIT) };
+    if num == 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    let to_string = |chars: &[libc::c_char]| {
+        unsafe { CStr::from_ptr(chars.as_ptr()) }
+            .to_string_lossy()
+            .into_owned()
+    };
+    let entries = unsafe { std::slice::from_raw_parts(mounts, num as usize) };
+    Ok(entries
+        .iter()
+        .map(|entry| MountEntry {
+            source: to_string(&entry.f_mntfromname),
+            mountpoint: PathBuf::from(to_string(&entry.f_mntonname)),
+            fstype: to_string(&entry.f_fstypename),
+        })
+        .collect())
+}
+
+fn unmount_with(path: &Path, flags: libc::c_int) -> Result<(), std::io::Error> {
+    let path_c = CString::new(path.as_os_str().as_bytes())?;
+    match unsafe { libc::unmount(path_c.as_ptr(), flags) } {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+/// The password stored for `account` under `service` in the login keychain.  There's none if it isn't stored.
+pub fn keyring_secret(service: &str, account: &str) -> Option<String> {
+    let output = Command::new("security")
+        .args(&["find-generic-password", "-s", service, "-a", account, "-w"])
+        .output()
+        .ok()?;
+    let secret = String::from_utf8(output.stdout).ok()?;
+    if output.status.success() && !secret.is_empty() {
+        Some(secret.trim_end_matches('\n').to_string())
+    } else {
+        None
+    }
+}
+
+/// Opens `path` with the user's preferred application for it
+pub fn open_file(path: &Path) -> Result<(), std::io::Error> {
+    let status = Command::new("open").arg(path).status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("open exited with {}", status),
+        ))
+    }
+}
+
 pub fn unmount(path: &Path) -> Result<(), std::io::Error> {
-    Command::new("umount").arg(path).spawn().map(|_| ())
+    unmount_with(path, 0)
+}
+
+/// Unmounts a mount whose daemon is gone, so that the mountpoint is usable when this returns
+pub fn force_unmount(path: &Path) -> Result<(), std::io::Error> {
+    unmount_with(path, libc::MNT_FORCE)
+}
+
+/// The launchd label of the agent that mounts collection `col`
+fn service_label(col: &str) -> String {
+    format!("{}.{}", crate::common::constants::ORG, col)
+}
+
+/// The launchd agent plist that mounts collection `col` at login
+pub fn service_file(col: &str) -> Result<PathBuf, std::io::Error> {
+    let base = directories::BaseDirs::new()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "No home directory"))?;
+    Ok(base
+        .home_dir()
+        .join("Library")
+        .join("LaunchAgents")
+        .join(format!("{}.plist", service_label(col))))
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// A launchd agent plist that runs the `tag` binary at `exe` to mount collection `col`.  The mount stays in the
+/// foreground, so that launchd can tell when it stops, and start it again if it failed.
+pub fn service_contents(exe: &Path, col: &str) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>{label}</string>
+    <key>ProgramArguments</key>
+    <array>
+        <string>{exe}</string>
+        <string>mount</string>
+        <string>--foreground</string>
+        <string>{col}</string>
+    </array>
+    <key>RunAtLoad</key>
+    <true/>
+    <key>KeepAlive</key>
+    <dict>
+        <key>SuccessfulExit</key>
+        <false/>
+    </dict>
+</dict>
+</plist>
+"#,
+        label = xml_escape(&service_label(col)),
+        exe = xml_escape(&exe.to_string_lossy()),
+        col = xml_escape(col),
+    )
+}
+
+fn launchctl(args: &[&str], file: &Path) -> Result<(), std::io::Error> {
+    let status = Command::new("launchctl").args(args).arg(file).status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("launchctl {} exited with {}", args.join(" "), status),
+        ))
+    }
+}
+
+/// Writes `contents` to the agent plist `file`, and loads it.  Installing it again replaces it, and reloads it if it's
+/// loaded.
+pub fn install_service(file: &Path, contents: &str) -> Result<(), std::io::Error> {
+    if let Some(dir) = file.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    if file.exists() {
+        // launchctl refuses to load an agent that's already loaded, and it may not be, so this can fail
+        let _ = launchctl(&["unload"], file);
+    }
+    std::fs::write(file, contents)?;
+    launchctl(&["load", "-w"], file)
+}
+
+/// Unloads the agent plist `file`, and removes it.  An agent that isn't installed is left alone.
+pub fn uninstall_service(file: &Path) -> Result<(), std::io::Error> {
+    if !file.exists() {
+        return Ok(());
+    }
+    // it may have been unloaded by hand already
+    let _ = launchctl(&["unload", "-w"], file);
+    std::fs::remove_file(file)
 }
@@ -29,12 +29,35 @@ pub mod mac;
 #[cfg(target_os = "macos")]
 pub use mac::*;
 
-use log::debug;
+use tracing::debug;
 
 const PLATFORM_TAG: &str = "platform";
 
 use crate::common::settings::Settings;
+use nix::sys::signal::kill;
+use nix::unistd::Pid;
 use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+
+/// The fsname prefix of our mounts, as in "supertag:my_collection"
+const MOUNT_SOURCE_PREFIX: &str = "supertag:";
+
+/// A line of the system's mount table
+#[derive(Debug, Clone, PartialEq)]
+pub struct MountEntry {
+    /// What was mounted, which for us is the fsname
+    pub source: String,
+    pub mountpoint: PathBuf,
+    pub fstype: String,
+}
+
+impl MountEntry {
+    /// The collection, if this is a supertag mount
+    pub fn collection(&self) -> Option<&str> {
+        self.source.strip_prefix(MOUNT_SOURCE_PREFIX)
+    }
+}
 
 /// Sorts the known collections (collections with a directory in the collections directory) by the
 /// collection creation time, as far as we can determine
@@ -81,16 +104,142 @@ pub fn primary_collection(
 
 /// Returns all mounted collections
 pub fn mounted_collections() -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
-    let output = std::process::Command::new("mount").output()?.stdout;
-    let supertag_re = regex::Regex::new(r"(?m)^supertag:(.+?) on (.+?)\s").unwrap();
-
-    Ok(supertag_re
-        .captures_iter(&String::from_utf8(output).unwrap())
-        .map(|el| {
-            (
-                el.get(1).unwrap().as_str().to_string(),
-                el.get(2).unwrap().as_str().to_string(),
-            )
+    Ok(mount_table()?
+        .iter()
+        .filter_map(|entry| {
+            let col = entry.collection()?;
+            Some((col.to_string(), entry.mountpoint.display().to_string()))
         })
         .collect())
 }
+
+/// Whether a collection is mounted, and whether that mount is still being served
+#[derive(Debug)]
+pub enum MountState {
+    Unmounted,
+    /// Mounted and answering.  The daemon's pid is known if it recorded it
+    Mounted {
+        mountpoint: PathBuf,
+        pid: Option<i32>,
+    },
+    /// In the mount table, but its daemon isn't serving it
+    Stale {
+        mountpoint: PathBuf,
+        reason: StaleMount,
+    },
+}
+
+impl Display for MountState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MountState::Unmounted => write!(f, "not mounted"),
+            MountState::Mounted {
+                mountpoint,
+                pid: Some(pid),
+            } => write!(f, "mounted at {}, pid {}", mountpoint.display(), pid),
+            MountState::Mounted {
+                mountpoint,
+                pid: None,
+            } => write!(f, "mounted at {}", mountpoint.display()),
+            MountState::Stale { mountpoint, reason } => {
+                write!(f, "stale at {}, {}", mountpoint.display(), reason)
+            }
+        }
+    }
+}
+
+/// Reports the mount state of collection `col`, whose daemon records its pid in `pid_file`
+pub fn mount_state(col: &str, pid_file: &Path) -> Result<MountState, Box<dyn std::error::Error>> {
+    let mountpoint = match mount_table()?
+        .into_iter()
+        .find(|entry| entry.collection() == Some(col))
+    {
+        Some(entry) => entry.mountpoint,
+        None => return Ok(MountState::Unmounted),
+    };
+
+    Ok(match stale_reason(&mountpoint, pid_file) {
+        Some(reason) => MountState::Stale { mountpoint, reason },
+        None => MountState::Mounted {
+            mountpoint,
+            pid: recorded_pid(pid_file),
+        },
+    })
+}
+
+/// Why a supertag mount is considered left behind by a daemon that's no longer serving it
+#[derive(Debug)]
+pub enum StaleMount {
+    /// The daemon that mounted it has exited
+    DeadDaemon(i32),
+    /// The filesystem doesn't answer, eg with "Transport endpoint is not connected"
+    Unresponsive(nix::Error),
+}
+
+impl Display for StaleMount {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StaleMount::DeadDaemon(pid) => {
+                write!(f, "its daemon, pid {}, is no longer running", pid)
+            }
+            StaleMount::Unresponsive(e) => write!(f, "it doesn't respond: {}", e),
+        }
+    }
+}
+
+fn pid_alive(pid: i32) -> bool {
+    match kill(Pid::from_raw(pid), None) {
+        Ok(_) => true,
+        // it exists, it's just not ours to signal
+        Err(nix::Error::Sys(nix::errno::Errno::EPERM)) => true,
+        Err(_) => false,
+    }
+}
+
+/// Checks whether `mountpoint` has a supertag mount on it whose daemon is gone.  `pid_file` is where that daemon
+/// recorded its pid.  A mount whose daemon is alive and answering is never stale.
+pub fn stale_mount(
+    mountpoint: &Path,
+    pid_file: &Path,
+) -> Result<Option<StaleMount>, Box<dyn std::error::Error>> {
+    let mounted = mount_table()?;
+    if !mounted
+        .iter()
+        .any(|entry| entry.collection().is_some() && entry.mountpoint == mountpoint)
+    {
+        return Ok(None);
+    }
+    debug!(
+        target: PLATFORM_TAG,
+        "{} is in the mount table, checking if it's stale",
+        mountpoint.display()
+    );
+    Ok(stale_reason(mountpoint, pid_file))
+}
+
+/// Whether the daemon that recorded its pid in `pid_file` is still running.  It outlives its mount for as long as it
+/// takes to shut down, and it removes its pid file last.
+pub fn daemon_running(pid_file: &Path) -> bool {
+    recorded_pid(pid_file).map_or(false, pid_alive)
+}
+
+fn recorded_pid(pid_file: &Path) -> Option<i32> {
+    std::fs::read_to_string(pid_file)
+        .ok()
+        .and_then(|contents| contents.trim().parse::<i32>().ok())
+}
+
+/// Why the supertag mount at `mountpoint` is stale, if it is
+fn stale_reason(mountpoint: &Path, pid_file: &Path) -> Option<StaleMount> {
+    // checked first, because on some platforms, a dead daemon's mount can hang rather than fail
+    if let Some(pid) = recorded_pid(pid_file) {
+        if !pid_alive(pid) {
+            return Some(StaleMount::DeadDaemon(pid));
+        }
+    }
+
+    match nix::sys::statfs::statfs(mountpoint) {
+        Ok(_) => None,
+        Err(e) => Some(StaleMount::Unresponsive(e)),
+    }
+}
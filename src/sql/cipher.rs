@@ -0,0 +1,143 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+//! Encryption at rest for collection databases, with SQLCipher.  Keys are remembered by database path for the life of
+//! the process, so that every connection `get_conn` opens to an encrypted database is keyed with it, including the
+//! ones the daemon opens after forking.  Without the `encryption` feature, plain sqlite would silently ignore a key,
+//! so encrypted databases are refused instead.
+
+use super::SQL_TAG;
+use crate::common::err::{STagError, STagResult};
+use crate::common::keyring;
+use crate::common::settings::Settings;
+use lazy_static::lazy_static;
+use parking_lot::RwLock;
+use rusqlite::{ffi, params, Connection, Result, NO_PARAMS};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tracing::{debug, info};
+
+/// Whether this build can open encrypted databases
+pub const SUPPORTED: bool = cfg!(feature = "encryption");
+
+lazy_static! {
+    static ref KEYS: RwLock<HashMap<PathBuf, String>> = RwLock::new(HashMap::new());
+}
+
+/// Remembers `key` as the key of the database at `db_path`, for every connection opened to it from now on
+pub fn set_key(db_path: &Path, key: String) {
+    debug!(target: SQL_TAG, "Setting the key for {:?}", db_path);
+    KEYS.write().insert(db_path.to_owned(), key);
+}
+
+/// Whether there's a key for the database at `db_path`
+pub fn has_key(db_path: &Path) -> bool {
+    KEYS.read().contains_key(db_path)
+}
+
+fn auth_error(msg: String) -> rusqlite::Error {
+    rusqlite::Error::SqliteFailure(ffi::Error::new(ffi::SQLITE_AUTH), Some(msg))
+}
+
+/// Keys `conn` to the database at `db_path`, if it has a key.  SQLCipher only checks a key once the database is read,
+/// so it's read here, where a wrong key can be reported as such.
+pub(super) fn apply_key(conn: &Connection, db_path: &Path) -> Result<()> {
+    if let Some(key) = KEYS.read().get(db_path) {
+        if !SUPPORTED {
+            return Err(auth_error(
+                "supertag was built without the encryption feature".to_string(),
+            ));
+        }
+        conn.pragma_update(None, "key", key)?;
+        conn.query_row("SELECT count(*) FROM sqlite_master", NO_PARAMS, |_| Ok(()))
+            .map_err(|_| auth_error(format!("Wrong key for {:?}", db_path)))?;
+    }
+    Ok(())
+}
+
+/// Makes sure there's a key for `col`'s database if the collection is encrypted, looking it up in the keyring or
+/// asking for it if there isn't one yet
+pub(super) fn unlock(settings: &Settings, col: &str) -> Result<()> {
+    let db_file = settings.db_file(col);
+    if !settings.get_config().collection.encrypted || has_key(&db_file) {
+        return Ok(());
+    }
+    let key = keyring::collection_key(col).map_err(|e| auth_error(e.to_string()))?;
+    set_key(&db_file, key);
+    Ok(())
+}
+
+/// Encrypts the plain database at `db_path` in place with its key.  It's exported into an encrypted copy, which then
+/// replaces it, so the database is never left half encrypted.
+pub fn encrypt(db_path: &Path) -> STagResult<()> {
+    let key = KEYS
+        .read()
+        .get(db_path)
+        .cloned()
+        .ok_or_else(|| STagError::InvalidPath(db_path.to_owned()))?;
+    if !SUPPORTED {
+        return Err(
+            auth_error("supertag was built without the encryption feature".to_string()).into(),
+        );
+    }
+    info!(target: SQL_TAG, "Encrypting {:?}", db_path);
+
+    let encrypted = db_path.with_extension("encrypting");
+    let _ = std::fs::remove_file(&encrypted);
+    {
+        let plain = Connection::open(db_path)?;
+        plain.execute(
+            "ATTACH DATABASE ?1 AS encrypted KEY ?2",
+            params![encrypted.to_string_lossy(), key],
+        )?;
+        plain.query_row(
+            "SELECT sqlcipher_export('encrypted')",
+            NO_PARAMS,
+            |_| Ok(()),
+        )?;
+        plain.execute("DETACH DATABASE encrypted", NO_PARAMS)?;
+    }
+    std::fs::rename(&encrypted, db_path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrong_key() -> STagResult<()> {
+        let dir = tempfile::tempdir()?;
+        let db_path = dir.path().join("db.sqlite3");
+        set_key(&db_path, "right".to_string());
+        if !SUPPORTED {
+            // a key that would be ignored is refused instead
+            assert!(crate::sql::get_conn(&db_path).is_err());
+            return Ok(());
+        }
+
+        let conn = crate::sql::get_conn(&db_path)?;
+        conn.execute("CREATE TABLE t (id INTEGER)", NO_PARAMS)?;
+        drop(conn);
+
+        set_key(&db_path, "wrong".to_string());
+        assert!(crate::sql::get_conn(&db_path).is_err());
+        set_key(&db_path, "right".to_string());
+        assert!(crate::sql::get_conn(&db_path).is_ok());
+        Ok(())
+    }
+}
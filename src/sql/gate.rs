@@ -0,0 +1,126 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+//! The write gate, an advisory lock that's held while the collection is closed for maintenance, like a migration or
+//! a backup.  Anything that writes to the collection should check it first, and the daemon that has the collection
+//! mounted mirrors it in memory, so that its writes don't need a query to check.
+
+use super::{float_to_utcdt, SQL_TAG};
+use crate::common::types::UtcDt;
+use rusqlite::{params, Connection, Result, NO_PARAMS};
+use tracing::{info, trace};
+
+/// Something holding the write gate closed
+#[derive(Debug, Clone)]
+pub struct GateHolder {
+    /// Identifies the maintenance, so that it can let go of the gate
+    pub holder: String,
+    /// Why writes are held off, as shown to the user
+    pub reason: String,
+    pub pid: i32,
+    pub ts: UtcDt,
+}
+
+/// Closes the gate on behalf of `holder`.  Closing it again as the same holder replaces its reason.
+pub fn close_gate(conn: &Connection, holder: &str, reason: &str, pid: i32, now: f64) -> Result<()> {
+    info!(target: SQL_TAG, "Closing the write gate for {}: {}", holder, reason);
+    let query =
+        "INSERT OR REPLACE INTO write_gate (holder, reason, pid, ts) VALUES (?1, ?2, ?3, ?4)";
+    trace!(target: SQL_TAG, "{}", query);
+    conn.execute(query, params![holder, reason, pid, now])?;
+    Ok(())
+}
+
+/// Lets go of the gate on behalf of `holder`.  The gate only opens once no one else holds it.  Returns whether
+/// `holder` was holding it.
+pub fn open_gate(conn: &Connection, holder: &str) -> Result<bool> {
+    info!(target: SQL_TAG, "Opening the write gate for {}", holder);
+    let query = "DELETE FROM write_gate WHERE holder=?1";
+    trace!(target: SQL_TAG, "{}", query);
+    Ok(conn.execute(query, params![holder])? > 0)
+}
+
+/// Lets go of the gate for every holder, opening it, even for maintenance that's still running or that died without
+/// letting go.  Returns how many holders there were.
+pub fn clear_gate(conn: &Connection) -> Result<usize> {
+    info!(target: SQL_TAG, "Clearing the write gate");
+    let query = "DELETE FROM write_gate";
+    trace!(target: SQL_TAG, "{}", query);
+    conn.execute(query, NO_PARAMS)
+}
+
+/// Everything holding the gate closed, oldest first.  The gate is open if there's nothing.
+pub fn gate_holders(conn: &Connection) -> Result<Vec<GateHolder>> {
+    let query = "SELECT holder, reason, pid, ts FROM write_gate ORDER BY ts, holder";
+    trace!(target: SQL_TAG, "{}", query);
+    let mut stmt = conn.prepare(query)?;
+    let holders = stmt
+        .query_map(NO_PARAMS, |row| {
+            Ok(GateHolder {
+                holder: row.get(0)?,
+                reason: row.get(1)?,
+                pid: row.get(2)?,
+                ts: float_to_utcdt(row.get(3)?),
+            })
+        })?
+        .collect::<Result<Vec<_>>>()?;
+    Ok(holders)
+}
+
+/// The reasons of everyone holding the gate closed, as one message, or None if it's open
+pub fn gate_reason(conn: &Connection) -> Result<Option<String>> {
+    let holders = gate_holders(conn)?;
+    if holders.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(
+        holders
+            .iter()
+            .map(|h| h.reason.as_str())
+            .collect::<Vec<_>>()
+            .join(", "),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql::migrations;
+
+    #[test]
+    fn test_gate_holders() -> Result<()> {
+        let mut conn = Connection::open_in_memory()?;
+        migrations::migrate(&mut conn, &crate::common::version_str())?;
+        assert!(gate_reason(&conn)?.is_none());
+
+        close_gate(&conn, "backup", "backing up", 10, 1.0)?;
+        close_gate(&conn, "migration", "migrating", 11, 2.0)?;
+        assert_eq!(
+            gate_reason(&conn)?,
+            Some("backing up, migrating".to_string())
+        );
+
+        // still closed until every holder lets go
+        assert!(open_gate(&conn, "backup")?);
+        assert!(!open_gate(&conn, "backup")?);
+        assert_eq!(gate_reason(&conn)?, Some("migrating".to_string()));
+
+        assert!(open_gate(&conn, "migration")?);
+        assert!(gate_holders(&conn)?.is_empty());
+        Ok(())
+    }
+}
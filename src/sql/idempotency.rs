@@ -0,0 +1,170 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+//! The results of rpc calls that were made with an idempotency key.  A client that doesn't know whether a call went
+//! through, because its connection dropped, can make it again with the same key, and get the original result back
+//! instead of making the change twice.
+
+use super::SQL_TAG;
+use rusqlite::{params, Connection, Result, TransactionBehavior};
+use tracing::{debug, trace};
+
+/// How long a result is kept for.  It only has to outlast a client's retries.
+pub const KEEP_SECS: f64 = 24.0 * 60.0 * 60.0;
+
+/// What `reserve` found for a key
+#[derive(Debug, PartialEq)]
+pub enum Reservation {
+    /// The key is new, and the caller is the only one that may make the call
+    Reserved,
+    /// A call to this method with the key is being made right now
+    Running(String),
+    /// A call to this method with the key was made, and this is its result
+    Done(String, String),
+}
+
+/// Claims `key` for a call to `method`.  Claiming is a single `INSERT` into the key's primary key, so of any number of
+/// clients that try to claim the same key at once, exactly one gets `Reserved`, and every other one is told about the
+/// call that it lost to.  Results older than `KEEP_SECS` are forgotten first, which frees their keys.
+pub fn reserve(conn: &mut Connection, key: &str, method: &str, now: f64) -> Result<Reservation> {
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+    let query = "DELETE FROM rpc_idempotency WHERE ts <= ?1";
+    trace!(target: SQL_TAG, "{}", query);
+    let pruned = tx.execute(query, params![now - KEEP_SECS])?;
+    debug!(target: SQL_TAG, "Pruned {} expired idempotency keys", pruned);
+
+    let query =
+        "INSERT OR IGNORE INTO rpc_idempotency (key, method, result, ts) VALUES (?1, ?2, NULL, ?3)";
+    trace!(target: SQL_TAG, "{}", query);
+    let reservation = if tx.execute(query, params![key, method, now])? == 1 {
+        Reservation::Reserved
+    } else {
+        let query = "SELECT method, result FROM rpc_idempotency WHERE key=?1";
+        trace!(target: SQL_TAG, "{}", query);
+        let (method, result): (String, Option<String>) =
+            tx.query_row(query, params![key], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        match result {
+            Some(result) => Reservation::Done(method, result),
+            None => Reservation::Running(method),
+        }
+    };
+    tx.commit()?;
+    Ok(reservation)
+}
+
+/// Keeps the `result` of the call that reserved `key`
+pub fn remember(conn: &Connection, key: &str, result: &str) -> Result<()> {
+    let query = "UPDATE rpc_idempotency SET result=?2 WHERE key=?1";
+    trace!(target: SQL_TAG, "{}", query);
+    conn.execute(query, params![key, result])?;
+    Ok(())
+}
+
+/// Gives up a reservation of `key` whose call failed, so that retrying it makes the call again
+pub fn release(conn: &Connection, key: &str) -> Result<()> {
+    let query = "DELETE FROM rpc_idempotency WHERE key=?1 AND result IS NULL";
+    trace!(target: SQL_TAG, "{}", query);
+    conn.execute(query, params![key])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql::migrations;
+    use std::sync::{Arc, Barrier};
+
+    #[test]
+    fn test_reserve() -> Result<()> {
+        let mut conn = Connection::open_in_memory()?;
+        migrations::migrate(&mut conn, &crate::common::version_str())?;
+
+        assert_eq!(
+            reserve(&mut conn, "k1", "retag", 100.0)?,
+            Reservation::Reserved
+        );
+        assert_eq!(
+            reserve(&mut conn, "k1", "retag", 150.0)?,
+            Reservation::Running("retag".to_string())
+        );
+        remember(&conn, "k1", r#"{"added":["t1"]}"#)?;
+        assert_eq!(
+            reserve(&mut conn, "k1", "retag", 200.0)?,
+            Reservation::Done("retag".to_string(), r#"{"added":["t1"]}"#.to_string())
+        );
+
+        // a failed call gives its key back
+        assert_eq!(
+            reserve(&mut conn, "k2", "rm", 200.0)?,
+            Reservation::Reserved
+        );
+        release(&conn, "k2")?;
+        assert_eq!(
+            reserve(&mut conn, "k2", "rm", 200.0)?,
+            Reservation::Reserved
+        );
+
+        // and a result is forgotten once it's expired
+        assert_eq!(
+            reserve(&mut conn, "k1", "tags", 100.0 + KEEP_SECS)?,
+            Reservation::Reserved
+        );
+        let kept: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM rpc_idempotency",
+            rusqlite::NO_PARAMS,
+            |row| row.get(0),
+        )?;
+        assert_eq!(kept, 1);
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that of many clients reserving the same key at once, only one gets it
+    fn test_reserve_concurrently() -> Result<()> {
+        let dir = tempfile::tempdir().unwrap();
+        let db_file = dir.path().join("db.sqlite3");
+        migrations::migrate(
+            &mut crate::sql::get_conn(&db_file)?,
+            &crate::common::version_str(),
+        )?;
+
+        let clients = 8;
+        let barrier = Arc::new(Barrier::new(clients));
+        let handles: Vec<_> = (0..clients)
+            .map(|_| {
+                let barrier = barrier.clone();
+                let db_file = db_file.clone();
+                std::thread::spawn(move || -> Result<Reservation> {
+                    let mut conn = crate::sql::get_conn(&db_file)?;
+                    barrier.wait();
+                    reserve(&mut conn, "k1", "retag", 100.0)
+                })
+            })
+            .collect();
+
+        let mut reserved = 0;
+        for handle in handles {
+            match handle.join().unwrap()? {
+                Reservation::Reserved => reserved += 1,
+                other => assert_eq!(other, Reservation::Running("retag".to_string())),
+            }
+        }
+        assert_eq!(reserved, 1);
+        Ok(())
+    }
+}
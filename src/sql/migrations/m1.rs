@@ -0,0 +1,37 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use rusqlite::Result as SqliteResult;
+use rusqlite::{Transaction, NO_PARAMS};
+
+pub fn migrate(tx: &Transaction) -> SqliteResult<()> {
+    // a record of every device/inode symbol pair that a collection has been mounted with.  only one row is ever
+    // "current", the rest have been retired at some version, and are accepted by the parser for a limited number of
+    // releases after that, so that old paths keep resolving
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS symbol_history (
+            id INTEGER PRIMARY KEY NOT NULL,
+            device_char TEXT NOT NULL,
+            inode_char TEXT NOT NULL,
+            first_version TEXT NOT NULL,
+            retired_version TEXT
+        )",
+        NO_PARAMS,
+    )?;
+
+    Ok(())
+}
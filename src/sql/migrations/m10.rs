@@ -0,0 +1,35 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use rusqlite::Result as SqliteResult;
+use rusqlite::{Transaction, NO_PARAMS};
+
+pub fn migrate(tx: &Transaction) -> SqliteResult<()> {
+    // an advisory lock, held by whatever is doing maintenance on the collection, during which writes are held off.
+    // there can be several holders at once, and writes only resume once they've all let go
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS write_gate (
+            holder TEXT PRIMARY KEY,
+            reason TEXT NOT NULL,
+            pid INTEGER NOT NULL,
+            ts FLOAT NOT NULL
+        )",
+        NO_PARAMS,
+    )?;
+
+    Ok(())
+}
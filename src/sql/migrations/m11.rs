@@ -0,0 +1,54 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use super::m3::NOW;
+use rusqlite::Result as SqliteResult;
+use rusqlite::{Transaction, NO_PARAMS};
+
+pub fn migrate(tx: &Transaction) -> SqliteResult<()> {
+    // the names that tags used to have, so that paths using an old name can be redirected to the tag.  a row points at
+    // the tag itself rather than its new name, so that it keeps up with later renames
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS tag_renames (
+            old_name TEXT PRIMARY KEY NOT NULL,
+            new_name TEXT NOT NULL,
+            tag_id INTEGER NOT NULL,
+            ts FLOAT NOT NULL,
+            FOREIGN KEY (tag_id) REFERENCES tags (id) ON DELETE CASCADE
+        )",
+        NO_PARAMS,
+    )?;
+
+    // names starting with a slash can't be real tags, they're where `swap_tags` parks a tag mid-swap
+    tx.execute(
+        &format!(
+            "CREATE TRIGGER IF NOT EXISTS tag_renames_record
+            AFTER UPDATE OF tag_name ON tags
+            WHEN old.tag_name != new.tag_name
+                AND substr(old.tag_name, 1, 1) != '/'
+                AND substr(new.tag_name, 1, 1) != '/'
+            BEGIN
+                INSERT OR REPLACE INTO tag_renames (old_name, new_name, tag_id, ts)
+                VALUES (old.tag_name, new.tag_name, new.id, {now});
+            END",
+            now = NOW
+        ),
+        NO_PARAMS,
+    )?;
+
+    Ok(())
+}
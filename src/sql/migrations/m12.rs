@@ -0,0 +1,31 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use rusqlite::Result as SqliteResult;
+use rusqlite::{Transaction, NO_PARAMS};
+
+pub fn migrate(tx: &Transaction) -> SqliteResult<()> {
+    // a hash of each file's contents, for finding the same file tagged more than once under different paths.  it's
+    // NULL until the file is hashed, and goes back to NULL when the target's size or mtime is seen to change
+    tx.execute("ALTER TABLE files ADD COLUMN content_hash TEXT", NO_PARAMS)?;
+    tx.execute(
+        "CREATE INDEX IF NOT EXISTS files_content_hash ON files (content_hash)",
+        NO_PARAMS,
+    )?;
+
+    Ok(())
+}
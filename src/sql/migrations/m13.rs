@@ -0,0 +1,30 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use rusqlite::Result as SqliteResult;
+use rusqlite::{Transaction, NO_PARAMS};
+
+pub fn migrate(tx: &Transaction) -> SqliteResult<()> {
+    // a checksum of each managed alias file as it was linked, so that `tag verify` can tell when one has been
+    // corrupted.  files linked before this are NULL until `tag verify --record`
+    tx.execute(
+        "ALTER TABLE files ADD COLUMN alias_checksum TEXT",
+        NO_PARAMS,
+    )?;
+
+    Ok(())
+}
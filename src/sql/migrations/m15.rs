@@ -0,0 +1,41 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use rusqlite::Result as SqliteResult;
+use rusqlite::{Transaction, NO_PARAMS};
+
+pub fn migrate(tx: &Transaction) -> SqliteResult<()> {
+    // tag groups nested in other tag groups.  a nested group's tags belong to every group above it, and it's listed
+    // as a directory in its parent group instead of at the top level
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS tag_group_group (
+            parent_id INTEGER NOT NULL,
+            child_id INTEGER NOT NULL,
+            ts FLOAT NOT NULL,
+            PRIMARY KEY (parent_id, child_id),
+            FOREIGN KEY (parent_id) REFERENCES tag_groups (id) ON DELETE CASCADE,
+            FOREIGN KEY (child_id) REFERENCES tag_groups (id) ON DELETE CASCADE
+        )",
+        NO_PARAMS,
+    )?;
+    tx.execute(
+        "CREATE INDEX IF NOT EXISTS tag_group_group_child_id ON tag_group_group (child_id)",
+        NO_PARAMS,
+    )?;
+
+    Ok(())
+}
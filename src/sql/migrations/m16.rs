@@ -0,0 +1,86 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use super::m3::NOW;
+use rusqlite::Result as SqliteResult;
+use rusqlite::{Transaction, NO_PARAMS};
+
+pub fn migrate(tx: &Transaction) -> SqliteResult<()> {
+    // when each file's set of tags last changed, which `tag sync` compares to decide whose tags win.  it's kept up to
+    // date by triggers, so that unlinks cascading from a deleted tag count too
+    tx.execute("ALTER TABLE files ADD COLUMN tags_mtime FLOAT", NO_PARAMS)?;
+    tx.execute("UPDATE files SET tags_mtime=mtime", NO_PARAMS)?;
+    for (name, event, file_id) in &[
+        (
+            "file_tag_inserted",
+            "AFTER INSERT ON file_tag",
+            "new.file_id",
+        ),
+        (
+            "file_tag_deleted",
+            "AFTER DELETE ON file_tag",
+            "old.file_id",
+        ),
+    ] {
+        tx.execute(
+            &format!(
+                "CREATE TRIGGER IF NOT EXISTS tags_mtime_{name} {event}
+                BEGIN
+                    UPDATE files SET tags_mtime={now} WHERE id={file_id};
+                END",
+                name = name,
+                event = event,
+                file_id = file_id,
+                now = NOW
+            ),
+            NO_PARAMS,
+        )?;
+    }
+
+    // files that were removed from the collection, so that `tag sync` removes them from the other side instead of
+    // bringing them back.  tagging a file at the same path again clears its tombstone
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS file_tombstones (
+            path TEXT PRIMARY KEY NOT NULL,
+            content_hash TEXT,
+            ts FLOAT NOT NULL
+        )",
+        NO_PARAMS,
+    )?;
+    tx.execute(
+        &format!(
+            "CREATE TRIGGER IF NOT EXISTS file_tombstones_record
+            AFTER DELETE ON files
+            BEGIN
+                INSERT OR REPLACE INTO file_tombstones (path, content_hash, ts)
+                VALUES (old.path, old.content_hash, {now});
+            END",
+            now = NOW
+        ),
+        NO_PARAMS,
+    )?;
+    tx.execute(
+        "CREATE TRIGGER IF NOT EXISTS file_tombstones_clear
+        AFTER INSERT ON files
+        BEGIN
+            DELETE FROM file_tombstones WHERE path=new.path;
+        END",
+        NO_PARAMS,
+    )?;
+
+    Ok(())
+}
@@ -0,0 +1,286 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use rusqlite::Result as SqliteResult;
+use rusqlite::{params, Transaction, NO_PARAMS};
+
+/// Now, in whole unix milliseconds, the physical half of a hybrid logical clock timestamp
+pub(crate) const NOW_MS: &str = "CAST((julianday('now') - 2440587.5) * 86400000.0 AS INTEGER)";
+
+/// (trigger name, event, condition, op, name, arg, path, content_hash) for every change that's recorded in the oplog.
+/// Files and tags are recorded by what they're called rather than their ids, which mean nothing in another copy of
+/// the database.  Links that are removed because their file or tag was deleted aren't recorded, since the deletion is.
+const OPLOG_TRIGGERS: &[(&str, &str, &str, &str, &str, &str, &str, &str)] = &[
+    (
+        "tags_created",
+        "AFTER INSERT ON tags",
+        "1",
+        "tag_created",
+        "new.tag_name",
+        "NULL",
+        "NULL",
+        "NULL",
+    ),
+    (
+        "tags_renamed",
+        "AFTER UPDATE OF tag_name ON tags",
+        "old.tag_name != new.tag_name",
+        "tag_renamed",
+        "old.tag_name",
+        "new.tag_name",
+        "NULL",
+        "NULL",
+    ),
+    (
+        "tags_deleted",
+        "AFTER DELETE ON tags",
+        "1",
+        "tag_deleted",
+        "old.tag_name",
+        "NULL",
+        "NULL",
+        "NULL",
+    ),
+    (
+        "file_tag_created",
+        "AFTER INSERT ON file_tag",
+        "1",
+        "file_tagged",
+        "(SELECT tag_name FROM tags WHERE id=new.tag_id)",
+        "(SELECT primary_tag FROM files WHERE id=new.file_id)",
+        "(SELECT path FROM files WHERE id=new.file_id)",
+        "(SELECT content_hash FROM files WHERE id=new.file_id)",
+    ),
+    (
+        "file_tag_deleted",
+        "AFTER DELETE ON file_tag",
+        "EXISTS (SELECT 1 FROM tags WHERE id=old.tag_id) AND EXISTS (SELECT 1 FROM files WHERE id=old.file_id)",
+        "file_untagged",
+        "(SELECT tag_name FROM tags WHERE id=old.tag_id)",
+        "NULL",
+        "(SELECT path FROM files WHERE id=old.file_id)",
+        "(SELECT content_hash FROM files WHERE id=old.file_id)",
+    ),
+    (
+        "files_deleted",
+        "AFTER DELETE ON files",
+        "1",
+        "file_deleted",
+        "NULL",
+        "NULL",
+        "old.path",
+        "old.content_hash",
+    ),
+    (
+        "tag_groups_created",
+        "AFTER INSERT ON tag_groups",
+        "1",
+        "group_created",
+        "new.name",
+        "NULL",
+        "NULL",
+        "NULL",
+    ),
+    (
+        "tag_groups_renamed",
+        "AFTER UPDATE OF name ON tag_groups",
+        "old.name != new.name",
+        "group_renamed",
+        "old.name",
+        "new.name",
+        "NULL",
+        "NULL",
+    ),
+    (
+        "tag_groups_deleted",
+        "AFTER DELETE ON tag_groups",
+        "1",
+        "group_deleted",
+        "old.name",
+        "NULL",
+        "NULL",
+        "NULL",
+    ),
+    (
+        "tag_group_tag_created",
+        "AFTER INSERT ON tag_group_tag",
+        "1",
+        "tag_grouped",
+        "(SELECT tag_name FROM tags WHERE id=new.tag_id)",
+        "(SELECT name FROM tag_groups WHERE id=new.tg_id)",
+        "NULL",
+        "NULL",
+    ),
+    (
+        "tag_group_tag_deleted",
+        "AFTER DELETE ON tag_group_tag",
+        "EXISTS (SELECT 1 FROM tags WHERE id=old.tag_id) AND EXISTS (SELECT 1 FROM tag_groups WHERE id=old.tg_id)",
+        "tag_ungrouped",
+        "(SELECT tag_name FROM tags WHERE id=old.tag_id)",
+        "(SELECT name FROM tag_groups WHERE id=old.tg_id)",
+        "NULL",
+        "NULL",
+    ),
+];
+
+/// (op, query) for the ops that build up everything that's already in the database, so that another copy can be
+/// merged with it.  Each query selects (name, arg, path, content_hash).
+const SEED_OPS: &[(&str, &str)] = &[
+    (
+        "tag_created",
+        "SELECT tag_name, NULL, NULL, NULL FROM tags ORDER BY id",
+    ),
+    (
+        "group_created",
+        "SELECT name, NULL, NULL, NULL FROM tag_groups ORDER BY id",
+    ),
+    (
+        "tag_grouped",
+        "SELECT tags.tag_name, tag_groups.name, NULL, NULL
+        FROM tag_group_tag
+        JOIN tags ON tags.id=tag_group_tag.tag_id
+        JOIN tag_groups ON tag_groups.id=tag_group_tag.tg_id
+        ORDER BY tag_groups.id, tags.id",
+    ),
+    (
+        "file_tagged",
+        "SELECT tags.tag_name, files.primary_tag, files.path, files.content_hash
+        FROM file_tag
+        JOIN tags ON tags.id=file_tag.tag_id
+        JOIN files ON files.id=file_tag.file_id
+        ORDER BY files.id, tags.id",
+    ),
+];
+
+type SeedRow = (
+    Option<String>,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+);
+
+pub fn migrate(tx: &Transaction) -> SqliteResult<()> {
+    // a hybrid logical clock for this copy of the database.  `node` tells its timestamps apart from every other
+    // copy's, and `replaying` is set while another copy's ops are applied, so that they aren't recorded again as ours
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS oplog_clock (
+            node TEXT NOT NULL,
+            ms INTEGER NOT NULL,
+            counter INTEGER NOT NULL,
+            replaying INTEGER NOT NULL
+        )",
+        NO_PARAMS,
+    )?;
+
+    // every change to tags, files and tag groups, in the order of their hybrid logical clock timestamps, which are
+    // (ms, counter, node).  two copies of the database that have diverged are merged by exchanging their ops
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS oplog (
+            ms INTEGER NOT NULL,
+            counter INTEGER NOT NULL,
+            node TEXT NOT NULL,
+            op TEXT NOT NULL,
+            name TEXT,
+            arg TEXT,
+            path TEXT,
+            content_hash TEXT,
+            PRIMARY KEY (ms, counter, node)
+        )",
+        NO_PARAMS,
+    )?;
+    tx.execute(
+        "CREATE INDEX IF NOT EXISTS oplog_name ON oplog (name)",
+        NO_PARAMS,
+    )?;
+    tx.execute(
+        "CREATE INDEX IF NOT EXISTS oplog_path ON oplog (path)",
+        NO_PARAMS,
+    )?;
+
+    start_recording(tx)
+}
+
+/// Records every change from now on in the oplog, starting over from ops that build up everything that's already in
+/// the database, under a new node
+pub(crate) fn start_recording(tx: &Transaction) -> SqliteResult<()> {
+    tx.execute("DELETE FROM oplog", NO_PARAMS)?;
+    tx.execute("DELETE FROM oplog_clock", NO_PARAMS)?;
+
+    let (node, ms): (String, i64) = tx.query_row(
+        &format!("SELECT lower(hex(randomblob(8))), {}", NOW_MS),
+        NO_PARAMS,
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    let mut counter = 0;
+    for (op, query) in SEED_OPS {
+        let rows = tx
+            .prepare(query)?
+            .query_map(NO_PARAMS, |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })?
+            .collect::<SqliteResult<Vec<SeedRow>>>()?;
+        for (name, arg, path, content_hash) in rows {
+            tx.execute(
+                "INSERT INTO oplog (ms, counter, node, op, name, arg, path, content_hash)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![ms, counter, node, op, name, arg, path, content_hash],
+            )?;
+            counter += 1;
+        }
+    }
+    tx.execute(
+        "INSERT INTO oplog_clock (node, ms, counter, replaying) VALUES (?1, ?2, ?3, 0)",
+        params![node, ms, counter],
+    )?;
+
+    for (name, event, condition, op, op_name, arg, path, content_hash) in OPLOG_TRIGGERS {
+        tx.execute(
+            &format!(
+                "CREATE TRIGGER IF NOT EXISTS oplog_{name} {event}
+                WHEN (SELECT replaying FROM oplog_clock)=0 AND {condition}
+                BEGIN
+                    UPDATE oplog_clock SET
+                        ms=MAX(ms, {now}),
+                        counter=CASE WHEN {now} > ms THEN 0 ELSE counter + 1 END;
+                    INSERT INTO oplog (ms, counter, node, op, name, arg, path, content_hash)
+                    SELECT ms, counter, node, '{op}', {op_name}, {arg}, {path}, {content_hash} FROM oplog_clock;
+                END",
+                name = name,
+                event = event,
+                condition = condition,
+                now = NOW_MS,
+                op = op,
+                op_name = op_name,
+                arg = arg,
+                path = path,
+                content_hash = content_hash,
+            ),
+            NO_PARAMS,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Stops recording changes in the oplog, and forgets the ops that it recorded
+pub(super) fn stop_recording(tx: &Transaction) -> SqliteResult<()> {
+    for (name, ..) in OPLOG_TRIGGERS {
+        tx.execute(&format!("DROP TRIGGER IF EXISTS oplog_{}", name), NO_PARAMS)?;
+    }
+    tx.execute("DELETE FROM oplog", NO_PARAMS)?;
+    Ok(())
+}
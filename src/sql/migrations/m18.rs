@@ -0,0 +1,29 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use rusqlite::Result as SqliteResult;
+use rusqlite::{Transaction, NO_PARAMS};
+
+pub fn migrate(tx: &Transaction) -> SqliteResult<()> {
+    // whether tags whose names only differ in case are the same tag.  it mirrors the collection's config, which is
+    // applied when the collection is mounted, so that looking up a tag doesn't need the config
+    tx.execute(
+        "ALTER TABLE supertag_meta ADD COLUMN tags_nocase INTEGER NOT NULL DEFAULT 0",
+        NO_PARAMS,
+    )?;
+    Ok(())
+}
@@ -0,0 +1,33 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use crate::common::settings::config::TagForm;
+use rusqlite::Result as SqliteResult;
+use rusqlite::{Transaction, NO_PARAMS};
+
+pub fn migrate(tx: &Transaction) -> SqliteResult<()> {
+    // the unicode normalization form that tag names are kept in.  like `tags_nocase`, it mirrors the collection's
+    // config, which is applied when the collection is mounted
+    tx.execute(
+        "ALTER TABLE supertag_meta ADD COLUMN tag_form TEXT NOT NULL DEFAULT 'nfc'",
+        NO_PARAMS,
+    )?;
+
+    // names were kept however they came in until now, so the same name from the cli and from Finder could be two tags
+    crate::sql::normalize_tags(tx, TagForm::Nfc, crate::sql::get_now_secs())?;
+    Ok(())
+}
@@ -0,0 +1,35 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use rusqlite::Result as SqliteResult;
+use rusqlite::{Transaction, NO_PARAMS};
+
+pub fn migrate(tx: &Transaction) -> SqliteResult<()> {
+    // alternate spellings of a tag.  an alias never exists as a tag itself, it is resolved to its canonical tag
+    // whenever we tag a file with it or intersect with it
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS tag_aliases (
+            alias TEXT PRIMARY KEY NOT NULL,
+            tag_id INTEGER NOT NULL,
+            ts FLOAT NOT NULL,
+            FOREIGN KEY (tag_id) REFERENCES tags (id) ON DELETE CASCADE
+        )",
+        NO_PARAMS,
+    )?;
+
+    Ok(())
+}
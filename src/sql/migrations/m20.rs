@@ -0,0 +1,42 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use rusqlite::Result as SqliteResult;
+use rusqlite::{Transaction, NO_PARAMS};
+
+pub fn migrate(tx: &Transaction) -> SqliteResult<()> {
+    // key/value pairs attached to a file's tagging with one tag, like why or how sure it is, as `rating=5`.  they go
+    // away when the file loses the tag
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS file_tag_meta (
+            file_id INTEGER NOT NULL,
+            tag_id INTEGER NOT NULL,
+            key TEXT NOT NULL,
+            value TEXT NOT NULL,
+            ts FLOAT NOT NULL,
+            PRIMARY KEY (file_id, tag_id, key),
+            FOREIGN KEY (file_id, tag_id) REFERENCES file_tag (file_id, tag_id) ON DELETE CASCADE
+        )",
+        NO_PARAMS,
+    )?;
+    tx.execute(
+        "CREATE INDEX IF NOT EXISTS file_tag_meta_tag_id ON file_tag_meta (tag_id)",
+        NO_PARAMS,
+    )?;
+
+    Ok(())
+}
@@ -0,0 +1,174 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use rusqlite::Result as SqliteResult;
+use rusqlite::{Transaction, NO_PARAMS};
+
+/// The current time in unix seconds, as sqlite computes it, for use inside of triggers
+pub(super) const NOW: &str = "((julianday('now') - 2440587.5) * 86400.0)";
+
+/// (trigger name, trigger event, event op, tag_id, file_id, tag_group_id)
+const EVENT_TRIGGERS: &[(&str, &str, &str, &str, &str, &str)] = &[
+    (
+        "tags_created",
+        "AFTER INSERT ON tags",
+        "tag_created",
+        "new.id",
+        "NULL",
+        "NULL",
+    ),
+    (
+        "tags_renamed",
+        "AFTER UPDATE OF tag_name ON tags WHEN old.tag_name != new.tag_name",
+        "tag_renamed",
+        "new.id",
+        "NULL",
+        "NULL",
+    ),
+    (
+        "tags_deleted",
+        "AFTER DELETE ON tags",
+        "tag_deleted",
+        "old.id",
+        "NULL",
+        "NULL",
+    ),
+    (
+        "files_renamed",
+        "AFTER UPDATE OF primary_tag ON files WHEN old.primary_tag != new.primary_tag",
+        "file_renamed",
+        "NULL",
+        "new.id",
+        "NULL",
+    ),
+    (
+        "files_deleted",
+        "AFTER DELETE ON files",
+        "file_deleted",
+        "NULL",
+        "old.id",
+        "NULL",
+    ),
+    (
+        "file_tag_created",
+        "AFTER INSERT ON file_tag",
+        "file_tagged",
+        "new.tag_id",
+        "new.file_id",
+        "NULL",
+    ),
+    (
+        "file_tag_deleted",
+        "AFTER DELETE ON file_tag",
+        "file_untagged",
+        "old.tag_id",
+        "old.file_id",
+        "NULL",
+    ),
+    (
+        "tag_groups_created",
+        "AFTER INSERT ON tag_groups",
+        "group_created",
+        "NULL",
+        "NULL",
+        "new.id",
+    ),
+    (
+        "tag_groups_renamed",
+        "AFTER UPDATE OF name ON tag_groups WHEN old.name != new.name",
+        "group_renamed",
+        "NULL",
+        "NULL",
+        "new.id",
+    ),
+    (
+        "tag_groups_deleted",
+        "AFTER DELETE ON tag_groups",
+        "group_deleted",
+        "NULL",
+        "NULL",
+        "old.id",
+    ),
+    (
+        "tag_group_tag_created",
+        "AFTER INSERT ON tag_group_tag",
+        "tag_grouped",
+        "new.tag_id",
+        "NULL",
+        "new.tg_id",
+    ),
+    (
+        "tag_group_tag_deleted",
+        "AFTER DELETE ON tag_group_tag",
+        "tag_ungrouped",
+        "old.tag_id",
+        "NULL",
+        "old.tg_id",
+    ),
+];
+
+pub fn migrate(tx: &Transaction) -> SqliteResult<()> {
+    // an append-only feed of changes, for external tools that mirror a collection.  `id` is the cursor they poll
+    // with, so it's AUTOINCREMENT to guarantee it's never reused, even if old events are pruned
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS events (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            op TEXT NOT NULL,
+            tag_id INTEGER,
+            file_id INTEGER,
+            tag_group_id INTEGER,
+            tx_id INTEGER NOT NULL,
+            ts FLOAT NOT NULL
+        )",
+        NO_PARAMS,
+    )?;
+
+    // a single row holding the id of the current write transaction, which is bumped as each one begins
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS event_tx (
+            id INTEGER NOT NULL
+        )",
+        NO_PARAMS,
+    )?;
+    tx.execute(
+        "INSERT INTO event_tx (id) SELECT 0 WHERE NOT EXISTS (SELECT 1 FROM event_tx)",
+        NO_PARAMS,
+    )?;
+
+    // triggers catch every change, no matter which code path makes it
+    for (name, event, op, tag_id, file_id, tag_group_id) in EVENT_TRIGGERS {
+        tx.execute(
+            &format!(
+                "CREATE TRIGGER IF NOT EXISTS event_{name} {event}
+                BEGIN
+                    INSERT INTO events (op, tag_id, file_id, tag_group_id, tx_id, ts)
+                    VALUES ('{op}', {tag_id}, {file_id}, {tag_group_id}, (SELECT id FROM event_tx), {now});
+                END",
+                name = name,
+                event = event,
+                op = op,
+                tag_id = tag_id,
+                file_id = file_id,
+                tag_group_id = tag_group_id,
+                now = NOW,
+            ),
+            NO_PARAMS,
+        )?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,37 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use rusqlite::Result as SqliteResult;
+use rusqlite::{Transaction, NO_PARAMS};
+
+pub fn migrate(tx: &Transaction) -> SqliteResult<()> {
+    // searches that the user has saved under a name, each of which appears as a directory of its live results.  the
+    // query is kept as the terms that `tag search` takes, and is parsed each time the search runs, so that it can
+    // mention tags that don't exist yet
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS saved_searches (
+            name TEXT PRIMARY KEY NOT NULL,
+            query TEXT NOT NULL,
+            extension TEXT,
+            max_age_s INTEGER,
+            ts FLOAT NOT NULL
+        )",
+        NO_PARAMS,
+    )?;
+
+    Ok(())
+}
@@ -0,0 +1,31 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use rusqlite::Result as SqliteResult;
+use rusqlite::{Transaction, NO_PARAMS};
+
+pub fn migrate(tx: &Transaction) -> SqliteResult<()> {
+    // what we last saw of each file's real target, so that it can be reported while the target's device is slow or
+    // offline.  both are NULL until the target has been stat'd
+    tx.execute(
+        "ALTER TABLE files ADD COLUMN target_size INTEGER",
+        NO_PARAMS,
+    )?;
+    tx.execute("ALTER TABLE files ADD COLUMN target_mtime FLOAT", NO_PARAMS)?;
+
+    Ok(())
+}
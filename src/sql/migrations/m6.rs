@@ -0,0 +1,28 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use rusqlite::Result as SqliteResult;
+use rusqlite::{Transaction, NO_PARAMS};
+
+pub fn migrate(tx: &Transaction) -> SqliteResult<()> {
+    // optional, user-facing decoration for each tag.  all are NULL until they're set with `tag edit`
+    tx.execute("ALTER TABLE tags ADD COLUMN color TEXT", NO_PARAMS)?;
+    tx.execute("ALTER TABLE tags ADD COLUMN icon TEXT", NO_PARAMS)?;
+    tx.execute("ALTER TABLE tags ADD COLUMN description TEXT", NO_PARAMS)?;
+
+    Ok(())
+}
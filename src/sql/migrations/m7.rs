@@ -0,0 +1,40 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use rusqlite::Result as SqliteResult;
+use rusqlite::{Transaction, NO_PARAMS};
+
+pub fn migrate(tx: &Transaction) -> SqliteResult<()> {
+    // arbitrary key/value pairs attached to files, like `client=acme`, which can be queried like tags
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS file_meta (
+            file_id INTEGER NOT NULL,
+            key TEXT NOT NULL,
+            value TEXT NOT NULL,
+            ts FLOAT NOT NULL,
+            PRIMARY KEY (file_id, key),
+            FOREIGN KEY (file_id) REFERENCES files (id) ON DELETE CASCADE
+        )",
+        NO_PARAMS,
+    )?;
+    tx.execute(
+        "CREATE INDEX IF NOT EXISTS file_meta_key_value ON file_meta (key, value)",
+        NO_PARAMS,
+    )?;
+
+    Ok(())
+}
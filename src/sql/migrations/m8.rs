@@ -0,0 +1,160 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use rusqlite::Result as SqliteResult;
+use rusqlite::{Transaction, NO_PARAMS};
+
+/// The tables whose changes can be undone.  Each must have a primary key.
+const JOURNALED_TABLES: &[&str] = &[
+    "tags",
+    "files",
+    "file_tag",
+    "tag_groups",
+    "tag_group_tag",
+    "tag_aliases",
+    "file_meta",
+];
+
+/// Only changes made by a transaction that started a journaled operation are recorded
+const JOURNALING: &str = "EXISTS (SELECT 1 FROM undo_ops WHERE tx_id=(SELECT id FROM event_tx))";
+const CURRENT_OP: &str = "(SELECT id FROM undo_ops WHERE tx_id=(SELECT id FROM event_tx))";
+
+/// (name, is primary key) of each of `table`'s columns
+fn columns(tx: &Transaction, table: &str) -> SqliteResult<Vec<(String, bool)>> {
+    tx.prepare(&format!("PRAGMA table_info({})", table))?
+        .query_map(NO_PARAMS, |row| {
+            Ok((row.get(1)?, row.get::<_, i64>(5)? > 0))
+        })?
+        .collect()
+}
+
+/// Renders the SQL expression that quotes each of `cols` of the `row` (`old` or `new`) into a statement, joined by
+/// `sep`, ie `id=' || quote(old.id) || ', name=' || quote(old.name) || '`
+fn quoted(row: &str, cols: &[&str], assign: bool, sep: &str) -> String {
+    cols.iter()
+        .map(|col| {
+            let prefix = if assign {
+                format!("{}=", col)
+            } else {
+                "".to_string()
+            };
+            format!("{}' || quote({}.{}) || '", prefix, row, col)
+        })
+        .collect::<Vec<_>>()
+        .join(sep)
+}
+
+/// (Re)creates the triggers that record, for every change to a journaled table, the statement that reverses it.  A
+/// later migration that changes the columns of a journaled table must call this again, or the new columns won't be
+/// restored.
+pub(super) fn create_undo_triggers(tx: &Transaction) -> SqliteResult<()> {
+    for table in JOURNALED_TABLES {
+        let columns = columns(tx, table)?;
+        let all: Vec<&str> = columns.iter().map(|(name, _)| name.as_str()).collect();
+        let pk: Vec<&str> = columns
+            .iter()
+            .filter(|(_, pk)| *pk)
+            .map(|(name, _)| name.as_str())
+            .collect();
+
+        let reversals = [
+            (
+                "insert",
+                "AFTER INSERT",
+                format!(
+                    "'DELETE FROM {} WHERE {}'",
+                    table,
+                    quoted("new", &pk, true, " AND ")
+                ),
+            ),
+            (
+                "update",
+                "AFTER UPDATE",
+                format!(
+                    "'UPDATE {} SET {} WHERE {}'",
+                    table,
+                    quoted("old", &all, true, ", "),
+                    quoted("new", &pk, true, " AND ")
+                ),
+            ),
+            (
+                "delete",
+                "AFTER DELETE",
+                format!(
+                    "'INSERT INTO {} ({}) VALUES ({})'",
+                    table,
+                    all.join(", "),
+                    quoted("old", &all, false, ", ")
+                ),
+            ),
+        ];
+
+        for (kind, event, reversal) in reversals.iter() {
+            tx.execute(
+                &format!("DROP TRIGGER IF EXISTS undo_{}_{}", table, kind),
+                NO_PARAMS,
+            )?;
+            tx.execute(
+                &format!(
+                    "CREATE TRIGGER undo_{table}_{kind} {event} ON {table} WHEN {journaling}
+                    BEGIN
+                        INSERT INTO undo_rows (op_id, stmt) VALUES ({op}, {reversal});
+                    END",
+                    table = table,
+                    kind = kind,
+                    event = event,
+                    journaling = JOURNALING,
+                    op = CURRENT_OP,
+                    reversal = reversal,
+                ),
+                NO_PARAMS,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+pub fn migrate(tx: &Transaction) -> SqliteResult<()> {
+    // destructive operations that can be undone with `tag undo`.  each belongs to exactly one write transaction
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS undo_ops (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            op TEXT NOT NULL,
+            detail TEXT NOT NULL,
+            tx_id INTEGER NOT NULL UNIQUE,
+            ts FLOAT NOT NULL
+        )",
+        NO_PARAMS,
+    )?;
+
+    // the statements that reverse an operation's changes, which are run newest first
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS undo_rows (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            op_id INTEGER NOT NULL,
+            stmt TEXT NOT NULL,
+            FOREIGN KEY (op_id) REFERENCES undo_ops (id) ON DELETE CASCADE
+        )",
+        NO_PARAMS,
+    )?;
+    tx.execute(
+        "CREATE INDEX IF NOT EXISTS undo_rows_op_id ON undo_rows (op_id)",
+        NO_PARAMS,
+    )?;
+
+    create_undo_triggers(tx)
+}
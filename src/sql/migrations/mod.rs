@@ -15,15 +15,84 @@
  * You should have received a copy of the GNU Affero General Public License
  * along with this program.  If not, see <http://www.gnu.org/licenses/>.
  */
-use log::debug;
 use rusqlite::{params, OptionalExtension, Transaction, TransactionBehavior, NO_PARAMS};
 use rusqlite::{Connection, Result as SqliteResult};
+use tracing::debug;
+
+/// The oplog's triggers are defined along with its tables, so that's where recording is started from
+pub(crate) use m17::start_recording as start_oplog;
 
 mod m0;
+mod m1;
+mod m10;
+mod m11;
+mod m12;
+mod m13;
+mod m14;
+mod m15;
+mod m16;
+mod m17;
+mod m18;
+mod m19;
+mod m2;
+mod m20;
+mod m21;
+mod m22;
+mod m23;
+mod m3;
+mod m4;
+mod m5;
+mod m6;
+mod m7;
+mod m8;
+mod m9;
 type MigrationFunction = Box<dyn Fn(&Transaction) -> SqliteResult<()>>;
 
 const TAG: &str = "migrations";
 
+/// Every migration after m0, in order.  A database that has run all of them is at version `latest_version()`
+fn all_migrations() -> Vec<MigrationFunction> {
+    vec![
+        Box::new(m1::migrate),
+        Box::new(m2::migrate),
+        Box::new(m3::migrate),
+        Box::new(m4::migrate),
+        Box::new(m5::migrate),
+        Box::new(m6::migrate),
+        Box::new(m7::migrate),
+        Box::new(m8::migrate),
+        Box::new(m9::migrate),
+        Box::new(m10::migrate),
+        Box::new(m11::migrate),
+        Box::new(m12::migrate),
+        Box::new(m13::migrate),
+        Box::new(m14::migrate),
+        Box::new(m15::migrate),
+        Box::new(m16::migrate),
+        Box::new(m17::migrate),
+        Box::new(m18::migrate),
+        Box::new(m19::migrate),
+        Box::new(m20::migrate),
+        Box::new(m21::migrate),
+        Box::new(m22::migrate),
+        Box::new(m23::migrate),
+    ]
+}
+
+/// The migration version that this release of supertag brings every database up to
+pub fn latest_version() -> i64 {
+    all_migrations().len() as i64
+}
+
+/// The migration version that a database is currently at
+pub fn current_version(conn: &Connection) -> SqliteResult<i64> {
+    conn.query_row(
+        "SELECT migration_version FROM supertag_meta",
+        NO_PARAMS,
+        |row| Ok(row.get(0)?),
+    )
+}
+
 pub fn migrate(conn: &mut Connection, app_version: &str) -> SqliteResult<()> {
     let maybe_table: Option<String> = conn
         .query_row(
@@ -41,35 +110,27 @@ pub fn migrate(conn: &mut Connection, app_version: &str) -> SqliteResult<()> {
         let _res = tx.commit();
     }
 
-    let migration_version: i64 = conn.query_row(
-        "SELECT migration_version FROM supertag_meta",
-        NO_PARAMS,
-        |row| Ok(row.get(0)?),
-    )?;
+    let migration_version = current_version(conn)?;
     debug!(
         target: TAG,
         "Currently on database version {}", migration_version
     );
 
-    #[allow(unused_mut)]
-    let mut migrations: Vec<MigrationFunction> = vec![];
-    //migrations.push(m1::migrate);
+    let migrations = all_migrations();
 
     for (i, mig) in migrations
         .iter()
         .skip(migration_version as usize)
         .enumerate()
     {
-        debug!(
-            target: TAG,
-            "Running migration {}",
-            (i as i64) + migration_version
-        );
+        // m0 is version 0, so the first entry in `migrations` brings us to version 1
+        let new_version = (i as i64) + migration_version + 1;
+        debug!(target: TAG, "Running migration {}", new_version);
         let tx = conn.transaction_with_behavior(TransactionBehavior::Exclusive)?;
         mig(&tx)?;
         let _res = tx.execute(
             "UPDATE supertag_meta SET migration_version=?1",
-            params![(i as i64) + migration_version],
+            params![new_version],
         )?;
         tx.commit()?;
     }
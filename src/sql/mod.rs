@@ -16,20 +16,37 @@
  * along with this program.  If not, see <http://www.gnu.org/licenses/>.
  */
 
-use rusqlite::{params, Connection, Row, ToSql, Transaction, NO_PARAMS};
+use rusqlite::{params, Connection, Row, ToSql, Transaction, TransactionBehavior, NO_PARAMS};
 use rusqlite::{OptionalExtension, Result};
 
 use crate::common::types::file_perms::{Permissions, UMask};
 use crate::common::types::{DeviceFile, TagCollectible, TagType, UtcDt};
 use libc::{gid_t, mode_t, uid_t};
-use log::{debug, error, info, trace, warn};
-use std::collections::HashSet;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::path::Path;
+use tracing::{debug, error, info, trace, warn};
 
+pub mod cipher;
+pub mod gate;
+pub mod idempotency;
 pub mod migrations;
+pub mod oplog;
+pub mod plan;
+pub mod portable;
+pub mod schema;
+pub mod stats;
+pub mod sync;
 pub mod tpool;
 pub mod types;
+pub mod undo;
 
+#[cfg(test)]
+mod proptests;
+
+use crate::common::identity;
+use crate::common::metrics::METRICS;
+use crate::common::query::Expr;
+use crate::common::settings::config::{Groups, Sort, TagForm};
 use crate::common::settings::Settings;
 use std::borrow::Cow;
 use types::*;
@@ -50,6 +67,7 @@ pub fn get_conn<P: AsRef<Path>>(db_path: P) -> Result<Connection> {
     trace!(target: SQL_TAG, "Opening {:?}", db_path.as_ref());
     let conn = Connection::open(&db_path)?;
     trace!(target: SQL_TAG, "Opened {:?}", db_path.as_ref());
+    cipher::apply_key(&conn, db_path.as_ref())?;
 
     trace!(target: SQL_TAG, "Enabling foreign keys");
     // so we get cascading deletes in our relationship tables
@@ -58,6 +76,7 @@ pub fn get_conn<P: AsRef<Path>>(db_path: P) -> Result<Connection> {
     conn.busy_handler(Some(|num| -> bool {
         if num >= MAX_CONN as i32 {
             error!(target: SQL_TAG, "Timed out waiting for connection lock");
+            METRICS.sqlite_busy_timeout();
             false
         } else {
             warn!(
@@ -65,6 +84,7 @@ pub fn get_conn<P: AsRef<Path>>(db_path: P) -> Result<Connection> {
                 "Sqlite database contention!  Tried {} times to acquire lock.  Trying again soon...",
                 num + 1
             );
+            METRICS.sqlite_busy_retry();
             std::thread::sleep(std::time::Duration::from_millis(100));
             true
         }
@@ -78,6 +98,7 @@ pub fn db_for_collection(settings: &Settings, collection: &str) -> Result<Connec
         "Acquiring db connection for collection {}", collection
     );
     let db_file = settings.db_file(&collection);
+    cipher::unlock(settings, collection)?;
     let conn = crate::sql::get_conn(&db_file)?;
 
     debug!(
@@ -87,6 +108,18 @@ pub fn db_for_collection(settings: &Settings, collection: &str) -> Result<Connec
     Ok(conn)
 }
 
+/// Starts an exclusive transaction for writing to a collection.  Every write should go through here, so that the
+/// events it produces share a tx_id that no other transaction has
+pub fn begin_write(conn: &mut Connection) -> Result<Transaction> {
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Exclusive)?;
+    tx.execute("UPDATE event_tx SET id = id + 1", NO_PARAMS)?;
+    Ok(tx)
+}
+
+fn utcdt_to_float(dt: &UtcDt) -> f64 {
+    dt.timestamp() as f64 + f64::from(dt.timestamp_subsec_nanos()) / 1e+9
+}
+
 fn float_to_utcdt(val: f64) -> UtcDt {
     let secs = val.trunc() as i64;
     let nsecs: u32 = (val.fract() * 1e+9) as u32;
@@ -120,10 +153,24 @@ fn to_taggedfile(row: &Row) -> Result<TaggedFile> {
         gid: row.get(7)?,
         permissions: Permissions::from(row.get::<usize, mode_t>(8)?),
         alias_file: row.get(9)?,
+        target_size: row.get::<usize, Option<i64>>(10)?.map(|size| size as u64),
+        target_mtime: row.get::<usize, Option<f64>>(11)?.map(float_to_utcdt),
+        is_dir: row.get(12)?,
     };
     Ok(tf)
 }
 
+fn to_file_record(row: &Row) -> Result<FileRecord> {
+    Ok(FileRecord {
+        id: row.get(0)?,
+        device: row.get::<usize, i64>(1)? as u64,
+        inode: row.get::<usize, i64>(2)? as u64,
+        path: row.get(3)?,
+        primary_tag: row.get(4)?,
+        target_size: row.get::<usize, Option<i64>>(5)?.map(|size| size as u64),
+    })
+}
+
 fn to_tag_group(row: &Row) -> Result<TagGroup> {
     let tag_ids_str = row.get(6).ok().unwrap_or("".to_string());
     let tag_ids = tag_ids_str
@@ -169,10 +216,12 @@ WHERE id IN ({})
 }
 
 /// Ensures a tag exists in the database. The return value is the authoritative name. This can differ from the name
-/// we pass in, in the case of a plugin-generated tag, where we've renamed the tag after the plugin generated it.
+/// we pass in, in the case of a plugin-generated tag, where we've renamed the tag after the plugin generated it.  A
+/// newly created tag is also added to the tag group that `groups` assigns it, if any.
 pub fn ensure_tag(
     tx: &Transaction,
     tag: &str,
+    groups: &Groups,
     uid: uid_t,
     gid: gid_t,
     permissions: &Permissions,
@@ -180,6 +229,10 @@ pub fn ensure_tag(
 ) -> Result<(String, i64)> {
     debug!(target: SQL_TAG, "Ensuring tag {} exists", tag);
 
+    // aliases are never tags themselves, so tagging with one lands on its canonical tag
+    let resolved = resolve_alias(tx, &tag_form(tx)?.normalize(tag))?;
+    let tag = resolved.as_str();
+
     // we'll use this as the default existing tag for the following scenarios:
     // 1) we're creating a tag that doesn't come from a plugin, in which case we'll use this query.
     // 2) we're creating a tag *from* a plugin, but the plugin doesn't find an existing tag with that plugin_id, BUT
@@ -187,7 +240,7 @@ pub fn ensure_tag(
     //    query
     let maybe_default_tag = tx
         .query_row(
-            "SELECT id, tag_name FROM tags WHERE tag_name=?1",
+            &format!("SELECT id, tag_name FROM tags WHERE {}", TAG_NAME_MATCHES),
             params![tag],
             |row| Ok((row.get(0)?, row.get(1)?)),
         )
@@ -220,6 +273,7 @@ pub fn ensure_tag(
         )?;
 
         let tag_id = get_tag_id(tx, tag)?.expect("No tag id?");
+        apply_group_rules(tx, tag, groups, uid, gid, permissions, now)?;
 
         // creating a new tag should update the root timestamp because all tags live at the root
         update_root_mtime(tx, now)?;
@@ -227,6 +281,73 @@ pub fn ensure_tag(
     }
 }
 
+/// Adds `tag` to the tag group its matching rule names, creating the group if needed.  A tag matched by the rules of
+/// more than one group is ambiguous, so it isn't added to any of them.  Returns the group the tag was added to.
+fn apply_group_rules(
+    tx: &Transaction,
+    tag: &str,
+    groups: &Groups,
+    uid: uid_t,
+    gid: gid_t,
+    permissions: &Permissions,
+    now: f64,
+) -> Result<Option<String>> {
+    match groups.groups_for(tag).as_slice() {
+        [] => Ok(None),
+        [group] => {
+            debug!(target: SQL_TAG, "Tag {} matches group rule for {}", tag, group);
+            ensure_tag_group(tx, group, uid, gid, permissions, now)?;
+            add_tag_to_group(tx, tag, group, uid, gid, permissions, now)?;
+            Ok(Some((*group).to_owned()))
+        }
+        conflicting => {
+            warn!(
+                target: SQL_TAG,
+                "Tag {} matches rules for multiple groups {:?}, not grouping it", tag, conflicting
+            );
+            Ok(None)
+        }
+    }
+}
+
+/// Retroactively applies the tag group rules in `groups` to every existing tag
+pub fn sync_tag_groups(
+    tx: &Transaction,
+    groups: &Groups,
+    uid: uid_t,
+    gid: gid_t,
+    permissions: &Permissions,
+    now: f64,
+) -> Result<GroupSync> {
+    info!(target: SQL_TAG, "Syncing tag groups with {} rules", groups.rules.len());
+
+    let mut sync = GroupSync::default();
+    for tag in get_all_tags(tx)? {
+        let matched = groups.groups_for(&tag.name);
+        if matched.len() > 1 {
+            sync.conflicts.push((
+                tag.name.clone(),
+                matched.iter().map(|g| (*g).to_owned()).collect(),
+            ));
+            continue;
+        }
+
+        let existing: Vec<String> = tag_groups_for_tag(tx, tag.id)?
+            .into_iter()
+            .map(|tg| tg.name)
+            .collect();
+        if matched.iter().all(|g| existing.iter().any(|e| e == g)) {
+            continue;
+        }
+
+        if let Some(group) = apply_group_rules(tx, &tag.name, groups, uid, gid, permissions, now)? {
+            sync.added.push((tag.name, group));
+        }
+    }
+
+    Ok(sync)
+}
+
 pub fn ensure_tag_group(
     tx: &Transaction,
     name: &str,
@@ -283,6 +404,37 @@ pub fn update_tag_mtime(tx: &Transaction, tag: &str, now: f64) -> Result<()> {
     Ok(())
 }
 
+/// Updates the mtime of every tag on the files in `file_ids`.  A change to a file changes every intersection that it's
+/// in, not just the tags that were added or removed, so this keeps file managers watching any of those intersections
+/// up to date.  Files are taken in chunks, so that one update covers many files in a bulk operation.
+pub fn touch_file_tags(tx: &Transaction, file_ids: &[i64], now: f64) -> Result<usize> {
+    let mut touched = 0;
+    for chunk in file_ids.chunks(500) {
+        let ids = chunk
+            .iter()
+            .map(|id| id.to_string())
+            .collect::<Vec<String>>()
+            .join(",");
+
+        let query = format!(
+            "
+            UPDATE tags SET mtime=?1
+            WHERE id IN (SELECT tag_id FROM file_tag WHERE file_id IN ({}))",
+            ids
+        );
+        trace!(target: SQL_TAG, "{}", query);
+
+        touched += tx.execute(&query, params![now])?;
+    }
+    debug!(
+        target: SQL_TAG,
+        "Touched {} tags of {} files",
+        touched,
+        file_ids.len()
+    );
+    Ok(touched)
+}
+
 pub fn update_tag_group_mtime(tx: &Transaction, name: &str, now: f64) -> Result<()> {
     debug!(
         target: SQL_TAG,
@@ -295,6 +447,156 @@ pub fn update_tag_group_mtime(tx: &Transaction, name: &str, now: f64) -> Result<
     Ok(())
 }
 
+/// Fetches the color, icon and description of `tag`, or None if the tag doesn't exist
+pub fn get_tag_meta(conn: &Connection, tag: &str) -> Result<Option<TagMeta>> {
+    debug!(target: SQL_TAG, "Getting tag meta for {}", tag);
+    let query = "SELECT color, icon, description FROM tags WHERE tag_name=?1";
+    trace!(target: SQL_TAG, "{}", query);
+    conn.query_row(query, params![tag], |row| {
+        let color: Option<String> = row.get(0)?;
+        Ok(TagMeta {
+            color: color.and_then(|c| c.parse().ok()),
+            icon: row.get(1)?,
+            description: row.get(2)?,
+        })
+    })
+    .optional()
+}
+
+pub fn set_tag_meta(tx: &Transaction, tag_id: i64, meta: &TagMeta, now: f64) -> Result<()> {
+    info!(target: SQL_TAG, "Setting tag meta for tag id {} to {:?}", tag_id, meta);
+    let query = "UPDATE tags SET color=?2, icon=?3, description=?4, mtime=?5 WHERE id=?1";
+    trace!(target: SQL_TAG, "{}", query);
+    tx.execute(
+        query,
+        params![
+            tag_id,
+            meta.color.map(|c| c.as_str()),
+            meta.icon,
+            meta.description,
+            now
+        ],
+    )?;
+    Ok(())
+}
+
+/// The key/value metadata attached to a file, sorted by key
+pub fn get_file_meta(conn: &Connection, file_id: i64) -> Result<Vec<(String, String)>> {
+    debug!(target: SQL_TAG, "Getting meta for file id {}", file_id);
+    let query = "SELECT key, value FROM file_meta WHERE file_id=?1 ORDER BY key";
+    trace!(target: SQL_TAG, "{}", query);
+    conn.prepare(query)?
+        .query_map(params![file_id], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect()
+}
+
+/// Sets the file's value for `key`, replacing any it had
+pub fn set_file_meta(
+    tx: &Transaction,
+    file_id: i64,
+    key: &str,
+    value: &str,
+    now: f64,
+) -> Result<()> {
+    info!(
+        target: SQL_TAG,
+        "Setting meta {}={} for file id {}", key, value, file_id
+    );
+    let query =
+        "INSERT OR REPLACE INTO file_meta (file_id, key, value, ts) VALUES (?1, ?2, ?3, ?4)";
+    trace!(target: SQL_TAG, "{}", query);
+    tx.execute(query, params![file_id, key, value, now])?;
+    Ok(())
+}
+
+/// Removes the file's value for `key`.  Returns whether it had one.
+pub fn remove_file_meta(tx: &Transaction, file_id: i64, key: &str) -> Result<bool> {
+    info!(target: SQL_TAG, "Removing meta {} from file id {}", key, file_id);
+    let query = "DELETE FROM file_meta WHERE file_id=?1 AND key=?2";
+    trace!(target: SQL_TAG, "{}", query);
+    Ok(tx.execute(query, params![file_id, key])? > 0)
+}
+
+/// The key/value metadata attached to the file's tagging with the tag `tag_id`, sorted by key
+pub fn get_file_tag_meta(
+    conn: &Connection,
+    file_id: i64,
+    tag_id: i64,
+) -> Result<Vec<(String, String)>> {
+    debug!(
+        target: SQL_TAG,
+        "Getting meta for file id {} and tag id {}", file_id, tag_id
+    );
+    let query = "SELECT key, value FROM file_tag_meta WHERE file_id=?1 AND tag_id=?2 ORDER BY key";
+    trace!(target: SQL_TAG, "{}", query);
+    conn.prepare(query)?
+        .query_map(params![file_id, tag_id], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?
+        .collect()
+}
+
+/// Sets the value for `key` on the file's tagging with the tag `tag_id`, replacing any it had.  Returns false if the
+/// file isn't tagged with it.
+pub fn set_file_tag_meta(
+    tx: &Transaction,
+    file_id: i64,
+    tag_id: i64,
+    key: &str,
+    value: &str,
+    now: f64,
+) -> Result<bool> {
+    info!(
+        target: SQL_TAG,
+        "Setting meta {}={} for file id {} and tag id {}", key, value, file_id, tag_id
+    );
+    let query = "
+INSERT OR REPLACE INTO file_tag_meta (file_id, tag_id, key, value, ts)
+SELECT file_id, tag_id, ?3, ?4, ?5
+FROM file_tag
+WHERE file_id=?1 AND tag_id=?2";
+    trace!(target: SQL_TAG, "{}", query);
+    Ok(tx.execute(query, params![file_id, tag_id, key, value, now])? > 0)
+}
+
+/// Removes the value for `key` from the file's tagging with the tag `tag_id`.  Returns whether it had one.
+pub fn remove_file_tag_meta(
+    tx: &Transaction,
+    file_id: i64,
+    tag_id: i64,
+    key: &str,
+) -> Result<bool> {
+    info!(
+        target: SQL_TAG,
+        "Removing meta {} from file id {} and tag id {}", key, file_id, tag_id
+    );
+    let query = "DELETE FROM file_tag_meta WHERE file_id=?1 AND tag_id=?2 AND key=?3";
+    trace!(target: SQL_TAG, "{}", query);
+    Ok(tx.execute(query, params![file_id, tag_id, key])? > 0)
+}
+
+/// The file's rating in stars, if it's been rated
+pub fn get_rating(conn: &Connection, file_id: i64) -> Result<Option<u8>> {
+    debug!(target: SQL_TAG, "Getting rating for file id {}", file_id);
+    conn.query_row(
+        "SELECT rating FROM files WHERE id=?1",
+        params![file_id],
+        |row| row.get(0),
+    )
+}
+
+/// Rates the file with so many stars, or clears its rating if `stars` is None
+pub fn set_rating(tx: &Transaction, file_id: i64, stars: Option<u8>) -> Result<()> {
+    info!(
+        target: SQL_TAG,
+        "Setting rating {:?} for file id {}", stars, file_id
+    );
+    let query = "UPDATE files SET rating=?2 WHERE id=?1";
+    trace!(target: SQL_TAG, "{}", query);
+    tx.execute(query, params![file_id, stars])?;
+    Ok(())
+}
+
 /// Adds a tag to a device/inode pair
 pub fn link_file_to_tag(
     tx: &Transaction,
@@ -355,18 +657,72 @@ pub fn link_file_to_tag(
             params![device as i64, inode as i64, tag, uid, gid, permissions, now],
         )?;
     }
+    if let Some(file_id) = get_file_id(tx, device, inode)? {
+        touch_file_tags(tx, &[file_id], now)?;
+    }
     update_tag_mtime(tx, tag, now)?;
     update_root_mtime(tx, now)?;
     Ok(())
 }
 
+/// The inverse of `link_file_to_tag`.  Returns whether the file was actually tagged with `tag`.
+pub fn unlink_file_from_tag(
+    tx: &Transaction,
+    device: u64,
+    inode: u64,
+    tag: &str,
+    now: f64,
+) -> Result<bool> {
+    debug!(
+        target: SQL_TAG,
+        "Unlinking file {}/{} from tag {}", device, inode, tag
+    );
+    let query = "
+DELETE FROM file_tag
+WHERE
+    file_id=(SELECT id FROM files WHERE device=?1 AND inode=?2)
+    AND tag_id=(SELECT id FROM tags WHERE tag_name=?3)";
+    trace!(target: SQL_TAG, "{}", query);
+    let changed = tx.execute(query, params![device as i64, inode as i64, tag])?;
+
+    if changed > 0 {
+        tx.execute(
+            "UPDATE tags SET num_files = num_files-?1 WHERE tag_name=?2",
+            params![changed as i64, tag],
+        )?;
+        if let Some(file_id) = get_file_id(tx, device, inode)? {
+            touch_file_tags(tx, &[file_id], now)?;
+        }
+        update_tag_mtime(tx, tag, now)?;
+        update_root_mtime(tx, now)?;
+    } else {
+        warn!(target: SQL_TAG, "File-tag doesn't exist, skipping");
+    }
+    Ok(changed > 0)
+}
+
 pub fn get_num_files(conn: &Connection, tags: &[TagType]) -> Result<usize> {
     Ok(files_tagged_with(conn, tags)?.len())
 }
 
+/// Matches the tag named ?1, or if tags are case-insensitive, a tag whose name only differs from it in case.  An exact
+/// match wins, which matters for tags that were created before tags were case-insensitive.
+const TAG_NAME_MATCHES: &str = "
+    (tag_name=?1 OR ((SELECT tags_nocase FROM supertag_meta) AND tag_name=?1 COLLATE NOCASE))
+ORDER BY tag_name=?1 DESC, id
+LIMIT 1";
+
+/// The tag named `tag`.  This reads the form that tag names are kept in first, so callers that look up many tags should
+/// read it once with `tag_form` and use `get_tag_in_form` instead.
 pub fn get_tag(conn: &Connection, tag: &str) -> Result<Option<Tag>> {
+    get_tag_in_form(conn, tag_form(conn)?, tag)
+}
+
+/// The tag named `tag`, where tag names are kept in `form`
+pub fn get_tag_in_form(conn: &Connection, form: TagForm, tag: &str) -> Result<Option<Tag>> {
     info!(target: SQL_TAG, "Getting tag {}", tag);
-    let query = "
+    let query = format!(
+        "
 SELECT
     id,
     tag_name,
@@ -376,17 +732,161 @@ SELECT
     permissions,
     num_files
 FROM tags
-WHERE
-    tag_name=?1
-";
+WHERE {}",
+        TAG_NAME_MATCHES
+    );
+    trace!(target: SQL_TAG, "{}", query);
+    let tag = form.normalize(tag);
+    conn.query_row(&query, params![tag.as_ref()], to_tag)
+        .optional()
+}
+
+/// Makes tags whose names only differ in case the same tag, or not, as the collection's config says
+pub fn set_tags_nocase(conn: &Connection, nocase: bool) -> Result<()> {
+    debug!(target: SQL_TAG, "Setting case-insensitive tags to {}", nocase);
+    conn.execute("UPDATE supertag_meta SET tags_nocase=?1", params![nocase])?;
+    Ok(())
+}
+
+/// Pairs of tags whose names only differ in case, which stay separate tags even once tags are case-insensitive
+pub fn case_collisions(conn: &Connection) -> Result<Vec<(String, String)>> {
+    let query = "
+SELECT a.tag_name, b.tag_name
+FROM tags AS a
+JOIN tags AS b
+    ON a.tag_name=b.tag_name COLLATE NOCASE
+    AND a.id < b.id
+ORDER BY a.id, b.id";
     trace!(target: SQL_TAG, "{}", query);
-    conn.query_row(query, params![tag], to_tag).optional()
+    conn.prepare(query)?
+        .query_map(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect()
+}
+
+/// The form that tag names are kept in, as the collection's config says
+pub fn tag_form(conn: &Connection) -> Result<TagForm> {
+    let form: String = conn.query_row("SELECT tag_form FROM supertag_meta", NO_PARAMS, |row| {
+        row.get(0)
+    })?;
+    Ok(form.parse().unwrap_or_else(|e| {
+        warn!(target: SQL_TAG, "{}, leaving tag names alone", e);
+        TagForm::None
+    }))
+}
+
+/// Keeps tag names in `form`, as the collection's config says.  If it's a different form than before, the tags are
+/// normalized into it.  Returns how many tags were merged into another.
+pub fn set_tag_form(conn: &mut Connection, form: TagForm) -> Result<usize> {
+    if tag_form(conn)? == form {
+        return Ok(0);
+    }
+    debug!(target: SQL_TAG, "Setting tag form to {}", form.as_str());
+    let tx = begin_write(conn)?;
+    tx.execute(
+        "UPDATE supertag_meta SET tag_form=?1",
+        params![form.as_str()],
+    )?;
+    let merged = normalize_tags(&tx, form, get_now_secs())?;
+    tx.commit()?;
+    Ok(merged)
+}
+
+/// Renames every tag into `form`.  Tags whose names are the same once they're in it are merged into one, which keeps
+/// the id of the tag that's already named that way, or else the oldest.  The other tags' files, groups, aliases, pins
+/// and file metadata go to it.  Returns how many tags were merged into another.
+pub fn normalize_tags(tx: &Transaction, form: TagForm, now: f64) -> Result<usize> {
+    info!(target: SQL_TAG, "Normalizing tags to {}", form.as_str());
+    let mut by_name: HashMap<String, Vec<(i64, String)>> = HashMap::new();
+    let tags = tx
+        .prepare("SELECT id, tag_name FROM tags ORDER BY id")?
+        .query_map(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<Vec<(i64, String)>>>()?;
+    for (id, name) in tags {
+        let normalized = form.normalize(&name).into_owned();
+        by_name.entry(normalized).or_default().push((id, name));
+    }
+
+    let mut merged = 0;
+    for (normalized, tags) in by_name {
+        if tags.len() == 1 && tags[0].1 == normalized {
+            continue;
+        }
+        let keep = tags
+            .iter()
+            .find(|(_, name)| *name == normalized)
+            .unwrap_or(&tags[0])
+            .0;
+        for &(id, ref name) in tags.iter().filter(|(id, _)| *id != keep) {
+            debug!(target: SQL_TAG, "Merging tag {} into {}", name, normalized);
+            tx.execute(
+                "INSERT OR IGNORE INTO file_tag (file_id, tag_id, ts, mtime, uid, gid, permissions)
+                SELECT file_id, ?1, ts, ?3, uid, gid, permissions FROM file_tag WHERE tag_id=?2",
+                params![keep, id, now],
+            )?;
+            tx.execute(
+                "UPDATE OR IGNORE file_tag_meta SET tag_id=?1 WHERE tag_id=?2",
+                params![keep, id],
+            )?;
+            tx.execute(
+                "INSERT OR IGNORE INTO tag_group_tag (tg_id, tag_id, ts, mtime, uid, gid, permissions)
+                SELECT tg_id, ?1, ts, ?3, uid, gid, permissions FROM tag_group_tag WHERE tag_id=?2",
+                params![keep, id, now],
+            )?;
+            tx.execute(
+                "UPDATE tag_aliases SET tag_id=?1 WHERE tag_id=?2",
+                params![keep, id],
+            )?;
+            tx.execute(
+                "UPDATE tag_renames SET tag_id=?1 WHERE tag_id=?2",
+                params![keep, id],
+            )?;
+            tx.execute(
+                "UPDATE pins SET tag_ids=substr(replace('/' || tag_ids, ?2, ?1), 2)",
+                params![format!("/t{}/", keep), format!("/t{}/", id)],
+            )?;
+            // what's left of it, like the links to files that the other tag already had, goes with it
+            tx.execute("DELETE FROM tags WHERE id=?1", params![id])?;
+            merged += 1;
+        }
+
+        tx.execute(
+            "UPDATE tags SET
+                tag_name=?2,
+                num_files=(SELECT COUNT(*) FROM file_tag WHERE tag_id=?1)
+            WHERE id=?1",
+            params![keep, normalized],
+        )?;
+        update_tag_mtime(tx, &normalized, now)?;
+        update_root_mtime(tx, now)?;
+    }
+    Ok(merged)
 }
 
+/// The ids of the tag group named ?1 and of every group nested beneath it, as `group_tree(id)`.  The UNION skips
+/// groups it has already seen, so it can't recurse forever.
+const GROUP_TREE: &str = "
+WITH RECURSIVE group_tree(id) AS (
+    SELECT id FROM tag_groups WHERE name=?1
+    UNION
+    SELECT tgg.child_id FROM tag_group_group AS tgg JOIN group_tree ON tgg.parent_id=group_tree.id
+)";
+
+/// Every tag in every tag group, counting the tags of the groups nested beneath it, as `group_tags(tg_id, tag_id)`
+const GROUP_TAGS: &str = "
+WITH RECURSIVE group_tags(tg_id, tag_id) AS (
+    SELECT tg_id, tag_id FROM tag_group_tag
+    UNION
+    SELECT tgg.parent_id, group_tags.tag_id
+    FROM tag_group_group AS tgg
+    JOIN group_tags ON group_tags.tg_id=tgg.child_id
+)";
+
+/// The tags in the tag group `name`, including the tags of the groups nested beneath it
 pub fn get_tags_in_tag_group(conn: &Connection, name: &str) -> Result<Vec<Tag>> {
     debug!(target: SQL_TAG, "Getting tags in tag group {}", name);
-    let query = "
-SELECT
+    let query = format!(
+        "{}
+SELECT DISTINCT
     t.id,
     t.tag_name,
     t.mtime,
@@ -397,16 +897,17 @@ SELECT
 FROM tags AS t
 JOIN tag_group_tag AS tgt
     ON tgt.tag_id=t.id
-JOIN tag_groups AS tg
-    ON tgt.tg_id=tg.id
 WHERE
-    tg.name=?1
-    ";
-    conn.prepare(query)?
+    tgt.tg_id IN (SELECT id FROM group_tree)
+    ",
+        GROUP_TREE
+    );
+    conn.prepare(&query)?
         .query_map(params![name], to_tag)?
         .collect()
 }
 
+/// The tag group named `name`, matched like `get_tag` matches tags when tags are case-insensitive
 pub fn get_tag_group(conn: &Connection, name: &str) -> Result<Option<TagGroup>> {
     debug!(target: SQL_TAG, "Getting tag group by name {}", name);
 
@@ -422,8 +923,10 @@ pub fn get_tag_group(conn: &Connection, name: &str) -> Result<Option<TagGroup>>
         GROUP_CONCAT(tgt.tag_id, ',')
     FROM tag_groups AS tg
     LEFT JOIN tag_group_tag AS tgt ON tgt.tg_id=tg.id
-    WHERE tg.name=?1
+    WHERE tg.name=?1 OR ((SELECT tags_nocase FROM supertag_meta) AND tg.name=?1 COLLATE NOCASE)
     GROUP BY tg.id
+    ORDER BY tg.name=?1 DESC, tg.id
+    LIMIT 1
     ",
         params![name],
         to_tag_group,
@@ -467,8 +970,32 @@ pub fn get_tag_group_by_id(conn: &Connection, id: i64) -> Result<Option<TagGroup
 }
 
 pub fn get_all_tags(conn: &Connection) -> Result<Vec<Tag>> {
-    info!(target: SQL_TAG, "Getting all tags");
-    let query = "
+    get_all_tags_sorted(conn, Sort::Name)
+}
+
+/// The ORDER BY terms for a query of tags, which must select `tag_name`, `mtime` and `num_files`
+fn tag_order(sort: Sort) -> &'static str {
+    match sort {
+        Sort::Name | Sort::Rating => "tag_name",
+        Sort::Mtime => "mtime DESC, tag_name",
+        Sort::NumFiles => "num_files DESC, tag_name",
+    }
+}
+
+/// The ORDER BY terms for a query of the `files` table, which must select `primary_tag` and `mtime`.  The file id
+/// breaks ties, so that the order is stable from one page to the next.
+fn file_order(sort: Sort) -> &'static str {
+    match sort {
+        Sort::Name | Sort::NumFiles => "primary_tag, files.id",
+        Sort::Mtime => "mtime DESC, primary_tag, files.id",
+        Sort::Rating => "files.rating DESC, primary_tag, files.id",
+    }
+}
+
+pub fn get_all_tags_sorted(conn: &Connection, sort: Sort) -> Result<Vec<Tag>> {
+    info!(target: SQL_TAG, "Getting all tags by {:?}", sort);
+    let query = format!(
+        "
     SELECT
         tags.id,
         tags.tag_name,
@@ -478,9 +1005,13 @@ pub fn get_all_tags(conn: &Connection) -> Result<Vec<Tag>> {
         tags.permissions,
         tags.num_files
     FROM tags
-    ORDER BY tag_name";
+    ORDER BY {}",
+        tag_order(sort)
+    );
     trace!(target: SQL_TAG, "{}", query);
-    conn.prepare(query)?.query_map(NO_PARAMS, to_tag)?.collect()
+    conn.prepare(&query)?
+        .query_map(NO_PARAMS, to_tag)?
+        .collect()
 }
 
 /// Returns all of the tag groups
@@ -591,7 +1122,13 @@ pub fn tag_group_intersections(conn: &Connection, tags: &[TagType]) -> Result<Ve
 
     let tag_ids: Vec<i64> = itags.iter().map(|t| t.id).collect();
 
-    for mut tg in tag_groups_for_tags(conn, tag_ids.as_slice())? {
+    // a tag group right after another one can only be a group nested in it
+    let parent = match tags.len().checked_sub(2).map(|idx| &tags[idx]) {
+        Some(TagType::Group(parent)) | Some(TagType::GroupAll(parent)) => Some(parent.as_str()),
+        _ => None,
+    };
+
+    for mut tg in nested_tag_groups_for_tags(conn, tag_ids.as_slice(), parent)? {
         tg.num_files = sum_tag_files;
         tag_groups.insert(tg);
     }
@@ -602,40 +1139,94 @@ pub fn tag_group_intersections(conn: &Connection, tags: &[TagType]) -> Result<Ve
 /// Finds all tags that intersect with the tags of the files tagged with `tags`.
 /// `exclude_provided` will keep `tags` out of the resulting Vec.  This is useful for getting the
 /// subdirectories of a path, where `tags` represents that path, and we don't want `tags` listed as
-/// subdirectories of itself.
+/// subdirectories of itself.  Aliases in `tags` are resolved to their canonical tags.
 pub fn intersect_tag(
     conn: &Connection,
     tags: &[TagType],
     exclude_provided: bool,
+) -> Result<Vec<Tag>> {
+    intersect_tag_sorted(conn, tags, exclude_provided, Sort::Name)
+}
+
+/// Like `intersect_tag`, but ordered by `sort`
+pub fn intersect_tag_sorted(
+    conn: &Connection,
+    tags: &[TagType],
+    exclude_provided: bool,
+    sort: Sort,
+) -> Result<Vec<Tag>> {
+    intersect_tag_impl(conn, tags, exclude_provided, sort, true)
+}
+
+/// Like `intersect_tag_sorted`, but skips counting each tag's files in the intersection, which is most of the work.
+/// The tags' `num_files` are `Tag::UNCOUNTED` and their mtimes are their own, not the intersection's, until
+/// `tag_intersection_stats` fills them in.
+pub fn intersect_tag_uncounted_sorted(
+    conn: &Connection,
+    tags: &[TagType],
+    exclude_provided: bool,
+    sort: Sort,
+) -> Result<Vec<Tag>> {
+    intersect_tag_impl(conn, tags, exclude_provided, sort, false)
+}
+
+fn intersect_tag_impl(
+    conn: &Connection,
+    tags: &[TagType],
+    exclude_provided: bool,
+    sort: Sort,
+    count: bool,
 ) -> Result<Vec<Tag>> {
     debug!(target: SQL_TAG, "Getting tag intersections for {:?}", tags);
 
     // short circuit here if we just want all the tags
     if tags.is_empty() {
-        return get_all_tags(conn);
+        return get_all_tags_sorted(conn, sort);
     }
 
-    let outer_tmpl = "SELECT
+    let resolved = resolve_tag_aliases(conn, tags)?;
+    let tags = resolved.as_slice();
+
+    let counted_tmpl = "SELECT
         tags.id,
         tags.tag_name,
         MAX(file_tag.mtime) as mtime,
         tags.uid,
         tags.gid,
         tags.permissions,
-        COUNT(file_tag.tag_id)
+        COUNT(file_tag.tag_id) AS num_files
     FROM tags
     JOIN file_tag ON tags.id=file_tag.tag_id
     WHERE
         file_tag.file_id IN";
+    let uncounted_tmpl = format!(
+        "SELECT
+        tags.id,
+        tags.tag_name,
+        tags.mtime,
+        tags.uid,
+        tags.gid,
+        tags.permissions,
+        {} AS num_files
+    FROM tags
+    WHERE
+        tags.id IN (SELECT file_tag.tag_id FROM file_tag WHERE file_tag.file_id IN",
+        Tag::UNCOUNTED
+    );
 
     let mut all_params: Vec<Box<dyn ToSql>> = vec![];
 
     let (subquery, params) = intersection_subquery(conn, tags, 0)?;
     all_params.extend(params);
-    let mut query = format!("{} {}", outer_tmpl, subquery);
-
+    let mut query = if count {
+        format!("{} {}", counted_tmpl, subquery)
+    } else {
+        format!("{} {})", uncounted_tmpl, subquery)
+    };
+
     if exclude_provided {
-        let regular_tags = tags.iter().collect_regular_names();
+        let mut regular_tags = tags.iter().collect_regular_names();
+        regular_tags.extend(tags.iter().collect_union_names());
         let exclude_params = make_params(regular_tags.len(), all_params.len());
 
         for t in regular_tags {
@@ -645,7 +1236,10 @@ pub fn intersect_tag(
         query = format!("{} {}", query, outer_where)
     }
 
-    query = format!("{} GROUP BY tags.id ORDER BY tags.tag_name", query);
+    if count {
+        query = format!("{} GROUP BY tags.id", query);
+    }
+    query = format!("{} ORDER BY {}", query, tag_order(sort));
 
     trace!(target: SQL_TAG, "{}", query);
     let isect_tags: Vec<Tag> = conn
@@ -658,7 +1252,7 @@ pub fn intersect_tag(
     // under "b_tags", because "b1" and "a1" might tag the same file, and "b1" is grouped under "b_tags+".  this only
     // really happens in cases where our last tag is a tag group, because otherwise, a tag group is paired with
     // (by immediately preceeding) a regular tag
-    if let Some(TagType::Group(last_group)) = tags.last() {
+    if let Some(TagType::Group(last_group)) | Some(TagType::GroupAll(last_group)) = tags.last() {
         // evaluate the tag group into the tags it represents
         let tag_groups = tag_names_for_tag_group(conn, last_group)?;
         let mut pruned_tags: Vec<Tag> = vec![];
@@ -675,6 +1269,40 @@ pub fn intersect_tag(
     }
 }
 
+/// The number of files that `tag_id` has within the intersection of `parents`, and the newest time one of them was
+/// tagged with it, the same as `intersect_tag_sorted` reports for it
+pub fn tag_intersection_stats(
+    conn: &Connection,
+    parents: &[TagType],
+    tag_id: i64,
+) -> Result<(i64, Option<UtcDt>)> {
+    debug!(
+        target: SQL_TAG,
+        "Counting tag {} within the intersection of {:?}", tag_id, parents
+    );
+    let resolved = resolve_tag_aliases(conn, parents)?;
+
+    let mut all_params: Vec<Box<dyn ToSql>> = vec![Box::new(tag_id)];
+    let (subquery, params) = intersection_subquery(conn, resolved.as_slice(), 1)?;
+    all_params.extend(params);
+
+    let query = format!(
+        "SELECT COUNT(file_tag.tag_id), MAX(file_tag.mtime)
+    FROM file_tag
+    WHERE
+        file_tag.tag_id=?1
+        AND file_tag.file_id IN {}",
+        subquery
+    );
+    trace!(target: SQL_TAG, "{}", query);
+    conn.query_row(&query, all_params, |row| {
+        Ok((
+            row.get(0)?,
+            row.get::<usize, Option<f64>>(1)?.map(float_to_utcdt),
+        ))
+    })
+}
+
 pub fn add_tag_to_group(
     tx: &Transaction,
     tag: &str,
@@ -713,6 +1341,100 @@ INSERT OR IGNORE INTO tag_group_tag (
     Ok(())
 }
 
+/// Nests the tag group `child` in the tag group `parent`.  Callers must make sure that this doesn't nest a group
+/// beneath itself, with `group_is_beneath`.
+pub fn add_group_to_group(tx: &Transaction, child: &str, parent: &str, now: f64) -> Result<()> {
+    info!(
+        target: SQL_TAG,
+        "Nesting tag group {} in tag group {}", child, parent
+    );
+    let query = "
+INSERT OR IGNORE INTO tag_group_group (
+    parent_id,
+    child_id,
+    ts
+) VALUES (
+    (SELECT id FROM tag_groups WHERE name=?1),
+    (SELECT id FROM tag_groups WHERE name=?2),
+    ?3
+)";
+    trace!(target: SQL_TAG, "{}", query);
+    tx.execute(query, params![parent, child, now])?;
+    update_tag_group_mtime(tx, parent, now)?;
+    Ok(())
+}
+
+/// Takes the tag group `child` back out of the tag group `parent`, leaving both groups in place
+pub fn remove_group_from_group(
+    tx: &Transaction,
+    child: &str,
+    parent: &str,
+    now: f64,
+) -> Result<()> {
+    info!(
+        target: SQL_TAG,
+        "Taking tag group {} out of tag group {}", child, parent
+    );
+    let query = "
+DELETE FROM tag_group_group
+WHERE
+    parent_id=(SELECT id FROM tag_groups WHERE name=?1)
+    AND child_id=(SELECT id FROM tag_groups WHERE name=?2)";
+    trace!(target: SQL_TAG, "{}", query);
+    tx.execute(query, params![parent, child])?;
+    update_tag_group_mtime(tx, parent, now)?;
+    Ok(())
+}
+
+/// Returns `true` if `group` is `ancestor` itself, or is nested anywhere beneath it
+pub fn group_is_beneath(conn: &Connection, group: &str, ancestor: &str) -> Result<bool> {
+    let query = format!(
+        "{}
+    SELECT 1
+    FROM group_tree
+    JOIN tag_groups AS tg ON tg.id=group_tree.id
+    WHERE tg.name=?2",
+        GROUP_TREE
+    );
+    trace!(target: SQL_TAG, "{}", query);
+    Ok(conn
+        .query_row(&query, params![ancestor, group], |_| Ok(()))
+        .optional()?
+        .is_some())
+}
+
+/// Returns `true` if the tag group `child` is nested directly in the tag group `parent`
+pub fn group_is_in_group(conn: &Connection, parent: &str, child: &str) -> Result<bool> {
+    let query = "
+SELECT 1
+FROM tag_group_group AS tgg
+JOIN tag_groups AS parent ON parent.id=tgg.parent_id
+JOIN tag_groups AS child ON child.id=tgg.child_id
+WHERE
+    parent.name=?1
+    AND child.name=?2";
+    trace!(target: SQL_TAG, "{}", query);
+    Ok(conn
+        .query_row(query, params![parent, child], |_| Ok(()))
+        .optional()?
+        .is_some())
+}
+
+/// Returns the names of every tag a file is tagged with, alphabetically
+pub fn tags_for_file(conn: &Connection, file_id: i64) -> Result<Vec<String>> {
+    debug!(target: SQL_TAG, "Getting tags for file id {}", file_id);
+    let query = "
+SELECT t.tag_name
+FROM file_tag AS ft
+JOIN tags AS t ON t.id=ft.tag_id
+WHERE ft.file_id=?1
+ORDER BY t.tag_name";
+    trace!(target: SQL_TAG, "{}", query);
+    conn.prepare(query)?
+        .query_map(params![file_id], |row| row.get(0))?
+        .collect()
+}
+
 /// For a `tag_id`, return all of the tag groups it is a part of
 pub fn tag_groups_for_tag(conn: &Connection, tag_id: i64) -> Result<Vec<TagGroup>> {
     debug!(target: SQL_TAG, "Getting tag groups for tag id {}", tag_id);
@@ -739,41 +1461,82 @@ pub fn tag_groups_for_tag(conn: &Connection, tag_id: i64) -> Result<Vec<TagGroup
         .collect()
 }
 
-/// For a `tag_id`, return all of the tag groups it is a part of
+/// For `tag_ids`, return the top-level tag groups that any of them are a part of, directly or through a nested group.
+/// Each group's `tag_ids` are the ones of `tag_ids` it holds.
 pub fn tag_groups_for_tags(conn: &Connection, tag_ids: &[i64]) -> Result<Vec<TagGroup>> {
+    nested_tag_groups_for_tags(conn, tag_ids, None)
+}
+
+/// Like `tag_groups_for_tags`, but returns the groups nested directly in `parent` instead of the top-level groups
+pub fn subgroups_for_tags(
+    conn: &Connection,
+    parent: &str,
+    tag_ids: &[i64],
+) -> Result<Vec<TagGroup>> {
+    nested_tag_groups_for_tags(conn, tag_ids, Some(parent))
+}
+
+fn nested_tag_groups_for_tags(
+    conn: &Connection,
+    tag_ids: &[i64],
+    parent: Option<&str>,
+) -> Result<Vec<TagGroup>> {
     info!(
         target: SQL_TAG,
-        "Getting tag groups for tag ids {:?}", tag_ids
+        "Getting tag groups under {:?} for tag ids {:?}", parent, tag_ids
     );
     if tag_ids.is_empty() {
-        Ok(vec![])
-    } else {
-        let query_tmpl = "SELECT
+        return Ok(vec![]);
+    }
+
+    let mut all_params: Vec<Box<dyn ToSql>> = vec![];
+    for tag_id in tag_ids {
+        all_params.push(Box::new(*tag_id));
+    }
+    let nesting = match parent {
+        Some(parent) => {
+            all_params.push(Box::new(parent.to_string()));
+            format!(
+                "tg.id IN (
+            SELECT tgg.child_id
+            FROM tag_group_group AS tgg
+            JOIN tag_groups AS parent ON parent.id=tgg.parent_id
+            WHERE parent.name=?{})",
+                all_params.len()
+            )
+        }
+        None => "tg.id NOT IN (SELECT child_id FROM tag_group_group)".to_string(),
+    };
+
+    let query = format!(
+        "{}
+    SELECT
         tg.id,
         tg.name,
         tg.mtime,
         tg.uid,
         tg.gid,
         tg.permissions,
-        GROUP_CONCAT(tgt.tag_id, ',')
+        GROUP_CONCAT(gt.tag_id, ',')
     FROM tag_groups AS tg
-    LEFT JOIN tag_group_tag AS tgt ON tgt.tg_id=tg.id
-    WHERE tgt.tag_id IN ";
-        let group_by = "GROUP BY tg.id";
-
-        let in_params = make_params(tag_ids.len(), 0);
-        let query = format!("{} ({}) {}", query_tmpl, in_params, group_by);
-
-        trace!(target: SQL_TAG, "{}", query);
-        trace!(target: SQL_TAG, "Params: {:?}", tag_ids);
+    JOIN group_tags AS gt ON gt.tg_id=tg.id
+    WHERE
+        gt.tag_id IN ({})
+        AND {}
+    GROUP BY tg.id",
+        GROUP_TAGS,
+        make_params(tag_ids.len(), 0),
+        nesting
+    );
+    trace!(target: SQL_TAG, "{}", query);
+    trace!(target: SQL_TAG, "Params: {:?}", tag_ids);
 
-        let res = conn
-            .prepare(&query)?
-            .query_map(tag_ids, to_tag_group)?
-            .collect::<Result<Vec<TagGroup>>>()?;
-        debug!(target: SQL_TAG, "Got {} tag groups", res.len());
-        Ok(res)
-    }
+    let res = conn
+        .prepare(&query)?
+        .query_map(all_params, to_tag_group)?
+        .collect::<Result<Vec<TagGroup>>>()?;
+    debug!(target: SQL_TAG, "Got {} tag groups", res.len());
+    Ok(res)
 }
 
 pub fn contains_file<P>(conn: &Connection, tags: &[TagType], pred: P) -> Result<Option<TaggedFile>>
@@ -788,8 +1551,100 @@ where
     Ok(ifiles.into_iter().find(pred))
 }
 
+/// Like `contains_file`, for a symlink name with a device/inode in it.  A path-identity file gets a new identity when
+/// it's moved, so a name listed before the move falls back to finding it by filename, as long as only one
+/// path-identity file in the intersection has that name.
+pub fn contains_device_file(
+    conn: &Connection,
+    tags: &[TagType],
+    device_file: &DeviceFile,
+) -> Result<Option<TaggedFile>> {
+    let ifiles = files_tagged_with(conn, tags)?;
+    if let Some(tf) = ifiles.iter().find(|tf| device_file.matches(tf)) {
+        return Ok(Some(tf.clone()));
+    }
+    if !identity::is_path_identity(device_file.device) {
+        return Ok(None);
+    }
+
+    debug!(target: SQL_TAG, "Falling back to finding {} by filename", device_file);
+    let mut named = ifiles.into_iter().filter(|tf| {
+        identity::is_path_identity(tf.device) && tf.primary_tag == device_file.filename
+    });
+    match (named.next(), named.next()) {
+        (Some(tf), None) => Ok(Some(tf)),
+        _ => Ok(None),
+    }
+}
+
 /// Finds all files that intersect with all of the provided `tags`
 pub fn files_tagged_with(conn: &Connection, tags: &[TagType]) -> Result<Vec<TaggedFile>> {
+    files_tagged_with_sorted(conn, tags, Sort::Name)
+}
+
+/// Like `files_tagged_with`, but ordered by `sort`
+pub fn files_tagged_with_sorted(
+    conn: &Connection,
+    tags: &[TagType],
+    sort: Sort,
+) -> Result<Vec<TaggedFile>> {
+    select_files_tagged_with(conn, tags, sort, None)
+}
+
+/// Like `files_tagged_with_sorted`, but only the `limit` files after the first `offset`, so that a huge intersection
+/// can be listed a page at a time
+pub fn files_tagged_with_page(
+    conn: &Connection,
+    tags: &[TagType],
+    sort: Sort,
+    offset: usize,
+    limit: usize,
+) -> Result<Vec<TaggedFile>> {
+    select_files_tagged_with(conn, tags, sort, Some((offset, limit)))
+}
+
+/// The names of the files tagged with `tags`, and how many files have each name.  With `only_shared`, names that only
+/// one file has are left out, which is all that's needed to find duplicate names.
+pub fn primary_tag_counts(
+    conn: &Connection,
+    tags: &[TagType],
+    only_shared: bool,
+) -> Result<Vec<(String, usize)>> {
+    let (subquery, params) = intersection_subquery(conn, tags, 0)?;
+    let query = format!(
+        "SELECT primary_tag, COUNT(*) FROM files WHERE id IN {} GROUP BY primary_tag {}",
+        subquery,
+        if only_shared {
+            "HAVING COUNT(*) > 1"
+        } else {
+            ""
+        }
+    );
+    trace!(target: SQL_TAG, "{}", query);
+    conn.prepare(&query)?
+        .query_map(params, |row| {
+            Ok((row.get(0)?, row.get::<_, i64>(1)? as usize))
+        })?
+        .collect()
+}
+
+/// Whether any file is tagged with `tags`, without fetching them all
+pub fn has_files(conn: &Connection, tags: &[TagType]) -> Result<bool> {
+    let (subquery, params) = intersection_subquery(conn, tags, 0)?;
+    let query = format!(
+        "SELECT EXISTS(SELECT 1 FROM file_tag WHERE file_id IN {})",
+        subquery
+    );
+    trace!(target: SQL_TAG, "{}", query);
+    conn.query_row(&query, params, |row| row.get(0))
+}
+
+fn select_files_tagged_with(
+    conn: &Connection,
+    tags: &[TagType],
+    sort: Sort,
+    page: Option<(usize, usize)>,
+) -> Result<Vec<TaggedFile>> {
     // FIXME need GROUP to account for null rows
     let outer_tmpl = "
 SELECT
@@ -802,7 +1657,10 @@ SELECT
     file_tag.uid,
     file_tag.gid,
     file_tag.permissions,
-    alias_file
+    alias_file,
+    target_size,
+    target_mtime,
+    is_dir
 FROM files
 JOIN file_tag ON file_tag.file_id=files.id
 JOIN tags ON file_tag.tag_id=tags.id
@@ -813,11 +1671,15 @@ WHERE
     let (subquery, params) = intersection_subquery(conn, tags, 0)?;
     all_params.extend(params);
 
-    let query = format!(
-        "{outer} {subquery} GROUP BY files.id ORDER BY primary_tag",
+    let mut query = format!(
+        "{outer} {subquery} GROUP BY files.id ORDER BY {order}",
         outer = outer_tmpl,
-        subquery = subquery
+        subquery = subquery,
+        order = file_order(sort)
     );
+    if let Some((offset, limit)) = page {
+        query = format!("{} LIMIT {} OFFSET {}", query, limit, offset);
+    }
 
     trace!(target: SQL_TAG, "{}", query);
     conn.prepare(&query)?
@@ -825,6 +1687,74 @@ WHERE
         .collect()
 }
 
+/// Which of a file's mtimes its date directories are computed from.  The real file's mtime is only known once it's
+/// been stat'd, so until then it's when the file was tagged.
+const DATE_BUCKET_MTIME: &str = "COALESCE(files.target_mtime, files.mtime)";
+
+/// The `strftime` format that renders an mtime as `bucket` is written, ie `2021` or `2021-07`
+fn date_bucket_format(bucket: &str) -> &'static str {
+    if bucket.contains('-') {
+        "%Y-%m"
+    } else {
+        "%Y"
+    }
+}
+
+/// The years, or with `months` the months, that the files tagged with `tags` were last modified in, ie `2021` or
+/// `2021-07`, each with the newest mtime in it.  With no tags, it's every file in the collection.
+pub fn date_buckets(
+    conn: &Connection,
+    tags: &[TagType],
+    months: bool,
+) -> Result<Vec<(String, UtcDt)>> {
+    let (filter, params) = if tags.is_empty() {
+        (String::new(), vec![])
+    } else {
+        let (subquery, params) = intersection_subquery(conn, tags, 0)?;
+        (format!("WHERE id IN {}", subquery), params)
+    };
+    let query = format!(
+        "
+SELECT
+    strftime('{}', mtime, 'unixepoch', 'localtime') AS bucket,
+    MAX(mtime)
+FROM (SELECT {} AS mtime FROM files {})
+GROUP BY bucket
+ORDER BY bucket",
+        if months { "%Y-%m" } else { "%Y" },
+        DATE_BUCKET_MTIME,
+        filter
+    );
+    trace!(target: SQL_TAG, "{}", query);
+    conn.prepare(&query)?
+        .query_map(params, |row| Ok((row.get(0)?, float_to_utcdt(row.get(1)?))))?
+        .collect()
+}
+
+/// The extensions, lowercased and sorted, of the names of the files tagged with `tags`, or of every file in the
+/// collection if there are no tags
+pub fn file_extensions(conn: &Connection, tags: &[TagType]) -> Result<Vec<String>> {
+    let (filter, params) = if tags.is_empty() {
+        (String::new(), vec![])
+    } else {
+        let (subquery, params) = intersection_subquery(conn, tags, 0)?;
+        (format!("WHERE id IN {}", subquery), params)
+    };
+    let query = format!("SELECT DISTINCT primary_tag FROM files {}", filter);
+    trace!(target: SQL_TAG, "{}", query);
+
+    let mut extensions = BTreeSet::new();
+    let mut stmt = conn.prepare(&query)?;
+    for name in stmt.query_map(params, |row| row.get::<_, String>(0))? {
+        if let Some(ext) = Path::new(&name?).extension().and_then(|ext| ext.to_str()) {
+            if !ext.is_empty() {
+                extensions.insert(ext.to_lowercase());
+            }
+        }
+    }
+    Ok(extensions.into_iter().collect())
+}
+
 /// A convenience method that builds a string of sqlite placeholders
 fn make_params(num: usize, offset: usize) -> String {
     let mut param_offset = offset + 1;
@@ -840,8 +1770,12 @@ fn make_params(num: usize, offset: usize) -> String {
 /// should be used in a query that ends in "WHERE file_tag.file_id IN {}"
 /// The basic idea is that, for regular tags, ie "t1", "t2", etc, we want an INTERSECTion of all file ids tagged with
 /// those tags.  For tag groups, ie "t_tags+", we want an INTERSECTion of all files tagged with all tags in the tag
-/// groups.  And for NOT tags, ie "-t3", we want to construct an EXCEPT query that excepts the INTERSECTion of all
-/// NOT tags.
+/// groups, or with every tag in the group if it's an all-of group, ie "t_tags+!".  And for NOT tags, ie "-t3", we want
+/// to leave out every file that has any of them, which is a single NOT IN over all of the NOT tags at once.  A boolean
+/// expression, ie "?(t1&(t2|t3))", is compiled into its own subquery and intersected like a regular tag, and so is a
+/// date, ie "@2021-07", which matches the files that were last modified in that month, and a rating, ie "★4+", which
+/// matches the files rated at least that many stars.  A file type, ie "filetype+/pdf", matches the files whose names
+/// have that extension, and a trailing "filetype+" matches any file with an extension.
 fn intersection_subquery(
     conn: &Connection,
     tags: &[TagType],
@@ -855,11 +1789,25 @@ fn intersection_subquery(
     // first let's separate our intersects from our excepts
     let mut excepts: Vec<Cow<str>> = Vec::new();
     let mut intersects: Vec<Cow<str>> = Vec::new();
+    let mut unions: Vec<&[String]> = Vec::new();
+    let mut metas: Vec<(&str, &str)> = Vec::new();
+    let mut dates: Vec<&str> = Vec::new();
+    let mut ratings: Vec<u8> = Vec::new();
+    let mut filetypes: Vec<&str> = Vec::new();
+    let mut exprs: Vec<&Expr> = Vec::new();
+    let mut everything = false;
     for tag in tags {
         match tag {
             TagType::Regular(name) => intersects.push(Cow::from(name)),
+            TagType::Meta(key, value) => metas.push((key, value)),
+            TagType::Date(bucket) => dates.push(bucket),
+            TagType::Rating(stars) => ratings.push(*stars),
+            TagType::FileType(ext) => filetypes.push(ext),
             TagType::Negation(name) => excepts.push(Cow::from(name)),
-            TagType::Group(_name) => {}
+            TagType::Union(names) => unions.push(names),
+            TagType::Expression(expr) => exprs.push(expr),
+            TagType::CollectionTag(_name) => everything = true,
+            TagType::Group(_name) | TagType::GroupAll(_name) => {}
             _ => {}
         }
     }
@@ -869,18 +1817,20 @@ fn intersection_subquery(
     // is immediately followed by a regular tag.  so in that case, we just consider the regular tag and ignore the
     // group altogether.
     let mut groups: Vec<Cow<str>> = Vec::new();
+    let mut all_of_group = false;
     match tags.last() {
-        Some(TagType::Group(last_group)) => {
+        Some(TagType::Group(last_group)) | Some(TagType::GroupAll(last_group)) => {
             // evaluate the tag group into the tags it represents
             let tag_groups = tag_names_for_tag_group(conn, last_group)?;
-            groups.extend(tag_groups.into_iter().map(Cow::from))
+            groups.extend(tag_groups.into_iter().map(Cow::from));
+            all_of_group = matches!(tags.last(), Some(TagType::GroupAll(_)));
         }
         _ => {}
     }
 
     debug!(
         target: SQL_TAG,
-        "Exceptions: {:?}, intersections: {:?}", excepts, intersects
+        "Exceptions: {:?}, intersections: {:?}, unions: {:?}", excepts, intersects, unions
     );
 
     let mut params: Vec<Box<dyn ToSql>> = vec![];
@@ -905,56 +1855,140 @@ WHERE
 
     let mut param_offset = offset;
 
-    // now let's intersect all our intersects
+    // now let's intersect all our intersects.  the collection's tag has no rows of its own, since every file has it,
+    // so it's every file that's tagged with anything
     let mut intersect_subqueries: Vec<String> = Vec::new();
+    if everything {
+        intersect_subqueries.push("\nSELECT file_tag.file_id FROM file_tag".to_string());
+    }
     for _ in 0..intersects.len() {
         intersect_subqueries.push(format!("{}?{}", intersect_tmpl, param_offset + 1));
         param_offset += 1;
     }
 
+    // file metadata intersects just like a tag does
+    for _ in 0..metas.len() {
+        intersect_subqueries.push(format!(
+            "\nSELECT file_id FROM file_meta WHERE key=?{} AND value=?{}",
+            param_offset + 1,
+            param_offset + 2
+        ));
+        param_offset += 2;
+    }
+
+    // and so do dates, which are computed from the files' mtimes instead of being stored
+    for bucket in &dates {
+        intersect_subqueries.push(format!(
+            "\nSELECT id AS file_id FROM files WHERE strftime('{}', {}, 'unixepoch', 'localtime')=?{}",
+            date_bucket_format(bucket),
+            DATE_BUCKET_MTIME,
+            param_offset + 1
+        ));
+        param_offset += 1;
+    }
+
+    // ratings are stored on the files themselves, and an unrated file has none
+    for stars in &ratings {
+        intersect_subqueries.push(format!(
+            "\nSELECT id AS file_id FROM files WHERE rating >= {}",
+            stars
+        ));
+    }
+
+    // file types are computed from the files' names, matched against the end of the name so that `gz` doesn't match
+    // `tgz`, and so that a hidden file's name isn't taken for its extension
+    for _ in 0..filetypes.len() {
+        intersect_subqueries.push(format!(
+            "\nSELECT id AS file_id FROM files WHERE length(primary_tag) > length(?{0}) + 1 \
+             AND lower(substr(primary_tag, -length(?{0}) - 1))='.' || lower(?{0})",
+            param_offset + 1
+        ));
+        param_offset += 1;
+    }
+    if let Some(TagType::FileTypeGroup) = tags.last() {
+        intersect_subqueries.push(
+            "\nSELECT id AS file_id FROM files WHERE instr(substr(primary_tag, 2), '.') > 0"
+                .to_string(),
+        );
+    }
+
+    // expressions intersect like tags too, each as a tree of subqueries
+    let mut expr_names: Vec<String> = Vec::new();
+    for expr in &exprs {
+        let subquery = expression_subquery(expr, &mut param_offset, &mut expr_names);
+        intersect_subqueries.push(format!("\nSELECT file_id FROM ({})", subquery));
+    }
+
+    // then our unions, each of which matches any file tagged with at least one of its members
+    let mut union_subqueries: Vec<String> = Vec::new();
+    for union in &unions {
+        let union_params = make_params(union.len(), param_offset as usize);
+        union_subqueries.push(format!("{} ({})", group_tmpl, union_params));
+        param_offset += union.len() as i32;
+    }
+
     // and finally our groups
     let mut group_subqueries: Vec<String> = Vec::new();
     if !groups.is_empty() {
         let group_params = make_params(groups.len(), param_offset as usize);
-        let group_subquery = format!("{} ({})", group_tmpl, group_params);
+        let mut group_subquery = format!("{} ({})", group_tmpl, group_params);
+        // an all-of group only keeps the files that matched once for every tag in the group
+        if all_of_group {
+            group_subquery = format!(
+                "{} GROUP BY file_tag.file_id HAVING COUNT(DISTINCT file_tag.tag_id)={}",
+                group_subquery,
+                groups.len()
+            );
+        }
         group_subqueries.push(group_subquery);
         param_offset += groups.len() as i32;
     }
 
-    // and intersect all our excepts
-    let mut except_subqueries: Vec<String> = Vec::new();
-    for _ in 0..excepts.len() {
-        except_subqueries.push(format!("{}?{}", intersect_tmpl, param_offset + 1));
-        param_offset += 1;
-    }
+    // and gather our excepts, a file with any one of them is left out
+    let except_params = make_params(excepts.len(), param_offset as usize);
+    let except_subquery = format!("{} ({})", group_tmpl, except_params);
 
-    let query = if except_subqueries.is_empty() {
+    let has_unions = !union_subqueries.is_empty();
+    let query = if excepts.is_empty() {
         format!(
             "({})",
             intersect_subqueries
                 .into_iter()
+                .chain(union_subqueries.into_iter())
                 .chain(group_subqueries.into_iter())
                 .collect::<Vec<_>>()
                 .join(" INTERSECT "),
         )
     } else {
-        if intersect_subqueries.is_empty() {
+        if intersect_subqueries.is_empty() && !has_unions && group_subqueries.is_empty() {
             "()".to_string()
         } else {
             format!(
-                "(SELECT * FROM ({}) EXCEPT SELECT * FROM ({}))",
+                "(SELECT file_id FROM ({}) WHERE file_id NOT IN ({}))",
                 intersect_subqueries
                     .into_iter()
+                    .chain(union_subqueries.into_iter())
                     .chain(group_subqueries.into_iter())
                     .collect::<Vec<_>>()
                     .join(" INTERSECT "),
-                except_subqueries.join(" INTERSECT ")
+                except_subquery
             )
         }
     };
 
+    let union_names = unions
+        .into_iter()
+        .flat_map(|names| names.iter().map(|n| Cow::from(n.as_str())));
+    let meta_params = metas
+        .into_iter()
+        .flat_map(|(key, value)| vec![Cow::from(key), Cow::from(value)]);
     for tag in intersects
         .into_iter()
+        .chain(meta_params)
+        .chain(dates.into_iter().map(Cow::from))
+        .chain(filetypes.into_iter().map(Cow::from))
+        .chain(expr_names.into_iter().map(Cow::from))
+        .chain(union_names)
         .chain(groups.into_iter())
         .chain(excepts.into_iter())
     {
@@ -964,6 +1998,41 @@ WHERE
     Ok((query, params))
 }
 
+/// Compiles a boolean expression into a query of the ids of the files that it matches, numbering its parameters after
+/// `param_offset` and collecting them into `names`.  Each tag is the files tagged with it, and the operators become
+/// INTERSECT, UNION, and NOT IN over every file.
+fn expression_subquery(expr: &Expr, param_offset: &mut i32, names: &mut Vec<String>) -> String {
+    let compound = |terms: &[Expr], op: &str, param_offset: &mut i32, names: &mut Vec<String>| {
+        terms
+            .iter()
+            .map(|term| {
+                format!(
+                    "SELECT file_id FROM ({})",
+                    expression_subquery(term, param_offset, names)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(op)
+    };
+
+    match expr {
+        Expr::Tag(name) => {
+            *param_offset += 1;
+            names.push(name.to_string());
+            format!(
+                "SELECT file_tag.file_id FROM file_tag JOIN tags ON tags.id=file_tag.tag_id WHERE tags.tag_name=?{}",
+                param_offset
+            )
+        }
+        Expr::Not(inner) => format!(
+            "SELECT id AS file_id FROM files WHERE id NOT IN ({})",
+            expression_subquery(inner, param_offset, names)
+        ),
+        Expr::And(terms) => compound(terms, " INTERSECT ", param_offset, names),
+        Expr::Or(terms) => compound(terms, " UNION ", param_offset, names),
+    }
+}
+
 pub fn add_file(
     tx: &Transaction,
     device_id: u64,
@@ -971,6 +2040,7 @@ pub fn add_file(
     path: &str,
     primary_tag: &str,
     tags: &[&str],
+    groups: &Groups,
     uid: uid_t,
     gid: gid_t,
     umask: &UMask,
@@ -1022,7 +2092,7 @@ INSERT OR IGNORE INTO files (
         debug!(target: SQL_TAG, "Linking to tag {}", tag);
 
         // auth = authoritative
-        let (auth_tag, _) = ensure_tag(tx, tag, uid, gid, &umask.dir_perms(), now)?;
+        let (auth_tag, _) = ensure_tag(tx, tag, groups, uid, gid, &umask.dir_perms(), now)?;
         debug!(target: SQL_TAG, "Resolving tag {} to {}", tag, auth_tag);
 
         link_file_to_tag(
@@ -1047,6 +2117,9 @@ INSERT OR IGNORE INTO files (
             gid,
             permissions: umask.file_perms().clone(),
             alias_file: alias_file.map(ToOwned::to_owned),
+            target_size: None,
+            target_mtime: None,
+            is_dir: false,
         };
 
         tagged.push(tf);
@@ -1056,8 +2129,123 @@ INSERT OR IGNORE INTO files (
     Ok(tagged)
 }
 
+/// The batched equivalent of `add_file`, for adding many files at once within a single transaction.  Each distinct tag
+/// is only ensured once, the statements are prepared once and reused, and tag file counts are updated once per tag at
+/// the end.  Returns how many new file-tag links were created.
+pub fn add_files(
+    tx: &Transaction,
+    files: &[NewFile],
+    groups: &Groups,
+    uid: uid_t,
+    gid: gid_t,
+    umask: &UMask,
+    now: f64,
+) -> Result<usize> {
+    info!(target: SQL_TAG, "Adding a batch of {} files", files.len());
+
+    let file_query = "
+INSERT OR IGNORE INTO files (
+    device,
+    inode,
+    path,
+    primary_tag,
+    ts,
+    mtime
+) VALUES (
+    ?1,
+    ?2,
+    ?3,
+    ?4,
+    ?5,
+    ?5
+)";
+    trace!(target: SQL_TAG, "{}", file_query);
+
+    let link_query = "
+INSERT OR IGNORE INTO file_tag (
+    file_id,
+    tag_id,
+    ts,
+    mtime,
+    uid,
+    gid,
+    permissions
+) VALUES (
+    (SELECT id FROM files WHERE device = ?1 AND inode = ?2),
+    ?3,
+    ?7,
+    ?7,
+    ?4,
+    ?5,
+    ?6
+)";
+    trace!(target: SQL_TAG, "{}", link_query);
+
+    let mut file_stmt = tx.prepare_cached(file_query)?;
+    let mut link_stmt = tx.prepare_cached(link_query)?;
+    let mut id_stmt = tx.prepare_cached("SELECT id FROM files WHERE device=?1 AND inode=?2")?;
+
+    // tag name -> tag id, and tag id -> how many files we've newly linked to it
+    let mut tag_ids: HashMap<&str, i64> = HashMap::new();
+    let mut new_links: HashMap<i64, i64> = HashMap::new();
+    let mut file_ids = Vec::with_capacity(files.len());
+
+    for file in files {
+        file_stmt.execute(params![
+            file.device as i64,
+            file.inode as i64,
+            file.path,
+            file.primary_tag,
+            now
+        ])?;
+        file_ids.push(
+            id_stmt.query_row(params![file.device as i64, file.inode as i64], |row| {
+                row.get::<usize, i64>(0)
+            })?,
+        );
+
+        for tag in file.tags.iter() {
+            let tag_id = match tag_ids.get(tag.as_str()) {
+                Some(tag_id) => *tag_id,
+                None => {
+                    let (_, tag_id) =
+                        ensure_tag(tx, tag, groups, uid, gid, &umask.dir_perms(), now)?;
+                    tag_ids.insert(tag.as_str(), tag_id);
+                    tag_id
+                }
+            };
+
+            let linked = link_stmt.execute(params![
+                file.device as i64,
+                file.inode as i64,
+                tag_id,
+                uid,
+                gid,
+                umask.file_perms(),
+                now
+            ])?;
+            *new_links.entry(tag_id).or_insert(0) += linked as i64;
+        }
+    }
+
+    let mut count_stmt =
+        tx.prepare_cached("UPDATE tags SET num_files = num_files+?1, mtime=?3 WHERE id=?2")?;
+    for (tag_id, linked) in new_links.iter() {
+        if *linked > 0 {
+            count_stmt.execute(params![linked, tag_id, now])?;
+        }
+    }
+    touch_file_tags(tx, &file_ids, now)?;
+
+    update_root_mtime(tx, now)?;
+    Ok(new_links.values().sum::<i64>() as usize)
+}
+
 pub fn purge_devicefile(tx: &Transaction, df: &DeviceFile, now: f64) -> Result<()> {
     info!(target: SQL_TAG, "Purging {:?}", df);
+    if let Some(file_id) = get_file_id(tx, df.device, df.inode)? {
+        touch_file_tags(tx, &[file_id], now)?;
+    }
 
     // update tag count
     let query = "
@@ -1090,6 +2278,11 @@ WHERE
 
 pub fn purge_path(tx: &Transaction, path: &str, now: f64) -> Result<()> {
     info!(target: SQL_TAG, "Purging {}", path);
+    let file_ids = tx
+        .prepare("SELECT id FROM files WHERE path=?1")?
+        .query_map(params![path], |row| Ok(row.get(0)?))?
+        .collect::<Result<Vec<i64>>>()?;
+    touch_file_tags(tx, &file_ids, now)?;
 
     let query = "
 UPDATE
@@ -1131,6 +2324,9 @@ pub fn remove_devicefile(
         |row| row.get(0),
     )?;
 
+    // the removed tags are touched too, since they're still on the file at this point
+    touch_file_tags(tx, &[file_id], now)?;
+
     let mut all_removed_ids = vec![];
     for &tag in tags {
         let query1 = "
@@ -1180,6 +2376,8 @@ pub fn remove_links(
     let mut all_removed_ids = vec![];
     let maybe_tf = contains_file(tx, tags, |tf| &tf.primary_tag == primary_tag)?;
     if let Some(tf) = maybe_tf {
+        // the removed tags are touched too, since they're still on the file at this point
+        touch_file_tags(tx, &[tf.id], now)?;
         for tag in tags.iter().collect_regular_names() {
             let query1 = "
 SELECT rowid
@@ -1235,13 +2433,205 @@ pub fn get_root_mtime(conn: &Connection) -> Result<UtcDt> {
         .unwrap_or_else(|| chrono::Utc::now()))
 }
 
-fn update_root_mtime(tx: &Transaction, now: f64) -> Result<usize> {
-    debug!(target: SQL_TAG, "Updating root mtime to {}", now);
-    tx.execute("UPDATE supertag_meta SET root_mtime=?1", params![now])
-}
-
-pub fn get_tag_id(conn: &Connection, tag: &str) -> Result<Option<i64>> {
-    debug!(target: SQL_TAG, "Getting tag id for {}", tag);
+/// Records what we last saw of a tagged file's real target, so that it can be reported later without touching the
+/// target's device.  If the target's size or mtime has changed, its content hash is forgotten.
+pub fn set_target_metadata(
+    tx: &Transaction,
+    device: u64,
+    inode: u64,
+    size: u64,
+    mtime: &UtcDt,
+) -> Result<usize> {
+    debug!(
+        target: SQL_TAG,
+        "Recording target size {} and mtime {} for {}/{}", size, mtime, device, inode
+    );
+    let query = "
+UPDATE files SET
+    content_hash=CASE WHEN target_size IS ?3 AND target_mtime IS ?4 THEN content_hash ELSE NULL END,
+    target_size=?3,
+    target_mtime=?4
+WHERE device=?1 AND inode=?2";
+    trace!(target: SQL_TAG, "{}", query);
+    tx.execute(
+        query,
+        params![
+            device as i64,
+            inode as i64,
+            size as i64,
+            utcdt_to_float(mtime)
+        ],
+    )
+}
+
+/// Records that a tagged file is a directory, so that the mount lists it as one
+pub fn set_is_dir(tx: &Transaction, device: u64, inode: u64) -> Result<usize> {
+    debug!(target: SQL_TAG, "Marking {}/{} as a directory", device, inode);
+    let query = "UPDATE files SET is_dir=1 WHERE device=?1 AND inode=?2";
+    trace!(target: SQL_TAG, "{}", query);
+    tx.execute(query, params![device as i64, inode as i64])
+}
+
+/// Records the hash of a tagged file's contents, for finding duplicates
+pub fn set_content_hash(tx: &Transaction, device: u64, inode: u64, hash: &str) -> Result<usize> {
+    debug!(target: SQL_TAG, "Recording content hash {} for {}/{}", hash, device, inode);
+    let query = "UPDATE files SET content_hash=?3 WHERE device=?1 AND inode=?2";
+    trace!(target: SQL_TAG, "{}", query);
+    tx.execute(query, params![device as i64, inode as i64, hash])
+}
+
+/// Records the checksum of a managed alias file, on the tagged file that was created from it
+pub fn set_alias_checksum(tx: &Transaction, alias_file: &str, checksum: &str) -> Result<usize> {
+    debug!(target: SQL_TAG, "Recording checksum {} for alias file {}", checksum, alias_file);
+    let query = "UPDATE files SET alias_checksum=?2 WHERE alias_file=?1";
+    trace!(target: SQL_TAG, "{}", query);
+    tx.execute(query, params![alias_file, checksum])
+}
+
+/// Every tagged file that was created from a managed alias file
+pub fn get_managed_files(conn: &Connection) -> Result<Vec<ManagedRecord>> {
+    let query = "SELECT id, path, alias_file, alias_checksum FROM files WHERE alias_file IS NOT NULL ORDER BY id";
+    trace!(target: SQL_TAG, "{}", query);
+    conn.prepare(query)?
+        .query_map(NO_PARAMS, |row| {
+            Ok(ManagedRecord {
+                id: row.get(0)?,
+                path: row.get(1)?,
+                alias_file: row.get(2)?,
+                checksum: row.get(3)?,
+            })
+        })?
+        .collect()
+}
+
+/// The tagged files whose contents haven't been hashed, or every tagged file if `all`
+pub fn get_unhashed_files(conn: &Connection, all: bool) -> Result<Vec<FileRecord>> {
+    let query = format!(
+        "SELECT id, device, inode, path, primary_tag, target_size FROM files {} ORDER BY id",
+        if all {
+            ""
+        } else {
+            "WHERE content_hash IS NULL"
+        }
+    );
+    trace!(target: SQL_TAG, "{}", query);
+    conn.prepare(&query)?
+        .query_map(NO_PARAMS, to_file_record)?
+        .collect()
+}
+
+/// Whether any two tagged files have the same content hash
+pub fn has_duplicate_files(conn: &Connection) -> Result<bool> {
+    let query = "
+SELECT EXISTS (
+    SELECT 1 FROM files
+    WHERE content_hash IS NOT NULL
+    GROUP BY content_hash
+    HAVING COUNT(*) > 1
+)";
+    trace!(target: SQL_TAG, "{}", query);
+    conn.query_row(query, NO_PARAMS, |row| row.get(0))
+}
+
+/// Returns `true` if any file in the collection has had all of its tags removed
+pub fn has_untagged_files(conn: &Connection) -> Result<bool> {
+    let query = "
+SELECT EXISTS (
+    SELECT 1 FROM files
+    WHERE NOT EXISTS (SELECT 1 FROM file_tag WHERE file_tag.file_id=files.id)
+)";
+    trace!(target: SQL_TAG, "{}", query);
+    conn.query_row(query, NO_PARAMS, |row| row.get(0))
+}
+
+/// Every file in the collection that has had all of its tags removed, by name.  Without any tag links to take them
+/// from, their owner and permissions are zeroed, for the caller to fill in.
+pub fn get_untagged_files(conn: &Connection) -> Result<Vec<TaggedFile>> {
+    let query = "
+SELECT
+    files.id,
+    inode,
+    device,
+    path,
+    primary_tag,
+    mtime,
+    0,
+    0,
+    0,
+    alias_file,
+    target_size,
+    target_mtime,
+    is_dir
+FROM files
+WHERE NOT EXISTS (SELECT 1 FROM file_tag WHERE file_tag.file_id=files.id)
+ORDER BY primary_tag, files.id";
+    trace!(target: SQL_TAG, "{}", query);
+    conn.prepare(query)?
+        .query_map(NO_PARAMS, to_taggedfile)?
+        .collect()
+}
+
+/// Every tagged file whose content hash is shared with another tagged file, with its hash.  Files with the same hash
+/// are next to each other.
+pub fn get_duplicate_files(conn: &Connection) -> Result<Vec<(String, TaggedFile)>> {
+    let query = "
+SELECT
+    files.id,
+    inode,
+    device,
+    path,
+    primary_tag,
+    MAX(file_tag.mtime) as mtime,
+    file_tag.uid,
+    file_tag.gid,
+    file_tag.permissions,
+    alias_file,
+    target_size,
+    target_mtime,
+    is_dir,
+    content_hash
+FROM files
+JOIN file_tag ON file_tag.file_id=files.id
+WHERE content_hash IN (
+    SELECT content_hash FROM files
+    WHERE content_hash IS NOT NULL
+    GROUP BY content_hash
+    HAVING COUNT(*) > 1
+)
+GROUP BY files.id
+ORDER BY content_hash, path";
+    trace!(target: SQL_TAG, "{}", query);
+    conn.prepare(query)?
+        .query_map(NO_PARAMS, |row| Ok((row.get(13)?, to_taggedfile(row)?)))?
+        .collect()
+}
+
+/// The version of supertag that last opened the database
+pub fn get_supertag_version(conn: &Connection) -> Result<String> {
+    conn.query_row(
+        "SELECT supertag_version FROM supertag_meta",
+        NO_PARAMS,
+        |row| Ok(row.get(0)?),
+    )
+}
+
+/// The problems that sqlite's `PRAGMA quick_check` finds with the database, if any.  Reading the database first rolls
+/// back a transaction that was interrupted by a crash.
+pub fn quick_check(conn: &Connection) -> Result<Vec<String>> {
+    let problems = conn
+        .prepare("PRAGMA quick_check")?
+        .query_map(NO_PARAMS, |row| row.get(0))?
+        .collect::<Result<Vec<String>>>()?;
+    Ok(problems.into_iter().filter(|p| p != "ok").collect())
+}
+
+fn update_root_mtime(tx: &Transaction, now: f64) -> Result<usize> {
+    debug!(target: SQL_TAG, "Updating root mtime to {}", now);
+    tx.execute("UPDATE supertag_meta SET root_mtime=?1", params![now])
+}
+
+pub fn get_tag_id(conn: &Connection, tag: &str) -> Result<Option<i64>> {
+    debug!(target: SQL_TAG, "Getting tag id for {}", tag);
     conn.query_row(
         "SELECT id FROM tags WHERE tag_name=?1",
         params![tag],
@@ -1250,6 +2640,98 @@ pub fn get_tag_id(conn: &Connection, tag: &str) -> Result<Option<i64>> {
     .optional()
 }
 
+/// Makes `alias` an alternate spelling of the tag with id `tag_id`.  Re-adding an existing alias points it at the new
+/// tag.
+pub fn add_alias(tx: &Transaction, alias: &str, tag_id: i64, now: f64) -> Result<()> {
+    info!(target: SQL_TAG, "Adding alias {} for tag id {}", alias, tag_id);
+    let query = "INSERT OR REPLACE INTO tag_aliases (alias, tag_id, ts) VALUES (?1, ?2, ?3)";
+    trace!(target: SQL_TAG, "{}", query);
+    tx.execute(query, params![alias, tag_id, now])?;
+    Ok(())
+}
+
+/// Resolves `tag` to the name of its canonical tag, if it is an alias.  Otherwise `tag` is returned unchanged.
+pub fn resolve_alias(conn: &Connection, tag: &str) -> Result<String> {
+    let query = "
+SELECT tags.tag_name
+FROM tag_aliases
+JOIN tags
+    ON tags.id=tag_aliases.tag_id
+WHERE
+    tag_aliases.alias=?1";
+    trace!(target: SQL_TAG, "{}", query);
+    let maybe_canonical: Option<String> = conn
+        .query_row(query, params![tag], |row| Ok(row.get(0)?))
+        .optional()?;
+
+    match maybe_canonical {
+        Some(canonical) => {
+            debug!(target: SQL_TAG, "Resolved alias {} to {}", tag, canonical);
+            Ok(canonical)
+        }
+        None => Ok(tag.to_string()),
+    }
+}
+
+/// Returns all of the aliases of a tag, sorted
+pub fn aliases_for_tag(conn: &Connection, tag: &str) -> Result<Vec<String>> {
+    let query = "
+SELECT tag_aliases.alias
+FROM tag_aliases
+JOIN tags
+    ON tags.id=tag_aliases.tag_id
+WHERE
+    tags.tag_name=?1
+ORDER BY tag_aliases.alias";
+    trace!(target: SQL_TAG, "{}", query);
+    conn.prepare(query)?
+        .query_map(params![tag], |row| Ok(row.get(0)?))?
+        .collect::<Result<Vec<String>>>()
+}
+
+/// The current name of the tag that was renamed from `old_name`, if that happened at or after `since` and no tag has
+/// taken the old name since
+pub fn renamed_tag(conn: &Connection, old_name: &str, since: f64) -> Result<Option<String>> {
+    let query = "
+SELECT tags.tag_name
+FROM tag_renames
+JOIN tags
+    ON tags.id=tag_renames.tag_id
+WHERE
+    tag_renames.old_name=?1
+    AND tag_renames.ts>=?2
+    AND NOT EXISTS (SELECT 1 FROM tags WHERE tag_name=?1)";
+    trace!(target: SQL_TAG, "{}", query);
+    conn.query_row(query, params![old_name, since], |row| Ok(row.get(0)?))
+        .optional()
+}
+
+/// Resolves the aliases of every tag name in `tags`
+fn resolve_tag_aliases(conn: &Connection, tags: &[TagType]) -> Result<Vec<TagType>> {
+    let mut resolved = Vec::with_capacity(tags.len());
+    for tt in tags {
+        resolved.push(match tt {
+            TagType::Regular(tag) => TagType::Regular(resolve_alias(conn, tag)?),
+            TagType::Negation(tag) => TagType::Negation(resolve_alias(conn, tag)?),
+            TagType::Union(members) => {
+                let mut names: Vec<String> = vec![];
+                for member in members {
+                    let name = resolve_alias(conn, member)?;
+                    if !names.contains(&name) {
+                        names.push(name);
+                    }
+                }
+                TagType::Union(names)
+            }
+            TagType::Expression(expr) => {
+                TagType::Expression(expr.try_map_tags(&mut |name| resolve_alias(conn, name))?)
+            }
+            _ => tt.to_owned(),
+        });
+    }
+    Ok(resolved)
+}
+
 pub fn get_tag_group_id(conn: &Connection, group: &str) -> Result<Option<i64>> {
     debug!(target: SQL_TAG, "Getting group tag id for {}", group);
     conn.query_row(
@@ -1305,6 +2787,7 @@ pub fn remove_tag_from_intersection(
     tx: &Transaction,
     tag: &str,
     intersect: &[TagType],
+    keep: &[i64],
     now: f64,
 ) -> Result<Vec<TaggedFile>> {
     info!(
@@ -1312,9 +2795,13 @@ pub fn remove_tag_from_intersection(
         "Deleting tag {} from tag intersection {:?}", tag, intersect
     );
     let mut total_removed = 0;
-    let files = files_tagged_with(tx, intersect)?;
+    let mut files = files_tagged_with(tx, intersect)?;
+    files.retain(|f| !keep.contains(&f.id));
     let tag_id = get_tag_id(tx, tag)?.ok_or(rusqlite::Error::QueryReturnedNoRows)?;
 
+    let file_ids = files.iter().map(|f| f.id).collect::<Vec<i64>>();
+    touch_file_tags(tx, &file_ids, now)?;
+
     // let's do our deletes in chunks so we don't blow up sqlite
     for chunk in files.chunks(500) {
         let ids = chunk
@@ -1356,6 +2843,15 @@ pub fn remove_tag(tx: &Transaction, tag: &str, now: f64, immediate: bool) -> Res
         "Deleting tag {}, immediate: {}", tag, immediate
     );
 
+    // every intersection that one of the tag's files is in loses it
+    let file_ids = tx
+        .prepare(
+            "SELECT file_id FROM file_tag WHERE tag_id=(SELECT id FROM tags WHERE tag_name=?1)",
+        )?
+        .query_map(params![tag], |row| Ok(row.get(0)?))?
+        .collect::<Result<Vec<i64>>>()?;
+    touch_file_tags(tx, &file_ids, now)?;
+
     // TODO is immediate required anymore?
     if immediate {
         let query1 = "DELETE FROM tags WHERE tag_name=?1";
@@ -1388,12 +2884,14 @@ WHERE file_tag.tag_id=(
 /// Renames a tag
 pub fn rename_tag(tx: &Transaction, old_tag: &str, new_tag: &str, now: f64) -> Result<()> {
     info!(target: SQL_TAG, "Renaming tag {} to {}", old_tag, new_tag);
+    let form = tag_form(tx)?;
+    let new_tag = form.normalize(new_tag);
     tx.execute(
         "UPDATE tags SET tag_name=?1 WHERE tag_name=?2",
-        params![new_tag, old_tag],
+        params![new_tag.as_ref(), form.normalize(old_tag).as_ref()],
     )?;
 
-    update_tag_mtime(tx, new_tag, now)?;
+    update_tag_mtime(tx, &new_tag, now)?;
     update_root_mtime(tx, now)?;
     Ok(())
 }
@@ -1412,13 +2910,87 @@ pub fn rename_tag_group(tx: &Transaction, old_name: &str, new_name: &str, now: f
     Ok(())
 }
 
+/// Exchanges the names of tags `a` and `b`.  Everything else belongs to the tag rather than its name, so files, groups,
+/// pins and aliases all go along with it.  Names are unique, so `a` is parked on a name that no tag can have while `b`
+/// takes its place.
+pub fn swap_tags(tx: &Transaction, a: &str, b: &str, now: f64) -> Result<()> {
+    info!(target: SQL_TAG, "Swapping tags {} and {}", a, b);
+    let parked = format!("/{}", a);
+    let query = "UPDATE tags SET tag_name=?1 WHERE tag_name=?2";
+    trace!(target: SQL_TAG, "{}", query);
+    tx.execute(query, params![parked, a])?;
+    tx.execute(query, params![a, b])?;
+    tx.execute(query, params![b, parked])?;
+
+    update_tag_mtime(tx, a, now)?;
+    update_tag_mtime(tx, b, now)?;
+    update_root_mtime(tx, now)?;
+    Ok(())
+}
+
+/// The tag group version of `swap_tags`, where the tags in each group go along with it
+pub fn swap_tag_groups(tx: &Transaction, a: &str, b: &str, now: f64) -> Result<()> {
+    info!(target: SQL_TAG, "Swapping tag groups {} and {}", a, b);
+    let parked = format!("/{}", a);
+    let query = "UPDATE tag_groups SET name=?1 WHERE name=?2";
+    trace!(target: SQL_TAG, "{}", query);
+    tx.execute(query, params![parked, a])?;
+    tx.execute(query, params![a, b])?;
+    tx.execute(query, params![b, parked])?;
+
+    update_tag_group_mtime(tx, a, now)?;
+    update_tag_group_mtime(tx, b, now)?;
+    update_root_mtime(tx, now)?;
+    Ok(())
+}
+
+/// Finds the files that merging the intersection of `src_tags` into `dst_tags` would give the same name as a different
+/// file that's already tagged with all of `dst_tags`
+pub fn merge_collisions(
+    conn: &Connection,
+    src_tags: &[TagType],
+    dst_tags: &[&str],
+) -> Result<Vec<MergeCollision>> {
+    let sources = files_tagged_with(conn, src_tags)?;
+    let dst_types = dst_tags
+        .iter()
+        .map(|t| TagType::Regular((*t).to_string()))
+        .collect::<Vec<_>>();
+    let dests = files_tagged_with(conn, &dst_types)?;
+
+    let mut collisions = vec![];
+    for source in &sources {
+        for dest in &dests {
+            // a file that's in both is already merged, so it doesn't collide with anything new
+            if dest.primary_tag == source.primary_tag
+                && !sources.iter().any(|s| s.id == dest.id)
+                && !dests.iter().any(|d| d.id == source.id)
+            {
+                collisions.push(MergeCollision {
+                    source: source.clone(),
+                    dest: dest.clone(),
+                });
+            }
+        }
+    }
+    debug!(
+        target: SQL_TAG,
+        "Merging {:?} into {:?} has {} collisions",
+        src_tags,
+        dst_tags,
+        collisions.len()
+    );
+    Ok(collisions)
+}
+
 /// Takes everything tagged with the intersection of `src_tags` and removes the last src_tag from it, retagging all
-/// of those files with every tag in `dst_tags`
+/// of those files with every tag in `dst_tags`.  The files in `keep` are left alone, keeping the src_tag.
 pub fn merge_tags(
     tx: &Transaction,
     src_tag: &str,
     src_tags: &[TagType],
     dst_tags: &[&str],
+    keep: &[i64],
     now: f64,
 ) -> Result<()> {
     info!(
@@ -1426,7 +2998,7 @@ pub fn merge_tags(
         "Merging tag intersection {:?} into {:?}", src_tags, dst_tags
     );
 
-    let removed = remove_tag_from_intersection(tx, src_tag, src_tags, now)?;
+    let removed = remove_tag_from_intersection(tx, src_tag, src_tags, keep, now)?;
     debug!(
         target: SQL_TAG,
         "Deleted-to-be-moved {} files",
@@ -1464,6 +3036,8 @@ pub fn merge_tags(
 
         update_tag_mtime(tx, new_tag, now)?;
     }
+    let file_ids = removed.iter().map(|tf| tf.id).collect::<Vec<i64>>();
+    touch_file_tags(tx, &file_ids, now)?;
 
     update_root_mtime(tx, now)?;
     Ok(())
@@ -1492,7 +3066,95 @@ pub fn rename_file(
             now
         ],
     )?;
-    // TODO update the mtimes of all tags that contain this file
+    if let Some(file_id) = get_file_id(tx, device_file.device, device_file.inode)? {
+        touch_file_tags(tx, &[file_id], now)?;
+    }
+    update_root_mtime(tx, now)?;
+    Ok(())
+}
+
+/// Every tagged file, in the order they were tagged
+pub fn get_all_files(conn: &Connection) -> Result<Vec<FileRecord>> {
+    let query = "SELECT id, device, inode, path, primary_tag, target_size FROM files ORDER BY id";
+    trace!(target: SQL_TAG, "{}", query);
+    conn.prepare(query)?
+        .query_map(NO_PARAMS, to_file_record)?
+        .collect()
+}
+
+/// The id of the tagged file with `device` and `inode`, if there is one
+pub fn get_file_id(conn: &Connection, device: u64, inode: u64) -> Result<Option<i64>> {
+    conn.query_row(
+        "SELECT id FROM files WHERE device=?1 AND inode=?2",
+        params![device as i64, inode as i64],
+        |row| Ok(row.get(0)?),
+    )
+    .optional()
+}
+
+/// The tagged file with the id `file_id`, if there is one.  Its owner and permissions come from its tag links, so
+/// they're zeroed here, for the caller to fill in.
+pub fn get_file(conn: &Connection, file_id: i64) -> Result<Option<TaggedFile>> {
+    let query = "
+SELECT
+    id,
+    inode,
+    device,
+    path,
+    primary_tag,
+    mtime,
+    0,
+    0,
+    0,
+    alias_file,
+    target_size,
+    target_mtime,
+    is_dir
+FROM files
+WHERE id=?1";
+    trace!(target: SQL_TAG, "{}", query);
+    conn.query_row(query, params![file_id], to_taggedfile)
+        .optional()
+}
+
+/// The id of the tagged file recorded at `path`, if there is one
+pub fn get_file_id_by_path(conn: &Connection, path: &str) -> Result<Option<i64>> {
+    conn.query_row(
+        "SELECT id FROM files WHERE path=?1 ORDER BY id LIMIT 1",
+        params![path],
+        |row| Ok(row.get(0)?),
+    )
+    .optional()
+}
+
+/// The tagged files on `device` whose contents hash to `hash`
+pub fn get_files_with_hash(conn: &Connection, device: u64, hash: &str) -> Result<Vec<FileRecord>> {
+    let query = "SELECT id, device, inode, path, primary_tag, target_size FROM files
+    WHERE device=?1 AND content_hash=?2 ORDER BY id";
+    trace!(target: SQL_TAG, "{}", query);
+    conn.prepare(query)?
+        .query_map(params![device as i64, hash], to_file_record)?
+        .collect()
+}
+
+/// Points the tagged file `id` at a real file, for when the one it was tagged as has moved or been replaced.  Its
+/// tags and name are kept.
+pub fn relocate_file(
+    tx: &Transaction,
+    id: i64,
+    device: u64,
+    inode: u64,
+    path: &str,
+    now: f64,
+) -> Result<()> {
+    info!(
+        target: SQL_TAG,
+        "Relocating file {} to {} ({}/{})", id, path, device, inode
+    );
+    tx.execute(
+        "UPDATE files SET device=?2, inode=?3, path=?4, mtime=?5 WHERE id=?1",
+        params![id, device as i64, inode as i64, path, now],
+    )?;
     update_root_mtime(tx, now)?;
     Ok(())
 }
@@ -1500,6 +3162,7 @@ pub fn rename_file(
 pub fn pin_tags(
     tx: &Transaction,
     tags: &[TagType],
+    groups: &Groups,
     uid: uid_t,
     gid: gid_t,
     permissions: &Permissions,
@@ -1511,8 +3174,7 @@ pub fn pin_tags(
     for tt in tags {
         match tt {
             TagType::Regular(tag) => {
-                ensure_tag(tx, tag, uid, gid, permissions, now)?;
-                let tag_id = get_tag_id(tx, tag)?.ok_or(rusqlite::Error::QueryReturnedNoRows)?;
+                let (_, tag_id) = ensure_tag(tx, tag, groups, uid, gid, permissions, now)?;
                 pin_ids.push(format!("t{}", tag_id));
             }
             TagType::Group(group) => {
@@ -1531,16 +3193,27 @@ pub fn pin_tags(
     // FIXME this should be where the caller is
     // here we're determining if we need to put a tag into a tag group.  this can be the case if we're manually creating
     // a tag inside of a tag group dir in a file browser.  in that case, the mkdir path might look something like
-    // `something/else/+tag_group/tag`, in which case we want `tag` to be put into `+tag_group`.
+    // `something/else/+tag_group/tag`, in which case we want `tag` to be put into `+tag_group`.  a tag group made
+    // inside of another one, like `+tag_group/+nested`, is nested in it the same way
     if tags.len() >= 2 {
         let last = &tags[tags.len() - 1];
         let second_to_last = &tags[tags.len() - 2];
-        if let (TagType::Regular(tag), TagType::Group(group)) = (last, second_to_last) {
-            debug!(
-                target: SQL_TAG,
-                "{} is being pinned under tag group {}, grouping", tag, group
-            );
-            add_tag_to_group(tx, tag, group, uid, gid, permissions, now)?;
+        match (last, second_to_last) {
+            (TagType::Regular(tag), TagType::Group(group)) => {
+                debug!(
+                    target: SQL_TAG,
+                    "{} is being pinned under tag group {}, grouping", tag, group
+                );
+                add_tag_to_group(tx, tag, group, uid, gid, permissions, now)?;
+            }
+            (TagType::Group(child), TagType::Group(parent)) => {
+                debug!(
+                    target: SQL_TAG,
+                    "Tag group {} is being pinned under tag group {}, nesting", child, parent
+                );
+                add_group_to_group(tx, child, parent, now)?;
+            }
+            _ => {}
         }
     }
 
@@ -1705,13 +3378,48 @@ pub fn pinned_subdirs(conn: &Connection, tags: &[TagType]) -> Result<Vec<TagOrTa
     Ok(records)
 }
 
+/// Every pinned directory in the collection, each as the tags and tag groups along its path.  Pins that refer to a
+/// tag or tag group that no longer exists are skipped.
+pub fn all_pins(conn: &Connection) -> Result<Vec<Vec<TagOrTagGroup>>> {
+    let all_tag_ids: Vec<String> = conn
+        .prepare("SELECT tag_ids FROM pins ORDER BY rowid")?
+        .query_map(NO_PARAMS, |row: &Row| -> Result<String> { Ok(row.get(0)?) })?
+        .collect::<Result<Vec<String>>>()?;
+
+    let mut pins = vec![];
+    'pins: for tag_id_str in all_tag_ids {
+        let mut pin = vec![];
+        for chunk in tag_id_str.split('/').filter(|c| !c.is_empty()) {
+            let id = match chunk.get(1..).and_then(|id| id.parse::<i64>().ok()) {
+                Some(id) => id,
+                None => continue 'pins,
+            };
+            let part = match chunk.chars().next() {
+                Some('g') => get_tag_group_by_id(conn, id)?.map(TagOrTagGroup::Group),
+                Some('t') => get_tag_by_id(conn, id)?.map(TagOrTagGroup::Tag),
+                _ => None,
+            };
+            match part {
+                Some(part) => pin.push(part),
+                None => continue 'pins,
+            }
+        }
+        pins.push(pin);
+    }
+    Ok(pins)
+}
+
+/// The names of the tags in `group`, including the tags of the groups nested beneath it
 pub fn tag_names_for_tag_group(conn: &Connection, group: &str) -> Result<HashSet<String>> {
-    let query = "SELECT
+    let query = format!(
+        "{}
+        SELECT
             tags.tag_name
         FROM tags
         JOIN tag_group_tag AS tgt ON tgt.tag_id=tags.id
-        JOIN tag_groups AS tg ON tg.id=tgt.tg_id
-        WHERE tg.name=?1";
+        WHERE tgt.tg_id IN (SELECT id FROM group_tree)",
+        GROUP_TREE
+    );
     conn.prepare(&query)?
         .query_map(params![group], |row| row.get(0))?
         .collect()
@@ -1726,14 +3434,1003 @@ pub fn tag_is_in_group(conn: &Connection, group: &str, tag: &str) -> Result<bool
     Ok(false)
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+fn first_char(val: String) -> char {
+    val.chars().next().unwrap_or_default()
+}
 
-    #[test]
-    fn test_float_to_dt_and_back() {
-        let now = get_now_secs();
-        let dt = float_to_utcdt(now);
-        assert_eq!(now as i64, dt.timestamp());
+/// Returns every symbol pair this collection has been mounted with, oldest first
+pub fn get_symbol_history(conn: &Connection) -> Result<Vec<SymbolRecord>> {
+    let query = "
+SELECT
+    id,
+    device_char,
+    inode_char,
+    first_version,
+    retired_version
+FROM symbol_history
+ORDER BY id";
+    trace!(target: SQL_TAG, "{}", query);
+    conn.prepare(query)?
+        .query_map(NO_PARAMS, |row| {
+            Ok(SymbolRecord {
+                id: row.get(0)?,
+                device_char: first_char(row.get(1)?),
+                inode_char: first_char(row.get(2)?),
+                first_version: row.get(3)?,
+                retired_version: row.get(4)?,
+            })
+        })?
+        .collect()
+}
+
+/// Retires the currently active symbol pair at `version` and records the new active pair
+pub fn replace_symbols(
+    tx: &Transaction,
+    device_char: char,
+    inode_char: char,
+    version: &str,
+) -> Result<()> {
+    info!(
+        target: SQL_TAG,
+        "Recording new symbols {} {} at version {}", device_char, inode_char, version
+    );
+    tx.execute(
+        "UPDATE symbol_history SET retired_version=?1 WHERE retired_version IS NULL",
+        params![version],
+    )?;
+    tx.execute(
+        "INSERT INTO symbol_history (device_char, inode_char, first_version) VALUES (?1, ?2, ?3)",
+        params![device_char.to_string(), inode_char.to_string(), version],
+    )?;
+    Ok(())
+}
+
+/// Finds all tag, tag group, and file names that contain `sym`
+pub fn names_containing(conn: &Connection, sym: &str) -> Result<Vec<String>> {
+    let query = "
+SELECT tag_name FROM tags WHERE instr(tag_name, ?1) > 0
+UNION
+SELECT name FROM tag_groups WHERE instr(name, ?1) > 0
+UNION
+SELECT primary_tag FROM files WHERE instr(primary_tag, ?1) > 0";
+    trace!(target: SQL_TAG, "{}", query);
+    conn.prepare(query)?
+        .query_map(params![sym], |row| row.get(0))?
+        .collect()
+}
+
+/// Returns the id of the newest write transaction.  Every `begin_write` changes it, whichever process it's in, so it
+/// tells a cache whether the collection has changed since it last looked.
+pub fn write_tx_id(conn: &Connection) -> Result<i64> {
+    conn.prepare_cached("SELECT id FROM event_tx")?
+        .query_row(NO_PARAMS, |row| row.get(0))
+}
+
+/// Returns the id of the newest event, or 0 if there are none yet
+pub fn latest_event_id(conn: &Connection) -> Result<i64> {
+    conn.query_row(
+        "SELECT COALESCE(MAX(id), 0) FROM events",
+        NO_PARAMS,
+        |row| row.get(0),
+    )
+}
+
+/// Returns the ids of every tag whose contents were changed by the events after `cursor`, and the cursor to pass next
+/// time.  Events about a file or a tag group count for the tags the file has, or that the group contains.
+pub fn tags_touched_since(conn: &Connection, cursor: i64) -> Result<(Vec<i64>, i64)> {
+    let latest = latest_event_id(conn)?;
+    if latest <= cursor {
+        return Ok((vec![], cursor));
+    }
+
+    let query = "
+SELECT e.tag_id FROM events AS e
+WHERE e.id > ?1 AND e.id <= ?2 AND e.tag_id IS NOT NULL
+UNION
+SELECT ft.tag_id FROM events AS e
+JOIN file_tag AS ft ON ft.file_id=e.file_id
+WHERE e.id > ?1 AND e.id <= ?2 AND e.tag_id IS NULL
+UNION
+SELECT tgt.tag_id FROM events AS e
+JOIN tag_group_tag AS tgt ON tgt.tg_id=e.tag_group_id
+WHERE e.id > ?1 AND e.id <= ?2 AND e.tag_id IS NULL";
+    trace!(target: SQL_TAG, "{}", query);
+    let tag_ids = conn
+        .prepare(query)?
+        .query_map(params![cursor, latest], |row| row.get(0))?
+        .collect::<Result<Vec<i64>>>()?;
+    Ok((tag_ids, latest))
+}
+
+/// Returns up to `limit` events that happened after the event `cursor`, oldest first.  A cursor of 0 starts from the
+/// beginning of the feed, and the id of the last event returned is the cursor for the next poll.
+pub fn events_since(conn: &Connection, cursor: i64, limit: u32) -> Result<Vec<Event>> {
+    let query = "
+SELECT
+    id,
+    op,
+    tag_id,
+    file_id,
+    tag_group_id,
+    tx_id,
+    ts
+FROM events
+WHERE id > ?
+ORDER BY id
+LIMIT ?";
+    trace!(target: SQL_TAG, "{}", query);
+    conn.prepare(query)?
+        .query_map(params![cursor, limit], |row| {
+            Ok(Event {
+                id: row.get(0)?,
+                op: row.get(1)?,
+                tag_id: row.get(2)?,
+                file_id: row.get(3)?,
+                tag_group_id: row.get(4)?,
+                tx_id: row.get(5)?,
+                ts: float_to_utcdt(row.get(6)?),
+            })
+        })?
+        .collect()
+}
+
+/// The separator between the terms of a saved search's query, since a term can contain spaces
+const SAVED_SEARCH_TERM_SEP: &str = "\n";
+
+fn to_saved_search(row: &Row) -> Result<SavedSearch> {
+    let query: String = row.get(1)?;
+    Ok(SavedSearch {
+        name: row.get(0)?,
+        terms: query
+            .split(SAVED_SEARCH_TERM_SEP)
+            .map(ToOwned::to_owned)
+            .collect(),
+        extension: row.get(2)?,
+        max_age_s: row.get(3)?,
+        ts: float_to_utcdt(row.get(4)?),
+    })
+}
+
+/// Saves a search under `name`.  Saving over an existing name replaces that search.
+pub fn add_saved_search(
+    tx: &Transaction,
+    name: &str,
+    terms: &[&str],
+    extension: Option<&str>,
+    max_age_s: Option<i64>,
+    now: f64,
+) -> Result<()> {
+    info!(
+        target: SQL_TAG,
+        "Saving search {} for {:?}, extension {:?}, max age {:?}", name, terms, extension, max_age_s
+    );
+    let query = "INSERT OR REPLACE INTO saved_searches (name, query, extension, max_age_s, ts) VALUES (?1, ?2, ?3, ?4, ?5)";
+    trace!(target: SQL_TAG, "{}", query);
+    tx.execute(
+        query,
+        params![
+            name,
+            terms.join(SAVED_SEARCH_TERM_SEP),
+            extension,
+            max_age_s,
+            now
+        ],
+    )?;
+    Ok(())
+}
+
+/// Removes the saved search `name`, returning whether it existed
+pub fn remove_saved_search(tx: &Transaction, name: &str) -> Result<bool> {
+    info!(target: SQL_TAG, "Removing saved search {}", name);
+    let query = "DELETE FROM saved_searches WHERE name=?1";
+    trace!(target: SQL_TAG, "{}", query);
+    Ok(tx.execute(query, params![name])? > 0)
+}
+
+pub fn get_saved_search(conn: &Connection, name: &str) -> Result<Option<SavedSearch>> {
+    let query = "SELECT name, query, extension, max_age_s, ts FROM saved_searches WHERE name=?1";
+    trace!(target: SQL_TAG, "{}", query);
+    conn.query_row(query, params![name], to_saved_search)
+        .optional()
+}
+
+/// Returns every saved search, sorted by name
+pub fn get_saved_searches(conn: &Connection) -> Result<Vec<SavedSearch>> {
+    let query = "SELECT name, query, extension, max_age_s, ts FROM saved_searches ORDER BY name";
+    trace!(target: SQL_TAG, "{}", query);
+    conn.prepare(query)?
+        .query_map(NO_PARAMS, to_saved_search)?
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_float_to_dt_and_back() {
+        let now = get_now_secs();
+        let dt = float_to_utcdt(now);
+        assert_eq!(now as i64, dt.timestamp());
+    }
+
+    #[test]
+    fn test_dt_to_float_and_back() {
+        let now = get_now_secs();
+        let there_and_back = utcdt_to_float(&float_to_utcdt(now));
+        assert!((now - there_and_back).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_files_tagged_with_page() -> Result<()> {
+        let mut conn = Connection::open_in_memory()?;
+        migrations::migrate(&mut conn, &crate::common::version_str())?;
+        let tx = begin_write(&mut conn)?;
+        tx.execute(
+            "INSERT INTO tags (id, tag_name, ts, mtime, uid, gid, permissions)
+            VALUES (1, 't1', 0, 0, 0, 0, 493)",
+            NO_PARAMS,
+        )?;
+        // every name is shared by two files
+        for id in 0..10 {
+            tx.execute(
+                "INSERT INTO files (id, device, inode, path, primary_tag, ts, mtime)
+                VALUES (?1, 1, ?1, '/f' || ?1, 'f' || (?1 / 2), 0, 0)",
+                params![id],
+            )?;
+            tx.execute(
+                "INSERT INTO file_tag (file_id, tag_id, ts, mtime, uid, gid, permissions)
+                VALUES (?1, 1, 0, 0, 0, 0, 493)",
+                params![id],
+            )?;
+        }
+        tx.commit()?;
+
+        let tags = [TagType::Regular("t1".to_string())];
+        let all = files_tagged_with(&conn, &tags)?;
+        assert_eq!(all.len(), 10);
+
+        let mut paged = vec![];
+        for offset in (0..12).step_by(3) {
+            let page = files_tagged_with_page(&conn, &tags, Sort::Name, offset, 3)?;
+            paged.extend(page.into_iter().map(|tf| tf.id));
+        }
+        let all: Vec<_> = all.into_iter().map(|tf| tf.id).collect();
+        assert_eq!(paged, all, "pages line up into the whole listing");
+
+        assert!(has_files(&conn, &tags)?);
+        assert!(!has_files(&conn, &[TagType::Regular("nope".to_string())])?);
+        assert_eq!(primary_tag_counts(&conn, &tags, true)?.len(), 5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_meta_intersection() -> Result<()> {
+        let mut conn = Connection::open_in_memory()?;
+        migrations::migrate(&mut conn, &crate::common::version_str())?;
+        let tx = begin_write(&mut conn)?;
+        tx.execute(
+            "INSERT INTO tags (id, tag_name, ts, mtime, uid, gid, permissions)
+            VALUES (1, 't1', 0, 0, 0, 0, 493), (2, 't2', 0, 0, 0, 0, 493)",
+            NO_PARAMS,
+        )?;
+        for id in 0..3 {
+            tx.execute(
+                "INSERT INTO files (id, device, inode, path, primary_tag, ts, mtime)
+                VALUES (?1, 1, ?1, '/f' || ?1, 'f' || ?1, 0, 0)",
+                params![id],
+            )?;
+            tx.execute(
+                "INSERT INTO file_tag (file_id, tag_id, ts, mtime, uid, gid, permissions)
+                VALUES (?1, 1, 0, 0, 0, 0, 493)",
+                params![id],
+            )?;
+        }
+        tx.execute(
+            "INSERT INTO file_tag (file_id, tag_id, ts, mtime, uid, gid, permissions)
+            VALUES (0, 2, 0, 0, 0, 0, 493)",
+            NO_PARAMS,
+        )?;
+        set_file_meta(&tx, 0, "client", "acme", 0.0)?;
+        set_file_meta(&tx, 1, "client", "acme", 0.0)?;
+        set_file_meta(&tx, 2, "client", "initech", 0.0)?;
+        tx.commit()?;
+
+        let acme = TagType::Meta("client".to_string(), "acme".to_string());
+        fn ids(conn: &Connection, tags: &[TagType]) -> Result<Vec<i64>> {
+            Ok(files_tagged_with(conn, tags)?
+                .into_iter()
+                .map(|tf| tf.id)
+                .collect())
+        }
+        assert_eq!(ids(&conn, &[acme.clone()])?, vec![0, 1]);
+        assert_eq!(
+            ids(&conn, &[acme.clone(), TagType::Negation("t2".to_string())])?,
+            vec![1]
+        );
+        assert_eq!(
+            ids(&conn, &[TagType::Regular("t2".to_string()), acme.clone()])?,
+            vec![0]
+        );
+
+        // setting a key again replaces its value
+        let tx = begin_write(&mut conn)?;
+        set_file_meta(&tx, 1, "client", "initech", 0.0)?;
+        assert!(remove_file_meta(&tx, 0, "client")?);
+        assert!(!remove_file_meta(&tx, 0, "client")?);
+        tx.commit()?;
+        assert!(ids(&conn, &[acme])?.is_empty());
+        assert_eq!(
+            get_file_meta(&conn, 1)?,
+            vec![("client".to_string(), "initech".to_string())]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_rating_intersection() -> Result<()> {
+        let mut conn = Connection::open_in_memory()?;
+        migrations::migrate(&mut conn, &crate::common::version_str())?;
+        let tx = begin_write(&mut conn)?;
+        tx.execute(
+            "INSERT INTO tags (id, tag_name, ts, mtime, uid, gid, permissions)
+            VALUES (1, 't1', 0, 0, 0, 0, 493)",
+            NO_PARAMS,
+        )?;
+        for id in 0..4 {
+            tx.execute(
+                "INSERT INTO files (id, device, inode, path, primary_tag, ts, mtime)
+                VALUES (?1, 1, ?1, '/f' || ?1, 'f' || ?1, 0, 0)",
+                params![id],
+            )?;
+            tx.execute(
+                "INSERT INTO file_tag (file_id, tag_id, ts, mtime, uid, gid, permissions)
+                VALUES (?1, 1, 0, 0, 0, 0, 493)",
+                params![id],
+            )?;
+        }
+        set_rating(&tx, 0, Some(3))?;
+        set_rating(&tx, 1, Some(5))?;
+        set_rating(&tx, 2, Some(4))?;
+        tx.commit()?;
+
+        fn ids(conn: &Connection, tags: &[TagType], sort: Sort) -> Result<Vec<i64>> {
+            Ok(files_tagged_with_sorted(conn, tags, sort)?
+                .into_iter()
+                .map(|tf| tf.id)
+                .collect())
+        }
+        let t1 = TagType::Regular("t1".to_string());
+        assert_eq!(
+            ids(&conn, &[t1.clone(), TagType::Rating(4)], Sort::Name)?,
+            vec![1, 2]
+        );
+        assert_eq!(
+            ids(&conn, &[t1.clone(), TagType::Rating(1)], Sort::Name)?,
+            vec![0, 1, 2]
+        );
+        // the unrated file comes last
+        assert_eq!(ids(&conn, &[t1.clone()], Sort::Rating)?, vec![1, 2, 0, 3]);
+
+        let tx = begin_write(&mut conn)?;
+        set_rating(&tx, 1, None)?;
+        tx.commit()?;
+        assert_eq!(get_rating(&conn, 1)?, None);
+        assert_eq!(get_rating(&conn, 2)?, Some(4));
+        assert_eq!(ids(&conn, &[t1, TagType::Rating(4)], Sort::Name)?, vec![2]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_negations_exclude_each_tag() -> Result<()> {
+        let mut conn = Connection::open_in_memory()?;
+        migrations::migrate(&mut conn, &crate::common::version_str())?;
+        let tx = begin_write(&mut conn)?;
+        tx.execute(
+            "INSERT INTO tags (id, tag_name, ts, mtime, uid, gid, permissions)
+            VALUES (1, 't1', 0, 0, 0, 0, 493), (2, 't2', 0, 0, 0, 0, 493), (3, 't3', 0, 0, 0, 0, 493)",
+            NO_PARAMS,
+        )?;
+        for (file_id, tag_id) in &[
+            (0, 1),
+            (0, 2),
+            (1, 1),
+            (1, 3),
+            (2, 1),
+            (3, 1),
+            (3, 2),
+            (3, 3),
+        ] {
+            tx.execute(
+                "INSERT OR IGNORE INTO files (id, device, inode, path, primary_tag, ts, mtime)
+                VALUES (?1, 1, ?1, '/f' || ?1, 'f' || ?1, 0, 0)",
+                params![file_id],
+            )?;
+            tx.execute(
+                "INSERT INTO file_tag (file_id, tag_id, ts, mtime, uid, gid, permissions)
+                VALUES (?1, ?2, 0, 0, 0, 0, 493)",
+                params![file_id, tag_id],
+            )?;
+        }
+        tx.commit()?;
+
+        let ids = |tags: &[TagType]| -> Result<Vec<i64>> {
+            Ok(files_tagged_with(&conn, tags)?
+                .into_iter()
+                .map(|tf| tf.id)
+                .collect())
+        };
+        let t1 = TagType::Regular("t1".to_string());
+        let not_t2 = TagType::Negation("t2".to_string());
+        let not_t3 = TagType::Negation("t3".to_string());
+        assert_eq!(ids(&[t1.clone(), not_t2.clone()])?, vec![1, 2]);
+        // a file is left out if it has any of the negated tags, not only if it has all of them
+        assert_eq!(ids(&[t1.clone(), not_t2, not_t3.clone()])?, vec![2]);
+        assert_eq!(
+            ids(&[
+                TagType::Union(vec!["t2".to_string(), "t3".to_string()]),
+                not_t3
+            ])?,
+            vec![0]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_date_intersection() -> Result<()> {
+        let mut conn = Connection::open_in_memory()?;
+        migrations::migrate(&mut conn, &crate::common::version_str())?;
+        let tx = begin_write(&mut conn)?;
+        tx.execute(
+            "INSERT INTO tags (id, tag_name, ts, mtime, uid, gid, permissions)
+            VALUES (1, 't1', 0, 0, 0, 0, 493)",
+            NO_PARAMS,
+        )?;
+        // middays in the middle of july 2021, march 2021, and july 2020, so that no timezone moves them to another
+        // month.  the last file's real mtime is known, and it wins over when it was tagged
+        let mtimes = [
+            (1626350400.0, None),
+            (1615809600.0, None),
+            (1594814400.0, Some(1626350400.0)),
+        ];
+        for (id, (mtime, target_mtime)) in mtimes.iter().enumerate() {
+            tx.execute(
+                "INSERT INTO files (id, device, inode, path, primary_tag, ts, mtime, target_mtime)
+                VALUES (?1, 1, ?1, '/f' || ?1, 'f' || ?1, 0, ?2, ?3)",
+                params![id as i64, mtime, target_mtime],
+            )?;
+            tx.execute(
+                "INSERT INTO file_tag (file_id, tag_id, ts, mtime, uid, gid, permissions)
+                VALUES (?1, 1, 0, 0, 0, 0, 493)",
+                params![id as i64],
+            )?;
+        }
+        tx.commit()?;
+
+        fn ids(conn: &Connection, tags: &[TagType]) -> Result<Vec<i64>> {
+            Ok(files_tagged_with(conn, tags)?
+                .into_iter()
+                .map(|tf| tf.id)
+                .collect())
+        }
+        let date = |bucket: &str| TagType::Date(bucket.to_string());
+        let t1 = TagType::Regular("t1".to_string());
+        assert_eq!(ids(&conn, &[t1.clone(), date("2021")])?, vec![0, 1, 2]);
+        assert_eq!(ids(&conn, &[date("2021"), date("2021-07")])?, vec![0, 2]);
+        assert!(ids(&conn, &[t1.clone(), date("2020")])?.is_empty());
+
+        let buckets = |tags: &[TagType], months: bool| -> Result<Vec<String>> {
+            Ok(date_buckets(&conn, tags, months)?
+                .into_iter()
+                .map(|(bucket, _)| bucket)
+                .collect())
+        };
+        assert_eq!(buckets(&[], false)?, vec!["2021"]);
+        assert_eq!(
+            buckets(&[t1.clone(), date("2021")], true)?,
+            vec!["2021-03", "2021-07"]
+        );
+        assert_eq!(buckets(&[t1, date("2021-03")], true)?, vec!["2021-03"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_filetype_intersection() -> Result<()> {
+        let mut conn = Connection::open_in_memory()?;
+        migrations::migrate(&mut conn, &crate::common::version_str())?;
+        let tx = begin_write(&mut conn)?;
+        tx.execute(
+            "INSERT INTO tags (id, tag_name, ts, mtime, uid, gid, permissions)
+            VALUES (1, 't1', 0, 0, 0, 0, 493)",
+            NO_PARAMS,
+        )?;
+        let names = ["a.pdf", "b.PDF", "c.tgz", "d.gz", ".gz", "README"];
+        for (id, name) in names.iter().enumerate() {
+            tx.execute(
+                "INSERT INTO files (id, device, inode, path, primary_tag, ts, mtime)
+                VALUES (?1, 1, ?1, '/' || ?2, ?2, 0, 0)",
+                params![id as i64, name],
+            )?;
+            tx.execute(
+                "INSERT INTO file_tag (file_id, tag_id, ts, mtime, uid, gid, permissions)
+                VALUES (?1, 1, 0, 0, 0, 0, 493)",
+                params![id as i64],
+            )?;
+        }
+        tx.commit()?;
+
+        fn ids(conn: &Connection, tags: &[TagType]) -> Result<Vec<i64>> {
+            Ok(files_tagged_with(conn, tags)?
+                .into_iter()
+                .map(|tf| tf.id)
+                .collect())
+        }
+        let t1 = TagType::Regular("t1".to_string());
+        let filetype = |ext: &str| vec![TagType::FileTypeGroup, TagType::FileType(ext.to_string())];
+        assert_eq!(ids(&conn, &filetype("pdf"))?, vec![0, 1]);
+        assert_eq!(ids(&conn, &filetype("gz"))?, vec![3]);
+        assert!(ids(&conn, &filetype("md"))?.is_empty());
+
+        // a trailing file type group is every file with an extension
+        assert_eq!(
+            ids(&conn, &[t1.clone(), TagType::FileTypeGroup])?,
+            vec![0, 1, 2, 3]
+        );
+        assert_eq!(
+            file_extensions(&conn, &[t1.clone(), TagType::FileTypeGroup])?,
+            vec!["gz", "pdf", "tgz"]
+        );
+        assert_eq!(
+            file_extensions(&conn, &[t1, TagType::Negation("nope".to_string())])?,
+            vec!["gz", "pdf", "tgz"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_duplicate_files() -> Result<()> {
+        let mut conn = Connection::open_in_memory()?;
+        migrations::migrate(&mut conn, &crate::common::version_str())?;
+        let tx = begin_write(&mut conn)?;
+        tx.execute(
+            "INSERT INTO tags (id, tag_name, ts, mtime, uid, gid, permissions)
+            VALUES (1, 't1', 0, 0, 0, 0, 493)",
+            NO_PARAMS,
+        )?;
+        for id in 0..4 {
+            tx.execute(
+                "INSERT INTO files (id, device, inode, path, primary_tag, ts, mtime)
+                VALUES (?1, 1, ?1, '/f' || ?1, 'f' || ?1, 0, 0)",
+                params![id],
+            )?;
+            tx.execute(
+                "INSERT INTO file_tag (file_id, tag_id, ts, mtime, uid, gid, permissions)
+                VALUES (?1, 1, 0, 0, 0, 0, 493)",
+                params![id],
+            )?;
+        }
+        let then = float_to_utcdt(100.0);
+        for (inode, hash) in [(0, "aa"), (1, "bb"), (2, "aa")].iter() {
+            set_target_metadata(&tx, 1, *inode, 10, &then)?;
+            set_content_hash(&tx, 1, *inode, hash)?;
+        }
+        tx.commit()?;
+
+        fn dupes(conn: &Connection) -> Result<Vec<(String, i64)>> {
+            Ok(get_duplicate_files(conn)?
+                .into_iter()
+                .map(|(hash, tf)| (hash, tf.id))
+                .collect())
+        }
+        assert_eq!(
+            dupes(&conn)?,
+            vec![("aa".to_string(), 0), ("aa".to_string(), 2)]
+        );
+        assert!(has_duplicate_files(&conn)?);
+        let unhashed: Vec<i64> = get_unhashed_files(&conn, false)?
+            .iter()
+            .map(|f| f.id)
+            .collect();
+        assert_eq!(unhashed, vec![3]);
+        assert_eq!(get_unhashed_files(&conn, true)?.len(), 4);
+
+        // seeing the same target again keeps its hash, but a change to it means the hash is stale
+        let tx = begin_write(&mut conn)?;
+        set_target_metadata(&tx, 1, 0, 10, &then)?;
+        assert_eq!(dupes(&tx)?.len(), 2);
+        set_target_metadata(&tx, 1, 2, 11, &then)?;
+        tx.commit()?;
+        assert!(dupes(&conn)?.is_empty());
+        assert!(!has_duplicate_files(&conn)?);
+        let unhashed: Vec<i64> = get_unhashed_files(&conn, false)?
+            .iter()
+            .map(|f| f.id)
+            .collect();
+        assert_eq!(unhashed, vec![2, 3]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_expression_intersection() -> Result<()> {
+        let mut conn = Connection::open_in_memory()?;
+        migrations::migrate(&mut conn, &crate::common::version_str())?;
+        let tx = begin_write(&mut conn)?;
+        tx.execute(
+            "INSERT INTO tags (id, tag_name, ts, mtime, uid, gid, permissions)
+            VALUES (1, 'rust', 0, 0, 0, 0, 493), (2, 'cli', 0, 0, 0, 0, 493),
+                (3, 'tui', 0, 0, 0, 0, 493), (4, 'wip', 0, 0, 0, 0, 493)",
+            NO_PARAMS,
+        )?;
+        let tagged = [(0, 1), (0, 2), (1, 1), (1, 3), (1, 4), (2, 1), (3, 2)];
+        for (file_id, tag_id) in &tagged {
+            tx.execute(
+                "INSERT OR IGNORE INTO files (id, device, inode, path, primary_tag, ts, mtime)
+                VALUES (?1, 1, ?1, '/f' || ?1, 'f' || ?1, 0, 0)",
+                params![file_id],
+            )?;
+            tx.execute(
+                "INSERT INTO file_tag (file_id, tag_id, ts, mtime, uid, gid, permissions)
+                VALUES (?1, ?2, 0, 0, 0, 0, 493)",
+                params![file_id, tag_id],
+            )?;
+        }
+        tx.commit()?;
+
+        let ids = |tags: &[TagType]| -> Result<Vec<i64>> {
+            Ok(files_tagged_with(&conn, tags)?
+                .into_iter()
+                .map(|tf| tf.id)
+                .collect())
+        };
+        let expr = |text: &str| {
+            TagType::Expression(crate::common::query::parse_expr(text).expect("Bad expression"))
+        };
+        assert_eq!(ids(&[expr("rust&(cli|tui)")])?, vec![0, 1]);
+        assert_eq!(ids(&[expr("rust&(cli|tui)&!wip")])?, vec![0]);
+        assert_eq!(ids(&[expr("!rust|wip")])?, vec![1, 3]);
+        // and intersected with the rest of the path
+        assert_eq!(
+            ids(&[TagType::Regular("cli".to_string()), expr("!rust")])?,
+            vec![3]
+        );
+        assert_eq!(
+            ids(&[expr("rust|cli"), TagType::Negation("cli".to_string())])?,
+            vec![1, 2]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_mtime_propagates_to_file_tags() -> Result<()> {
+        let mut conn = Connection::open_in_memory()?;
+        migrations::migrate(&mut conn, &crate::common::version_str())?;
+        let tx = begin_write(&mut conn)?;
+        tx.execute(
+            "INSERT INTO tags (id, tag_name, ts, mtime, uid, gid, permissions, num_files)
+            VALUES (1, 'a', 0, 0, 0, 0, 493, 2), (2, 'b', 0, 0, 0, 0, 493, 1),
+                (3, 'c', 0, 0, 0, 0, 493, 1)",
+            NO_PARAMS,
+        )?;
+        // file 0 is in a and b, file 1 is in a and c
+        for (file_id, tag_id) in &[(0, 1), (0, 2), (1, 1), (1, 3)] {
+            tx.execute(
+                "INSERT OR IGNORE INTO files (id, device, inode, path, primary_tag, ts, mtime)
+                VALUES (?1, 1, ?1, '/f' || ?1, 'f' || ?1, 0, 0)",
+                params![file_id],
+            )?;
+            tx.execute(
+                "INSERT INTO file_tag (file_id, tag_id, ts, mtime, uid, gid, permissions)
+                VALUES (?1, ?2, 0, 0, 0, 0, 493)",
+                params![file_id, tag_id],
+            )?;
+        }
+        tx.commit()?;
+
+        fn mtimes(conn: &Connection) -> Result<Vec<i64>> {
+            conn.prepare("SELECT mtime FROM tags ORDER BY id")?
+                .query_map(NO_PARAMS, |row| Ok(row.get::<usize, f64>(0)? as i64))?
+                .collect()
+        }
+
+        // removing a from file 0 changes a/b and b, but nothing that file 1 is in by itself
+        let tx = begin_write(&mut conn)?;
+        assert!(unlink_file_from_tag(&tx, 1, 0, "a", 100.0)?);
+        tx.commit()?;
+        assert_eq!(mtimes(&conn)?, vec![100, 100, 0]);
+
+        // a bulk removal touches the tags of every file it removes from
+        let tx = begin_write(&mut conn)?;
+        remove_tag_from_intersection(&tx, "c", &[TagType::Regular("c".to_string())], &[], 200.0)?;
+        tx.commit()?;
+        assert_eq!(mtimes(&conn)?, vec![200, 100, 200]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_group_any_of_and_all_of() -> Result<()> {
+        let mut conn = Connection::open_in_memory()?;
+        migrations::migrate(&mut conn, &crate::common::version_str())?;
+        let tx = begin_write(&mut conn)?;
+        tx.execute(
+            "INSERT INTO tags (id, tag_name, ts, mtime, uid, gid, permissions)
+            VALUES (1, 't1', 0, 0, 0, 0, 493), (2, 't2', 0, 0, 0, 0, 493)",
+            NO_PARAMS,
+        )?;
+        // file 0 has both tags, file 1 only t1, file 2 only t2
+        for (file_id, tag_id) in &[(0, 1), (0, 2), (1, 1), (2, 2)] {
+            tx.execute(
+                "INSERT OR IGNORE INTO files (id, device, inode, path, primary_tag, ts, mtime)
+                VALUES (?1, 1, ?1, '/f' || ?1, 'f' || ?1, 0, 0)",
+                params![file_id],
+            )?;
+            tx.execute(
+                "INSERT INTO file_tag (file_id, tag_id, ts, mtime, uid, gid, permissions)
+                VALUES (?1, ?2, 0, 0, 0, 0, 493)",
+                params![file_id, tag_id],
+            )?;
+        }
+        let perms = Permissions::default();
+        ensure_tag_group(&tx, "g", 0, 0, &perms, 0.0)?;
+        add_tag_to_group(&tx, "t1", "g", 0, 0, &perms, 0.0)?;
+        add_tag_to_group(&tx, "t2", "g", 0, 0, &perms, 0.0)?;
+        tx.commit()?;
+
+        let ids = |tags: &[TagType]| -> Result<Vec<i64>> {
+            Ok(files_tagged_with(&conn, tags)?
+                .into_iter()
+                .map(|tf| tf.id)
+                .collect())
+        };
+        assert_eq!(ids(&[TagType::Group("g".to_string())])?, vec![0, 1, 2]);
+        assert_eq!(ids(&[TagType::GroupAll("g".to_string())])?, vec![0]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_nested_groups() -> Result<()> {
+        let mut conn = Connection::open_in_memory()?;
+        migrations::migrate(&mut conn, &crate::common::version_str())?;
+        let tx = begin_write(&mut conn)?;
+        tx.execute(
+            "INSERT INTO tags (id, tag_name, ts, mtime, uid, gid, permissions)
+            VALUES (1, 'mp4', 0, 0, 0, 0, 493), (2, 'mp3', 0, 0, 0, 0, 493), (3, 'txt', 0, 0, 0, 0, 493)",
+            NO_PARAMS,
+        )?;
+        for (file_id, tag_id) in &[(1, 1), (2, 2), (3, 3)] {
+            tx.execute(
+                "INSERT INTO files (id, device, inode, path, primary_tag, ts, mtime)
+                VALUES (?1, 1, ?1, '/f' || ?1, 'f' || ?1, 0, 0)",
+                params![file_id],
+            )?;
+            tx.execute(
+                "INSERT INTO file_tag (file_id, tag_id, ts, mtime, uid, gid, permissions)
+                VALUES (?1, ?2, 0, 0, 0, 0, 493)",
+                params![file_id, tag_id],
+            )?;
+        }
+        let perms = Permissions::default();
+        for group in &["media", "video", "audio"] {
+            ensure_tag_group(&tx, group, 0, 0, &perms, 0.0)?;
+        }
+        add_tag_to_group(&tx, "mp4", "video", 0, 0, &perms, 0.0)?;
+        add_tag_to_group(&tx, "mp3", "audio", 0, 0, &perms, 0.0)?;
+        add_group_to_group(&tx, "video", "media", 0.0)?;
+        add_group_to_group(&tx, "audio", "media", 0.0)?;
+        tx.commit()?;
+
+        let media = TagType::Group("media".to_string());
+        let ids: Vec<i64> = files_tagged_with(&conn, &[media.clone()])?
+            .into_iter()
+            .map(|tf| tf.id)
+            .collect();
+        assert_eq!(ids, vec![1, 2]);
+        assert!(tag_is_in_group(&conn, "media", "mp4")?);
+
+        let names = |groups: Vec<TagGroup>| {
+            let mut names: Vec<String> = groups.into_iter().map(|tg| tg.name).collect();
+            names.sort();
+            names
+        };
+        assert_eq!(
+            names(tag_groups_for_tags(&conn, &[1, 2, 3])?),
+            vec!["media"]
+        );
+        assert_eq!(
+            names(subgroups_for_tags(&conn, "media", &[1, 2, 3])?),
+            vec!["audio", "video"]
+        );
+        assert_eq!(
+            names(tag_group_intersections(
+                &conn,
+                &[media, TagType::Group("video".to_string())]
+            )?),
+            vec!["video"]
+        );
+
+        assert!(group_is_beneath(&conn, "video", "media")?);
+        assert!(!group_is_beneath(&conn, "media", "video")?);
+        assert!(group_is_in_group(&conn, "media", "audio")?);
+
+        let tx = begin_write(&mut conn)?;
+        remove_group_from_group(&tx, "audio", "media", 1.0)?;
+        tx.commit()?;
+        assert_eq!(
+            names(tag_groups_for_tags(&conn, &[1, 2, 3])?),
+            vec!["audio", "media"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_uncounted_intersection() -> Result<()> {
+        let mut conn = Connection::open_in_memory()?;
+        migrations::migrate(&mut conn, &crate::common::version_str())?;
+        let tx = begin_write(&mut conn)?;
+        tx.execute(
+            "INSERT INTO tags (id, tag_name, ts, mtime, uid, gid, permissions)
+            VALUES (1, 't1', 0, 0, 0, 0, 493), (2, 't2', 0, 0, 0, 0, 493), (3, 't3', 0, 0, 0, 0, 493)",
+            NO_PARAMS,
+        )?;
+        tx.execute(
+            "INSERT INTO files (id, device, inode, path, primary_tag, ts, mtime)
+            VALUES (1, 1, 1, '/f1', 'f1', 0, 0), (2, 1, 2, '/f2', 'f2', 0, 0)",
+            NO_PARAMS,
+        )?;
+        tx.execute(
+            "INSERT INTO file_tag (file_id, tag_id, ts, mtime, uid, gid, permissions)
+            VALUES (1, 1, 0, 1, 0, 0, 493), (1, 2, 0, 2, 0, 0, 493), (1, 3, 0, 3, 0, 0, 493),
+                   (2, 1, 0, 4, 0, 0, 493), (2, 2, 0, 5, 0, 0, 493)",
+            NO_PARAMS,
+        )?;
+        tx.commit()?;
+
+        let parents = [TagType::Regular("t1".to_string())];
+        let counted = intersect_tag_sorted(&conn, &parents, true, Sort::Name)?;
+        let uncounted = intersect_tag_uncounted_sorted(&conn, &parents, true, Sort::Name)?;
+        assert_eq!(
+            uncounted.iter().map(|t| &t.name).collect::<Vec<_>>(),
+            counted.iter().map(|t| &t.name).collect::<Vec<_>>()
+        );
+        assert!(uncounted.iter().all(|t| !t.is_counted()));
+
+        // filling in each one gives what the counted listing had
+        for (tag, expected) in uncounted.iter().zip(&counted) {
+            let (num_files, mtime) = tag_intersection_stats(&conn, &parents, tag.id)?;
+            assert_eq!(num_files, expected.num_files);
+            assert_eq!(mtime, Some(expected.mtime));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_swap_tags() -> Result<()> {
+        let mut conn = Connection::open_in_memory()?;
+        migrations::migrate(&mut conn, &crate::common::version_str())?;
+        let tx = begin_write(&mut conn)?;
+        tx.execute(
+            "INSERT INTO tags (id, tag_name, ts, mtime, uid, gid, permissions)
+            VALUES (1, 't1', 0, 0, 0, 0, 493), (2, 't2', 0, 0, 0, 0, 493)",
+            NO_PARAMS,
+        )?;
+        tx.execute(
+            "INSERT INTO files (id, device, inode, path, primary_tag, ts, mtime)
+            VALUES (1, 1, 1, '/f1', 'f1', 0, 0), (2, 1, 2, '/f2', 'f2', 0, 0)",
+            NO_PARAMS,
+        )?;
+        tx.execute(
+            "INSERT INTO file_tag (file_id, tag_id, ts, mtime, uid, gid, permissions)
+            VALUES (1, 1, 0, 0, 0, 0, 493), (2, 2, 0, 0, 0, 0, 493)",
+            NO_PARAMS,
+        )?;
+        let perms = Permissions::default();
+        ensure_tag_group(&tx, "g", 0, 0, &perms, 0.0)?;
+        add_tag_to_group(&tx, "t1", "g", 0, 0, &perms, 0.0)?;
+        swap_tags(&tx, "t1", "t2", 10.0)?;
+        tx.commit()?;
+
+        let ids = |tag: &str| -> Result<Vec<i64>> {
+            Ok(
+                files_tagged_with(&conn, &[TagType::Regular(tag.to_string())])?
+                    .into_iter()
+                    .map(|tf| tf.id)
+                    .collect(),
+            )
+        };
+        assert_eq!(ids("t1")?, vec![2]);
+        assert_eq!(ids("t2")?, vec![1]);
+        assert_eq!(get_tag_id(&conn, "t2")?, Some(1));
+
+        let t2_groups = tag_groups_for_tag(&conn, 1)?;
+        assert_eq!(t2_groups.len(), 1, "the group went along with t1's files");
+        assert!(tag_groups_for_tag(&conn, 2)?.is_empty());
+        assert_eq!(
+            get_tag(&conn, "t1")?.map(|t| t.mtime),
+            Some(float_to_utcdt(10.0))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_renamed_tag() -> Result<()> {
+        let mut conn = Connection::open_in_memory()?;
+        migrations::migrate(&mut conn, &crate::common::version_str())?;
+        let tx = begin_write(&mut conn)?;
+        tx.execute(
+            "INSERT INTO tags (id, tag_name, ts, mtime, uid, gid, permissions)
+            VALUES (1, 't1', 0, 0, 0, 0, 493), (2, 't2', 0, 0, 0, 0, 493)",
+            NO_PARAMS,
+        )?;
+        rename_tag(&tx, "t1", "t3", 0.0)?;
+        rename_tag(&tx, "t3", "t4", 0.0)?;
+        swap_tags(&tx, "t2", "t4", 0.0)?;
+        tx.commit()?;
+
+        let an_hour_ago = get_now_secs() - 3600.0;
+        // both old names follow the tag through every rename since, including the swap
+        assert_eq!(
+            renamed_tag(&conn, "t1", an_hour_ago)?,
+            Some("t2".to_string())
+        );
+        assert_eq!(
+            renamed_tag(&conn, "t3", an_hour_ago)?,
+            Some("t2".to_string())
+        );
+        // tags that exist under the name are never redirected
+        assert_eq!(renamed_tag(&conn, "t4", an_hour_ago)?, None);
+        assert_eq!(renamed_tag(&conn, "t1", get_now_secs() + 3600.0)?, None);
+
+        let tx = begin_write(&mut conn)?;
+        tx.execute(
+            "INSERT INTO tags (id, tag_name, ts, mtime, uid, gid, permissions)
+            VALUES (3, 't1', 0, 0, 0, 0, 493)",
+            NO_PARAMS,
+        )?;
+        tx.commit()?;
+        assert_eq!(renamed_tag(&conn, "t1", an_hour_ago)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalize_tags() -> Result<()> {
+        let composed = "caf\u{e9}";
+        let decomposed = "cafe\u{301}";
+        let mut conn = Connection::open_in_memory()?;
+        migrations::migrate(&mut conn, &crate::common::version_str())?;
+        conn.execute("UPDATE supertag_meta SET tag_form='none'", NO_PARAMS)?;
+        let tx = begin_write(&mut conn)?;
+        tx.execute(
+            "INSERT INTO tags (id, tag_name, ts, mtime, uid, gid, permissions)
+            VALUES (1, ?1, 0, 0, 0, 0, 493), (2, ?2, 0, 0, 0, 0, 493), (3, 'r\u{e9}sum\u{e9}', 0, 0, 0, 0, 493)",
+            params![decomposed, composed],
+        )?;
+        tx.execute(
+            "INSERT INTO files (id, device, inode, path, primary_tag, ts, mtime)
+            VALUES (1, 1, 1, '/f1', 'f1', 0, 0), (2, 1, 2, '/f2', 'f2', 0, 0)",
+            NO_PARAMS,
+        )?;
+        tx.execute(
+            "INSERT INTO file_tag (file_id, tag_id, ts, mtime, uid, gid, permissions)
+            VALUES (1, 1, 0, 0, 0, 0, 493), (1, 2, 0, 0, 0, 0, 493), (2, 1, 0, 0, 0, 0, 493)",
+            NO_PARAMS,
+        )?;
+        tx.execute("INSERT INTO pins (tag_ids) VALUES ('t1/t3/')", NO_PARAMS)?;
+        tx.commit()?;
+        assert_eq!(get_tag_id(&conn, decomposed)?, Some(1));
+
+        // the tag already named in the form is kept, and the other's files and pins go to it
+        assert_eq!(set_tag_form(&mut conn, TagForm::Nfc)?, 1);
+        assert_eq!(set_tag_form(&mut conn, TagForm::Nfc)?, 0);
+        let tag = get_tag(&conn, decomposed)?.expect("the tag is found by either form");
+        assert_eq!((tag.id, tag.name.as_str(), tag.num_files), (2, composed, 2));
+        assert_eq!(get_tag_id(&conn, decomposed)?, None);
+        let pin: String =
+            conn.query_row("SELECT tag_ids FROM pins", NO_PARAMS, |row| row.get(0))?;
+        assert_eq!(pin, "t2/t3/");
+
+        // and going the other way renames every tag with a composed name
+        assert_eq!(set_tag_form(&mut conn, TagForm::Nfd)?, 0);
+        assert_eq!(get_tag_id(&conn, decomposed)?, Some(2));
+        assert_eq!(get_tag_id(&conn, "re\u{301}sume\u{301}")?, Some(3));
+        Ok(())
     }
 }
@@ -0,0 +1,491 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+//! Every change to a collection's tags, files and tag groups is recorded in the `oplog` table, stamped with a hybrid
+//! logical clock.  Two copies of a collection that were changed independently are merged by exchanging their ops:
+//! each copy applies the other's, and a change is only applied if no change to the same thing came after it, so both
+//! copies end up in the same state no matter the order they merge in.
+//!
+//! Nothing merges copies of a collection yet, so changes are only recorded once `start_recording` is called, and until
+//! then the oplog stays empty.
+
+use super::types::NewFile;
+use super::SQL_TAG;
+use crate::common::settings::config::Groups;
+use crate::common::types::file_perms::UMask;
+use libc::{gid_t, uid_t};
+use rusqlite::{params, Connection, OptionalExtension, Result, Row, Transaction, NO_PARAMS};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, trace};
+
+/// A hybrid logical clock timestamp.  Timestamps order by wall clock milliseconds, then by a counter for changes made
+/// in the same millisecond, and finally by the node that made them, so that no two copies' timestamps are equal.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Hlc {
+    pub ms: i64,
+    pub counter: i64,
+    pub node: String,
+}
+
+/// A change recorded in the oplog.  What `name`, `arg`, `path` and `content_hash` hold depends on `op`:
+///
+/// * `tag_created`, `tag_deleted`, `group_created`, `group_deleted`: `name` is the tag or group
+/// * `tag_renamed`, `group_renamed`: `name` is the old name, `arg` the new one
+/// * `file_tagged`: `name` is the tag, `arg` the file's primary tag, and `path` and `content_hash` identify the file
+/// * `file_untagged`: `name` is the tag, and `path` and `content_hash` identify the file
+/// * `file_deleted`: `path` and `content_hash` identify the file
+/// * `tag_grouped`, `tag_ungrouped`: `name` is the tag, `arg` the group
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Op {
+    pub hlc: Hlc,
+    pub op: String,
+    pub name: Option<String>,
+    pub arg: Option<String>,
+    pub path: Option<String>,
+    pub content_hash: Option<String>,
+}
+
+impl Op {
+    /// The ops that, if they come after this one, override it.  Every op that changes the same thing as this one is
+    /// included, along with deleting the file or tag that it changes.  Each is (op, whether it has to match this op's
+    /// `name`, its `arg`, and its `path`).
+    fn superseded_by(&self) -> &'static [(&'static str, bool, bool, bool)] {
+        match self.op.as_str() {
+            "tag_created" | "tag_deleted" => &[
+                ("tag_created", true, false, false),
+                ("tag_deleted", true, false, false),
+            ],
+            "tag_renamed" => &[("tag_deleted", true, false, false)],
+            "group_created" | "group_deleted" => &[
+                ("group_created", true, false, false),
+                ("group_deleted", true, false, false),
+            ],
+            "group_renamed" => &[("group_deleted", true, false, false)],
+            "file_tagged" | "file_untagged" => &[
+                ("file_tagged", true, false, true),
+                ("file_untagged", true, false, true),
+                ("file_deleted", false, false, true),
+                ("tag_deleted", true, false, false),
+            ],
+            "file_deleted" => &[("file_tagged", false, false, true)],
+            "tag_grouped" | "tag_ungrouped" => &[
+                ("tag_grouped", true, true, false),
+                ("tag_ungrouped", true, true, false),
+                ("tag_deleted", true, false, false),
+            ],
+            _ => &[],
+        }
+    }
+}
+
+const OP_COLUMNS: &str = "ms, counter, node, op, name, arg, path, content_hash";
+
+fn to_op(row: &Row) -> Result<Op> {
+    Ok(Op {
+        hlc: Hlc {
+            ms: row.get(0)?,
+            counter: row.get(1)?,
+            node: row.get(2)?,
+        },
+        op: row.get(3)?,
+        name: row.get(4)?,
+        arg: row.get(5)?,
+        path: row.get(6)?,
+        content_hash: row.get(7)?,
+    })
+}
+
+/// Records every change from now on, starting with ops that build up everything that's already in the collection, so
+/// that another copy can be merged with it
+pub fn start_recording(tx: &Transaction) -> Result<()> {
+    info!(target: SQL_TAG, "Starting the oplog");
+    super::migrations::start_oplog(tx)
+}
+
+/// The node that this copy of the collection stamps its changes with
+pub fn node(conn: &Connection) -> Result<String> {
+    conn.query_row("SELECT node FROM oplog_clock", NO_PARAMS, |row| row.get(0))
+}
+
+/// The latest timestamp in the oplog, from any copy of the collection, if it has any ops
+pub fn latest(conn: &Connection) -> Result<Option<Hlc>> {
+    conn.query_row(
+        "SELECT ms, counter, node FROM oplog ORDER BY ms DESC, counter DESC, node DESC LIMIT 1",
+        NO_PARAMS,
+        |row| {
+            Ok(Hlc {
+                ms: row.get(0)?,
+                counter: row.get(1)?,
+                node: row.get(2)?,
+            })
+        },
+    )
+    .optional()
+}
+
+/// Every op in the oplog that comes after `since`, or all of them, in the order they happened
+pub fn ops_since(conn: &Connection, since: Option<&Hlc>) -> Result<Vec<Op>> {
+    let query = format!(
+        "SELECT {} FROM oplog WHERE (ms, counter, node) > (?1, ?2, ?3) ORDER BY ms, counter, node",
+        OP_COLUMNS
+    );
+    trace!(target: SQL_TAG, "{}", query);
+    let (ms, counter, node) = match since {
+        Some(hlc) => (hlc.ms, hlc.counter, hlc.node.as_str()),
+        None => (i64::MIN, i64::MIN, ""),
+    };
+    conn.prepare(&query)?
+        .query_map(params![ms, counter, node], to_op)?
+        .collect()
+}
+
+/// Whether an op that overrides `op` comes after it in the oplog
+fn is_superseded(conn: &Connection, op: &Op) -> Result<bool> {
+    let query = "
+SELECT EXISTS (
+    SELECT 1 FROM oplog
+    WHERE
+        op=?1
+        AND (ms, counter, node) > (?2, ?3, ?4)
+        AND (NOT ?5 OR name=?6)
+        AND (NOT ?7 OR arg=?8)
+        AND (NOT ?9 OR path=?10)
+)";
+    let mut stmt = conn.prepare_cached(query)?;
+    for (other, by_name, by_arg, by_path) in op.superseded_by() {
+        let superseded: bool = stmt.query_row(
+            params![
+                other,
+                op.hlc.ms,
+                op.hlc.counter,
+                op.hlc.node,
+                by_name,
+                op.name,
+                by_arg,
+                op.arg,
+                by_path,
+                op.path
+            ],
+            |row| row.get(0),
+        )?;
+        if superseded {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Finds the file that `op` is about, by its path, or by its contents if it's the only file with them.  Returns its
+/// (device, inode).
+fn find_file(conn: &Connection, op: &Op) -> Result<Option<(u64, u64)>> {
+    let to_device_inode = |row: &Row| {
+        Ok((
+            row.get::<usize, i64>(0)? as u64,
+            row.get::<usize, i64>(1)? as u64,
+        ))
+    };
+    if let Some(path) = &op.path {
+        let found = conn
+            .query_row(
+                "SELECT device, inode FROM files WHERE path=?1",
+                params![path],
+                to_device_inode,
+            )
+            .optional()?;
+        if found.is_some() {
+            return Ok(found);
+        }
+    }
+    if let Some(hash) = &op.content_hash {
+        let found = conn
+            .prepare("SELECT device, inode FROM files WHERE content_hash=?1 LIMIT 2")?
+            .query_map(params![hash], to_device_inode)?
+            .collect::<Result<Vec<_>>>()?;
+        if let [one] = found.as_slice() {
+            return Ok(Some(*one));
+        }
+    }
+    Ok(None)
+}
+
+/// Applies a single op to the collection.  Ops about tags, groups or files that we don't have are skipped, except for
+/// tagging a file we don't have, which adds it if `identify` can find it on this machine.
+fn apply<F>(
+    tx: &Transaction,
+    op: &Op,
+    groups: &Groups,
+    uid: uid_t,
+    gid: gid_t,
+    umask: &UMask,
+    now: f64,
+    identify: &mut F,
+) -> Result<()>
+where
+    F: FnMut(&str, Option<&str>) -> Option<(u64, u64)>,
+{
+    debug!(target: SQL_TAG, "Applying {:?}", op);
+    let name = op.name.as_deref().unwrap_or_default();
+    let arg = op.arg.as_deref().unwrap_or_default();
+    match op.op.as_str() {
+        "tag_created" => {
+            super::ensure_tag(tx, name, groups, uid, gid, &umask.dir_perms(), now)?;
+        }
+        "tag_deleted" => {
+            if super::tag_exists(tx, name)? {
+                super::remove_tag(tx, name, now, true)?;
+            }
+        }
+        "tag_renamed" => {
+            if super::tag_exists(tx, name)? && !super::tag_exists(tx, arg)? {
+                super::rename_tag(tx, name, arg, now)?;
+            }
+        }
+        "group_created" => {
+            super::ensure_tag_group(tx, name, uid, gid, &umask.dir_perms(), now)?;
+        }
+        "group_deleted" => super::remove_taggroup(tx, name)?,
+        "group_renamed" => {
+            if super::tag_group_exists(tx, name)? && !super::tag_group_exists(tx, arg)? {
+                super::rename_tag_group(tx, name, arg, now)?;
+            }
+        }
+        "tag_grouped" => {
+            if super::tag_exists(tx, name)? && super::tag_group_exists(tx, arg)? {
+                super::add_tag_to_group(tx, name, arg, uid, gid, &umask.file_perms(), now)?;
+            }
+        }
+        "tag_ungrouped" => {
+            tx.execute(
+                "DELETE FROM tag_group_tag
+                WHERE tg_id=(SELECT id FROM tag_groups WHERE name=?1)
+                AND tag_id=(SELECT id FROM tags WHERE tag_name=?2)",
+                params![arg, name],
+            )?;
+        }
+        "file_tagged" => match find_file(tx, op)? {
+            Some((device, inode)) => {
+                let (tag, _) =
+                    super::ensure_tag(tx, name, groups, uid, gid, &umask.dir_perms(), now)?;
+                super::link_file_to_tag(
+                    tx,
+                    device,
+                    inode,
+                    &tag,
+                    uid,
+                    gid,
+                    &umask.file_perms(),
+                    now,
+                )?;
+            }
+            None => {
+                let path = op.path.as_deref().unwrap_or_default();
+                match identify(path, op.content_hash.as_deref()) {
+                    Some((device, inode)) => {
+                        let file = NewFile {
+                            device,
+                            inode,
+                            path: path.to_owned(),
+                            primary_tag: arg.to_owned(),
+                            tags: vec![name.to_owned()],
+                        };
+                        super::add_files(tx, &[file], groups, uid, gid, umask, now)?;
+                    }
+                    None => info!(target: SQL_TAG, "Can't find {} to tag it, skipping", path),
+                }
+            }
+        },
+        "file_untagged" => {
+            if let Some((device, inode)) = find_file(tx, op)? {
+                super::unlink_file_from_tag(tx, device, inode, name, now)?;
+            }
+        }
+        "file_deleted" => {
+            if let Some(path) = &op.path {
+                super::purge_path(tx, path, now)?;
+            }
+        }
+        other => debug!(target: SQL_TAG, "Unknown op {}, skipping", other),
+    }
+    Ok(())
+}
+
+/// Merges another copy of the collection's ops into ours.  Ops we already have are ignored, and the rest are applied
+/// in the order they happened, unless a later op, from either copy, overrides them.  `identify` finds the (device,
+/// inode) of a file that we don't have yet from its path and content hash, so that it can be tagged.  Our changes
+/// made while merging aren't recorded again, and our clock is moved past theirs, so that whatever we change next
+/// comes after everything we've merged.  Returns how many ops were applied.
+pub fn merge<F>(
+    tx: &Transaction,
+    theirs: &[Op],
+    groups: &Groups,
+    uid: uid_t,
+    gid: gid_t,
+    umask: &UMask,
+    now: f64,
+    mut identify: F,
+) -> Result<usize>
+where
+    F: FnMut(&str, Option<&str>) -> Option<(u64, u64)>,
+{
+    let mut ops: Vec<&Op> = theirs.iter().collect();
+    ops.sort_by(|a, b| a.hlc.cmp(&b.hlc));
+
+    let mut new_ops = vec![];
+    {
+        let mut insert_stmt = tx.prepare_cached(&format!(
+            "INSERT OR IGNORE INTO oplog ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            OP_COLUMNS
+        ))?;
+        for op in ops {
+            let inserted = insert_stmt.execute(params![
+                op.hlc.ms,
+                op.hlc.counter,
+                op.hlc.node,
+                op.op,
+                op.name,
+                op.arg,
+                op.path,
+                op.content_hash
+            ])?;
+            if inserted > 0 {
+                new_ops.push(op);
+            }
+        }
+    }
+    info!(
+        target: SQL_TAG,
+        "Merging {} new ops of {}",
+        new_ops.len(),
+        theirs.len()
+    );
+
+    tx.execute("UPDATE oplog_clock SET replaying=1", NO_PARAMS)?;
+    let mut applied = 0;
+    for op in new_ops.iter() {
+        if is_superseded(tx, op)? {
+            trace!(target: SQL_TAG, "{:?} is superseded, skipping", op);
+            continue;
+        }
+        apply(tx, op, groups, uid, gid, umask, now, &mut identify)?;
+        applied += 1;
+    }
+    tx.execute("UPDATE oplog_clock SET replaying=0", NO_PARAMS)?;
+
+    if let Some(last) = new_ops.last() {
+        tx.execute(
+            "UPDATE oplog_clock SET
+                counter=CASE WHEN ?1 > ms THEN ?2 WHEN ?1 = ms THEN MAX(counter, ?2) ELSE counter END,
+                ms=MAX(ms, ?1)",
+            params![last.hlc.ms, last.hlc.counter],
+        )?;
+    }
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql::{begin_write, get_tag_group, migrations, tags_for_file};
+
+    fn new_db() -> Result<Connection> {
+        let mut conn = Connection::open_in_memory()?;
+        conn.execute("PRAGMA foreign_keys = 1", NO_PARAMS)?;
+        migrations::migrate(&mut conn, &crate::common::version_str())?;
+        let tx = begin_write(&mut conn)?;
+        start_recording(&tx)?;
+        tx.commit()?;
+        Ok(conn)
+    }
+
+    fn tag_file(conn: &mut Connection, inode: u64, path: &str, tags: &[&str]) -> Result<()> {
+        let tx = begin_write(conn)?;
+        let file = NewFile {
+            device: 1,
+            inode,
+            path: path.to_owned(),
+            primary_tag: path.trim_start_matches('/').to_owned(),
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+        };
+        let groups = Groups { rules: vec![] };
+        super::super::add_files(&tx, &[file], &groups, 0, 0, &UMask::default(), 1.0)?;
+        tx.commit()
+    }
+
+    fn merge_from(ours: &mut Connection, theirs: &Connection) -> Result<usize> {
+        let their_ops = ops_since(theirs, None)?;
+        let tx = begin_write(ours)?;
+        let groups = Groups { rules: vec![] };
+        // both copies are on the same machine, so every file can be found at its path
+        let applied = merge(
+            &tx,
+            &their_ops,
+            &groups,
+            0,
+            0,
+            &UMask::default(),
+            2.0,
+            |path, _| Some((1, path.len() as u64)),
+        )?;
+        tx.commit()?;
+        Ok(applied)
+    }
+
+    fn file_tags(conn: &Connection, path: &str) -> Result<Vec<String>> {
+        match super::super::get_file_id_by_path(conn, path)? {
+            Some(id) => tags_for_file(conn, id),
+            None => Ok(vec![]),
+        }
+    }
+
+    #[test]
+    fn test_merge_converges() -> Result<()> {
+        let mut a = new_db()?;
+        let mut b = new_db()?;
+        assert_ne!(node(&a)?, node(&b)?);
+
+        // inodes are the path lengths, to match how `merge_from` identifies files
+        tag_file(&mut a, 3, "/f1", &["t1", "t2"])?;
+        tag_file(&mut b, 5, "/f234", &["t3"])?;
+        assert_eq!(merge_from(&mut b, &a)?, 4);
+
+        // a untags f1 while b, later, deletes one of its tags and groups the other
+        let tx = begin_write(&mut a)?;
+        super::super::unlink_file_from_tag(&tx, 1, 3, "t1", 3.0)?;
+        tx.commit()?;
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        let tx = begin_write(&mut b)?;
+        super::super::remove_tag(&tx, "t2", 3.0, true)?;
+        super::super::ensure_tag_group(&tx, "g", 0, 0, &UMask::default().dir_perms(), 3.0)?;
+        super::super::add_tag_to_group(&tx, "t3", "g", 0, 0, &UMask::default().file_perms(), 3.0)?;
+        tx.commit()?;
+
+        merge_from(&mut a, &b)?;
+        merge_from(&mut b, &a)?;
+
+        for db in [&a, &b].iter() {
+            assert_eq!(file_tags(db, "/f1")?, Vec::<String>::new());
+            assert_eq!(file_tags(db, "/f234")?, vec!["t3".to_string()]);
+            assert!(get_tag_group(db, "g")?.is_some());
+            assert_eq!(ops_since(db, None)?.len(), ops_since(&a, None)?.len());
+        }
+        assert_eq!(latest(&a)?, latest(&b)?);
+
+        // merging again changes nothing
+        assert_eq!(merge_from(&mut a, &b)?, 0);
+        Ok(())
+    }
+}
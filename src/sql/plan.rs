@@ -0,0 +1,245 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+//! Dry runs of write transactions.  A dry run makes its changes as normal, so that every check along the way still
+//! happens, then reads back the events its changes fired and rolls them all back.
+
+use super::SQL_TAG;
+use rusqlite::{params, Connection, OptionalExtension, Result, Transaction, NO_PARAMS};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use tracing::{debug, trace};
+
+const SAVEPOINT: &str = "dry_run";
+
+/// What a dry run would have changed, one line per change
+#[derive(Debug, Default)]
+pub struct Plan {
+    pub changes: Vec<String>,
+    pub files_affected: usize,
+    pub tags_created: usize,
+    pub rows_removed: usize,
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for change in &self.changes {
+            writeln!(f, "  {}", change)?;
+        }
+        write!(
+            f,
+            "{} files affected, {} tags created, {} rows removed",
+            self.files_affected, self.tags_created, self.rows_removed
+        )
+    }
+}
+
+/// Begins a write transaction whose changes will be reported by `rollback` instead of committed
+pub fn begin(conn: &mut Connection) -> Result<Transaction> {
+    let tx = super::begin_write(conn)?;
+    tx.execute_batch(&format!("SAVEPOINT {}", SAVEPOINT))?;
+    Ok(tx)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Kind {
+    Tag,
+    File,
+    Group,
+}
+
+impl Kind {
+    fn query(self) -> &'static str {
+        match self {
+            Kind::Tag => "SELECT tag_name FROM tags WHERE id=?1",
+            Kind::File => "SELECT primary_tag FROM files WHERE id=?1",
+            Kind::Group => "SELECT name FROM tag_groups WHERE id=?1",
+        }
+    }
+}
+
+type RawEvent = (String, Option<i64>, Option<i64>, Option<i64>);
+
+/// The names of the rows that events refer to, as of one point in a transaction
+type Names = HashMap<(Kind, i64), String>;
+
+fn lookup_names(conn: &Connection, events: &[RawEvent]) -> Result<Names> {
+    let mut names = Names::new();
+    for (_, tag_id, file_id, tg_id) in events {
+        for (kind, id) in &[
+            (Kind::Tag, tag_id),
+            (Kind::File, file_id),
+            (Kind::Group, tg_id),
+        ] {
+            if let Some(id) = id {
+                if let Some(name) = lookup_name(conn, kind.query(), *id)? {
+                    names.insert((*kind, *id), name);
+                }
+            }
+        }
+    }
+    Ok(names)
+}
+
+fn lookup_name(conn: &Connection, query: &str, id: i64) -> Result<Option<String>> {
+    conn.query_row(query, params![id], |row| row.get(0))
+        .optional()
+}
+
+/// Reads back the changes made in a transaction from `begin`, then rolls them back
+pub fn rollback(tx: Transaction) -> Result<Plan> {
+    let query = "SELECT op, tag_id, file_id, tag_group_id FROM events WHERE tx_id=(SELECT id FROM event_tx) ORDER BY id";
+    trace!(target: SQL_TAG, "{}", query);
+    let events = tx
+        .prepare(query)?
+        .query_map(NO_PARAMS, |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        })?
+        .collect::<Result<Vec<RawEvent>>>()?;
+
+    // rows that were removed can only be named from before the changes, and rows that were created only from after
+    let after = lookup_names(&tx, &events)?;
+    tx.execute_batch(&format!("ROLLBACK TO {}", SAVEPOINT))?;
+    let before = lookup_names(&tx, &events)?;
+    tx.rollback()?;
+
+    let unknown = |id: i64| format!("#{}", id);
+    let name = |kind: Kind, id: Option<i64>| -> String {
+        let key = (kind, id.unwrap_or_default());
+        after
+            .get(&key)
+            .or_else(|| before.get(&key))
+            .cloned()
+            .unwrap_or_else(|| unknown(key.1))
+    };
+    let renamed = |kind: Kind, id: Option<i64>| -> String {
+        let key = (kind, id.unwrap_or_default());
+        let old = before.get(&key).cloned().unwrap_or_else(|| unknown(key.1));
+        format!("{} to {}", old, name(kind, id))
+    };
+
+    let mut plan = Plan::default();
+    let mut file_ids = BTreeSet::new();
+    for (op, tag_id, file_id, tg_id) in &events {
+        if let Some(id) = file_id {
+            file_ids.insert(*id);
+        }
+        let change = match op.as_str() {
+            "tag_created" => {
+                plan.tags_created += 1;
+                format!("create tag {}", name(Kind::Tag, *tag_id))
+            }
+            "tag_renamed" => format!("rename tag {}", renamed(Kind::Tag, *tag_id)),
+            "tag_deleted" => format!("delete tag {}", name(Kind::Tag, *tag_id)),
+            "file_renamed" => format!("rename file {}", renamed(Kind::File, *file_id)),
+            "file_deleted" => format!("delete file {}", name(Kind::File, *file_id)),
+            "file_tagged" => format!(
+                "tag {} with {}",
+                name(Kind::File, *file_id),
+                name(Kind::Tag, *tag_id)
+            ),
+            "file_untagged" => format!(
+                "untag {} from {}",
+                name(Kind::File, *file_id),
+                name(Kind::Tag, *tag_id)
+            ),
+            "group_created" => format!("create tag group {}", name(Kind::Group, *tg_id)),
+            "group_renamed" => format!("rename tag group {}", renamed(Kind::Group, *tg_id)),
+            "group_deleted" => format!("delete tag group {}", name(Kind::Group, *tg_id)),
+            "tag_grouped" => format!(
+                "add {} to tag group {}",
+                name(Kind::Tag, *tag_id),
+                name(Kind::Group, *tg_id)
+            ),
+            "tag_ungrouped" => format!(
+                "remove {} from tag group {}",
+                name(Kind::Tag, *tag_id),
+                name(Kind::Group, *tg_id)
+            ),
+            other => other.to_string(),
+        };
+        if op.ends_with("_deleted") || op.ends_with("untagged") || op.ends_with("ungrouped") {
+            plan.rows_removed += 1;
+        }
+        plan.changes.push(change);
+    }
+    plan.files_affected = file_ids.len();
+
+    debug!(target: SQL_TAG, "Dry run would have made {} changes", plan.changes.len());
+    Ok(plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql::{begin_write, migrations, tags_for_file};
+
+    #[test]
+    fn test_dry_run() -> Result<()> {
+        let mut conn = Connection::open_in_memory()?;
+        migrations::migrate(&mut conn, &crate::common::version_str())?;
+        let tx = begin_write(&mut conn)?;
+        tx.execute(
+            "INSERT INTO tags (id, tag_name, ts, mtime, uid, gid, permissions, num_files)
+            VALUES (1, 't1', 0, 0, 0, 0, 493, 1)",
+            NO_PARAMS,
+        )?;
+        tx.execute(
+            "INSERT INTO files (id, device, inode, path, primary_tag, ts, mtime)
+            VALUES (1, 1, 1, '/f1', 'f1', 0, 0)",
+            NO_PARAMS,
+        )?;
+        tx.execute(
+            "INSERT INTO file_tag (file_id, tag_id, ts, mtime, uid, gid, permissions)
+            VALUES (1, 1, 0, 0, 0, 0, 493)",
+            NO_PARAMS,
+        )?;
+        tx.commit()?;
+
+        let tx = begin(&mut conn)?;
+        tx.execute(
+            "INSERT INTO tags (id, tag_name, ts, mtime, uid, gid, permissions, num_files)
+            VALUES (2, 't2', 0, 0, 0, 0, 493, 0)",
+            NO_PARAMS,
+        )?;
+        tx.execute(
+            "INSERT INTO file_tag (file_id, tag_id, ts, mtime, uid, gid, permissions)
+            VALUES (1, 2, 0, 0, 0, 0, 493)",
+            NO_PARAMS,
+        )?;
+        tx.execute("DELETE FROM file_tag WHERE tag_id=1", NO_PARAMS)?;
+        tx.execute("DELETE FROM tags WHERE id=1", NO_PARAMS)?;
+        let plan = rollback(tx)?;
+
+        assert_eq!(
+            plan.changes,
+            vec![
+                "create tag t2",
+                "tag f1 with t2",
+                "untag f1 from t1",
+                "delete tag t1"
+            ]
+        );
+        assert_eq!(plan.files_affected, 1);
+        assert_eq!(plan.tags_created, 1);
+        assert_eq!(plan.rows_removed, 2);
+
+        // nothing was kept
+        assert_eq!(tags_for_file(&conn, 1)?, vec!["t1".to_string()]);
+        Ok(())
+    }
+}
@@ -0,0 +1,288 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Dumps a collection's tables to plain data and loads them back, so that a collection can be moved to another
+//! machine.  Rows are matched to columns by name, so a dump can be loaded by any release at the same or a later
+//! schema version, per the compatibility policy in `schema`.
+
+use super::schema;
+use super::SQL_TAG;
+use rusqlite::types::{Value, ValueRef};
+use rusqlite::{Connection, Result, Transaction, NO_PARAMS};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, trace, warn};
+
+/// Tables that the database keeps about itself, or about what's being done to it right now, which are never dumped or
+/// loaded
+const SKIPPED_TABLES: &[&str] = &["supertag_meta", "write_gate", "rpc_idempotency"];
+
+/// Our triggers write to these tables as other tables are loaded, so they're loaded last, over whatever they wrote
+const TRIGGERED_TABLES: &[&str] = &["events", "oplog", "oplog_clock"];
+
+/// Every row of a single table
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TableDump {
+    pub name: String,
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<serde_json::Value>>,
+}
+
+impl TableDump {
+    pub fn column_idx(&self, column: &str) -> Option<usize> {
+        self.columns.iter().position(|c| c == column)
+    }
+}
+
+/// Quotes a table or column name, in case it's also a keyword
+fn quoted(name: &str) -> String {
+    format!("\"{}\"", name)
+}
+
+fn to_json(value: ValueRef) -> serde_json::Value {
+    match value {
+        ValueRef::Null => serde_json::Value::Null,
+        ValueRef::Integer(i) => i.into(),
+        ValueRef::Real(f) => serde_json::Number::from_f64(f)
+            .map(serde_json::Value::Number)
+            .unwrap_or(serde_json::Value::Null),
+        ValueRef::Text(text) => String::from_utf8_lossy(text).into(),
+        ValueRef::Blob(blob) => blob.to_vec().into(),
+    }
+}
+
+fn from_json(value: &serde_json::Value) -> Value {
+    match value {
+        serde_json::Value::Null => Value::Null,
+        serde_json::Value::Bool(b) => Value::Integer(*b as i64),
+        serde_json::Value::Number(n) => match n.as_i64() {
+            Some(i) => Value::Integer(i),
+            None => Value::Real(n.as_f64().unwrap_or_default()),
+        },
+        serde_json::Value::String(s) => Value::Text(s.clone()),
+        serde_json::Value::Array(bytes) => Value::Blob(
+            bytes
+                .iter()
+                .map(|b| b.as_u64().unwrap_or_default() as u8)
+                .collect(),
+        ),
+        serde_json::Value::Object(_) => Value::Null,
+    }
+}
+
+/// Reads every row of every table in `conn`
+pub fn dump_tables(conn: &Connection) -> Result<Vec<TableDump>> {
+    let mut dumps = vec![];
+    for (table, columns) in schema::live_schema(conn)? {
+        if SKIPPED_TABLES.contains(&table.as_str()) {
+            continue;
+        }
+
+        let columns = columns
+            .into_iter()
+            .map(|(name, _sql_type)| name)
+            .collect::<Vec<_>>();
+        let query = format!(
+            "SELECT {} FROM {}",
+            columns
+                .iter()
+                .map(|c| quoted(c))
+                .collect::<Vec<_>>()
+                .join(", "),
+            quoted(&table)
+        );
+        trace!(target: SQL_TAG, "{}", query);
+        let rows = conn
+            .prepare(&query)?
+            .query_map(NO_PARAMS, |row| {
+                (0..columns.len())
+                    .map(|idx| Ok(to_json(row.get_raw_checked(idx)?)))
+                    .collect::<Result<Vec<_>>>()
+            })?
+            .collect::<Result<Vec<_>>>()?;
+
+        debug!(target: SQL_TAG, "Dumped {} rows from {}", rows.len(), table);
+        dumps.push(TableDump {
+            name: table,
+            columns,
+            rows,
+        });
+    }
+    Ok(dumps)
+}
+
+/// Replaces the contents of every table in `dumps` with the dumped rows.  Dumped tables or columns that don't exist in
+/// this database are skipped, and columns that weren't dumped get their defaults.
+pub fn load_tables(tx: &Transaction, dumps: &[TableDump]) -> Result<()> {
+    let live = schema::live_schema(tx)?;
+
+    // everything else is loaded before the triggered tables, so the events and ops that loading produces can be
+    // replaced
+    let mut ordered = dumps
+        .iter()
+        .filter(|d| !SKIPPED_TABLES.contains(&d.name.as_str()))
+        .collect::<Vec<_>>();
+    ordered.sort_by_key(|d| TRIGGERED_TABLES.contains(&d.name.as_str()));
+
+    for dump in ordered {
+        let live_columns = match live.iter().find(|(name, _columns)| *name == dump.name) {
+            Some((_name, columns)) => columns,
+            None => {
+                warn!(target: SQL_TAG, "Skipping unknown table {}", dump.name);
+                continue;
+            }
+        };
+        let shared = dump
+            .columns
+            .iter()
+            .enumerate()
+            .filter(|(_idx, col)| live_columns.iter().any(|(name, _type)| name == *col))
+            .collect::<Vec<_>>();
+
+        let query = format!("DELETE FROM {}", quoted(&dump.name));
+        trace!(target: SQL_TAG, "{}", query);
+        tx.execute(&query, NO_PARAMS)?;
+        if shared.is_empty() {
+            continue;
+        }
+
+        let query = format!(
+            "INSERT INTO {} ({}) VALUES ({})",
+            quoted(&dump.name),
+            shared
+                .iter()
+                .map(|(_idx, col)| quoted(col))
+                .collect::<Vec<_>>()
+                .join(", "),
+            vec!["?"; shared.len()].join(", ")
+        );
+        trace!(target: SQL_TAG, "{}", query);
+        let mut stmt = tx.prepare(&query)?;
+        for row in &dump.rows {
+            let values = shared
+                .iter()
+                .map(|(idx, _col)| row.get(*idx).map_or(Value::Null, from_json))
+                .collect::<Vec<_>>();
+            stmt.execute(values)?;
+        }
+        debug!(
+            target: SQL_TAG,
+            "Loaded {} rows into {}",
+            dump.rows.len(),
+            dump.name
+        );
+    }
+
+    // the clock's node tells this copy's ops apart from every other copy's, and a loaded copy is another copy
+    tx.execute(
+        "UPDATE oplog_clock SET node=lower(hex(randomblob(8)))",
+        NO_PARAMS,
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql;
+    use crate::sql::migrations;
+
+    fn new_db() -> Result<Connection> {
+        let mut conn = Connection::open_in_memory()?;
+        migrations::migrate(&mut conn, &crate::common::version_str())?;
+        Ok(conn)
+    }
+
+    #[test]
+    fn test_dump_and_load() -> Result<()> {
+        let mut conn = new_db()?;
+        let tx = sql::begin_write(&mut conn)?;
+        tx.execute(
+            "INSERT INTO tags (tag_name, ts, mtime, uid, gid, permissions, num_files)
+            VALUES ('music', 1.5, 2.5, 1000, 1000, 493, 0)",
+            NO_PARAMS,
+        )?;
+        tx.commit()?;
+
+        let dumps = dump_tables(&conn)?;
+        assert!(dumps.iter().all(|d| d.name != "supertag_meta"));
+        let tags = dumps.iter().find(|d| d.name == "tags").unwrap();
+        assert_eq!(tags.rows.len(), 1);
+        let events_before = dumps.iter().find(|d| d.name == "events").unwrap().clone();
+        let oplog_before = dumps.iter().find(|d| d.name == "oplog").unwrap().clone();
+
+        let json = serde_json::to_string(&dumps).unwrap();
+        let dumps: Vec<TableDump> = serde_json::from_str(&json).unwrap();
+
+        let mut other = new_db()?;
+        let tx = other.transaction()?;
+        load_tables(&tx, &dumps)?;
+        tx.commit()?;
+
+        let reloaded = dump_tables(&other)?;
+        assert_eq!(
+            reloaded.iter().find(|d| d.name == "tags"),
+            Some(tags),
+            "tags survive the round trip, floats included"
+        );
+        assert_eq!(
+            reloaded.iter().find(|d| d.name == "events"),
+            Some(&events_before),
+            "the events that loading triggers are replaced by the dumped ones"
+        );
+        assert_eq!(
+            reloaded.iter().find(|d| d.name == "oplog"),
+            Some(&oplog_before),
+            "and so are the ops"
+        );
+        assert_ne!(
+            sql::oplog::node(&other)?,
+            sql::oplog::node(&conn)?,
+            "but the copy gets a node of its own"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_skips_unknown_tables_and_columns() -> Result<()> {
+        let mut conn = new_db()?;
+        let dumps = vec![
+            TableDump {
+                name: "pins".to_string(),
+                columns: vec!["tag_ids".to_string(), "from_the_future".to_string()],
+                rows: vec![vec!["t1/".into(), 1.into()]],
+            },
+            TableDump {
+                name: "also_from_the_future".to_string(),
+                columns: vec!["id".to_string()],
+                rows: vec![vec![1.into()]],
+            },
+        ];
+
+        let tx = conn.transaction()?;
+        load_tables(&tx, &dumps)?;
+        tx.commit()?;
+
+        let pins = dump_tables(&conn)?
+            .into_iter()
+            .find(|d| d.name == "pins")
+            .unwrap();
+        assert_eq!(pins.columns, vec!["tag_ids".to_string()]);
+        assert_eq!(pins.rows, vec![vec![serde_json::Value::from("t1/")]]);
+        Ok(())
+    }
+}
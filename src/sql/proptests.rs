@@ -0,0 +1,223 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Property tests for `intersection_subquery`.  Random paths of tags are compiled into SQL and run against a random
+//! fixture, and the files they return are checked against a slow evaluation of the same path in Rust.
+
+use super::*;
+use crate::common::query::Expr;
+use proptest::prelude::*;
+
+const TAGS: &[&str] = &["t0", "t1", "t2", "t3", "t4"];
+/// A tag group holding the first two tags
+const GROUP: &str = "g";
+const GROUP_TAGS: &[&str] = &["t0", "t1"];
+const META_KEY: &str = "k";
+const META_VALUES: &[&str] = &["v0", "v1"];
+
+/// A file in the fixture, with the tags in `tags`, as a bit per tag in `TAGS`, and maybe a metadata value
+#[derive(Debug, Clone)]
+struct FileSpec {
+    tags: u8,
+    meta: Option<usize>,
+}
+
+impl FileSpec {
+    fn has(&self, tag: &str) -> bool {
+        match TAGS.iter().position(|t| *t == tag) {
+            Some(idx) => self.tags & (1 << idx) != 0,
+            None => false,
+        }
+    }
+}
+
+fn tag_name() -> impl Strategy<Value = String> {
+    (0..TAGS.len()).prop_map(|idx| TAGS[idx].to_string())
+}
+
+fn expr() -> impl Strategy<Value = Expr> {
+    tag_name()
+        .prop_map(Expr::Tag)
+        .prop_recursive(3, 12, 3, |inner| {
+            prop_oneof![
+                inner.clone().prop_map(|e| Expr::Not(Box::new(e))),
+                prop::collection::vec(inner.clone(), 2..4).prop_map(Expr::And),
+                prop::collection::vec(inner, 2..4).prop_map(Expr::Or),
+            ]
+        })
+}
+
+fn tag_type() -> impl Strategy<Value = TagType> {
+    prop_oneof![
+        tag_name().prop_map(TagType::Regular),
+        tag_name().prop_map(TagType::Negation),
+        prop::collection::vec(tag_name(), 2..4).prop_map(TagType::Union),
+        (0..META_VALUES.len())
+            .prop_map(|idx| TagType::Meta(META_KEY.to_string(), META_VALUES[idx].to_string())),
+        expr().prop_map(TagType::Expression),
+        Just(TagType::CollectionTag("col".to_string())),
+        Just(TagType::Group(GROUP.to_string())),
+        Just(TagType::GroupAll(GROUP.to_string())),
+    ]
+}
+
+fn files() -> impl Strategy<Value = Vec<FileSpec>> {
+    // every file has at least one tag, otherwise it isn't in the collection
+    prop::collection::vec(
+        (1u8..1 << TAGS.len(), prop::option::of(0..META_VALUES.len()))
+            .prop_map(|(tags, meta)| FileSpec { tags, meta }),
+        1..10,
+    )
+}
+
+fn fixture(files: &[FileSpec]) -> Result<Connection> {
+    let mut conn = Connection::open_in_memory()?;
+    migrations::migrate(&mut conn, &crate::common::version_str())?;
+    let tx = begin_write(&mut conn)?;
+    let perms = Permissions::default();
+    for (idx, tag) in TAGS.iter().enumerate() {
+        tx.execute(
+            "INSERT INTO tags (id, tag_name, ts, mtime, uid, gid, permissions)
+            VALUES (?1, ?2, 0, 0, 0, 0, 493)",
+            params![idx as i64, tag],
+        )?;
+    }
+    ensure_tag_group(&tx, GROUP, 0, 0, &perms, 0.0)?;
+    for tag in GROUP_TAGS {
+        add_tag_to_group(&tx, tag, GROUP, 0, 0, &perms, 0.0)?;
+    }
+
+    for (file_id, file) in files.iter().enumerate() {
+        let file_id = file_id as i64;
+        tx.execute(
+            "INSERT INTO files (id, device, inode, path, primary_tag, ts, mtime)
+            VALUES (?1, 1, ?1, '/f' || ?1, 'f' || ?1, 0, 0)",
+            params![file_id],
+        )?;
+        for (tag_id, tag) in TAGS.iter().enumerate() {
+            if file.has(tag) {
+                tx.execute(
+                    "INSERT INTO file_tag (file_id, tag_id, ts, mtime, uid, gid, permissions)
+                    VALUES (?1, ?2, 0, 0, 0, 0, 493)",
+                    params![file_id, tag_id as i64],
+                )?;
+            }
+        }
+        if let Some(value) = file.meta {
+            set_file_meta(&tx, file_id, META_KEY, META_VALUES[value], 0.0)?;
+        }
+    }
+    tx.commit()?;
+    Ok(conn)
+}
+
+fn eval(expr: &Expr, file: &FileSpec) -> bool {
+    match expr {
+        Expr::Tag(name) => file.has(name),
+        Expr::Not(inner) => !eval(inner, file),
+        Expr::And(terms) => terms.iter().all(|t| eval(t, file)),
+        Expr::Or(terms) => terms.iter().any(|t| eval(t, file)),
+    }
+}
+
+/// The ids of the files that `tags` should match.  A tag group only counts when it's last, and with nothing to start
+/// from, negations alone match nothing.
+fn reference(files: &[FileSpec], tags: &[TagType]) -> Vec<i64> {
+    let has_positive = tags.iter().enumerate().any(|(idx, tt)| match tt {
+        TagType::Negation(_) => false,
+        TagType::Group(_) | TagType::GroupAll(_) => idx == tags.len() - 1,
+        _ => true,
+    });
+    if !has_positive {
+        return vec![];
+    }
+
+    let matches = |file: &FileSpec| {
+        tags.iter().enumerate().all(|(idx, tt)| match tt {
+            TagType::Regular(name) => file.has(name),
+            TagType::Negation(name) => !file.has(name),
+            TagType::Union(names) => names.iter().any(|n| file.has(n)),
+            TagType::Meta(_, value) => file.meta.map(|m| META_VALUES[m]) == Some(value.as_str()),
+            TagType::Expression(expr) => eval(expr, file),
+            TagType::Group(_) if idx == tags.len() - 1 => GROUP_TAGS.iter().any(|t| file.has(t)),
+            TagType::GroupAll(_) if idx == tags.len() - 1 => GROUP_TAGS.iter().all(|t| file.has(t)),
+            _ => true,
+        })
+    };
+    files
+        .iter()
+        .enumerate()
+        .filter(|(_, file)| matches(file))
+        .map(|(id, _)| id as i64)
+        .collect()
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(128))]
+
+    #[test]
+    fn test_intersection_matches_reference(
+        specs in files(),
+        tags in prop::collection::vec(tag_type(), 0..5),
+    ) {
+        let conn = fixture(&specs).expect("Couldn't build fixture");
+        let (subquery, params) = intersection_subquery(&conn, &tags, 0)
+            .expect("Couldn't build subquery");
+        let query = format!("SELECT id FROM files WHERE id IN {} ORDER BY id", subquery);
+
+        let mut stmt = conn.prepare(&query)
+            .unwrap_or_else(|e| panic!("{:?} didn't parse: {}\n{}", tags, e, query));
+        let ids = stmt
+            .query_map(params, |row| row.get(0))
+            .and_then(|rows| rows.collect::<Result<Vec<i64>>>())
+            .unwrap_or_else(|e| panic!("{:?} didn't run: {}\n{}", tags, e, query));
+
+        prop_assert_eq!(ids, reference(&specs, &tags), "{:?}\n{}", tags, query);
+    }
+}
+
+#[test]
+fn test_negation_with_last_group() -> Result<()> {
+    // a trailing group is all there is to start from, which the negation must not throw away
+    let specs = vec![
+        FileSpec {
+            tags: 0b00001,
+            meta: None,
+        },
+        FileSpec {
+            tags: 0b00101,
+            meta: None,
+        },
+        FileSpec {
+            tags: 0b00100,
+            meta: None,
+        },
+    ];
+    let conn = fixture(&specs)?;
+    let tags = vec![
+        TagType::Negation("t2".to_string()),
+        TagType::Group(GROUP.to_string()),
+    ];
+    let ids: Vec<i64> = files_tagged_with(&conn, &tags)?
+        .into_iter()
+        .map(|tf| tf.id)
+        .collect();
+    assert_eq!(ids, vec![0]);
+    assert_eq!(ids, reference(&specs, &tags));
+    Ok(())
+}
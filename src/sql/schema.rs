@@ -0,0 +1,462 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! The documented, stable on-disk schema of a collection database.
+//!
+//! Third-party tools are welcome to read a collection's `db.sqlite3` directly.  In exchange, we commit to the
+//! following compatibility policy for everything described in `SCHEMA`:
+//!
+//! * Documented tables and columns are never renamed, removed, or given a different type or meaning.
+//! * New tables and new columns may be added in any release.  Every change to the schema comes with a new migration,
+//!   which bumps `supertag_meta.migration_version`.
+//! * Readers should select columns by name, ignore tables and columns they don't know about, and check that
+//!   `supertag_meta.migration_version` is at least the version they were written against.
+//! * The database is only ever written by supertag.  Tools should open it read-only, especially while the collection
+//!   is mounted.
+//!
+//! `SCHEMA` is checked against the schema that our migrations actually produce, so it can't drift from reality.
+
+use super::migrations;
+use super::SQL_TAG;
+use rusqlite::{Connection, Result, NO_PARAMS};
+use tracing::trace;
+
+pub const COMPATIBILITY_POLICY: &str = "Documented tables and columns are never renamed, removed, or retyped. \
+New tables and columns may appear in any release, along with a bump of supertag_meta.migration_version. \
+Select columns by name, ignore anything undocumented, and open the database read-only.";
+
+pub struct ColumnDoc {
+    pub name: &'static str,
+    pub sql_type: &'static str,
+    pub doc: &'static str,
+}
+
+pub struct TableDoc {
+    pub name: &'static str,
+    pub doc: &'static str,
+    pub columns: &'static [ColumnDoc],
+}
+
+macro_rules! column {
+    ($name:expr, $sql_type:expr, $doc:expr) => {
+        ColumnDoc {
+            name: $name,
+            sql_type: $sql_type,
+            doc: $doc,
+        }
+    };
+}
+
+pub const SCHEMA: &[TableDoc] = &[
+    TableDoc {
+        name: "supertag_meta",
+        doc: "A single row of metadata about the database itself.",
+        columns: &[
+            column!("migration_version", "INTEGER", "The schema version, ie the number of migrations that have run."),
+            column!("supertag_version", "TEXT", "The version of supertag that last opened the database."),
+            column!("root_mtime", "FLOAT", "Modification time of the collection's root directory, in unix seconds."),
+            column!("tags_nocase", "INTEGER", "1 if tags whose names only differ in case are the same tag, from the collection's config."),
+            column!("tag_form", "TEXT", "The Unicode normalization form that tag names are kept in, one of `nfc`, `nfd` or `none`, from the collection's config."),
+        ],
+    },
+    TableDoc {
+        name: "files",
+        doc: "Every file that has been tagged, as it exists on the real filesystem.",
+        columns: &[
+            column!("id", "INTEGER", "Primary key."),
+            column!("device", "INTEGER", "Device id of the real file.  Stored as a signed integer."),
+            column!("inode", "INTEGER", "Inode of the real file.  Stored as a signed integer."),
+            column!("path", "TEXT", "Absolute path of the real file."),
+            column!("primary_tag", "TEXT", "The name the file appears as in filedirs.  Defaults to the real file name."),
+            column!("ts", "FLOAT", "When the file was first tagged, in unix seconds."),
+            column!("mtime", "FLOAT", "When the file's entry was last modified, in unix seconds."),
+            column!("alias_file", "TEXT", "MacOS only.  Path of the alias file that this file was created from."),
+            column!("target_size", "INTEGER", "Size of the real file when it was last stat'd, or NULL if it hasn't been."),
+            column!("target_mtime", "FLOAT", "Modification time of the real file when it was last stat'd, in unix seconds, or NULL if it hasn't been."),
+            column!("content_hash", "TEXT", "Hex BLAKE3 hash of the real file's contents, or NULL if it hasn't been hashed since it last changed."),
+            column!("alias_checksum", "TEXT", "MacOS only.  Hex BLAKE3 hash of the alias file when it was linked, or NULL if it was linked before checksums were recorded."),
+            column!("is_dir", "INTEGER", "1 if the real file is a directory, which is browsed in place, otherwise 0."),
+            column!("tags_mtime", "FLOAT", "When the file was last tagged or untagged, in unix seconds."),
+            column!("rating", "INTEGER", "How many stars the file is rated, from 1 to 5, or NULL if it hasn't been."),
+        ],
+    },
+    TableDoc {
+        name: "tags",
+        doc: "Every tag, each of which appears as a directory.",
+        columns: &[
+            column!("id", "INTEGER", "Primary key."),
+            column!("tag_name", "TEXT", "The unique name of the tag."),
+            column!("ts", "FLOAT", "When the tag was created, in unix seconds."),
+            column!("mtime", "FLOAT", "When the tag was last modified, in unix seconds."),
+            column!("uid", "INTEGER", "Owner of the tag directory."),
+            column!("gid", "INTEGER", "Group of the tag directory."),
+            column!("permissions", "INTEGER", "Mode bits of the tag directory."),
+            column!("num_files", "INTEGER", "Number of files tagged with this tag."),
+            column!("color", "TEXT", "The tag's color, like `red`, or NULL if it has none."),
+            column!("icon", "TEXT", "Path of an image to use as the tag's icon, or NULL if it has none."),
+            column!("description", "TEXT", "A free-form description of the tag, or NULL if it has none."),
+        ],
+    },
+    TableDoc {
+        name: "file_tag",
+        doc: "Which files are tagged with which tags.",
+        columns: &[
+            column!("file_id", "INTEGER", "References files.id."),
+            column!("tag_id", "INTEGER", "References tags.id."),
+            column!("ts", "FLOAT", "When the file was tagged, in unix seconds."),
+            column!("mtime", "FLOAT", "When the tagging was last modified, in unix seconds."),
+            column!("uid", "INTEGER", "Owner of the symlink in the tag directory."),
+            column!("gid", "INTEGER", "Group of the symlink in the tag directory."),
+            column!("permissions", "INTEGER", "Mode bits of the symlink in the tag directory."),
+        ],
+    },
+    TableDoc {
+        name: "pins",
+        doc: "Tag directory paths that exist even though no files are tagged with them.",
+        columns: &[column!(
+            "tag_ids",
+            "TEXT",
+            "The path as tag ids, like `t1/g2/`, where `t` prefixes a tag id and `g` a tag group id."
+        )],
+    },
+    TableDoc {
+        name: "tag_groups",
+        doc: "Every tag group, each of which collects several tags under one directory.",
+        columns: &[
+            column!("id", "INTEGER", "Primary key."),
+            column!("name", "TEXT", "The unique name of the tag group, without its suffix."),
+            column!("ts", "FLOAT", "When the tag group was created, in unix seconds."),
+            column!("mtime", "FLOAT", "When the tag group was last modified, in unix seconds."),
+            column!("uid", "INTEGER", "Owner of the tag group directory."),
+            column!("gid", "INTEGER", "Group of the tag group directory."),
+            column!("permissions", "INTEGER", "Mode bits of the tag group directory."),
+        ],
+    },
+    TableDoc {
+        name: "tag_group_tag",
+        doc: "Which tags belong to which tag groups.",
+        columns: &[
+            column!("tg_id", "INTEGER", "References tag_groups.id."),
+            column!("tag_id", "INTEGER", "References tags.id."),
+            column!("ts", "FLOAT", "When the tag was added to the group, in unix seconds."),
+            column!("mtime", "FLOAT", "When the membership was last modified, in unix seconds."),
+            column!("uid", "INTEGER", "Owner of the tag directory under the group."),
+            column!("gid", "INTEGER", "Group of the tag directory under the group."),
+            column!("permissions", "INTEGER", "Mode bits of the tag directory under the group."),
+        ],
+    },
+    TableDoc {
+        name: "symbol_history",
+        doc: "Every device/inode symbol pair the collection has been mounted with.",
+        columns: &[
+            column!("id", "INTEGER", "Primary key."),
+            column!("device_char", "TEXT", "The symbol separating a file name from its device id."),
+            column!("inode_char", "TEXT", "The symbol separating a device id from its inode."),
+            column!("first_version", "TEXT", "The supertag version that first used this pair."),
+            column!("retired_version", "TEXT", "The supertag version that stopped using this pair, or NULL if current."),
+        ],
+    },
+    TableDoc {
+        name: "tag_aliases",
+        doc: "Alternate spellings of tags.  An alias never exists in the tags table itself.",
+        columns: &[
+            column!("alias", "TEXT", "The alternate spelling."),
+            column!("tag_id", "INTEGER", "References tags.id, the canonical tag the alias resolves to."),
+            column!("ts", "FLOAT", "When the alias was added, in unix seconds."),
+        ],
+    },
+    TableDoc {
+        name: "events",
+        doc: "An append-only feed of every change to tags, files, and tag groups, for tools that mirror a collection.",
+        columns: &[
+            column!("id", "INTEGER", "Primary key, and the cursor to poll with.  Always increasing, never reused."),
+            column!("op", "TEXT", "What happened, eg `file_tagged`.  See `EVENT_OPS`."),
+            column!("tag_id", "INTEGER", "References tags.id, if the change involved a tag.  The tag may since be deleted."),
+            column!("file_id", "INTEGER", "References files.id, if the change involved a file.  The file may since be deleted."),
+            column!("tag_group_id", "INTEGER", "References tag_groups.id, if the change involved a tag group."),
+            column!("tx_id", "INTEGER", "Changes made in the same transaction share a tx_id."),
+            column!("ts", "FLOAT", "When the change was made, in unix seconds."),
+        ],
+    },
+    TableDoc {
+        name: "event_tx",
+        doc: "A single row holding the tx_id of the current write transaction.",
+        columns: &[column!("id", "INTEGER", "The current tx_id.")],
+    },
+    TableDoc {
+        name: "saved_searches",
+        doc: "Named searches, each of which appears as a directory of its live results.",
+        columns: &[
+            column!("name", "TEXT", "The unique name of the search, and of its directory."),
+            column!("query", "TEXT", "The search terms, one per line, as `tag search` takes them."),
+            column!("extension", "TEXT", "Only files with this extension match, or NULL for any extension."),
+            column!("max_age_s", "INTEGER", "Only files modified within this many seconds match, or NULL for any age."),
+            column!("ts", "FLOAT", "When the search was saved, in unix seconds."),
+        ],
+    },
+    TableDoc {
+        name: "file_meta",
+        doc: "Arbitrary key/value pairs attached to files, like `client=acme`.  A file has at most one value per key.",
+        columns: &[
+            column!("file_id", "INTEGER", "References files.id."),
+            column!("key", "TEXT", "The name of the value, eg `client`."),
+            column!("value", "TEXT", "The value, eg `acme`."),
+            column!("ts", "FLOAT", "When the value was last set, in unix seconds."),
+        ],
+    },
+    TableDoc {
+        name: "undo_ops",
+        doc: "Destructive operations that `tag undo` can reverse, newest last.  Only the newest `undo.keep` are kept.",
+        columns: &[
+            column!("id", "INTEGER", "Primary key."),
+            column!("op", "TEXT", "What kind of operation it was, eg `rmdir`."),
+            column!("detail", "TEXT", "What the operation was done to, eg the path that was removed."),
+            column!("tx_id", "INTEGER", "The transaction the operation was made in, whose changes are journaled."),
+            column!("ts", "FLOAT", "When the operation was made, in unix seconds."),
+        ],
+    },
+    TableDoc {
+        name: "undo_rows",
+        doc: "The statements that reverse each change an operation made, recorded by triggers.",
+        columns: &[
+            column!("id", "INTEGER", "Primary key.  Statements are run in descending order."),
+            column!("op_id", "INTEGER", "References undo_ops.id."),
+            column!("stmt", "TEXT", "The SQL that reverses one change."),
+        ],
+    },
+    TableDoc {
+        name: "xtx_intents",
+        doc: "Operations spanning this collection and another that have committed here, but may not have finished in the other collection yet.",
+        columns: &[
+            column!("id", "TEXT", "Primary key.  The same in both collections' databases."),
+            column!("op", "TEXT", "What kind of operation it is, eg `mv`."),
+            column!("peer", "TEXT", "The other collection's name."),
+            column!("undo_op_id", "INTEGER", "In the destination collection, the undo_ops.id that rolls the operation back here.  Null in the source."),
+            column!("ts", "FLOAT", "When the operation was started, in unix seconds."),
+        ],
+    },
+    TableDoc {
+        name: "write_gate",
+        doc: "Whatever is doing maintenance on the collection, like a backup.  Writes are refused while any row exists.",
+        columns: &[
+            column!("holder", "TEXT", "Primary key.  Identifies the maintenance, so that it can let go of the gate."),
+            column!("reason", "TEXT", "Why writes are held off, as shown to the user."),
+            column!("pid", "INTEGER", "The process doing the maintenance."),
+            column!("ts", "FLOAT", "When the gate was closed, in unix seconds."),
+        ],
+    },
+    TableDoc {
+        name: "tag_renames",
+        doc: "The names that tags were renamed from, most recent rename of each name only.",
+        columns: &[
+            column!("old_name", "TEXT", "Primary key.  The name the tag was renamed from."),
+            column!("new_name", "TEXT", "The name it was renamed to.  The tag may have been renamed again since."),
+            column!("tag_id", "INTEGER", "References tags.id, the tag that was renamed."),
+            column!("ts", "FLOAT", "When the tag was renamed, in unix seconds."),
+        ],
+    },
+    TableDoc {
+        name: "tag_group_group",
+        doc: "Which tag groups are nested in which other tag groups.  A nested group's tags belong to every group above it.",
+        columns: &[
+            column!("parent_id", "INTEGER", "References tag_groups.id, the group the other is nested in."),
+            column!("child_id", "INTEGER", "References tag_groups.id, the nested group."),
+            column!("ts", "FLOAT", "When the group was nested, in unix seconds."),
+        ],
+    },
+    TableDoc {
+        name: "file_tombstones",
+        doc: "Files that were removed from the collection, so that `tag sync` removes them elsewhere too.",
+        columns: &[
+            column!("path", "TEXT", "Primary key.  Absolute path of the removed file."),
+            column!("content_hash", "TEXT", "Hex BLAKE3 hash of the removed file's contents, or NULL if it wasn't hashed."),
+            column!("ts", "FLOAT", "When the file was removed, in unix seconds."),
+        ],
+    },
+    TableDoc {
+        name: "oplog_clock",
+        doc: "A single row holding this copy of the collection's hybrid logical clock, which stamps its `oplog` ops.",
+        columns: &[
+            column!("node", "TEXT", "Random hex id that tells this copy's ops apart from every other copy's."),
+            column!("ms", "INTEGER", "Unix milliseconds of the latest timestamp, ours or merged from another copy."),
+            column!("counter", "INTEGER", "Orders timestamps within the same millisecond."),
+            column!("replaying", "INTEGER", "1 while another copy's ops are being merged, so they aren't recorded as ours."),
+        ],
+    },
+    TableDoc {
+        name: "oplog",
+        doc: "Append-only log of every change to tags, files and tag groups, for merging copies of a collection.  Empty unless recording has been started.",
+        columns: &[
+            column!("ms", "INTEGER", "Timestamp milliseconds.  Part of the primary key, with `counter` and `node`."),
+            column!("counter", "INTEGER", "Timestamp counter, for ops in the same millisecond."),
+            column!("node", "TEXT", "The `oplog_clock.node` of the copy that made the change."),
+            column!("op", "TEXT", "What changed, one of `EVENT_OPS` other than `file_renamed`."),
+            column!("name", "TEXT", "The tag or tag group changed, or its old name for a rename."),
+            column!("arg", "TEXT", "The new name, the group, or a tagged file's primary tag, depending on `op`."),
+            column!("path", "TEXT", "Absolute path of the file changed, for file ops."),
+            column!("content_hash", "TEXT", "Hex BLAKE3 hash of the file changed, if it was hashed."),
+        ],
+    },
+    TableDoc {
+        name: "file_tag_meta",
+        doc: "Key/value pairs attached to a file's tagging with one tag, like `rating=5`.  They go away when the file loses the tag.",
+        columns: &[
+            column!("file_id", "INTEGER", "References file_tag.file_id."),
+            column!("tag_id", "INTEGER", "References file_tag.tag_id."),
+            column!("key", "TEXT", "The name of the value, eg `rating`."),
+            column!("value", "TEXT", "The value, eg `5`."),
+            column!("ts", "FLOAT", "When the value was last set, in unix seconds."),
+        ],
+    },
+    TableDoc {
+        name: "rpc_idempotency",
+        doc: "The results of recent rpc calls that were made with an `idempotency_key`, so that a retried call returns them instead of running again.",
+        columns: &[
+            column!("key", "TEXT", "Primary key.  The idempotency key the call was made with."),
+            column!("method", "TEXT", "The rpc method that was called."),
+            column!("result", "TEXT", "The call's result, as JSON.  Null while the call is being made."),
+            column!("ts", "FLOAT", "When the call was made, in unix seconds.  Results are forgotten a day later."),
+        ],
+    },
+];
+
+/// Every `events.op`.  New ops may be added in any release, so readers should skip ops they don't know.
+pub const EVENT_OPS: &[&str] = &[
+    "tag_created",
+    "tag_renamed",
+    "tag_deleted",
+    "file_renamed",
+    "file_deleted",
+    "file_tagged",
+    "file_untagged",
+    "group_created",
+    "group_renamed",
+    "group_deleted",
+    "tag_grouped",
+    "tag_ungrouped",
+];
+
+/// A table's columns and their declared types, as sqlite reports them
+pub type LiveTable = (String, Vec<(String, String)>);
+
+/// Reads the tables and columns that actually exist in `conn`, skipping sqlite's internal tables
+pub fn live_schema(conn: &Connection) -> Result<Vec<LiveTable>> {
+    let query = "SELECT name FROM sqlite_master WHERE type='table' AND name NOT LIKE 'sqlite_%' ORDER BY rowid";
+    trace!(target: SQL_TAG, "{}", query);
+    let table_names = conn
+        .prepare(query)?
+        .query_map(NO_PARAMS, |row| Ok(row.get::<_, String>(0)?))?
+        .collect::<Result<Vec<String>>>()?;
+
+    let mut tables = vec![];
+    for table in table_names {
+        let query = format!("PRAGMA table_info({})", table);
+        trace!(target: SQL_TAG, "{}", query);
+        let columns = conn
+            .prepare(&query)?
+            .query_map(NO_PARAMS, |row| Ok((row.get(1)?, row.get(2)?)))?
+            .collect::<Result<Vec<(String, String)>>>()?;
+        tables.push((table, columns));
+    }
+    Ok(tables)
+}
+
+/// Counts the rows of every table that actually exists in `conn`, in the same order as `live_schema`
+pub fn table_counts(conn: &Connection) -> Result<Vec<(String, i64)>> {
+    let mut counts = vec![];
+    for (table, _columns) in live_schema(conn)? {
+        let query = format!("SELECT COUNT(*) FROM {}", table);
+        trace!(target: SQL_TAG, "{}", query);
+        let count = conn.query_row(&query, NO_PARAMS, |row| Ok(row.get(0)?))?;
+        counts.push((table, count));
+    }
+    Ok(counts)
+}
+
+/// Builds an in-memory database at the latest schema version
+pub fn reference_db() -> Result<Connection> {
+    let mut conn = Connection::open_in_memory()?;
+    migrations::migrate(&mut conn, &crate::common::version_str())?;
+    Ok(conn)
+}
+
+fn find_doc(table: &str) -> Option<&'static TableDoc> {
+    SCHEMA.iter().find(|t| t.name == table)
+}
+
+/// Renders the schema of `conn`, annotated with our documentation.  Anything that isn't documented is marked as such,
+/// since it isn't covered by the compatibility policy.
+pub fn describe(conn: &Connection) -> Result<String> {
+    let mut out = String::new();
+    out.push_str(&format!(
+        "Schema version {}\n",
+        migrations::current_version(conn)?
+    ));
+
+    for (table, columns) in live_schema(conn)? {
+        let table_doc = find_doc(&table);
+        out.push_str(&format!(
+            "\n{}\n    {}\n",
+            table,
+            table_doc.map_or("(undocumented)", |t| t.doc)
+        ));
+
+        for (name, sql_type) in columns {
+            let doc = table_doc
+                .and_then(|t| t.columns.iter().find(|c| c.name == name))
+                .map_or("(undocumented)", |c| c.doc);
+            out.push_str(&format!("    {:<18} {:<8} {}\n", name, sql_type, doc));
+        }
+    }
+
+    out.push_str(&format!("\nCompatibility: {}\n", COMPATIBILITY_POLICY));
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schema_matches_docs() -> Result<()> {
+        let conn = reference_db()?;
+
+        let live = live_schema(&conn)?;
+        let documented = SCHEMA
+            .iter()
+            .map(|t| {
+                let columns = t
+                    .columns
+                    .iter()
+                    .map(|c| (c.name.to_string(), c.sql_type.to_string()))
+                    .collect::<Vec<_>>();
+                (t.name.to_string(), columns)
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            live, documented,
+            "the live schema has drifted from the documented schema in sql/schema.rs"
+        );
+        assert_eq!(
+            migrations::current_version(&conn)?,
+            migrations::latest_version()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,261 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Aggregate queries over a whole collection, for `tag stats`.  The counting is done by sqlite, so that large
+//! collections don't need to be loaded into memory to be summarized.
+
+use super::SQL_TAG;
+use rusqlite::{params, Connection, Result, NO_PARAMS};
+use std::collections::HashMap;
+use tracing::{debug, trace};
+
+/// How many files a tag has
+#[derive(Debug, Clone, PartialEq)]
+pub struct TagCount {
+    pub name: String,
+    pub num_files: i64,
+}
+
+/// How often each pair of tags is found on the same file.  `counts[i][j]` is the number of files tagged with both
+/// `tags[i]` and `tags[j]`, so the diagonal is each tag's own file count.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CoOccurrence {
+    pub tags: Vec<String>,
+    pub counts: Vec<Vec<i64>>,
+}
+
+const TOP_TAGS_QUERY: &str = "
+SELECT
+    t.id,
+    t.tag_name,
+    COUNT(ft.file_id) AS n
+FROM tags t
+JOIN file_tag ft ON ft.tag_id=t.id
+GROUP BY t.id
+ORDER BY n DESC, t.tag_name
+LIMIT ?1
+";
+
+/// Every tag and the number of files tagged with it, most used first.  Tags without files are included.
+pub fn tag_counts(conn: &Connection) -> Result<Vec<TagCount>> {
+    debug!(target: SQL_TAG, "Counting files for all tags");
+    let query = "
+SELECT
+    t.tag_name,
+    COUNT(ft.file_id) AS n
+FROM tags t
+LEFT JOIN file_tag ft ON ft.tag_id=t.id
+GROUP BY t.id
+ORDER BY n DESC, t.tag_name
+";
+    trace!(target: SQL_TAG, "{}", query);
+    conn.prepare(query)?
+        .query_map(NO_PARAMS, |row| {
+            Ok(TagCount {
+                name: row.get(0)?,
+                num_files: row.get(1)?,
+            })
+        })?
+        .collect()
+}
+
+/// The co-occurrence of the `top` most used tags
+pub fn co_occurrence(conn: &Connection, top: usize) -> Result<CoOccurrence> {
+    debug!(target: SQL_TAG, "Computing co-occurrence of the top {} tags", top);
+    let top = top as i64;
+
+    trace!(target: SQL_TAG, "{}", TOP_TAGS_QUERY);
+    let mut stmt = conn.prepare(TOP_TAGS_QUERY)?;
+    let top_tags = stmt
+        .query_map(params![top], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get(2)?))
+        })?
+        .collect::<Result<Vec<(i64, String, i64)>>>()?;
+
+    let idx: HashMap<i64, usize> = top_tags
+        .iter()
+        .enumerate()
+        .map(|(i, (id, _, _))| (*id, i))
+        .collect();
+    let mut counts = vec![vec![0; top_tags.len()]; top_tags.len()];
+    for (i, (_, _, n)) in top_tags.iter().enumerate() {
+        counts[i][i] = *n;
+    }
+
+    let query = format!(
+        "
+WITH top AS ({})
+SELECT
+    a.tag_id,
+    b.tag_id,
+    COUNT(*)
+FROM file_tag a
+JOIN file_tag b ON b.file_id=a.file_id AND b.tag_id > a.tag_id
+WHERE
+    a.tag_id IN (SELECT id FROM top)
+    AND b.tag_id IN (SELECT id FROM top)
+GROUP BY a.tag_id, b.tag_id
+",
+        TOP_TAGS_QUERY
+    );
+    trace!(target: SQL_TAG, "{}", query);
+    let mut stmt = conn.prepare(&query)?;
+    let mut rows = stmt.query(params![top])?;
+    while let Some(row) = rows.next()? {
+        let a: i64 = row.get(0)?;
+        let b: i64 = row.get(1)?;
+        let n: i64 = row.get(2)?;
+        if let (Some(&i), Some(&j)) = (idx.get(&a), idx.get(&b)) {
+            counts[i][j] = n;
+            counts[j][i] = n;
+        }
+    }
+
+    Ok(CoOccurrence {
+        tags: top_tags.into_iter().map(|(_, name, _)| name).collect(),
+        counts,
+    })
+}
+
+/// How many tags and files the collection has, as (tags, files)
+pub fn totals(conn: &Connection) -> Result<(i64, i64)> {
+    let query = "SELECT (SELECT COUNT(*) FROM tags), (SELECT COUNT(*) FROM files)";
+    trace!(target: SQL_TAG, "{}", query);
+    conn.query_row(query, NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?)))
+}
+
+/// Paths of the files that are in the collection but no longer have any tags
+pub fn orphaned_files(conn: &Connection) -> Result<Vec<String>> {
+    debug!(target: SQL_TAG, "Finding files without tags");
+    let query = "
+SELECT path
+FROM files
+WHERE id NOT IN (SELECT file_id FROM file_tag)
+ORDER BY path
+";
+    trace!(target: SQL_TAG, "{}", query);
+    conn.prepare(query)?
+        .query_map(NO_PARAMS, |row| row.get(0))?
+        .collect()
+}
+
+/// Whether `path`, a file in the collection's managed directory, belongs to a file that still has tags
+pub fn managed_file_tagged(conn: &Connection, path: &str) -> Result<bool> {
+    trace!(target: SQL_TAG, "Checking if managed file {} is tagged", path);
+    conn.query_row(
+        "SELECT EXISTS(
+            SELECT 1
+            FROM files f
+            JOIN file_tag ft ON ft.file_id=f.id
+            WHERE f.alias_file=?1 OR f.path=?1
+        )",
+        params![path],
+        |row| row.get(0),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql;
+    use crate::sql::migrations;
+
+    fn new_db() -> Result<Connection> {
+        let mut conn = Connection::open_in_memory()?;
+        migrations::migrate(&mut conn, &crate::common::version_str())?;
+        let tx = sql::begin_write(&mut conn)?;
+        for (id, tag) in &[(1, "a"), (2, "b"), (3, "c"), (4, "unused")] {
+            tx.execute(
+                "INSERT INTO tags (id, tag_name, ts, mtime, uid, gid, permissions)
+                VALUES (?1, ?2, 0, 0, 0, 0, 493)",
+                params![id, tag],
+            )?;
+        }
+        for id in 1..=4 {
+            tx.execute(
+                "INSERT INTO files (id, device, inode, path, primary_tag, ts, mtime, alias_file)
+                VALUES (?1, 1, ?1, '/f' || ?1, 'f' || ?1, 0, 0, '/managed/f' || ?1)",
+                params![id],
+            )?;
+        }
+        // file 1 is a+b+c, file 2 is a+b, file 3 is a, file 4 has no tags
+        for (file_id, tag_id) in &[(1, 1), (1, 2), (1, 3), (2, 1), (2, 2), (3, 1)] {
+            tx.execute(
+                "INSERT INTO file_tag (file_id, tag_id, ts, mtime, uid, gid, permissions)
+                VALUES (?1, ?2, 0, 0, 0, 0, 493)",
+                params![file_id, tag_id],
+            )?;
+        }
+        tx.commit()?;
+        Ok(conn)
+    }
+
+    #[test]
+    fn test_tag_counts() -> Result<()> {
+        let conn = new_db()?;
+        let counts: Vec<_> = tag_counts(&conn)?
+            .into_iter()
+            .map(|tc| (tc.name, tc.num_files))
+            .collect();
+        assert_eq!(
+            counts,
+            vec![
+                ("a".to_string(), 3),
+                ("b".to_string(), 2),
+                ("c".to_string(), 1),
+                ("unused".to_string(), 0),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_co_occurrence() -> Result<()> {
+        let conn = new_db()?;
+        let co = co_occurrence(&conn, 2)?;
+        assert_eq!(co.tags, vec!["a", "b"]);
+        assert_eq!(co.counts, vec![vec![3, 2], vec![2, 2]]);
+
+        let co = co_occurrence(&conn, 10)?;
+        assert_eq!(
+            co.tags,
+            vec!["a", "b", "c"],
+            "tags without files are left out"
+        );
+        assert_eq!(co.counts[0][2], 1);
+        assert_eq!(co.counts[2][1], 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_totals() -> Result<()> {
+        let conn = new_db()?;
+        assert_eq!(totals(&conn)?, (4, 4));
+        Ok(())
+    }
+
+    #[test]
+    fn test_orphans() -> Result<()> {
+        let conn = new_db()?;
+        assert_eq!(orphaned_files(&conn)?, vec!["/f4"]);
+        assert!(managed_file_tagged(&conn, "/managed/f1")?);
+        assert!(!managed_file_tagged(&conn, "/managed/f4")?);
+        assert!(!managed_file_tagged(&conn, "/managed/unknown")?);
+        Ok(())
+    }
+}
@@ -0,0 +1,102 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+//! What `tag sync` exchanges about a collection: each file with its tags and when they last changed, and the files
+//! that were removed
+
+use super::SQL_TAG;
+use rusqlite::{Connection, Result, NO_PARAMS};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tracing::trace;
+
+/// A tagged file, as another machine's collection needs to see it to sync with ours
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SyncFile {
+    pub id: i64,
+    pub device: u64,
+    pub inode: u64,
+    pub path: String,
+    pub content_hash: Option<String>,
+    pub primary_tag: String,
+    /// Sorted by name
+    pub tags: Vec<String>,
+    /// When the file was last tagged or untagged, in unix seconds
+    pub tags_mtime: f64,
+}
+
+/// A file that was removed from the collection
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Tombstone {
+    pub path: String,
+    pub content_hash: Option<String>,
+    pub ts: f64,
+}
+
+/// Every file in the collection, with its tags
+pub fn sync_files(conn: &Connection) -> Result<Vec<SyncFile>> {
+    let query = "
+SELECT file_tag.file_id, tags.tag_name
+FROM file_tag
+JOIN tags ON tags.id=file_tag.tag_id
+ORDER BY tags.tag_name";
+    trace!(target: SQL_TAG, "{}", query);
+    let mut tags: HashMap<i64, Vec<String>> = HashMap::new();
+    let mut stmt = conn.prepare(query)?;
+    let mut rows = stmt.query(NO_PARAMS)?;
+    while let Some(row) = rows.next()? {
+        tags.entry(row.get(0)?).or_default().push(row.get(1)?);
+    }
+
+    let query = "
+SELECT id, device, inode, path, content_hash, primary_tag, COALESCE(tags_mtime, mtime)
+FROM files
+ORDER BY id";
+    trace!(target: SQL_TAG, "{}", query);
+    let mut stmt = conn.prepare(query)?;
+    let files = stmt
+        .query_map(NO_PARAMS, |row| {
+            let id = row.get(0)?;
+            Ok(SyncFile {
+                id,
+                device: row.get::<usize, i64>(1)? as u64,
+                inode: row.get::<usize, i64>(2)? as u64,
+                path: row.get(3)?,
+                content_hash: row.get(4)?,
+                primary_tag: row.get(5)?,
+                tags: tags.remove(&id).unwrap_or_default(),
+                tags_mtime: row.get(6)?,
+            })
+        })?
+        .collect::<Result<Vec<_>>>()?;
+    Ok(files)
+}
+
+/// Every file that was removed from the collection, and hasn't been tagged again since
+pub fn tombstones(conn: &Connection) -> Result<Vec<Tombstone>> {
+    let query = "SELECT path, content_hash, ts FROM file_tombstones ORDER BY path";
+    trace!(target: SQL_TAG, "{}", query);
+    conn.prepare(query)?
+        .query_map(NO_PARAMS, |row| {
+            Ok(Tombstone {
+                path: row.get(0)?,
+                content_hash: row.get(1)?,
+                ts: row.get(2)?,
+            })
+        })?
+        .collect()
+}
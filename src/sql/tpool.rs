@@ -22,17 +22,19 @@ use std::sync::Arc;
 use std::thread::ThreadId;
 
 use crate::sql;
-use log::trace;
 use parking_lot::{Mutex, RwLock};
 use rusqlite::Connection;
 use std::path::PathBuf;
+use tracing::{debug, trace, warn};
 
 // the pool is shared amongst threads, hence Arc
 // we want it to be safe & fast, and most of our access is Read, so RwLock
 // Arc for Hashmap value because it is shared, though not outside of this thread
 // Mutex because the RefCell has interior mutability, and different threads could (in theory) have a reference to the same value
 // RefCell because creating a transaction requires a mutable &Connection
-type ConnMap = Arc<RwLock<HashMap<ThreadId, Arc<Mutex<RefCell<Connection>>>>>>;
+// connections are keyed by database first, because a process mounting several collections shares one map
+type ThreadConns = HashMap<ThreadId, Arc<Mutex<RefCell<Connection>>>>;
+type ConnMap = Arc<RwLock<HashMap<PathBuf, ThreadConns>>>;
 
 const TAG: &str = "db_thread_pool";
 
@@ -51,6 +53,16 @@ impl ThreadConnPool {
         }
     }
 
+    /// A pool for another collection's database that shares this pool's connection map, so that a process serving
+    /// several collections keeps all of its connections in one place.  Each pool only ever hands out and closes
+    /// connections to its own database.
+    pub fn for_db(&self, db_path: PathBuf) -> Self {
+        Self {
+            pool: Arc::clone(&self.pool),
+            db_path,
+        }
+    }
+
     pub fn raw_conn(&self) -> Connection {
         sql::get_conn(&self.db_path).expect("Couldn't create db connection")
     }
@@ -64,7 +76,10 @@ impl ThreadConnPool {
         let read_guard = self.pool.read();
         trace!(target: TAG, "Got read lock");
 
-        match read_guard.get(&tid) {
+        match read_guard
+            .get(&self.db_path)
+            .and_then(|conns| conns.get(&tid))
+        {
             // we have one already?  just clone the Arc
             Some(val) => {
                 trace!(target: TAG, "Found an existing db connection");
@@ -90,9 +105,36 @@ impl ThreadConnPool {
                 trace!(target: TAG, "Acquiring write lock...");
                 let mut write_guard = self.pool.write();
                 trace!(target: TAG, "Got write lock");
-                write_guard.insert(tid, Arc::clone(&new_conn));
+                write_guard
+                    .entry(self.db_path.clone())
+                    .or_default()
+                    .insert(tid, Arc::clone(&new_conn));
                 new_conn
             }
         }
     }
+
+    /// Closes every pooled connection to our database that isn't currently in use, so their statements are finalized
+    /// and their file handles released.  Threads that ask for a connection afterwards will get a new one.  Returns how
+    /// many connections were closed.
+    pub fn close_all(&self) -> usize {
+        let conns = match self.pool.write().remove(&self.db_path) {
+            Some(conns) => conns,
+            None => return 0,
+        };
+        let mut closed = 0;
+        for (_, conn) in conns {
+            match Arc::try_unwrap(conn) {
+                Ok(conn) => {
+                    if let Err((_, e)) = conn.into_inner().into_inner().close() {
+                        warn!(target: TAG, "Couldn't cleanly close db connection: {:?}", e);
+                    } else {
+                        closed += 1;
+                    }
+                }
+                Err(_) => debug!(target: TAG, "Db connection is still in use, leaving it open"),
+            }
+        }
+        closed
+    }
 }
@@ -18,6 +18,7 @@
 
 use crate::common;
 use crate::common::settings::Settings;
+use crate::common::types::color::TagColor;
 use crate::common::types::file_perms::Permissions;
 use crate::common::types::UtcDt;
 use fuse_sys::FileEntry;
@@ -36,6 +37,12 @@ pub struct TaggedFile {
     pub gid: gid_t,
     pub permissions: Permissions,
     pub alias_file: Option<String>,
+    /// The real file's size when it was last stat'd
+    pub target_size: Option<u64>,
+    /// The real file's mtime when it was last stat'd
+    pub target_mtime: Option<UtcDt>,
+    /// Whether the real file is a directory
+    pub is_dir: bool,
 }
 
 impl TaggedFile {
@@ -79,6 +86,15 @@ pub struct Tag {
     pub num_files: i64,
 }
 
+impl Tag {
+    /// The `num_files` of a tag listed by `intersect_tag_uncounted_sorted`, whose files haven't been counted yet
+    pub const UNCOUNTED: i64 = -1;
+
+    pub fn is_counted(&self) -> bool {
+        self.num_files != Self::UNCOUNTED
+    }
+}
+
 impl From<Tag> for FileEntry {
     fn from(tag: Tag) -> Self {
         FileEntry {
@@ -88,6 +104,20 @@ impl From<Tag> for FileEntry {
     }
 }
 
+/// The optional decoration a tag can be given with `tag edit`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TagMeta {
+    pub color: Option<TagColor>,
+    pub icon: Option<String>,
+    pub description: Option<String>,
+}
+
+impl TagMeta {
+    pub fn is_empty(&self) -> bool {
+        self.color.is_none() && self.icon.is_none() && self.description.is_none()
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct TagGroup {
     pub id: i64,
@@ -124,3 +154,103 @@ impl TagOrTagGroup {
         }
     }
 }
+
+/// A device/inode symbol pair that a collection has been mounted with
+#[derive(Debug, Clone)]
+pub struct SymbolRecord {
+    pub id: i64,
+    pub device_char: char,
+    pub inode_char: char,
+    pub first_version: String,
+    pub retired_version: Option<String>,
+}
+
+/// A file to be added by `add_files`, along with the tags it should be linked to
+#[derive(Debug, Clone)]
+pub struct NewFile {
+    pub device: u64,
+    pub inode: u64,
+    pub path: String,
+    pub primary_tag: String,
+    pub tags: Vec<String>,
+}
+
+/// The outcome of applying the tag group rules to every existing tag
+#[derive(Debug, Clone, Default)]
+pub struct GroupSync {
+    /// (tag, group) pairs that weren't grouped before
+    pub added: Vec<(String, String)>,
+    /// Tags matched by the rules of more than one group, which are left alone
+    pub conflicts: Vec<(String, Vec<String>)>,
+}
+
+/// A single change from the `events` feed
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub id: i64,
+    pub op: String,
+    pub tag_id: Option<i64>,
+    pub file_id: Option<i64>,
+    pub tag_group_id: Option<i64>,
+    pub tx_id: i64,
+    pub ts: UtcDt,
+}
+
+/// A file that merging a tag would bring into a destination that already has a different file with the same name
+#[derive(Debug, Clone)]
+pub struct MergeCollision {
+    pub source: TaggedFile,
+    pub dest: TaggedFile,
+}
+
+/// A search saved under a name, which appears as a directory of the files it currently matches
+#[derive(Debug, Clone)]
+pub struct SavedSearch {
+    pub name: String,
+    /// The terms, as `tag search` takes them
+    pub terms: Vec<String>,
+    /// Only files with this extension match
+    pub extension: Option<String>,
+    /// Only files modified within this many seconds match
+    pub max_age_s: Option<i64>,
+    pub ts: UtcDt,
+}
+
+impl SavedSearch {
+    /// Whether `tf`, already matching the terms, also passes the extension and age filters as of `now`
+    pub fn filter_matches(&self, tf: &TaggedFile, now: &UtcDt) -> bool {
+        if let Some(ext) = &self.extension {
+            let file_ext = std::path::Path::new(&tf.primary_tag).extension();
+            if file_ext.map_or(true, |e| !e.to_string_lossy().eq_ignore_ascii_case(ext)) {
+                return false;
+            }
+        }
+        if let Some(max_age_s) = self.max_age_s {
+            if now.signed_duration_since(tf.mtime).num_seconds() > max_age_s {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// A tagged file that was created from a managed alias file, with the checksum the alias file had when it was linked
+#[derive(Debug, Clone, PartialEq)]
+pub struct ManagedRecord {
+    pub id: i64,
+    pub path: String,
+    pub alias_file: String,
+    pub checksum: Option<String>,
+}
+
+/// A row of the files table on its own, without any of the tags it's linked to
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileRecord {
+    pub id: i64,
+    pub device: u64,
+    pub inode: u64,
+    pub path: String,
+    pub primary_tag: String,
+    /// The real file's size when it was last stat'd
+    pub target_size: Option<u64>,
+}
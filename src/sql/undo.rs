@@ -0,0 +1,220 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+//! The journal behind `tag undo`.  A destructive operation starts by recording itself with `journal_op`, and from then
+//! on, triggers record the statement that reverses each change its transaction makes.  Undoing the operation runs
+//! those statements newest first.
+
+use super::{float_to_utcdt, SQL_TAG};
+use crate::common::types::UtcDt;
+use rusqlite::{params, Connection, OptionalExtension, Result, Transaction, NO_PARAMS};
+use tracing::{debug, info, trace};
+
+/// A destructive operation that can be undone
+#[derive(Debug, Clone)]
+pub struct UndoOp {
+    pub id: i64,
+    /// What kind of operation it was, eg `rmdir`
+    pub op: String,
+    /// What it was done to, eg the path that was removed
+    pub detail: String,
+    pub ts: UtcDt,
+}
+
+/// Starts journaling the changes of `tx` as the operation `op`, and forgets all but the newest `keep` operations.  If
+/// `tx` is already journaled, it stays the operation it was started as.  A `keep` of 0 turns journaling off.
+pub fn journal_op(tx: &Transaction, op: &str, detail: &str, keep: usize, now: f64) -> Result<()> {
+    if keep == 0 {
+        return Ok(());
+    }
+    info!(target: SQL_TAG, "Journaling {} of {}", op, detail);
+
+    let query = "INSERT OR IGNORE INTO undo_ops (op, detail, tx_id, ts) VALUES (?1, ?2, (SELECT id FROM event_tx), ?3)";
+    trace!(target: SQL_TAG, "{}", query);
+    tx.execute(query, params![op, detail, now])?;
+
+    let query =
+        "DELETE FROM undo_ops WHERE id NOT IN (SELECT id FROM undo_ops ORDER BY id DESC LIMIT ?1)";
+    trace!(target: SQL_TAG, "{}", query);
+    let pruned = tx.execute(query, params![keep as i64])?;
+    debug!(target: SQL_TAG, "Pruned {} old operations", pruned);
+    Ok(())
+}
+
+fn to_undo_op(row: &rusqlite::Row) -> Result<UndoOp> {
+    Ok(UndoOp {
+        id: row.get(0)?,
+        op: row.get(1)?,
+        detail: row.get(2)?,
+        ts: float_to_utcdt(row.get(3)?),
+    })
+}
+
+/// The newest `limit` operations that can be undone, newest first
+pub fn recent_ops(conn: &Connection, limit: usize) -> Result<Vec<UndoOp>> {
+    let query = "SELECT id, op, detail, ts FROM undo_ops ORDER BY id DESC LIMIT ?1";
+    trace!(target: SQL_TAG, "{}", query);
+    conn.prepare(query)?
+        .query_map(params![limit as i64], to_undo_op)?
+        .collect()
+}
+
+/// The operation being journaled in `tx`, if `journal_op` has been called in it
+pub fn current_op_id(tx: &Transaction) -> Result<Option<i64>> {
+    tx.query_row(
+        "SELECT id FROM undo_ops WHERE tx_id=(SELECT id FROM event_tx)",
+        NO_PARAMS,
+        |row| row.get(0),
+    )
+    .optional()
+}
+
+/// Reverses the newest operation and removes it from the journal.  Returns the operation, or None if there was
+/// nothing to undo.  Fails without changing anything if the operation can't be reversed, for example because a tag it
+/// removed has since been created again.
+pub fn undo_last(tx: &Transaction) -> Result<Option<UndoOp>> {
+    let query = "SELECT id FROM undo_ops ORDER BY id DESC LIMIT 1";
+    trace!(target: SQL_TAG, "{}", query);
+    match tx
+        .query_row(query, NO_PARAMS, |row| row.get(0))
+        .optional()?
+    {
+        Some(op_id) => undo_op(tx, op_id),
+        None => Ok(None),
+    }
+}
+
+/// Reverses the operation `op_id` and removes it from the journal, like `undo_last`, even if it isn't the newest
+pub fn undo_op(tx: &Transaction, op_id: i64) -> Result<Option<UndoOp>> {
+    let query = "SELECT id, op, detail, ts FROM undo_ops WHERE id=?1";
+    trace!(target: SQL_TAG, "{}", query);
+    let op = match tx.query_row(query, params![op_id], to_undo_op).optional()? {
+        Some(op) => op,
+        None => return Ok(None),
+    };
+    info!(target: SQL_TAG, "Undoing {} of {}", op.op, op.detail);
+
+    // rows are restored in the reverse of the order they were removed, so a child row may come back before its
+    // parent.  the foreign keys only need to hold once everything is back
+    tx.execute("PRAGMA defer_foreign_keys = 1", NO_PARAMS)?;
+
+    let stmts = tx
+        .prepare("SELECT stmt FROM undo_rows WHERE op_id=?1 ORDER BY id DESC")?
+        .query_map(params![op.id], |row| row.get(0))?
+        .collect::<Result<Vec<String>>>()?;
+    for stmt in &stmts {
+        trace!(target: SQL_TAG, "{}", stmt);
+        tx.execute(stmt, NO_PARAMS)?;
+    }
+    debug!(target: SQL_TAG, "Reversed {} changes", stmts.len());
+
+    tx.execute("DELETE FROM undo_ops WHERE id=?1", params![op.id])?;
+    Ok(Some(op))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql::{begin_write, get_tag, migrations, remove_tag, tags_for_file};
+
+    #[test]
+    fn test_undo_rmdir() -> Result<()> {
+        let mut conn = Connection::open_in_memory()?;
+        conn.execute("PRAGMA foreign_keys = 1", NO_PARAMS)?;
+        migrations::migrate(&mut conn, &crate::common::version_str())?;
+        let tx = begin_write(&mut conn)?;
+        tx.execute(
+            "INSERT INTO tags (id, tag_name, ts, mtime, uid, gid, permissions, num_files)
+            VALUES (1, 'it''s', 0, 0, 0, 0, 493, 1), (2, 't2', 0, 0, 0, 0, 493, 1)",
+            NO_PARAMS,
+        )?;
+        tx.execute(
+            "INSERT INTO files (id, device, inode, path, primary_tag, ts, mtime)
+            VALUES (1, 1, 1, '/f1', 'f1', 0, 0)",
+            NO_PARAMS,
+        )?;
+        tx.execute(
+            "INSERT INTO file_tag (file_id, tag_id, ts, mtime, uid, gid, permissions)
+            VALUES (1, 1, 0, 0, 0, 0, 493), (1, 2, 0, 0, 0, 0, 493)",
+            NO_PARAMS,
+        )?;
+        tx.commit()?;
+
+        // nothing has been journaled yet
+        assert!(recent_ops(&conn, 10)?.is_empty());
+
+        let tx = begin_write(&mut conn)?;
+        journal_op(&tx, "rmdir", "/it's", 10, 1.0)?;
+        remove_tag(&tx, "it's", 1.0, true)?;
+        tx.commit()?;
+        assert!(get_tag(&conn, "it's")?.is_none());
+        assert_eq!(tags_for_file(&conn, 1)?, vec!["t2".to_string()]);
+
+        let ops = recent_ops(&conn, 10)?;
+        assert_eq!(ops.len(), 1);
+        assert_eq!(ops[0].op, "rmdir");
+
+        let tx = begin_write(&mut conn)?;
+        let undone = undo_last(&tx)?;
+        tx.commit()?;
+        assert_eq!(undone.map(|op| op.detail), Some("/it's".to_string()));
+
+        // the tag and its link come back as they were
+        let tag = get_tag(&conn, "it's")?.unwrap();
+        assert_eq!(tag.id, 1);
+        assert_eq!(tag.num_files, 1);
+        assert_eq!(
+            tags_for_file(&conn, 1)?,
+            vec!["it's".to_string(), "t2".to_string()]
+        );
+
+        let tx = begin_write(&mut conn)?;
+        assert!(undo_last(&tx)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_journal_retention() -> Result<()> {
+        let mut conn = Connection::open_in_memory()?;
+        migrations::migrate(&mut conn, &crate::common::version_str())?;
+        for i in 0..5 {
+            let tx = begin_write(&mut conn)?;
+            journal_op(&tx, "rm", &format!("/{}", i), 3, 0.0)?;
+            // a second operation in the same transaction is part of the first
+            journal_op(&tx, "rmdir", &format!("/{}", i), 3, 0.0)?;
+            tx.commit()?;
+        }
+        let details: Vec<_> = recent_ops(&conn, 10)?
+            .into_iter()
+            .map(|op| (op.op, op.detail))
+            .collect();
+        assert_eq!(
+            details,
+            vec![
+                ("rm".to_string(), "/4".to_string()),
+                ("rm".to_string(), "/3".to_string()),
+                ("rm".to_string(), "/2".to_string()),
+            ]
+        );
+
+        let tx = begin_write(&mut conn)?;
+        journal_op(&tx, "rm", "/off", 0, 0.0)?;
+        tx.commit()?;
+        assert_eq!(recent_ops(&conn, 10)?.len(), 3);
+        Ok(())
+    }
+}
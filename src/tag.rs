@@ -88,35 +88,57 @@ fn main() -> Result<(), Box<dyn Error>> {
     // Here we're setting up the logger two different ways: one way if we're running mount, and
     // another way for all the other subcommands.  We do this for two reasons: 1) only mount should
     // go to the collection log file, and 2) the default log level for non-mount should be silent
-    if let Some(args) = matches.subcommand_matches("mount") {
+    // `mount --status` only reports, so it's treated like any other subcommand
+    if let Some(args) = matches
+        .subcommand_matches("mount")
+        .filter(|args| !args.is_present("status"))
+    {
         let maybe_log = match matches.occurrences_of("verbosity") {
             0 => None,
             1 => Some(log::LevelFilter::Info),
             2 => Some(log::LevelFilter::Debug),
             _ => Some(log::LevelFilter::Trace),
         };
-        let collection = args.value_of("collection").expect("Collection required!");
-        settings.set_collection(collection, false);
+        let collections = args
+            .values_of("collection")
+            .expect("Collection required!")
+            .collect::<Vec<_>>();
 
         // now let's set up our logger for the mount command.  this has a wrinkle because we only
         // want to log to stdout if we haven't forked, because if we have forked to the background,
         // we don't want the background process spitting output to the terminal while other commands
         // are trying to run
-        let mut log_outputs: Vec<fern::Output> = vec![];
-
-        let rotating_log = common::log::RotatingLogger::new(
-            settings.log_dir(collection),
-            format!("%Y-%m-%d-%H-{}.log", collection),
-            6,
-            100,
-        )?;
-
-        log_outputs.push(From::<Box<dyn log::Log>>::from(Box::new(rotating_log)));
-        if args.is_present("foreground") {
-            log_outputs.push(std::io::stdout().into());
+        let mut rotating_logs = vec![];
+
+        // one process can serve several collections, and each collection's log dir gets the whole log, so that
+        // looking in any of them tells the full story
+        for collection in &collections {
+            settings.set_collection(collection, false);
+            rotating_logs.push(common::log::RotatingLogger::new(
+                settings.log_dir(collection),
+                format!("%Y-%m-%d-%H-{}.log", collection),
+                6,
+                100,
+            )?);
         }
+        settings.set_collection(collections[0], false);
         if let Some(log_level) = maybe_log {
-            supertag::common::log::setup_logger(log_level, log_outputs)?;
+            if args.value_of("log-format") == Some("json") {
+                let outputs = common::log::JsonOutputs {
+                    logs: rotating_logs,
+                    stdout: args.is_present("foreground"),
+                };
+                common::log::setup_json_logger(log_level, outputs)?;
+            } else {
+                let mut log_outputs: Vec<fern::Output> = rotating_logs
+                    .into_iter()
+                    .map(|rotating_log| From::<Box<dyn log::Log>>::from(Box::new(rotating_log)))
+                    .collect();
+                if args.is_present("foreground") {
+                    log_outputs.push(std::io::stdout().into());
+                }
+                common::log::setup_logger(log_level, log_outputs)?;
+            }
         }
 
         let mut cli_source = HashMapSource(Default::default());
@@ -140,6 +162,18 @@ fn main() -> Result<(), Box<dyn Error>> {
                 .expect("Permissions not specified")
                 .into(),
         );
+        for key in &["volname", "volicon", "trace"] {
+            if let Some(val) = args.value_of(key) {
+                if collections.len() > 1 {
+                    return Err(format!(
+                        "--{} can only be given when mounting one collection",
+                        key
+                    )
+                    .into());
+                }
+                cli_source.0.insert(format!("mount.{}", key), val.into());
+            }
+        }
 
         config_sources.push(Box::new(cli_source));
     } else {
@@ -150,7 +184,13 @@ fn main() -> Result<(), Box<dyn Error>> {
             _ => Some(log::LevelFilter::Trace),
         };
         if let Some(log_level) = maybe_log {
-            common::log::setup_logger(log_level, vec![std::io::stdout().into()])?;
+            // `tag rpc` owns stdout for its responses, so its logging can't go there
+            let output: fern::Output = if matches.subcommand_name() == Some("rpc") {
+                std::io::stderr().into()
+            } else {
+                std::io::stdout().into()
+            };
+            common::log::setup_logger(log_level, vec![output])?;
         }
 
         // these 3 settings aren't used for anything tag related, but we still need them set as defaults
@@ -169,6 +209,14 @@ permissions="777""#,
     let conf = crate::common::settings::config::build(config_sources, &*pd);
     settings.update_config(conf);
 
+    // --dry-run is global, so it may have been given before or after the subcommand
+    let dry_run = matches.is_present("dry-run")
+        || matches
+            .subcommand()
+            .1
+            .map_or(false, |args| args.is_present("dry-run"));
+    settings.set_dry_run(dry_run);
+
     match matches.subcommand() {
         ("ln", Some(args)) => handlers::ln::handle(args, settings),
         ("mv", Some(args)) => handlers::mv::handle(args, settings),
@@ -176,6 +224,39 @@ permissions="777""#,
         ("rmdir", Some(args)) => handlers::rmdir::handle(args, settings),
         ("unmount", Some(args)) => handlers::unmount::handle(args, settings),
         ("fstab", Some(args)) => handlers::fstab::handle(args, settings),
+        ("service", Some(args)) => handlers::service::handle(args, settings),
+        ("migrate-symbols", Some(args)) => handlers::migrate_symbols::handle(args, settings),
+        ("db", Some(args)) => handlers::db::handle(args, settings),
+        ("alias", Some(args)) => handlers::alias::handle(args, settings),
+        ("search", Some(args)) => handlers::search::handle(args, settings),
+        ("import", Some(args)) => handlers::import::handle(args, settings),
+        ("groups", Some(args)) => handlers::groups::handle(args, settings),
+        ("watch", Some(args)) => handlers::watch::handle(args, settings),
+        ("events", Some(args)) => handlers::events::handle(args, settings),
+        ("queries", Some(args)) => handlers::queries::handle(args, settings),
+        ("report-issue", Some(args)) => handlers::report_issue::handle(args, settings),
+        ("export", Some(args)) => handlers::export::handle(args, settings),
+        ("import-collection", Some(args)) => handlers::import_collection::handle(args, settings),
+        ("doctor", Some(args)) => handlers::doctor::handle(args, settings),
+        ("edit", Some(args)) => handlers::edit::handle(args, settings),
+        ("stats", Some(args)) => handlers::stats::handle(args, settings),
+        ("meta", Some(args)) => handlers::meta::handle(args, settings),
+        ("rate", Some(args)) => handlers::rate::handle(args, settings),
+        ("undo", Some(args)) => handlers::undo::handle(args, settings),
+        ("rpc", Some(args)) => handlers::rpc::handle(args, settings),
+        ("merge", Some(args)) => handlers::merge::handle(args, settings),
+        ("swap", Some(args)) => handlers::swap::handle(args, settings),
+        ("retag", Some(args)) => handlers::retag::handle(args, settings),
+        ("sync", Some(args)) => handlers::sync::handle(args, settings),
+        ("materialize", Some(args)) => handlers::materialize::handle(args, settings),
+        ("demo", Some(args)) => handlers::demo::handle(args, settings),
+        ("open", Some(args)) => handlers::open::handle(args, settings),
+        ("replay", Some(args)) => handlers::replay::handle(args, settings),
+        ("status", Some(args)) => handlers::status::handle(args, settings),
+        ("collection", Some(args)) => handlers::collection::handle(args, settings),
+        ("dupes", Some(args)) => handlers::dupes::handle(args, settings),
+        ("verify", Some(args)) => handlers::verify::handle(args, settings),
+        ("ls", Some(args)) => handlers::ls::handle(args, settings),
         ("mount", Some(args)) => handlers::mount::handle(args, settings),
         _ => Err("Command not found".into()),
     }
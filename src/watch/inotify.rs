@@ -0,0 +1,84 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use super::{NewFiles, WATCH_TAG};
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+use std::convert::TryFrom;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::debug;
+
+pub(super) struct InotifyFiles {
+    inotify: Inotify,
+    dir: PathBuf,
+}
+
+impl InotifyFiles {
+    pub fn new(dir: &Path) -> std::io::Result<Self> {
+        let inotify =
+            Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC).map_err(to_io)?;
+        // a file is only complete once whoever is writing it closes it, or it's moved in whole
+        inotify
+            .add_watch(
+                dir,
+                AddWatchFlags::IN_CLOSE_WRITE | AddWatchFlags::IN_MOVED_TO,
+            )
+            .map_err(to_io)?;
+        Ok(Self {
+            inotify,
+            dir: dir.to_owned(),
+        })
+    }
+}
+
+impl NewFiles for InotifyFiles {
+    fn wait(&mut self, timeout: Duration) -> std::io::Result<Vec<PathBuf>> {
+        let mut fds = [PollFd::new(self.inotify.as_raw_fd(), PollFlags::POLLIN)];
+        let timeout_ms = i32::try_from(timeout.as_millis()).unwrap_or(i32::MAX);
+        match poll(&mut fds, timeout_ms) {
+            Ok(0) => return Ok(vec![]),
+            Ok(_) => {}
+            // a signal, like the Ctrl-C that stops the watch, is the same as nothing happening, so that the caller gets
+            // to check whether it should stop
+            Err(nix::Error::Sys(Errno::EINTR)) => return Ok(vec![]),
+            Err(e) => return Err(to_io(e)),
+        }
+
+        let mut files = vec![];
+        for event in self.inotify.read_events().map_err(to_io)? {
+            if event.mask.contains(AddWatchFlags::IN_ISDIR) {
+                continue;
+            }
+            if let Some(name) = event.name {
+                debug!(target: WATCH_TAG, "{:?} appeared, {:?}", name, event.mask);
+                let file = self.dir.join(name);
+                if !files.contains(&file) {
+                    files.push(file);
+                }
+            }
+        }
+        Ok(files)
+    }
+}
+
+fn to_io(e: nix::Error) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Other, e)
+}
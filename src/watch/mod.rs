@@ -0,0 +1,99 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Watches a directory and links every new file that appears in it to a set of tags.  This runs as its own long-lived
+//! process, next to the mount daemon, and talks to the collection's database the same way the `tag` cli does.
+
+#[cfg(target_os = "linux")]
+mod inotify;
+#[cfg(not(target_os = "linux"))]
+mod poll;
+
+#[cfg(target_os = "linux")]
+use self::inotify::InotifyFiles as PlatformFiles;
+#[cfg(not(target_os = "linux"))]
+use self::poll::PolledFiles as PlatformFiles;
+
+use crate::common::err::STagResult;
+use crate::common::notify::Notifier;
+use crate::common::settings::Settings;
+use crate::common::types::file_perms::UMask;
+use libc::{gid_t, uid_t};
+use rusqlite::Connection;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tracing::{error, info};
+
+const WATCH_TAG: &str = "watch";
+
+/// How long we block waiting for new files before checking whether we've been asked to stop
+const WAIT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// A source of files that have newly appeared in a directory
+trait NewFiles {
+    /// Blocks for up to `timeout`, and returns the files that finished appearing since the last call
+    fn wait(&mut self, timeout: Duration) -> std::io::Result<Vec<PathBuf>>;
+}
+
+/// Links every file that appears in `dir` to the tags in `tag_path`, until `stop` is set.  Files that are already in
+/// `dir` when we start are left alone.  `on_link` is called with each file after it has been linked.
+pub fn watch<P: AsRef<Path>, N: Notifier, F: FnMut(&Path)>(
+    settings: &Settings,
+    conn: &mut Connection,
+    mountpoint: P,
+    dir: &Path,
+    tag_path: &Path,
+    uid: uid_t,
+    gid: gid_t,
+    umask: &UMask,
+    notifier: &N,
+    stop: &AtomicBool,
+    mut on_link: F,
+) -> STagResult<()> {
+    info!(target: WATCH_TAG, "Watching {:?} for files to link to {:?}", dir, tag_path);
+    let mut new_files = PlatformFiles::new(dir)?;
+
+    while !stop.load(Ordering::Relaxed) {
+        for file in new_files.wait(WAIT_INTERVAL)? {
+            // the file may already be gone again, like an editor's temporary file
+            if !file.is_file() {
+                continue;
+            }
+
+            // one file failing to link shouldn't stop us from linking the rest
+            match crate::ln(
+                settings,
+                conn,
+                mountpoint.as_ref(),
+                vec![file.as_path()],
+                tag_path,
+                uid,
+                gid,
+                umask,
+                notifier,
+            ) {
+                Ok(()) => on_link(&file),
+                Err(e) => error!(target: WATCH_TAG, "Couldn't link {:?}: {:?}", file, e),
+            }
+        }
+    }
+
+    info!(target: WATCH_TAG, "Stopped watching {:?}", dir);
+    Ok(())
+}
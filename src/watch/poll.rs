@@ -0,0 +1,69 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Platforms without inotify find new files by periodically listing the directory.  This is good enough for the
+//! handful of directories someone would watch, and avoids binding to FSEvents.
+
+use super::{NewFiles, WATCH_TAG};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::debug;
+
+pub(super) struct PolledFiles {
+    dir: PathBuf,
+    seen: HashSet<PathBuf>,
+}
+
+impl PolledFiles {
+    pub fn new(dir: &Path) -> std::io::Result<Self> {
+        let mut polled = Self {
+            dir: dir.to_owned(),
+            seen: HashSet::new(),
+        };
+        // the files that are already here aren't new
+        polled.seen = polled.list()?.into_iter().collect();
+        Ok(polled)
+    }
+
+    fn list(&self) -> std::io::Result<Vec<PathBuf>> {
+        let mut files = vec![];
+        for entry in std::fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                files.push(entry.path());
+            }
+        }
+        Ok(files)
+    }
+}
+
+impl NewFiles for PolledFiles {
+    fn wait(&mut self, timeout: Duration) -> std::io::Result<Vec<PathBuf>> {
+        std::thread::sleep(timeout);
+
+        let current: HashSet<PathBuf> = self.list()?.into_iter().collect();
+        let new: Vec<PathBuf> = current.difference(&self.seen).cloned().collect();
+        if !new.is_empty() {
+            debug!(target: WATCH_TAG, "{:?} appeared", new);
+        }
+        // forgetting files that went away means they're linked again if they come back
+        self.seen = current;
+        Ok(new)
+    }
+}
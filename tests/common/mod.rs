@@ -46,6 +46,7 @@ use supertag::common::notify::uds::UDSNotifier;
 use supertag::common::notify::{Listener, Notifier};
 use supertag::common::types::file_perms::UMask;
 use supertag::common::types::note::Note;
+use supertag::common::types::MergeResolution;
 use supertag::common::{get_device_inode, has_ext_prefix, settings};
 use supertag::fuse::opcache::READDIR_EXPIRE_S;
 use supertag::sql::tpool::ThreadConnPool;
@@ -327,6 +328,7 @@ impl TestHelper {
 
         let socket_file = share_settings.notify_socket_file(&collection);
         let uds_notifier = UDSNotifier::new(socket_file, true).unwrap();
+        uds_notifier.serve_rpc(supertag::cli::rpc::socket_source(share_settings.clone()));
         let notifier = Arc::new(Mutex::new(uds_notifier));
         let ops = fuse::TagFilesystem::new(share_settings.clone(), conn_pool, notifier.clone());
 
@@ -906,6 +908,7 @@ impl TestHelper {
                     self.gid,
                     &UMask::default(),
                     &*(self.notifier.lock()),
+                    |_| Ok(MergeResolution::KeepBoth),
                 )?;
             }
         }
@@ -70,6 +70,15 @@ impl Notifier for TestNotifier {
         Ok(())
     }
 
+    fn protected_source(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        info!(target: TAG, "protected_source");
+        self.notes
+            .lock()
+            .unwrap()
+            .push(Note::ProtectedSource(path.to_owned()));
+        Ok(())
+    }
+
     fn tag_to_tg(&self, tag: &str) -> Result<(), Box<dyn Error>> {
         info!(target: TAG, "tag_to_tg");
         self.notes
@@ -79,6 +88,57 @@ impl Notifier for TestNotifier {
         Ok(())
     }
 
+    fn warm_progress(&self, done: usize, total: usize) -> Result<(), Box<dyn Error>> {
+        info!(target: TAG, "warm_progress");
+        self.notes
+            .lock()
+            .unwrap()
+            .push(Note::WarmProgress(done, total));
+        Ok(())
+    }
+
+    fn op_failed(&self, path: &Path, reason: &str) -> Result<(), Box<dyn Error>> {
+        info!(target: TAG, "op_failed");
+        self.notes
+            .lock()
+            .unwrap()
+            .push(Note::OpFailed(path.to_owned(), reason.to_owned()));
+        Ok(())
+    }
+
+    fn merged(&self, src: &str, dst: &str, num_files: usize) -> Result<(), Box<dyn Error>> {
+        info!(target: TAG, "merged");
+        self.notes
+            .lock()
+            .unwrap()
+            .push(Note::Merged(src.to_owned(), dst.to_owned(), num_files));
+        Ok(())
+    }
+
+    fn retagged(
+        &self,
+        path: &Path,
+        added: &[String],
+        removed: &[String],
+    ) -> Result<(), Box<dyn Error>> {
+        info!(target: TAG, "retagged");
+        self.notes.lock().unwrap().push(Note::Retagged(
+            path.to_owned(),
+            added.to_vec(),
+            removed.to_vec(),
+        ));
+        Ok(())
+    }
+
+    fn disk_full(&self, db_path: &Path, free_bytes: u64) -> Result<(), Box<dyn Error>> {
+        info!(target: TAG, "disk_full");
+        self.notes
+            .lock()
+            .unwrap()
+            .push(Note::DiskFull(db_path.to_owned(), free_bytes));
+        Ok(())
+    }
+
     fn listener(&self) -> Result<Self::Listener, Box<dyn Error>> {
         Ok(Self::Listener::new(self.notes.clone()))
     }
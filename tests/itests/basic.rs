@@ -18,12 +18,18 @@
 
 use super::{TestHelper, TestResult};
 use crate::common::OpMode;
-use std::io::ErrorKind;
+use std::io::Write;
 #[cfg(target_os = "linux")]
 #[cfg(target_os = "macos")]
 use std::os::macos::fs::MetadataExt;
+use std::path::Path;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+use supertag::cli::ln::SubdirNames;
 use supertag::common::err::STagError;
+use supertag::common::notify::uds::UDSNotifier;
 use supertag::common::types::file_perms::UMask;
 use tempfile::NamedTempFile;
 
@@ -170,8 +176,8 @@ fn test_disable_recursive_symlinks_manual() -> TestResult {
         th.mountpoint_path(&["a2"]),
         th.mountpoint_path(&["a1", "a2"]),
     ) {
-        Err(e) => match e.kind() {
-            ErrorKind::Other => Ok(()),
+        Err(e) => match e.raw_os_error() {
+            Some(libc::ELOOP) => Ok(()),
             _ => panic!("Wrong error {:?}", e),
         },
         _ => panic!("Should have had error"),
@@ -293,6 +299,804 @@ fn test_tag_difference() -> TestResult {
     Ok(())
 }
 
+// tests that a path component like `t1|t2` is the union of its member tags, and that it can be intersected with
+// other tags like a regular tag
+#[test]
+fn test_tag_union() -> TestResult {
+    let th = TestHelper::new(None);
+
+    let linked1 = th.ln(&["t1", "t3"])?;
+    let linked2 = th.ln(&["t2", "t3"])?;
+    let linked3 = th.ln(&["t2"])?;
+
+    th.assert_parts_exists(&["t1|t2"]);
+    th.assert_parts_exists(&["t1|t2", "t3"]);
+    th.assert_parts_exists(&["t3", "t1|t2"]);
+
+    // unions can only be made from existing tags
+    th.assert_parts_not_exists(&["t1|nope"]);
+
+    th.assert_count(&["t1|t2"], 3);
+    th.assert_count(&["t1|t2", "t3"], 2);
+    th.assert_count(&["t3", "t1|t2"], 2);
+
+    th.assert_path_exists(linked1.link_filedir_path(&["t1|t2"], false));
+    th.assert_path_exists(linked2.link_filedir_path(&["t1|t2", "t3"], false));
+    th.assert_path_not_exists(linked3.link_filedir_path(&["t1|t2", "t3"], false));
+
+    // and a tag can't be made with the separator in it, even one that wouldn't read as a union
+    assert!(th.mkdir("t4|").is_err());
+    assert!(th.ln(&["t5|"]).is_err());
+    th.assert_parts_not_exists(&["t4|"]);
+    th.assert_parts_not_exists(&["t5|"]);
+
+    Ok(())
+}
+
+// tests that a path component like `?(t1&(t2|t3)&!t4)` holds the files matching the boolean expression, and that it
+// can be intersected with other tags like a regular tag
+#[test]
+fn test_tag_expression() -> TestResult {
+    let th = TestHelper::new(None);
+
+    let linked1 = th.ln(&["t1", "t2"])?;
+    let linked2 = th.ln(&["t1", "t3", "t4"])?;
+    let linked3 = th.ln(&["t1", "t3", "t5"])?;
+    let _linked4 = th.ln(&["t2", "t5"])?;
+
+    th.assert_parts_exists(&["?(t1&(t2|t3)&!t4)"]);
+    th.assert_count(&["?(t1&(t2|t3)&!t4)"], 2);
+    th.assert_count(&["?(t1|t5)"], 4);
+    th.assert_count(&["?(t1|t5)", "t2"], 2);
+    th.assert_count(&["t5", "?(!t1)"], 1);
+
+    th.assert_path_exists(linked1.link_filedir_path(&["?(t1&(t2|t3)&!t4)"], false));
+    th.assert_path_not_exists(linked2.link_filedir_path(&["?(t1&(t2|t3)&!t4)"], false));
+    th.assert_path_exists(linked3.link_filedir_path(&["?(t1&(t2|t3)&!t4)", "t5"], false));
+
+    // expressions can only be made from existing tags, and must be well formed
+    th.assert_parts_not_exists(&["?(t1&nope)"]);
+    th.assert_parts_not_exists(&["?(t1&)"]);
+
+    Ok(())
+}
+
+// tests that, with dates enabled, the years and months that files were modified in are listed, and intersect with tags
+#[test]
+fn test_date_dirs() -> TestResult {
+    let test_config = r#"
+[symbols]
+inode_char = "-"
+device_char = "﹫"
+sync_char = "\u007F"
+filedir_str = "⋂"
+filedir_cli_str = "_"
+tag_group_str = "+"
+
+[dates]
+enabled = true
+"#;
+    let th = TestHelper::new(Some(test_config));
+    let linked1 = th.ln(&["t1", "t2"])?;
+    th.ln(&["t2"])?;
+
+    // the files were just made, so they're in the current month
+    let now = chrono::Local::now();
+    let year = format!("@{}", now.format("%Y"));
+    let month = format!("@{}", now.format("%Y-%m"));
+
+    assert!(th.ls(&[])?.contains(&year));
+    assert!(th.ls_tags(&["t1"])?.contains(&year));
+    assert!(th.ls_tags(&["t1", &year])?.contains(&month));
+    assert!(!th.ls_tags(&["t1", &year])?.contains(&year));
+
+    th.assert_count(&["t2", &year], 2);
+    th.assert_count(&["t1", &year, &month], 1);
+    th.assert_path_exists(linked1.link_filedir_path(&["t1", &year, &month], false));
+    th.assert_path_exists(linked1.link_filedir_path(&[&month, "t2"], false));
+
+    // a date that no file was modified in doesn't exist
+    th.assert_parts_not_exists(&["t1", "@1999"]);
+
+    Ok(())
+}
+
+// tests that, with file types enabled, the file type group lists the extensions of the files before it, and that they
+// intersect with tags
+#[test]
+fn test_filetype_dirs() -> TestResult {
+    let test_config = r#"
+[symbols]
+inode_char = "-"
+device_char = "﹫"
+sync_char = "\u007F"
+filedir_str = "⋂"
+filedir_cli_str = "_"
+tag_group_str = "+"
+
+[filetypes]
+enabled = true
+group = "filetype"
+"#;
+    let th = TestHelper::new(Some(test_config));
+    let mut builder = tempfile::Builder::new();
+    builder
+        .prefix("supertag-testfile")
+        .suffix(".pdf")
+        .rand_bytes(8);
+    let pdf = th.ln_with_tempfile(Rc::new(builder.tempfile()?), &["t1", "t2"])?;
+    let tmp = th.ln(&["t1"])?;
+
+    assert!(th.ls(&[])?.contains(&"filetype+".to_string()));
+    assert!(th.ls_tags(&["t1"])?.contains(&"filetype+".to_string()));
+    assert_eq!(th.ls(&["t1", "filetype+"])?, vec!["pdf", "tmp"]);
+    assert_eq!(th.ls(&["t2", "filetype+"])?, vec!["pdf"]);
+
+    th.assert_count(&["t1", "filetype+", "pdf"], 1);
+    th.assert_path_exists(pdf.link_filedir_path(&["t1", "filetype+", "pdf"], false));
+    th.assert_path_not_exists(tmp.link_filedir_path(&["t1", "filetype+", "pdf"], false));
+    th.assert_path_exists(tmp.link_filedir_path(&["filetype+", "tmp"], false));
+
+    // an extension that no file has doesn't exist
+    th.assert_parts_not_exists(&["t1", "filetype+", "md"]);
+
+    Ok(())
+}
+
+// tests that, with duplicates enabled, files with the same contents are listed in the duplicates directory, whatever
+// they're tagged with
+#[test]
+fn test_duplicates_dir() -> TestResult {
+    let test_config = r#"
+[symbols]
+inode_char = "-"
+device_char = "﹫"
+sync_char = "\u007F"
+filedir_str = "⋂"
+filedir_cli_str = "_"
+tag_group_str = "+"
+
+[duplicates]
+enabled = true
+dir = "+duplicates"
+"#;
+    let mut th = TestHelper::new(Some(test_config));
+    let mut builder = tempfile::Builder::new();
+    builder.prefix("supertag-testfile").rand_bytes(8);
+    let mut unique_file = builder.tempfile()?;
+    unique_file.write_all(b"unique")?;
+    let unique = th.ln_with_tempfile(Rc::new(unique_file), &["t1"])?;
+    assert!(!th.ls(&[])?.contains(&"+duplicates".to_string()));
+
+    // the test files are empty, so they're all the same
+    let copy1 = th.ln(&["t1"])?;
+    let copy2 = th.ln(&["t2"])?;
+    assert!(th.ls(&[])?.contains(&"+duplicates".to_string()));
+
+    let mut expected = vec![copy1.link_filename(false), copy2.link_filename(false)];
+    expected.sort();
+    assert_eq!(th.ls(&["+duplicates"])?, expected);
+    assert!(!th
+        .ls(&["+duplicates"])?
+        .contains(&unique.link_filename(false)));
+
+    let dupe_path = th.mountpoint_path(&["+duplicates", &copy1.link_filename(false)]);
+    assert_eq!(std::fs::read_link(&dupe_path)?, copy1.target_path());
+
+    // and it's read-only
+    assert!(std::fs::remove_file(&dupe_path).is_err());
+    th.assert_size(&["t1"], 2);
+
+    // a file linked through the mount is hashed as well
+    th.symlink_mode = OpMode::MANUAL;
+    let copy3 = th.ln(&["t3"])?;
+    assert!(th
+        .ls(&["+duplicates"])?
+        .contains(&copy3.link_filename(false)));
+
+    Ok(())
+}
+
+// tests that a tagged directory can be browsed in place, and that its files can be listed on their own too
+#[test]
+fn test_tagged_dir() -> TestResult {
+    let test_config = r#"
+[symbols]
+inode_char = "-"
+device_char = "﹫"
+sync_char = "\u007F"
+filedir_str = "⋂"
+filedir_cli_str = "_"
+tag_group_str = "+"
+
+[tagging]
+dir_children = true
+"#;
+    let th = TestHelper::new(Some(test_config));
+    let dir = tempfile::Builder::new()
+        .prefix("supertag-testdir")
+        .tempdir()?;
+    let real_dir = dir.path().canonicalize()?;
+    std::fs::create_dir(real_dir.join("sub"))?;
+    std::fs::write(real_dir.join("a.txt"), b"a")?;
+    std::fs::write(real_dir.join("sub/b.txt"), b"b")?;
+    th.ln_cli(&real_dir, &th.mountpoint_path(&["t1"]))?;
+
+    let dir_name = real_dir.file_name().unwrap().to_string_lossy().to_string();
+    let mut expected = vec!["a.txt".to_string(), "b.txt".to_string(), dir_name.clone()];
+    expected.sort();
+    assert_eq!(th.ls_filedir(&["t1"])?, expected);
+
+    let tagged_dir = th.filedir_path(&["t1"]).join(&dir_name);
+    assert!(std::fs::symlink_metadata(&tagged_dir)?.is_dir());
+    assert_eq!(th.ls(&["t1", "⋂", &dir_name])?, vec!["a.txt", "sub"]);
+    assert_eq!(th.ls(&["t1", "⋂", &dir_name, "sub"])?, vec!["b.txt"]);
+    assert_eq!(
+        std::fs::read_link(tagged_dir.join("sub/b.txt"))?,
+        real_dir.join("sub/b.txt")
+    );
+    assert_eq!(std::fs::read(tagged_dir.join("a.txt"))?, b"a");
+
+    // and it's read-only
+    assert!(std::fs::write(tagged_dir.join("c.txt"), b"c").is_err());
+    assert!(!real_dir.join("c.txt").exists());
+    Ok(())
+}
+
+// tests that linking recursively links every file in the tree, keeping its subdirectories as tags or a name prefix
+#[test]
+fn test_recursive_ln() -> TestResult {
+    let th = TestHelper::new(None);
+    let make_tree = || -> std::io::Result<tempfile::TempDir> {
+        let dir = tempfile::Builder::new()
+            .prefix("supertag-testdir")
+            .tempdir()?;
+        std::fs::create_dir_all(dir.path().join("2021/07"))?;
+        std::fs::write(dir.path().join("a.txt"), b"a")?;
+        std::fs::write(dir.path().join("2021/b.txt"), b"b")?;
+        std::fs::write(dir.path().join("2021/07/c.txt"), b"c")?;
+        Ok(dir)
+    };
+    let ln_tree = |dir: &Path, tags: &[&str], subdirs: SubdirNames| -> TestResult {
+        let tree = supertag::cli::ln::tree_files(&th.settings, vec![dir], subdirs)?;
+        let mut conn = th.fresh_conn();
+        let summary = supertag::cli::ln::ln_tree_with_progress(
+            &th.settings,
+            &mut conn,
+            &th.real_mountpoint(),
+            &tree,
+            &th.mountpoint_path(tags),
+            th.uid,
+            th.gid,
+            &UMask::default(),
+            &AtomicBool::new(false),
+            |_, _| {},
+        )?;
+        assert_eq!((summary.linked, summary.total), (3, 3));
+        Ok(())
+    };
+
+    let as_tags = make_tree()?;
+    ln_tree(as_tags.path(), &["t1"], SubdirNames::Tags)?;
+    assert_eq!(th.ls_filedir(&["t1"])?, vec!["a.txt", "b.txt", "c.txt"]);
+    assert_eq!(th.ls_filedir(&["t1", "2021"])?, vec!["b.txt", "c.txt"]);
+    assert_eq!(th.ls_filedir(&["t1", "2021", "07"])?, vec!["c.txt"]);
+
+    let as_prefix = make_tree()?;
+    ln_tree(as_prefix.path(), &["t2"], SubdirNames::Prefix)?;
+    assert_eq!(
+        th.ls_filedir(&["t2"])?,
+        vec!["2021_07_c.txt", "2021_b.txt", "a.txt"]
+    );
+    Ok(())
+}
+
+// tests that linking recursively never walks into a mounted collection or the collections dir
+#[test]
+fn test_recursive_ln_skips_collections() -> TestResult {
+    let th = TestHelper::new(None);
+    let other = TestHelper::new(None);
+    other.ln(&["t1"])?;
+
+    let skipped = supertag::cli::ln::skipped_dirs(&th.settings);
+    assert!(skipped.contains(&th.real_mountpoint()));
+    assert!(skipped.contains(&other.real_mountpoint()));
+
+    let other_mount = other.real_mountpoint();
+    let tree = supertag::cli::ln::tree_files(&th.settings, vec![&other_mount], SubdirNames::Tags)?;
+    assert!(tree.is_empty());
+
+    // the project dir holds the collections dir, with its database and socket
+    let base = th.project_directories.base();
+    std::fs::write(base.join("keep.txt"), b"keep")?;
+    let tree = supertag::cli::ln::tree_files(&th.settings, vec![&base], SubdirNames::Tags)?;
+    let collections_dir = th.settings.collections_dir().canonicalize()?;
+    assert!(tree.iter().any(|file| file.primary_tag == "keep.txt"));
+    assert!(tree
+        .iter()
+        .all(|file| !file.src.starts_with(&collections_dir)));
+    Ok(())
+}
+
+// tests that tagging with an alias lands on the canonical tag, and that intersecting with an alias intersects with
+// the canonical tag
+#[test]
+fn test_tag_aliases() -> TestResult {
+    let th = TestHelper::new(None);
+    let linked1 = th.ln(&["machine-learning", "t1"])?;
+
+    {
+        let mut conn = th.fresh_conn();
+        let tx = conn.transaction()?;
+        let tag_id = supertag::sql::get_tag_id(&tx, "machine-learning")?.unwrap();
+        supertag::sql::add_alias(&tx, "ml", tag_id, supertag::sql::get_now_secs())?;
+        tx.commit()?;
+
+        assert_eq!(
+            supertag::sql::resolve_alias(&conn, "ml")?,
+            "machine-learning"
+        );
+        assert_eq!(supertag::sql::resolve_alias(&conn, "t1")?, "t1");
+    }
+
+    let linked2 = th.ln(&["ml"])?;
+
+    th.assert_parts_not_exists(&["ml"]);
+    th.assert_count(&["machine-learning"], 2);
+    th.assert_path_exists(linked2.link_filedir_path(&["machine-learning"], false));
+    th.assert_path_exists(linked1.link_filedir_path(&["t1", "machine-learning"], false));
+
+    let conn = th.fresh_conn();
+    let tags = supertag::sql::intersect_tag(
+        &conn,
+        &[supertag::common::types::TagType::Regular("ml".to_string())],
+        true,
+    )?;
+    assert_eq!(
+        tags.into_iter().map(|t| t.name).collect::<Vec<_>>(),
+        vec!["t1".to_string()]
+    );
+
+    Ok(())
+}
+
+// tests that changes land in the events feed in order, and that a cursor only picks up what came after it
+#[test]
+fn test_events_feed() -> TestResult {
+    let th = TestHelper::new(None);
+    th.ln(&["t1", "t2"])?;
+
+    let events = supertag::sql::events_since(&th.fresh_conn(), 0, 1000)?;
+    let ops = events.iter().map(|e| e.op.as_str()).collect::<Vec<_>>();
+    assert_eq!(ops.iter().filter(|op| **op == "tag_created").count(), 2);
+    assert_eq!(ops.iter().filter(|op| **op == "file_tagged").count(), 2);
+    assert!(events.windows(2).all(|w| w[0].id < w[1].id));
+
+    // both tags were linked in the same transaction
+    let tagged = events
+        .iter()
+        .filter(|e| e.op == "file_tagged")
+        .collect::<Vec<_>>();
+    assert_eq!(tagged[0].tx_id, tagged[1].tx_id);
+    assert_eq!(tagged[0].file_id, tagged[1].file_id);
+
+    let cursor = events.last().unwrap().id;
+    assert!(supertag::sql::events_since(&th.fresh_conn(), cursor, 1000)?.is_empty());
+
+    th.ln(&["t1"])?;
+    let more = supertag::sql::events_since(&th.fresh_conn(), cursor, 1000)?;
+    assert_eq!(
+        more.iter().map(|e| e.op.as_str()).collect::<Vec<_>>(),
+        vec!["file_tagged"]
+    );
+    assert!(more[0].tx_id > tagged[0].tx_id);
+    assert_eq!(
+        supertag::sql::events_since(&th.fresh_conn(), 0, 1)?.len(),
+        1
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_saved_search() -> TestResult {
+    let th = TestHelper::new(None);
+    let _both = th.ln(&["t1", "t2"])?;
+    let only_t1 = th.ln(&["t1"])?;
+
+    {
+        let mut conn = th.fresh_conn();
+        let tx = supertag::sql::begin_write(&mut conn)?;
+        let now = supertag::sql::get_now_secs();
+        supertag::sql::add_saved_search(&tx, "only-t1", &["t1", "-t2"], None, None, now)?;
+        supertag::sql::add_saved_search(&tx, "no-match", &["t1"], Some("nope"), None, now)?;
+        tx.commit()?;
+    }
+
+    assert!(th.ls(&[])?.contains(&"queries".to_string()));
+    assert_eq!(th.ls(&["queries"])?, vec!["no-match", "only-t1"]);
+    assert_eq!(
+        th.ls(&["queries", "only-t1"])?,
+        vec![only_t1.link_filename(false)]
+    );
+    assert!(th.ls(&["queries", "no-match"])?.is_empty());
+
+    let result_path = th.mountpoint_path(&["queries", "only-t1", &only_t1.link_filename(false)]);
+    assert_eq!(std::fs::read_link(&result_path)?, only_t1.target_path());
+
+    // results are live
+    let another = th.ln(&["t1"])?;
+    assert_eq!(th.ls(&["queries", "only-t1"])?.len(), 2);
+    assert!(th
+        .ls(&["queries", "only-t1"])?
+        .contains(&another.link_filename(false)));
+
+    // and read-only
+    assert!(std::fs::remove_file(&result_path).is_err());
+    th.assert_size(&["t1"], 3);
+
+    Ok(())
+}
+
+// tests that the collection's tag matches every file without being linked to any of them
+#[test]
+fn test_collection_tag() -> TestResult {
+    let test_config = r#"
+[symbols]
+inode_char = "-"
+device_char = "﹫"
+sync_char = "\u007F"
+filedir_str = "⋂"
+filedir_cli_str = "_"
+tag_group_str = "+"
+
+[mount]
+
+[collection]
+tag = "col:test"
+"#;
+    let th = TestHelper::new(Some(test_config));
+    let _t1 = th.ln(&["t1"])?;
+    let t2 = th.ln(&["t2"])?;
+
+    let conn = th.fresh_conn();
+    let everything = th.settings.query_to_tags(&["col:test"])?;
+    assert_eq!(
+        supertag::sql::files_tagged_with(&conn, &everything)?.len(),
+        2
+    );
+
+    let not_t1 = th.settings.query_to_tags(&["col:test", "-t1"])?;
+    let found = supertag::sql::files_tagged_with(&conn, &not_t1)?;
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].path, t2.target_path().to_string_lossy());
+
+    // it never becomes a real tag
+    assert!(supertag::sql::get_tag(&conn, "col:test")?.is_none());
+    assert!(!th.ls(&[])?.contains(&"col:test".to_string()));
+    Ok(())
+}
+
+// tests that tags whose names only differ in case are the same tag, when the collection says so
+#[test]
+fn test_case_insensitive_tags() -> TestResult {
+    let test_config = r#"
+[symbols]
+inode_char = "-"
+device_char = "﹫"
+sync_char = "\u007F"
+filedir_str = "⋂"
+filedir_cli_str = "_"
+tag_group_str = "+"
+
+[mount]
+
+[collection]
+case_insensitive_tags = true
+"#;
+    let th = TestHelper::new(Some(test_config));
+    let first = th.ln(&["Music"])?;
+    let second = th.ln(&["music", "jazz"])?;
+
+    // tagging with another spelling lands on the tag that was there first
+    let conn = th.fresh_conn();
+    assert_eq!(
+        supertag::sql::get_tag(&conn, "MUSIC")?.map(|t| t.name),
+        Some("Music".to_string())
+    );
+    let tags = th.ls(&[])?;
+    assert!(tags.contains(&"Music".to_string()));
+    assert!(!tags.contains(&"music".to_string()));
+
+    // and paths find it with any spelling
+    let mut files = th.ls_filedir(&["mUsIc"])?;
+    files.sort();
+    let mut expected = vec![first.link_filename(false), second.link_filename(false)];
+    expected.sort();
+    assert_eq!(files, expected);
+    assert_eq!(
+        th.ls_filedir(&["jazz", "MUSIC"])?,
+        vec![second.link_filename(false)]
+    );
+
+    // including inside unions and expressions
+    let mut files = th.ls_filedir(&["MUSIC|jazz"])?;
+    files.sort();
+    assert_eq!(files, expected);
+    assert_eq!(
+        th.ls_filedir(&["?(MUSIC&!JAZZ)"])?,
+        vec![first.link_filename(false)]
+    );
+
+    // changes find it with any spelling too
+    th.rm(&second.link_filedir_path(&["JAZZ"], false))?;
+    th.assert_path_not_exists(second.link_filedir_path(&["jazz"], false));
+    th.assert_path_exists(second.link_filedir_path(&["Music"], false));
+
+    th.mv(
+        first.link_filedir_path(&["mUSIC"], false),
+        first.link_filedir_path(&["JAZZ"], false),
+    )?;
+    th.assert_path_exists(first.link_filedir_path(&["jazz"], false));
+    th.assert_path_not_exists(first.link_filedir_path(&["Music"], false));
+    assert!(!th.ls(&[])?.contains(&"JAZZ".to_string()));
+
+    th.rmdir(&["JAZZ"])?;
+    th.assert_parts_not_exists(&["jazz"]);
+
+    // and so do tag groups
+    th.mkdir("Genres+")?;
+    th.mv(
+        &th.mountpoint_path(&["Music"]),
+        &th.mountpoint_path(&["Genres+"]),
+    )?;
+    th.assert_parts_exists(&["genres+", "MUSIC"]);
+    assert_eq!(
+        th.ls_filedir(&["GENRES+", "music"])?,
+        vec![second.link_filename(false)]
+    );
+    Ok(())
+}
+
+// tests that media files are passed through as regular, readable files when thumbnail passthrough is on, and that
+// everything else stays a symlink
+#[test]
+fn test_thumbnail_passthrough() -> TestResult {
+    let test_config = r#"
+[symbols]
+inode_char = "-"
+device_char = "﹫"
+sync_char = "\u007F"
+filedir_str = "⋂"
+filedir_cli_str = "_"
+tag_group_str = "+"
+
+[mount]
+
+[thumbnails]
+passthrough = true
+extensions = ["png"]
+"#;
+    let th = TestHelper::new(Some(test_config));
+
+    let mut builder = tempfile::Builder::new();
+    builder
+        .prefix("supertag-testfile")
+        .suffix(".png")
+        .rand_bytes(8);
+    let image = Rc::new(builder.tempfile()?);
+    std::fs::write(image.path(), b"not really a png")?;
+    let linked_image = th.ln_with_tempfile(image, &["t1"])?;
+    let linked_other = th.ln(&["t1"])?;
+
+    let image_path = linked_image.link_filedir_path(&["t1"], false);
+    let md = std::fs::symlink_metadata(&image_path)?;
+    assert!(md.file_type().is_file());
+    assert_eq!(md.len(), 16);
+    assert_eq!(std::fs::read(&image_path)?, b"not really a png");
+
+    // it's read-only
+    assert!(std::fs::OpenOptions::new()
+        .write(true)
+        .open(&image_path)
+        .is_err());
+
+    let other_path = linked_other.link_filedir_path(&["t1"], false);
+    assert!(std::fs::symlink_metadata(&other_path)?
+        .file_type()
+        .is_symlink());
+    Ok(())
+}
+
+// tests that removing a tag can be undone, bringing back its links
+#[test]
+fn test_undo_rmdir() -> TestResult {
+    let th = TestHelper::new(None);
+    let linked = th.ln(&["t1", "t2"])?;
+
+    th.rmdir(&["t1"])?;
+    th.assert_parts_not_exists(&["t1"]);
+
+    {
+        let mut conn = th.fresh_conn();
+        let ops = supertag::sql::undo::recent_ops(&conn, 10)?;
+        assert_eq!(ops[0].op, "rmdir");
+
+        let tx = supertag::sql::begin_write(&mut conn)?;
+        assert!(supertag::sql::undo::undo_last(&tx)?.is_some());
+        tx.commit()?;
+    }
+
+    th.assert_path_exists(linked.link_filedir_path(&["t1"], false));
+    th.assert_path_exists(linked.link_filedir_path(&["t1", "t2"], false));
+    Ok(())
+}
+
+const WRITE_THROUGH_CONFIG: &str = r#"
+[symbols]
+inode_char = "-"
+device_char = "﹫"
+sync_char = "\u007F"
+filedir_str = "⋂"
+filedir_cli_str = "_"
+tag_group_str = "+"
+
+[mount]
+write_through = true
+"#;
+
+// tests that with write through on, tagged files are regular files whose writes land in their targets
+#[test]
+fn test_write_through() -> TestResult {
+    let th = TestHelper::new(Some(WRITE_THROUGH_CONFIG));
+    let linked = th.ln(&["t1"])?;
+    std::fs::write(linked.target_path(), b"original")?;
+
+    let path = linked.link_filedir_path(&["t1"], false);
+    let md = std::fs::symlink_metadata(&path)?;
+    assert!(md.file_type().is_file());
+    assert_eq!(md.len(), 8);
+    assert_eq!(std::fs::read(&path)?, b"original");
+
+    // truncating and rewriting through the mount changes the target itself
+    std::fs::write(&path, b"edited")?;
+    assert_eq!(std::fs::read(linked.target_path())?, b"edited");
+    assert_eq!(std::fs::symlink_metadata(&path)?.len(), 6);
+
+    {
+        use std::io::{Seek, SeekFrom, Write};
+        let mut file = std::fs::OpenOptions::new().write(true).open(&path)?;
+        file.seek(SeekFrom::Start(4))?;
+        file.write_all(b"ing")?;
+    }
+    assert_eq!(std::fs::read(linked.target_path())?, b"editing");
+    Ok(())
+}
+
+// tests that reads and writes through the mount that span many of fuse's buffers, at offsets that don't line up with
+// them, go to and come from the right places in the target.  every read and write goes through read_buf and write_buf
+#[test]
+fn test_write_through_buffers() -> TestResult {
+    use std::os::unix::fs::FileExt;
+    let th = TestHelper::new(Some(WRITE_THROUGH_CONFIG));
+    let linked = th.ln(&["t1"])?;
+    let path = linked.link_filedir_path(&["t1"], false);
+
+    let data: Vec<u8> = (0..3 * 1024 * 1024 + 123)
+        .map(|i| (i % 251) as u8)
+        .collect();
+    let offset = 4097;
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&path)?;
+    file.write_all_at(&data, offset)?;
+    drop(file);
+
+    let target = std::fs::read(linked.target_path())?;
+    assert_eq!(target.len(), offset as usize + data.len());
+    assert!(target[..offset as usize].iter().all(|b| *b == 0));
+    assert_eq!(&target[offset as usize..], data.as_slice());
+
+    let file = std::fs::File::open(&path)?;
+    let mut read = vec![0u8; 200_000];
+    file.read_exact_at(&mut read, offset + 1_000_001)?;
+    assert_eq!(read.as_slice(), &data[1_000_001..1_200_001]);
+    assert_eq!(std::fs::read(&path)?, target);
+    Ok(())
+}
+
+// tests that preallocating through the mount grows the target, and that the bytes already written stay put
+#[cfg(target_os = "linux")]
+#[test]
+fn test_write_through_fallocate() -> TestResult {
+    use std::os::unix::io::AsRawFd;
+    let th = TestHelper::new(Some(WRITE_THROUGH_CONFIG));
+    let linked = th.ln(&["t1"])?;
+    std::fs::write(linked.target_path(), b"original")?;
+    let path = linked.link_filedir_path(&["t1"], false);
+
+    let file = std::fs::OpenOptions::new().write(true).open(&path)?;
+    assert_eq!(unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, 4096) }, 0);
+    drop(file);
+    assert_eq!(std::fs::metadata(linked.target_path())?.len(), 4096);
+    assert_eq!(&std::fs::read(&path)?[..8], b"original");
+    Ok(())
+}
+
+// tests that with rename tracking on, a symlink follows its target when the target is renamed on disk
+#[test]
+fn test_follow_renames() -> TestResult {
+    let test_config = r#"
+[symbols]
+inode_char = "-"
+device_char = "﹫"
+sync_char = "\u007F"
+filedir_str = "⋂"
+filedir_cli_str = "_"
+tag_group_str = "+"
+
+[mount]
+
+[tracking]
+follow_renames = true
+"#;
+    let th = TestHelper::new(Some(test_config));
+    let linked = th.ln(&["t1"])?;
+    let link_path = linked.link_filedir_path(&["t1"], false);
+
+    let target = linked.target_path();
+    let renamed = target.with_file_name(format!(
+        "{}-renamed",
+        target.file_name().unwrap().to_string_lossy()
+    ));
+    std::fs::rename(&target, &renamed)?;
+
+    assert_eq!(std::fs::read_link(&link_path)?, renamed);
+
+    let conn = th.fresh_conn();
+    let files = supertag::sql::get_all_files(&conn)?;
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0].path, renamed.to_string_lossy());
+
+    // put it back, so the tempfile cleans up after itself
+    std::fs::rename(&renamed, &target)?;
+    Ok(())
+}
+
+// tests that a tagged file that was moved on disk is found and pointed at its new location
+#[test]
+fn test_doctor_relocates_moved_file() -> TestResult {
+    use supertag::common::doctor::{self, Outcome, Problem};
+
+    let th = TestHelper::new(None);
+    let linked = th.ln(&["t1"])?;
+    let _untouched = th.ln(&["t1"])?;
+
+    let target = linked.target_path();
+    let new_home = tempfile::tempdir()?;
+    let new_root = new_home.path().canonicalize()?;
+    let moved = new_root.join(target.file_name().unwrap());
+    std::fs::rename(&target, &moved)?;
+
+    let mut conn = th.fresh_conn();
+    let findings = doctor::scan(&conn)?;
+    assert_eq!(findings.len(), 1);
+    assert_eq!(findings[0].problem, Problem::Missing);
+    assert_eq!(findings[0].file.path, target.to_string_lossy());
+
+    let tx = supertag::sql::begin_write(&mut conn)?;
+    let outcomes =
+        doctor::repair_by_search(&tx, &findings, &[new_root], supertag::sql::get_now_secs())?;
+    tx.commit()?;
+    assert_eq!(outcomes, vec![Outcome::Relocated(moved.clone())]);
+    assert!(doctor::scan(&conn)?.is_empty());
+
+    // put it back, so the tempfile can clean up after itself
+    std::fs::rename(&moved, &target)?;
+    Ok(())
+}
+
 #[test]
 fn test_filedir() -> TestResult {
     let th = TestHelper::new(None);
@@ -473,3 +1277,530 @@ fn test_cli_filedir() -> TestResult {
     assert_eq!(files1, files2);
     Ok(())
 }
+
+// tests that tag directories expose computed stats via xattrs
+#[test]
+fn test_tag_dir_xattrs() -> TestResult {
+    let th = TestHelper::new(None);
+    th.ln(&["t1", "t2"])?;
+    th.ln(&["t1"])?;
+
+    let t1 = th.mountpoint_path(&["t1"]);
+    let num_files = xattr::get(&t1, supertag::common::constants::XATTR_NUM_FILES)?;
+    assert_eq!(num_files, Some(b"2".to_vec()));
+
+    let t2_t1 = th.mountpoint_path(&["t2", "t1"]);
+    let num_files = xattr::get(&t2_t1, supertag::common::constants::XATTR_NUM_FILES)?;
+    assert_eq!(num_files, Some(b"1".to_vec()));
+
+    let tags = xattr::get(&t2_t1, supertag::common::constants::XATTR_TAGS)?;
+    assert_eq!(tags, Some(b"t2\nt1".to_vec()));
+
+    let query = xattr::get(&t2_t1, supertag::common::constants::XATTR_QUERY)?;
+    assert_eq!(query, Some(b"t1 AND t2".to_vec()));
+    Ok(())
+}
+
+// tests that directory listings come back in the order configured by mount.sort
+#[test]
+fn test_readdir_sort() -> TestResult {
+    let test_config = r#"
+[symbols]
+inode_char = "-"
+device_char = "﹫"
+sync_char = "\u007F"
+filedir_str = "⋂"
+filedir_cli_str = "_"
+tag_group_str = "+"
+
+[mount]
+sort = "num_files"
+"#;
+    let th = TestHelper::new(Some(test_config));
+    th.ln(&["a", "b", "c"])?;
+    th.ln(&["a", "c"])?;
+    th.ln(&["a", "c"])?;
+    th.ln(&["a"])?;
+
+    let listing = |parts: &[&str]| -> std::io::Result<Vec<String>> {
+        std::fs::read_dir(th.mountpoint_path(parts))?
+            .map(|entry| entry.map(|e| e.file_name().to_string_lossy().to_string()))
+            .filter(|name| match name {
+                Ok(name) => ["a", "b", "c"].contains(&name.as_str()),
+                Err(_) => true,
+            })
+            .collect()
+    };
+
+    assert_eq!(listing(&[])?, vec!["a", "c", "b"]);
+    assert_eq!(listing(&["a"])?, vec!["c", "b"]);
+    Ok(())
+}
+
+// tests that a tag's color and description show up as xattrs on its directory once they've been set
+#[test]
+fn test_tag_meta_xattrs() -> TestResult {
+    let th = TestHelper::new(None);
+    th.ln(&["t1", "t2"])?;
+
+    let t1 = th.mountpoint_path(&["t1"]);
+    assert_eq!(
+        xattr::get(&t1, supertag::common::constants::XATTR_COLOR)?,
+        None
+    );
+
+    {
+        let mut conn = th.fresh_conn();
+        let tag_id = supertag::sql::get_tag_id(&conn, "t1")?.unwrap();
+        let meta = supertag::sql::types::TagMeta {
+            color: Some(supertag::common::types::color::TagColor::Red),
+            icon: None,
+            description: Some("first".to_string()),
+        };
+        let tx = supertag::sql::begin_write(&mut conn)?;
+        supertag::sql::set_tag_meta(&tx, tag_id, &meta, supertag::sql::get_now_secs())?;
+        tx.commit()?;
+    }
+
+    let color = xattr::get(&t1, supertag::common::constants::XATTR_COLOR)?;
+    assert_eq!(color, Some(b"red".to_vec()));
+    let description = xattr::get(&t1, supertag::common::constants::XATTR_DESCRIPTION)?;
+    assert_eq!(description, Some(b"first".to_vec()));
+    assert_eq!(
+        xattr::get(&t1, supertag::common::constants::XATTR_ICON)?,
+        None
+    );
+
+    let names: Vec<_> = xattr::list(&t1)?.collect();
+    assert!(names.contains(&supertag::common::constants::XATTR_COLOR.into()));
+
+    // the metadata belongs to the tag, not to every intersection it appears in
+    let t2 = th.mountpoint_path(&["t2"]);
+    assert_eq!(
+        xattr::get(&t2, supertag::common::constants::XATTR_COLOR)?,
+        None
+    );
+    Ok(())
+}
+
+// tests that a file's tags can be replaced wholesale, which is what writing the tags xattr of a file entry does
+#[test]
+fn test_retag_file() -> TestResult {
+    let th = TestHelper::new(None);
+    let linked = th.ln(&["t1", "t2"])?;
+
+    {
+        let mut conn = th.fresh_conn();
+        let tf = supertag::sql::files_tagged_with(
+            &conn,
+            &[supertag::common::types::TagType::Regular("t1".to_string())],
+        )?
+        .remove(0);
+
+        let tx = supertag::sql::begin_write(&mut conn)?;
+        let (added, removed) = supertag::common::fsops::retag(
+            &th.settings,
+            &tx,
+            &tf,
+            &["t2", "t3"],
+            th.uid,
+            th.gid,
+            &th.umask,
+        )?;
+        tx.commit()?;
+        assert_eq!(added, vec!["t3".to_string()]);
+        assert_eq!(removed, vec!["t1".to_string()]);
+        assert_eq!(
+            supertag::sql::tags_for_file(&conn, tf.id)?,
+            vec!["t2".to_string(), "t3".to_string()]
+        );
+
+        // a file can't be left without tags
+        let tx = supertag::sql::begin_write(&mut conn)?;
+        assert!(supertag::common::fsops::retag(
+            &th.settings,
+            &tx,
+            &tf,
+            &[],
+            th.uid,
+            th.gid,
+            &th.umask
+        )
+        .is_err());
+    }
+
+    for tag in &["t1", "t2", "t3"] {
+        supertag::common::fsops::flush_path(th.mountpoint_path(&[tag]), &th.settings);
+    }
+    th.assert_count(&["t1"], 0);
+    th.assert_path_exists(linked.link_filedir_path(&["t2", "t3"], false));
+
+    // a tag directory's tags are its path, so they can't be written
+    let t2 = th.mountpoint_path(&["t2"]);
+    assert!(xattr::set(&t2, supertag::common::constants::XATTR_TAGS, b"t4").is_err());
+    Ok(())
+}
+
+// tests tagging a file by writing its tags xattr on an entry that's a symlink.  linux doesn't allow user xattrs on
+// symlinks, so this only applies on macos
+#[cfg(target_os = "macos")]
+#[test]
+fn test_file_tags_xattr() -> TestResult {
+    let th = TestHelper::new(None);
+    let linked = th.ln(&["t1", "t2"])?;
+
+    let link = linked.link_filedir_path(&["t1"], false);
+    let tags = xattr::get(&link, supertag::common::constants::XATTR_TAGS)?;
+    assert_eq!(tags, Some(b"t1\nt2".to_vec()));
+
+    xattr::set(&link, supertag::common::constants::XATTR_TAGS, b"t2, t3")?;
+    th.assert_count(&["t1"], 0);
+    th.assert_path_exists(linked.link_filedir_path(&["t2", "t3"], false));
+    Ok(())
+}
+
+// tests tagging a file by writing its tags xattr on linux.  linux refuses user xattrs on symlinks, so it goes through
+// an entry that's a regular file, which every entry is with write through
+#[cfg(target_os = "linux")]
+#[test]
+fn test_file_tags_xattr_write_through() -> TestResult {
+    let th = TestHelper::new(Some(WRITE_THROUGH_CONFIG));
+    let linked = th.ln(&["t1", "t2"])?;
+
+    let entry = linked.link_filedir_path(&["t1"], false);
+    assert!(std::fs::symlink_metadata(&entry)?.file_type().is_file());
+    let tags = xattr::get(&entry, supertag::common::constants::XATTR_TAGS)?;
+    assert_eq!(tags, Some(b"t1\nt2".to_vec()));
+    assert!(xattr::list(&entry)?.any(|name| name == supertag::common::constants::XATTR_TAGS));
+
+    xattr::set(&entry, supertag::common::constants::XATTR_TAGS, b"t2, t3")?;
+    th.assert_count(&["t1"], 0);
+    let moved = linked.link_filedir_path(&["t2", "t3"], false);
+    th.assert_path_exists(&moved);
+    assert_eq!(
+        xattr::get(&moved, supertag::common::constants::XATTR_TAGS)?,
+        Some(b"t2\nt3".to_vec())
+    );
+    Ok(())
+}
+
+// tests that files can be matched by their key/value metadata with a `meta:key=value` path component
+#[test]
+fn test_file_meta() -> TestResult {
+    let th = TestHelper::new(None);
+    let acme = th.ln(&["t1"])?;
+    let other = th.ln(&["t1"])?;
+
+    {
+        let mut conn = th.fresh_conn();
+        let (device, inode) = supertag::common::get_device_inode(&acme.target_path())?;
+        let file_id = supertag::sql::get_file_id(&conn, device, inode)?.unwrap();
+        let tx = supertag::sql::begin_write(&mut conn)?;
+        let now = supertag::sql::get_now_secs();
+        supertag::sql::set_file_meta(&tx, file_id, "client", "acme", now)?;
+        supertag::sql::set_file_meta(&tx, file_id, "source", "scanner", now)?;
+        tx.commit()?;
+        assert_eq!(
+            supertag::sql::get_file_meta(&conn, file_id)?,
+            vec![
+                ("client".to_string(), "acme".to_string()),
+                ("source".to_string(), "scanner".to_string())
+            ]
+        );
+    }
+
+    th.assert_path_exists(acme.link_filedir_path(&["meta:client=acme"], false));
+    th.assert_path_not_exists(other.link_filedir_path(&["meta:client=acme"], false));
+    th.assert_path_exists(acme.link_filedir_path(&["t1", "meta:client=acme"], false));
+    th.assert_count(&["t1", "meta:client=acme"], 1);
+
+    // nothing has this value, so there's no directory for it
+    th.assert_path_not_exists(th.mountpoint_path(&["meta:client=initech"]));
+
+    // linux doesn't allow user xattrs on symlinks, so the file entries' xattrs are only reachable on macos
+    #[cfg(target_os = "macos")]
+    {
+        let link = other.link_filedir_path(&["t1"], false);
+        let name = format!("{}client", supertag::common::constants::XATTR_META_PREFIX);
+        xattr::set(&link, &name, b"acme")?;
+        assert_eq!(xattr::get(&link, &name)?, Some(b"acme".to_vec()));
+        th.assert_count(&["t1", "meta:client=acme"], 2);
+
+        xattr::remove(&link, &name)?;
+        assert_eq!(xattr::get(&link, &name)?, None);
+        th.assert_count(&["t1", "meta:client=acme"], 1);
+    }
+    Ok(())
+}
+
+// tests that metadata on a file's tagging with one tag shows on its links in that tag's directories, and goes away
+// when the file loses the tag
+#[test]
+fn test_file_tag_meta() -> TestResult {
+    let th = TestHelper::new(None);
+    let linked = th.ln(&["t1", "t2"])?;
+
+    let mut conn = th.fresh_conn();
+    let (device, inode) = supertag::common::get_device_inode(&linked.target_path())?;
+    let file_id = supertag::sql::get_file_id(&conn, device, inode)?.unwrap();
+    let t1 = supertag::sql::get_tag(&conn, "t1")?.unwrap().id;
+    let t2 = supertag::sql::get_tag(&conn, "t2")?.unwrap().id;
+    let tx = supertag::sql::begin_write(&mut conn)?;
+    let now = supertag::sql::get_now_secs();
+    assert!(supertag::sql::set_file_tag_meta(
+        &tx, file_id, t1, "rating", "5", now
+    )?);
+    assert!(supertag::sql::set_file_tag_meta(
+        &tx, file_id, t2, "source", "scanner", now
+    )?);
+    tx.commit()?;
+    assert_eq!(
+        supertag::sql::get_file_tag_meta(&conn, file_id, t1)?,
+        vec![("rating".to_string(), "5".to_string())]
+    );
+
+    // linux doesn't allow user xattrs on symlinks, so the file entries' xattrs are only reachable on macos
+    #[cfg(target_os = "macos")]
+    {
+        let name = format!(
+            "{}rating",
+            supertag::common::constants::XATTR_TAG_META_PREFIX
+        );
+        let in_t1 = linked.link_filedir_path(&["t2", "t1"], false);
+        let in_t2 = linked.link_filedir_path(&["t1", "t2"], false);
+        assert_eq!(xattr::get(&in_t1, &name)?, Some(b"5".to_vec()));
+        assert_eq!(xattr::get(&in_t2, &name)?, None);
+        assert!(xattr::list(&in_t1)?.any(|n| n.to_str() == Some(name.as_str())));
+
+        xattr::set(&in_t2, &name, b"4")?;
+        assert_eq!(xattr::get(&in_t2, &name)?, Some(b"4".to_vec()));
+        assert_eq!(xattr::get(&in_t1, &name)?, Some(b"5".to_vec()));
+    }
+
+    th.rm(&linked.link_filedir_path(&["t1"], false))?;
+    assert!(supertag::sql::get_file_tag_meta(&conn, file_id, t1)?.is_empty());
+    assert!(!supertag::sql::get_file_tag_meta(&conn, file_id, t2)?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_import_dir() -> TestResult {
+    let th = TestHelper::new(None);
+
+    let dir = tempfile::Builder::new()
+        .prefix("supertag-import")
+        .tempdir()?;
+    std::fs::create_dir_all(dir.path().join("photos").join("2020"))?;
+    std::fs::write(dir.path().join("top.txt"), b"")?;
+    std::fs::write(dir.path().join("photos").join("beach.jpg"), b"")?;
+    std::fs::write(dir.path().join("photos").join("2020").join("snow.jpg"), b"")?;
+
+    let mut conn = th.fresh_conn();
+    let total = supertag::import(
+        &th.settings,
+        &mut conn,
+        th.real_mountpoint(),
+        dir.path(),
+        &th.mountpoint_path(&["imported"]),
+        true,
+        th.uid,
+        th.gid,
+        &th.umask,
+        |_| {},
+    )?;
+    assert_eq!(total, 3);
+
+    th.check_only_files(&["imported"], &["top.txt", "beach.jpg", "snow.jpg"], true);
+    th.check_only_files(&["photos"], &["beach.jpg", "snow.jpg"], true);
+    th.check_only_files(&["photos", "2020"], &["snow.jpg"], true);
+    th.assert_count(&["imported"], 3);
+
+    Ok(())
+}
+
+#[test]
+fn test_watch_dir() -> TestResult {
+    let th = TestHelper::new(None);
+
+    let dir = tempfile::Builder::new()
+        .prefix("supertag-watch")
+        .tempdir()?;
+    std::fs::write(dir.path().join("before.txt"), b"")?;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let (linked_tx, linked_rx) = mpsc::channel();
+    let watcher = {
+        let settings = th.settings.clone();
+        let mut conn = th.fresh_conn();
+        let mountpoint = th.real_mountpoint();
+        let watched = dir.path().to_owned();
+        let tag_path = th.mountpoint_path(&["watched"]);
+        let (uid, gid) = (th.uid, th.gid);
+        let stop = stop.clone();
+        std::thread::spawn(move || {
+            let socket = settings.notify_socket_file(&settings.get_collection());
+            let notifier = UDSNotifier::new(socket, false).unwrap();
+            supertag::watch(
+                &settings,
+                &mut conn,
+                &mountpoint,
+                &watched,
+                &tag_path,
+                uid,
+                gid,
+                &UMask::default(),
+                &notifier,
+                &stop,
+                |file| linked_tx.send(file.to_owned()).unwrap(),
+            )
+            .unwrap();
+        })
+    };
+
+    // give the watcher a moment to start, so that the new file is seen as new
+    th.sleep(1.0);
+    std::fs::write(dir.path().join("after.txt"), b"")?;
+    let linked = linked_rx.recv_timeout(Duration::from_secs(5))?;
+    assert_eq!(linked, dir.path().join("after.txt"));
+
+    stop.store(true, Ordering::Relaxed);
+    watcher.join().unwrap();
+
+    th.check_only_files(&["watched"], &["after.txt"], true);
+
+    Ok(())
+}
+
+#[test]
+/// Tests that a bulk ln reports each file, and that stopping it before it starts links nothing
+fn test_ln_with_progress() -> TestResult {
+    let th = TestHelper::new(None);
+    let files = (0..3)
+        .map(|_| NamedTempFile::new())
+        .collect::<std::io::Result<Vec<NamedTempFile>>>()?;
+    let paths = files.iter().map(|f| f.path()).collect::<Vec<_>>();
+
+    let mut conn = th.fresh_conn();
+    let notifier = th.notifier.lock();
+
+    let stop = AtomicBool::new(true);
+    let summary = supertag::cli::ln::ln_with_progress(
+        &th.settings,
+        &mut conn,
+        th.real_mountpoint(),
+        paths.clone(),
+        &th.mountpoint_path(&["t1"]),
+        th.uid,
+        th.gid,
+        &UMask::default(),
+        &*notifier,
+        &stop,
+        |_, _| panic!("Nothing should have been linked"),
+    )?;
+    assert!(summary.interrupted());
+    assert_eq!(summary.linked, 0);
+    th.assert_path_not_exists(th.mountpoint_path(&["t1"]));
+
+    stop.store(false, Ordering::Relaxed);
+    let mut reported = vec![];
+    let summary = supertag::cli::ln::ln_with_progress(
+        &th.settings,
+        &mut conn,
+        th.real_mountpoint(),
+        paths,
+        &th.mountpoint_path(&["t1"]),
+        th.uid,
+        th.gid,
+        &UMask::default(),
+        &*notifier,
+        &stop,
+        |done, _| reported.push(done),
+    )?;
+    assert!(!summary.interrupted());
+    assert_eq!(reported, vec![1, 2, 3]);
+    th.assert_count(&["t1"], 3);
+    Ok(())
+}
+
+#[test]
+/// Tests that materializing a query writes symlinks to just the matching files, outside of the mount
+fn test_materialize() -> TestResult {
+    let th = TestHelper::new(None);
+    let l1 = th.ln(&["t1", "t2"])?;
+    let _l2 = th.ln(&["t1"])?;
+
+    let out = tempfile::tempdir()?;
+    let out_dir = out.path().join("snapshot");
+    let conn = th.fresh_conn();
+    let tags = th.settings.query_to_tags(&["t1", "t2"])?;
+    let summary = supertag::materialize(
+        &th.settings,
+        &conn,
+        &tags,
+        &out_dir,
+        supertag::cli::materialize::MaterializeMode::Symlink,
+    )?;
+    assert_eq!(summary.written, 1);
+    assert!(summary.missing.is_empty());
+
+    let entries = std::fs::read_dir(&out_dir)?.collect::<std::io::Result<Vec<_>>>()?;
+    assert_eq!(entries.len(), 1);
+    assert_eq!(std::fs::read_link(entries[0].path())?, l1.target_path());
+
+    // a second run doesn't clobber the first
+    assert!(supertag::materialize(
+        &th.settings,
+        &conn,
+        &tags,
+        &out_dir,
+        supertag::cli::materialize::MaterializeMode::Symlink,
+    )
+    .is_err());
+    Ok(())
+}
+
+// tests that `tag ls` lists the same tags and file names as the mount does, display transforms and all
+#[test]
+fn test_tag_ls() -> TestResult {
+    let test_config = r#"
+[symbols]
+inode_char = "-"
+device_char = "﹫"
+sync_char = "\u007F"
+filedir_str = "⋂"
+filedir_cli_str = "_"
+tag_group_str = "+"
+
+[display]
+strip_extensions = true
+underscores_to_spaces = true
+"#;
+    let th = TestHelper::new(Some(test_config));
+    let named = |dir: &Path, name: &str| -> std::io::Result<Rc<NamedTempFile>> {
+        Ok(Rc::new(
+            tempfile::Builder::new()
+                .prefix(name)
+                .suffix(".txt")
+                .rand_bytes(0)
+                .tempfile_in(dir)?,
+        ))
+    };
+    let dir1 = tempfile::tempdir()?;
+    let dir2 = tempfile::tempdir()?;
+    // these are both displayed as "same name", so they're listed under their fully qualified names
+    th.ln_with_tempfile(named(dir1.path(), "same_name")?, &["t1", "t2"])?;
+    th.ln_with_tempfile(named(dir2.path(), "same name")?, &["t1"])?;
+    th.ln_with_tempfile(named(dir1.path(), "other_file")?, &["t1", "t3"])?;
+
+    let conn = th.fresh_conn();
+    let (tags, files) = supertag::cli::handlers::ls::list(&th.settings, &conn, "t1")?;
+    let mut tag_names: Vec<String> = tags.into_iter().map(|tag| tag.name).collect();
+    tag_names.sort();
+    assert_eq!(tag_names, th.ls_tags(&["t1"])?);
+
+    let mut file_names: Vec<String> = files.into_iter().map(|(name, _)| name).collect();
+    file_names.sort();
+    assert_eq!(file_names, th.ls_filedir(&["t1"])?);
+    assert!(file_names.contains(&"other file".to_string()));
+    Ok(())
+}
@@ -15,7 +15,7 @@
  * You should have received a copy of the GNU Affero General Public License
  * along with this program.  If not, see <http://www.gnu.org/licenses/>.
  */
-use crate::common::{TestHelper, TestResult};
+use crate::common::{OpMode, TestHelper, TestResult};
 use rusqlite::{params, NO_PARAMS};
 use std::time::{Duration, UNIX_EPOCH};
 
@@ -115,3 +115,66 @@ fn test_file_cache() -> TestResult {
     );
     Ok(())
 }
+
+// tests that a mutation invalidates every cached view of the tags it touched, not just the path it was made through
+#[test]
+fn test_permutation_invalidation() -> TestResult {
+    let mut th = TestHelper::new(None);
+    th.symlink_mode = OpMode::MANUAL;
+    let _ = th.ln(&["t1", "t2"])?;
+
+    // listing caches the entries, including the /t2/t1 permutation
+    let _ = std::fs::read_dir(th.mountpoint_path(&[]))?.count();
+    let _ = std::fs::read_dir(th.mountpoint_path(&["t2"]))?.count();
+    th.assert_size(&["t2", "t1"], 1);
+
+    // linked through /t1/t2, but /t2/t1 sees it right away
+    let _ = th.ln(&["t1", "t2"])?;
+    th.assert_size(&["t2", "t1"], 2);
+    Ok(())
+}
+
+#[test]
+fn test_lazy_listing_sizes() -> TestResult {
+    _test_listing_sizes(TestHelper::new(None))
+}
+
+#[test]
+fn test_eager_listing_sizes() -> TestResult {
+    let test_config = r#"
+[symbols]
+inode_char = "-"
+device_char = "﹫"
+sync_char = "\u007F"
+filedir_str = "⋂"
+filedir_cli_str = "_"
+tag_group_str = "+"
+
+[mount]
+listing_sizes = true
+"#;
+    _test_listing_sizes(TestHelper::new(Some(test_config)))
+}
+
+// tests that the tagdirs of a listing have the same sizes and mtimes when they're stat'd, whether or not the listing
+// counted them
+fn _test_listing_sizes(th: TestHelper) -> TestResult {
+    let _ = th.ln(&["t1", "t2", "t3"])?;
+    th.sleep(1.0);
+    let _ = th.ln(&["t1", "t2"])?;
+
+    let _ = std::fs::read_dir(th.mountpoint_path(&["t1"]))?.count();
+    th.assert_size(&["t1", "t2"], 2);
+    th.assert_size(&["t1", "t3"], 1);
+
+    let mtime = |tags: &[&str]| {
+        th.mountpoint_path(tags)
+            .metadata()
+            .unwrap()
+            .modified()
+            .unwrap()
+    };
+    assert!(mtime(&["t1", "t2"]) > mtime(&["t1", "t3"]));
+    assert_eq!(mtime(&["t1", "t2"]), mtime(&["t2"]));
+    Ok(())
+}
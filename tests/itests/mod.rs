@@ -29,4 +29,5 @@ mod notify;
 mod perms;
 mod pins;
 mod remove;
+mod rules;
 mod taggroup;
@@ -18,7 +18,17 @@
 
 use super::{TestHelper, TestResult};
 use crate::common::OpMode;
+use rusqlite::NO_PARAMS;
+use std::rc::Rc;
+use std::time::Duration;
+use supertag::common::archive::PathRemaps;
 use supertag::common::err::STagError;
+use supertag::common::fsops;
+use supertag::common::notify::{Listener, Notifier};
+use supertag::common::sync::SyncState;
+use supertag::common::types::note::Note;
+use supertag::common::types::{DeviceFile, MergeResolution, TagType};
+use supertag::sql;
 
 #[test]
 fn test_nested_tag_merge_cli() -> TestResult {
@@ -314,6 +324,57 @@ fn _test_rename_collision(th: TestHelper) -> TestResult {
     Ok(())
 }
 
+#[test]
+fn test_rename_uncollide_cli() -> TestResult {
+    let th = TestHelper::new(None);
+    _test_rename_uncollide(th)
+}
+
+#[test]
+fn test_rename_uncollide_manual() -> TestResult {
+    let mut th = TestHelper::new(None);
+    th.symlink_mode = OpMode::MANUAL;
+    th.rename_mode = OpMode::MANUAL;
+    _test_rename_uncollide(th)
+}
+
+#[test]
+#[cfg(target_os = "macos")]
+fn test_rename_uncollide_finder() -> TestResult {
+    let mut th = TestHelper::new(None);
+    th.symlink_mode = OpMode::FINDER;
+    th.rename_mode = OpMode::FINDER;
+    _test_rename_uncollide(th)
+}
+
+/// Tests that renaming a file out of a name collision, by its fully-qualified name, switches both files back to their
+/// simple names
+fn _test_rename_uncollide(th: TestHelper) -> TestResult {
+    let l1 = th.ln(&["t1"])?;
+    let l2 = th.ln(&["t1"])?;
+
+    let collision_name = supertag::common::get_filename(l2.tmp.path())?;
+    let collided_path = l1.new_link_path(&["t1"], collision_name, true);
+    th.mv(
+        &l1.link_filedir_path(&["t1"], false),
+        &l1.new_link_path(&["t1"], collision_name, false),
+    )?;
+    th.assert_path_exists(&collided_path);
+
+    // rename l1 away from the collision, using the only name it's listed under
+    let new_dst_path = l1.new_link_path(&["t1"], "some_new_name", false);
+    th.mv(&collided_path, &new_dst_path)?;
+    th.assert_path_exists(&new_dst_path);
+    assert!(!th.readdir_exists(&collided_path));
+
+    // l2 no longer collides, so it's listed by its simple name again, although its fully-qualified name still resolves
+    th.assert_path_exists(l2.link_filedir_path(&["t1"], false));
+    assert!(th.getattr_exists(l2.link_filedir_path(&["t1"], true)));
+    assert!(!th.readdir_exists(l2.link_filedir_path(&["t1"], true)));
+
+    Ok(())
+}
+
 #[test]
 fn test_rename_tag_cli() -> TestResult {
     let th = TestHelper::new(None);
@@ -350,6 +411,43 @@ fn _test_rename_tag(th: TestHelper) -> TestResult {
     Ok(())
 }
 
+// tests that with redirects on, a renamed tag's old path still leads to its files
+#[test]
+fn test_rename_tag_redirect() -> TestResult {
+    let test_config = r#"
+[symbols]
+inode_char = "-"
+device_char = "﹫"
+sync_char = "\u007F"
+filedir_str = "⋂"
+filedir_cli_str = "_"
+tag_group_str = "+"
+
+[mount]
+
+[rename]
+redirect_tags = true
+"#;
+    let th = TestHelper::new(Some(test_config));
+    let linked = th.ln(&["t1"])?;
+    th.mv(
+        &th.mountpoint_path(&["t1"]),
+        &th.mountpoint_path(&["new_t1"]),
+    )?;
+
+    th.assert_path_exists(linked.link_filedir_path(&["new_t1"], false));
+    th.assert_path_exists(linked.link_filedir_path(&["t1"], false));
+    assert_eq!(
+        std::fs::read_link(linked.link_filedir_path(&["t1"], false))?,
+        linked.target_path()
+    );
+
+    // once a tag takes the old name back, it's no longer redirected
+    th.mkdir("t1")?;
+    th.assert_path_not_exists(linked.link_filedir_path(&["t1"], false));
+    Ok(())
+}
+
 // tests that a bogus tag name isn't allowed
 #[test]
 fn test_rename_tag_invalid_name() -> TestResult {
@@ -373,3 +471,428 @@ fn test_rename_tag_invalid_name() -> TestResult {
 
     Ok(())
 }
+
+#[test]
+fn test_move_file_between_filedirs_cli() -> TestResult {
+    let th = TestHelper::new(None);
+    _test_move_file_between_filedirs(th)
+}
+
+#[test]
+fn test_move_file_between_filedirs_manual() -> TestResult {
+    let mut th = TestHelper::new(None);
+    th.rename_mode = OpMode::MANUAL;
+    _test_move_file_between_filedirs(th)
+}
+
+/// Tests the default "move" semantics of moving a file between filedirs: the file gains the destination's tags and
+/// loses the source's tags that the destination doesn't have
+fn _test_move_file_between_filedirs(th: TestHelper) -> TestResult {
+    let l1 = th.ln(&["a"])?;
+    let _l2 = th.ln(&["b"])?;
+    let _l3 = th.ln(&["c"])?;
+
+    // /a/⋂/x -> /a/b/⋂/x adds b, and keeps a
+    let dst = th.filedir_path(&["a", "b"]).join(l1.link_filename(false));
+    th.mv(&l1.link_filedir_path(&["a"], false), &dst)?;
+    th.assert_path_exists(l1.link_filedir_path(&["a"], false));
+    th.assert_path_exists(l1.link_filedir_path(&["b"], false));
+    th.assert_path_exists(l1.link_filedir_path(&["a", "b"], false));
+
+    // /a/⋂/x -> /c/⋂/x replaces a with c.  b isn't in the source path, so it stays
+    let dst = th.filedir_path(&["c"]).join(l1.link_filename(false));
+    th.mv(&l1.link_filedir_path(&["a"], false), &dst)?;
+    th.assert_path_not_exists(l1.link_filedir_path(&["a"], false));
+    th.assert_path_exists(l1.link_filedir_path(&["c"], false));
+    th.assert_path_exists(l1.link_filedir_path(&["b", "c"], false));
+
+    Ok(())
+}
+
+#[test]
+fn test_move_file_between_filedirs_additive() -> TestResult {
+    let test_config = r#"
+[symbols]
+inode_char = "-"
+device_char = "﹫"
+sync_char = "\u007F"
+filedir_str = "⋂"
+filedir_cli_str = "_"
+tag_group_str = "+"
+
+[mount]
+
+[rename]
+file_mode = "additive"
+"#;
+    let th = TestHelper::new(Some(test_config));
+    let l1 = th.ln(&["a"])?;
+    let _l2 = th.ln(&["c"])?;
+
+    let dst = th.filedir_path(&["c"]).join(l1.link_filename(false));
+    th.mv(&l1.link_filedir_path(&["a"], false), &dst)?;
+
+    // nothing is ever removed in additive mode
+    th.assert_path_exists(l1.link_filedir_path(&["a"], false));
+    th.assert_path_exists(l1.link_filedir_path(&["c"], false));
+    th.assert_path_exists(l1.link_filedir_path(&["a", "c"], false));
+
+    Ok(())
+}
+
+fn _test_merge_collision(resolution: MergeResolution) -> TestResult {
+    let th = TestHelper::new(None);
+    let td1 = tempfile::TempDir::new()?;
+    let td2 = tempfile::TempDir::new()?;
+    let to_link1 = Rc::new(
+        tempfile::Builder::new()
+            .prefix("collision")
+            .rand_bytes(0)
+            .tempfile_in(td1.path())?,
+    );
+    let to_link2 = Rc::new(
+        tempfile::Builder::new()
+            .prefix("collision")
+            .rand_bytes(0)
+            .tempfile_in(td2.path())?,
+    );
+    let l1 = th.ln_with_tempfile(to_link1, &["t1"])?;
+    let l2 = th.ln_with_tempfile(to_link2, &["t2"])?;
+
+    let src = th.mountpoint_path(&["t1"]);
+    let dst = th.mountpoint_path(&["t2"]);
+    let mut conn = th.fresh_conn();
+    let collisions = supertag::cli::rename::merge_collisions(
+        &th.settings,
+        &conn,
+        th.real_mountpoint(),
+        &src,
+        &dst,
+    )?;
+    assert_eq!(collisions.len(), 1);
+    assert_eq!(
+        collisions[0].source.path,
+        l1.target_path().to_string_lossy()
+    );
+    assert_eq!(collisions[0].dest.path, l2.target_path().to_string_lossy());
+
+    let mut asked = 0;
+    supertag::rename(
+        &th.settings,
+        &mut conn,
+        th.real_mountpoint(),
+        &src,
+        &dst,
+        th.uid,
+        th.gid,
+        &th.umask,
+        &*(th.notifier.lock()),
+        |_| {
+            asked += 1;
+            Ok(resolution)
+        },
+    )?;
+    assert_eq!(asked, 1);
+
+    match resolution {
+        MergeResolution::KeepBoth => {
+            th.assert_path_exists(l1.link_filedir_path(&["t2"], true));
+            th.assert_path_exists(l2.link_filedir_path(&["t2"], true));
+        }
+        MergeResolution::Source => {
+            th.assert_path_exists(l1.link_filedir_path(&["t2"], false));
+            th.assert_path_not_exists(l2.link_filedir_path(&["t2"], true));
+        }
+        MergeResolution::Dest => {
+            th.assert_path_exists(l1.link_filedir_path(&["t1"], false));
+            th.assert_path_exists(l2.link_filedir_path(&["t2"], false));
+            th.assert_path_not_exists(l1.link_filedir_path(&["t2"], true));
+        }
+        MergeResolution::Newest => unreachable!(),
+    }
+    Ok(())
+}
+
+#[test]
+fn test_merge_collision_keep_both() -> TestResult {
+    _test_merge_collision(MergeResolution::KeepBoth)
+}
+
+#[test]
+fn test_merge_collision_source() -> TestResult {
+    _test_merge_collision(MergeResolution::Source)
+}
+
+#[test]
+fn test_merge_collision_dest() -> TestResult {
+    _test_merge_collision(MergeResolution::Dest)
+}
+
+#[test]
+/// Tests that `tag merge` retags every file of the source tag, removes it, and says how many files it moved
+fn test_merge_command() -> TestResult {
+    let th = TestHelper::new(None);
+    let l1 = th.ln(&["t1"])?;
+    let l2 = th.ln(&["t1", "t3"])?;
+    let l3 = th.ln(&["t2"])?;
+
+    let mut listener = th
+        .notifier
+        .lock()
+        .listener()
+        .expect("Couldn't get listener");
+    let idx = listener.marker();
+
+    let mut conn = th.fresh_conn();
+    let moved = supertag::merge(
+        &th.settings,
+        &mut conn,
+        th.real_mountpoint(),
+        "t1",
+        "t2",
+        th.uid,
+        th.gid,
+        &th.umask,
+        &*(th.notifier.lock()),
+        |_| Ok(MergeResolution::KeepBoth),
+    )?;
+    assert_eq!(moved, 2);
+
+    th.assert_path_exists(l1.link_filedir_path(&["t2"], false));
+    th.assert_path_exists(l2.link_filedir_path(&["t2", "t3"], false));
+    th.assert_path_exists(l3.link_filedir_path(&["t2"], false));
+    th.assert_parts_not_exists(&["t1"]);
+
+    th.assert_note(
+        &mut listener,
+        idx,
+        &[&Note::Merged("t1".to_string(), "t2".to_string(), 2)],
+        Duration::from_secs(3),
+    );
+    Ok(())
+}
+
+#[test]
+/// Tests that `tag swap` trades two tags' names, and that each tag's files follow it to the other name
+fn test_swap_command() -> TestResult {
+    let th = TestHelper::new(None);
+    let l1 = th.ln(&["t1", "t3"])?;
+    let l2 = th.ln(&["t2"])?;
+
+    let mut conn = th.fresh_conn();
+    supertag::swap(&th.settings, &mut conn, th.real_mountpoint(), "t1", "t2")?;
+
+    th.assert_path_exists(l1.link_filedir_path(&["t2"], false));
+    th.assert_path_exists(l1.link_filedir_path(&["t2", "t3"], false));
+    th.assert_path_exists(l2.link_filedir_path(&["t1"], false));
+    th.assert_path_not_exists(l1.link_filedir_path(&["t1"], false));
+    th.assert_path_not_exists(l2.link_filedir_path(&["t2"], false));
+
+    // swapping a tag with a tag group makes no sense
+    th.mkdir("g1+")?;
+    let res = supertag::swap(&th.settings, &mut conn, th.real_mountpoint(), "t1", "g1+");
+    assert!(matches!(res, Err(STagError::BadTag(_))));
+    Ok(())
+}
+
+#[test]
+/// Tests that `tag retag` swaps some of a file's tags for others in one step, leaving its other tags alone, and
+/// sends a single note about it
+fn test_retag_command() -> TestResult {
+    let th = TestHelper::new(None);
+    let l1 = th.ln(&["t1", "t2", "t3"])?;
+
+    let mut listener = th
+        .notifier
+        .lock()
+        .listener()
+        .expect("Couldn't get listener");
+    let idx = listener.marker();
+
+    let mut conn = th.fresh_conn();
+    let file = sql::files_tagged_with(&conn, &[TagType::Regular("t1".to_string())])?.remove(0);
+    let (added, removed) = supertag::retag(
+        &th.settings,
+        &mut conn,
+        th.real_mountpoint(),
+        &file,
+        &["t1", "t2"],
+        &["t4", "t5"],
+        th.uid,
+        th.gid,
+        &th.umask,
+        &*(th.notifier.lock()),
+    )?;
+    assert_eq!(added, vec!["t4".to_string(), "t5".to_string()]);
+    assert_eq!(removed, vec!["t1".to_string(), "t2".to_string()]);
+
+    th.assert_path_exists(l1.link_filedir_path(&["t3", "t4", "t5"], false));
+    th.assert_path_not_exists(l1.link_filedir_path(&["t1"], false));
+    th.assert_path_not_exists(l1.link_filedir_path(&["t2"], false));
+
+    th.assert_note(
+        &mut listener,
+        idx,
+        &[&Note::Retagged(
+            l1.target_path(),
+            vec!["t4".to_string(), "t5".to_string()],
+            vec!["t1".to_string(), "t2".to_string()],
+        )],
+        Duration::from_secs(3),
+    );
+
+    // a file can't be taken out of a tag it isn't in, and nothing changes when that's asked for
+    let res = supertag::retag(
+        &th.settings,
+        &mut conn,
+        th.real_mountpoint(),
+        &file,
+        &["t1"],
+        &["t6"],
+        th.uid,
+        th.gid,
+        &th.umask,
+        &*(th.notifier.lock()),
+    );
+    assert!(matches!(res, Err(STagError::BadTag(_))));
+    th.assert_path_not_exists(l1.link_filedir_path(&["t6"], false));
+    Ok(())
+}
+
+#[test]
+/// Tests moving a file into a tag directory of another collection, which changes both collections' databases together
+fn test_move_between_collections() -> TestResult {
+    let th = TestHelper::new(None);
+    let l1 = th.ln(&["t1"])?;
+    let other = "other";
+    let _ = th.settings.for_collection(other);
+
+    supertag::cli::rename::move_between_collections(
+        &th.settings,
+        &th.collection,
+        other,
+        l1.link_filedir_path(&["t1"], false),
+        th.settings.mountpoint(other).join("t2"),
+        th.uid,
+        th.gid,
+        &th.umask,
+        &*(th.notifier.lock()),
+    )?;
+    th.assert_path_not_exists(l1.link_filedir_path(&["t1"], false));
+
+    let other_conn = sql::db_for_collection(&th.settings, other)?;
+    let moved = sql::files_tagged_with(&other_conn, &[TagType::Regular("t2".to_string())])?;
+    assert_eq!(moved.len(), 1);
+    assert_eq!(moved[0].path, l1.target_path().to_string_lossy());
+
+    // once both have committed, nothing is left to recover
+    for conn in &[th.fresh_conn(), other_conn] {
+        let intents: i64 =
+            conn.query_row("SELECT COUNT(*) FROM xtx_intents", NO_PARAMS, |row| {
+                row.get(0)
+            })?;
+        assert_eq!(intents, 0);
+    }
+    assert_eq!(std::fs::read_dir(th.settings.xtx_dir())?.count(), 0);
+    Ok(())
+}
+
+#[test]
+/// Tests syncing two collections, standing in for the same collection on two machines.  The file is added to the
+/// other side, its newer tags there win back here, and removing it there removes it here.
+fn test_sync_collections() -> TestResult {
+    let th = TestHelper::new(None);
+    let l1 = th.ln(&["t1", "t2"])?;
+    let other = "other";
+    let other_settings = th.settings.for_collection(other);
+    let remaps = PathRemaps::default();
+
+    let mut conn = th.fresh_conn();
+    let mut other_conn = sql::db_for_collection(&th.settings, other)?;
+    sql::migrations::migrate(&mut other_conn, &supertag::common::version_str())?;
+    let tagged_with = |conn: &rusqlite::Connection, tag: &str| -> rusqlite::Result<Vec<String>> {
+        Ok(
+            sql::files_tagged_with(conn, &[TagType::Regular(tag.to_string())])?
+                .into_iter()
+                .map(|tf| tf.path)
+                .collect(),
+        )
+    };
+    let path = l1.target_path().to_string_lossy().to_string();
+
+    let ours = SyncState::load(&other_conn)?;
+    let theirs = SyncState::load(&conn)?;
+    let tx = sql::begin_write(&mut other_conn)?;
+    let summary = fsops::sync_from(
+        &other_settings,
+        &tx,
+        &ours,
+        &theirs,
+        &remaps,
+        th.uid,
+        th.gid,
+        &th.umask,
+    )?;
+    tx.commit()?;
+    assert_eq!(summary.added, 1);
+    assert_eq!(tagged_with(&other_conn, "t2")?, vec![path.clone()]);
+
+    let tf = sql::files_tagged_with(&other_conn, &[TagType::Regular("t1".to_string())])?.remove(0);
+    let tx = sql::begin_write(&mut other_conn)?;
+    fsops::retag(
+        &other_settings,
+        &tx,
+        &tf,
+        &["t3"],
+        th.uid,
+        th.gid,
+        &th.umask,
+    )?;
+    tx.commit()?;
+
+    let ours = SyncState::load(&conn)?;
+    let theirs = SyncState::load(&other_conn)?;
+    let tx = sql::begin_write(&mut conn)?;
+    let summary = fsops::sync_from(
+        &th.settings,
+        &tx,
+        &ours,
+        &theirs,
+        &remaps,
+        th.uid,
+        th.gid,
+        &th.umask,
+    )?;
+    tx.commit()?;
+    assert_eq!(summary.retagged, 1);
+    assert_eq!(tagged_with(&conn, "t3")?, vec![path.clone()]);
+    assert!(tagged_with(&conn, "t1")?.is_empty());
+
+    // so that the removal is unmistakably newer than the retag
+    std::thread::sleep(Duration::from_millis(10));
+    let tx = sql::begin_write(&mut other_conn)?;
+    sql::purge_devicefile(
+        &tx,
+        &DeviceFile::new(&tf.primary_tag, tf.device, tf.inode),
+        sql::get_now_secs(),
+    )?;
+    tx.commit()?;
+
+    let ours = SyncState::load(&conn)?;
+    let theirs = SyncState::load(&other_conn)?;
+    let tx = sql::begin_write(&mut conn)?;
+    let summary = fsops::sync_from(
+        &th.settings,
+        &tx,
+        &ours,
+        &theirs,
+        &remaps,
+        th.uid,
+        th.gid,
+        &th.umask,
+    )?;
+    tx.commit()?;
+    assert_eq!(summary.removed, 1);
+    assert!(tagged_with(&conn, "t3")?.is_empty());
+    Ok(())
+}
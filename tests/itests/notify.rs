@@ -18,9 +18,11 @@
 
 use super::{TestHelper, TestResult};
 use crate::common::OpMode;
+use serde_json::json;
+use std::os::unix::fs::PermissionsExt;
 use std::time::Duration;
 use supertag::common::err::STagError;
-use supertag::common::notify::{Listener, Notifier};
+use supertag::common::notify::{uds, Listener, Notifier};
 use supertag::common::types::note::Note;
 
 #[test]
@@ -147,3 +149,209 @@ fn test_non_rm_delete_dir() -> TestResult {
 
     Ok(())
 }
+
+#[test]
+/// Tests that a tag name that's too long gets ENAMETOOLONG, instead of a generic EIO, and a notification
+fn test_tag_name_too_long() -> TestResult {
+    let th = TestHelper::new(None);
+    let name = "t".repeat(supertag::common::constants::NAME_MAX + 1);
+    let target = th.mountpoint_path(&[&name]);
+
+    let mut listener = th
+        .notifier
+        .lock()
+        .listener()
+        .expect("Couldn't get listener");
+    let idx = listener.marker();
+
+    match std::fs::create_dir(&target) {
+        Err(e) if e.raw_os_error() == Some(libc::ENAMETOOLONG) => {}
+        Err(e) => panic!("Wrong error {:?}", e),
+        Ok(_) => panic!("Should have had an error"),
+    }
+
+    th.assert_note(
+        &mut listener,
+        idx,
+        &[&Note::OpFailed(
+            target,
+            STagError::NameTooLong(name.clone()).to_string(),
+        )],
+        Duration::from_secs(3),
+    );
+    th.assert_parts_not_exists(&[&name]);
+
+    Ok(())
+}
+
+#[test]
+/// Tests that a path negating more tags than the config allows is refused, with a notification
+fn test_too_many_negations() -> TestResult {
+    let th = TestHelper::new(None);
+    th.ln(&["t1"])?;
+    let negated: Vec<String> = (0..9).map(|i| format!("-n{}", i)).collect();
+    for name in &negated {
+        th.mkdir(&name[1..])?;
+    }
+
+    let mut parts = vec!["t1"];
+    parts.extend(negated.iter().map(String::as_str));
+    th.assert_parts_exists(&parts[..parts.len() - 1]);
+    let target = th.mountpoint_path(&parts);
+
+    let mut listener = th
+        .notifier
+        .lock()
+        .listener()
+        .expect("Couldn't get listener");
+    let idx = listener.marker();
+
+    match std::fs::metadata(&target) {
+        Err(e) if e.raw_os_error() == Some(libc::ENAMETOOLONG) => {}
+        Err(e) => panic!("Wrong error {:?}", e),
+        Ok(_) => panic!("Should have had an error"),
+    }
+
+    th.assert_note(
+        &mut listener,
+        idx,
+        &[&Note::OpFailed(
+            target,
+            STagError::TooManyNegations(9, 8).to_string(),
+        )],
+        Duration::from_secs(3),
+    );
+
+    Ok(())
+}
+
+#[test]
+/// Tests that tagging the collection's own database is refused, with a notification
+fn test_tag_collection_db() -> TestResult {
+    let th = TestHelper::new(None);
+    th.mkdir("t1")?;
+
+    let db_file = th.settings.db_file(&th.collection).canonicalize()?;
+    let mut listener = th
+        .notifier
+        .lock()
+        .listener()
+        .expect("Couldn't get listener");
+    let idx = listener.marker();
+
+    match std::os::unix::fs::symlink(&db_file, th.mountpoint_path(&["t1", "db"])) {
+        Err(e) if e.raw_os_error() == Some(libc::EPERM) => {}
+        Err(e) => panic!("Wrong error {:?}", e),
+        Ok(_) => panic!("Should have had an error"),
+    }
+
+    th.assert_note(
+        &mut listener,
+        idx,
+        &[&Note::ProtectedSource(db_file)],
+        Duration::from_secs(3),
+    );
+
+    Ok(())
+}
+
+#[test]
+/// Tests that a peer can ask the notifier for the daemon's health
+fn test_health() -> TestResult {
+    let th = TestHelper::new(None);
+    let _f1 = th.ln(&["t1", "t2"])?;
+    th.mkdir("t3")?;
+
+    let socket_file = th.settings.notify_socket_file(&th.collection);
+    let health = uds::request_health(&socket_file)?;
+    assert_eq!(health.collection, th.collection);
+    assert_eq!(health.db_path, th.settings.db_file(&th.collection));
+    assert_eq!(health.pid, std::process::id());
+    assert_eq!(health.num_tags, 3);
+    assert_eq!(health.num_files, 1);
+    assert!(health.requests > 0);
+    assert_eq!(health.write_gate, None);
+    Ok(())
+}
+
+#[test]
+/// Tests that a peer can make JSON-RPC calls on the notifier socket, in between the notes that it's sent
+fn test_rpc() -> TestResult {
+    let th = TestHelper::new(None);
+    let f1 = th.ln(&["t1"])?;
+    let socket_file = th.settings.notify_socket_file(&th.collection);
+
+    let described = uds::request_rpc(&socket_file, "rpc.describe", json!(null))?;
+    assert_eq!(described["version"], supertag::cli::rpc::PROTOCOL_VERSION);
+
+    let col = json!(th.collection);
+    let tags = uds::request_rpc(&socket_file, "tags", json!({ "collection": col }))?;
+    assert_eq!(tags, json!([{"name": "t1", "num_files": 1}]));
+
+    let file = f1.tmp.path().canonicalize()?.to_string_lossy().to_string();
+    let retagged = uds::request_rpc(
+        &socket_file,
+        "retag",
+        json!({"collection": col, "file": file, "add": ["t2"], "remove": ["t1"]}),
+    )?;
+    assert_eq!(retagged, json!({"added": ["t2"], "removed": ["t1"]}));
+    th.assert_path_exists(f1.link_filedir_path(&["t2"], false));
+
+    let files = uds::request_rpc(
+        &socket_file,
+        "query",
+        json!({"collection": col, "terms": ["t2"]}),
+    )?;
+    assert_eq!(files[0]["path"], json!(file));
+
+    let err = uds::request_rpc(&socket_file, "nope", json!(null)).unwrap_err();
+    assert!(err.to_string().contains("nope"), "{}", err);
+
+    // only the methods meant for the socket can be called on it, and only by the mount's owner
+    let err =
+        uds::request_rpc(&socket_file, "rm", json!({"collection": col, "file": file})).unwrap_err();
+    assert!(err.to_string().contains("rm"), "{}", err);
+    th.assert_path_exists(f1.link_filedir_path(&["t2"], false));
+    let mode = std::fs::metadata(&socket_file)?.permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    Ok(())
+}
+
+#[test]
+/// Tests that retrying an RPC call with the same idempotency key returns the original result, without making the
+/// call again
+fn test_rpc_idempotency() -> TestResult {
+    let th = TestHelper::new(None);
+    let f1 = th.ln(&["t1"])?;
+    let socket_file = th.settings.notify_socket_file(&th.collection);
+    let col = json!(th.collection);
+    let file = f1.tmp.path().canonicalize()?.to_string_lossy().to_string();
+
+    let add = json!({"collection": col, "file": file, "add": ["t2"], "idempotency_key": "k1"});
+    let first = uds::request_rpc(&socket_file, "retag", add.clone())?;
+    assert_eq!(first, json!({"added": ["t2"], "removed": []}));
+
+    // the file is taken back out of t2, so if the retry were made again, it would add it again
+    uds::request_rpc(
+        &socket_file,
+        "retag",
+        json!({"collection": col, "file": file, "remove": ["t2"]}),
+    )?;
+    th.sleep_readdir_cache();
+    th.assert_path_not_exists(f1.link_filedir_path(&["t2"], false));
+
+    let retried = uds::request_rpc(&socket_file, "retag", add)?;
+    assert_eq!(retried, first);
+    th.sleep_readdir_cache();
+    th.assert_path_not_exists(f1.link_filedir_path(&["t2"], false));
+
+    // a key belongs to the call it was first made with
+    let err = uds::request_rpc(
+        &socket_file,
+        "tags",
+        json!({"collection": col, "idempotency_key": "k1"}),
+    )
+    .unwrap_err();
+    assert!(err.to_string().contains("already used"), "{}", err);
+    Ok(())
+}
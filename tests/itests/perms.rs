@@ -19,8 +19,14 @@
 use super::{TestHelper, TestResult};
 #[cfg(target_os = "linux")]
 use crate::common::OpMode;
+use fuse_sys::{Filesystem, FuseResult, Request};
+use nix::errno::Errno;
 use nix::sys::stat::stat;
+use std::path::{Path, PathBuf};
+use supertag::common::constants::XATTR_TAGS;
 use supertag::common::types::file_perms::{Permissions, UMask};
+use supertag::fuse::TagFilesystem;
+use supertag::sql::tpool::ThreadConnPool;
 
 #[test]
 fn test_mountdir_perms() -> TestResult {
@@ -90,3 +96,72 @@ fn _test_tag_perms(th: TestHelper) -> TestResult {
 
     Ok(())
 }
+
+// tests that a user who isn't allowed to write to a tag can't take files out of it, put files in to it, rename it or
+// change the tags of its files, when other users can use the mount
+#[test]
+fn test_tag_write_denied() -> TestResult {
+    let test_config = r#"
+[symbols]
+inode_char = "-"
+device_char = "﹫"
+sync_char = "\u007F"
+filedir_str = "⋂"
+filedir_cli_str = "_"
+tag_group_str = "+"
+
+[mount]
+allow_other = true
+"#;
+    let th = TestHelper::new(Some(test_config));
+    let guarded = th.ln(&["t1"])?;
+
+    // a second filesystem over the same database, so that we can make requests as somebody else
+    let db_file = th.settings.db_file(&th.collection);
+    let ops = TagFilesystem::new(
+        th.settings.clone(),
+        ThreadConnPool::new(db_file),
+        th.notifier.clone(),
+    );
+    let other = Request {
+        uid: th.uid + 1,
+        gid: th.gid + 1,
+        pid: 0,
+        umask: 0o022,
+    };
+    let rel = |path: PathBuf| Path::new("/").join(path.strip_prefix(th.real_mountpoint()).unwrap());
+
+    // a tag of their own, that they can write to
+    ops.mkdir(&other, Path::new("/open"), 0o777)?;
+    let open = th.ln(&["open"])?;
+
+    let guarded_path = rel(guarded.link_filedir_path(&["t1"], false));
+    let open_path = rel(open.link_filedir_path(&["open"], false));
+    let moved_path = rel(open.link_filedir_path(&["t1"], false));
+
+    assert_denied(ops.rmdir(&other, Path::new("/t1")));
+    assert_denied(ops.unlink(&other, &guarded_path));
+    assert_denied(ops.rename(
+        &other,
+        &guarded_path,
+        &rel(guarded.link_filedir_path(&["open"], false)),
+    ));
+    assert_denied(ops.rename(&other, &open_path, &moved_path));
+    assert_denied(ops.exchange(&other, Path::new("/open"), Path::new("/t1")));
+    assert_denied(ops.setxattr_impl(&other, &open_path, XATTR_TAGS, b"open,t1", 0, 0));
+    assert_denied(ops.setxattr_impl(&other, &guarded_path, "user.supertag.meta.k", b"v", 0, 0));
+    assert_denied(ops.removexattr_impl(&other, &guarded_path, "user.supertag.meta.k", 0));
+
+    th.assert_path_exists(guarded.link_filedir_path(&["t1"], false));
+    th.assert_path_not_exists(open.link_filedir_path(&["t1"], false));
+
+    // but they can still do what they like with their own tag
+    ops.setxattr_impl(&other, &open_path, XATTR_TAGS, b"open,t2", 0, 0)?;
+    th.assert_path_exists(open.link_filedir_path(&["open", "t2"], false));
+
+    Ok(())
+}
+
+fn assert_denied<T: std::fmt::Debug>(res: FuseResult<T>) {
+    assert_eq!(res.unwrap_err().errno, Errno::EACCES);
+}
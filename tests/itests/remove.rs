@@ -18,6 +18,7 @@
 
 use super::{TestHelper, TestResult};
 use crate::common::{make_unlink_name, OpMode};
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 
 #[test]
@@ -460,3 +461,77 @@ fn test_rename_delete_stat() -> TestResult {
 
     Ok(())
 }
+
+#[test]
+/// Tests that a dry run of rm and rmdir leaves the collection as it was
+fn test_remove_dry_run() -> TestResult {
+    let th = TestHelper::new(None);
+    let linked = th.ln(&["t1", "t2"])?;
+    let t1_path = linked.link_filedir_path(&["t1"], false);
+
+    let mut settings = th.settings.for_collection(&th.collection);
+    settings.set_dry_run(true);
+
+    let mut conn = th.fresh_conn();
+    supertag::rm(&settings, &mut conn, &t1_path, &th.real_mountpoint())?;
+    supertag::rmdir(
+        &settings,
+        &mut conn,
+        th.real_mountpoint(),
+        &th.mountpoint_path(&["t2"]),
+    )?;
+
+    th.assert_path_exists(&t1_path);
+    th.assert_path_exists(linked.link_filedir_path(&["t2"], false));
+    th.assert_count(&["t1"], 1);
+    th.assert_count(&["t2"], 1);
+    Ok(())
+}
+
+/// Tests that a glob in a filedir path removes every file it matches, without touching the others
+#[test]
+fn test_rm_glob() -> TestResult {
+    let th = TestHelper::new(None);
+    let dir = tempfile::Builder::new()
+        .prefix("supertag-testdir")
+        .tempdir()?;
+    for name in &["a.log", "b.log", "c.txt"] {
+        let path = dir.path().join(name);
+        std::fs::write(&path, name)?;
+        th.ln_with_file(&path, &["t1"])?;
+    }
+
+    let pattern = th.filedir_path(&["t1"]).join("*.log");
+    let mut conn = th.fresh_conn();
+    let matched =
+        supertag::cli::glob::expand(&th.settings, &conn, &th.real_mountpoint(), &pattern)?;
+    assert_eq!(matched.len(), 2);
+
+    let matched: Vec<&Path> = matched.iter().map(PathBuf::as_path).collect();
+    supertag::cli::rm::rm_all(&th.settings, &mut conn, &matched, &th.real_mountpoint())?;
+    assert_eq!(th.ls_filedir(&["t1"])?, vec!["c.txt"]);
+
+    Ok(())
+}
+
+/// Tests that a file whose last tag is removed shows up in the untagged directory, and that unlinking it there purges
+/// it from the collection
+#[test]
+fn test_untagged_dir() -> TestResult {
+    let th = TestHelper::new(None);
+    let untagged_dir = th.settings.get_config().untagged.dir;
+    let linked = th.ln(&["t1"])?;
+    assert!(!th.ls(&[])?.contains(&untagged_dir));
+
+    th.rm(&linked.link_filedir_path(&["t1"], false))?;
+    let untagged = th.mountpoint_path(&[&untagged_dir, &linked.link_filename(false)]);
+    assert!(th.ls(&[])?.contains(&untagged_dir));
+    assert_eq!(std::fs::read_link(&untagged)?, linked.target_path());
+
+    std::fs::remove_file(&untagged)?;
+    th.assert_path_not_exists(&untagged);
+    assert!(!th.ls(&[])?.contains(&untagged_dir));
+    assert!(th.ls(&[&untagged_dir])?.is_empty());
+
+    Ok(())
+}
@@ -0,0 +1,97 @@
+/*
+ * Supertag
+ * Copyright (C) 2020 Andrew Moffat
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use super::{LinkedFile, OpMode, TestHelper, TestResult};
+use std::ffi::OsStr;
+use std::fs;
+use std::rc::Rc;
+use supertag::cli::commands::{add_subcommands, ArgDefaults};
+use supertag::cli::handlers;
+
+#[test]
+fn test_rules_cli() -> TestResult {
+    let th = TestHelper::new(None);
+    _test_rules(th)
+}
+
+#[test]
+fn test_rules_manual() -> TestResult {
+    let mut th = TestHelper::new(None);
+    th.symlink_mode = OpMode::MANUAL;
+    th.mkdir_mode = OpMode::MANUAL;
+    _test_rules(th)
+}
+
+fn _test_rules(th: TestHelper) -> TestResult {
+    let col_conf = th.settings.config_file(&th.collection);
+
+    // our test files are all named *.tmp
+    fs::write(
+        &col_conf,
+        "rules = [\"*.tmp -> scratch\", \"*.nope -> never\"]\n",
+    )?;
+    let l1 = th.ln(&["a"])?;
+    th.assert_path_exists(l1.link_filedir_path(&["a"], false));
+    th.assert_path_exists(l1.link_filedir_path(&["scratch"], false));
+    th.assert_parts_not_exists(&["never"]);
+
+    // editing the rules takes effect without remounting
+    fs::write(&col_conf, "rules = [\"*.tmp -> drafts\"]\n")?;
+    let l2 = th.ln(&["b"])?;
+    th.assert_path_exists(l2.link_filedir_path(&["drafts"], false));
+    th.assert_path_not_exists(l2.link_filedir_path(&["scratch"], false));
+
+    Ok(())
+}
+
+#[test]
+/// Tests that `tag ln` follows the rules of the collection that it resolves from the tag path, and not those of the
+/// collection it started out with
+fn test_rules_ln_handler() -> TestResult {
+    let th = TestHelper::new(None);
+    fs::write(
+        th.settings.config_file(&th.collection),
+        "rules = [\"*.tmp -> scratch\"]\n",
+    )?;
+
+    let mut builder = tempfile::Builder::new();
+    builder.prefix("supertag-testfile").suffix(".tmp");
+    let linked = LinkedFile::new(&th, Rc::new(builder.tempfile()?), &["a"]);
+
+    let defaults = ArgDefaults {
+        uid: th.uid.to_string(),
+        gid: th.gid.to_string(),
+        mount_perms: "755".to_string(),
+    };
+    let app = add_subcommands(clap::App::new("tag"), &defaults);
+    let target = linked.target_path();
+    let tag_path = th.mountpoint_path(&["a"]);
+    let matches = app.get_matches_from(vec![
+        OsStr::new("tag"),
+        OsStr::new("ln"),
+        target.as_os_str(),
+        tag_path.as_os_str(),
+    ]);
+    let args = matches.subcommand_matches("ln").unwrap();
+    handlers::ln::handle(args, th.settings.for_collection("rules-other"))?;
+
+    th.assert_path_exists(linked.link_filedir_path(&["a"], false));
+    th.assert_path_exists(linked.link_filedir_path(&["scratch"], false));
+
+    Ok(())
+}
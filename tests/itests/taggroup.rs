@@ -513,6 +513,38 @@ fn test_nested_tag_group() -> TestResult {
     Ok(())
 }
 
+/// Tests that a tag group made inside of another one is nested in it, and holds its tags there
+#[test]
+fn test_tag_group_of_groups() -> TestResult {
+    let th = TestHelper::new(None);
+    th.mkdir("media+")?;
+    th.mkdir("media+/video+")?;
+    th.mkdir("media+/audio+")?;
+
+    let _l1 = th.ln(&["mp4"])?;
+    let _l2 = th.ln(&["mp3"])?;
+    th.mv(
+        &th.mountpoint_path(&["mp4"]),
+        &th.mountpoint_path(&["video+"]),
+    )?;
+    th.mv(
+        &th.mountpoint_path(&["mp3"]),
+        &th.mountpoint_path(&["audio+"]),
+    )?;
+
+    th.assert_size(&["media+"], 2);
+    th.assert_parts_exists(&["media+", "video+", "mp4"]);
+    th.assert_parts_exists(&["media+", "audio+", "mp3"]);
+    assert_eq!(th.ls(&["media+"])?, vec!["audio+", "video+"]);
+    assert!(!th.ls(&[])?.contains(&"video+".to_string()));
+
+    // a group can't be nested beneath itself
+    assert!(th.mkdir("video+/media+").is_err());
+    th.assert_parts_not_exists(&["media+", "video+", "mp3"]);
+
+    Ok(())
+}
+
 #[test]
 fn test_tag_group_multiple_cli() -> TestResult {
     let th = TestHelper::new(None);
@@ -615,3 +647,39 @@ fn _test_tag_group_nested_size(th: TestHelper) -> TestResult {
 
     Ok(())
 }
+
+#[test]
+fn test_tag_group_rules() -> TestResult {
+    let test_config = r#"
+[symbols]
+inode_char = "-"
+device_char = "﹫"
+sync_char = "\u007F"
+filedir_str = "⋂"
+filedir_cli_str = "_"
+tag_group_str = "+"
+
+[mount]
+
+[[groups.rules]]
+pattern = "client-*"
+group = "clients"
+
+[[groups.rules]]
+pattern = "*-internal"
+group = "internal"
+"#;
+    let th = TestHelper::new(Some(test_config));
+    let _l1 = th.ln(&["client-acme", "invoices"])?;
+    let _l2 = th.ln(&["client-acme-internal"])?;
+
+    assert!(th.getattr_exists(th.mountpoint_path(&["clients+", "client-acme"])));
+    assert!(!th.getattr_exists(th.mountpoint_path(&["clients+", "invoices"])));
+
+    // matches the rules of both groups, so it's left out of both
+    assert!(!th.getattr_exists(th.mountpoint_path(&["clients+", "client-acme-internal"])));
+    th.assert_parts_not_exists(&["internal+"]);
+    th.assert_parts_exists(&["client-acme-internal"]);
+
+    Ok(())
+}